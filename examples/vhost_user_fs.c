@@ -0,0 +1,33 @@
+/*
+ * This is an example serving a directory to a VMM like QEMU or
+ * cloud-hypervisor, using the virtio-fs server of libkrun as a vhost-user-fs
+ * backend. For instance, with QEMU:
+ *
+ *   ./vhost_user_fs /tmp/vhost-fs.sock /srv/shared &
+ *   qemu-system-x86_64 ... \
+ *       -object memory-backend-memfd,id=mem,size=4G,share=on \
+ *       -numa node,memdev=mem \
+ *       -chardev socket,id=fs0,path=/tmp/vhost-fs.sock \
+ *       -device vhost-user-fs-pci,chardev=fs0,tag=shared
+ */
+
+#include <stdio.h>
+#include <string.h>
+#include <libkrun.h>
+
+int main(int argc, char *const argv[])
+{
+    int err;
+
+    if (argc != 3) {
+        fprintf(stderr, "Usage: %s SOCKET_PATH SHARED_DIR\n", argv[0]);
+        return -1;
+    }
+
+    // Only log errors.
+    krun_set_log_level(1);
+
+    err = krun_serve_vhost_user_fs(argv[1], argv[2]);
+    fprintf(stderr, "Error serving %s: %s\n", argv[2], strerror(-err));
+    return -1;
+}
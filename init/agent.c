@@ -0,0 +1,1022 @@
+/*
+ * Guest side of the libkrun agent protocol. See utils/src/agent.rs for the
+ * host side and the description of the wire format.
+ */
+
+#define _GNU_SOURCE
+
+#include <errno.h>
+#include <fcntl.h>
+#include <poll.h>
+#include <signal.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+#include <unistd.h>
+
+#include <sys/mman.h>
+#include <sys/socket.h>
+#include <sys/stat.h>
+#include <sys/sysinfo.h>
+#include <sys/wait.h>
+
+#include <linux/vm_sockets.h>
+
+#include "agent.h"
+
+#define AGENT_PORT 1025
+#define AGENT_EXEC_HOOK_PORT 1026
+#define AGENT_MAX_PAYLOAD (1 << 20)
+#define AGENT_MAX_OUTPUT (64 * 1024)
+#define AGENT_MAX_ARGS 256
+
+enum agent_op {
+    AGENT_OP_PING = 1,
+    AGENT_OP_EXEC = 2,
+    AGENT_OP_STATS = 3,
+    AGENT_OP_SET_TIME = 4,
+    AGENT_OP_SHUTDOWN = 5,
+    AGENT_OP_WRITE_FILE = 6,
+    AGENT_OP_READ_FILE = 7,
+    AGENT_OP_EXEC_HOOK = 8,
+    AGENT_OP_SPAWN = 9,
+    AGENT_OP_SET_CLIPBOARD = 10,
+    AGENT_OP_GET_CLIPBOARD = 11,
+    AGENT_OP_SUSPEND = 12,
+    AGENT_OP_SIGNAL = 13,
+};
+
+enum agent_frame {
+    AGENT_FRAME_STDOUT = 1,
+    AGENT_FRAME_STDERR = 2,
+    AGENT_FRAME_EXIT = 3,
+};
+
+/* Common header of the file operations, followed by the path. */
+#define AGENT_FILE_HDR_LEN 12
+
+static int read_full(int fd, void *buf, size_t len)
+{
+    char *p = buf;
+    ssize_t n;
+
+    while (len > 0) {
+        n = read(fd, p, len);
+        if (n < 0 && errno == EINTR) {
+            continue;
+        }
+        if (n <= 0) {
+            return -1;
+        }
+        p += n;
+        len -= n;
+    }
+
+    return 0;
+}
+
+static int write_full(int fd, const void *buf, size_t len)
+{
+    const char *p = buf;
+    ssize_t n;
+
+    while (len > 0) {
+        n = write(fd, p, len);
+        if (n < 0 && errno == EINTR) {
+            continue;
+        }
+        if (n < 0) {
+            return -1;
+        }
+        p += n;
+        len -= n;
+    }
+
+    return 0;
+}
+
+/* All the architectures we support are little endian. */
+static void send_response(int fd, int32_t result, const void *payload,
+                          uint32_t len)
+{
+    char hdr[8];
+
+    memcpy(&hdr[0], &result, sizeof(result));
+    memcpy(&hdr[4], &len, sizeof(len));
+    if (write_full(fd, hdr, sizeof(hdr)) == 0 && len > 0) {
+        write_full(fd, payload, len);
+    }
+}
+
+static void handle_exec(int fd, char *payload, uint32_t len)
+{
+    char *argv[AGENT_MAX_ARGS];
+    char *output;
+    char *p = payload;
+    char buf[4096];
+    size_t out_len = 0;
+    ssize_t n;
+    int pipefd[2];
+    int argc = 0;
+    int status;
+    int nullfd;
+    pid_t pid;
+
+    if (len == 0 || payload[len - 1] != '\0') {
+        send_response(fd, -EINVAL, NULL, 0);
+        return;
+    }
+    while (p < payload + len && argc < AGENT_MAX_ARGS - 1) {
+        argv[argc++] = p;
+        p += strlen(p) + 1;
+    }
+    argv[argc] = NULL;
+    if (p < payload + len) {
+        send_response(fd, -E2BIG, NULL, 0);
+        return;
+    }
+
+    if (pipe(pipefd) < 0) {
+        send_response(fd, -errno, NULL, 0);
+        return;
+    }
+
+    pid = fork();
+    if (pid < 0) {
+        send_response(fd, -errno, NULL, 0);
+        close(pipefd[0]);
+        close(pipefd[1]);
+        return;
+    }
+    if (pid == 0) {
+        close(fd);
+        close(pipefd[0]);
+        nullfd = open("/dev/null", O_RDONLY);
+        if (nullfd >= 0) {
+            dup2(nullfd, STDIN_FILENO);
+            close(nullfd);
+        }
+        dup2(pipefd[1], STDOUT_FILENO);
+        dup2(pipefd[1], STDERR_FILENO);
+        close(pipefd[1]);
+        execvp(argv[0], argv);
+        _exit(127);
+    }
+    close(pipefd[1]);
+
+    /* Keep draining the pipe past the limit, so the command doesn't block. */
+    output = malloc(AGENT_MAX_OUTPUT);
+    while ((n = read(pipefd[0], buf, sizeof(buf))) != 0) {
+        if (n < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            break;
+        }
+        if (output && out_len < AGENT_MAX_OUTPUT) {
+            if (n > AGENT_MAX_OUTPUT - out_len) {
+                n = AGENT_MAX_OUTPUT - out_len;
+            }
+            memcpy(output + out_len, buf, n);
+            out_len += n;
+        }
+    }
+    close(pipefd[0]);
+
+    while (waitpid(pid, &status, 0) < 0) {
+        if (errno != EINTR) {
+            status = 0;
+            break;
+        }
+    }
+
+    if (WIFSIGNALED(status)) {
+        status = 128 + WTERMSIG(status);
+    } else {
+        status = WEXITSTATUS(status);
+    }
+    send_response(fd, status, output, out_len);
+    free(output);
+}
+
+static int send_frame(int fd, uint8_t kind, const void *data, uint32_t len)
+{
+    char hdr[8] = {kind};
+
+    memcpy(&hdr[4], &len, sizeof(len));
+    if (write_full(fd, hdr, sizeof(hdr)) < 0) {
+        return -1;
+    }
+    return write_full(fd, data, len);
+}
+
+/*
+ * Splits "payload" into the argument and environment lists of an exec spec,
+ * which point into it. Returns -1 if it's malformed or too long.
+ */
+static int parse_exec_spec(char *payload, uint32_t len, char **argv,
+                           char **envp, int max)
+{
+    char **list = argv;
+    char *p = payload;
+    int n = 0;
+
+    if (len == 0 || payload[len - 1] != '\0') {
+        return -1;
+    }
+    for (; p < payload + len; p += strlen(p) + 1) {
+        if (*p == '\0' && list == argv) {
+            argv[n] = NULL;
+            if (n == 0) {
+                return -1;
+            }
+            list = envp;
+            n = 0;
+            continue;
+        }
+        if (n == max - 1) {
+            return -1;
+        }
+        list[n++] = p;
+    }
+    list[n] = NULL;
+
+    return list == envp ? 0 : -1;
+}
+
+/*
+ * Runs the command and, once its PID has been sent back, relays its standard
+ * input from the connection and its output to it until the command exits.
+ */
+static void handle_spawn(int fd, char *payload, uint32_t len)
+{
+    extern char **environ;
+    char *argv[AGENT_MAX_ARGS];
+    char *envp[AGENT_MAX_ARGS];
+    int in[2], out[2], err[2];
+    struct pollfd fds[3];
+    char buf[4096];
+    int32_t status;
+    ssize_t n;
+    pid_t pid;
+    int open_outputs = 2;
+    int i;
+
+    if (parse_exec_spec(payload, len, argv, envp, AGENT_MAX_ARGS) < 0) {
+        send_response(fd, -EINVAL, NULL, 0);
+        return;
+    }
+
+    if (pipe2(in, O_CLOEXEC) < 0 || pipe2(out, O_CLOEXEC) < 0 ||
+        pipe2(err, O_CLOEXEC) < 0) {
+        send_response(fd, -errno, NULL, 0);
+        return;
+    }
+
+    pid = fork();
+    if (pid < 0) {
+        send_response(fd, -errno, NULL, 0);
+        for (i = 0; i < 2; i++) {
+            close(in[i]);
+            close(out[i]);
+            close(err[i]);
+        }
+        return;
+    }
+    if (pid == 0) {
+        dup2(in[0], STDIN_FILENO);
+        dup2(out[1], STDOUT_FILENO);
+        dup2(err[1], STDERR_FILENO);
+        execvpe(argv[0], argv, envp[0] ? envp : environ);
+        _exit(127);
+    }
+    close(in[0]);
+    close(out[1]);
+    close(err[1]);
+
+    send_response(fd, pid, NULL, 0);
+
+    /* The command may exit without consuming all its input. */
+    signal(SIGPIPE, SIG_IGN);
+
+    fds[0].fd = fd;
+    fds[1].fd = out[0];
+    fds[2].fd = err[0];
+    for (i = 0; i < 3; i++) {
+        fds[i].events = POLLIN;
+    }
+
+    while (open_outputs > 0) {
+        if (poll(fds, 3, -1) < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            break;
+        }
+
+        if (fds[0].revents) {
+            n = read(fd, buf, sizeof(buf));
+            if (n > 0) {
+                if (in[1] >= 0 && write_full(in[1], buf, n) < 0) {
+                    close(in[1]);
+                    in[1] = -1;
+                }
+            } else if (n == 0 || errno != EINTR) {
+                /* The host closed the input, or went away. */
+                if (in[1] >= 0) {
+                    close(in[1]);
+                    in[1] = -1;
+                }
+                fds[0].fd = -1;
+            }
+        }
+
+        for (i = 1; i < 3; i++) {
+            if (!fds[i].revents) {
+                continue;
+            }
+            n = read(fds[i].fd, buf, sizeof(buf));
+            if (n > 0) {
+                send_frame(fd, i == 1 ? AGENT_FRAME_STDOUT : AGENT_FRAME_STDERR,
+                           buf, n);
+            } else if (n == 0 || errno != EINTR) {
+                close(fds[i].fd);
+                fds[i].fd = -1;
+                open_outputs--;
+            }
+        }
+    }
+    if (in[1] >= 0) {
+        close(in[1]);
+    }
+
+    while (waitpid(pid, &status, 0) < 0) {
+        if (errno != EINTR) {
+            status = 0;
+            break;
+        }
+    }
+    if (WIFSIGNALED(status)) {
+        status = 128 + WTERMSIG(status);
+    } else {
+        status = WEXITSTATUS(status);
+    }
+    send_frame(fd, AGENT_FRAME_EXIT, &status, sizeof(status));
+}
+
+static void handle_stats(int fd)
+{
+    struct sysinfo info;
+    char buf[512];
+    int len;
+
+    if (sysinfo(&info) < 0) {
+        send_response(fd, -errno, NULL, 0);
+        return;
+    }
+
+    len = snprintf(
+        buf, sizeof(buf),
+        "{\"uptime\":%ld,\"load\":[%.2f,%.2f,%.2f],\"mem_total\":%llu,"
+        "\"mem_free\":%llu,\"mem_shared\":%llu,\"mem_buffers\":%llu,"
+        "\"procs\":%u}",
+        info.uptime, info.loads[0] / 65536.0, info.loads[1] / 65536.0,
+        info.loads[2] / 65536.0,
+        (unsigned long long)info.totalram * info.mem_unit,
+        (unsigned long long)info.freeram * info.mem_unit,
+        (unsigned long long)info.sharedram * info.mem_unit,
+        (unsigned long long)info.bufferram * info.mem_unit, info.procs);
+    send_response(fd, 0, buf, len);
+}
+
+static void handle_set_time(int fd, char *payload, uint32_t len)
+{
+    struct timespec ts;
+    uint64_t ns;
+
+    if (len != sizeof(ns)) {
+        send_response(fd, -EINVAL, NULL, 0);
+        return;
+    }
+    memcpy(&ns, payload, sizeof(ns));
+
+    ts.tv_sec = ns / 1000000000;
+    ts.tv_nsec = ns % 1000000000;
+    if (clock_settime(CLOCK_REALTIME, &ts) < 0) {
+        send_response(fd, -errno, NULL, 0);
+    } else {
+        send_response(fd, 0, NULL, 0);
+    }
+}
+
+/*
+ * Parses the header of a file operation, returning the offset in "offset",
+ * the mode or length in "arg" and the length of the NUL-terminated path
+ * following it.
+ */
+static int parse_file_request(char *payload, uint32_t len, uint64_t *offset,
+                              uint32_t *arg)
+{
+    char *path = payload + AGENT_FILE_HDR_LEN;
+    size_t path_len;
+
+    if (len <= AGENT_FILE_HDR_LEN) {
+        return -1;
+    }
+    path_len = strnlen(path, len - AGENT_FILE_HDR_LEN);
+    if (path_len == 0 || path_len == len - AGENT_FILE_HDR_LEN) {
+        return -1;
+    }
+
+    memcpy(offset, &payload[0], sizeof(*offset));
+    memcpy(arg, &payload[8], sizeof(*arg));
+    return path_len + 1;
+}
+
+static void handle_write_file(int fd, char *payload, uint32_t len)
+{
+    char *path = payload + AGENT_FILE_HDR_LEN;
+    uint64_t offset;
+    uint32_t mode;
+    char *data;
+    size_t data_len;
+    int path_len;
+    int flags;
+    int filefd;
+    int ret = 0;
+
+    path_len = parse_file_request(payload, len, &offset, &mode);
+    if (path_len < 0) {
+        send_response(fd, -EINVAL, NULL, 0);
+        return;
+    }
+    data = path + path_len;
+    data_len = len - AGENT_FILE_HDR_LEN - path_len;
+
+    flags = O_WRONLY | O_CLOEXEC;
+    if (offset == 0) {
+        flags |= O_CREAT | O_TRUNC;
+    }
+    filefd = open(path, flags, mode & 07777);
+    if (filefd < 0) {
+        send_response(fd, -errno, NULL, 0);
+        return;
+    }
+    /* The mode is only applied by open() when creating the file. */
+    if (offset == 0 && fchmod(filefd, mode & 07777) < 0) {
+        ret = -errno;
+    }
+
+    while (ret == 0 && data_len > 0) {
+        ssize_t n = pwrite(filefd, data, data_len, offset);
+        if (n < 0) {
+            if (errno != EINTR) {
+                ret = -errno;
+            }
+            continue;
+        }
+        data += n;
+        data_len -= n;
+        offset += n;
+    }
+    close(filefd);
+
+    send_response(fd, ret, NULL, 0);
+}
+
+static void handle_read_file(int fd, char *payload, uint32_t len)
+{
+    char *path = payload + AGENT_FILE_HDR_LEN;
+    uint64_t offset;
+    uint32_t count;
+    size_t done = 0;
+    char *data;
+    int filefd;
+    int ret = 0;
+
+    if (parse_file_request(payload, len, &offset, &count) < 0 ||
+        count > AGENT_MAX_PAYLOAD) {
+        send_response(fd, -EINVAL, NULL, 0);
+        return;
+    }
+
+    filefd = open(path, O_RDONLY | O_CLOEXEC);
+    if (filefd < 0) {
+        send_response(fd, -errno, NULL, 0);
+        return;
+    }
+    data = malloc(count ? count : 1);
+    if (data == NULL) {
+        close(filefd);
+        send_response(fd, -ENOMEM, NULL, 0);
+        return;
+    }
+
+    while (done < count) {
+        ssize_t n = pread(filefd, data + done, count - done, offset + done);
+        if (n < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            ret = -errno;
+            break;
+        }
+        if (n == 0) {
+            break;
+        }
+        done += n;
+    }
+    close(filefd);
+
+    if (ret < 0) {
+        send_response(fd, ret, NULL, 0);
+    } else {
+        send_response(fd, 0, data, done);
+    }
+    free(data);
+}
+
+/*
+ * Runs the first of the clipboard tools in "tools" present in the guest,
+ * with "infd" and "outfd" as its standard input and output, and returns its
+ * exit status, 127 if none could be run, or a negative errno.
+ */
+static int run_clipboard_tool(int connfd, char **tools[], int infd, int outfd)
+{
+    int status;
+    pid_t pid;
+
+    pid = fork();
+    if (pid < 0) {
+        return -errno;
+    }
+    if (pid == 0) {
+        /* The tools may leave a process behind to serve the selection. */
+        close(connfd);
+        dup2(infd, STDIN_FILENO);
+        dup2(outfd, STDOUT_FILENO);
+        for (; *tools != NULL; tools++) {
+            execvp((*tools)[0], *tools);
+        }
+        _exit(127);
+    }
+
+    while (waitpid(pid, &status, 0) < 0) {
+        if (errno != EINTR) {
+            return -errno;
+        }
+    }
+    return WIFEXITED(status) ? WEXITSTATUS(status) : -EIO;
+}
+
+static void handle_set_clipboard(int fd, char *payload, uint32_t len)
+{
+    char *mime = payload;
+    size_t mime_len = strnlen(payload, len);
+    char *wl_copy[] = {"wl-copy", "--type", mime, NULL};
+    char *xclip[] = {"xclip", "-selection", "clipboard", "-t", mime, "-i", NULL};
+    char **tools[] = {wl_copy, xclip, NULL};
+    int datafd;
+    int nullfd = -1;
+    int ret;
+
+    if (mime_len == 0 || mime_len == len) {
+        send_response(fd, -EINVAL, NULL, 0);
+        return;
+    }
+
+    /* Pass the data in a file, as it may not fit in a pipe. */
+    datafd = memfd_create("clipboard", MFD_CLOEXEC);
+    if (datafd < 0 ||
+        write_full(datafd, mime + mime_len + 1, len - mime_len - 1) < 0 ||
+        lseek(datafd, 0, SEEK_SET) < 0 ||
+        (nullfd = open("/dev/null", O_WRONLY | O_CLOEXEC)) < 0) {
+        ret = -errno;
+    } else {
+        ret = run_clipboard_tool(fd, tools, datafd, nullfd);
+        if (ret == 127) {
+            ret = -ENOSYS;
+        } else if (ret > 0) {
+            ret = -EIO;
+        }
+    }
+    send_response(fd, ret, NULL, 0);
+
+    if (datafd >= 0) {
+        close(datafd);
+    }
+    if (nullfd >= 0) {
+        close(nullfd);
+    }
+}
+
+static void handle_get_clipboard(int fd, char *payload, uint32_t len)
+{
+    char *mime = payload;
+    char *wl_paste[] = {"wl-paste", "--no-newline", "--type", mime, NULL};
+    char *xclip[] = {"xclip", "-selection", "clipboard", "-t", mime, "-o", NULL};
+    char **tools[] = {wl_paste, xclip, NULL};
+    char *data = NULL;
+    int datafd;
+    int nullfd = -1;
+    off_t size = 0;
+    int ret;
+
+    if (len < 2 || strnlen(payload, len) != len - 1) {
+        send_response(fd, -EINVAL, NULL, 0);
+        return;
+    }
+
+    /* Collect the output in a file, so it can be sized before sending it. */
+    datafd = memfd_create("clipboard", MFD_CLOEXEC);
+    if (datafd < 0 || (nullfd = open("/dev/null", O_RDONLY | O_CLOEXEC)) < 0) {
+        ret = -errno;
+        goto out;
+    }
+
+    ret = run_clipboard_tool(fd, tools, nullfd, datafd);
+    if (ret == 127) {
+        ret = -ENOSYS;
+    } else if (ret > 0) {
+        /* Nothing is selected, or not in the requested type. */
+        ret = -ENODATA;
+    }
+    if (ret < 0) {
+        goto out;
+    }
+
+    size = lseek(datafd, 0, SEEK_END);
+    if (size < 0) {
+        ret = -errno;
+    } else if (size > AGENT_MAX_PAYLOAD) {
+        ret = -EFBIG;
+    } else if ((data = malloc(size ? size : 1)) == NULL) {
+        ret = -ENOMEM;
+    } else if (pread(datafd, data, size, 0) != size) {
+        ret = -EIO;
+    }
+
+out:
+    if (ret < 0) {
+        send_response(fd, ret, NULL, 0);
+    } else {
+        send_response(fd, 0, data, size);
+    }
+    free(data);
+    if (datafd >= 0) {
+        close(datafd);
+    }
+    if (nullfd >= 0) {
+        close(nullfd);
+    }
+}
+
+/*
+ * Returns whether the kernel can suspend to RAM, as opposed to only idling
+ * the CPUs, which wouldn't stop the vCPUs.
+ */
+static int can_suspend(void)
+{
+    char buf[128];
+    ssize_t n;
+    int fd;
+
+    fd = open("/sys/power/mem_sleep", O_RDONLY | O_CLOEXEC);
+    if (fd < 0) {
+        return 0;
+    }
+    n = read(fd, buf, sizeof(buf) - 1);
+    close(fd);
+    if (n <= 0) {
+        return 0;
+    }
+    buf[n] = '\0';
+    return strstr(buf, "deep") != NULL;
+}
+
+static int write_sysfs(const char *path, const char *value)
+{
+    int fd;
+    int ret = 0;
+
+    fd = open(path, O_WRONLY | O_CLOEXEC);
+    if (fd < 0) {
+        return -errno;
+    }
+    if (write(fd, value, strlen(value)) < 0) {
+        ret = -errno;
+    }
+    close(fd);
+    return ret;
+}
+
+static void handle_suspend(int fd)
+{
+    int ret;
+
+    if (!can_suspend()) {
+        send_response(fd, -EOPNOTSUPP, NULL, 0);
+        return;
+    }
+    ret = write_sysfs("/sys/power/mem_sleep", "deep");
+    if (ret < 0) {
+        send_response(fd, ret, NULL, 0);
+        return;
+    }
+
+    /*
+     * Reply first, as writing the state only returns once the guest is
+     * resumed.
+     */
+    send_response(fd, 0, NULL, 0);
+    sync();
+    ret = write_sysfs("/sys/power/state", "mem");
+    if (ret < 0) {
+        fprintf(stderr, "agent: couldn't suspend: %s\n", strerror(-ret));
+    }
+}
+
+static void handle_signal(int fd, const char *payload, uint32_t len)
+{
+    union sigval value;
+    int32_t sig;
+
+    if (len != sizeof(sig)) {
+        send_response(fd, -EINVAL, NULL, 0);
+        return;
+    }
+    memcpy(&sig, payload, sizeof(sig));
+    if (sig <= 0 || sig >= SIGRTMIN) {
+        send_response(fd, -EINVAL, NULL, 0);
+        return;
+    }
+
+    value.sival_int = sig;
+    if (sigqueue(1, AGENT_FORWARD_SIGNAL, value) < 0) {
+        send_response(fd, -errno, NULL, 0);
+        return;
+    }
+    send_response(fd, 0, NULL, 0);
+}
+
+static void handle_connection(int fd)
+{
+    char hdr[8];
+    char *payload;
+    uint32_t len;
+
+    if (read_full(fd, hdr, sizeof(hdr)) < 0) {
+        return;
+    }
+    memcpy(&len, &hdr[4], sizeof(len));
+    if (len > AGENT_MAX_PAYLOAD) {
+        send_response(fd, -E2BIG, NULL, 0);
+        return;
+    }
+
+    payload = malloc(len + 1);
+    if (payload == NULL) {
+        send_response(fd, -ENOMEM, NULL, 0);
+        return;
+    }
+    if (read_full(fd, payload, len) < 0) {
+        free(payload);
+        return;
+    }
+
+    switch (hdr[0]) {
+    case AGENT_OP_PING:
+        send_response(fd, 0, NULL, 0);
+        break;
+    case AGENT_OP_EXEC:
+        handle_exec(fd, payload, len);
+        break;
+    case AGENT_OP_SPAWN:
+        handle_spawn(fd, payload, len);
+        break;
+    case AGENT_OP_STATS:
+        handle_stats(fd);
+        break;
+    case AGENT_OP_SET_TIME:
+        handle_set_time(fd, payload, len);
+        break;
+    case AGENT_OP_WRITE_FILE:
+        handle_write_file(fd, payload, len);
+        break;
+    case AGENT_OP_READ_FILE:
+        handle_read_file(fd, payload, len);
+        break;
+    case AGENT_OP_SET_CLIPBOARD:
+        handle_set_clipboard(fd, payload, len);
+        break;
+    case AGENT_OP_GET_CLIPBOARD:
+        handle_get_clipboard(fd, payload, len);
+        break;
+    case AGENT_OP_SUSPEND:
+        handle_suspend(fd);
+        break;
+    case AGENT_OP_SIGNAL:
+        handle_signal(fd, payload, len);
+        break;
+    case AGENT_OP_SHUTDOWN:
+        /*
+         * Reply first, as the VM may be gone as soon as the workload exits.
+         * Init ignores SIGTERM, but it exits, powering off the VM, once the
+         * workload does.
+         */
+        send_response(fd, 0, NULL, 0);
+        kill(-1, SIGTERM);
+        break;
+    default:
+        send_response(fd, -ENOSYS, NULL, 0);
+        break;
+    }
+
+    free(payload);
+}
+
+void agent_worker(void)
+{
+    struct sockaddr_vm addr;
+    int sockfd;
+    int fd;
+
+    sockfd = socket(AF_VSOCK, SOCK_STREAM | SOCK_CLOEXEC, 0);
+    if (sockfd < 0) {
+        perror("socket(agent)");
+        return;
+    }
+
+    memset(&addr, 0, sizeof(addr));
+    addr.svm_family = AF_VSOCK;
+    addr.svm_port = AGENT_PORT;
+    addr.svm_cid = VMADDR_CID_ANY;
+
+    if (bind(sockfd, (struct sockaddr *)&addr, sizeof(addr)) < 0 ||
+        listen(sockfd, 16) < 0) {
+        perror("bind(agent)");
+        close(sockfd);
+        return;
+    }
+
+    /* Let the kernel reap the per-connection processes. */
+    signal(SIGCHLD, SIG_IGN);
+
+    while (1) {
+        /* Not inherited by the commands the connection runs. */
+        fd = accept4(sockfd, NULL, NULL, SOCK_CLOEXEC);
+        if (fd < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            perror("accept(agent)");
+            break;
+        }
+
+        /* Serve every connection in its own process, so a long running
+         * command doesn't block other requests. */
+        if (fork() == 0) {
+            close(sockfd);
+            signal(SIGCHLD, SIG_DFL);
+            handle_connection(fd);
+            _exit(0);
+        }
+        close(fd);
+    }
+
+    close(sockfd);
+}
+
+/* Appends the NUL-terminated strings in "list" to "buf". */
+static char *append_strings(char *buf, size_t *len, char **list)
+{
+    size_t item_len;
+    char *new_buf;
+
+    for (; *list != NULL; list++) {
+        item_len = strlen(*list) + 1;
+        new_buf = realloc(buf, *len + item_len);
+        if (new_buf == NULL) {
+            free(buf);
+            return NULL;
+        }
+        buf = new_buf;
+        memcpy(buf + *len, *list, item_len);
+        *len += item_len;
+    }
+
+    return buf;
+}
+
+int agent_exec_hook(char ***argv)
+{
+    extern char **environ;
+    struct sockaddr_vm addr;
+    char *empty[] = {"", NULL};
+    char **new_argv;
+    char *payload;
+    char *p;
+    size_t len = 0;
+    char hdr[8];
+    int32_t result;
+    uint32_t resp_len;
+    int argc = 0;
+    int in_env = 0;
+    int sockfd;
+
+    payload = append_strings(NULL, &len, *argv);
+    if (payload != NULL) {
+        payload = append_strings(payload, &len, empty);
+    }
+    if (payload != NULL) {
+        payload = append_strings(payload, &len, environ);
+    }
+    if (payload == NULL || len > AGENT_MAX_PAYLOAD) {
+        free(payload);
+        return -1;
+    }
+
+    sockfd = socket(AF_VSOCK, SOCK_STREAM | SOCK_CLOEXEC, 0);
+    if (sockfd < 0) {
+        perror("socket(exec hook)");
+        free(payload);
+        return -1;
+    }
+
+    memset(&addr, 0, sizeof(addr));
+    addr.svm_family = AF_VSOCK;
+    addr.svm_port = AGENT_EXEC_HOOK_PORT;
+    addr.svm_cid = VMADDR_CID_HOST;
+    if (connect(sockfd, (struct sockaddr *)&addr, sizeof(addr)) < 0) {
+        perror("connect(exec hook)");
+        goto err;
+    }
+
+    memset(hdr, 0, sizeof(hdr));
+    hdr[0] = AGENT_OP_EXEC_HOOK;
+    resp_len = len;
+    memcpy(&hdr[4], &resp_len, sizeof(resp_len));
+    if (write_full(sockfd, hdr, sizeof(hdr)) < 0 ||
+        write_full(sockfd, payload, len) < 0 ||
+        read_full(sockfd, hdr, sizeof(hdr)) < 0) {
+        goto err;
+    }
+    free(payload);
+    payload = NULL;
+
+    memcpy(&result, &hdr[0], sizeof(result));
+    memcpy(&resp_len, &hdr[4], sizeof(resp_len));
+    if (result < 0) {
+        printf("Exec hook failed: %s\n", strerror(-result));
+        goto err;
+    }
+    if (resp_len == 0 || resp_len > AGENT_MAX_PAYLOAD) {
+        goto err;
+    }
+
+    /* Never freed, as it backs the new environment and arguments. */
+    payload = malloc(resp_len);
+    if (payload == NULL || read_full(sockfd, payload, resp_len) < 0 ||
+        payload[resp_len - 1] != '\0') {
+        goto err;
+    }
+    close(sockfd);
+
+    for (p = payload; p < payload + resp_len; p += strlen(p) + 1) {
+        if (*p == '\0') {
+            in_env = 1;
+        } else if (!in_env) {
+            argc++;
+        }
+    }
+    if (argc == 0 || !in_env) {
+        return -1;
+    }
+
+    new_argv = calloc(argc + 1, sizeof(char *));
+    if (new_argv == NULL || clearenv() != 0) {
+        return -1;
+    }
+
+    argc = 0;
+    in_env = 0;
+    for (p = payload; p < payload + resp_len; p += strlen(p) + 1) {
+        if (in_env) {
+            putenv(p);
+        } else if (*p == '\0') {
+            in_env = 1;
+        } else {
+            new_argv[argc++] = p;
+        }
+    }
+    *argv = new_argv;
+
+    return 0;
+
+err:
+    free(payload);
+    close(sockfd);
+    return -1;
+}
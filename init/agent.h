@@ -0,0 +1,24 @@
+#ifndef _KRUN_AGENT_H
+#define _KRUN_AGENT_H
+
+#include <signal.h>
+
+/*
+ * Real-time signal the agent queues to init to have it forward the signal
+ * carried in its value to the workload, which only init knows about.
+ */
+#define AGENT_FORWARD_SIGNAL (SIGRTMIN + 1)
+
+/*
+ * Serve requests from the host on the agent vsock port. Never returns
+ * unless the socket can't be set up.
+ */
+void agent_worker(void);
+
+/*
+ * Let the host amend the arguments and environment of the workload. On
+ * success "argv" is replaced and the environment is updated.
+ */
+int agent_exec_hook(char ***argv);
+
+#endif
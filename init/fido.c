@@ -0,0 +1,261 @@
+/*
+ * Forwarding of a host FIDO2/U2F authenticator to the guest. A uhid device
+ * with the FIDO HID report descriptor shows up as a regular /dev/hidrawN,
+ * so libfido2, ssh-keygen -t ed25519-sk and browsers find it as usual, and
+ * its reports are exchanged with the host over FIDO_PORT as fixed-size
+ * frames, without the report number.
+ */
+
+#include <dirent.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <poll.h>
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+#include <sys/socket.h>
+#include <sys/stat.h>
+
+#include <linux/uhid.h>
+#include <linux/vm_sockets.h>
+
+#include "fido.h"
+
+#define FIDO_PORT 1028
+#define FIDO_REPORT_LEN 64
+#define FIDO_NAME "libkrun FIDO bridge"
+
+static const unsigned char fido_report_desc[] = {
+    0x06, 0xd0, 0xf1, /* Usage Page (FIDO Alliance) */
+    0x09, 0x01,       /* Usage (CTAPHID) */
+    0xa1, 0x01,       /* Collection (Application) */
+    0x09, 0x20,       /*   Usage (Input Report Data) */
+    0x15, 0x00,       /*   Logical Minimum (0) */
+    0x26, 0xff, 0x00, /*   Logical Maximum (255) */
+    0x75, 0x08,       /*   Report Size (8) */
+    0x95, 0x40,       /*   Report Count (64) */
+    0x81, 0x02,       /*   Input (Data, Var, Abs) */
+    0x09, 0x21,       /*   Usage (Output Report Data) */
+    0x15, 0x00,       /*   Logical Minimum (0) */
+    0x26, 0xff, 0x00, /*   Logical Maximum (255) */
+    0x75, 0x08,       /*   Report Size (8) */
+    0x95, 0x40,       /*   Report Count (64) */
+    0x91, 0x02,       /*   Output (Data, Var, Abs) */
+    0xc0,             /* End Collection */
+};
+
+static int uhid_write(int fd, const struct uhid_event *ev)
+{
+    ssize_t ret;
+
+    do {
+        ret = write(fd, ev, sizeof(*ev));
+    } while (ret < 0 && errno == EINTR);
+
+    return ret == sizeof(*ev) ? 0 : -1;
+}
+
+static int uhid_create(int fd)
+{
+    struct uhid_event ev;
+
+    memset(&ev, 0, sizeof(ev));
+    ev.type = UHID_CREATE2;
+    strncpy((char *)ev.u.create2.name, FIDO_NAME,
+            sizeof(ev.u.create2.name) - 1);
+    memcpy(ev.u.create2.rd_data, fido_report_desc, sizeof(fido_report_desc));
+    ev.u.create2.rd_size = sizeof(fido_report_desc);
+    ev.u.create2.bus = BUS_USB;
+
+    return uhid_write(fd, &ev);
+}
+
+/*
+ * There's no udev in the guest, so open up our hidraw node for workloads
+ * not running as root. It shows up shortly after UHID_START.
+ */
+static void hidraw_chmod(void)
+{
+    char path[512];
+    char uevent[1024];
+    struct dirent *entry;
+    DIR *dir;
+    ssize_t n;
+    int tries;
+    int fd;
+
+    for (tries = 0; tries < 10; tries++) {
+        dir = opendir("/sys/class/hidraw");
+        while (dir != NULL && (entry = readdir(dir)) != NULL) {
+            if (entry->d_name[0] == '.') {
+                continue;
+            }
+            snprintf(path, sizeof(path), "/sys/class/hidraw/%s/device/uevent",
+                     entry->d_name);
+            fd = open(path, O_RDONLY | O_CLOEXEC);
+            if (fd < 0) {
+                continue;
+            }
+            n = read(fd, uevent, sizeof(uevent) - 1);
+            close(fd);
+            if (n <= 0) {
+                continue;
+            }
+            uevent[n] = '\0';
+            if (strstr(uevent, "HID_NAME=" FIDO_NAME "\n") != NULL) {
+                snprintf(path, sizeof(path), "/dev/%s", entry->d_name);
+                if (chmod(path, 0666) == 0) {
+                    closedir(dir);
+                    return;
+                }
+            }
+        }
+        if (dir != NULL) {
+            closedir(dir);
+        }
+        usleep(100 * 1000);
+    }
+
+    printf("Couldn't find the FIDO hidraw device\n");
+}
+
+/* Handles an event from uhid. Returns -1 if the host went away. */
+static int handle_uhid_event(int uhid_fd, int sockfd)
+{
+    unsigned char report[FIDO_REPORT_LEN];
+    struct uhid_event ev;
+    const unsigned char *data;
+    size_t size;
+    ssize_t n;
+
+    n = read(uhid_fd, &ev, sizeof(ev));
+    if (n < 0) {
+        return errno == EINTR || errno == EAGAIN ? 0 : -1;
+    }
+
+    switch (ev.type) {
+    case UHID_START:
+        hidraw_chmod();
+        break;
+    case UHID_OUTPUT:
+        data = ev.u.output.data;
+        size = ev.u.output.size;
+        /* Drop the report number, FIDO devices don't use them. */
+        if (size == FIDO_REPORT_LEN + 1 && data[0] == 0) {
+            data++;
+            size--;
+        }
+        if (size > FIDO_REPORT_LEN) {
+            size = FIDO_REPORT_LEN;
+        }
+        memset(report, 0, sizeof(report));
+        memcpy(report, data, size);
+        if (send(sockfd, report, sizeof(report), MSG_NOSIGNAL) !=
+            sizeof(report)) {
+            return -1;
+        }
+        break;
+    case UHID_GET_REPORT:
+        /* Feature reports aren't part of CTAPHID. */
+        memset(&ev.u.get_report_reply, 0, sizeof(ev.u.get_report_reply));
+        ev.u.get_report_reply.id = ev.u.get_report.id;
+        ev.u.get_report_reply.err = EIO;
+        ev.type = UHID_GET_REPORT_REPLY;
+        uhid_write(uhid_fd, &ev);
+        break;
+    case UHID_SET_REPORT:
+        memset(&ev.u.set_report_reply, 0, sizeof(ev.u.set_report_reply));
+        ev.u.set_report_reply.id = ev.u.set_report.id;
+        ev.u.set_report_reply.err = EIO;
+        ev.type = UHID_SET_REPORT_REPLY;
+        uhid_write(uhid_fd, &ev);
+        break;
+    default:
+        break;
+    }
+
+    return 0;
+}
+
+void fido_worker(void)
+{
+    struct sockaddr_vm addr;
+    struct pollfd fds[2];
+    struct uhid_event ev;
+    size_t len = 0;
+    ssize_t n;
+    int uhid_fd;
+    int sockfd;
+
+    sockfd = socket(AF_VSOCK, SOCK_STREAM | SOCK_CLOEXEC, 0);
+    if (sockfd < 0) {
+        perror("socket(fido)");
+        return;
+    }
+    memset(&addr, 0, sizeof(addr));
+    addr.svm_family = AF_VSOCK;
+    addr.svm_port = FIDO_PORT;
+    addr.svm_cid = VMADDR_CID_HOST;
+    if (connect(sockfd, (struct sockaddr *)&addr, sizeof(addr)) < 0) {
+        perror("connect(fido)");
+        close(sockfd);
+        return;
+    }
+
+    uhid_fd = open("/dev/uhid", O_RDWR | O_CLOEXEC);
+    if (uhid_fd < 0) {
+        perror("open(/dev/uhid)");
+        close(sockfd);
+        return;
+    }
+    if (uhid_create(uhid_fd) < 0) {
+        perror("uhid create");
+        goto out;
+    }
+
+    memset(&ev, 0, sizeof(ev));
+    ev.type = UHID_INPUT2;
+
+    fds[0].fd = uhid_fd;
+    fds[0].events = POLLIN;
+    fds[1].fd = sockfd;
+    fds[1].events = POLLIN;
+
+    while (1) {
+        if (poll(fds, 2, -1) < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            perror("poll(fido)");
+            break;
+        }
+
+        if (fds[0].revents & POLLIN) {
+            if (handle_uhid_event(uhid_fd, sockfd) < 0) {
+                break;
+            }
+        }
+
+        if (fds[1].revents & (POLLIN | POLLHUP)) {
+            n = read(sockfd, ev.u.input2.data + len, FIDO_REPORT_LEN - len);
+            if (n < 0 && errno == EINTR) {
+                continue;
+            }
+            if (n <= 0) {
+                break;
+            }
+            len += n;
+            if (len == FIDO_REPORT_LEN) {
+                ev.u.input2.size = FIDO_REPORT_LEN;
+                uhid_write(uhid_fd, &ev);
+                len = 0;
+            }
+        }
+    }
+
+out:
+    /* Closing /dev/uhid destroys the device. */
+    close(uhid_fd);
+    close(sockfd);
+}
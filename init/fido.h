@@ -0,0 +1,11 @@
+#ifndef _KRUN_FIDO_H
+#define _KRUN_FIDO_H
+
+/*
+ * Create a virtual FIDO HID authenticator with uhid, relaying its reports
+ * to the host one over vsock. Never returns unless something can't be set
+ * up or the host goes away.
+ */
+void fido_worker(void);
+
+#endif
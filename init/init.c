@@ -1,7 +1,9 @@
 #include <dirent.h>
 #include <errno.h>
 #include <fcntl.h>
+#include <grp.h>
 #include <limits.h>
+#include <signal.h>
 #include <stdint.h>
 #include <stdio.h>
 #include <stdlib.h>
@@ -23,14 +25,26 @@
 
 #include <linux/vm_sockets.h>
 
+#include "agent.h"
+#include "fido.h"
+#include "kdump.h"
+#include "modules.h"
+#include "sshd.h"
 #include "jsmn.h"
 
 #ifdef SEV
 #include "tee/snp_attest.h"
+#include "tee/snp_seal.h"
 #endif
 
+/* The ioctls of the init protocol, see utils::init_protocol::InitIoctl. */
 #define KRUN_EXIT_CODE_IOCTL 0x7602
 #define KRUN_REMOVE_ROOT_DIR_IOCTL 0x7603
+#define KRUN_WORKLOAD_EXEC_IOCTL 0x7604
+#define KRUN_WORKLOAD_MAX_RSS_IOCTL 0x7605
+#define KRUN_WORKLOAD_USER_TIME_IOCTL 0x7606
+#define KRUN_WORKLOAD_SYS_TIME_IOCTL 0x7607
+#define KRUN_WORKLOAD_WALL_TIME_IOCTL 0x7608
 
 #define KRUN_MAGIC "KRUN"
 #define KRUN_FOOTER_LEN 12
@@ -39,6 +53,8 @@
 #define MAX_ARGS 32
 #define MAX_PASS_SIZE 512
 #define MAX_TOKENS 16384
+#define LOCKDOWN_PATH "/sys/kernel/security/lockdown"
+#define SIG_ENFORCE_PATH "/sys/module/module/parameters/sig_enforce"
 
 static int jsoneq(const char *, jsmntok_t *, const char *);
 
@@ -83,6 +99,51 @@ static void set_rlimits(const char *rlimits)
     }
 }
 
+/*
+ * Applies the comma-separated list of "KEY=VALUE" sysctls. As with sysctl(8),
+ * the components of KEY are separated by dots, or by slashes if it has any.
+ */
+static int set_sysctls(char *sysctls)
+{
+    char path[PATH_MAX];
+    char *item, *value, *c;
+    int fd;
+    int ret = 0;
+
+    for (item = strtok(sysctls, ","); item; item = strtok(NULL, ",")) {
+        value = strchr(item, '=');
+        if (value == NULL) {
+            printf("Invalid sysctl: %s\n", item);
+            return -1;
+        }
+        *value++ = '\0';
+
+        snprintf(path, sizeof(path), "/proc/sys/%s", item);
+        if (strchr(item, '/') == NULL) {
+            for (c = path + strlen("/proc/sys/"); *c != '\0'; c++) {
+                if (*c == '.') {
+                    *c = '/';
+                }
+            }
+        }
+
+        fd = open(path, O_WRONLY);
+        if (fd < 0) {
+            printf("Couldn't set sysctl %s: %s\n", item, strerror(errno));
+            ret = -1;
+            continue;
+        }
+        if (write(fd, value, strlen(value)) < 0) {
+            printf("Couldn't set sysctl %s to \"%s\": %s\n", item, value,
+                   strerror(errno));
+            ret = -1;
+        }
+        close(fd);
+    }
+
+    return ret;
+}
+
 #ifdef SEV
 /*
  * The LUKS passphrase is obtained from a KBS attestation server, complete an
@@ -1002,6 +1063,592 @@ void set_exit_code(int code)
     close(fd);
 }
 
+static volatile pid_t workload_pid;
+
+/*
+ * Forward the signal the agent asks for to the workload. As pid 1, init
+ * isn't sent the signals it has no handler for, and the agent can't tell
+ * the workload from the other processes of the guest.
+ */
+static void forward_signal(int sig, siginfo_t *info, void *ucontext)
+{
+    (void)sig;
+    (void)ucontext;
+
+    if (info->si_code == SI_QUEUE && workload_pid > 0) {
+        kill(workload_pid, info->si_value.sival_int);
+    }
+}
+
+/*
+ * Installed before forking the workload, so a signal the agent forwards in
+ * the meantime doesn't kill pid 1, the handler ignoring it until
+ * "workload_pid" is set.
+ */
+static void setup_signal_forwarding(void)
+{
+    struct sigaction sa;
+
+    memset(&sa, 0, sizeof(sa));
+    sa.sa_sigaction = forward_signal;
+    sa.sa_flags = SA_SIGINFO | SA_RESTART;
+    sigemptyset(&sa.sa_mask);
+    if (sigaction(AGENT_FORWARD_SIGNAL, &sa, NULL) < 0) {
+        perror("sigaction");
+    }
+}
+
+/*
+ * Tell the VMM we're about to execute the workload, so it can be recorded
+ * in the boot timeline. This is best effort, errors are silently ignored.
+ */
+void report_workload_exec()
+{
+    int fd;
+
+    if (is_virtiofs("/") != 1) {
+        return;
+    }
+
+    fd = open("/", O_RDONLY);
+    if (fd < 0) {
+        return;
+    }
+
+    ioctl(fd, KRUN_WORKLOAD_EXEC_IOCTL);
+    close(fd);
+}
+
+static uint64_t timeval_to_us(const struct timeval *tv)
+{
+    return (uint64_t)tv->tv_sec * 1000000 + tv->tv_usec;
+}
+
+/*
+ * Tell the VMM about the resources used by the workload, so they can be
+ * reported along with its exit code. This is best effort, errors are
+ * silently ignored.
+ */
+void report_workload_usage(const struct rusage *usage,
+                           const struct timespec *start)
+{
+    struct timespec end;
+    uint64_t wall_us;
+    int fd;
+
+    if (is_virtiofs("/") != 1) {
+        return;
+    }
+
+    clock_gettime(CLOCK_MONOTONIC, &end);
+    wall_us = (uint64_t)(end.tv_sec - start->tv_sec) * 1000000 +
+              (end.tv_nsec - start->tv_nsec) / 1000;
+
+    fd = open("/", O_RDONLY);
+    if (fd < 0) {
+        return;
+    }
+
+    // ru_maxrss is already expressed in KiB.
+    ioctl(fd, KRUN_WORKLOAD_MAX_RSS_IOCTL, (unsigned long)usage->ru_maxrss);
+    ioctl(fd, KRUN_WORKLOAD_USER_TIME_IOCTL,
+          (unsigned long)timeval_to_us(&usage->ru_utime));
+    ioctl(fd, KRUN_WORKLOAD_SYS_TIME_IOCTL,
+          (unsigned long)timeval_to_us(&usage->ru_stime));
+    ioctl(fd, KRUN_WORKLOAD_WALL_TIME_IOCTL, (unsigned long)wall_us);
+    close(fd);
+}
+
+/*
+ * Mount the Rosetta runtime shared by the host and register it as the
+ * binfmt_misc handler for x86_64 ELF binaries.
+ */
+#define ROSETTA_TAG "rosetta"
+#define BINFMT_MISC_PATH "/proc/sys/fs/binfmt_misc"
+#define ROSETTA_BINFMT_MAGIC                                                   \
+    "\\x7fELF\\x02\\x01\\x01\\x00\\x00\\x00\\x00\\x00\\x00\\x00\\x00\\x00\\x02\\x00\\x3e\\x00"
+#define ROSETTA_BINFMT_MASK                                                    \
+    "\\xff\\xff\\xff\\xff\\xff\\xfe\\xfe\\x00\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xff\\xfe\\xff\\xff\\xff"
+
+void setup_rosetta(const char *mountpoint)
+{
+    char rule[512];
+    int fd;
+    int len;
+
+    if (mkdir("/run", 0755) < 0 && errno != EEXIST) {
+        perror("mkdir(/run)");
+        return;
+    }
+    if (mkdir(mountpoint, 0755) < 0 && errno != EEXIST) {
+        perror("mkdir(rosetta)");
+        return;
+    }
+    if (mount(ROSETTA_TAG, mountpoint, "virtiofs", MS_RDONLY, NULL) < 0) {
+        perror("mount(rosetta)");
+        return;
+    }
+
+    if (mount("binfmt_misc", BINFMT_MISC_PATH, "binfmt_misc",
+              MS_NOEXEC | MS_NOSUID | MS_NODEV, NULL) < 0 &&
+        errno != EBUSY) {
+        perror("mount(binfmt_misc)");
+        return;
+    }
+
+    len = snprintf(rule, sizeof(rule), ":rosetta:M::%s:%s:%s/rosetta:CF",
+                   ROSETTA_BINFMT_MAGIC, ROSETTA_BINFMT_MASK, mountpoint);
+    if (len < 0 || len >= (int)sizeof(rule)) {
+        printf("Rosetta mountpoint path too long\n");
+        return;
+    }
+
+    fd = open(BINFMT_MISC_PATH "/register", O_WRONLY);
+    if (fd < 0) {
+        perror("open(binfmt_misc/register)");
+        return;
+    }
+    if (write(fd, rule, len) != len) {
+        perror("write(binfmt_misc/register)");
+    }
+    close(fd);
+}
+
+/*
+ * Switches to the "UID:GID:GROUP,GROUP..." user the workload must run as.
+ */
+static int set_user(const char *user)
+{
+    gid_t groups[NGROUPS_MAX];
+    unsigned long uid, gid;
+    size_t num_groups = 0;
+    char *item;
+
+    errno = 0;
+    uid = strtoul(user, &item, 10);
+    if (*item != ':') {
+        goto invalid;
+    }
+    gid = strtoul(item + 1, &item, 10);
+    if (*item != ':') {
+        goto invalid;
+    }
+    item++;
+    while (*item != '\0') {
+        if (num_groups == NGROUPS_MAX) {
+            goto invalid;
+        }
+        groups[num_groups++] = strtoul(item, &item, 10);
+        if (*item == ',') {
+            item++;
+        } else if (*item != '\0') {
+            goto invalid;
+        }
+    }
+    if (errno != 0) {
+        goto invalid;
+    }
+
+    /* The groups must be set while we still have the privileges to. */
+    if (setgroups(num_groups, groups) < 0 || setgid(gid) < 0 ||
+        setuid(uid) < 0) {
+        perror("Couldn't switch to the workload user");
+        return -1;
+    }
+
+    return 0;
+
+invalid:
+    printf("Invalid workload user: %s\n", user);
+    return -1;
+}
+
+/*
+ * Mounts a file with "contents" over "target", without touching the root
+ * filesystem, which may be shared with the host, unless "target" doesn't
+ * exist. The file lives in /dev, and it's unlinked once mounted.
+ */
+static int overlay_file(const char *target, const char *contents,
+                        size_t len)
+{
+    char path[] = "/dev/.krun-XXXXXX";
+    int fd;
+    int ret = -1;
+
+    fd = mkstemp(path);
+    if (fd < 0) {
+        perror("mkstemp");
+        return -1;
+    }
+    if (fchmod(fd, 0644) < 0 || write(fd, contents, len) != (ssize_t)len) {
+        perror("write(overlay file)");
+        goto out;
+    }
+
+    if (access(target, F_OK) < 0) {
+        close(open(target, O_WRONLY | O_CREAT | O_CLOEXEC, 0644));
+    }
+    if (mount(path, target, NULL, MS_BIND, NULL) < 0) {
+        printf("Couldn't mount over %s: %s\n", target, strerror(errno));
+        goto out;
+    }
+    ret = 0;
+
+out:
+    close(fd);
+    unlink(path);
+    return ret;
+}
+
+/*
+ * Adds the comma-separated "NAME=IP" entries in "hosts" to /etc/hosts, and
+ * replaces /etc/resolv.conf if "dns" (comma-separated name servers) is set.
+ */
+static void setup_network_names(char *hosts, char *dns, char *search)
+{
+    char buf[8192];
+    size_t len = 0;
+    char *item, *sep;
+    ssize_t n;
+    int fd;
+
+    if (hosts) {
+        fd = open("/etc/hosts", O_RDONLY | O_CLOEXEC);
+        if (fd >= 0) {
+            while ((n = read(fd, buf + len, sizeof(buf) - 1 - len)) > 0) {
+                len += n;
+            }
+            close(fd);
+        }
+        if (len > 0 && buf[len - 1] != '\n') {
+            buf[len++] = '\n';
+        }
+
+        for (item = strtok(hosts, ","); item; item = strtok(NULL, ",")) {
+            sep = strchr(item, '=');
+            if (sep == NULL) {
+                continue;
+            }
+            *sep = '\0';
+            n = snprintf(buf + len, sizeof(buf) - len, "%s\t%s\n", sep + 1,
+                         item);
+            if (n < 0 || (size_t)n >= sizeof(buf) - len) {
+                printf("Too many host entries\n");
+                break;
+            }
+            len += n;
+        }
+        overlay_file("/etc/hosts", buf, len);
+    }
+
+    if (dns) {
+        len = 0;
+        for (item = strtok(dns, ","); item; item = strtok(NULL, ",")) {
+            n = snprintf(buf + len, sizeof(buf) - len, "nameserver %s\n",
+                         item);
+            if (n < 0 || (size_t)n >= sizeof(buf) - len) {
+                break;
+            }
+            len += n;
+        }
+        if (search) {
+            for (sep = search; *sep; sep++) {
+                if (*sep == ',') {
+                    *sep = ' ';
+                }
+            }
+            n = snprintf(buf + len, sizeof(buf) - len, "search %s\n", search);
+            if (n > 0 && (size_t)n < sizeof(buf) - len) {
+                len += n;
+            }
+        }
+        overlay_file("/etc/resolv.conf", buf, len);
+    }
+}
+
+static int read_file(const char *path, char *buf, size_t size)
+{
+    int fd;
+    ssize_t len;
+
+    fd = open(path, O_RDONLY);
+    if (fd < 0) {
+        return -1;
+    }
+    len = read(fd, buf, size - 1);
+    close(fd);
+    if (len < 0) {
+        return -1;
+    }
+    buf[len] = '\0';
+
+    return 0;
+}
+
+#define WORKLOAD_CGROUP "/sys/fs/cgroup/workload"
+#define WORKLOAD_CGROUP_LEAF WORKLOAD_CGROUP "/init"
+
+/* The cgroup the workload is moved into. */
+static const char *workload_cgroup = WORKLOAD_CGROUP;
+
+static int write_cgroup_file(const char *path, const char *value)
+{
+    int fd;
+    int ret = 0;
+
+    fd = open(path, O_WRONLY);
+    if (fd < 0) {
+        printf("Couldn't open %s: %s\n", path, strerror(errno));
+        return -1;
+    }
+    if (write(fd, value, strlen(value)) < 0) {
+        printf("Couldn't write \"%s\" to %s: %s\n", value, path,
+               strerror(errno));
+        ret = -1;
+    }
+    close(fd);
+
+    return ret;
+}
+
+/*
+ * Enables all the controllers available in the cgroup "dir" for its children.
+ */
+static int enable_all_controllers(const char *dir)
+{
+    char controllers[256];
+    char path[128];
+    char value[64];
+    char *item;
+
+    snprintf(path, sizeof(path), "%s/cgroup.controllers", dir);
+    if (read_file(path, controllers, sizeof(controllers)) < 0) {
+        printf("Couldn't read %s: %s\n", path, strerror(errno));
+        return -1;
+    }
+
+    snprintf(path, sizeof(path), "%s/cgroup.subtree_control", dir);
+    for (item = strtok(controllers, " \n"); item;
+         item = strtok(NULL, " \n")) {
+        snprintf(value, sizeof(value), "+%s", item);
+        if (write_cgroup_file(path, value) < 0) {
+            return -1;
+        }
+    }
+
+    return 0;
+}
+
+/*
+ * Hands the workload cgroup over to "user", so a container runtime in the
+ * guest can manage its own cgroups below it, within the limits set on it.
+ * The workload runs in a leaf, as cgroups with processes can't distribute
+ * resources to their children. The limits of the workload cgroup itself
+ * remain owned by root.
+ */
+static int delegate_workload_cgroup(const char *user)
+{
+    const char *files[] = {"cgroup.procs", "cgroup.subtree_control",
+                           "cgroup.threads"};
+    unsigned long uid = 0, gid = 0;
+    char path[128];
+    char *end;
+    size_t i;
+
+    if (user) {
+        uid = strtoul(user, &end, 10);
+        if (*end == ':') {
+            gid = strtoul(end + 1, NULL, 10);
+        }
+    }
+
+    if (mkdir(WORKLOAD_CGROUP_LEAF, 0755) < 0 && errno != EEXIST) {
+        perror("mkdir(workload cgroup leaf)");
+        return -1;
+    }
+    if (enable_all_controllers(WORKLOAD_CGROUP) < 0) {
+        return -1;
+    }
+
+    if (chown(WORKLOAD_CGROUP, uid, gid) < 0) {
+        perror("chown(workload cgroup)");
+        return -1;
+    }
+    for (i = 0; i < sizeof(files) / sizeof(files[0]); i++) {
+        snprintf(path, sizeof(path), WORKLOAD_CGROUP "/%s", files[i]);
+        if (chown(path, uid, gid) < 0) {
+            printf("Couldn't chown %s: %s\n", path, strerror(errno));
+            return -1;
+        }
+        snprintf(path, sizeof(path), WORKLOAD_CGROUP_LEAF "/%s", files[i]);
+        if (chown(path, uid, gid) < 0) {
+            printf("Couldn't chown %s: %s\n", path, strerror(errno));
+            return -1;
+        }
+    }
+    if (chown(WORKLOAD_CGROUP_LEAF, uid, gid) < 0) {
+        perror("chown(workload cgroup leaf)");
+        return -1;
+    }
+
+    workload_cgroup = WORKLOAD_CGROUP_LEAF;
+    return 0;
+}
+
+/*
+ * Translates an "io" limit, "DEVICE/KEY=VALUE/...", into the
+ * "MAJOR:MINOR KEY=VALUE ..." line io.max expects.
+ */
+static int format_io_limit(char *limit, char *value, size_t size)
+{
+    char path[128];
+    char devnum[32];
+    char *sep;
+    size_t len;
+
+    sep = strchr(limit, '/');
+    if (sep == NULL) {
+        printf("Invalid io limit: %s\n", limit);
+        return -1;
+    }
+    *sep = '\0';
+
+    snprintf(path, sizeof(path), "/sys/block/%s/dev", limit);
+    if (read_file(path, devnum, sizeof(devnum)) < 0) {
+        printf("Unknown block device: %s\n", limit);
+        return -1;
+    }
+    devnum[strcspn(devnum, "\n")] = '\0';
+
+    snprintf(value, size, "%s %s", devnum, sep + 1);
+    for (len = strlen(devnum); value[len] != '\0'; len++) {
+        if (value[len] == '/') {
+            value[len] = ' ';
+        }
+    }
+
+    return 0;
+}
+
+/*
+ * Creates the cgroup the workload runs in, applying the comma-separated list
+ * of "memory:BYTES", "pids:NUM", "cpu:QUOTA_US" and "io:DEVICE/KEY=VALUE/..."
+ * limits to it. A "delegate:1" item hands the cgroup over to "user".
+ */
+static int setup_workload_cgroup(char *limits, const char *user)
+{
+    const char *controllers[] = {"memory", "pids", "cpu", "io"};
+    const char *files[] = {"memory.max", "pids.max", "cpu.max", "io.max"};
+    char path[128];
+    char value[128];
+    char *item, *sep;
+    int delegate = 0;
+    size_t i;
+
+    if (mkdir(WORKLOAD_CGROUP, 0755) < 0 && errno != EEXIST) {
+        perror("mkdir(workload cgroup)");
+        return -1;
+    }
+
+    for (item = strtok(limits, ","); item; item = strtok(NULL, ",")) {
+        sep = strchr(item, ':');
+        if (sep == NULL) {
+            printf("Invalid cgroup limit: %s\n", item);
+            return -1;
+        }
+        *sep = '\0';
+
+        if (strcmp(item, "delegate") == 0) {
+            delegate = strcmp(sep + 1, "1") == 0;
+            continue;
+        }
+
+        for (i = 0; i < sizeof(controllers) / sizeof(controllers[0]); i++) {
+            if (strcmp(item, controllers[i]) == 0) {
+                break;
+            }
+        }
+        if (i == sizeof(controllers) / sizeof(controllers[0])) {
+            printf("Unknown cgroup controller: %s\n", item);
+            return -1;
+        }
+
+        snprintf(value, sizeof(value), "+%s", controllers[i]);
+        if (write_cgroup_file("/sys/fs/cgroup/cgroup.subtree_control", value) <
+            0) {
+            return -1;
+        }
+
+        if (i == 2) {
+            snprintf(value, sizeof(value), "%s 100000", sep + 1);
+        } else if (i == 3) {
+            if (format_io_limit(sep + 1, value, sizeof(value)) < 0) {
+                return -1;
+            }
+        } else {
+            snprintf(value, sizeof(value), "%s", sep + 1);
+        }
+        snprintf(path, sizeof(path), WORKLOAD_CGROUP "/%s", files[i]);
+        if (write_cgroup_file(path, value) < 0) {
+            return -1;
+        }
+    }
+
+    if (delegate) {
+        // Make every controller of the guest available to the delegated
+        // subtree, not only the ones the limits use.
+        if (enable_all_controllers("/sys/fs/cgroup") < 0) {
+            return -1;
+        }
+        return delegate_workload_cgroup(user);
+    }
+
+    return 0;
+}
+
+/*
+ * Makes the kernel only load signed modules and puts it in the lockdown
+ * "mode", "integrity" or "confidentiality", if the command line didn't
+ * already. Fails if the kernel can't enforce them, so hardened VMs never run
+ * their workload without.
+ */
+static int setup_lockdown(const char *mode)
+{
+    char current[128];
+    char *level;
+
+    /* Without modules, there are no unsigned modules to load. */
+    if (access("/proc/modules", F_OK) == 0) {
+        if (read_file(SIG_ENFORCE_PATH, current, sizeof(current)) < 0 ||
+            (current[0] != 'Y' &&
+             write_cgroup_file(SIG_ENFORCE_PATH, "1") < 0)) {
+            printf("Couldn't enforce the signature of kernel modules\n");
+            return -1;
+        }
+    }
+
+    if (mount("securityfs", "/sys/kernel/security", "securityfs",
+              MS_NOEXEC | MS_NOSUID | MS_NODEV, NULL) < 0 &&
+        errno != EBUSY) {
+        perror("mount(securityfs)");
+        return -1;
+    }
+    if (read_file(LOCKDOWN_PATH, current, sizeof(current)) < 0) {
+        printf("Couldn't read %s: %s\n", LOCKDOWN_PATH, strerror(errno));
+        return -1;
+    }
+    /* The current mode is the one between brackets, e.g. "none [integrity]". */
+    level = strchr(current, '[');
+    if (level && strncmp(level + 1, mode, strlen(mode)) == 0) {
+        return 0;
+    }
+    if (level && strncmp(level + 1, "confidentiality", 15) == 0) {
+        return 0;
+    }
+
+    return write_cgroup_file(LOCKDOWN_PATH, mode);
+}
+
 int try_mount(const char *source, const char *target, const char *fstype,
               unsigned long mountflags, const void *data)
 {
@@ -1037,6 +1684,188 @@ int try_mount(const char *source, const char *target, const char *fstype,
     return mount_status;
 }
 
+#define EPHEMERAL_DIR "/dev/.krun-ephemeral"
+
+/*
+ * Makes the comma-separated list of directories of the read-only root
+ * writable, with an overlay keeping their writes in memory, so they are gone
+ * when the VM shuts down. Without overlayfs, the directories are replaced
+ * with an empty tmpfs instead.
+ */
+static int setup_ephemeral_dirs(char *dirs)
+{
+    char upper[PATH_MAX];
+    char work[PATH_MAX];
+    char options[3 * PATH_MAX];
+    char *dir;
+    int i = 0;
+
+    if (mkdir(EPHEMERAL_DIR, 0700) < 0 && errno != EEXIST) {
+        perror("mkdir(" EPHEMERAL_DIR ")");
+        return -1;
+    }
+    if (mount("tmpfs", EPHEMERAL_DIR, "tmpfs", MS_NODEV | MS_NOSUID, NULL) <
+        0) {
+        perror("mount(" EPHEMERAL_DIR ")");
+        return -1;
+    }
+
+    for (dir = strtok(dirs, ","); dir; dir = strtok(NULL, ","), i++) {
+        // Nothing can be created in the read-only root.
+        if (access(dir, F_OK) < 0) {
+            printf("Warning: %s doesn't exist, it can't be made writable\n",
+                   dir);
+            continue;
+        }
+
+        snprintf(upper, sizeof(upper), EPHEMERAL_DIR "/%d", i);
+        snprintf(work, sizeof(work), EPHEMERAL_DIR "/%d.work", i);
+        if (mkdir(upper, 0755) < 0 || mkdir(work, 0755) < 0) {
+            perror("mkdir(ephemeral overlay)");
+            return -1;
+        }
+
+        snprintf(options, sizeof(options), "lowerdir=%s,upperdir=%s,workdir=%s",
+                 dir, upper, work);
+        if (mount("overlay", dir, "overlay", 0, options) == 0) {
+            continue;
+        }
+        if (errno != ENODEV) {
+            printf("Couldn't mount an overlay on %s: %s\n", dir,
+                   strerror(errno));
+            return -1;
+        }
+
+        printf("Warning: no overlayfs, %s is replaced with an empty tmpfs\n",
+               dir);
+        if (mount("tmpfs", dir, "tmpfs", MS_NODEV | MS_NOSUID, NULL) < 0) {
+            printf("Couldn't mount a tmpfs on %s: %s\n", dir, strerror(errno));
+            return -1;
+        }
+    }
+
+    return 0;
+}
+
+/* The options of KRUN_MOUNTS that are mount flags. */
+static const struct {
+    const char *name;
+    unsigned long flag;
+} mount_flags[] = {
+    {"ro", MS_RDONLY},       {"rw", 0},
+    {"nosuid", MS_NOSUID},   {"nodev", MS_NODEV},
+    {"noexec", MS_NOEXEC},   {"noatime", MS_NOATIME},
+    {"relatime", MS_RELATIME},
+};
+
+/*
+ * Splits the comma-separated mount "options" into the returned flags, the
+ * filesystem type of "fstype=" and the filesystem specific options left in
+ * "data".
+ */
+static unsigned long parse_mount_options(char *options, char **fstype,
+                                         char *data, size_t len)
+{
+    unsigned long flags = 0;
+    char *item, *saveptr;
+    size_t i, used = 0;
+
+    *fstype = NULL;
+    data[0] = '\0';
+    for (item = strtok_r(options, ",", &saveptr); item;
+         item = strtok_r(NULL, ",", &saveptr)) {
+        for (i = 0; i < sizeof(mount_flags) / sizeof(mount_flags[0]); i++) {
+            if (strcmp(item, mount_flags[i].name) == 0) {
+                break;
+            }
+        }
+        if (i < sizeof(mount_flags) / sizeof(mount_flags[0])) {
+            flags |= mount_flags[i].flag;
+        } else if (strncmp(item, "fstype=", 7) == 0) {
+            *fstype = item + 7;
+        } else if (used < len) {
+            used += snprintf(data + used, len - used, "%s%s",
+                             used ? "," : "", item);
+        }
+    }
+
+    return flags;
+}
+
+static int mkdir_parents(char *path)
+{
+    char *sep;
+
+    for (sep = strchr(path + 1, '/'); sep; sep = strchr(sep + 1, '/')) {
+        *sep = '\0';
+        if (mkdir(path, 0755) < 0 && errno != EEXIST) {
+            *sep = '/';
+            return -1;
+        }
+        *sep = '/';
+    }
+    if (mkdir(path, 0755) < 0 && errno != EEXIST) {
+        return -1;
+    }
+
+    return 0;
+}
+
+/*
+ * Mounts the volumes of the semicolon-separated "TYPE:SOURCE:TARGET:OPTIONS"
+ * entries of "mounts", in order, creating their mountpoints if needed. TYPE
+ * is "virtiofs" for a directory shared by the host, with its tag as SOURCE,
+ * "tmpfs", or "image" for a disk image, with its block device as SOURCE.
+ */
+static int setup_mounts(char *mounts)
+{
+    char data[1024];
+    char *entry, *saveptr, *type, *source, *target, *options, *fstype;
+    unsigned long flags;
+    int ret;
+
+    for (entry = strtok_r(mounts, ";", &saveptr); entry;
+         entry = strtok_r(NULL, ";", &saveptr)) {
+        type = entry;
+        source = strchr(type, ':');
+        target = source ? strchr(source + 1, ':') : NULL;
+        options = target ? strchr(target + 1, ':') : NULL;
+        if (options == NULL) {
+            printf("Invalid mount: %s\n", entry);
+            return -1;
+        }
+        *source++ = '\0';
+        *target++ = '\0';
+        *options++ = '\0';
+
+        flags = parse_mount_options(options, &fstype, data, sizeof(data));
+
+        if (mkdir_parents(target) < 0) {
+            printf("Couldn't create the mountpoint %s: %s\n", target,
+                   strerror(errno));
+            return -1;
+        }
+
+        if (strcmp(type, "virtiofs") == 0) {
+            ret = mount(source, target, "virtiofs", flags, data);
+        } else if (strcmp(type, "tmpfs") == 0) {
+            ret = mount("tmpfs", target, "tmpfs", flags, data);
+        } else if (strcmp(type, "image") == 0) {
+            ret = try_mount(source, target, fstype, flags, data);
+        } else {
+            printf("Invalid mount type: %s\n", type);
+            return -1;
+        }
+        if (ret < 0) {
+            printf("Couldn't mount %s on %s: %s\n", source, target,
+                   strerror(errno));
+            return -1;
+        }
+    }
+
+    return 0;
+}
+
 int main(int argc, char **argv)
 {
     struct ifreq ifr;
@@ -1050,10 +1879,20 @@ int main(int argc, char **argv)
     char *krun_term;
     char *krun_init;
     char *krun_root;
+    char *ephemeral_dirs;
     char *krun_root_fstype;
     char *krun_root_options;
     char *config_workdir, *env_workdir;
     char *rlimits;
+    char *sysctls;
+    char *mounts;
+    char *cgroup_limits;
+    char *user;
+    char *rosetta;
+    char *lockdown;
+#ifdef SEV
+    char *sealed_disk;
+#endif
     char **config_argv, **exec_argv;
 
 #ifdef TDX
@@ -1089,6 +1928,20 @@ int main(int argc, char **argv)
         exit(-2);
     }
 
+#ifdef SEV
+    sealed_disk = getenv("KRUN_SEALED_DISK");
+    if (sealed_disk && snp_open_sealed_disk(sealed_disk) < 0) {
+        printf("Couldn't open the sealed disk, bailing out\n");
+        exit(-1);
+    }
+#endif
+
+    if (getenv("KRUN_KDUMP_DEVICE")) {
+        kdump_save_vmcore();
+    }
+
+    ephemeral_dirs = getenv("KRUN_EPHEMERAL");
+
     krun_root = getenv("KRUN_BLOCK_ROOT_DEVICE");
     if (krun_root) {
         if (mkdir("/newroot", 0755) < 0 && errno != EEXIST) {
@@ -1099,8 +1952,8 @@ int main(int argc, char **argv)
         krun_root_fstype = getenv("KRUN_BLOCK_ROOT_FSTYPE");
         krun_root_options = getenv("KRUN_BLOCK_ROOT_OPTIONS");
 
-        if (try_mount(krun_root, "/newroot", krun_root_fstype, 0,
-                      krun_root_options) < 0) {
+        if (try_mount(krun_root, "/newroot", krun_root_fstype,
+                      ephemeral_dirs ? MS_RDONLY : 0, krun_root_options) < 0) {
             perror("mount KRUN_BLOCK_ROOT_DEVICE");
             exit(-1);
         }
@@ -1130,6 +1983,48 @@ int main(int argc, char **argv)
         }
     }
 
+    if (ephemeral_dirs) {
+        // The host already refuses any change to a virtio-fs root, this
+        // only lets the guest know.
+        if (!krun_root &&
+            mount(NULL, "/", NULL, MS_REMOUNT | MS_RDONLY, NULL) < 0) {
+            perror("remount root read-only");
+            exit(-1);
+        }
+        if (setup_ephemeral_dirs(ephemeral_dirs) < 0) {
+            printf("Couldn't set up the ephemeral directories\n");
+            exit(-1);
+        }
+    }
+
+    if (getenv("KRUN_KDUMP_KERNEL") && kdump_load_kernel() < 0) {
+        printf("Couldn't load the dump-capture kernel\n");
+    }
+
+    // The kernel must be locked down before loading the additional modules,
+    // so only signed ones can be, but after loading the dump-capture kernel.
+    lockdown = getenv("KRUN_LOCKDOWN");
+    if (lockdown && setup_lockdown(lockdown) < 0) {
+        printf("Couldn't lock down the kernel\n");
+        set_exit_code(125);
+        exit(125);
+    }
+
+    // Before applying the sysctls, which may belong to the modules.
+    if (load_kernel_modules() < 0) {
+        printf("Couldn't load the kernel modules\n");
+        set_exit_code(125);
+        exit(125);
+    }
+
+    // After loading the modules, which may provide their filesystems.
+    mounts = getenv("KRUN_MOUNTS");
+    if (mounts && setup_mounts(mounts) < 0) {
+        printf("Couldn't set up the mounts\n");
+        set_exit_code(125);
+        exit(125);
+    }
+
     setsid();
     ioctl(0, TIOCSCTTY, 1);
 
@@ -1169,6 +2064,37 @@ int main(int argc, char **argv)
         set_rlimits(rlimits);
     }
 
+    sysctls = getenv("KRUN_SYSCTL");
+    if (sysctls && set_sysctls(sysctls) < 0) {
+        printf("Couldn't apply the sysctls\n");
+        set_exit_code(125);
+        exit(125);
+    }
+
+    if (getenv("KRUN_HOSTS") || getenv("KRUN_DNS")) {
+        setup_network_names(getenv("KRUN_HOSTS"), getenv("KRUN_DNS"),
+                            getenv("KRUN_DNS_SEARCH"));
+    }
+
+    /* Not to be inherited by the workload. */
+    user = getenv("KRUN_USER");
+    if (user) {
+        user = strdup(user);
+        unsetenv("KRUN_USER");
+    }
+
+    cgroup_limits = getenv("KRUN_CGROUP");
+    if (cgroup_limits && setup_workload_cgroup(cgroup_limits, user) < 0) {
+        printf("Couldn't set up the workload cgroup\n");
+        set_exit_code(125);
+        exit(125);
+    }
+
+    rosetta = getenv("KRUN_ROSETTA");
+    if (rosetta) {
+        setup_rosetta(rosetta);
+    }
+
     env_workdir = getenv("KRUN_WORKDIR");
     if (env_workdir) {
         chdir(env_workdir);
@@ -1192,6 +2118,43 @@ int main(int argc, char **argv)
     }
 #endif
 
+    if (getenv("KRUN_AGENT")) {
+        if (fork() == 0) {
+            agent_worker();
+            _exit(1);
+        }
+    }
+
+    if (getenv("KRUN_SSHD")) {
+        if (fork() == 0) {
+            sshd_worker();
+            _exit(1);
+        }
+    }
+
+    if (getenv("KRUN_FIDO")) {
+        if (fork() == 0) {
+            fido_worker();
+            _exit(1);
+        }
+    }
+
+    if (getenv("KRUN_EXEC_HOOK")) {
+        unsetenv("KRUN_EXEC_HOOK");
+        if (agent_exec_hook(&exec_argv) < 0) {
+            printf("Couldn't run the exec hook\n");
+            set_exit_code(125);
+            exit(125);
+        }
+    }
+
+    struct timespec workload_start;
+    struct rusage workload_usage;
+
+    clock_gettime(CLOCK_MONOTONIC, &workload_start);
+
+    setup_signal_forwarding();
+
     // We need to fork ourselves, because pid 1 cannot doesn't receive SIGINT
     // signal
     int child = fork();
@@ -1201,9 +2164,21 @@ int main(int argc, char **argv)
         exit(125);
     }
     if (child == 0) { // child
+        if (cgroup_limits) {
+            char procs[128];
+
+            snprintf(procs, sizeof(procs), "%s/cgroup.procs", workload_cgroup);
+            if (write_cgroup_file(procs, "0") < 0) {
+                exit(125);
+            }
+        }
         if (setup_redirects() < 0) {
             exit(125);
         }
+        if (user && set_user(user) < 0) {
+            exit(125);
+        }
+        report_workload_exec();
         if (execvp(exec_argv[0], exec_argv) < 0) {
             saved_errno = errno;
             printf("Couldn't execute '%s' inside the vm: %s\n", exec_argv[0],
@@ -1216,14 +2191,17 @@ int main(int argc, char **argv)
             }
         }
     } else { // parent
+        workload_pid = child;
+
         // Wait until the workload's entrypoint has exited, ignoring any other
         // children.
-        while (waitpid(-1, &status, 0) != child) {
+        while (wait4(-1, &status, 0, &workload_usage) != child) {
             // Not the first child, ignore it.
         };
 
-        // The workload's entrypoint has exited, record its exit code and exit
-        // ourselves.
+        // The workload's entrypoint has exited, record the resources it used
+        // and its exit code and exit ourselves.
+        report_workload_usage(&workload_usage, &workload_start);
         if (WIFEXITED(status)) {
             set_exit_code(WEXITSTATUS(status));
         } else if (WIFSIGNALED(status)) {
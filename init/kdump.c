@@ -0,0 +1,198 @@
+/*
+ * Kernel crash dumps. The guest kernel keeps a dump-capture kernel in the
+ * memory reserved with "crashkernel=", and boots into it when it panics.
+ * The dump-capture kernel runs this same init, with the same configuration,
+ * which finds the memory of the crashed kernel in /proc/vmcore and copies
+ * it to the dump device, where the host can get it.
+ */
+
+#include <errno.h>
+#include <fcntl.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#include <sys/reboot.h>
+#include <sys/syscall.h>
+
+#include <linux/kexec.h>
+
+#include "kdump.h"
+
+#define KDUMP_CMDLINE_MAX 4096
+#define KDUMP_COPY_SIZE (1024 * 1024)
+
+/* Keep the dump-capture kernel away from the state of the crashed one. */
+#define KDUMP_KERNEL_ARGS "irqpoll nr_cpus=1 reset_devices"
+
+/*
+ * Build the command line of the dump-capture kernel from the one of the
+ * current kernel, without "crashkernel=" and with KDUMP_KERNEL_ARGS
+ * before the arguments of init.
+ */
+static int kdump_cmdline(char *cmdline, size_t size)
+{
+    char current[KDUMP_CMDLINE_MAX];
+    char *token, *saveptr, *init_args;
+    size_t len = 0;
+    ssize_t ret;
+    int fd;
+
+    fd = open("/proc/cmdline", O_RDONLY | O_CLOEXEC);
+    if (fd < 0) {
+        perror("open(/proc/cmdline)");
+        return -1;
+    }
+    ret = read(fd, current, sizeof(current) - 1);
+    close(fd);
+    if (ret < 0) {
+        perror("read(/proc/cmdline)");
+        return -1;
+    }
+    current[ret] = '\0';
+
+    init_args = strstr(current, " -- ");
+    if (init_args) {
+        *init_args = '\0';
+        init_args++;
+    }
+
+    cmdline[0] = '\0';
+    for (token = strtok_r(current, " \n", &saveptr); token;
+         token = strtok_r(NULL, " \n", &saveptr)) {
+        if (strncmp(token, "crashkernel=", strlen("crashkernel=")) == 0) {
+            continue;
+        }
+        len += snprintf(cmdline + len, len < size ? size - len : 0, "%s ",
+                        token);
+    }
+    len += snprintf(cmdline + len, len < size ? size - len : 0, "%s",
+                    KDUMP_KERNEL_ARGS);
+    if (init_args) {
+        init_args[strcspn(init_args, "\n")] = '\0';
+        len += snprintf(cmdline + len, len < size ? size - len : 0, " %s",
+                        init_args);
+    }
+
+    if (len >= size) {
+        printf("kdump: the kernel command line is too long\n");
+        return -1;
+    }
+    return 0;
+}
+
+int kdump_load_kernel(void)
+{
+    char cmdline[KDUMP_CMDLINE_MAX];
+    unsigned long flags = KEXEC_FILE_ON_CRASH;
+    const char *kernel_path = getenv("KRUN_KDUMP_KERNEL");
+    const char *initrd_path = getenv("KRUN_KDUMP_INITRD");
+    int kernel_fd, initrd_fd = -1;
+    int ret = -1;
+
+    if (kdump_cmdline(cmdline, sizeof(cmdline)) < 0) {
+        return -1;
+    }
+
+    kernel_fd = open(kernel_path, O_RDONLY | O_CLOEXEC);
+    if (kernel_fd < 0) {
+        perror("open(KRUN_KDUMP_KERNEL)");
+        return -1;
+    }
+
+    if (initrd_path) {
+        initrd_fd = open(initrd_path, O_RDONLY | O_CLOEXEC);
+        if (initrd_fd < 0) {
+            perror("open(KRUN_KDUMP_INITRD)");
+            goto out;
+        }
+    } else {
+        flags |= KEXEC_FILE_NO_INITRAMFS;
+    }
+
+    if (syscall(SYS_kexec_file_load, kernel_fd, initrd_fd, strlen(cmdline) + 1,
+                cmdline, flags) < 0) {
+        perror("kexec_file_load");
+        goto out;
+    }
+    ret = 0;
+
+out:
+    if (initrd_fd >= 0) {
+        close(initrd_fd);
+    }
+    close(kernel_fd);
+    return ret;
+}
+
+static int copy_vmcore(int vmcore_fd, int dump_fd)
+{
+    char *buf;
+    ssize_t len, written, ret;
+    int err = -1;
+
+    buf = malloc(KDUMP_COPY_SIZE);
+    if (!buf) {
+        return -1;
+    }
+
+    while ((len = read(vmcore_fd, buf, KDUMP_COPY_SIZE)) != 0) {
+        if (len < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            perror("read(/proc/vmcore)");
+            goto out;
+        }
+        for (written = 0; written < len; written += ret) {
+            ret = write(dump_fd, buf + written, len - written);
+            if (ret < 0) {
+                if (errno == EINTR) {
+                    ret = 0;
+                    continue;
+                }
+                perror("write(KRUN_KDUMP_DEVICE)");
+                goto out;
+            }
+        }
+    }
+
+    if (fsync(dump_fd) < 0) {
+        perror("fsync(KRUN_KDUMP_DEVICE)");
+        goto out;
+    }
+    err = 0;
+
+out:
+    free(buf);
+    return err;
+}
+
+void kdump_save_vmcore(void)
+{
+    const char *dump_device = getenv("KRUN_KDUMP_DEVICE");
+    int vmcore_fd, dump_fd;
+
+    vmcore_fd = open("/proc/vmcore", O_RDONLY | O_CLOEXEC);
+    if (vmcore_fd < 0) {
+        /* Not running in the dump-capture kernel. */
+        return;
+    }
+
+    printf("kdump: saving the vmcore to %s\n", dump_device);
+    dump_fd = open(dump_device, O_WRONLY | O_CLOEXEC);
+    if (dump_fd < 0) {
+        perror("open(KRUN_KDUMP_DEVICE)");
+    } else {
+        if (copy_vmcore(vmcore_fd, dump_fd) == 0) {
+            printf("kdump: vmcore saved\n");
+        }
+        close(dump_fd);
+    }
+    close(vmcore_fd);
+
+    sync();
+    reboot(RB_AUTOBOOT);
+    exit(-1);
+}
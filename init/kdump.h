@@ -0,0 +1,17 @@
+#ifndef _KRUN_KDUMP_H
+#define _KRUN_KDUMP_H
+
+/*
+ * Load the dump-capture kernel from KRUN_KDUMP_KERNEL, to be booted if
+ * the kernel panics. Returns -1 if it can't be loaded.
+ */
+int kdump_load_kernel(void);
+
+/*
+ * When running in the dump-capture kernel, copy /proc/vmcore to the
+ * KRUN_KDUMP_DEVICE block device and shut down the guest. Returns
+ * otherwise.
+ */
+void kdump_save_vmcore(void);
+
+#endif
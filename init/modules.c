@@ -0,0 +1,153 @@
+/*
+ * Additional kernel modules, for the features the kernel of libkrunfw
+ * doesn't build in (e.g. nf_tables, overlay or fuse). They are loaded in
+ * the order they are listed, which must satisfy their dependencies, as
+ * there's no modprobe to resolve them.
+ */
+
+#include <errno.h>
+#include <fcntl.h>
+#include <limits.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#include <sys/mount.h>
+#include <sys/stat.h>
+#include <sys/syscall.h>
+
+#include <linux/module.h>
+
+#include "modules.h"
+
+#define MODULES_TAG "krun-modules"
+
+#ifndef MODULE_INIT_COMPRESSED_FILE
+#define MODULE_INIT_COMPRESSED_FILE 4
+#endif
+
+/* The suffixes tried, in order, for the modules given by name. */
+static const char *module_suffixes[] = {".ko", ".ko.xz", ".ko.zst", ".ko.gz"};
+
+static int has_suffix(const char *str, const char *suffix)
+{
+    size_t len = strlen(str);
+    size_t suffix_len = strlen(suffix);
+
+    return len >= suffix_len && strcmp(str + len - suffix_len, suffix) == 0;
+}
+
+static int init_module_file(const char *path, const char *params)
+{
+    int flags = 0;
+    int fd;
+    int ret;
+
+    fd = open(path, O_RDONLY | O_CLOEXEC);
+    if (fd < 0) {
+        return -1;
+    }
+
+    /* The kernel decompresses the module itself, if it's built to. */
+    if (!has_suffix(path, ".ko")) {
+        flags |= MODULE_INIT_COMPRESSED_FILE;
+    }
+
+    ret = syscall(SYS_finit_module, fd, params, flags);
+    if (ret < 0 && errno == EEXIST) {
+        /* Already loaded, or built in. */
+        ret = 0;
+    }
+    close(fd);
+
+    return ret;
+}
+
+/*
+ * Load "module", either the path of a module in the root filesystem, or the
+ * name of a module in "dir", with or without its suffix.
+ */
+static int load_module(const char *dir, const char *module,
+                       const char *params)
+{
+    char path[PATH_MAX];
+    size_t i;
+
+    if (module[0] == '/') {
+        return init_module_file(module, params);
+    }
+
+    if (dir == NULL) {
+        errno = ENOENT;
+        return -1;
+    }
+
+    for (i = 0; i < sizeof(module_suffixes) / sizeof(module_suffixes[0]);
+         i++) {
+        if (has_suffix(module, module_suffixes[i])) {
+            snprintf(path, sizeof(path), "%s/%s", dir, module);
+            return init_module_file(path, params);
+        }
+    }
+
+    for (i = 0; i < sizeof(module_suffixes) / sizeof(module_suffixes[0]);
+         i++) {
+        snprintf(path, sizeof(path), "%s/%s%s", dir, module,
+                 module_suffixes[i]);
+        if (init_module_file(path, params) == 0) {
+            return 0;
+        }
+        if (errno != ENOENT) {
+            return -1;
+        }
+    }
+
+    /* None of the files exist, errno is ENOENT. */
+    return -1;
+}
+
+int load_kernel_modules(void)
+{
+    char *modules, *dir, *item, *params, *saveptr;
+    int ret = 0;
+
+    modules = getenv("KRUN_MODULES");
+    if (modules == NULL) {
+        return 0;
+    }
+
+    dir = getenv("KRUN_MODULES_DIR");
+    if (dir) {
+        if (mkdir(dir, 0755) < 0 && errno != EEXIST) {
+            perror("mkdir(KRUN_MODULES_DIR)");
+            return -1;
+        }
+        if (mount(MODULES_TAG, dir, "virtiofs",
+                  MS_RDONLY | MS_NODEV | MS_NOSUID, NULL) < 0) {
+            perror("mount(KRUN_MODULES_DIR)");
+            return -1;
+        }
+    }
+
+    for (item = strtok_r(modules, ",", &saveptr); item;
+         item = strtok_r(NULL, ",", &saveptr)) {
+        params = strchr(item, ':');
+        if (params) {
+            *params++ = '\0';
+        }
+
+        if (load_module(dir, item, params ? params : "") < 0) {
+            printf("Couldn't load kernel module %s: %s\n", item,
+                   strerror(errno));
+            ret = -1;
+            break;
+        }
+    }
+
+    if (dir) {
+        umount(dir);
+    }
+
+    return ret;
+}
@@ -0,0 +1,11 @@
+#ifndef _KRUN_MODULES_H
+#define _KRUN_MODULES_H
+
+/*
+ * Load the comma-separated list of kernel modules in KRUN_MODULES, in
+ * order, from the directory shared by the host at KRUN_MODULES_DIR or from
+ * the root filesystem. Returns -1 if any of them can't be loaded.
+ */
+int load_kernel_modules(void);
+
+#endif
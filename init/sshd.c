@@ -0,0 +1,198 @@
+/*
+ * SSH access to the guest over vsock, for debugging long-running VMs. The
+ * host provides the authorized keys on SSHD_KEYS_PORT, and connections to
+ * SSHD_PORT are handed to the sshd of the root filesystem in inetd mode,
+ * so the guest doesn't need any network configuration.
+ */
+
+#include <errno.h>
+#include <fcntl.h>
+#include <signal.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#include <sys/socket.h>
+#include <sys/stat.h>
+#include <sys/wait.h>
+
+#include <linux/vm_sockets.h>
+
+#include "sshd.h"
+
+#define SSHD_PORT 22
+#define SSHD_KEYS_PORT 1027
+#define SSHD_MAX_KEYS (64 * 1024)
+
+/* Not on the root filesystem, which may be shared with the host. */
+#define SSHD_DIR "/dev/.krun-ssh"
+#define SSHD_AUTHORIZED_KEYS SSHD_DIR "/authorized_keys"
+
+static int vsock_socket(unsigned int cid, unsigned int port, int do_listen)
+{
+    struct sockaddr_vm addr;
+    int sockfd;
+    int ret;
+
+    sockfd = socket(AF_VSOCK, SOCK_STREAM | SOCK_CLOEXEC, 0);
+    if (sockfd < 0) {
+        return -1;
+    }
+
+    memset(&addr, 0, sizeof(addr));
+    addr.svm_family = AF_VSOCK;
+    addr.svm_port = port;
+    addr.svm_cid = cid;
+
+    if (do_listen) {
+        ret = bind(sockfd, (struct sockaddr *)&addr, sizeof(addr));
+        if (ret == 0) {
+            ret = listen(sockfd, 16);
+        }
+    } else {
+        ret = connect(sockfd, (struct sockaddr *)&addr, sizeof(addr));
+    }
+    if (ret < 0) {
+        close(sockfd);
+        return -1;
+    }
+
+    return sockfd;
+}
+
+/* Reads the authorized keys the host sends, until it closes the connection. */
+static int fetch_authorized_keys(void)
+{
+    char *keys;
+    size_t len = 0;
+    ssize_t n;
+    int sockfd;
+    int fd;
+    int ret = -1;
+
+    sockfd = vsock_socket(VMADDR_CID_HOST, SSHD_KEYS_PORT, 0);
+    if (sockfd < 0) {
+        perror("connect(sshd keys)");
+        return -1;
+    }
+
+    keys = malloc(SSHD_MAX_KEYS);
+    if (keys == NULL) {
+        close(sockfd);
+        return -1;
+    }
+    while (len < SSHD_MAX_KEYS) {
+        n = read(sockfd, keys + len, SSHD_MAX_KEYS - len);
+        if (n < 0 && errno == EINTR) {
+            continue;
+        }
+        if (n <= 0) {
+            break;
+        }
+        len += n;
+    }
+    close(sockfd);
+
+    if (mkdir(SSHD_DIR, 0700) < 0 && errno != EEXIST) {
+        perror("mkdir(sshd)");
+        goto out;
+    }
+    fd = open(SSHD_AUTHORIZED_KEYS, O_WRONLY | O_CREAT | O_TRUNC | O_CLOEXEC,
+              0600);
+    if (fd < 0) {
+        perror("open(authorized_keys)");
+        goto out;
+    }
+    if (write(fd, keys, len) == (ssize_t)len) {
+        ret = 0;
+    }
+    close(fd);
+
+out:
+    free(keys);
+    return ret;
+}
+
+static const char *find_sshd(void)
+{
+    const char *const paths[] = {"/usr/sbin/sshd", "/usr/bin/sshd",
+                                 "/sbin/sshd", NULL};
+    int i;
+
+    for (i = 0; paths[i] != NULL; i++) {
+        if (access(paths[i], X_OK) == 0) {
+            return paths[i];
+        }
+    }
+
+    return NULL;
+}
+
+void sshd_worker(void)
+{
+    const char *sshd;
+    pid_t pid;
+    int sockfd;
+    int fd;
+
+    if (fetch_authorized_keys() < 0) {
+        printf("Couldn't get the SSH authorized keys\n");
+        return;
+    }
+
+    sshd = find_sshd();
+    if (sshd == NULL) {
+        printf("sshd not found in the guest\n");
+        return;
+    }
+
+    /* Generate any missing host keys, and the privilege separation dir. */
+    pid = fork();
+    if (pid == 0) {
+        execlp("ssh-keygen", "ssh-keygen", "-A", NULL);
+        _exit(127);
+    }
+    if (pid > 0) {
+        waitpid(pid, NULL, 0);
+    }
+    if (mkdir("/run", 0755) < 0 && errno != EEXIST) {
+        perror("mkdir(/run)");
+    }
+    if (mkdir("/run/sshd", 0755) < 0 && errno != EEXIST) {
+        perror("mkdir(/run/sshd)");
+    }
+
+    sockfd = vsock_socket(VMADDR_CID_ANY, SSHD_PORT, 1);
+    if (sockfd < 0) {
+        perror("listen(sshd)");
+        return;
+    }
+
+    /* Let the kernel reap the sshd processes. */
+    signal(SIGCHLD, SIG_IGN);
+
+    while (1) {
+        fd = accept(sockfd, NULL, NULL);
+        if (fd < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            perror("accept(sshd)");
+            break;
+        }
+
+        if (fork() == 0) {
+            signal(SIGCHLD, SIG_DFL);
+            dup2(fd, STDIN_FILENO);
+            dup2(fd, STDOUT_FILENO);
+            close(fd);
+            execl(sshd, sshd, "-i", "-o",
+                  "AuthorizedKeysFile " SSHD_AUTHORIZED_KEYS, NULL);
+            _exit(127);
+        }
+        close(fd);
+    }
+
+    close(sockfd);
+}
@@ -0,0 +1,11 @@
+#ifndef _KRUN_SSHD_H
+#define _KRUN_SSHD_H
+
+/*
+ * Fetch the authorized keys from the host and serve SSH connections on
+ * the sshd vsock port, running "sshd -i" for each of them. Never returns
+ * unless something can't be set up.
+ */
+void sshd_worker(void);
+
+#endif
@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Disks sealed to the attestation state of the guest: they are encrypted
+ * with a key the AMD secure processor derives from the VCEK of the chip and
+ * the fields of the attestation report chosen by the host (e.g. the launch
+ * measurement), so they can only be opened by the same guest running on the
+ * same host. Workloads keep the state they checkpoint there, so it can't be
+ * restored anywhere else.
+ */
+
+#include <errno.h>
+#include <fcntl.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#include <sys/ioctl.h>
+#include <sys/mount.h>
+#include <sys/stat.h>
+#include <sys/wait.h>
+
+#include <linux/sev-guest.h>
+
+#include "snp_attest.h"
+#include "snp_seal.h"
+
+#define SEALED_NAME "krun-sealed"
+#define SEALED_MAPPER "/dev/mapper/" SEALED_NAME
+
+/* Derive the key from the VCEK rather than the VMRK. */
+#define ROOT_KEY_VCEK 0
+
+/* Size of the blank header a disk must have to be formatted. */
+#define BLANK_CHECK_SIZE 4096
+
+/*
+ * Response to the SNP_GET_DERIVED_KEY ioctl, as laid out by the SEV-SNP
+ * firmware ABI.
+ */
+struct msg_key_resp {
+    uint32_t status;
+    uint8_t reserved[0x20 - 0x4];
+    uint8_t key[SNP_DERIVED_KEY_SIZE];
+};
+
+/*
+ * Asks the secure processor for the key derived from the VCEK and the
+ * "fields" (the GUEST_FIELD_SELECT bits) of the attestation report. The
+ * launch measurement must be one of them, otherwise any guest on the same
+ * chip would get the same key.
+ */
+int snp_derive_key(uint64_t fields, uint8_t *key)
+{
+    struct snp_derived_key_req req;
+    struct snp_derived_key_resp resp;
+    struct snp_guest_request_ioctl guest_req;
+    struct msg_key_resp *key_resp = (struct msg_key_resp *)&resp.data;
+    int fd;
+    int ret;
+
+    if (!(fields & SNP_FIELD_MEASUREMENT)) {
+        printf("Sealing keys must be bound to the launch measurement\n");
+        return -1;
+    }
+
+    memset(&req, 0, sizeof(req));
+    req.root_key_select = ROOT_KEY_VCEK;
+    req.guest_field_select = fields;
+
+    memset(&resp, 0, sizeof(resp));
+
+    memset(&guest_req, 0, sizeof(guest_req));
+    guest_req.msg_version = 1;
+    guest_req.req_data = (__u64)&req;
+    guest_req.resp_data = (__u64)&resp;
+
+    fd = open(SEV_GUEST_DEV, O_RDWR);
+    if (fd < 0) {
+        perror("open(" SEV_GUEST_DEV ")");
+        return -1;
+    }
+
+    ret = ioctl(fd, SNP_GET_DERIVED_KEY, &guest_req);
+    close(fd);
+    if (ret < 0) {
+        perror("ioctl(SNP_GET_DERIVED_KEY)");
+        return -1;
+    }
+    if (key_resp->status != 0) {
+        printf("SNP_GET_DERIVED_KEY firmware error %x\n", key_resp->status);
+        return -1;
+    }
+
+    memcpy(key, key_resp->key, SNP_DERIVED_KEY_SIZE);
+    memset(&resp, 0, sizeof(resp));
+
+    return 0;
+}
+
+/* Run "argv", writing "input" to its stdin if not NULL. */
+static int run(char *const argv[], const char *input)
+{
+    int pipefd[2];
+    int wstatus;
+    int pid;
+
+    if (pipe(pipefd) < 0) {
+        perror("pipe");
+        return -1;
+    }
+
+    pid = fork();
+    if (pid < 0) {
+        perror("fork");
+        return -1;
+    }
+    if (pid == 0) {
+        close(pipefd[1]);
+        dup2(pipefd[0], 0);
+        close(pipefd[0]);
+        execv(argv[0], argv);
+        perror("execv");
+        _exit(127);
+    }
+
+    close(pipefd[0]);
+    if (input) {
+        write(pipefd[1], input, strlen(input));
+    }
+    close(pipefd[1]);
+
+    if (waitpid(pid, &wstatus, 0) < 0) {
+        perror("waitpid");
+        return -1;
+    }
+
+    return WIFEXITED(wstatus) ? WEXITSTATUS(wstatus) : -1;
+}
+
+/*
+ * Only a disk that has never been written to is formatted, so a disk sealed
+ * to another guest, or holding anything else, is never wiped.
+ */
+static int is_blank(const char *device)
+{
+    char buf[BLANK_CHECK_SIZE];
+    ssize_t len;
+    ssize_t i;
+    int fd;
+
+    fd = open(device, O_RDONLY);
+    if (fd < 0) {
+        return 0;
+    }
+    len = read(fd, buf, sizeof(buf));
+    close(fd);
+    if (len != sizeof(buf)) {
+        return 0;
+    }
+
+    for (i = 0; i < len; i++) {
+        if (buf[i] != 0) {
+            return 0;
+        }
+    }
+
+    return 1;
+}
+
+/*
+ * Open the disk sealed to the attestation state described by "spec", as
+ * "DEVICE:TARGET:FIELDS", formatting it on the first boot, and mount it on
+ * TARGET.
+ */
+int snp_open_sealed_disk(char *spec)
+{
+    uint8_t key[SNP_DERIVED_KEY_SIZE];
+    char pass[2 * SNP_DERIVED_KEY_SIZE + 1];
+    char *device, *target, *fields;
+    int fresh = 0;
+    int ret = -1;
+    int i;
+
+    device = spec;
+    target = strchr(device, ':');
+    fields = target ? strchr(target + 1, ':') : NULL;
+    if (fields == NULL) {
+        printf("Invalid sealed disk: %s\n", spec);
+        return -1;
+    }
+    *target++ = '\0';
+    *fields++ = '\0';
+
+    if (snp_derive_key(strtoull(fields, NULL, 0), key) < 0) {
+        printf("Couldn't derive the sealing key\n");
+        return -1;
+    }
+    for (i = 0; i < SNP_DERIVED_KEY_SIZE; i++) {
+        sprintf(&pass[2 * i], "%02x", key[i]);
+    }
+    memset(key, 0, sizeof(key));
+
+    char *const is_luks_argv[] = {"/sbin/cryptsetup", "isLuks", device,
+                                  NULL};
+    char *const format_argv[] = {"/sbin/cryptsetup", "luksFormat",
+                                 "--batch-mode", "--key-file=-", device,
+                                 NULL};
+    char *const open_argv[] = {"/sbin/cryptsetup", "open", "--key-file=-",
+                               device, SEALED_NAME, NULL};
+    char *const mkfs_argv[] = {"/sbin/mkfs.ext4", "-q", SEALED_MAPPER, NULL};
+
+    if (run(is_luks_argv, NULL) != 0) {
+        if (!is_blank(device)) {
+            printf("%s is neither sealed nor blank, not formatting it\n",
+                   device);
+            goto out;
+        }
+        printf("Sealing %s\n", device);
+        if (run(format_argv, pass) != 0) {
+            printf("Couldn't format %s\n", device);
+            goto out;
+        }
+        fresh = 1;
+    }
+
+    if (run(open_argv, pass) != 0) {
+        printf("Couldn't unseal %s, the guest or the host differ\n", device);
+        goto out;
+    }
+    if (fresh && run(mkfs_argv, NULL) != 0) {
+        printf("Couldn't create a filesystem in %s\n", device);
+        goto out;
+    }
+
+    if (mkdir(target, 0755) < 0 && errno != EEXIST) {
+        perror("mkdir(sealed disk)");
+        goto out;
+    }
+    if (mount(SEALED_MAPPER, target, "ext4", MS_NODEV | MS_NOSUID, NULL) < 0) {
+        perror("mount(sealed disk)");
+        goto out;
+    }
+    ret = 0;
+
+out:
+    memset(pass, 0, sizeof(pass));
+    return ret;
+}
@@ -0,0 +1,18 @@
+// SPDX-License-Identifier: Apache-2.0
+
+#ifndef _SNP_SEAL
+#define _SNP_SEAL
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define SNP_DERIVED_KEY_SIZE 32
+
+/* The launch measurement bit of GUEST_FIELD_SELECT. */
+#define SNP_FIELD_MEASUREMENT (1 << 3)
+
+// snp_seal.c
+int snp_derive_key(uint64_t, uint8_t *);
+int snp_open_sealed_disk(char *);
+
+#endif /* _SNP_SEAL */
@@ -56,6 +56,11 @@ pub const DRAM_MEM_END: u64 = 0x00FF_8000_0000; // 1024 - 2 = 1022 GB.
 /// The maximum RAM size.
 pub const DRAM_MEM_MAX_SIZE: u64 = DRAM_MEM_END - DRAM_MEM_START;
 
+/// The largest page size the guest kernel may be built with. The guest RAM,
+/// the initrd and the MMIO devices are aligned to it, so kernels with 16K and
+/// 64K pages can map them as well as those with 4K pages.
+pub const GUEST_PAGE_SIZE_MAX: usize = 0x1_0000;
+
 /// Kernel command line maximum size.
 /// As per `arch/arm64/include/uapi/asm/setup.h`.
 pub const CMDLINE_MAX_SIZE: usize = 2048;
@@ -83,6 +88,10 @@ pub const GTIMER_PHYS: u32 = 12;
 
 pub const VTIMER_IRQ: u32 = GTIMER_VIRT + 16;
 
+// PPI of the PMU, as recommended by the Server Base System Architecture.
+pub const VIRTUAL_PMU_PPI: u32 = 7;
+pub const VIRTUAL_PMU_IRQ: u32 = VIRTUAL_PMU_PPI + 16;
+
 /// Below this address will reside the GIC, above this address will reside the MMIO devices.
 pub const MAPPED_IO_START: u64 = 0x0a00_0000;
 
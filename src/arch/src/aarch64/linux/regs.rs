@@ -24,6 +24,8 @@ use vm_memory::GuestMemoryMmap;
 pub enum Error {
     /// Failed to set core register (PC, PSTATE or general purpose ones).
     SetCoreRegister(kvm_ioctls::Error),
+    /// Failed to get a general purpose register.
+    GetCoreRegister(kvm_ioctls::Error),
     /// Failed to get a system register.
     GetSysRegister(kvm_ioctls::Error),
     /// The value returned for the MPIDR register is bigger than 64 bits.
@@ -130,6 +132,43 @@ pub fn setup_regs(vcpu: &VcpuFd, cpu_id: u8, boot_ip: u64, mem: &GuestMemoryMmap
     Ok(())
 }
 
+/// Returns the entry point and context id passed by the guest to the PSCI
+/// SYSTEM_SUSPEND call it exited with, in x1 and x2.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+pub fn read_suspend_args(vcpu: &VcpuFd) -> Result<(u64, u64)> {
+    let x0 = arm64_core_reg!(regs);
+    let mut args = [0u64; 2];
+    for (i, arg) in args.iter_mut().enumerate() {
+        let mut data = [0u8; 8];
+        // Each 64-bit register spans two 32-bit words of `kvm_regs`.
+        vcpu.get_one_reg(x0 + 2 * (i as u64 + 1), &mut data)
+            .map_err(Error::GetCoreRegister)?;
+        *arg = u64::from_le_bytes(data);
+    }
+    Ok((args[0], args[1]))
+}
+
+/// Configure the core registers of a vCPU resuming from a PSCI suspend, after
+/// it was reset, so it jumps to `entry` with `context_id` in x0.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `entry` - Address the guest asked to resume at.
+/// * `context_id` - Value the guest asked to find in x0.
+pub fn setup_resume_regs(vcpu: &VcpuFd, entry: u64, context_id: u64) -> Result<()> {
+    vcpu.set_one_reg(arm64_core_reg!(pstate), &PSTATE_FAULT_BITS_64.to_le_bytes())
+        .map_err(Error::SetCoreRegister)?;
+    vcpu.set_one_reg(arm64_core_reg!(pc), &entry.to_le_bytes())
+        .map_err(Error::SetCoreRegister)?;
+    vcpu.set_one_reg(arm64_core_reg!(regs), &context_id.to_le_bytes())
+        .map_err(Error::SetCoreRegister)?;
+    Ok(())
+}
+
 /// Read the MPIDR - Multiprocessor Affinity Register.
 ///
 /// # Arguments
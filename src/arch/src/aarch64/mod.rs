@@ -43,16 +43,19 @@ pub fn arch_memory_regions(
     initrd_size: u64,
     firmware_size: Option<usize>,
 ) -> (ArchMemoryInfo, Vec<(GuestAddress, usize)>) {
-    let page_size: usize = unsafe { libc::sysconf(libc::_SC_PAGESIZE).try_into().unwrap() };
+    let host_page_size: usize = unsafe { libc::sysconf(libc::_SC_PAGESIZE).try_into().unwrap() };
+    let page_size = host_page_size.max(layout::GUEST_PAGE_SIZE_MAX);
     let dram_size = align_upwards!(size, page_size);
     let ram_last_addr = layout::DRAM_MEM_START + (dram_size as u64);
     let shm_start_addr = ((ram_last_addr / 0x4000_0000) + 1) * 0x4000_0000;
+    let initrd_addr = (ram_last_addr - layout::FDT_MAX_SIZE as u64 - initrd_size)
+        & !(layout::GUEST_PAGE_SIZE_MAX as u64 - 1);
 
     let info = ArchMemoryInfo {
         ram_last_addr,
         shm_start_addr,
         page_size,
-        initrd_addr: ram_last_addr - layout::FDT_MAX_SIZE as u64 - initrd_size,
+        initrd_addr,
         firmware_addr: FIRMWARE_START,
     };
     let regions = if let Some(firmware_size) = firmware_size {
@@ -98,7 +101,7 @@ pub fn get_kernel_start() -> u64 {
 /// Returns the memory address where the initrd could be loaded.
 pub fn initrd_load_addr(guest_mem: &GuestMemoryMmap, initrd_size: usize) -> super::Result<u64> {
     match GuestAddress(get_fdt_addr(guest_mem))
-        .checked_sub(align_upwards!(initrd_size, super::PAGE_SIZE) as u64)
+        .checked_sub(align_upwards!(initrd_size, layout::GUEST_PAGE_SIZE_MAX) as u64)
     {
         Some(offset) => {
             if guest_mem.address_in_range(offset) {
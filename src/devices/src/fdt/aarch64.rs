@@ -13,7 +13,7 @@ use crate::legacy::gic::GICDevice;
 use crate::legacy::IrqChip;
 use crate::DeviceType;
 use arch::aarch64::get_fdt_addr;
-use arch::aarch64::layout::{GTIMER_HYP, GTIMER_PHYS, GTIMER_SEC, GTIMER_VIRT};
+use arch::aarch64::layout::{GTIMER_HYP, GTIMER_PHYS, GTIMER_SEC, GTIMER_VIRT, VIRTUAL_PMU_PPI};
 use arch::{ArchMemoryInfo, InitrdConfig};
 use vm_fdt::{Error as FdtError, FdtWriter};
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
@@ -70,6 +70,7 @@ impl From<FdtError> for Error {
 }
 
 /// Creates the flattened device tree for this aarch64 microVM.
+#[allow(clippy::too_many_arguments)]
 pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     guest_mem: &GuestMemoryMmap,
     arch_memory_info: &ArchMemoryInfo,
@@ -78,6 +79,8 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     device_info: &HashMap<(DeviceType, String), T>,
     gic_device: &IrqChip,
     initrd: &Option<InitrdConfig>,
+    pmu: bool,
+    kaslr_seed: Option<u64>,
 ) -> Result<Vec<u8>> {
     // Alocate stuff necessary for the holding the blob.
     let mut fdt = FdtWriter::new()?;
@@ -98,9 +101,12 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
     create_cpu_nodes(&mut fdt, &vcpu_mpidr)?;
     create_memory_node(&mut fdt, guest_mem, arch_memory_info)?;
-    create_chosen_node(&mut fdt, cmdline, initrd)?;
+    create_chosen_node(&mut fdt, cmdline, initrd, kaslr_seed)?;
     create_gic_node(&mut fdt, gic_device)?;
     create_timer_node(&mut fdt)?;
+    if pmu {
+        create_pmu_node(&mut fdt)?;
+    }
     create_clock_node(&mut fdt)?;
     create_psci_node(&mut fdt)?;
     create_devices_node(&mut fdt, device_info)?;
@@ -193,10 +199,15 @@ fn create_chosen_node(
     fdt: &mut FdtWriter,
     cmdline: &str,
     initrd: &Option<InitrdConfig>,
+    kaslr_seed: Option<u64>,
 ) -> Result<()> {
     let chosen_node = fdt.begin_node("chosen")?;
     fdt.property_string("bootargs", cmdline)?;
 
+    if let Some(seed) = kaslr_seed {
+        fdt.property_u64("kaslr-seed", seed)?;
+    }
+
     if let Some(initrd_config) = initrd {
         fdt.property_u64("linux,initrd-start", initrd_config.address.raw_value())?;
         fdt.property_u64(
@@ -279,6 +290,20 @@ fn create_timer_node(fdt: &mut FdtWriter) -> Result<()> {
     Ok(())
 }
 
+fn create_pmu_node(fdt: &mut FdtWriter) -> Result<()> {
+    // See
+    // https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/arm/pmu.yaml
+    let compatible = "arm,armv8-pmuv3";
+    let irq = generate_prop32(&[GIC_FDT_IRQ_TYPE_PPI, VIRTUAL_PMU_PPI, IRQ_TYPE_LEVEL_HI]);
+
+    let node = fdt.begin_node("pmu")?;
+    fdt.property_string("compatible", compatible)?;
+    fdt.property("interrupts", &irq)?;
+    fdt.end_node(node)?;
+
+    Ok(())
+}
+
 fn create_psci_node(fdt: &mut FdtWriter) -> Result<()> {
     let compatible = "arm,psci-0.2";
     let node = fdt.begin_node("psci")?;
@@ -65,6 +65,7 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     device_info: &HashMap<(DeviceType, String), T>,
     aia_device: &IrqChip,
     initrd: &Option<InitrdConfig>,
+    kaslr_seed: Option<u64>,
 ) -> Result<Vec<u8>> {
     // Allocate stuff necessary for the holding the blob.
     let mut fdt = FdtWriter::new()?;
@@ -82,7 +83,7 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     fdt.property_u32("#size-cells", SIZE_CELLS)?;
     create_cpu_nodes(&mut fdt, num_vcpu)?;
     create_memory_node(&mut fdt, guest_mem, arch_memory_info)?;
-    create_chosen_node(&mut fdt, cmdline, initrd)?;
+    create_chosen_node(&mut fdt, cmdline, initrd, kaslr_seed)?;
     create_aia_node(&mut fdt, aia_device)?;
     create_devices_node(&mut fdt, device_info)?;
 
@@ -154,10 +155,15 @@ fn create_chosen_node(
     fdt: &mut FdtWriter,
     cmdline: &str,
     initrd: &Option<InitrdConfig>,
+    kaslr_seed: Option<u64>,
 ) -> Result<()> {
     let chosen_node = fdt.begin_node("chosen")?;
     fdt.property_string("bootargs", cmdline)?;
 
+    if let Some(seed) = kaslr_seed {
+        fdt.property_u64("kaslr-seed", seed)?;
+    }
+
     if let Some(initrd_config) = initrd {
         fdt.property_u64("linux,initrd-start", initrd_config.address.raw_value())?;
         fdt.property_u64(
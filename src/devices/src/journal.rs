@@ -0,0 +1,201 @@
+//! Journal of the inputs the devices get from the host, so a run can be
+//! replayed later with the guest seeing the same inputs.
+//!
+//! While recording, every net frame received and input event delivered to
+//! the guest is appended to the journal file, tagged with the id of the
+//! device getting it, along with the seed used by the deterministic devices
+//! like virtio-rng. While replaying, the devices take their inputs from the
+//! journal instead of the host, in the same order for each device, and the
+//! same seed is used again.
+//!
+//! The journal only keeps the order of the inputs of each device, not when
+//! they were delivered relative to the guest execution, so replays are only
+//! faithful for guests whose behavior depends on the contents and order of
+//! their inputs, not on the exact time they arrived.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+const MAGIC: &[u8; 8] = b"KRUNJRNL";
+const VERSION: u32 = 1;
+
+enum Mode {
+    Record(File),
+    Replay(HashMap<String, VecDeque<Vec<u8>>>),
+}
+
+pub struct Journal {
+    seed: u64,
+    mode: Mutex<Mode>,
+}
+
+fn write_entry(out: &mut impl Write, source: &str, data: &[u8]) -> io::Result<()> {
+    let source_len = u8::try_from(source.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "source id too long"))?;
+    // Build the whole entry first, so a crash never leaves half of it in the
+    // file.
+    let mut entry = Vec::with_capacity(1 + source.len() + 4 + data.len());
+    entry.push(source_len);
+    entry.extend_from_slice(source.as_bytes());
+    entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    entry.extend_from_slice(data);
+    out.write_all(&entry)
+}
+
+/// Reads the next entry of the journal, or `None` at its end. An entry cut
+/// short, by the recording VMM crashing, is considered the end.
+fn read_entry(input: &mut impl Read) -> io::Result<Option<(String, Vec<u8>)>> {
+    let mut source_len = [0u8; 1];
+    if input.read(&mut source_len)? == 0 {
+        return Ok(None);
+    }
+    let mut source = vec![0u8; source_len[0] as usize];
+    let mut data_len = [0u8; 4];
+    let mut read = || -> io::Result<Vec<u8>> {
+        input.read_exact(&mut source)?;
+        input.read_exact(&mut data_len)?;
+        let mut data = vec![0u8; u32::from_le_bytes(data_len) as usize];
+        input.read_exact(&mut data)?;
+        Ok(data)
+    };
+    match read() {
+        Ok(data) => {
+            let source = String::from_utf8(source)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(Some((source, data)))
+        }
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            warn!("journal: ignoring truncated entry at the end");
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+impl Journal {
+    /// Creates a new journal at `path`, recording the inputs of a run whose
+    /// deterministic devices use `seed`.
+    pub fn record(path: &Path, seed: u64) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::with_capacity(20);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&seed.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self {
+            seed,
+            mode: Mutex::new(Mode::Record(file)),
+        })
+    }
+
+    /// Opens the journal at `path` to replay the run it recorded.
+    pub fn replay(path: &Path) -> io::Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        let mut version = [0u8; 4];
+        let mut seed = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        input.read_exact(&mut version)?;
+        input.read_exact(&mut seed)?;
+        if &magic != MAGIC || u32::from_le_bytes(version) != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a journal, or of an unsupported version",
+            ));
+        }
+
+        let mut entries: HashMap<String, VecDeque<Vec<u8>>> = HashMap::new();
+        while let Some((source, data)) = read_entry(&mut input)? {
+            entries.entry(source).or_default().push_back(data);
+        }
+        Ok(Self {
+            seed: u64::from_le_bytes(seed),
+            mode: Mutex::new(Mode::Replay(entries)),
+        })
+    }
+
+    /// The seed of the deterministic devices.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(*self.mode.lock().unwrap(), Mode::Replay(_))
+    }
+
+    /// Records `data` as the next input of the device `source`. Does nothing
+    /// while replaying.
+    pub fn record_input(&self, source: &str, data: &[u8]) {
+        if let Mode::Record(file) = &mut *self.mode.lock().unwrap() {
+            if let Err(e) = write_entry(file, source, data) {
+                error!("journal: failed to record an input of {source}: {e}");
+            }
+        }
+    }
+
+    /// Takes the next input of the device `source` while replaying.
+    pub fn next_input(&self, source: &str) -> Option<Vec<u8>> {
+        match &mut *self.mode.lock().unwrap() {
+            Mode::Replay(entries) => entries.get_mut(source)?.pop_front(),
+            Mode::Record(_) => None,
+        }
+    }
+
+    /// Puts back an input taken with `next_input` that couldn't be delivered
+    /// yet, so it's the next one again.
+    pub fn unread_input(&self, source: &str, data: Vec<u8>) {
+        if let Mode::Replay(entries) = &mut *self.mode.lock().unwrap() {
+            entries
+                .entry(source.to_string())
+                .or_default()
+                .push_front(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_record_replay() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path();
+
+        let journal = Journal::record(path, 42).unwrap();
+        assert!(!journal.is_replaying());
+        journal.record_input("eth0", b"frame 1");
+        journal.record_input("input0", &[1, 2, 3, 4, 5, 6, 7, 8]);
+        journal.record_input("eth0", b"frame 2");
+        assert!(journal.next_input("eth0").is_none());
+        drop(journal);
+
+        // Simulate a crash while recording the last entry.
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&[4, b'e', b't', b'h']).unwrap();
+
+        let journal = Journal::replay(path).unwrap();
+        assert!(journal.is_replaying());
+        assert_eq!(journal.seed(), 42);
+        assert_eq!(journal.next_input("eth0").unwrap(), b"frame 1");
+        journal.unread_input("eth0", b"frame 1".to_vec());
+        assert_eq!(journal.next_input("eth0").unwrap(), b"frame 1");
+        assert_eq!(journal.next_input("eth0").unwrap(), b"frame 2");
+        assert!(journal.next_input("eth0").is_none());
+        assert_eq!(journal.next_input("input0").unwrap().len(), 8);
+        assert!(journal.next_input("eth1").is_none());
+    }
+
+    #[test]
+    fn test_invalid_journal() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path();
+        std::fs::write(path, b"KRUNJRNL\x02\0\0\0\0\0\0\0\0\0\0\0").unwrap();
+        assert!(Journal::replay(path).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Output of the early boot console, kept on the host and served on a UNIX
+//! stream socket.
+//!
+//! The guest only gets to use the virtio console and vsock once their drivers
+//! are loaded, so anything the kernel prints before that goes to the UART it
+//! uses as `earlycon`. Its output is kept here, up to `CAPACITY` bytes, so
+//! whoever connects to the socket gets everything printed so far, including
+//! before connecting, followed by anything printed afterwards.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use nix::sys::socket::{send, MsgFlags};
+
+/// How much of the output is kept for clients connecting later on.
+pub const CAPACITY: usize = 1 << 20;
+
+/// Writes all of `buf` to `client`, without raising SIGPIPE if it went away.
+fn send_all(client: &UnixStream, mut buf: &[u8]) -> io::Result<()> {
+    #[cfg(target_os = "macos")]
+    let flags = MsgFlags::empty();
+    #[cfg(target_os = "linux")]
+    let flags = MsgFlags::MSG_NOSIGNAL;
+
+    while !buf.is_empty() {
+        let sent = send(client.as_raw_fd(), buf, flags).map_err(io::Error::from)?;
+        buf = &buf[sent..];
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Inner {
+    buffer: VecDeque<u8>,
+    clients: Vec<UnixStream>,
+}
+
+impl Inner {
+    fn add_client(&mut self, client: UnixStream) {
+        #[cfg(target_os = "macos")]
+        {
+            // nix doesn't provide an abstraction for SO_NOSIGPIPE, fall back to libc.
+            let option_value: libc::c_int = 1;
+            unsafe {
+                libc::setsockopt(
+                    client.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_NOSIGPIPE,
+                    &option_value as *const _ as *const libc::c_void,
+                    std::mem::size_of_val(&option_value) as libc::socklen_t,
+                )
+            };
+        }
+
+        let (front, back) = self.buffer.as_slices();
+        if send_all(&client, front)
+            .and_then(|_| send_all(&client, back))
+            .and_then(|_| client.set_nonblocking(true))
+            .is_ok()
+        {
+            self.clients.push(client);
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct BootLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl BootLog {
+    /// Creates the boot log, serving it on a new socket at `path`.
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        let boot_log = Self::default();
+        let inner = boot_log.inner.clone();
+        thread::Builder::new()
+            .name("boot log".into())
+            .spawn(move || {
+                for client in listener.incoming() {
+                    match client {
+                        Ok(client) => inner.lock().unwrap().add_client(client),
+                        Err(e) => warn!("boot log: error accepting a connection: {e}"),
+                    }
+                }
+            })?;
+        Ok(boot_log)
+    }
+
+    /// Returns everything kept so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.inner.lock().unwrap().buffer.iter().copied().collect()
+    }
+}
+
+impl Write for BootLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let buf_tail = &buf[buf.len().saturating_sub(CAPACITY)..];
+        let excess = (inner.buffer.len() + buf_tail.len()).saturating_sub(CAPACITY);
+        inner.buffer.drain(..excess);
+        inner.buffer.extend(buf_tail);
+
+        // Clients not keeping up would block the vCPU, so they're dropped
+        // instead.
+        inner.clients.retain(|client| send_all(client, buf).is_ok());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    #[test]
+    fn test_boot_log() {
+        let mut boot_log = BootLog::default();
+        boot_log.write_all(b"early").unwrap();
+
+        let (client, mut peer) = UnixStream::pair().unwrap();
+        boot_log.inner.lock().unwrap().add_client(client);
+        boot_log.write_all(b" boot").unwrap();
+
+        let mut received = [0u8; 10];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"early boot");
+
+        // Clients that went away are dropped.
+        drop(peer);
+        boot_log.write_all(b"!").unwrap();
+        assert!(boot_log.inner.lock().unwrap().clients.is_empty());
+        assert_eq!(boot_log.contents(), b"early boot!");
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut boot_log = BootLog::default();
+        boot_log.write_all(&vec![0; CAPACITY]).unwrap();
+        boot_log.write_all(&[1, 2]).unwrap();
+
+        let contents = boot_log.contents();
+        assert_eq!(contents.len(), CAPACITY);
+        assert_eq!(&contents[CAPACITY - 3..], &[0, 1, 2]);
+    }
+}
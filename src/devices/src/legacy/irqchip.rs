@@ -145,7 +145,7 @@ pub trait IrqChipT: BusDevice + AIADevice {
     ) -> Result<(), DeviceError>;
 }
 
-#[cfg(any(test, feature = "test_utils"))]
+#[cfg(any(test, fuzzing, feature = "test_utils"))]
 pub mod test_utils {
     use super::*;
 
@@ -29,10 +29,13 @@ pub struct KvmGicV2 {
 
 impl KvmGicV2 {
     pub fn new(vm: &VmFd, vcpu_count: u64) -> Self {
+        // Each region gets its own 64K page, as KVM requires on hosts with 64K
+        // pages, and so guests can map them whatever their page size.
+        let region_size = arch::aarch64::layout::GUEST_PAGE_SIZE_MAX as u64;
         let dist_size = KVM_VGIC_V2_DIST_SIZE;
-        let dist_addr = arch::MMIO_MEM_START - dist_size;
+        let dist_addr = arch::MMIO_MEM_START - region_size;
         let cpu_size = KVM_VGIC_V2_CPU_SIZE;
-        let cpu_addr = dist_addr - cpu_size;
+        let cpu_addr = dist_addr - region_size;
 
         let mut gic_device = kvm_bindings::kvm_create_device {
             type_: kvm_bindings::kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
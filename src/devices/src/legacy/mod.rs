@@ -6,6 +6,7 @@
 // found in the THIRD-PARTY file.
 
 pub mod aia;
+mod boot_log;
 pub mod gic;
 #[cfg(target_os = "macos")]
 mod gicv3;
@@ -42,6 +43,7 @@ mod riscv64;
 #[cfg(target_arch = "riscv64")]
 use riscv64::serial;
 
+pub use self::boot_log::BootLog;
 #[cfg(target_os = "macos")]
 pub use self::gicv3::GicV3;
 #[cfg(target_arch = "aarch64")]
@@ -52,7 +54,7 @@ pub use self::i8042::Error as I8042DeviceError;
 pub use self::i8042::I8042Device;
 #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
 pub use self::ioapic::IoApic;
-#[cfg(any(test, feature = "test_utils"))]
+#[cfg(any(test, fuzzing, feature = "test_utils"))]
 pub use self::irqchip::test_utils::DummyIrqChip;
 pub use self::irqchip::{IrqChip, IrqChipDevice, IrqChipT};
 #[cfg(all(target_os = "linux", target_arch = "riscv64"))]
@@ -70,6 +70,10 @@ pub struct Serial {
     in_buffer: VecDeque<u8>,
     out: Option<Box<dyn io::Write + Send>>,
     input: Option<Box<dyn ReadableFd + Send>>,
+    /// Where the reads submitted to the event manager store the input.
+    input_buf: Box<[u8; 32]>,
+    /// Whether a read of the input is in flight.
+    input_pending: bool,
 }
 
 impl Serial {
@@ -91,6 +95,8 @@ impl Serial {
             in_buffer: VecDeque::new(),
             out,
             input,
+            input_buf: Box::new([0u8; 32]),
+            input_pending: false,
         }
     }
 
@@ -260,7 +266,7 @@ impl BusDevice for Serial {
 
 impl Subscriber for Serial {
     /// Handle a read event (EPOLLIN) on the serial input fd.
-    fn process(&mut self, event: &EpollEvent, _: &mut EventManager) {
+    fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
         let source = event.fd();
         let event_set = event.event_set();
 
@@ -273,7 +279,10 @@ impl Subscriber for Serial {
         }
 
         if let Some(input) = self.input.as_mut() {
-            if input.as_raw_fd() == source {
+            if input.as_raw_fd() != source {
+                return;
+            }
+            if !event_manager.is_io_uring() {
                 let mut out = [0u8; 32];
                 match input.read(&mut out[..]) {
                     Ok(count) => {
@@ -284,6 +293,33 @@ impl Subscriber for Serial {
                         warn!("error while reading stdin: {e:?}");
                     }
                 }
+            } else if !self.input_pending {
+                // The input stays readable until the read completes, so only
+                // submit one at a time.
+                let buf = self.input_buf.as_mut_ptr();
+                let len = self.input_buf.len() as u32;
+                // SAFETY: The buffer is heap allocated and lives as long as the
+                // serial, which is only dropped along with the event manager.
+                match unsafe { event_manager.submit_read(source, source, buf, len, None, 0) } {
+                    Ok(()) => self.input_pending = true,
+                    Err(e) => warn!("error while reading stdin: {e:?}"),
+                }
+            }
+        }
+    }
+
+    /// Handle the completion of a read of the serial input fd.
+    fn process_completion(&mut self, _token: u64, result: i32, _: &mut EventManager) {
+        self.input_pending = false;
+        match usize::try_from(result) {
+            Ok(count) => {
+                let out = *self.input_buf;
+                self.raw_input(&out[..count])
+                    .unwrap_or_else(|e| warn!("Serial error on input: {e}"));
+            }
+            Err(_) => {
+                let e = io::Error::from_raw_os_error(-result);
+                warn!("error while reading stdin: {e:?}");
             }
         }
     }
@@ -301,6 +337,7 @@ impl Subscriber for Serial {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs::File;
     use std::io;
     use std::io::Write;
     use std::os::unix::io::{AsRawFd, RawFd};
@@ -391,6 +428,30 @@ mod tests {
         serial.process(&invalid_event, &mut event_manager);
     }
 
+    #[test]
+    fn test_event_handling_reads_input() {
+        for mut event_manager in [
+            EventManager::new().unwrap(),
+            EventManager::new_io_uring().unwrap(),
+        ] {
+            let intr_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap();
+            let (rx, tx) = nix::unistd::pipe().unwrap();
+
+            let serial = Arc::new(Mutex::new(Serial::new_in_out(
+                intr_evt,
+                Box::new(File::from(rx)),
+                Box::new(SharedBuffer::new()),
+            )));
+            event_manager.add_subscriber(serial.clone()).unwrap();
+
+            nix::unistd::write(&tx, &RAW_INPUT_BUF).unwrap();
+            while serial.lock().unwrap().in_buffer.len() < RAW_INPUT_BUF.len() {
+                event_manager.run_with_timeout(100).unwrap();
+            }
+            assert!(serial.lock().unwrap().in_buffer.iter().eq(&RAW_INPUT_BUF));
+        }
+    }
+
     #[test]
     fn test_serial_output() {
         let intr_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap();
@@ -16,7 +16,12 @@ use std::io;
 mod bus;
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 pub mod fdt;
+pub mod journal;
 pub mod legacy;
+#[cfg(not(feature = "tee"))]
+pub mod rfb;
+#[cfg(all(target_os = "linux", not(any(feature = "tee", feature = "nitro"))))]
+pub mod vhost_user;
 pub mod virtio;
 
 pub use self::bus::{Bus, BusDevice, Error as BusError};
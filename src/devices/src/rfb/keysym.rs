@@ -0,0 +1,154 @@
+//! Translation of the X11 keysyms used by RFB to evdev key codes.
+//!
+//! Clients send the keysym resulting from the modifiers they hold, along
+//! with the modifiers themselves, so shifted symbols map to the key that
+//! produces them on a US layout, and the guest applies shift on its own.
+
+const KEY_ESC: u16 = 1;
+const KEY_1: u16 = 2;
+const KEY_0: u16 = 11;
+const KEY_MINUS: u16 = 12;
+const KEY_EQUAL: u16 = 13;
+const KEY_BACKSPACE: u16 = 14;
+const KEY_TAB: u16 = 15;
+const KEY_LEFTBRACE: u16 = 26;
+const KEY_RIGHTBRACE: u16 = 27;
+const KEY_ENTER: u16 = 28;
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_SEMICOLON: u16 = 39;
+const KEY_APOSTROPHE: u16 = 40;
+const KEY_GRAVE: u16 = 41;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_BACKSLASH: u16 = 43;
+const KEY_COMMA: u16 = 51;
+const KEY_DOT: u16 = 52;
+const KEY_SLASH: u16 = 53;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_LEFTALT: u16 = 56;
+const KEY_SPACE: u16 = 57;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_F1: u16 = 59;
+const KEY_NUMLOCK: u16 = 69;
+const KEY_SCROLLLOCK: u16 = 70;
+const KEY_F11: u16 = 87;
+const KEY_F12: u16 = 88;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_SYSRQ: u16 = 99;
+const KEY_RIGHTALT: u16 = 100;
+const KEY_HOME: u16 = 102;
+const KEY_UP: u16 = 103;
+const KEY_PAGEUP: u16 = 104;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_END: u16 = 107;
+const KEY_DOWN: u16 = 108;
+const KEY_PAGEDOWN: u16 = 109;
+const KEY_INSERT: u16 = 110;
+const KEY_DELETE: u16 = 111;
+const KEY_PAUSE: u16 = 119;
+const KEY_LEFTMETA: u16 = 125;
+const KEY_RIGHTMETA: u16 = 126;
+const KEY_COMPOSE: u16 = 127;
+
+/// Letters in the order of the keys of the three letter rows, with the key
+/// code of the first one of each.
+const LETTER_ROWS: [(&[u8], u16); 3] = [(b"qwertyuiop", 16), (b"asdfghjkl", 30), (b"zxcvbnm", 44)];
+
+fn ascii_to_evdev(c: u8) -> Option<u16> {
+    let c = c.to_ascii_lowercase();
+    for (row, first) in LETTER_ROWS {
+        if let Some(i) = row.iter().position(|l| *l == c) {
+            return Some(first + i as u16);
+        }
+    }
+
+    let code = match c {
+        b'1'..=b'9' => KEY_1 + (c - b'1') as u16,
+        b'0' => KEY_0,
+        b'!' => KEY_1,
+        b'@' => KEY_1 + 1,
+        b'#' => KEY_1 + 2,
+        b'$' => KEY_1 + 3,
+        b'%' => KEY_1 + 4,
+        b'^' => KEY_1 + 5,
+        b'&' => KEY_1 + 6,
+        b'*' => KEY_1 + 7,
+        b'(' => KEY_1 + 8,
+        b')' => KEY_0,
+        b' ' => KEY_SPACE,
+        b'-' | b'_' => KEY_MINUS,
+        b'=' | b'+' => KEY_EQUAL,
+        b'[' | b'{' => KEY_LEFTBRACE,
+        b']' | b'}' => KEY_RIGHTBRACE,
+        b'\\' | b'|' => KEY_BACKSLASH,
+        b';' | b':' => KEY_SEMICOLON,
+        b'\'' | b'"' => KEY_APOSTROPHE,
+        b'`' | b'~' => KEY_GRAVE,
+        b',' | b'<' => KEY_COMMA,
+        b'.' | b'>' => KEY_DOT,
+        b'/' | b'?' => KEY_SLASH,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Returns the evdev key code for `keysym`, if there's one.
+pub fn to_evdev(keysym: u32) -> Option<u16> {
+    let code = match keysym {
+        0x20..=0x7e => return ascii_to_evdev(keysym as u8),
+        0xff08 => KEY_BACKSPACE,
+        0xff09 => KEY_TAB,
+        0xff0d => KEY_ENTER,
+        0xff13 => KEY_PAUSE,
+        0xff14 => KEY_SCROLLLOCK,
+        0xff1b => KEY_ESC,
+        0xff50 => KEY_HOME,
+        0xff51 => KEY_LEFT,
+        0xff52 => KEY_UP,
+        0xff53 => KEY_RIGHT,
+        0xff54 => KEY_DOWN,
+        0xff55 => KEY_PAGEUP,
+        0xff56 => KEY_PAGEDOWN,
+        0xff57 => KEY_END,
+        0xff61 => KEY_SYSRQ,
+        0xff63 => KEY_INSERT,
+        0xff67 => KEY_COMPOSE,
+        0xff7f => KEY_NUMLOCK,
+        0xffbe..=0xffc7 => KEY_F1 + (keysym - 0xffbe) as u16,
+        0xffc8 => KEY_F11,
+        0xffc9 => KEY_F12,
+        0xffe1 => KEY_LEFTSHIFT,
+        0xffe2 => KEY_RIGHTSHIFT,
+        0xffe3 => KEY_LEFTCTRL,
+        0xffe4 => KEY_RIGHTCTRL,
+        0xffe5 => KEY_CAPSLOCK,
+        0xffe7 | 0xffeb => KEY_LEFTMETA,
+        0xffe8 | 0xffec => KEY_RIGHTMETA,
+        0xffe9 => KEY_LEFTALT,
+        0xffea | 0xfe03 => KEY_RIGHTALT,
+        0xffff => KEY_DELETE,
+        _ => return None,
+    };
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_evdev() {
+        // KEY_Q, KEY_A and KEY_M.
+        assert_eq!(to_evdev(b'q' as u32), Some(16));
+        assert_eq!(to_evdev(b'A' as u32), Some(30));
+        assert_eq!(to_evdev(b'm' as u32), Some(50));
+        assert_eq!(to_evdev(b'1' as u32), Some(KEY_1));
+        assert_eq!(to_evdev(b'(' as u32), Some(10));
+        assert_eq!(to_evdev(b'0' as u32), to_evdev(b')' as u32));
+        assert_eq!(to_evdev(b'?' as u32), Some(KEY_SLASH));
+        assert_eq!(to_evdev(0xffc1), Some(62));
+        assert_eq!(to_evdev(0xffc9), Some(KEY_F12));
+        assert_eq!(to_evdev(0xff0d), Some(KEY_ENTER));
+        assert_eq!(to_evdev(0xe9), None);
+    }
+}
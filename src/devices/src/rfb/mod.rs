@@ -0,0 +1,531 @@
+//! A minimal RFB (VNC) server, giving remote clients a view of a display and
+//! feeding their keyboard and pointer to virtio-input devices.
+//!
+//! It speaks RFB 3.3 to 3.8 over a UNIX socket, without authentication, so
+//! access is controlled with the permissions of the socket, and only sends
+//! full frames in the Raw encoding, which is good enough locally or through
+//! an SSH tunnel.
+
+mod keysym;
+
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::virtio::input::codes;
+use crate::virtio::{InputEvent, InputEventQueue, TABLET_ABS_MAX};
+
+const ENCODING_RAW: i32 = 0;
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+const DEFAULT_WIDTH: u16 = 640;
+const DEFAULT_HEIGHT: u16 = 480;
+
+/// A framebuffer with 4 bytes per pixel and no padding between lines.
+struct Framebuffer {
+    width: u16,
+    height: u16,
+    /// Offsets of the red, green and blue bytes within each pixel.
+    offsets: [usize; 3],
+    data: Vec<u8>,
+    /// Incremented on every change, for clients to know when to update.
+    generation: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_colour: bool,
+    max: [u16; 3],
+    shift: [u8; 3],
+}
+
+impl Default for PixelFormat {
+    /// 32 bits per pixel, little endian 0x00RRGGBB.
+    fn default() -> Self {
+        Self {
+            bits_per_pixel: 32,
+            depth: 24,
+            big_endian: false,
+            true_colour: true,
+            max: [255, 255, 255],
+            shift: [16, 8, 0],
+        }
+    }
+}
+
+impl PixelFormat {
+    fn encode(&self) -> [u8; 16] {
+        let mut buf = [0u8; 16];
+        buf[0] = self.bits_per_pixel;
+        buf[1] = self.depth;
+        buf[2] = self.big_endian as u8;
+        buf[3] = self.true_colour as u8;
+        for i in 0..3 {
+            buf[4 + 2 * i..6 + 2 * i].copy_from_slice(&self.max[i].to_be_bytes());
+            buf[10 + i] = self.shift[i];
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8; 16]) -> Self {
+        let max = |i: usize| u16::from_be_bytes([buf[4 + 2 * i], buf[5 + 2 * i]]);
+        Self {
+            bits_per_pixel: buf[0],
+            depth: buf[1],
+            big_endian: buf[2] != 0,
+            true_colour: buf[3] != 0,
+            max: [max(0), max(1), max(2)],
+            shift: [buf[10], buf[11], buf[12]],
+        }
+    }
+
+    fn is_supported(&self) -> bool {
+        self.true_colour && matches!(self.bits_per_pixel, 8 | 16 | 32)
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel as usize / 8
+    }
+
+    /// Converts a line of `fb`-formatted pixels into this format.
+    fn convert(&self, offsets: &[usize; 3], src: &[u8], out: &mut Vec<u8>) {
+        let bpp = self.bytes_per_pixel();
+        for pixel in src.chunks_exact(4) {
+            let mut value = 0u32;
+            for i in 0..3 {
+                let channel = pixel[offsets[i]] as u32 * self.max[i] as u32 / 255;
+                value |= channel << self.shift[i];
+            }
+            let bytes = if self.big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            };
+            if self.big_endian {
+                out.extend_from_slice(&bytes[4 - bpp..]);
+            } else {
+                out.extend_from_slice(&bytes[..bpp]);
+            }
+        }
+    }
+}
+
+struct ClientState {
+    /// The size of the framebuffer as known by the client.
+    width: u16,
+    height: u16,
+    pixel_format: PixelFormat,
+    desktop_size: bool,
+    update_requested: bool,
+    incremental: bool,
+    closed: bool,
+}
+
+pub struct RfbServer {
+    fb: Mutex<Framebuffer>,
+    /// Notified when the framebuffer changes or a client asks for an update.
+    changed: Condvar,
+    keyboard: Arc<InputEventQueue>,
+    tablet: Arc<InputEventQueue>,
+}
+
+impl RfbServer {
+    pub fn new(keyboard: Arc<InputEventQueue>, tablet: Arc<InputEventQueue>) -> Self {
+        Self {
+            fb: Mutex::new(Framebuffer {
+                width: DEFAULT_WIDTH,
+                height: DEFAULT_HEIGHT,
+                offsets: [2, 1, 0],
+                data: vec![0; DEFAULT_WIDTH as usize * DEFAULT_HEIGHT as usize * 4],
+                generation: 0,
+            }),
+            changed: Condvar::new(),
+            keyboard,
+            tablet,
+        }
+    }
+
+    /// Resizes the framebuffer, whose pixels have their red, green and blue
+    /// bytes at `offsets`, clearing it.
+    pub fn configure(&self, width: u32, height: u32, offsets: [usize; 3]) {
+        let width = width.min(u16::MAX as u32) as u16;
+        let height = height.min(u16::MAX as u32) as u16;
+        let mut fb = self.fb.lock().unwrap();
+        fb.width = width;
+        fb.height = height;
+        fb.offsets = offsets;
+        fb.data = vec![0; width as usize * height as usize * 4];
+        fb.generation += 1;
+        self.changed.notify_all();
+    }
+
+    /// Replaces the contents of the framebuffer with `data`.
+    pub fn update(&self, data: &[u8]) {
+        let mut fb = self.fb.lock().unwrap();
+        let len = fb.data.len().min(data.len());
+        fb.data[..len].copy_from_slice(&data[..len]);
+        fb.generation += 1;
+        self.changed.notify_all();
+    }
+
+    /// Starts accepting clients on a new UNIX socket at `path`.
+    pub fn listen(self: &Arc<Self>, path: &Path) -> io::Result<()> {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        let server = self.clone();
+        thread::Builder::new()
+            .name("rfb server".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            error!("rfb: error accepting a client: {e:?}");
+                            continue;
+                        }
+                    };
+                    let server = server.clone();
+                    let res = thread::Builder::new()
+                        .name("rfb client".into())
+                        .spawn(move || {
+                            if let Err(e) = server.serve(stream) {
+                                debug!("rfb: client disconnected: {e:?}");
+                            }
+                        });
+                    if let Err(e) = res {
+                        error!("rfb: error spawning a client thread: {e:?}");
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    fn handshake(&self, stream: &mut UnixStream) -> io::Result<()> {
+        stream.write_all(b"RFB 003.008\n")?;
+        let mut version = [0u8; 12];
+        stream.read_exact(&mut version)?;
+        let minor = match &version {
+            b"RFB 003.003\n" => 3,
+            b"RFB 003.007\n" => 7,
+            // Later versions must be treated as 3.8.
+            _ if version.starts_with(b"RFB 003.") => 8,
+            _ => return Err(io::Error::other("unsupported protocol version")),
+        };
+
+        // The "None" security type.
+        if minor == 3 {
+            stream.write_all(&1u32.to_be_bytes())?;
+        } else {
+            stream.write_all(&[1, 1])?;
+            let mut security = [0u8];
+            stream.read_exact(&mut security)?;
+            if security[0] != 1 {
+                return Err(io::Error::other("unsupported security type"));
+            }
+            if minor == 8 {
+                stream.write_all(&0u32.to_be_bytes())?;
+            }
+        }
+
+        // ClientInit, whether to share the desktop, which we always do.
+        let mut shared = [0u8];
+        stream.read_exact(&mut shared)?;
+
+        let (width, height) = {
+            let fb = self.fb.lock().unwrap();
+            (fb.width, fb.height)
+        };
+        let name = b"libkrun";
+        let mut init = Vec::new();
+        init.extend_from_slice(&width.to_be_bytes());
+        init.extend_from_slice(&height.to_be_bytes());
+        init.extend_from_slice(&PixelFormat::default().encode());
+        init.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        init.extend_from_slice(name);
+        stream.write_all(&init)
+    }
+
+    fn serve(self: Arc<Self>, mut stream: UnixStream) -> io::Result<()> {
+        self.handshake(&mut stream)?;
+
+        let (width, height) = {
+            let fb = self.fb.lock().unwrap();
+            (fb.width, fb.height)
+        };
+        let state = Arc::new(Mutex::new(ClientState {
+            width,
+            height,
+            pixel_format: PixelFormat::default(),
+            desktop_size: false,
+            update_requested: false,
+            incremental: false,
+            closed: false,
+        }));
+
+        let writer = {
+            let server = self.clone();
+            let state = state.clone();
+            let stream = stream.try_clone()?;
+            thread::Builder::new()
+                .name("rfb updates".into())
+                .spawn(move || server.send_updates(stream, state))?
+        };
+
+        let res = self.read_messages(&mut stream, &state);
+        state.lock().unwrap().closed = true;
+        {
+            let _fb = self.fb.lock().unwrap();
+            self.changed.notify_all();
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+        let _ = writer.join();
+        res
+    }
+
+    /// Sends a framebuffer update each time the client asks for one, as soon
+    /// as there's something new to show.
+    fn send_updates(&self, mut stream: UnixStream, state: Arc<Mutex<ClientState>>) {
+        let mut sent_generation = None;
+        loop {
+            let mut fb = self.fb.lock().unwrap();
+            let (pixel_format, resize, width, height) = loop {
+                let mut client = state.lock().unwrap();
+                if client.closed {
+                    return;
+                }
+                if client.update_requested
+                    && (!client.incremental || sent_generation != Some(fb.generation))
+                {
+                    client.update_requested = false;
+                    let resize = client.desktop_size
+                        && (fb.width, fb.height) != (client.width, client.height);
+                    if resize {
+                        (client.width, client.height) = (fb.width, fb.height);
+                    }
+                    break (client.pixel_format, resize, client.width, client.height);
+                }
+                drop(client);
+                fb = self.changed.wait(fb).unwrap();
+            };
+
+            let mut rects = Vec::new();
+            let mut num_rects = 1u16;
+            if resize {
+                rects.extend_from_slice(&[0, 0, 0, 0]);
+                rects.extend_from_slice(&width.to_be_bytes());
+                rects.extend_from_slice(&height.to_be_bytes());
+                rects.extend_from_slice(&ENCODING_DESKTOP_SIZE.to_be_bytes());
+                num_rects += 1;
+            }
+            rects.extend_from_slice(&[0, 0, 0, 0]);
+            rects.extend_from_slice(&width.to_be_bytes());
+            rects.extend_from_slice(&height.to_be_bytes());
+            rects.extend_from_slice(&ENCODING_RAW.to_be_bytes());
+
+            // Clients that can't be resized get the framebuffer cropped or
+            // padded to their size.
+            let src_stride = fb.width as usize * 4;
+            let mut line = Vec::new();
+            for y in 0..height as usize {
+                line.clear();
+                let pixels = if y < fb.height as usize {
+                    let start = y * src_stride;
+                    &fb.data[start..start + src_stride.min(width as usize * 4)]
+                } else {
+                    &[]
+                };
+                pixel_format.convert(&fb.offsets, pixels, &mut line);
+                line.resize(width as usize * pixel_format.bytes_per_pixel(), 0);
+                rects.extend_from_slice(&line);
+            }
+            sent_generation = Some(fb.generation);
+            drop(fb);
+
+            let mut header = vec![0u8, 0];
+            header.extend_from_slice(&num_rects.to_be_bytes());
+            if stream.write_all(&header).is_err() || stream.write_all(&rects).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn read_messages(&self, stream: &mut UnixStream, state: &Mutex<ClientState>) -> io::Result<()> {
+        let mut buttons = 0u8;
+        loop {
+            let mut msg_type = [0u8];
+            stream.read_exact(&mut msg_type)?;
+            match msg_type[0] {
+                // SetPixelFormat
+                0 => {
+                    let mut buf = [0u8; 19];
+                    stream.read_exact(&mut buf)?;
+                    let pixel_format = PixelFormat::decode(buf[3..].try_into().unwrap());
+                    if !pixel_format.is_supported() {
+                        return Err(io::Error::other("unsupported pixel format"));
+                    }
+                    state.lock().unwrap().pixel_format = pixel_format;
+                }
+                // SetEncodings
+                2 => {
+                    let mut buf = [0u8; 3];
+                    stream.read_exact(&mut buf)?;
+                    let count = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+                    let mut encodings = vec![0u8; count * 4];
+                    stream.read_exact(&mut encodings)?;
+                    state.lock().unwrap().desktop_size = encodings.chunks_exact(4).any(|e| {
+                        i32::from_be_bytes(e.try_into().unwrap()) == ENCODING_DESKTOP_SIZE
+                    });
+                }
+                // FramebufferUpdateRequest
+                3 => {
+                    let mut buf = [0u8; 9];
+                    stream.read_exact(&mut buf)?;
+                    let _fb = self.fb.lock().unwrap();
+                    let mut client = state.lock().unwrap();
+                    client.incremental = buf[0] != 0;
+                    client.update_requested = true;
+                    self.changed.notify_all();
+                }
+                // KeyEvent
+                4 => {
+                    let mut buf = [0u8; 7];
+                    stream.read_exact(&mut buf)?;
+                    let keysym = u32::from_be_bytes(buf[3..].try_into().unwrap());
+                    if let Some(code) = keysym::to_evdev(keysym) {
+                        self.keyboard.push(&[
+                            InputEvent::new(codes::EV_KEY, code, buf[0].min(1) as u32),
+                            InputEvent::syn(),
+                        ]);
+                    }
+                }
+                // PointerEvent
+                5 => {
+                    let mut buf = [0u8; 5];
+                    stream.read_exact(&mut buf)?;
+                    let size = {
+                        let client = state.lock().unwrap();
+                        (client.width, client.height)
+                    };
+                    let x = u16::from_be_bytes([buf[1], buf[2]]);
+                    let y = u16::from_be_bytes([buf[3], buf[4]]);
+                    let events = pointer_events(buttons, buf[0], (x, y), size);
+                    buttons = buf[0];
+                    self.tablet.push(&events);
+                }
+                // ClientCutText
+                6 => {
+                    let mut buf = [0u8; 7];
+                    stream.read_exact(&mut buf)?;
+                    let len = u32::from_be_bytes(buf[3..].try_into().unwrap());
+                    io::copy(&mut (&mut *stream).take(len as u64), &mut io::sink())?;
+                }
+                t => return Err(io::Error::other(format!("unknown message type {t}"))),
+            }
+        }
+    }
+}
+
+/// Returns the events for a pointer at `pos` within a display of `size`,
+/// whose buttons went from `old` to `new`.
+fn pointer_events(old: u8, new: u8, pos: (u16, u16), size: (u16, u16)) -> Vec<InputEvent> {
+    let scale = |v: u16, max: u16| {
+        (v.min(max.saturating_sub(1)) as u32 * TABLET_ABS_MAX) / (max.max(2) - 1) as u32
+    };
+    let mut events = vec![
+        InputEvent::new(codes::EV_ABS, codes::ABS_X, scale(pos.0, size.0)),
+        InputEvent::new(codes::EV_ABS, codes::ABS_Y, scale(pos.1, size.1)),
+    ];
+
+    for (bit, button) in [codes::BTN_LEFT, codes::BTN_MIDDLE, codes::BTN_RIGHT]
+        .into_iter()
+        .enumerate()
+    {
+        let mask = 1 << bit;
+        if (old ^ new) & mask != 0 {
+            events.push(InputEvent::new(
+                codes::EV_KEY,
+                button,
+                (new & mask != 0) as u32,
+            ));
+        }
+    }
+    // The wheel is reported as presses of buttons 4 (up) and 5 (down).
+    if new & !old & (1 << 3) != 0 {
+        events.push(InputEvent::new(codes::EV_REL, codes::REL_WHEEL, 1));
+    }
+    if new & !old & (1 << 4) != 0 {
+        events.push(InputEvent::new(
+            codes::EV_REL,
+            codes::REL_WHEEL,
+            -1i32 as u32,
+        ));
+    }
+
+    events.push(InputEvent::syn());
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pixel_format() {
+        let pf = PixelFormat::default();
+        assert_eq!(PixelFormat::decode(&pf.encode()), pf);
+
+        // A BGRX pixel.
+        let offsets = [2, 1, 0];
+        let pixel = [0x30, 0x20, 0x10, 0xff];
+        let mut out = Vec::new();
+        pf.convert(&offsets, &pixel, &mut out);
+        assert_eq!(out, [0x30, 0x20, 0x10, 0]);
+
+        // RGB565, big endian.
+        let pf = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            true_colour: true,
+            max: [31, 63, 31],
+            shift: [11, 5, 0],
+        };
+        out.clear();
+        pf.convert(&offsets, &[0xff, 0, 0xff, 0], &mut out);
+        assert_eq!(out, [0xf8, 0x1f]);
+    }
+
+    #[test]
+    fn test_pointer_events() {
+        let events = pointer_events(0, 1, (0, 479), (640, 480));
+        assert_eq!(
+            events,
+            [
+                InputEvent::new(codes::EV_ABS, codes::ABS_X, 0),
+                InputEvent::new(codes::EV_ABS, codes::ABS_Y, TABLET_ABS_MAX),
+                InputEvent::new(codes::EV_KEY, codes::BTN_LEFT, 1),
+                InputEvent::syn(),
+            ]
+        );
+
+        // Releasing the left button while scrolling down.
+        let events = pointer_events(1, 1 << 4, (639, 0), (640, 480));
+        assert_eq!(
+            events[0],
+            InputEvent::new(codes::EV_ABS, codes::ABS_X, TABLET_ABS_MAX)
+        );
+        assert_eq!(
+            events[2],
+            InputEvent::new(codes::EV_KEY, codes::BTN_LEFT, 0)
+        );
+        assert_eq!(
+            events[3],
+            InputEvent::new(codes::EV_REL, codes::REL_WHEEL, u32::MAX)
+        );
+    }
+}
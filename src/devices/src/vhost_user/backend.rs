@@ -0,0 +1,535 @@
+use std::num::Wrapping;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::eventfd::EventFd;
+use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+
+use super::protocol::{self, *};
+use super::{Error, Result};
+use crate::virtio::Queue;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const SOCKET_TOKEN: u64 = u64::MAX;
+
+/// A device whose queues are processed in a vhost-user backend.
+pub trait VhostUserBackend {
+    fn num_queues(&self) -> usize;
+
+    fn max_queue_size(&self) -> u16;
+
+    /// The device specific features. VIRTIO_F_VERSION_1 is always offered.
+    fn features(&self) -> u64;
+
+    /// Processes the requests available in `queue`. Returns whether any was
+    /// added to the used ring.
+    fn process_queue(
+        &mut self,
+        queue_index: usize,
+        queue: &mut Queue,
+        mem: &GuestMemoryMmap,
+    ) -> bool;
+
+    /// Called when the frontend disconnects or resets the device.
+    fn reset(&mut self) {}
+}
+
+struct Vring {
+    queue: Queue,
+    kick: Option<EventFd>,
+    call: Option<EventFd>,
+    enabled: bool,
+}
+
+impl Vring {
+    fn new(max_size: u16) -> Self {
+        Self {
+            queue: Queue::new(max_size),
+            kick: None,
+            call: None,
+            enabled: false,
+        }
+    }
+}
+
+struct Connection<'a, B: VhostUserBackend> {
+    stream: UnixStream,
+    backend: &'a mut B,
+    epoll: Epoll,
+    acked_features: u64,
+    acked_protocol_features: u64,
+    mem: Option<GuestMemoryMmap>,
+    regions: Vec<MemoryRegion>,
+    vrings: Vec<Vring>,
+}
+
+impl<'a, B: VhostUserBackend> Connection<'a, B> {
+    fn new(stream: UnixStream, backend: &'a mut B) -> Result<Self> {
+        let epoll = Epoll::new().map_err(Error::Epoll)?;
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                stream.as_raw_fd(),
+                &EpollEvent::new(EventSet::IN, SOCKET_TOKEN),
+            )
+            .map_err(Error::Epoll)?;
+        let vrings = (0..backend.num_queues())
+            .map(|_| Vring::new(backend.max_queue_size()))
+            .collect();
+        Ok(Self {
+            stream,
+            backend,
+            epoll,
+            acked_features: 0,
+            acked_protocol_features: 0,
+            mem: None,
+            regions: Vec::new(),
+            vrings,
+        })
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let mut events = vec![EpollEvent::default(); self.vrings.len() + 1];
+        loop {
+            let count = match self.epoll.wait(events.len(), -1, &mut events) {
+                Ok(count) => count,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Epoll(e)),
+            };
+            for event in events[..count].iter() {
+                if event.data() == SOCKET_TOKEN {
+                    let Some(msg) = protocol::recv(&mut self.stream)? else {
+                        return Ok(());
+                    };
+                    self.handle_message(msg)?;
+                } else {
+                    self.handle_kick(event.data() as usize);
+                }
+            }
+        }
+    }
+
+    fn vring(&mut self, index: u32) -> Result<&mut Vring> {
+        self.vrings
+            .get_mut(index as usize)
+            .ok_or(Error::InvalidMessage)
+    }
+
+    /// Translates an address of the frontend to a guest physical address.
+    fn to_guest_addr(&self, addr: u64) -> Result<GuestAddress> {
+        self.regions
+            .iter()
+            .find(|r| addr >= r.userspace_addr && addr - r.userspace_addr < r.memory_size)
+            .map(|r| GuestAddress(addr - r.userspace_addr + r.guest_phys_addr))
+            .ok_or(Error::InvalidMessage)
+    }
+
+    fn set_mem_table(&mut self, msg: &mut Message) -> Result<()> {
+        let regions = msg.memory_regions()?;
+        if regions.len() != msg.files.len() {
+            return Err(Error::InvalidMessage);
+        }
+        let mut guest_regions = Vec::new();
+        for (region, file) in regions.iter().zip(std::mem::take(&mut msg.files)) {
+            let mmap = MmapRegion::from_file(
+                FileOffset::new(file, region.mmap_offset),
+                region.memory_size as usize,
+            )
+            .map_err(Error::MapMemory)?;
+            guest_regions.push(
+                GuestRegionMmap::new(mmap, GuestAddress(region.guest_phys_addr))
+                    .map_err(Error::GuestMemory)?,
+            );
+        }
+        self.mem = Some(GuestMemoryMmap::from_regions(guest_regions).map_err(Error::GuestMemory)?);
+        self.regions = regions;
+        Ok(())
+    }
+
+    fn set_vring_fd(&mut self, msg: &mut Message) -> Result<(u32, Option<EventFd>)> {
+        let value: u64 = msg.payload()?;
+        let index = (value & VRING_INDEX_MASK) as u32;
+        self.vring(index)?;
+        if value & VRING_NOFD_MASK != 0 {
+            return Ok((index, None));
+        }
+        let file = msg.files.pop().ok_or(Error::InvalidMessage)?;
+        // SAFETY: the descriptor is owned by `file`, which we consume.
+        Ok((
+            index,
+            Some(unsafe { EventFd::from_raw_fd(file.into_raw_fd()) }),
+        ))
+    }
+
+    fn stop_vring(&mut self, index: u32) -> Result<u16> {
+        let max_size = self.backend.max_queue_size();
+        let vring = self.vring(index)?;
+        let next_avail = vring.queue.next_avail.0;
+        if let Some(kick) = vring.kick.take() {
+            let _ = self.epoll.ctl(
+                ControlOperation::Delete,
+                kick.as_raw_fd(),
+                &EpollEvent::default(),
+            );
+        }
+        let vring = self.vring(index)?;
+        vring.queue = Queue::new(max_size);
+        vring.enabled = false;
+        Ok(next_avail)
+    }
+
+    fn handle_message(&mut self, mut msg: Message) -> Result<()> {
+        let request = msg.header.request;
+        debug!("vhost-user: request {request}");
+
+        let result = match request {
+            GET_FEATURES => {
+                let features =
+                    self.backend.features() | VIRTIO_F_VERSION_1 | VHOST_USER_F_PROTOCOL_FEATURES;
+                return protocol::reply(&mut self.stream, request, &features);
+            }
+            GET_PROTOCOL_FEATURES => {
+                let features = PROTOCOL_F_MQ | PROTOCOL_F_REPLY_ACK;
+                return protocol::reply(&mut self.stream, request, &features);
+            }
+            GET_QUEUE_NUM => {
+                let num = self.vrings.len() as u64;
+                return protocol::reply(&mut self.stream, request, &num);
+            }
+            GET_VRING_BASE => {
+                let state: VringState = msg.payload()?;
+                let num = self.stop_vring(state.index)? as u32;
+                let reply = VringState {
+                    index: state.index,
+                    num,
+                };
+                return protocol::reply(&mut self.stream, request, &reply);
+            }
+            SET_OWNER => Ok(()),
+            RESET_OWNER => {
+                for index in 0..self.vrings.len() {
+                    self.stop_vring(index as u32)?;
+                }
+                self.backend.reset();
+                Ok(())
+            }
+            SET_FEATURES => msg.payload().map(|features| self.acked_features = features),
+            SET_PROTOCOL_FEATURES => msg
+                .payload()
+                .map(|features| self.acked_protocol_features = features),
+            SET_MEM_TABLE => self.set_mem_table(&mut msg),
+            SET_VRING_NUM => {
+                let state: VringState = msg.payload()?;
+                let vring = self.vring(state.index)?;
+                if state.num > vring.queue.get_max_size() as u32
+                    || !(state.num as u16).is_power_of_two()
+                {
+                    Err(Error::InvalidMessage)
+                } else {
+                    vring.queue.size = state.num as u16;
+                    Ok(())
+                }
+            }
+            SET_VRING_ADDR => {
+                let addr: VringAddr = msg.payload()?;
+                let desc_table = self.to_guest_addr(addr.desc)?;
+                let avail_ring = self.to_guest_addr(addr.avail)?;
+                let used_ring = self.to_guest_addr(addr.used)?;
+                let vring = self.vring(addr.index)?;
+                vring.queue.desc_table = desc_table;
+                vring.queue.avail_ring = avail_ring;
+                vring.queue.used_ring = used_ring;
+                Ok(())
+            }
+            SET_VRING_BASE => {
+                let state: VringState = msg.payload()?;
+                let vring = self.vring(state.index)?;
+                vring.queue.next_avail = Wrapping(state.num as u16);
+                vring.queue.next_used = Wrapping(state.num as u16);
+                Ok(())
+            }
+            SET_VRING_KICK => {
+                let (index, kick) = self.set_vring_fd(&mut msg)?;
+                // Polling the rings isn't supported.
+                let kick = kick.ok_or(Error::Unsupported(request))?;
+                self.epoll
+                    .ctl(
+                        ControlOperation::Add,
+                        kick.as_raw_fd(),
+                        &EpollEvent::new(EventSet::IN, index as u64),
+                    )
+                    .map_err(Error::Epoll)?;
+                let protocol_features = self.acked_features & VHOST_USER_F_PROTOCOL_FEATURES != 0;
+                let vring = self.vring(index)?;
+                vring.kick = Some(kick);
+                // Without protocol features, rings are enabled as soon as they
+                // start. Otherwise, it's up to SET_VRING_ENABLE.
+                if !protocol_features {
+                    vring.enabled = true;
+                }
+                vring.queue.ready = true;
+                // The driver may have made requests available already.
+                self.handle_kick(index as usize);
+                Ok(())
+            }
+            SET_VRING_CALL => {
+                let (index, call) = self.set_vring_fd(&mut msg)?;
+                self.vring(index)?.call = call;
+                Ok(())
+            }
+            // Errors are only logged.
+            SET_VRING_ERR => self.set_vring_fd(&mut msg).map(|_| ()),
+            SET_VRING_ENABLE => {
+                let state: VringState = msg.payload()?;
+                let vring = self.vring(state.index)?;
+                vring.enabled = state.num != 0;
+                if vring.enabled {
+                    self.handle_kick(state.index as usize);
+                }
+                Ok(())
+            }
+            _ => Err(Error::Unsupported(request)),
+        };
+
+        if let Err(ref e) = result {
+            warn!("vhost-user: request {request} failed: {e:?}");
+        }
+        if msg.header.flags & FLAG_NEED_REPLY != 0
+            && self.acked_protocol_features & PROTOCOL_F_REPLY_ACK != 0
+        {
+            let status: u64 = result.is_err().into();
+            protocol::reply(&mut self.stream, request, &status)?;
+        }
+        match result {
+            // A broken connection can't be recovered from.
+            Err(Error::Socket(e)) => Err(Error::Socket(e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_kick(&mut self, index: usize) {
+        let Some(mem) = self.mem.as_ref() else {
+            return;
+        };
+        let Some(vring) = self.vrings.get_mut(index) else {
+            return;
+        };
+        let Some(kick) = vring.kick.as_ref() else {
+            return;
+        };
+        // Nothing to read if this wasn't triggered by the frontend.
+        let _ = kick.read();
+        if !vring.enabled || !vring.queue.is_valid(mem) {
+            return;
+        }
+
+        loop {
+            if let Err(e) = vring.queue.disable_notification(mem) {
+                error!("vhost-user: failed to disable notifications: {e:?}");
+            }
+            let used = self.backend.process_queue(index, &mut vring.queue, mem);
+            if used && vring.queue.needs_notification(mem).unwrap_or(true) {
+                if let Some(call) = vring.call.as_ref() {
+                    if let Err(e) = call.write(1) {
+                        error!("vhost-user: failed to signal the frontend: {e:?}");
+                    }
+                }
+            }
+            match vring.queue.enable_notification(mem) {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    error!("vhost-user: failed to enable notifications: {e:?}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Serves `backend` to the frontends connecting to `socket_path`, one after
+/// another. Only returns on error.
+pub fn serve<B: VhostUserBackend>(socket_path: &Path, backend: &mut B) -> Result<()> {
+    let listener = UnixListener::bind(socket_path).map_err(Error::Bind)?;
+    loop {
+        let (stream, _) = listener.accept().map_err(Error::Accept)?;
+        info!("vhost-user: frontend connected");
+        let result = Connection::new(stream, backend)?.run();
+        backend.reset();
+        match result {
+            Ok(()) => info!("vhost-user: frontend disconnected"),
+            Err(e) => error!("vhost-user: frontend connection failed: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::{IoSlice, Read, Write};
+    use std::os::fd::RawFd;
+    use std::thread;
+
+    use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+    use vm_memory::ByteValued;
+
+    use crate::virtio::queue::tests::VirtQueue as GuestQueue;
+
+    // Where the frontend pretends to have mapped the guest memory.
+    const FRONTEND_ADDR: u64 = 0x7f00_0000_0000;
+
+    /// Marks every request as used, without writing anything.
+    struct NullBackend;
+
+    impl VhostUserBackend for NullBackend {
+        fn num_queues(&self) -> usize {
+            1
+        }
+
+        fn max_queue_size(&self) -> u16 {
+            16
+        }
+
+        fn features(&self) -> u64 {
+            0
+        }
+
+        fn process_queue(&mut self, _: usize, queue: &mut Queue, mem: &GuestMemoryMmap) -> bool {
+            let mut used = false;
+            while let Some(head) = queue.pop(mem) {
+                queue.add_used(mem, head.index, 0).unwrap();
+                used = true;
+            }
+            used
+        }
+    }
+
+    fn send<T: ByteValued>(stream: &mut UnixStream, request: u32, payload: &T, fds: &[RawFd]) {
+        let header = Header {
+            request,
+            flags: 0x1,
+            size: std::mem::size_of::<T>() as u32,
+        };
+        let cmsgs = [ControlMessage::ScmRights(fds)];
+        let cmsgs = if fds.is_empty() { &[][..] } else { &cmsgs[..] };
+        sendmsg::<()>(
+            stream.as_raw_fd(),
+            &[IoSlice::new(header.as_slice())],
+            cmsgs,
+            MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+        stream.write_all(payload.as_slice()).unwrap();
+    }
+
+    fn recv_u64(stream: &mut UnixStream) -> u64 {
+        let mut buf = [0u8; 20];
+        stream.read_exact(&mut buf).unwrap();
+        u64::from_ne_bytes(buf[12..].try_into().unwrap())
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct MemTable {
+        num_regions: u32,
+        padding: u32,
+        region: MemoryRegion,
+    }
+
+    // SAFETY: only plain old data.
+    unsafe impl ByteValued for MemTable {}
+
+    #[test]
+    fn test_process_requests() {
+        let (mut frontend, backend_stream) = UnixStream::pair().unwrap();
+        let backend = thread::spawn(move || {
+            let mut backend = NullBackend;
+            Connection::new(backend_stream, &mut backend).unwrap().run()
+        });
+
+        send(&mut frontend, GET_FEATURES, &0u64, &[]);
+        let features = recv_u64(&mut frontend);
+        assert_ne!(features & VIRTIO_F_VERSION_1, 0);
+        assert_ne!(features & VHOST_USER_F_PROTOCOL_FEATURES, 0);
+        send(&mut frontend, SET_FEATURES, &VIRTIO_F_VERSION_1, &[]);
+
+        // SAFETY: creating a memfd has no preconditions.
+        let memfd = unsafe { libc::memfd_create(c"guest".as_ptr(), libc::MFD_CLOEXEC) };
+        // SAFETY: we just created it.
+        let memfd = unsafe { File::from_raw_fd(memfd) };
+        memfd.set_len(0x10000).unwrap();
+        let table = MemTable {
+            num_regions: 1,
+            padding: 0,
+            region: MemoryRegion {
+                guest_phys_addr: 0,
+                memory_size: 0x10000,
+                userspace_addr: FRONTEND_ADDR,
+                mmap_offset: 0,
+            },
+        };
+        send(&mut frontend, SET_MEM_TABLE, &table, &[memfd.as_raw_fd()]);
+
+        let mem = GuestMemoryMmap::from_regions(vec![GuestRegionMmap::new(
+            MmapRegion::from_file(FileOffset::new(memfd.try_clone().unwrap(), 0), 0x10000).unwrap(),
+            GuestAddress(0),
+        )
+        .unwrap()])
+        .unwrap();
+        let vq = GuestQueue::new(GuestAddress(0), &mem, 16);
+        let queue = vq.create_queue();
+        send(
+            &mut frontend,
+            SET_VRING_NUM,
+            &VringState { index: 0, num: 16 },
+            &[],
+        );
+        let addr = VringAddr {
+            index: 0,
+            flags: 0,
+            desc: FRONTEND_ADDR + queue.desc_table.0,
+            used: FRONTEND_ADDR + queue.used_ring.0,
+            avail: FRONTEND_ADDR + queue.avail_ring.0,
+            log: 0,
+        };
+        send(&mut frontend, SET_VRING_ADDR, &addr, &[]);
+        send(
+            &mut frontend,
+            SET_VRING_BASE,
+            &VringState { index: 0, num: 0 },
+            &[],
+        );
+
+        let kick = EventFd::new(0).unwrap();
+        let call = EventFd::new(0).unwrap();
+        send(&mut frontend, SET_VRING_CALL, &0u64, &[call.as_raw_fd()]);
+        send(&mut frontend, SET_VRING_KICK, &0u64, &[kick.as_raw_fd()]);
+
+        vq.dtable[0].set(0x8000, 0x100, 0, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+        kick.write(1).unwrap();
+        call.read().unwrap();
+        assert_eq!(vq.used.idx.get(), 1);
+
+        // Stopping the ring reports where the frontend must resume from.
+        send(
+            &mut frontend,
+            GET_VRING_BASE,
+            &VringState { index: 0, num: 0 },
+            &[],
+        );
+        let mut buf = [0u8; 20];
+        frontend.read_exact(&mut buf).unwrap();
+        assert_eq!(u32::from_ne_bytes(buf[16..].try_into().unwrap()), 1);
+
+        drop(frontend);
+        backend.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+//! The virtio-fs server of libkrun, as a vhost-user-fs backend.
+
+use std::io;
+use std::sync::atomic::AtomicI32;
+use std::sync::Arc;
+
+use vm_memory::GuestMemoryMmap;
+
+use super::VhostUserBackend;
+use crate::virtio::descriptor_utils::{Reader, Writer};
+use crate::virtio::fs::passthrough::{self, PassthroughFs};
+use crate::virtio::fs::Server;
+use crate::virtio::Queue;
+
+// The high priority queue and a single request queue.
+const NUM_QUEUES: usize = 2;
+const QUEUE_SIZE: u16 = 1024;
+
+pub struct FsBackend {
+    server: Server<PassthroughFs>,
+    exit_code: Arc<AtomicI32>,
+}
+
+impl FsBackend {
+    /// Creates a backend sharing `shared_dir`.
+    pub fn new(shared_dir: String) -> io::Result<Self> {
+        let cfg = passthrough::Config {
+            root_dir: shared_dir,
+            ..Default::default()
+        };
+        Ok(Self {
+            server: Server::new(PassthroughFs::new(cfg)?),
+            exit_code: Arc::new(AtomicI32::new(i32::MAX)),
+        })
+    }
+}
+
+impl VhostUserBackend for FsBackend {
+    fn num_queues(&self) -> usize {
+        NUM_QUEUES
+    }
+
+    fn max_queue_size(&self) -> u16 {
+        QUEUE_SIZE
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn process_queue(
+        &mut self,
+        _queue_index: usize,
+        queue: &mut Queue,
+        mem: &GuestMemoryMmap,
+    ) -> bool {
+        let mut used = false;
+        while let Some(head) = queue.pop(mem) {
+            let (reader, writer) = match (
+                Reader::new(mem, head.clone()),
+                Writer::new(mem, head.clone()),
+            ) {
+                (Ok(reader), Ok(writer)) => (reader, writer),
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("vhost-user-fs: invalid descriptor chain: {e:?}");
+                    if let Err(e) = queue.add_used(mem, head.index, 0) {
+                        error!("failed to add used elements to the queue: {e:?}");
+                    }
+                    used = true;
+                    continue;
+                }
+            };
+
+            let written = match self
+                .server
+                .handle_message(reader, writer, &None, &self.exit_code)
+            {
+                Ok(written) => written,
+                Err(e) => {
+                    error!("error handling message: {e:?}");
+                    0
+                }
+            };
+
+            if let Err(e) = queue.add_used(mem, head.index, written as u32) {
+                error!("failed to add used elements to the queue: {e:?}");
+            }
+            used = true;
+        }
+        used
+    }
+}
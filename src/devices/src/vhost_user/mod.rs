@@ -0,0 +1,40 @@
+//! vhost-user backends, exporting libkrun devices to other VMMs, like QEMU or
+//! cloud-hypervisor, which implement the transport and hand over the guest
+//! memory and the virtqueues through a UNIX socket.
+//!
+//! Only the parts of the protocol needed by those frontends for a device
+//! processing its queues in the backend are implemented: no dirty page
+//! logging, so migration isn't supported, and no postcopy or inflight
+//! descriptor tracking.
+
+mod backend;
+pub mod fs;
+mod protocol;
+
+use std::io;
+
+pub use self::backend::{serve, VhostUserBackend};
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to accept a connection from the frontend.
+    Accept(io::Error),
+    /// Failed to bind the socket of the backend.
+    Bind(io::Error),
+    /// Failed to create or use the epoll instance.
+    Epoll(io::Error),
+    /// The frontend sent a message that doesn't make sense.
+    InvalidMessage,
+    /// Failed to map the guest memory.
+    MapMemory(vm_memory::mmap::MmapRegionError),
+    /// Failed to build the guest memory map.
+    GuestMemory(vm_memory::Error),
+    /// Failed to receive a message from the frontend.
+    Recv(nix::Error),
+    /// Error reading from or writing to the socket.
+    Socket(io::Error),
+    /// The frontend requested something that isn't supported.
+    Unsupported(u32),
+}
+
+type Result<T> = std::result::Result<T, Error>;
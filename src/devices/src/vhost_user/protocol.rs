@@ -0,0 +1,183 @@
+//! Messages of the vhost-user protocol, as described in
+//! https://qemu-project.gitlab.io/qemu/interop/vhost-user.html
+
+use std::fs::File;
+use std::io::{IoSliceMut, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use nix::cmsg_space;
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+use vm_memory::ByteValued;
+
+use super::{Error, Result};
+
+pub const GET_FEATURES: u32 = 1;
+pub const SET_FEATURES: u32 = 2;
+pub const SET_OWNER: u32 = 3;
+pub const RESET_OWNER: u32 = 4;
+pub const SET_MEM_TABLE: u32 = 5;
+pub const SET_VRING_NUM: u32 = 8;
+pub const SET_VRING_ADDR: u32 = 9;
+pub const SET_VRING_BASE: u32 = 10;
+pub const GET_VRING_BASE: u32 = 11;
+pub const SET_VRING_KICK: u32 = 12;
+pub const SET_VRING_CALL: u32 = 13;
+pub const SET_VRING_ERR: u32 = 14;
+pub const GET_PROTOCOL_FEATURES: u32 = 15;
+pub const SET_PROTOCOL_FEATURES: u32 = 16;
+pub const GET_QUEUE_NUM: u32 = 17;
+pub const SET_VRING_ENABLE: u32 = 18;
+
+/// Feature bit telling the frontend the backend supports protocol features.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+
+pub const PROTOCOL_F_MQ: u64 = 1 << 0;
+pub const PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+
+const VERSION: u32 = 0x1;
+const FLAG_REPLY: u32 = 0x4;
+pub const FLAG_NEED_REPLY: u32 = 0x8;
+
+/// Set in the payload of SET_VRING_KICK and SET_VRING_CALL when no file
+/// descriptor is sent along with it.
+pub const VRING_NOFD_MASK: u64 = 0x100;
+pub const VRING_INDEX_MASK: u64 = 0xff;
+
+pub const MAX_REGIONS: usize = 8;
+const MAX_PAYLOAD_SIZE: usize = 4096;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Header {
+    pub request: u32,
+    pub flags: u32,
+    pub size: u32,
+}
+
+// SAFETY: only plain old data.
+unsafe impl ByteValued for Header {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub mmap_offset: u64,
+}
+
+// SAFETY: only plain old data.
+unsafe impl ByteValued for MemoryRegion {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VringState {
+    pub index: u32,
+    pub num: u32,
+}
+
+// SAFETY: only plain old data.
+unsafe impl ByteValued for VringState {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VringAddr {
+    pub index: u32,
+    pub flags: u32,
+    pub desc: u64,
+    pub used: u64,
+    pub avail: u64,
+    pub log: u64,
+}
+
+// SAFETY: only plain old data.
+unsafe impl ByteValued for VringAddr {}
+
+pub struct Message {
+    pub header: Header,
+    pub payload: Vec<u8>,
+    pub files: Vec<File>,
+}
+
+impl Message {
+    /// Decodes the payload as `T`.
+    pub fn payload<T: ByteValued>(&self) -> Result<T> {
+        self.payload_at(0)
+    }
+
+    pub fn payload_at<T: ByteValued>(&self, offset: usize) -> Result<T> {
+        let end = offset + std::mem::size_of::<T>();
+        if self.payload.len() < end {
+            return Err(Error::InvalidMessage);
+        }
+        T::from_slice(&self.payload[offset..end])
+            .copied()
+            .ok_or(Error::InvalidMessage)
+    }
+
+    /// Decodes the memory regions of a SET_MEM_TABLE payload.
+    pub fn memory_regions(&self) -> Result<Vec<MemoryRegion>> {
+        let num_regions: u32 = self.payload()?;
+        // The number of regions is followed by 4 bytes of padding.
+        let size = std::mem::size_of::<MemoryRegion>();
+        (0..num_regions as usize)
+            .map(|i| self.payload_at(8 + i * size))
+            .collect()
+    }
+}
+
+/// Receives a message from the frontend, along with the file descriptors
+/// sent with it. Returns `None` if the frontend closed the connection.
+pub fn recv(stream: &mut UnixStream) -> Result<Option<Message>> {
+    let mut header = Header::default();
+    let mut files = Vec::new();
+    {
+        let mut iov = [IoSliceMut::new(header.as_mut_slice())];
+        let mut cmsg = cmsg_space!([RawFd; MAX_REGIONS]);
+        let msg = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .map_err(Error::Recv)?;
+        if msg.bytes == 0 {
+            return Ok(None);
+        }
+        for cmsg in msg.cmsgs().map_err(Error::Recv)? {
+            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                // SAFETY: the descriptors were just received and are owned by us.
+                files.extend(fds.into_iter().map(|fd| unsafe { File::from_raw_fd(fd) }));
+            }
+        }
+        if msg.bytes != std::mem::size_of::<Header>() {
+            return Err(Error::InvalidMessage);
+        }
+    }
+
+    let size = header.size as usize;
+    if size > MAX_PAYLOAD_SIZE {
+        return Err(Error::InvalidMessage);
+    }
+    let mut payload = vec![0u8; size];
+    stream.read_exact(&mut payload).map_err(Error::Socket)?;
+
+    Ok(Some(Message {
+        header,
+        payload,
+        files,
+    }))
+}
+
+/// Sends the reply to `request`.
+pub fn reply<T: ByteValued>(stream: &mut UnixStream, request: u32, payload: &T) -> Result<()> {
+    let header = Header {
+        request,
+        flags: VERSION | FLAG_REPLY,
+        size: std::mem::size_of::<T>() as u32,
+    };
+    let mut buf = header.as_slice().to_vec();
+    buf.extend_from_slice(payload.as_slice());
+    stream.write_all(&buf).map_err(Error::Socket)
+}
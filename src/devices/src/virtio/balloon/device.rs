@@ -1,15 +1,18 @@
-use std::cmp;
 use std::convert::TryInto;
-use std::io::Write;
+use std::thread::{self, JoinHandle};
 
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use serde::{Deserialize, Serialize};
 use utils::eventfd::EventFd;
-use vm_memory::{ByteValued, GuestMemory, GuestMemoryMmap};
+use vm_memory::{ByteValued, Bytes, GuestMemory, GuestMemoryMmap};
 
 use super::super::{
-    ActivateError, ActivateResult, BalloonError, DeviceState, Queue as VirtQueue, VirtioDevice,
+    ActivateError, ActivateResult, BalloonError, ConfigLayout, DeviceState, Queue as VirtQueue,
+    VirtioDevice,
 };
+use super::pressure::{MemoryPressureConfig, MemoryStats, PressureMonitor};
 use super::{defs, defs::uapi};
-use crate::virtio::InterruptTransport;
+use crate::virtio::{persist, InterruptTransport, PersistError};
 
 // Inflate queue.
 pub(crate) const IFQ_INDEX: usize = 0;
@@ -24,6 +27,7 @@ pub(crate) const FRQ_INDEX: usize = 4;
 
 // Supported features.
 pub(crate) const AVAIL_FEATURES: u64 = (1 << uapi::VIRTIO_F_VERSION_1 as u64)
+    | (1 << uapi::VIRTIO_F_RING_PACKED as u64)
     | (1 << uapi::VIRTIO_BALLOON_F_STATS_VQ as u64)
     | (1 << uapi::VIRTIO_BALLOON_F_FREE_PAGE_HINT as u64)
     | (1 << uapi::VIRTIO_BALLOON_F_REPORTING as u64);
@@ -44,6 +48,19 @@ pub struct VirtioBalloonConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioBalloonConfig {}
 
+/// The size of the statistics of the driver, one `struct virtio_balloon_stat`
+/// per tag.
+const MAX_STATS_SIZE: usize = uapi::VIRTIO_BALLOON_S_NR * MemoryStats::STAT_SIZE;
+
+const CONFIG_LAYOUT: ConfigLayout = ConfigLayout::new("balloon", &[4, 4, 4, 4]);
+const _: () = assert!(CONFIG_LAYOUT.size() == std::mem::size_of::<VirtioBalloonConfig>());
+
+/// The statistics buffer held, which the driver is waiting for.
+#[derive(Serialize, Deserialize)]
+struct BalloonState {
+    stats_desc_index: Option<u16>,
+}
+
 pub struct Balloon {
     pub(crate) queues: Vec<VirtQueue>,
     pub(crate) queue_events: Vec<EventFd>,
@@ -51,6 +68,15 @@ pub struct Balloon {
     pub(crate) acked_features: u64,
     pub(crate) activate_evt: EventFd,
     pub(crate) device_state: DeviceState,
+    pub(crate) stats_evt: EventFd,
+    // The thread signaling `stats_evt` while the device is active, stopped
+    // when the sender is dropped.
+    stats_thread: Option<(Sender<()>, JoinHandle<()>)>,
+    // Buffer last filled in by the driver with its statistics, which is held
+    // until we want the driver to refresh them.
+    stats_desc_index: Option<u16>,
+    stats: MemoryStats,
+    pressure: Option<PressureMonitor>,
     config: VirtioBalloonConfig,
 }
 
@@ -72,6 +98,11 @@ impl Balloon {
             activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
                 .map_err(BalloonError::EventFd)?,
             device_state: DeviceState::Inactive,
+            stats_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
+            stats_thread: None,
+            stats_desc_index: None,
+            stats: MemoryStats::default(),
+            pressure: None,
             config,
         })
     }
@@ -88,6 +119,78 @@ impl Balloon {
         defs::BALLOON_DEV_ID
     }
 
+    /// Enables periodically polling the guest for its memory statistics and
+    /// notifying the embedder when it is under memory pressure.
+    pub fn set_memory_pressure(&mut self, config: MemoryPressureConfig) {
+        self.pressure = Some(PressureMonitor::new(config));
+    }
+
+    pub fn process_stq(&mut self) -> bool {
+        debug!("balloon: process_stq()");
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem, _) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+        let mut have_stats = false;
+
+        while let Some(head) = self.queues[STQ_INDEX].pop(mem) {
+            // The driver is only expected to have a single buffer in flight,
+            // give back any other one we may be holding.
+            if let Some(index) = self.stats_desc_index.take() {
+                have_used = true;
+                if let Err(e) = self.queues[STQ_INDEX].add_used(mem, index, 0) {
+                    error!("failed to add used elements to the queue: {e:?}");
+                }
+            }
+
+            let index = head.index;
+            for desc in head.into_iter() {
+                if desc.len as usize > MAX_STATS_SIZE {
+                    error!("balloon: stats buffer too large ({} bytes)", desc.len);
+                    continue;
+                }
+                let mut buf = vec![0u8; desc.len as usize];
+                match mem.read_slice(&mut buf, desc.addr) {
+                    Ok(()) => {
+                        self.stats.parse(&buf);
+                        have_stats = true;
+                    }
+                    Err(e) => error!("balloon: failed to read stats: {e:?}"),
+                }
+            }
+            self.stats_desc_index = Some(index);
+        }
+
+        if have_stats {
+            if let Some(pressure) = &mut self.pressure {
+                pressure.update(&self.stats);
+            }
+        }
+
+        have_used
+    }
+
+    /// Hands the stats buffer back to the driver, which makes it send
+    /// updated statistics.
+    pub fn request_stats(&mut self) -> bool {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem, _) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let Some(index) = self.stats_desc_index.take() else {
+            return false;
+        };
+        if let Err(e) = self.queues[STQ_INDEX].add_used(mem, index, 0) {
+            error!("failed to add used elements to the queue: {e:?}");
+        }
+        true
+    }
+
     pub fn process_frq(&mut self) -> bool {
         debug!("balloon: process_frq()");
         let mem = match self.device_state {
@@ -158,26 +261,12 @@ impl VirtioDevice for Balloon {
         &self.queue_events
     }
 
-    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_slice = self.config.as_slice();
-        let config_len = config_slice.len() as u64;
-        if offset >= config_len {
-            error!("Failed to read config space");
-            return;
-        }
-        if let Some(end) = offset.checked_add(data.len() as u64) {
-            // This write can't fail, offset and end are checked against config_len.
-            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
-                .unwrap();
-        }
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        CONFIG_LAYOUT.read(self.config.as_slice(), offset, data);
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        warn!(
-            "balloon: guest driver attempted to write device config (offset={:x}, len={:x})",
-            offset,
-            data.len()
-        );
+        CONFIG_LAYOUT.write_read_only(offset, data);
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
@@ -195,6 +284,31 @@ impl VirtioDevice for Balloon {
             return Err(ActivateError::BadActivate);
         }
 
+        if self.pressure.is_some() {
+            let stats_evt = self.stats_evt.try_clone().map_err(|e| {
+                error!("Cannot clone stats_evt: {e:?}");
+                ActivateError::BadActivate
+            })?;
+            let (stop, stopped) = bounded(0);
+            let thread = thread::Builder::new()
+                .name("balloon stats".into())
+                .spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) =
+                        stopped.recv_timeout(defs::STATS_INTERVAL)
+                    {
+                        if let Err(e) = stats_evt.write(1) {
+                            error!("Failed to write to balloon stats_evt: {e:?}");
+                            break;
+                        }
+                    }
+                })
+                .map_err(|e| {
+                    error!("Cannot spawn balloon stats thread: {e:?}");
+                    ActivateError::BadActivate
+                })?;
+            self.stats_thread = Some((stop, thread));
+        }
+
         self.device_state = DeviceState::Activated(mem, interrupt);
 
         Ok(())
@@ -203,4 +317,82 @@ impl VirtioDevice for Balloon {
     fn is_activated(&self) -> bool {
         self.device_state.is_activated()
     }
+
+    fn reset(&mut self) -> bool {
+        if let Some((stop, thread)) = self.stats_thread.take() {
+            drop(stop);
+            if let Err(e) = thread.join() {
+                error!("error waiting for the balloon stats thread: {e:?}");
+            }
+        }
+        self.stats_desc_index = None;
+        self.device_state = DeviceState::Inactive;
+        true
+    }
+
+    fn save_device_state(&self) -> Vec<u8> {
+        persist::encode_device_state(&BalloonState {
+            stats_desc_index: self.stats_desc_index,
+        })
+    }
+
+    fn restore_device_state(&mut self, state: &[u8]) -> Result<(), PersistError> {
+        let state: BalloonState = persist::decode_device_state(state)?;
+        self.stats_desc_index = state.stats_desc_index;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::test_utils::{guest_memory, Buffer, TestInterrupt, TestQueue};
+
+    fn stat(tag: u16, val: u64) -> Vec<u8> {
+        let mut stat = tag.to_le_bytes().to_vec();
+        stat.extend_from_slice(&val.to_le_bytes());
+        stat
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut balloon = Balloon::new().unwrap();
+        balloon.set_memory_pressure(MemoryPressureConfig {
+            threshold_pct: 10,
+            callback: Arc::new(|_| {}),
+        });
+        let mem = guest_memory();
+        let mut stq = TestQueue::new(&mem, GuestAddress(0), 16);
+        balloon.queues[STQ_INDEX] = stq.create_queue();
+        let interrupt = TestInterrupt::new();
+
+        for _ in 0..2 {
+            balloon
+                .activate(mem.clone(), interrupt.transport())
+                .unwrap();
+            assert!(balloon.stats_thread.is_some());
+
+            // Buffers larger than all the statistics aren't read.
+            let mut stats = stat(uapi::VIRTIO_BALLOON_S_MEMTOT, 1 << 30);
+            stats.resize(MAX_STATS_SIZE + MemoryStats::STAT_SIZE, 0);
+            stq.add_chain(&[Buffer::Readable(&stats)]);
+            balloon.process_stq();
+            assert_eq!(balloon.stats.total, 0);
+
+            stats.truncate(MemoryStats::STAT_SIZE);
+            stq.add_chain(&[Buffer::Readable(&stats)]);
+            balloon.process_stq();
+            assert_eq!(balloon.stats.total, 1 << 30);
+            balloon.stats = MemoryStats::default();
+
+            // The stats thread is stopped on reset, before being started again.
+            assert!(balloon.reset());
+            assert!(balloon.stats_thread.is_none());
+            assert!(!balloon.is_activated());
+        }
+    }
 }
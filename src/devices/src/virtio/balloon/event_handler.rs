@@ -36,7 +36,7 @@ impl Balloon {
     }
 
     pub(crate) fn handle_stq_event(&mut self, event: &EpollEvent) {
-        debug!("balloon: stats queue event (ignored)");
+        debug!("balloon: stats queue event");
 
         let event_set = event.event_set();
         if event_set != EventSet::IN {
@@ -46,6 +46,24 @@ impl Balloon {
 
         if let Err(e) = self.queue_events[STQ_INDEX].read() {
             error!("Failed to read balloon stats queue event: {e:?}");
+        } else if self.process_stq() {
+            self.device_state.signal_used_queue();
+        }
+    }
+
+    pub(crate) fn handle_stats_timer_event(&mut self, event: &EpollEvent) {
+        debug!("balloon: stats timer event");
+
+        let event_set = event.event_set();
+        if event_set != EventSet::IN {
+            warn!("balloon: stats timer unexpected event {event_set:?}");
+            return;
+        }
+
+        if let Err(e) = self.stats_evt.read() {
+            error!("Failed to read balloon stats timer event: {e:?}");
+        } else if self.request_stats() {
+            self.device_state.signal_used_queue();
         }
     }
 
@@ -156,6 +174,16 @@ impl Balloon {
                 error!("Failed to register balloon frq with event manager: {e:?}");
             });
 
+        event_manager
+            .register(
+                self.stats_evt.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, self.stats_evt.as_raw_fd() as u64),
+                self_subscriber.clone(),
+            )
+            .unwrap_or_else(|e| {
+                error!("Failed to register balloon stats timer with event manager: {e:?}");
+            });
+
         event_manager
             .unregister(self.activate_evt.as_raw_fd())
             .unwrap_or_else(|e| {
@@ -172,6 +200,7 @@ impl Subscriber for Balloon {
         let stq = self.queue_events[STQ_INDEX].as_raw_fd();
         let phq = self.queue_events[PHQ_INDEX].as_raw_fd();
         let frq = self.queue_events[FRQ_INDEX].as_raw_fd();
+        let stats_evt = self.stats_evt.as_raw_fd();
         let activate_evt = self.activate_evt.as_raw_fd();
 
         if self.is_activated() {
@@ -181,6 +210,7 @@ impl Subscriber for Balloon {
                 _ if source == stq => self.handle_stq_event(event),
                 _ if source == phq => self.handle_phq_event(event),
                 _ if source == frq => self.handle_frq_event(event),
+                _ if source == stats_evt => self.handle_stats_timer_event(event),
                 _ if source == activate_evt => {
                     self.handle_activate_event(event_manager);
                 }
@@ -1,20 +1,29 @@
 mod device;
 mod event_handler;
+mod pressure;
 
 pub use self::defs::uapi::VIRTIO_ID_BALLOON as TYPE_BALLOON;
 pub use self::device::Balloon;
+pub use self::pressure::{MemoryPressureCallback, MemoryPressureConfig, MemoryStats};
 
 mod defs {
     pub const BALLOON_DEV_ID: &str = "virtio_balloon";
     pub const NUM_QUEUES: usize = 5;
     pub const QUEUE_SIZES: &[u16] = &[256; NUM_QUEUES];
+    /// How often the guest is asked for fresh memory statistics.
+    pub const STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
 
     pub mod uapi {
         pub const VIRTIO_F_VERSION_1: u32 = 32;
+        pub const VIRTIO_F_RING_PACKED: u32 = 34;
         pub const VIRTIO_ID_BALLOON: u32 = 5;
         pub const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1;
         pub const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u32 = 3;
         pub const VIRTIO_BALLOON_F_REPORTING: u32 = 5;
+        pub const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+        pub const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+        pub const VIRTIO_BALLOON_S_OOM_KILL: u16 = 10;
+        pub const VIRTIO_BALLOON_S_NR: usize = 16;
     }
 }
 
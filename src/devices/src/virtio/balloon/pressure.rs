@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use super::defs::uapi;
+
+/// Guest memory statistics, as reported by the driver through the stats
+/// queue. Values the driver didn't report are left as zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Total amount of memory, in bytes.
+    pub total: u64,
+    /// Amount of memory available for starting new applications, in bytes.
+    pub available: u64,
+    /// Number of times the guest OOM killer has been invoked.
+    pub oom_kills: u64,
+}
+
+impl MemoryStats {
+    /// Size of a `struct virtio_balloon_stat`: a le16 tag followed by a le64
+    /// value, packed.
+    pub(crate) const STAT_SIZE: usize = 10;
+
+    /// Updates the statistics from a buffer holding an array of
+    /// `struct virtio_balloon_stat`.
+    pub(crate) fn parse(&mut self, buf: &[u8]) {
+        for stat in buf.chunks_exact(Self::STAT_SIZE) {
+            let tag = u16::from_le_bytes([stat[0], stat[1]]);
+            let val = u64::from_le_bytes(stat[2..].try_into().unwrap());
+            match tag {
+                uapi::VIRTIO_BALLOON_S_MEMTOT => self.total = val,
+                uapi::VIRTIO_BALLOON_S_AVAIL => self.available = val,
+                uapi::VIRTIO_BALLOON_S_OOM_KILL => self.oom_kills = val,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Function called when the guest enters memory pressure or the guest OOM
+/// killer has been invoked.
+pub type MemoryPressureCallback = Arc<dyn Fn(&MemoryStats) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct MemoryPressureConfig {
+    /// The guest is considered under pressure when the available memory
+    /// drops below this percentage of the total memory.
+    pub threshold_pct: u32,
+    pub callback: MemoryPressureCallback,
+}
+
+pub(crate) struct PressureMonitor {
+    config: MemoryPressureConfig,
+    under_pressure: bool,
+    oom_kills: u64,
+}
+
+impl PressureMonitor {
+    pub(crate) fn new(config: MemoryPressureConfig) -> Self {
+        PressureMonitor {
+            config,
+            under_pressure: false,
+            oom_kills: 0,
+        }
+    }
+
+    /// Returns whether the embedder needs to be notified about `stats`. This
+    /// happens when the available memory drops below the threshold, but not
+    /// again until it has gone above it, and every time the OOM killer has
+    /// been invoked since the last report.
+    fn needs_notification(&mut self, stats: &MemoryStats) -> bool {
+        let under_pressure = stats.total != 0
+            && stats.available * 100 < stats.total * u64::from(self.config.threshold_pct);
+        let new_oom_kills = stats.oom_kills > self.oom_kills;
+
+        let notify = (under_pressure && !self.under_pressure) || new_oom_kills;
+        self.under_pressure = under_pressure;
+        self.oom_kills = stats.oom_kills;
+        notify
+    }
+
+    pub(crate) fn update(&mut self, stats: &MemoryStats) {
+        debug!("balloon: guest memory stats {stats:?}");
+        if self.needs_notification(stats) {
+            (self.config.callback)(stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(tag: u16, val: u64) -> Vec<u8> {
+        let mut buf = tag.to_le_bytes().to_vec();
+        buf.extend_from_slice(&val.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut buf = stat(uapi::VIRTIO_BALLOON_S_MEMTOT, 1 << 30);
+        buf.extend(stat(uapi::VIRTIO_BALLOON_S_AVAIL, 1 << 20));
+        buf.extend(stat(0, 42));
+        buf.extend(stat(uapi::VIRTIO_BALLOON_S_OOM_KILL, 2));
+
+        let mut stats = MemoryStats::default();
+        stats.parse(&buf);
+        assert_eq!(
+            stats,
+            MemoryStats {
+                total: 1 << 30,
+                available: 1 << 20,
+                oom_kills: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_needs_notification() {
+        let mut monitor = PressureMonitor::new(MemoryPressureConfig {
+            threshold_pct: 10,
+            callback: Arc::new(|_| {}),
+        });
+        let mut stats = MemoryStats {
+            total: 1000,
+            available: 500,
+            oom_kills: 0,
+        };
+        assert!(!monitor.needs_notification(&stats));
+
+        stats.available = 50;
+        assert!(monitor.needs_notification(&stats));
+        assert!(!monitor.needs_notification(&stats));
+
+        stats.oom_kills = 1;
+        assert!(monitor.needs_notification(&stats));
+
+        stats.available = 500;
+        assert!(!monitor.needs_notification(&stats));
+        stats.available = 50;
+        assert!(monitor.needs_notification(&stats));
+    }
+}
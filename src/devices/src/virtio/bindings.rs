@@ -22,6 +22,9 @@ pub const LINUX_O_RSYNC: libc::c_int = 1052672;
 pub const LINUX_O_DSYNC: libc::c_int = 4096;
 pub const LINUX_O_ASYNC: libc::c_int = 0x2000;
 
+pub const LINUX_FALLOC_FL_KEEP_SIZE: libc::c_int = 0x01;
+pub const LINUX_FALLOC_FL_PUNCH_HOLE: libc::c_int = 0x02;
+
 pub const LINUX_RENAME_NOREPLACE: libc::c_int = 1 << 0;
 pub const LINUX_RENAME_EXCHANGE: libc::c_int = 1 << 1;
 pub const LINUX_RENAME_WHITEOUT: libc::c_int = 1 << 2;
@@ -8,30 +8,39 @@
 use std::cmp;
 use std::convert::From;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::io;
 #[cfg(target_os = "linux")]
 use std::os::linux::fs::MetadataExt;
 #[cfg(target_os = "macos")]
 use std::os::macos::fs::MetadataExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
-use std::sync::Arc;
-use std::thread::JoinHandle;
+use std::sync::{Arc, Mutex};
 
 use imago::file::File as ImagoFile;
 use imago::qcow2::Qcow2;
 use imago::SyncFormatAccess;
 use log::{error, warn};
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
+use utils::worker_pool::PoolTask;
 use virtio_bindings::{
-    virtio_blk::*, virtio_config::VIRTIO_F_VERSION_1, virtio_ring::VIRTIO_RING_F_EVENT_IDX,
+    virtio_blk::*,
+    virtio_config::{VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1},
+    virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC},
 };
 use vm_memory::{ByteValued, GuestMemoryMmap};
 
+#[cfg(feature = "http")]
+use super::http::HttpDisk;
+#[cfg(feature = "luks")]
+use super::luks2::Luks2;
+#[cfg(feature = "verity")]
+use super::verity::Verity;
 use super::worker::BlockWorker;
+use super::zoned::Zones;
 use super::{
-    super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK},
-    Error, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
+    super::{ActivateResult, ConfigLayout, DeviceState, Queue, VirtioDevice, TYPE_BLOCK},
+    Error, QUEUE_SIZE, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
 };
 
 use crate::virtio::{block::ImageType, ActivateError, InterruptTransport};
@@ -67,6 +76,13 @@ pub(crate) struct DiskProperties {
     pub(crate) file: Arc<SyncFormatAccess<ImagoFile>>,
     nsectors: u64,
     image_id: Vec<u8>,
+    #[cfg(feature = "http")]
+    http: Option<Arc<HttpDisk>>,
+    #[cfg(feature = "luks")]
+    crypt: Option<Arc<Luks2>>,
+    #[cfg(feature = "verity")]
+    verity: Option<Arc<Verity>>,
+    zones: Option<Arc<Mutex<Zones>>>,
 }
 
 impl DiskProperties {
@@ -91,9 +107,68 @@ impl DiskProperties {
             nsectors: disk_size >> SECTOR_SHIFT,
             image_id: disk_image_id,
             file: disk_image,
+            #[cfg(feature = "http")]
+            http: None,
+            #[cfg(feature = "luks")]
+            crypt: None,
+            #[cfg(feature = "verity")]
+            verity: None,
+            zones: None,
         })
     }
 
+    /// Makes the disk a cache of the remote image `http`.
+    #[cfg(feature = "http")]
+    pub fn with_http(mut self, http: Option<Arc<HttpDisk>>) -> Self {
+        self.http = http;
+        self
+    }
+
+    #[cfg(feature = "http")]
+    pub fn http(&self) -> Option<&HttpDisk> {
+        self.http.as_deref()
+    }
+
+    /// Makes the disk the plaintext of the unlocked volume `crypt`.
+    #[cfg(feature = "luks")]
+    pub fn with_crypt(mut self, crypt: Option<Arc<Luks2>>) -> Self {
+        if let Some(crypt) = &crypt {
+            self.nsectors = crypt.size() >> SECTOR_SHIFT;
+        }
+        self.crypt = crypt;
+        self
+    }
+
+    #[cfg(feature = "luks")]
+    pub fn crypt(&self) -> Option<&Luks2> {
+        self.crypt.as_deref()
+    }
+
+    /// Makes the reads of the disk checked by the hash tree `verity`.
+    #[cfg(feature = "verity")]
+    pub fn with_verity(mut self, verity: Option<Arc<Verity>>) -> Self {
+        if let Some(verity) = &verity {
+            self.nsectors = verity.size() >> SECTOR_SHIFT;
+        }
+        self.verity = verity;
+        self
+    }
+
+    #[cfg(feature = "verity")]
+    pub fn verity(&self) -> Option<&Verity> {
+        self.verity.as_deref()
+    }
+
+    /// Makes the disk a zoned device, split in `zones`.
+    pub fn with_zones(mut self, zones: Option<Arc<Mutex<Zones>>>) -> Self {
+        self.zones = zones;
+        self
+    }
+
+    pub fn zones(&self) -> Option<&Mutex<Zones>> {
+        self.zones.as_deref()
+    }
+
     pub fn file(&self) -> &SyncFormatAccess<ImagoFile> {
         self.file.as_ref()
     }
@@ -160,17 +235,51 @@ impl Drop for DiskProperties {
     }
 }
 
+/// Maximum number of segments in a request, leaving room in the queue for
+/// the request header and status descriptors.
+fn seg_max(queue_size: u16) -> u32 {
+    queue_size as u32 - 2
+}
+/// Maximum number of segments in a request when the driver uses indirect
+/// descriptors.
+const SEG_MAX_INDIRECT: u32 = 1024;
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C, packed)]
 struct VirtioBlkConfig {
     capacity: u64,
     size_max: u32,
     seg_max: u32,
+    // The geometry, topology, discard, write zeroes and secure erase
+    // characteristics, which aren't offered.
+    _unused: [u32; 14],
+    zone_sectors: u32,
+    max_open_zones: u32,
+    max_active_zones: u32,
+    max_append_sectors: u32,
+    write_granularity: u32,
+    model: u8,
+    _unused2: [u8; 3],
 }
 
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioBlkConfig {}
 
+const CONFIG_LAYOUT: ConfigLayout = ConfigLayout::new(
+    "block",
+    &[
+        8, 4, 4, // capacity, size_max, seg_max
+        2, 1, 1, 4, // geometry, blk_size
+        1, 1, 2, 4, // topology
+        1, 1, 2, // writeback, num_queues
+        4, 4, 4, // discard
+        4, 4, 1, 3, // write zeroes
+        4, 4, 4, // secure erase
+        4, 4, 4, 4, 4, 1, 3, // zoned
+    ],
+);
+const _: () = assert!(CONFIG_LAYOUT.size() == std::mem::size_of::<VirtioBlkConfig>());
+
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block {
     // Host file and properties.
@@ -178,7 +287,14 @@ pub struct Block {
     cache_type: CacheType,
     disk_image: Arc<SyncFormatAccess<ImagoFile>>,
     disk_image_id: Vec<u8>,
-    worker_thread: Option<JoinHandle<()>>,
+    #[cfg(feature = "http")]
+    http: Option<Arc<HttpDisk>>,
+    #[cfg(feature = "luks")]
+    crypt: Option<Arc<Luks2>>,
+    #[cfg(feature = "verity")]
+    verity: Option<Arc<Verity>>,
+    zones: Option<Arc<Mutex<Zones>>>,
+    worker: Option<PoolTask>,
     worker_stopfd: EventFd,
 
     // Virtio fields.
@@ -233,9 +349,11 @@ impl Block {
             DiskProperties::new(Arc::clone(&disk_image), disk_image_id.clone(), cache_type)?;
 
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_F_RING_PACKED)
             | (1u64 << VIRTIO_BLK_F_FLUSH)
             | (1u64 << VIRTIO_BLK_F_SEG_MAX)
-            | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_RING_F_INDIRECT_DESC);
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
@@ -248,8 +366,8 @@ impl Block {
         let config = VirtioBlkConfig {
             capacity: disk_properties.nsectors(),
             size_max: 0,
-            // QUEUE_SIZE - 2
-            seg_max: 254,
+            seg_max: seg_max(QUEUE_SIZE),
+            ..Default::default()
         };
 
         Ok(Block {
@@ -260,16 +378,150 @@ impl Block {
             cache_type,
             disk_image,
             disk_image_id,
+            #[cfg(feature = "http")]
+            http: None,
+            #[cfg(feature = "luks")]
+            crypt: None,
+            #[cfg(feature = "verity")]
+            verity: None,
+            zones: None,
             avail_features,
             acked_features: 0u64,
             queue_evts,
             queues,
             device_state: DeviceState::Inactive,
-            worker_thread: None,
+            worker: None,
             worker_stopfd: EventFd::new(EFD_NONBLOCK)?,
         })
     }
 
+    /// Makes the disk image the cache of `http`, whose chunks are fetched
+    /// as the guest first uses them.
+    #[cfg(feature = "http")]
+    pub fn set_http_disk(&mut self, http: Arc<HttpDisk>) {
+        self.disk = self
+            .disk
+            .take()
+            .map(|disk| disk.with_http(Some(http.clone())));
+        self.http = Some(http);
+    }
+
+    /// Unlocks the disk, a LUKS2 volume, with `passphrase`, so the guest
+    /// sees its plaintext while the disk stays encrypted.
+    pub fn unlock(&mut self, passphrase: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "luks")]
+        {
+            let crypt = Arc::new(Luks2::open(&self.disk_image, passphrase)?);
+            self.config.capacity = crypt.size() >> SECTOR_SHIFT;
+            self.disk = self
+                .disk
+                .take()
+                .map(|disk| disk.with_crypt(Some(crypt.clone())));
+            self.crypt = Some(crypt);
+            Ok(())
+        }
+        #[cfg(not(feature = "luks"))]
+        {
+            let _ = passphrase;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "libkrun was built without LUKS support",
+            ))
+        }
+    }
+
+    /// Checks every read of the disk, which must be read-only, against the
+    /// dm-verity hash tree at `hash_path` whose root hash is `root_hash`.
+    pub fn set_verity(&mut self, hash_path: &Path, root_hash: &[u8]) -> io::Result<()> {
+        if self.avail_features & (1u64 << VIRTIO_BLK_F_RO) == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "dm-verity needs a read-only disk",
+            ));
+        }
+        #[cfg(feature = "verity")]
+        {
+            #[cfg(feature = "luks")]
+            if self.crypt.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "dm-verity over an encrypted disk",
+                ));
+            }
+            let verity = Arc::new(Verity::open(hash_path, root_hash)?);
+            if verity.size() > self.disk_image.size() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the hash tree covers more than the disk",
+                ));
+            }
+            self.config.capacity = verity.size() >> SECTOR_SHIFT;
+            self.disk = self
+                .disk
+                .take()
+                .map(|disk| disk.with_verity(Some(verity.clone())));
+            self.verity = Some(verity);
+            Ok(())
+        }
+        #[cfg(not(feature = "verity"))]
+        {
+            let _ = (hash_path, root_hash);
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "libkrun was built without dm-verity support",
+            ))
+        }
+    }
+
+    /// Makes the disk a host-managed zoned device with zones of
+    /// `zone_size` bytes, keeping their write pointers at `state_path`. At
+    /// most `max_open` zones can be open and `max_active` zones open or
+    /// closed at once, or any number if 0.
+    pub fn set_zoned(
+        &mut self,
+        state_path: &Path,
+        zone_size: u64,
+        max_open: u32,
+        max_active: u32,
+    ) -> io::Result<()> {
+        if self.is_read_only() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "zoned disks can't be read-only",
+            ));
+        }
+        let zone_sectors = zone_size >> SECTOR_SHIFT;
+        if zone_sectors << SECTOR_SHIFT != zone_size || zone_sectors > u32::MAX.into() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid zone size",
+            ));
+        }
+        let zones = Zones::open(
+            state_path,
+            self.config.capacity,
+            zone_sectors,
+            max_open,
+            max_active,
+        )?;
+        self.config.capacity = zones.nsectors();
+        self.config.zone_sectors = zone_sectors as u32;
+        self.config.max_open_zones = max_open;
+        self.config.max_active_zones = max_active;
+        self.config.max_append_sectors = zone_sectors as u32;
+        self.config.write_granularity = SECTOR_SIZE as u32;
+        self.config.model = VIRTIO_BLK_Z_HM as u8;
+        self.avail_features |= 1u64 << VIRTIO_BLK_F_ZONED;
+
+        let zones = Arc::new(Mutex::new(zones));
+        self.disk = self
+            .disk
+            .take()
+            .map(|disk| disk.with_zones(Some(zones.clone())));
+        self.zones = Some(zones);
+        Ok(())
+    }
+
     /// Provides the ID of this block device.
     pub fn id(&self) -> &String {
         &self.id
@@ -303,6 +555,13 @@ impl VirtioDevice for Block {
         &mut self.queues
     }
 
+    fn set_queue_size(&mut self, size: u16) {
+        for queue in self.queues.iter_mut() {
+            queue.set_max_size(size);
+        }
+        self.config.seg_max = seg_max(size);
+    }
+
     fn queue_events(&self) -> &[EventFd] {
         &self.queue_evts
     }
@@ -317,24 +576,22 @@ impl VirtioDevice for Block {
 
     fn set_acked_features(&mut self, acked_features: u64) {
         self.acked_features = acked_features;
+        // The driver reads seg_max after negotiating the features. With
+        // indirect descriptors, requests are no longer bounded by the size
+        // of the queue.
+        self.config.seg_max = if acked_features & (1 << VIRTIO_RING_F_INDIRECT_DESC) != 0 {
+            SEG_MAX_INDIRECT
+        } else {
+            seg_max(self.queues[0].get_max_size())
+        };
     }
 
-    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_slice = self.config.as_slice();
-        let config_len = config_slice.len() as u64;
-        if offset >= config_len {
-            error!("Failed to read config space");
-            return;
-        }
-        if let Some(end) = offset.checked_add(data.len() as u64) {
-            // This write can't fail, offset and end are checked against config_len.
-            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
-                .unwrap();
-        }
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        CONFIG_LAYOUT.read(self.config.as_slice(), offset, data);
     }
 
-    fn write_config(&mut self, _offset: u64, _data: &[u8]) {
-        error!("Guest attempted to write config");
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        CONFIG_LAYOUT.write_read_only(offset, data);
     }
 
     fn is_activated(&self) -> bool {
@@ -342,8 +599,8 @@ impl VirtioDevice for Block {
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
-        if self.worker_thread.is_some() {
-            panic!("virtio_blk: worker thread already exists");
+        if self.worker.is_some() {
+            panic!("virtio_blk: worker already exists");
         }
 
         let event_idx: bool = (self.acked_features & (1 << VIRTIO_RING_F_EVENT_IDX)) != 0;
@@ -358,6 +615,13 @@ impl VirtioDevice for Block {
             )
             .map_err(|_| ActivateError::BadActivate)?,
         };
+        #[cfg(feature = "http")]
+        let disk = disk.with_http(self.http.clone());
+        #[cfg(feature = "luks")]
+        let disk = disk.with_crypt(self.crypt.clone());
+        #[cfg(feature = "verity")]
+        let disk = disk.with_verity(self.verity.clone());
+        let disk = disk.with_zones(self.zones.clone());
 
         let worker = BlockWorker::new(
             self.queues[0].clone(),
@@ -367,17 +631,17 @@ impl VirtioDevice for Block {
             disk,
             self.worker_stopfd.try_clone().unwrap(),
         );
-        self.worker_thread = Some(worker.run());
+        self.worker = Some(worker.run().map_err(ActivateError::EpollCtl)?);
 
         self.device_state = DeviceState::Activated(mem, interrupt);
         Ok(())
     }
 
     fn reset(&mut self) -> bool {
-        if let Some(worker) = self.worker_thread.take() {
+        if let Some(worker) = self.worker.take() {
             let _ = self.worker_stopfd.write(1);
             if let Err(e) = worker.join() {
-                error!("error waiting for worker thread: {e:?}");
+                error!("error waiting for worker: {e:?}");
             }
         }
         self.device_state = DeviceState::Inactive;
@@ -0,0 +1,619 @@
+//! Disks streamed over HTTP, so large images stored in object storage can
+//! boot before they are fully downloaded.
+//!
+//! The guest uses a local cache file, sparse and as large as the remote
+//! image, and the chunks of the image it touches are fetched with range
+//! requests the first time. Which chunks were fetched is kept next to the
+//! cache, so they aren't fetched again when the VM is restarted. Writes of the
+//! guest only ever go to the cache, once the chunks they change were fetched.
+//!
+//! The cache is only reused for the same image, as told by its URL, without
+//! the query that changes with every signature of a pre-signed URL, and by
+//! the `ETag` or `Last-Modified` header of the server. Without either, the
+//! cache is emptied every time. A chunk fetched after the image changed is an
+//! error rather than a mix of the two versions.
+//!
+//! Both `http` and `https` URLs are supported, which includes the pre-signed
+//! URLs of S3 and compatible object stores.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use imago::file::File as ImagoFile;
+use imago::SyncFormatAccess;
+use log::debug;
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+
+const CHUNK_SIZE: u64 = 1 << 20;
+/// The size of the images, so the one the server reports can't make the map
+/// of the chunks too large, 2 MiB at most.
+const MAX_IMAGE_SIZE: u64 = 16 << 40;
+const MAP_MAGIC: &[u8; 8] = b"KRUNHTTP";
+const TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_HEADERS_SIZE: usize = 64 << 10;
+
+fn http_error(msg: String) -> io::Error {
+    io::Error::other(format!("HTTP: {msg}"))
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// The path with the query.
+    target: String,
+}
+
+impl Url {
+    /// The URL without its query.
+    fn identity(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        let path = self.target.split('?').next().unwrap_or_default();
+        format!("{scheme}://{}:{}{path}", self.host, self.port)
+    }
+
+    fn parse(url: &str) -> io::Result<Self> {
+        let bad_url = || io::Error::new(ErrorKind::InvalidInput, "bad HTTP URL");
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(bad_url());
+        };
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(i) if rest.as_bytes()[i] == b'/' => (&rest[..i], rest[i..].to_string()),
+            Some(i) => (&rest[..i], format!("/{}", &rest[i..])),
+            None => (rest, "/".to_string()),
+        };
+        // Bracketed IPv6 addresses have colons of their own.
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => (
+                &authority[..i],
+                authority[i + 1..].parse().map_err(|_| bad_url())?,
+            ),
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() || host.contains('@') {
+            return Err(bad_url());
+        }
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            target,
+        })
+    }
+
+    fn connect(&self) -> io::Result<BufReader<Stream>> {
+        let tcp = TcpStream::connect((self.host.trim_matches(['[', ']']), self.port))?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+        let stream = if self.tls {
+            let connector = SslConnector::builder(SslMethod::tls_client())
+                .map_err(io::Error::other)?
+                .build();
+            let tls = connector
+                .connect(self.host.trim_matches(['[', ']']), tcp)
+                .map_err(|e| http_error(format!("TLS handshake failed: {e}")))?;
+            Stream::Tls(Box::new(tls))
+        } else {
+            Stream::Plain(tcp)
+        };
+        Ok(BufReader::new(stream))
+    }
+}
+
+/// What the server says of the image in its responses.
+#[derive(Debug, PartialEq, Eq)]
+struct Version {
+    size: u64,
+    /// The `ETag` or the `Last-Modified` header.
+    validator: Option<String>,
+}
+
+/// Reads a line of the headers of a response into `line`, `size` being the
+/// size of the headers read so far.
+fn read_header_line(
+    conn: &mut BufReader<Stream>,
+    line: &mut String,
+    size: &mut usize,
+) -> io::Result<()> {
+    let limit = (MAX_HEADERS_SIZE - *size) as u64;
+    let len = conn.take(limit).read_line(line)?;
+    *size += len;
+    if !line.ends_with('\n') {
+        if *size == MAX_HEADERS_SIZE {
+            return Err(http_error("headers too large".to_string()));
+        }
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// A keep-alive connection to the server of an image.
+struct Client {
+    url: Url,
+    conn: Option<BufReader<Stream>>,
+}
+
+impl Client {
+    /// Fetches the bytes at `offset` of the image into `buf`, returning the
+    /// version of the image they are from.
+    fn get_range(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<Version> {
+        // A kept-alive connection may have been closed by the server since.
+        match self.conn.is_some() {
+            true => self.try_get_range(offset, buf).or_else(|e| {
+                debug!("retrying HTTP request on a new connection: {e}");
+                self.conn = None;
+                self.try_get_range(offset, buf)
+            }),
+            false => self.try_get_range(offset, buf),
+        }
+    }
+
+    fn try_get_range(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<Version> {
+        let end = offset + buf.len() as u64 - 1;
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => self.conn.insert(self.url.connect()?),
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nRange: bytes={offset}-{end}\r\n\
+             User-Agent: libkrun\r\nAccept-Encoding: identity\r\n\r\n",
+            self.url.target, self.url.host
+        );
+        conn.get_mut().write_all(request.as_bytes())?;
+        conn.get_mut().flush()?;
+
+        let mut headers_size = 0;
+        let mut status = String::new();
+        read_header_line(conn, &mut status, &mut headers_size)?;
+        let code = status.split(' ').nth(1).unwrap_or_default().to_string();
+        let (mut content_length, mut total, mut close) = (None, None, false);
+        let (mut etag, mut last_modified) = (None, None);
+        loop {
+            let mut line = String::new();
+            read_header_line(conn, &mut line, &mut headers_size)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse::<u64>().ok(),
+                "content-range" => {
+                    total = value
+                        .rsplit_once('/')
+                        .and_then(|(_, total)| total.parse::<u64>().ok())
+                }
+                "transfer-encoding" if !value.eq_ignore_ascii_case("identity") => {
+                    return Err(http_error(format!("unsupported transfer encoding {value}")));
+                }
+                "connection" => close = value.eq_ignore_ascii_case("close"),
+                "etag" => etag = Some(value.to_string()),
+                "last-modified" => last_modified = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        if code != "206" {
+            self.conn = None;
+            return Err(http_error(format!(
+                "unexpected status {}",
+                status.trim_end()
+            )));
+        }
+        let (Some(total), Some(content_length)) = (total, content_length) else {
+            self.conn = None;
+            return Err(http_error("range response without its size".to_string()));
+        };
+        if total > MAX_IMAGE_SIZE {
+            self.conn = None;
+            return Err(http_error(format!("image too large ({total} bytes)")));
+        }
+        // Shorter responses are fine past the end of the image, longer ones
+        // than what was requested never are.
+        let len = buf.len().min(total.saturating_sub(offset) as usize);
+        if content_length != len as u64 {
+            self.conn = None;
+            return Err(http_error("unexpected range".to_string()));
+        }
+        conn.read_exact(&mut buf[..len])?;
+        if close {
+            self.conn = None;
+        }
+        Ok(Version {
+            size: total,
+            validator: etag.or(last_modified),
+        })
+    }
+}
+
+/// The chunks of an image fetched into its cache.
+struct Map {
+    file: File,
+    /// Where `fetched` is in `file`, after the header.
+    offset: u64,
+    fetched: Vec<u8>,
+}
+
+/// A remote image cached in a local sparse file.
+pub struct HttpDisk {
+    client: Mutex<Client>,
+    version: Version,
+    map: Mutex<Map>,
+}
+
+impl HttpDisk {
+    /// Where the chunks of the cache at `cache_path` which were fetched are
+    /// recorded.
+    fn map_path(cache_path: &Path) -> PathBuf {
+        let mut path = cache_path.as_os_str().to_owned();
+        path.push(".chunks");
+        PathBuf::from(path)
+    }
+
+    /// The header of the map of the chunks of the cache of `version` of the
+    /// image at `url`: the magic, the size of the image, the size of the
+    /// chunks, and the length of the identity of the image followed by it.
+    fn map_header(url: &Url, version: &Version) -> Vec<u8> {
+        let identity = format!(
+            "{}\n{}",
+            url.identity(),
+            version.validator.as_deref().unwrap_or_default()
+        );
+        let mut header = MAP_MAGIC.to_vec();
+        header.extend_from_slice(&version.size.to_le_bytes());
+        header.extend_from_slice(&CHUNK_SIZE.to_le_bytes());
+        header.extend_from_slice(&(identity.len() as u32).to_le_bytes());
+        header.extend_from_slice(identity.as_bytes());
+        header
+    }
+
+    /// Prepares the cache at `cache_path` of the image at `url`, creating it
+    /// if needed. A cache of another image, or of a changed one, is emptied.
+    pub fn open(url: &str, cache_path: &Path) -> io::Result<Self> {
+        let mut client = Client {
+            url: Url::parse(url)?,
+            conn: None,
+        };
+        let version = client.get_range(0, &mut [0u8])?;
+        let size = version.size;
+        let chunks = size.div_ceil(CHUNK_SIZE) as usize;
+
+        let cache = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(cache_path)?;
+        let map_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(Self::map_path(cache_path))?;
+
+        let header = Self::map_header(&client.url, &version);
+        let offset = header.len() as u64;
+        let mut fetched = vec![0u8; chunks.div_ceil(8)];
+        let mut existing = vec![0u8; header.len() + fetched.len()];
+        // There's no telling whether an image without a validator changed.
+        let reusable = version.validator.is_some()
+            && map_file.read_exact_at(&mut existing, 0).is_ok()
+            && existing[..header.len()] == header
+            && cache.metadata()?.len() == size;
+        if reusable {
+            fetched.copy_from_slice(&existing[header.len()..]);
+        } else {
+            // Drops whatever the cache held.
+            cache.set_len(0)?;
+            cache.set_len(size)?;
+            cache.sync_all()?;
+            map_file.set_len(0)?;
+            map_file.write_all_at(&header, 0)?;
+            map_file.write_all_at(&fetched, offset)?;
+            map_file.sync_all()?;
+        }
+
+        Ok(Self {
+            client: Mutex::new(client),
+            version,
+            map: Mutex::new(Map {
+                file: map_file,
+                offset,
+                fetched,
+            }),
+        })
+    }
+
+    /// The size of the image.
+    pub fn size(&self) -> u64 {
+        self.version.size
+    }
+
+    /// Makes sure the chunks holding the `len` bytes at `offset` of
+    /// `cache` were fetched, before they are read or written.
+    pub fn fetch(
+        &self,
+        cache: &SyncFormatAccess<ImagoFile>,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let size = self.size();
+        let end = (offset + len as u64).min(size);
+        let mut map = self.map.lock().unwrap();
+        for chunk in offset / CHUNK_SIZE..end.div_ceil(CHUNK_SIZE) {
+            let (byte, bit) = ((chunk / 8) as usize, 1 << (chunk % 8));
+            if map.fetched[byte] & bit != 0 {
+                continue;
+            }
+
+            let start = chunk * CHUNK_SIZE;
+            let mut data = vec![0u8; CHUNK_SIZE.min(size - start) as usize];
+            let version = self.client.lock().unwrap().get_range(start, &mut data)?;
+            if version != self.version {
+                return Err(http_error("the image changed".to_string()));
+            }
+            cache.write(&data[..], start)?;
+            // The chunk must be in the cache before it's recorded, or it
+            // could later be fetched again over the writes of the guest.
+            cache.flush()?;
+            cache.sync()?;
+
+            map.fetched[byte] |= bit;
+            let fetched = map.fetched[byte];
+            let offset = map.offset + byte as u64;
+            map.file.write_all_at(&[fetched], offset)?;
+            map.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use imago::raw::Raw;
+    use utils::tempdir::TempDir;
+
+    /// Serves range requests of `image`, which may be changed, counting them.
+    fn serve(image: Arc<Mutex<Vec<u8>>>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/images/disk.raw?sig=abc",
+            listener.local_addr().unwrap()
+        );
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                loop {
+                    let mut range = None;
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    assert_eq!(line, "GET /images/disk.raw?sig=abc HTTP/1.1\r\n");
+                    loop {
+                        line.clear();
+                        reader.read_line(&mut line).unwrap();
+                        if let Some(value) = line.trim_end().strip_prefix("Range: bytes=") {
+                            let (start, end) = value.split_once('-').unwrap();
+                            range = Some((
+                                start.parse::<usize>().unwrap(),
+                                end.parse::<usize>().unwrap(),
+                            ));
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let image = image.lock().unwrap();
+                    let mut hasher = DefaultHasher::new();
+                    image.hash(&mut hasher);
+                    let (start, end) = range.unwrap();
+                    let end = end.min(image.len() - 1);
+                    let body = &image[start..=end];
+                    let header = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\
+                         Content-Range: bytes {start}-{end}/{}\r\nETag: \"{:x}\"\r\n\r\n",
+                        body.len(),
+                        image.len(),
+                        hasher.finish()
+                    );
+                    let stream = reader.get_mut();
+                    stream.write_all(header.as_bytes()).unwrap();
+                    stream.write_all(body).unwrap();
+                }
+            }
+        });
+        (url, requests)
+    }
+
+    /// Answers every request with `response`.
+    fn serve_response(response: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/disk.raw", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                    line.clear();
+                }
+                // The client may hang up before the end.
+                let _ = reader.get_mut().write_all(&response);
+            }
+        });
+        url
+    }
+
+    fn open_cache(path: &Path) -> SyncFormatAccess<ImagoFile> {
+        SyncFormatAccess::new(
+            Raw::<ImagoFile>::open_path_sync(path.to_str().unwrap(), true).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_url() {
+        let url = Url::parse("https://bucket.s3.amazonaws.com/disk.img?X-Amz-Signature=1").unwrap();
+        assert!(url.tls);
+        assert_eq!(
+            (url.host.as_str(), url.port),
+            ("bucket.s3.amazonaws.com", 443)
+        );
+        assert_eq!(url.target, "/disk.img?X-Amz-Signature=1");
+        let url = Url::parse("http://[::1]:8080").unwrap();
+        assert_eq!(
+            (url.host.as_str(), url.port, url.target.as_str()),
+            ("[::1]", 8080, "/")
+        );
+        assert!(Url::parse("ftp://host/disk").is_err());
+        assert!(Url::parse("http://user@host/disk").is_err());
+    }
+
+    #[test]
+    fn test_http_disk() {
+        let image: Vec<u8> = (0..5 * CHUNK_SIZE / 2).map(|i| (i % 253) as u8).collect();
+        let (url, requests) = serve(Arc::new(Mutex::new(image.clone())));
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("cache.raw");
+
+        let disk = HttpDisk::open(&url, &path).unwrap();
+        assert_eq!(disk.size(), image.len() as u64);
+        let cache = open_cache(&path);
+        let mut buf = vec![0u8; 4096];
+        disk.fetch(&cache, 2 * CHUNK_SIZE + 4096, buf.len())
+            .unwrap();
+        cache.read(&mut buf[..], 2 * CHUNK_SIZE + 4096).unwrap();
+        assert_eq!(buf, image[2 * CHUNK_SIZE as usize + 4096..][..4096]);
+        // Only the size and the one chunk were fetched.
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        disk.fetch(&cache, 2 * CHUNK_SIZE, 512).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // The guest writes over the fetched chunks, which a restart keeps.
+        cache.write(&[0xffu8; 512][..], 2 * CHUNK_SIZE).unwrap();
+        drop(disk);
+        let disk = HttpDisk::open(&url, &path).unwrap();
+        disk.fetch(&cache, 2 * CHUNK_SIZE, 512).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        cache.read(&mut buf[..], 2 * CHUNK_SIZE).unwrap();
+        assert_eq!(buf[..512], [0xff; 512]);
+
+        disk.fetch(&cache, 0, image.len()).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+        let mut all = vec![0u8; image.len()];
+        cache.read(&mut all[..], 0).unwrap();
+        assert_eq!(
+            all[..2 * CHUNK_SIZE as usize],
+            image[..2 * CHUNK_SIZE as usize]
+        );
+    }
+
+    #[test]
+    fn test_changed_image() {
+        let image = Arc::new(Mutex::new(vec![1u8; 2 * CHUNK_SIZE as usize]));
+        let (url, _) = serve(image.clone());
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("cache.raw");
+        let mut buf = vec![0u8; 512];
+
+        let disk = HttpDisk::open(&url, &path).unwrap();
+        let cache = open_cache(&path);
+        disk.fetch(&cache, 0, buf.len()).unwrap();
+        // The image changes, keeping its size, while it's in use.
+        image.lock().unwrap().fill(2);
+        assert!(disk.fetch(&cache, CHUNK_SIZE, buf.len()).is_err());
+
+        // The cache of the old version isn't reused.
+        drop(disk);
+        let disk = HttpDisk::open(&url, &path).unwrap();
+        disk.fetch(&cache, 0, 2 * CHUNK_SIZE as usize).unwrap();
+        cache.read(&mut buf[..], 0).unwrap();
+        assert_eq!(buf, [2; 512]);
+
+        // Nor is the cache of another image, even with the same content.
+        let (other_url, requests) = serve(image);
+        drop(disk);
+        let disk = HttpDisk::open(&other_url, &path).unwrap();
+        disk.fetch(&cache, 0, buf.len()).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_bad_responses() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("cache.raw");
+
+        let mut response = b"HTTP/1.1 206 Partial Content\r\nX-Padding: ".to_vec();
+        response.resize(4 * MAX_HEADERS_SIZE, b'a');
+        let err = HttpDisk::open(&serve_response(response), &path)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("headers too large"), "{err}");
+
+        let response = format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Length: 1\r\n\
+             Content-Range: bytes 0-0/{}\r\n\r\n\0",
+            u64::MAX
+        );
+        let err = HttpDisk::open(&serve_response(response.into_bytes()), &path)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("image too large"), "{err}");
+    }
+}
@@ -0,0 +1,866 @@
+//! Host-side decryption of LUKS2 volumes, so data volumes stay encrypted at
+//! rest without trusting the guest to set up dm-crypt.
+//!
+//! The volume is unlocked with a passphrase, through any of its keyslots,
+//! and the guest only ever sees the plaintext of its data segment. Only the
+//! `aes-xts-plain64` cipher is supported, which is what `cryptsetup` uses by
+//! default, with keyslots derived by PBKDF2, Argon2i or Argon2id.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{compiler_fence, Ordering};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use imago::file::File as ImagoFile;
+use imago::SyncFormatAccess;
+use log::warn;
+use openssl::cipher::{Cipher, CipherRef};
+use openssl::cipher_ctx::CipherCtx;
+use openssl::hash::{self, MessageDigest};
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use serde::Deserialize;
+use vm_memory::VolatileSlice;
+
+const MAGIC: &[u8] = b"LUKS\xba\xbe";
+const SECONDARY_MAGIC: &[u8] = b"SKUL\xba\xbe";
+const BINARY_HEADER_SIZE: usize = 4096;
+/// Where the secondary header may be, depending on the size of the headers.
+const SECONDARY_OFFSETS: &[u64] = &[
+    0x4000, 0x8000, 0x10000, 0x20000, 0x40000, 0x80000, 0x100000, 0x200000, 0x400000,
+];
+const CHECKSUM_OFFSET: usize = 448;
+/// The key material of the keyslots is always encrypted in 512-byte sectors.
+const KEYSLOT_SECTOR_SIZE: usize = 512;
+
+// Limits on the keyslot parameters read from the header, which is untrusted,
+// so a crafted one can't exhaust the memory of the VMM, or keep it busy for
+// ever, while unlocking it. Those of the sizes match the ones cryptsetup
+// enforces, the iterations are many times what it benchmarks.
+const MAX_KEY_SIZE: usize = 64;
+const MAX_STRIPES: usize = 4000;
+const MAX_PBKDF2_ITERATIONS: usize = 10_000_000;
+const MAX_ARGON2_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MAX_ARGON2_CPUS: u32 = 16;
+const MAX_ARGON2_TIME: u32 = 1000;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("LUKS2: {msg}"))
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, format!("LUKS2: unsupported {what}"))
+}
+
+fn ssl<T>(r: std::result::Result<T, openssl::error::ErrorStack>) -> io::Result<T> {
+    r.map_err(io::Error::other)
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    keyslots: HashMap<String, serde_json::Value>,
+    segments: HashMap<String, Segment>,
+    digests: HashMap<String, Digest>,
+    #[serde(default)]
+    config: Config,
+}
+
+#[derive(Default, Deserialize)]
+struct Config {
+    #[serde(default)]
+    requirements: Requirements,
+}
+
+#[derive(Default, Deserialize)]
+struct Requirements {
+    #[serde(default)]
+    mandatory: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Keyslot {
+    #[serde(rename = "type")]
+    kind: String,
+    key_size: usize,
+    area: Area,
+    kdf: Kdf,
+    af: Af,
+}
+
+#[derive(Deserialize)]
+struct Area {
+    #[serde(rename = "type")]
+    kind: String,
+    offset: String,
+    encryption: String,
+    key_size: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Kdf {
+    Pbkdf2 {
+        hash: String,
+        iterations: usize,
+        salt: String,
+    },
+    Argon2i {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+    Argon2id {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct Af {
+    #[serde(rename = "type")]
+    kind: String,
+    stripes: usize,
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct Segment {
+    #[serde(rename = "type")]
+    kind: String,
+    offset: String,
+    size: String,
+    iv_tweak: String,
+    encryption: String,
+    sector_size: usize,
+}
+
+#[derive(Deserialize)]
+struct Digest {
+    #[serde(rename = "type")]
+    kind: String,
+    keyslots: Vec<String>,
+    segments: Vec<String>,
+    hash: String,
+    iterations: usize,
+    salt: String,
+    digest: String,
+}
+
+fn parse_u64(s: &str) -> io::Result<u64> {
+    s.parse().map_err(|_| invalid("bad number"))
+}
+
+fn base64(s: &str) -> io::Result<Vec<u8>> {
+    BASE64.decode(s).map_err(|_| invalid("bad base64"))
+}
+
+fn message_digest(name: &str) -> io::Result<MessageDigest> {
+    MessageDigest::from_name(name).ok_or_else(|| unsupported("hash"))
+}
+
+/// The AES-XTS cipher of `spec` for keys of `key_size` bytes.
+fn xts_cipher(spec: &str, key_size: usize) -> io::Result<&'static CipherRef> {
+    match (spec, key_size) {
+        ("aes-xts-plain64", 32) => Ok(Cipher::aes_128_xts()),
+        ("aes-xts-plain64", 64) => Ok(Cipher::aes_256_xts()),
+        _ => Err(unsupported("cipher")),
+    }
+}
+
+/// Encrypts or decrypts `buf` in place, in sectors of `sector_size` bytes
+/// whose IVs count up from `first_iv`.
+fn xts(
+    cipher: &CipherRef,
+    key: &[u8],
+    encrypt: bool,
+    buf: &mut [u8],
+    sector_size: usize,
+    first_iv: u64,
+) -> io::Result<()> {
+    let mut ctx = ssl(CipherCtx::new())?;
+    if encrypt {
+        ssl(ctx.encrypt_init(Some(cipher), Some(key), None))?;
+    } else {
+        ssl(ctx.decrypt_init(Some(cipher), Some(key), None))?;
+    }
+    let mut out = vec![0u8; sector_size];
+    for (i, sector) in buf.chunks_mut(sector_size).enumerate() {
+        let mut iv = [0u8; 16];
+        iv[..8].copy_from_slice(&(first_iv + i as u64).to_le_bytes());
+        if encrypt {
+            ssl(ctx.encrypt_init(None, None, Some(&iv)))?;
+        } else {
+            ssl(ctx.decrypt_init(None, None, Some(&iv)))?;
+        }
+        let len = ssl(ctx.cipher_update(sector, Some(&mut out)))?;
+        sector.copy_from_slice(&out[..len]);
+    }
+    Ok(())
+}
+
+/// Mixes `block` with `hash`, the diffusion of the anti-forensic splitter.
+fn diffuse(block: &mut [u8], hash: MessageDigest) -> io::Result<()> {
+    for (i, chunk) in block.chunks_mut(hash.size()).enumerate() {
+        let mut input = (i as u32).to_be_bytes().to_vec();
+        input.extend_from_slice(chunk);
+        let digest = ssl(hash::hash(hash, &input))?;
+        let len = chunk.len();
+        chunk.copy_from_slice(&digest[..len]);
+    }
+    Ok(())
+}
+
+/// Recovers a key of `key_size` bytes from its anti-forensic split in
+/// `stripes`.
+fn af_merge(
+    split: &[u8],
+    key_size: usize,
+    stripes: usize,
+    hash: MessageDigest,
+) -> io::Result<Vec<u8>> {
+    let mut key = vec![0u8; key_size];
+    for (i, stripe) in split.chunks(key_size).take(stripes).enumerate() {
+        key.iter_mut().zip(stripe).for_each(|(k, s)| *k ^= s);
+        if i < stripes - 1 {
+            diffuse(&mut key, hash)?;
+        }
+    }
+    Ok(key)
+}
+
+/// The `len` bytes Argon2 derives from `passphrase` and `salt`.
+fn argon2(
+    algorithm: Algorithm,
+    passphrase: &[u8],
+    salt: &[u8],
+    time: u32,
+    memory: u32,
+    cpus: u32,
+    len: usize,
+) -> io::Result<Vec<u8>> {
+    let params =
+        Params::new(memory, time, cpus, Some(len)).map_err(|_| invalid("bad argon2 parameters"))?;
+    let mut key = vec![0u8; len];
+    Argon2::new(algorithm, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|_| invalid("bad argon2 parameters"))?;
+    Ok(key)
+}
+
+fn wipe(bytes: &mut [u8]) {
+    bytes.fill(0);
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// The checksum-verified JSON metadata of the header at `offset`, with its
+/// sequence number.
+fn read_header(
+    disk: &SyncFormatAccess<ImagoFile>,
+    offset: u64,
+    magic: &[u8],
+) -> io::Result<(u64, Metadata)> {
+    let mut binary = vec![0u8; BINARY_HEADER_SIZE];
+    disk.read(&mut binary[..], offset)?;
+    if &binary[..MAGIC.len()] != magic {
+        return Err(invalid("no header"));
+    }
+    if u16::from_be_bytes([binary[6], binary[7]]) != 2 {
+        return Err(unsupported("version"));
+    }
+    let hdr_size = u64::from_be_bytes(binary[8..16].try_into().unwrap());
+    let seqid = u64::from_be_bytes(binary[16..24].try_into().unwrap());
+    if !SECONDARY_OFFSETS.contains(&hdr_size) {
+        return Err(invalid("bad header size"));
+    }
+    let checksum_alg = &binary[72..104];
+    let checksum_alg = &checksum_alg[..checksum_alg.iter().position(|&b| b == 0).unwrap_or(32)];
+    let checksum_hash = message_digest(std::str::from_utf8(checksum_alg).unwrap_or_default())?;
+
+    let mut header = vec![0u8; hdr_size as usize];
+    disk.read(&mut header[..], offset)?;
+    let checksum = header[CHECKSUM_OFFSET..][..checksum_hash.size()].to_vec();
+    header[CHECKSUM_OFFSET..][..64].fill(0);
+    let computed = ssl(hash::hash(checksum_hash, &header))?;
+    if !memcmp::eq(&computed, &checksum) {
+        return Err(invalid("bad header checksum"));
+    }
+
+    let json = &header[BINARY_HEADER_SIZE..];
+    let json = &json[..json.iter().position(|&b| b == 0).unwrap_or(json.len())];
+    let metadata = serde_json::from_slice(json).map_err(|_| invalid("bad metadata"))?;
+    Ok((seqid, metadata))
+}
+
+/// The metadata of the most recent valid header of the two.
+fn read_metadata(disk: &SyncFormatAccess<ImagoFile>) -> io::Result<Metadata> {
+    let primary = read_header(disk, 0, MAGIC);
+    let secondary = SECONDARY_OFFSETS
+        .iter()
+        .filter(|&&offset| offset < disk.size())
+        .find_map(|&offset| read_header(disk, offset, SECONDARY_MAGIC).ok());
+    match (primary, secondary) {
+        (Ok(primary), Some(secondary)) if secondary.0 > primary.0 => Ok(secondary.1),
+        (Ok(primary), _) => Ok(primary.1),
+        (Err(_), Some(secondary)) => Ok(secondary.1),
+        (Err(e), None) => Err(e),
+    }
+}
+
+/// The key of `keyslot` derived from `passphrase`, which may not be the
+/// right one.
+fn unlock_keyslot(
+    disk: &SyncFormatAccess<ImagoFile>,
+    keyslot: &Keyslot,
+    passphrase: &[u8],
+) -> io::Result<Vec<u8>> {
+    if keyslot.kind != "luks2" || keyslot.af.kind != "luks1" || keyslot.area.kind != "raw" {
+        return Err(unsupported("keyslot"));
+    }
+    if keyslot.af.stripes == 0 {
+        return Err(invalid("bad keyslot"));
+    }
+    if keyslot.key_size > MAX_KEY_SIZE
+        || keyslot.area.key_size > MAX_KEY_SIZE
+        || keyslot.af.stripes > MAX_STRIPES
+    {
+        return Err(unsupported("keyslot size"));
+    }
+    match keyslot.kdf {
+        Kdf::Pbkdf2 { iterations, .. } if iterations > MAX_PBKDF2_ITERATIONS => {
+            return Err(unsupported("pbkdf2 iterations"));
+        }
+        Kdf::Argon2i {
+            time, memory, cpus, ..
+        }
+        | Kdf::Argon2id {
+            time, memory, cpus, ..
+        } if time > MAX_ARGON2_TIME || memory > MAX_ARGON2_MEMORY_KIB || cpus > MAX_ARGON2_CPUS => {
+            return Err(unsupported("argon2 parameters"));
+        }
+        _ => {}
+    }
+    let area_cipher = xts_cipher(&keyslot.area.encryption, keyslot.area.key_size)?;
+
+    let mut area_key = match &keyslot.kdf {
+        Kdf::Pbkdf2 {
+            hash,
+            iterations,
+            salt,
+        } => {
+            let mut key = vec![0u8; keyslot.area.key_size];
+            ssl(pbkdf2_hmac(
+                passphrase,
+                &base64(salt)?,
+                *iterations,
+                message_digest(hash)?,
+                &mut key,
+            ))?;
+            key
+        }
+        Kdf::Argon2i {
+            time,
+            memory,
+            cpus,
+            salt,
+        } => argon2(
+            Algorithm::Argon2i,
+            passphrase,
+            &base64(salt)?,
+            *time,
+            *memory,
+            *cpus,
+            keyslot.area.key_size,
+        )?,
+        Kdf::Argon2id {
+            time,
+            memory,
+            cpus,
+            salt,
+        } => argon2(
+            Algorithm::Argon2id,
+            passphrase,
+            &base64(salt)?,
+            *time,
+            *memory,
+            *cpus,
+            keyslot.area.key_size,
+        )?,
+    };
+
+    let split_len = keyslot
+        .key_size
+        .checked_mul(keyslot.af.stripes)
+        .ok_or_else(|| invalid("bad keyslot"))?;
+    let mut split = vec![0u8; split_len.next_multiple_of(KEYSLOT_SECTOR_SIZE)];
+    let result = disk
+        .read(&mut split[..], parse_u64(&keyslot.area.offset)?)
+        .and_then(|()| {
+            xts(
+                area_cipher,
+                &area_key,
+                false,
+                &mut split,
+                KEYSLOT_SECTOR_SIZE,
+                0,
+            )
+        })
+        .and_then(|()| {
+            af_merge(
+                &split,
+                keyslot.key_size,
+                keyslot.af.stripes,
+                message_digest(&keyslot.af.hash)?,
+            )
+        });
+    wipe(&mut area_key);
+    wipe(&mut split);
+    result
+}
+
+/// Whether `key` is the one `digest` was computed from.
+fn check_digest(digest: &Digest, key: &[u8]) -> io::Result<bool> {
+    if digest.iterations > MAX_PBKDF2_ITERATIONS {
+        return Err(unsupported("pbkdf2 iterations"));
+    }
+    let expected = base64(&digest.digest)?;
+    let mut computed = vec![0u8; expected.len()];
+    ssl(pbkdf2_hmac(
+        key,
+        &base64(&digest.salt)?,
+        digest.iterations,
+        message_digest(&digest.hash)?,
+        &mut computed,
+    ))?;
+    Ok(memcmp::eq(&computed, &expected))
+}
+
+/// An unlocked LUKS2 volume.
+pub struct Luks2 {
+    cipher: &'static CipherRef,
+    key: Vec<u8>,
+    /// Where the data segment starts in the disk.
+    offset: u64,
+    size: u64,
+    sector_size: usize,
+    /// Added to the 512-byte sector numbers the IVs are computed from.
+    iv_tweak: u64,
+}
+
+impl Luks2 {
+    /// Unlocks the LUKS2 volume of `disk` with `passphrase`.
+    pub fn open(disk: &SyncFormatAccess<ImagoFile>, passphrase: &[u8]) -> io::Result<Self> {
+        let metadata = read_metadata(disk)?;
+        if !metadata.config.requirements.mandatory.is_empty() {
+            return Err(unsupported("requirements"));
+        }
+        let [(segment_id, segment)] = &metadata.segments.iter().collect::<Vec<_>>()[..] else {
+            return Err(unsupported("segments"));
+        };
+        if segment.kind != "crypt" || !matches!(segment.sector_size, 512 | 1024 | 2048 | 4096) {
+            return Err(unsupported("segment"));
+        }
+        let offset = parse_u64(&segment.offset)?;
+        let size = match segment.size.as_str() {
+            "dynamic" => disk
+                .size()
+                .checked_sub(offset)
+                .ok_or_else(|| invalid("bad segment"))?,
+            size => parse_u64(size)?,
+        };
+        let size = size - size % segment.sector_size as u64;
+
+        let digests: Vec<_> = metadata
+            .digests
+            .values()
+            .filter(|digest| digest.kind == "pbkdf2" && digest.segments.contains(segment_id))
+            .collect();
+        let mut keyslots: Vec<_> = metadata.keyslots.iter().collect();
+        keyslots.sort_by_key(|(id, _)| id.parse::<u32>().unwrap_or(u32::MAX));
+
+        for (id, keyslot) in keyslots {
+            let Some(digest) = digests.iter().find(|digest| digest.keyslots.contains(id)) else {
+                continue;
+            };
+            // Keyslots of kinds this doesn't know can't hold the key.
+            let Ok(keyslot) = Keyslot::deserialize(keyslot) else {
+                continue;
+            };
+            // A keyslot that can't be used doesn't keep the others from
+            // being tried.
+            let mut key = match unlock_keyslot(disk, &keyslot, passphrase) {
+                Ok(key) => key,
+                Err(e) => {
+                    warn!("Skipping keyslot {id}: {e}");
+                    continue;
+                }
+            };
+            let matches = check_digest(digest, &key).unwrap_or_else(|e| {
+                warn!("Skipping keyslot {id}: {e}");
+                false
+            });
+            if !matches {
+                wipe(&mut key);
+                continue;
+            }
+            return Ok(Self {
+                cipher: xts_cipher(&segment.encryption, key.len())?,
+                key,
+                offset,
+                size,
+                sector_size: segment.sector_size,
+                iv_tweak: parse_u64(&segment.iv_tweak)?,
+            });
+        }
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "LUKS2: no keyslot matches the passphrase",
+        ))
+    }
+
+    /// The size of the plaintext of the volume.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The part of the segment holding the `len` bytes at `offset` of the
+    /// plaintext, in whole sectors, as its offset and length.
+    fn sectors(&self, offset: u64, len: usize) -> io::Result<(u64, usize)> {
+        let end = offset
+            .checked_add(len as u64)
+            .filter(|&end| end <= self.size)
+            .ok_or_else(|| io::Error::from(ErrorKind::InvalidInput))?;
+        let sector_size = self.sector_size as u64;
+        let start = offset - offset % sector_size;
+        Ok((start, (end.next_multiple_of(sector_size) - start) as usize))
+    }
+
+    fn crypt(&self, encrypt: bool, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let shift = self.sector_size.trailing_zeros() - 9;
+        let first_iv = ((offset >> 9) + self.iv_tweak) >> shift;
+        xts(
+            self.cipher,
+            &self.key,
+            encrypt,
+            buf,
+            self.sector_size,
+            first_iv,
+        )
+    }
+
+    /// Reads the plaintext at `offset` into `bufs`.
+    pub fn read(
+        &self,
+        disk: &SyncFormatAccess<ImagoFile>,
+        bufs: &[VolatileSlice],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        let (start, sectors_len) = self.sectors(offset, len)?;
+        let mut data = vec![0u8; sectors_len];
+        disk.read(&mut data[..], self.offset + start)?;
+        self.crypt(false, &mut data, start)?;
+
+        let mut pos = (offset - start) as usize;
+        for buf in bufs {
+            buf.copy_from(&data[pos..pos + buf.len()]);
+            pos += buf.len();
+        }
+        Ok(len)
+    }
+
+    /// Writes `bufs` at `offset` of the plaintext.
+    pub fn write(
+        &self,
+        disk: &SyncFormatAccess<ImagoFile>,
+        bufs: &[VolatileSlice],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        let (start, sectors_len) = self.sectors(offset, len)?;
+        let mut data = vec![0u8; sectors_len];
+        // The sectors only partly written keep the rest of their data.
+        if start != offset || sectors_len != len {
+            disk.read(&mut data[..], self.offset + start)?;
+            self.crypt(false, &mut data, start)?;
+        }
+
+        let mut pos = (offset - start) as usize;
+        for buf in bufs {
+            buf.copy_to(&mut data[pos..pos + buf.len()]);
+            pos += buf.len();
+        }
+        self.crypt(true, &mut data, start)?;
+        disk.write(&data[..], self.offset + start)?;
+        Ok(len)
+    }
+}
+
+impl Drop for Luks2 {
+    fn drop(&mut self) {
+        wipe(&mut self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    use imago::raw::Raw;
+    use utils::tempdir::TempDir;
+
+    const HDR_SIZE: u64 = 0x4000;
+    const KEYSLOT_OFFSET: u64 = 0x8000;
+    const DATA_OFFSET: u64 = 0x100000;
+    const STRIPES: usize = 4000;
+
+    fn open(path: &Path) -> SyncFormatAccess<ImagoFile> {
+        SyncFormatAccess::new(
+            Raw::<ImagoFile>::open_path_sync(path.to_str().unwrap(), true).unwrap(),
+        )
+        .unwrap()
+    }
+
+    /// Formats `path` as `cryptsetup luksFormat` would, with a keyslot for
+    /// `passphrase` per KDF of `kdfs`, and returns the volume key. The area
+    /// keys are always derived with the parameters of the tests, the ones of
+    /// `kdfs` only being those of the header.
+    fn format(
+        path: &Path,
+        passphrase: &[u8],
+        kdfs: &[serde_json::Value],
+        sector_size: usize,
+    ) -> Vec<u8> {
+        let key: Vec<u8> = (0..64).map(|_| rand::random()).collect();
+        let mut keyslots = serde_json::Map::new();
+        let mut splits = Vec::new();
+        for (i, kdf) in kdfs.iter().enumerate() {
+            let salt: Vec<u8> = (0..32).map(|_| rand::random()).collect();
+            let area_key = match kdf["type"].as_str().unwrap() {
+                "pbkdf2" => {
+                    let mut area_key = vec![0u8; 64];
+                    pbkdf2_hmac(
+                        passphrase,
+                        &salt,
+                        1000,
+                        MessageDigest::sha256(),
+                        &mut area_key,
+                    )
+                    .unwrap();
+                    area_key
+                }
+                _ => argon2(Algorithm::Argon2id, passphrase, &salt, 2, 64, 1, 64).unwrap(),
+            };
+            let mut kdf = kdf.clone();
+            kdf["salt"] = BASE64.encode(&salt).into();
+
+            // The anti-forensic split of the key.
+            let mut split: Vec<u8> = (0..64 * (STRIPES - 1)).map(|_| rand::random()).collect();
+            let mut d = vec![0u8; 64];
+            for stripe in split.chunks(64) {
+                d.iter_mut().zip(stripe).for_each(|(d, s)| *d ^= s);
+                diffuse(&mut d, MessageDigest::sha256()).unwrap();
+            }
+            split.extend(d.iter().zip(&key).map(|(d, k)| d ^ k));
+            split.resize(split.len().next_multiple_of(512), 0);
+            xts(Cipher::aes_256_xts(), &area_key, true, &mut split, 512, 0).unwrap();
+
+            let area_offset = KEYSLOT_OFFSET + (i * split.len()) as u64;
+            keyslots.insert(
+                i.to_string(),
+                serde_json::json!({
+                    "type": "luks2",
+                    "key_size": 64,
+                    "af": { "type": "luks1", "stripes": STRIPES, "hash": "sha256" },
+                    "area": {
+                        "type": "raw",
+                        "offset": area_offset.to_string(),
+                        "size": split.len().to_string(),
+                        "encryption": "aes-xts-plain64",
+                        "key_size": 64
+                    },
+                    "kdf": kdf,
+                    "priority": 1
+                }),
+            );
+            splits.push((area_offset, split));
+        }
+
+        let digest_salt = [7u8; 32];
+        let mut digest = [0u8; 32];
+        pbkdf2_hmac(
+            &key,
+            &digest_salt,
+            1000,
+            MessageDigest::sha256(),
+            &mut digest,
+        )
+        .unwrap();
+
+        let metadata = serde_json::json!({
+            "keyslots": keyslots,
+            "tokens": {},
+            "segments": {
+                "0": {
+                    "type": "crypt",
+                    "offset": DATA_OFFSET.to_string(),
+                    "size": "dynamic",
+                    "iv_tweak": "0",
+                    "encryption": "aes-xts-plain64",
+                    "sector_size": sector_size
+                }
+            },
+            "digests": {
+                "0": {
+                    "type": "pbkdf2",
+                    "keyslots": keyslots.keys().collect::<Vec<_>>(),
+                    "segments": ["0"],
+                    "hash": "sha256",
+                    "iterations": 1000,
+                    "salt": BASE64.encode(digest_salt),
+                    "digest": BASE64.encode(digest)
+                }
+            },
+            "config": { "json_size": (HDR_SIZE - 4096).to_string(), "keyslots_size": "1015808" }
+        });
+
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        for (offset, magic) in [(0, MAGIC), (HDR_SIZE, SECONDARY_MAGIC)] {
+            let mut header = vec![0u8; HDR_SIZE as usize];
+            header[..6].copy_from_slice(magic);
+            header[6..8].copy_from_slice(&2u16.to_be_bytes());
+            header[8..16].copy_from_slice(&HDR_SIZE.to_be_bytes());
+            header[16..24].copy_from_slice(&1u64.to_be_bytes());
+            header[72..78].copy_from_slice(b"sha256");
+            header[256..264].copy_from_slice(&offset.to_be_bytes());
+            let json = metadata.to_string();
+            header[4096..][..json.len()].copy_from_slice(json.as_bytes());
+            let checksum = hash::hash(MessageDigest::sha256(), &header).unwrap();
+            header[CHECKSUM_OFFSET..][..32].copy_from_slice(&checksum);
+            file.write_all_at(&header, offset).unwrap();
+        }
+        for (offset, split) in splits {
+            file.write_all_at(&split, offset).unwrap();
+        }
+        key
+    }
+
+    fn volume(dir: &TempDir, name: &str) -> std::path::PathBuf {
+        let path = dir.as_path().join(name);
+        std::fs::File::create(&path)
+            .unwrap()
+            .set_len(DATA_OFFSET + (1 << 20))
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_luks2() {
+        let dir = TempDir::new().unwrap();
+        for (sector_size, kdf) in [
+            (
+                512,
+                serde_json::json!({ "type": "pbkdf2", "hash": "sha256", "iterations": 1000 }),
+            ),
+            (
+                4096,
+                serde_json::json!({ "type": "argon2id", "time": 2, "memory": 64, "cpus": 1 }),
+            ),
+        ] {
+            let path = volume(&dir, &format!("luks-{sector_size}"));
+            let key = format(&path, b"secret", &[kdf], sector_size);
+            let disk = open(&path);
+
+            let err = Luks2::open(&disk, b"wrong").err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+            let luks = Luks2::open(&disk, b"secret").unwrap();
+            assert_eq!(luks.key, key);
+            assert_eq!(luks.size(), 1 << 20);
+
+            let mut zeros = vec![0u8; 4096];
+            luks.write(&disk, &[VolatileSlice::from(&mut zeros[..])], 0)
+                .unwrap();
+            // Writes not aligned to the sectors of the volume.
+            let mut data: Vec<u8> = (0..3 * 512).map(|i| (i % 251) as u8).collect();
+            let (first, second) = data.split_at_mut(512);
+            let bufs = [VolatileSlice::from(first), VolatileSlice::from(second)];
+            assert_eq!(luks.write(&disk, &bufs, 512).unwrap(), 3 * 512);
+
+            let mut read = vec![0u8; 4096];
+            luks.read(&disk, &[VolatileSlice::from(&mut read[..])], 0)
+                .unwrap();
+            assert_eq!(read[..512], [0; 512]);
+            assert_eq!(read[512..4 * 512], data);
+            assert_eq!(read[4 * 512..], [0; 4096 - 4 * 512]);
+
+            // What reaches the disk is the ciphertext of dm-crypt.
+            let mut raw = vec![0u8; 4096];
+            disk.read(&mut raw[..], DATA_OFFSET).unwrap();
+            assert_ne!(raw[512..4 * 512], data);
+            xts(Cipher::aes_256_xts(), &key, false, &mut raw, sector_size, 0).unwrap();
+            assert_eq!(raw, read);
+
+            assert_eq!(
+                luks.read(&disk, &[VolatileSlice::from(&mut read[..])], 1 << 20)
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn test_secondary_header() {
+        let dir = TempDir::new().unwrap();
+        let path = volume(&dir, "luks");
+        let kdf = serde_json::json!({ "type": "pbkdf2", "hash": "sha256", "iterations": 1000 });
+        let key = format(&path, b"secret", &[kdf], 512);
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(b"corrupted", 4096).unwrap();
+        assert_eq!(Luks2::open(&open(&path), b"secret").unwrap().key, key);
+
+        file.write_all_at(b"corrupted", HDR_SIZE + 4096).unwrap();
+        assert!(Luks2::open(&open(&path), b"secret").is_err());
+    }
+
+    #[test]
+    fn test_keyslot_limits() {
+        let dir = TempDir::new().unwrap();
+        let path = volume(&dir, "luks");
+        let limits = [
+            serde_json::json!({
+                "type": "pbkdf2",
+                "hash": "sha256",
+                "iterations": usize::MAX
+            }),
+            serde_json::json!({
+                "type": "argon2id",
+                "time": 2,
+                "memory": u32::MAX,
+                "cpus": 1
+            }),
+        ];
+        format(&path, b"secret", &limits, 512);
+        let err = Luks2::open(&open(&path), b"secret").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        // The keyslots that can't be used are skipped.
+        let kdf = serde_json::json!({ "type": "pbkdf2", "hash": "sha256", "iterations": 1000 });
+        let key = format(
+            &path,
+            b"secret",
+            &[limits[0].clone(), limits[1].clone(), kdf],
+            512,
+        );
+        assert_eq!(Luks2::open(&open(&path), b"secret").unwrap().key, key);
+    }
+}
@@ -2,9 +2,22 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod device;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "luks")]
+mod luks2;
+mod overlay;
+#[cfg(feature = "verity")]
+mod verity;
 mod worker;
+mod zoned;
 
 pub use self::device::{Block, CacheType};
+#[cfg(feature = "http")]
+pub use self::http::HttpDisk;
+pub use self::overlay::create_overlay;
+#[cfg(fuzzing)]
+pub(crate) use self::worker::BlockWorker;
 
 use vm_memory::GuestMemoryError;
 
@@ -0,0 +1,170 @@
+//! Copy-on-write overlays over base disk images, so many VMs can share a
+//! single base image without being able to change it.
+//!
+//! An overlay is an empty qcow2 image whose backing file is the base image:
+//! the guest reads the data of the base until it writes its own, which only
+//! ever goes to the overlay. The base image is opened read-only.
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use imago::file::File as ImagoFile;
+use imago::qcow2::Qcow2;
+use imago::SyncFormatAccess;
+
+use super::ImageType;
+
+const QCOW2_MAGIC: u32 = 0x5146_49fb;
+const CLUSTER_BITS: u32 = 16;
+const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+/// Bytes of the header, up to and including the compression type.
+const HEADER_LENGTH: u32 = 112;
+const EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+/// 16-bit refcounts.
+const REFCOUNT_ORDER: u32 = 4;
+/// Longest backing file name qemu accepts.
+const MAX_BACKING_NAME: usize = 1023;
+
+/// The size of the disk the image at `path` holds.
+fn image_size(path: &Path, format: &ImageType) -> io::Result<u64> {
+    let path = path.to_string_lossy();
+    let image = match format {
+        ImageType::Qcow2 => {
+            let mut qcow2 = Qcow2::<ImagoFile>::open_path_sync(path.as_ref(), false)?;
+            qcow2.open_implicit_dependencies_sync()?;
+            SyncFormatAccess::new(qcow2)?
+        }
+        ImageType::Raw => SyncFormatAccess::new(imago::raw::Raw::<ImagoFile>::open_path_sync(
+            path.as_ref(),
+            false,
+        )?)?,
+    };
+    Ok(image.size())
+}
+
+/// Creates a qcow2 image at `path` holding no data of its own over the image
+/// at `base`, of format `base_format`. Existing files are never overwritten.
+pub fn create_overlay(path: &Path, base: &Path, base_format: &ImageType) -> io::Result<()> {
+    let base = fs::canonicalize(base)?;
+    let size = image_size(&base, base_format)?;
+    let backing_name = base.to_string_lossy().into_owned().into_bytes();
+    if backing_name.len() > MAX_BACKING_NAME {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    let backing_format: &[u8] = match base_format {
+        ImageType::Raw => b"raw",
+        ImageType::Qcow2 => b"qcow2",
+    };
+
+    // The header, followed by the refcount table, the refcount block and the
+    // L1 table, each in its own clusters.
+    let l2_entries = CLUSTER_SIZE / 8;
+    let l1_size = size.div_ceil(CLUSTER_SIZE * l2_entries);
+    let l1_clusters = (l1_size * 8).div_ceil(CLUSTER_SIZE).max(1);
+    let clusters = 3 + l1_clusters;
+    if clusters > CLUSTER_SIZE / 2 {
+        return Err(io::Error::from_raw_os_error(libc::EFBIG));
+    }
+
+    let mut header = vec![0u8; CLUSTER_SIZE as usize];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        header[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    put(0, &QCOW2_MAGIC.to_be_bytes());
+    put(4, &3u32.to_be_bytes());
+    // The extensions follow the header, and the backing file name them.
+    let ext_len = (backing_format.len() as u32).next_multiple_of(8);
+    let backing_offset = u64::from(HEADER_LENGTH + 8 + ext_len + 8);
+    put(8, &backing_offset.to_be_bytes());
+    put(16, &(backing_name.len() as u32).to_be_bytes());
+    put(20, &CLUSTER_BITS.to_be_bytes());
+    put(24, &size.to_be_bytes());
+    put(36, &(l1_size as u32).to_be_bytes());
+    put(40, &(3 * CLUSTER_SIZE).to_be_bytes());
+    put(48, &CLUSTER_SIZE.to_be_bytes());
+    put(56, &1u32.to_be_bytes());
+    put(96, &REFCOUNT_ORDER.to_be_bytes());
+    put(100, &HEADER_LENGTH.to_be_bytes());
+    let ext = HEADER_LENGTH as usize;
+    put(ext, &EXT_BACKING_FORMAT.to_be_bytes());
+    put(ext + 4, &(backing_format.len() as u32).to_be_bytes());
+    put(ext + 8, backing_format);
+    // The end of the extensions is all zeros.
+    put(backing_offset as usize, &backing_name);
+
+    let refcount_table = (2 * CLUSTER_SIZE).to_be_bytes();
+    let refcounts: Vec<u8> = (0..clusters).flat_map(|_| 1u16.to_be_bytes()).collect();
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    let result = file
+        .set_len(clusters * CLUSTER_SIZE)
+        .and_then(|()| file.write_all_at(&header, 0))
+        .and_then(|()| file.write_all_at(&refcount_table, CLUSTER_SIZE))
+        .and_then(|()| file.write_all_at(&refcounts, 2 * CLUSTER_SIZE))
+        .and_then(|()| file.sync_all());
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempdir::TempDir;
+
+    fn open_overlay(path: &Path) -> SyncFormatAccess<ImagoFile> {
+        let mut qcow2 = Qcow2::<ImagoFile>::open_path_sync(path.to_str().unwrap(), true).unwrap();
+        qcow2.open_implicit_dependencies_sync().unwrap();
+        SyncFormatAccess::new(qcow2).unwrap()
+    }
+
+    #[test]
+    fn test_overlay() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.as_path().join("base.raw");
+        let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+        fs::write(&base, &data).unwrap();
+
+        let path = dir.as_path().join("overlay.qcow2");
+        create_overlay(&path, &base, &ImageType::Raw).unwrap();
+        let overlay = open_overlay(&path);
+        assert_eq!(overlay.size(), data.len() as u64);
+
+        let mut buf = vec![0u8; 4096];
+        overlay.read(&mut buf[..], 1 << 20).unwrap();
+        assert_eq!(buf, data[1 << 20..][..4096]);
+
+        // Writes go to the overlay only.
+        overlay.write(&[0xffu8; 512][..], 1 << 20).unwrap();
+        overlay.flush().unwrap();
+        overlay.read(&mut buf[..], 1 << 20).unwrap();
+        assert_eq!(buf[..512], [0xff; 512]);
+        assert_eq!(buf[512..], data[(1 << 20) + 512..][..4096 - 512]);
+        assert_eq!(fs::read(&base).unwrap(), data);
+
+        // A second overlay sees the base, and overlays can stack.
+        let other = dir.as_path().join("other.qcow2");
+        create_overlay(&other, &base, &ImageType::Raw).unwrap();
+        let top = dir.as_path().join("top.qcow2");
+        create_overlay(&top, &path, &ImageType::Qcow2).unwrap();
+        open_overlay(&other).read(&mut buf[..], 1 << 20).unwrap();
+        assert_eq!(buf, data[1 << 20..][..4096]);
+        open_overlay(&top).read(&mut buf[..], 1 << 20).unwrap();
+        assert_eq!(buf[..512], [0xff; 512]);
+
+        assert_eq!(
+            create_overlay(&path, &base, &ImageType::Raw)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Host-side dm-verity, so a read-only disk image can't be tampered with
+//! without the guest noticing.
+//!
+//! Every block the guest reads is checked against a hash tree, in the format
+//! `veritysetup format` creates, whose root hash is given by the VMM. Blocks
+//! that don't match fail to read, like with dm-verity in the guest. The hash
+//! blocks are only read and checked once, and kept verified in memory.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use imago::file::File as ImagoFile;
+use imago::SyncFormatAccess;
+use log::error;
+use sha2::{Digest, Sha256, Sha512};
+use vm_memory::VolatileSlice;
+
+const SUPERBLOCK_MAGIC: &[u8] = b"verity\0\0";
+const SUPERBLOCK_SIZE: u64 = 512;
+const MAX_SALT_SIZE: usize = 256;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("dm-verity: {msg}"))
+}
+
+#[derive(Clone, Copy)]
+enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn digest_size(self) -> usize {
+        match self {
+            Algorithm::Sha256 => 32,
+            Algorithm::Sha512 => 64,
+        }
+    }
+
+    fn hash(self, parts: [&[u8]; 2]) -> Vec<u8> {
+        match self {
+            Algorithm::Sha256 => Sha256::new()
+                .chain_update(parts[0])
+                .chain_update(parts[1])
+                .finalize()
+                .to_vec(),
+            Algorithm::Sha512 => Sha512::new()
+                .chain_update(parts[0])
+                .chain_update(parts[1])
+                .finalize()
+                .to_vec(),
+        }
+    }
+}
+
+/// A hash tree checking the data of a disk.
+pub struct Verity {
+    hash_file: File,
+    algorithm: Algorithm,
+    /// Whether the salt comes after the data, as with the format of Chrome OS.
+    salt_last: bool,
+    salt: Vec<u8>,
+    root_hash: Vec<u8>,
+    data_block_size: u64,
+    data_blocks: u64,
+    hash_block_size: u64,
+    hash_per_block_bits: u32,
+    /// The first hash block of each level, the one hashing the data first.
+    levels: Vec<u64>,
+    verified: Mutex<HashMap<u64, Arc<[u8]>>>,
+}
+
+impl Verity {
+    /// Opens the hash tree, with its superblock, at `hash_path`, whose root
+    /// hash is `root_hash`.
+    pub fn open(hash_path: &Path, root_hash: &[u8]) -> io::Result<Self> {
+        let hash_file = File::open(hash_path)?;
+        let mut sb = [0u8; SUPERBLOCK_SIZE as usize];
+        hash_file.read_exact_at(&mut sb, 0)?;
+        if &sb[..8] != SUPERBLOCK_MAGIC || u32::from_le_bytes(sb[8..12].try_into().unwrap()) != 1 {
+            return Err(invalid("no superblock"));
+        }
+        let salt_last = match u32::from_le_bytes(sb[12..16].try_into().unwrap()) {
+            0 => true,
+            1 => false,
+            _ => return Err(invalid("unsupported hash type")),
+        };
+        let algorithm = &sb[32..64];
+        let algorithm = match &algorithm[..algorithm.iter().position(|&b| b == 0).unwrap_or(32)] {
+            b"sha256" => Algorithm::Sha256,
+            b"sha512" => Algorithm::Sha512,
+            _ => return Err(invalid("unsupported hash algorithm")),
+        };
+        let data_block_size = u64::from(u32::from_le_bytes(sb[64..68].try_into().unwrap()));
+        let hash_block_size = u64::from(u32::from_le_bytes(sb[68..72].try_into().unwrap()));
+        let data_blocks = u64::from_le_bytes(sb[72..80].try_into().unwrap());
+        let salt_size = u16::from_le_bytes([sb[80], sb[81]]) as usize;
+        let valid_block_size =
+            |size: u64| size.is_power_of_two() && (512..=1 << 20).contains(&size);
+        if !valid_block_size(data_block_size)
+            || !valid_block_size(hash_block_size)
+            || data_blocks == 0
+            || salt_size > MAX_SALT_SIZE
+        {
+            return Err(invalid("bad superblock"));
+        }
+        if root_hash.len() != algorithm.digest_size() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "dm-verity: bad root hash size",
+            ));
+        }
+
+        // Each hash takes a power of two of bytes in the hash blocks.
+        let hash_per_block_bits = (hash_block_size / algorithm.digest_size() as u64).ilog2();
+        let mut depth = 0;
+        while hash_per_block_bits * depth < 64
+            && (data_blocks - 1) >> (hash_per_block_bits * depth) != 0
+        {
+            depth += 1;
+        }
+        // The tree follows the superblock, its level closest to the root
+        // first.
+        let mut position = SUPERBLOCK_SIZE.div_ceil(hash_block_size);
+        let mut levels = vec![0; depth as usize];
+        for (i, level) in levels.iter_mut().enumerate().rev() {
+            *level = position;
+            let shift = (i as u32 + 1) * hash_per_block_bits;
+            position += if shift >= 64 {
+                1
+            } else {
+                data_blocks.div_ceil(1 << shift)
+            };
+        }
+
+        Ok(Self {
+            hash_file,
+            algorithm,
+            salt_last,
+            salt: sb[88..88 + salt_size].to_vec(),
+            root_hash: root_hash.to_vec(),
+            data_block_size,
+            data_blocks,
+            hash_block_size,
+            hash_per_block_bits,
+            levels,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The size of the data the tree covers.
+    pub fn size(&self) -> u64 {
+        self.data_blocks * self.data_block_size
+    }
+
+    fn hash(&self, block: &[u8]) -> Vec<u8> {
+        if self.salt_last {
+            self.algorithm.hash([block, &self.salt])
+        } else {
+            self.algorithm.hash([&self.salt, block])
+        }
+    }
+
+    /// The hash of the `index`th block of `level`, or of the `index`th data
+    /// block for the level below the first.
+    fn expected_hash(&self, level: usize, index: u64) -> io::Result<Vec<u8>> {
+        if level == self.levels.len() {
+            return Ok(self.root_hash.clone());
+        }
+        let parent = self.hash_block(level, index >> self.hash_per_block_bits)?;
+        let slot = (index & ((1 << self.hash_per_block_bits) - 1)) as usize;
+        let slot_size = (self.hash_block_size >> self.hash_per_block_bits) as usize;
+        Ok(parent[slot * slot_size..][..self.algorithm.digest_size()].to_vec())
+    }
+
+    /// The `index`th hash block of `level`, once verified.
+    fn hash_block(&self, level: usize, index: u64) -> io::Result<Arc<[u8]>> {
+        let position = self.levels[level] + index;
+        if let Some(block) = self.verified.lock().unwrap().get(&position) {
+            return Ok(block.clone());
+        }
+        let mut block = vec![0u8; self.hash_block_size as usize];
+        self.hash_file
+            .read_exact_at(&mut block, position * self.hash_block_size)?;
+        if self.hash(&block) != self.expected_hash(level + 1, index)? {
+            error!("dm-verity: hash block {position} is corrupted");
+            return Err(invalid("corrupted hash block"));
+        }
+        let block: Arc<[u8]> = block.into();
+        self.verified
+            .lock()
+            .unwrap()
+            .insert(position, block.clone());
+        Ok(block)
+    }
+
+    /// Reads the data at `offset` into `bufs`, failing if any of it doesn't
+    /// match the tree.
+    pub fn read(
+        &self,
+        disk: &SyncFormatAccess<ImagoFile>,
+        bufs: &[VolatileSlice],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        let end = offset
+            .checked_add(len as u64)
+            .filter(|&end| end <= self.size())
+            .ok_or_else(|| io::Error::from(ErrorKind::InvalidInput))?;
+        let first = offset / self.data_block_size;
+        let start = first * self.data_block_size;
+        let mut data = vec![0u8; (end.next_multiple_of(self.data_block_size) - start) as usize];
+        disk.read(&mut data[..], start)?;
+
+        for (i, block) in data.chunks(self.data_block_size as usize).enumerate() {
+            let index = first + i as u64;
+            if self.hash(block) != self.expected_hash(0, index)? {
+                error!("dm-verity: data block {index} is corrupted");
+                return Err(invalid("corrupted data block"));
+            }
+        }
+
+        let mut pos = (offset - start) as usize;
+        for buf in bufs {
+            buf.copy_from(&data[pos..pos + buf.len()]);
+            pos += buf.len();
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use imago::raw::Raw;
+    use utils::tempdir::TempDir;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Writes the hash tree of `data` at `path`, as `veritysetup format`
+    /// would, and returns its root hash.
+    fn format(data: &[u8], salt: &[u8], path: &Path) -> Vec<u8> {
+        let algorithm = Algorithm::Sha256;
+        let mut hashes: Vec<Vec<u8>> = data
+            .chunks(BLOCK_SIZE)
+            .map(|block| algorithm.hash([salt, block]))
+            .collect();
+        let mut levels = Vec::new();
+        while hashes.len() > 1 {
+            let level: Vec<Vec<u8>> = hashes
+                .chunks(BLOCK_SIZE / 32)
+                .map(|group| {
+                    let mut block = group.concat();
+                    block.resize(BLOCK_SIZE, 0);
+                    block
+                })
+                .collect();
+            hashes = level
+                .iter()
+                .map(|block| algorithm.hash([salt, block]))
+                .collect();
+            levels.push(level.concat());
+        }
+
+        let mut file = vec![0u8; BLOCK_SIZE];
+        file[..8].copy_from_slice(SUPERBLOCK_MAGIC);
+        file[8..12].copy_from_slice(&1u32.to_le_bytes());
+        file[12..16].copy_from_slice(&1u32.to_le_bytes());
+        file[32..38].copy_from_slice(b"sha256");
+        file[64..68].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        file[68..72].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        file[72..80].copy_from_slice(&((data.len() / BLOCK_SIZE) as u64).to_le_bytes());
+        file[80..82].copy_from_slice(&(salt.len() as u16).to_le_bytes());
+        file[88..88 + salt.len()].copy_from_slice(salt);
+        for level in levels.iter().rev() {
+            file.extend_from_slice(level);
+        }
+        std::fs::write(path, file).unwrap();
+        hashes.remove(0)
+    }
+
+    fn read(verity: &Verity, path: &Path, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let disk = SyncFormatAccess::new(
+            Raw::<ImagoFile>::open_path_sync(path.to_str().unwrap(), false).unwrap(),
+        )
+        .unwrap();
+        let mut buf = vec![0u8; len];
+        verity.read(&disk, &[VolatileSlice::from(&mut buf[..])], offset)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_verity() {
+        let dir = TempDir::new().unwrap();
+        let data_path = dir.as_path().join("data");
+        let hash_path = dir.as_path().join("hash");
+        // Enough blocks for two levels of hashes.
+        let data: Vec<u8> = (0..300 * BLOCK_SIZE).map(|i| (i / 7) as u8).collect();
+        std::fs::write(&data_path, &data).unwrap();
+        let root_hash = format(&data, &[0x5a; 32], &hash_path);
+
+        let verity = Verity::open(&hash_path, &root_hash).unwrap();
+        assert_eq!(verity.size(), data.len() as u64);
+        assert_eq!(
+            read(&verity, &data_path, 1000, 10000).unwrap(),
+            data[1000..11000]
+        );
+        assert_eq!(
+            read(&verity, &data_path, 299 * BLOCK_SIZE as u64, BLOCK_SIZE).unwrap(),
+            data[299 * BLOCK_SIZE..]
+        );
+        assert!(read(&verity, &data_path, 299 * BLOCK_SIZE as u64, BLOCK_SIZE + 1).is_err());
+
+        // Tampered data only fails the reads of its block.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&data_path)
+            .unwrap();
+        file.write_all_at(&[0xff], 200 * BLOCK_SIZE as u64 + 5)
+            .unwrap();
+        assert!(read(&verity, &data_path, 200 * BLOCK_SIZE as u64, 512).is_err());
+        assert!(read(&verity, &data_path, 201 * BLOCK_SIZE as u64, 512).is_ok());
+
+        let mut wrong_root = root_hash.clone();
+        wrong_root[0] ^= 1;
+        let verity = Verity::open(&hash_path, &wrong_root).unwrap();
+        assert!(read(&verity, &data_path, 0, 512).is_err());
+
+        // So do tampered hashes, once not verified yet.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&hash_path)
+            .unwrap();
+        file.write_all_at(&[0xff], 2 * BLOCK_SIZE as u64).unwrap();
+        let verity = Verity::open(&hash_path, &root_hash).unwrap();
+        assert!(read(&verity, &data_path, 0, 512).is_err());
+        assert!(read(&verity, &data_path, 150 * BLOCK_SIZE as u64, 512).is_ok());
+    }
+}
@@ -2,14 +2,17 @@ use crate::virtio::descriptor_utils::{Reader, Writer};
 
 use super::super::Queue;
 use super::device::{CacheType, DiskProperties};
+use super::zoned::Zones;
+use super::SECTOR_SHIFT;
 
 use crate::virtio::InterruptTransport;
 use std::io::{self, Write};
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, RawFd};
 use std::result;
-use std::thread;
-use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use std::sync::Mutex;
+use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
+use utils::worker_pool::{run_handler, PoolHandler, PoolTask};
 use virtio_bindings::virtio_blk::*;
 use vm_memory::{ByteValued, GuestMemoryMmap};
 
@@ -21,6 +24,10 @@ pub enum RequestError {
     ReadingFromDescriptor(io::Error),
     WritingToDescriptor(io::Error),
     UnknownRequest,
+    /// The request breaks the rules of zoned devices, with the status to
+    /// complete it with.
+    Zone(u8),
+    ZoneState(io::Error),
 }
 
 /// The request header represents the mandatory fields of each block device request.
@@ -72,71 +79,24 @@ impl BlockWorker {
         }
     }
 
-    pub fn run(self) -> thread::JoinHandle<()> {
-        thread::Builder::new()
-            .name("block worker".into())
-            .spawn(|| self.work())
-            .unwrap()
-    }
-
-    fn work(mut self) {
-        let virtq_ev_fd = self.queue_evt.as_raw_fd();
-        let stop_ev_fd = self.stop_fd.as_raw_fd();
-
-        let epoll = Epoll::new().unwrap();
-
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            virtq_ev_fd,
-            &EpollEvent::new(EventSet::IN, virtq_ev_fd as u64),
-        );
-
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            stop_ev_fd,
-            &EpollEvent::new(EventSet::IN, stop_ev_fd as u64),
-        );
-
-        loop {
-            let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
-            match epoll.wait(epoll_events.len(), -1, epoll_events.as_mut_slice()) {
-                Ok(ev_cnt) => {
-                    for event in &epoll_events[0..ev_cnt] {
-                        let source = event.fd();
-                        let event_set = event.event_set();
-                        match event_set {
-                            EventSet::IN if source == virtq_ev_fd => {
-                                self.process_queue_event();
-                            }
-                            EventSet::IN if source == stop_ev_fd => {
-                                debug!("stopping worker thread");
-                                let _ = self.stop_fd.read();
-                                return;
-                            }
-                            _ => {
-                                log::warn!(
-                                    "Received unknown event: {event_set:?} from fd: {source:?}"
-                                );
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    debug!("failed to consume muxer epoll event: {e}");
-                }
-            }
-        }
+    pub fn run(self) -> io::Result<PoolTask> {
+        let fds = [
+            (self.queue_evt.as_raw_fd(), EventSet::IN),
+            (self.stop_fd.as_raw_fd(), EventSet::IN),
+        ];
+        run_handler("block worker", &fds, Box::new(self))
     }
 
     fn process_queue_event(&mut self) {
-        if let Err(e) = self.queue_evt.read() {
-            error!("Failed to get queue event: {e:?}");
-        } else {
-            self.process_virtio_queues();
+        match self.queue_evt.read() {
+            Ok(_) => self.process_virtio_queues(),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => error!("Failed to get queue event: {e:?}"),
         }
     }
 
     /// Process device virtio queue(s).
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     fn process_virtio_queues(&mut self) {
         let mem = self.mem.clone();
         loop {
@@ -150,7 +110,7 @@ impl BlockWorker {
         }
     }
 
-    fn process_queue(&mut self, mem: &GuestMemoryMmap) {
+    pub(crate) fn process_queue(&mut self, mem: &GuestMemoryMmap) {
         while let Some(head) = self.queue.pop(mem) {
             let mut reader = match Reader::new(mem, head.clone()) {
                 Ok(r) => r,
@@ -177,12 +137,25 @@ impl BlockWorker {
             let (status, len): (u8, usize) =
                 match self.process_request(request_header, &mut reader, &mut writer) {
                     Ok(l) => (VIRTIO_BLK_S_OK.try_into().unwrap(), l),
+                    Err(RequestError::Zone(status)) => {
+                        debug!("zone request failed with status {status}");
+                        (status, 0)
+                    }
                     Err(e) => {
                         error!("error processing request: {e:?}");
                         (VIRTIO_BLK_S_IOERR.try_into().unwrap(), 0)
                     }
                 };
 
+            // The status is the last byte of the request, however much of
+            // the data before it was written.
+            let data_len = writer.available_bytes().saturating_sub(1);
+            if data_len > 0 {
+                match writer.split_at(data_len) {
+                    Ok(status_writer) => writer = status_writer,
+                    Err(e) => error!("Failed to find virtio block status: {e:?}"),
+                }
+            }
             if let Err(e) = writer.write_obj(status) {
                 error!("Failed to write virtio block status: {e:?}")
             }
@@ -213,24 +186,42 @@ impl BlockWorker {
                 } else {
                     writer
                         .write_from_at(&self.disk, data_len, request_header.sector * 512)
+                        .inspect(|&len| self.interrupt.metrics().rx_bytes.add(len as u64))
                         .map_err(RequestError::WritingToDescriptor)
                 }
             }
             VIRTIO_BLK_T_OUT => {
                 let data_len = reader.available_bytes();
                 if !data_len.is_multiple_of(512) {
-                    Err(RequestError::InvalidDataLength)
-                } else {
-                    reader
-                        .read_to_at(&self.disk, data_len, request_header.sector * 512)
-                        .map_err(RequestError::ReadingFromDescriptor)
+                    return Err(RequestError::InvalidDataLength);
+                }
+                let sector = request_header.sector;
+                let nsectors = data_len as u64 >> SECTOR_SHIFT;
+                let mut zones = self.disk.zones().map(|zones| zones.lock().unwrap());
+                if let Some(zones) = zones.as_mut() {
+                    zones.prepare_write(sector, nsectors)?;
+                }
+                let len = reader
+                    .read_to_at(&self.disk, data_len, sector * 512)
+                    .inspect(|&len| self.interrupt.metrics().tx_bytes.add(len as u64))
+                    .map_err(RequestError::ReadingFromDescriptor)?;
+                if let Some(zones) = zones.as_mut() {
+                    zones.advance(sector, nsectors)?;
                 }
+                Ok(len)
             }
             VIRTIO_BLK_T_FLUSH => match self.disk.cache_type() {
                 CacheType::Writeback => {
                     let diskfile = self.disk.file();
                     diskfile.flush().map_err(RequestError::FlushingToDisk)?;
                     diskfile.sync().map_err(RequestError::FlushingToDisk)?;
+                    if let Some(zones) = self.disk.zones() {
+                        zones
+                            .lock()
+                            .unwrap()
+                            .sync()
+                            .map_err(RequestError::FlushingToDisk)?;
+                    }
                     Ok(0)
                 }
                 CacheType::Unsafe => Ok(0),
@@ -247,7 +238,75 @@ impl BlockWorker {
                     Ok(disk_id.len())
                 }
             }
+            _ => match self.disk.zones() {
+                Some(zones) => self.process_zone_request(zones, request_header, reader, writer),
+                None => Err(RequestError::UnknownRequest),
+            },
+        }
+    }
+
+    fn process_zone_request(
+        &self,
+        zones: &Mutex<Zones>,
+        request_header: RequestHeader,
+        reader: &mut Reader,
+        writer: &mut Writer,
+    ) -> result::Result<usize, RequestError> {
+        let mut zones = zones.lock().unwrap();
+        match request_header.request_type {
+            VIRTIO_BLK_T_ZONE_APPEND => {
+                let data_len = reader.available_bytes();
+                if !data_len.is_multiple_of(512) {
+                    return Err(RequestError::InvalidDataLength);
+                }
+                let nsectors = data_len as u64 >> SECTOR_SHIFT;
+                let sector = zones.prepare_append(request_header.sector, nsectors)?;
+                reader
+                    .read_to_at(&self.disk, data_len, sector * 512)
+                    .inspect(|&len| self.interrupt.metrics().tx_bytes.add(len as u64))
+                    .map_err(RequestError::ReadingFromDescriptor)?;
+                zones.advance(sector, nsectors)?;
+                writer
+                    .write_all(&sector.to_le_bytes())
+                    .map_err(RequestError::WritingToDescriptor)?;
+                Ok(8)
+            }
+            VIRTIO_BLK_T_ZONE_REPORT => {
+                let data_len = writer.available_bytes().saturating_sub(1);
+                let report = zones.report(request_header.sector, data_len)?;
+                writer
+                    .write_all(&report)
+                    .map_err(RequestError::WritingToDescriptor)?;
+                Ok(report.len())
+            }
+            VIRTIO_BLK_T_ZONE_OPEN
+            | VIRTIO_BLK_T_ZONE_CLOSE
+            | VIRTIO_BLK_T_ZONE_FINISH
+            | VIRTIO_BLK_T_ZONE_RESET
+            | VIRTIO_BLK_T_ZONE_RESET_ALL => {
+                zones.manage(request_header.request_type, request_header.sector)?;
+                Ok(0)
+            }
             _ => Err(RequestError::UnknownRequest),
         }
     }
 }
+
+impl PoolHandler for BlockWorker {
+    fn handle_event(&mut self, source: RawFd, events: EventSet) -> bool {
+        match events {
+            EventSet::IN if source == self.queue_evt.as_raw_fd() => {
+                self.process_queue_event();
+            }
+            EventSet::IN if source == self.stop_fd.as_raw_fd() => {
+                debug!("stopping worker");
+                let _ = self.stop_fd.read();
+                return false;
+            }
+            _ => {
+                log::warn!("Received unknown event: {events:?} from fd: {source:?}");
+            }
+        }
+        true
+    }
+}
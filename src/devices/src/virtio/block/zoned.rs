@@ -0,0 +1,466 @@
+//! Emulated zoned block devices, to develop software for ZNS and SMR drives
+//! in the guest without one on the host.
+//!
+//! The disk is split in zones of a fixed size, all of them sequential write
+//! required: the guest writes each zone in order from its write pointer, and
+//! can only rewind it by resetting the whole zone. Like on a real drive, the
+//! number of zones that are open or hold data without being full can be
+//! limited.
+//!
+//! The write pointers and conditions of the zones are kept next to the disk,
+//! so they survive restarts, after which the zones that were open are closed.
+//! What the guest reads past the write pointer of a zone is whatever the disk
+//! held there.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use log::debug;
+use virtio_bindings::virtio_blk::*;
+
+use super::worker::RequestError;
+
+const STATE_MAGIC: &[u8; 8] = b"KRUNZONE";
+/// The magic, the size of the zones and their number.
+const STATE_HEADER_SIZE: u64 = 24;
+/// The write pointer and the condition of a zone.
+const ZONE_STATE_SIZE: usize = 16;
+/// The size of the header of a zone report, and of each of its descriptors.
+const REPORT_ENTRY_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cond {
+    Empty,
+    ImplicitOpen,
+    ExplicitOpen,
+    Closed,
+    Full,
+}
+
+impl Cond {
+    fn from_state(state: u8) -> Option<Self> {
+        match u32::from(state) {
+            VIRTIO_BLK_ZS_EMPTY => Some(Cond::Empty),
+            VIRTIO_BLK_ZS_IOPEN => Some(Cond::ImplicitOpen),
+            VIRTIO_BLK_ZS_EOPEN => Some(Cond::ExplicitOpen),
+            VIRTIO_BLK_ZS_CLOSED => Some(Cond::Closed),
+            VIRTIO_BLK_ZS_FULL => Some(Cond::Full),
+            _ => None,
+        }
+    }
+
+    fn state(self) -> u8 {
+        let state = match self {
+            Cond::Empty => VIRTIO_BLK_ZS_EMPTY,
+            Cond::ImplicitOpen => VIRTIO_BLK_ZS_IOPEN,
+            Cond::ExplicitOpen => VIRTIO_BLK_ZS_EOPEN,
+            Cond::Closed => VIRTIO_BLK_ZS_CLOSED,
+            Cond::Full => VIRTIO_BLK_ZS_FULL,
+        };
+        state as u8
+    }
+
+    fn is_open(self) -> bool {
+        matches!(self, Cond::ImplicitOpen | Cond::ExplicitOpen)
+    }
+
+    fn is_active(self) -> bool {
+        self.is_open() || self == Cond::Closed
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Zone {
+    /// The write pointer, in sectors.
+    wp: u64,
+    cond: Cond,
+}
+
+fn zone_error(status: u32) -> RequestError {
+    RequestError::Zone(status as u8)
+}
+
+pub struct Zones {
+    /// The size of each zone, in sectors.
+    zone_sectors: u64,
+    /// The most zones that can be open at once, 0 if not limited.
+    max_open: u32,
+    /// The most zones that can be open or closed at once, 0 if not limited.
+    max_active: u32,
+    zones: Vec<Zone>,
+    state: File,
+}
+
+impl Zones {
+    /// Splits a disk of `nsectors` sectors in zones of `zone_sectors`,
+    /// resuming from the state at `state_path` if it has zones of that size.
+    pub fn open(
+        state_path: &Path,
+        nsectors: u64,
+        zone_sectors: u64,
+        max_open: u32,
+        max_active: u32,
+    ) -> io::Result<Self> {
+        if !zone_sectors.is_power_of_two() || zone_sectors > nsectors {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the size of the zones must be a power of two no larger than the disk",
+            ));
+        }
+        let nzones = nsectors / zone_sectors;
+        let state = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(state_path)?;
+
+        let mut header = [0u8; STATE_HEADER_SIZE as usize];
+        header[..8].copy_from_slice(STATE_MAGIC);
+        header[8..16].copy_from_slice(&zone_sectors.to_le_bytes());
+        header[16..].copy_from_slice(&nzones.to_le_bytes());
+        let mut saved = vec![0u8; STATE_HEADER_SIZE as usize + nzones as usize * ZONE_STATE_SIZE];
+        let resumed = state.read_exact_at(&mut saved, 0).is_ok() && saved[..header.len()] == header;
+
+        let mut zones = Vec::with_capacity(nzones as usize);
+        for (index, entry) in saved[STATE_HEADER_SIZE as usize..]
+            .chunks(ZONE_STATE_SIZE)
+            .enumerate()
+        {
+            let start = index as u64 * zone_sectors;
+            let wp = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let zone = match Cond::from_state(entry[8]) {
+                Some(cond) if resumed && wp >= start && wp <= start + zone_sectors => {
+                    Zone { wp, cond }
+                }
+                _ => Zone {
+                    wp: start,
+                    cond: Cond::Empty,
+                },
+            };
+            zones.push(zone);
+        }
+        if !resumed {
+            debug!("zoned: new state at {}", state_path.display());
+            state.set_len(0)?;
+            state.write_all_at(&header, 0)?;
+        }
+
+        let mut zones = Self {
+            zone_sectors,
+            max_open,
+            max_active,
+            zones,
+            state,
+        };
+        for index in 0..zones.zones.len() {
+            let zone = zones.zones[index];
+            if zone.cond.is_open() {
+                zones.close(index)?;
+            } else if !resumed {
+                zones.save(index)?;
+            }
+        }
+        zones.state.sync_data()?;
+        Ok(zones)
+    }
+
+    /// The number of sectors the zones cover.
+    pub fn nsectors(&self) -> u64 {
+        self.zones.len() as u64 * self.zone_sectors
+    }
+
+    fn save(&self, index: usize) -> io::Result<()> {
+        let zone = &self.zones[index];
+        let mut entry = [0u8; ZONE_STATE_SIZE];
+        entry[..8].copy_from_slice(&zone.wp.to_le_bytes());
+        entry[8] = zone.cond.state();
+        self.state
+            .write_all_at(&entry, STATE_HEADER_SIZE + (index * ZONE_STATE_SIZE) as u64)
+    }
+
+    fn set(&mut self, index: usize, wp: u64, cond: Cond) -> io::Result<()> {
+        self.zones[index] = Zone { wp, cond };
+        self.save(index)
+    }
+
+    /// The zone starting at `sector`.
+    fn zone_at(&self, sector: u64) -> Result<usize, RequestError> {
+        let index = sector / self.zone_sectors;
+        if !sector.is_multiple_of(self.zone_sectors) || index >= self.zones.len() as u64 {
+            return Err(zone_error(VIRTIO_BLK_S_ZONE_INVALID_CMD));
+        }
+        Ok(index as usize)
+    }
+
+    fn start(&self, index: usize) -> u64 {
+        index as u64 * self.zone_sectors
+    }
+
+    fn open_zone(&mut self, index: usize, cond: Cond) -> Result<(), RequestError> {
+        let zone = self.zones[index];
+        if zone.cond.is_open() {
+            if zone.cond != cond && cond == Cond::ExplicitOpen {
+                self.set(index, zone.wp, cond)
+                    .map_err(RequestError::ZoneState)?;
+            }
+            return Ok(());
+        }
+        let count = |f: fn(Cond) -> bool| self.zones.iter().filter(|z| f(z.cond)).count();
+        if zone.cond == Cond::Empty
+            && self.max_active != 0
+            && count(Cond::is_active) >= self.max_active as usize
+        {
+            return Err(zone_error(VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE));
+        }
+        if self.max_open != 0 && count(Cond::is_open) >= self.max_open as usize {
+            // Like drives do, make room by closing a zone the guest didn't
+            // open itself.
+            match self.zones.iter().position(|z| z.cond == Cond::ImplicitOpen) {
+                Some(implicit) => self.close(implicit).map_err(RequestError::ZoneState)?,
+                None => return Err(zone_error(VIRTIO_BLK_S_ZONE_OPEN_RESOURCE)),
+            }
+        }
+        self.set(index, zone.wp, cond)
+            .map_err(RequestError::ZoneState)
+    }
+
+    fn close(&mut self, index: usize) -> io::Result<()> {
+        let zone = self.zones[index];
+        if !zone.cond.is_open() {
+            return Ok(());
+        }
+        let cond = if zone.wp == self.start(index) {
+            Cond::Empty
+        } else {
+            Cond::Closed
+        };
+        self.set(index, zone.wp, cond)
+    }
+
+    /// Checks a write of `nsectors` at `sector`, opening its zone if needed.
+    pub fn prepare_write(&mut self, sector: u64, nsectors: u64) -> Result<(), RequestError> {
+        let index = sector / self.zone_sectors;
+        if index >= self.zones.len() as u64
+            || sector + nsectors > self.start(index as usize) + self.zone_sectors
+        {
+            return Err(zone_error(VIRTIO_BLK_S_ZONE_INVALID_CMD));
+        }
+        let index = index as usize;
+        let zone = self.zones[index];
+        if zone.cond == Cond::Full {
+            return Err(zone_error(VIRTIO_BLK_S_ZONE_INVALID_CMD));
+        }
+        if sector != zone.wp {
+            return Err(zone_error(VIRTIO_BLK_S_ZONE_UNALIGNED_WP));
+        }
+        self.open_zone(index, Cond::ImplicitOpen)
+    }
+
+    /// Checks an append of `nsectors` to the zone starting at `sector`,
+    /// returning where the data goes.
+    pub fn prepare_append(&mut self, sector: u64, nsectors: u64) -> Result<u64, RequestError> {
+        let zone = self.zones[self.zone_at(sector)?];
+        if zone.cond == Cond::Full {
+            return Err(zone_error(VIRTIO_BLK_S_ZONE_INVALID_CMD));
+        }
+        self.prepare_write(zone.wp, nsectors)?;
+        Ok(zone.wp)
+    }
+
+    /// Moves the write pointer past a write of `nsectors` at `sector`.
+    pub fn advance(&mut self, sector: u64, nsectors: u64) -> Result<(), RequestError> {
+        let index = (sector / self.zone_sectors) as usize;
+        let wp = sector + nsectors;
+        let cond = if wp == self.start(index) + self.zone_sectors {
+            Cond::Full
+        } else {
+            self.zones[index].cond
+        };
+        self.set(index, wp, cond).map_err(RequestError::ZoneState)
+    }
+
+    /// Handles the zone management request `request_type` for the zone
+    /// starting at `sector`.
+    pub fn manage(&mut self, request_type: u32, sector: u64) -> Result<(), RequestError> {
+        if request_type == VIRTIO_BLK_T_ZONE_RESET_ALL {
+            for index in 0..self.zones.len() {
+                self.reset(index)?;
+            }
+            return Ok(());
+        }
+        let index = self.zone_at(sector)?;
+        let zone = self.zones[index];
+        match request_type {
+            VIRTIO_BLK_T_ZONE_OPEN if zone.cond == Cond::Full => Ok(()),
+            VIRTIO_BLK_T_ZONE_OPEN => self.open_zone(index, Cond::ExplicitOpen),
+            VIRTIO_BLK_T_ZONE_CLOSE => self.close(index).map_err(RequestError::ZoneState),
+            VIRTIO_BLK_T_ZONE_FINISH => self
+                .set(index, self.start(index) + self.zone_sectors, Cond::Full)
+                .map_err(RequestError::ZoneState),
+            VIRTIO_BLK_T_ZONE_RESET => self.reset(index),
+            _ => Err(RequestError::UnknownRequest),
+        }
+    }
+
+    fn reset(&mut self, index: usize) -> Result<(), RequestError> {
+        if self.zones[index].cond == Cond::Empty {
+            return Ok(());
+        }
+        self.set(index, self.start(index), Cond::Empty)
+            .map_err(RequestError::ZoneState)
+    }
+
+    /// Reports the zones from the one holding `sector`, as many as fit in
+    /// `len` bytes.
+    pub fn report(&self, sector: u64, len: usize) -> Result<Vec<u8>, RequestError> {
+        if len < REPORT_ENTRY_SIZE {
+            return Err(RequestError::InvalidDataLength);
+        }
+        let first = (sector / self.zone_sectors) as usize;
+        if first >= self.zones.len() {
+            return Err(zone_error(VIRTIO_BLK_S_ZONE_INVALID_CMD));
+        }
+        let count = (len / REPORT_ENTRY_SIZE - 1).min(self.zones.len() - first);
+
+        let mut report = vec![0u8; REPORT_ENTRY_SIZE * (count + 1)];
+        report[..8].copy_from_slice(&(count as u64).to_le_bytes());
+        for (i, entry) in report[REPORT_ENTRY_SIZE..]
+            .chunks_mut(REPORT_ENTRY_SIZE)
+            .enumerate()
+        {
+            let index = first + i;
+            let zone = &self.zones[index];
+            entry[..8].copy_from_slice(&self.zone_sectors.to_le_bytes());
+            entry[8..16].copy_from_slice(&self.start(index).to_le_bytes());
+            entry[16..24].copy_from_slice(&zone.wp.to_le_bytes());
+            entry[24] = VIRTIO_BLK_ZT_SWR as u8;
+            entry[25] = zone.cond.state();
+        }
+        Ok(report)
+    }
+
+    /// Makes the state of the zones durable.
+    pub fn sync(&self) -> io::Result<()> {
+        self.state.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempdir::TempDir;
+
+    fn status(result: Result<impl std::fmt::Debug, RequestError>) -> u32 {
+        match result {
+            Err(RequestError::Zone(status)) => status.into(),
+            result => panic!("unexpected result {result:?}"),
+        }
+    }
+
+    fn zone_report(zones: &Zones, sector: u64) -> Vec<(u64, u64, u8)> {
+        let report = zones.report(sector, 1 << 12).unwrap();
+        let count = u64::from_le_bytes(report[..8].try_into().unwrap()) as usize;
+        assert_eq!(report.len(), REPORT_ENTRY_SIZE * (count + 1));
+        report[REPORT_ENTRY_SIZE..]
+            .chunks(REPORT_ENTRY_SIZE)
+            .map(|entry| {
+                let start = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+                let wp = u64::from_le_bytes(entry[16..24].try_into().unwrap());
+                assert_eq!(entry[24], VIRTIO_BLK_ZT_SWR as u8);
+                (start, wp, entry[25])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_zones() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("disk.zones");
+        // Four zones of 8 sectors, and a runt that isn't exposed.
+        let mut zones = Zones::open(&path, 36, 8, 2, 3).unwrap();
+        assert_eq!(zones.nsectors(), 32);
+        let empty = VIRTIO_BLK_ZS_EMPTY as u8;
+        assert_eq!(
+            zone_report(&zones, 0),
+            [
+                (0, 0, empty),
+                (8, 8, empty),
+                (16, 16, empty),
+                (24, 24, empty)
+            ]
+        );
+
+        // Writes must be at the write pointer, and within the zone.
+        zones.prepare_write(0, 4).unwrap();
+        zones.advance(0, 4).unwrap();
+        assert_eq!(
+            status(zones.prepare_write(0, 4)),
+            VIRTIO_BLK_S_ZONE_UNALIGNED_WP
+        );
+        assert_eq!(
+            status(zones.prepare_write(4, 8)),
+            VIRTIO_BLK_S_ZONE_INVALID_CMD
+        );
+        assert_eq!(zones.prepare_append(0, 4).unwrap(), 4);
+        zones.advance(4, 4).unwrap();
+        assert_eq!(zone_report(&zones, 0)[0], (0, 8, VIRTIO_BLK_ZS_FULL as u8));
+        assert_eq!(
+            status(zones.prepare_append(0, 1)),
+            VIRTIO_BLK_S_ZONE_INVALID_CMD
+        );
+
+        // Implicitly open zones are closed to open others, explicitly open
+        // ones aren't.
+        zones.manage(VIRTIO_BLK_T_ZONE_OPEN, 8).unwrap();
+        zones.prepare_write(16, 1).unwrap();
+        zones.advance(16, 1).unwrap();
+        zones.prepare_write(24, 1).unwrap();
+        zones.advance(24, 1).unwrap();
+        assert_eq!(
+            zone_report(&zones, 8),
+            [
+                (8, 8, VIRTIO_BLK_ZS_EOPEN as u8),
+                (16, 17, VIRTIO_BLK_ZS_CLOSED as u8),
+                (24, 25, VIRTIO_BLK_ZS_IOPEN as u8),
+            ]
+        );
+        zones.manage(VIRTIO_BLK_T_ZONE_RESET, 0).unwrap();
+        assert_eq!(
+            status(zones.prepare_write(0, 1)),
+            VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE
+        );
+        zones.manage(VIRTIO_BLK_T_ZONE_FINISH, 16).unwrap();
+        zones.manage(VIRTIO_BLK_T_ZONE_OPEN, 0).unwrap();
+        assert_eq!(
+            status(zones.manage(VIRTIO_BLK_T_ZONE_OPEN, 24)),
+            VIRTIO_BLK_S_ZONE_OPEN_RESOURCE
+        );
+        zones.manage(VIRTIO_BLK_T_ZONE_CLOSE, 0).unwrap();
+        assert_eq!(zone_report(&zones, 0)[0], (0, 0, empty));
+        drop(zones);
+
+        // The state survives restarts, with open zones closed.
+        let mut zones = Zones::open(&path, 36, 8, 2, 3).unwrap();
+        assert_eq!(
+            zone_report(&zones, 0),
+            [
+                (0, 0, empty),
+                (8, 8, empty),
+                (16, 24, VIRTIO_BLK_ZS_FULL as u8),
+                (24, 25, VIRTIO_BLK_ZS_CLOSED as u8),
+            ]
+        );
+        zones.manage(VIRTIO_BLK_T_ZONE_RESET_ALL, 0).unwrap();
+        assert!(zone_report(&zones, 0)
+            .iter()
+            .all(|&(start, wp, state)| start == wp && state == empty));
+
+        // Zones of another size start over.
+        let zones = Zones::open(&path, 36, 16, 0, 0).unwrap();
+        assert_eq!(zone_report(&zones, 0), [(0, 0, empty), (16, 16, empty)]);
+    }
+}
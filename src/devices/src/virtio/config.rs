@@ -0,0 +1,169 @@
+//! Validation of the guest accesses to the config space of the devices.
+//!
+//! The config space is described as the sizes of its fields, in order. An
+//! access must stay within the config space, and either within a single field,
+//! like reading a 64-bit field in two halves, or cover whole fields. Accesses
+//! straddling the boundary of a field, which would give the driver a torn
+//! value, are rejected, and reads rejected return zeroes.
+
+use std::ops::Range;
+
+pub struct ConfigLayout<'a> {
+    device: &'a str,
+    // Empty for an opaque layout, only checked against `size`.
+    fields: &'a [usize],
+    size: usize,
+}
+
+impl<'a> ConfigLayout<'a> {
+    /// The layout of the config space of `device`, with fields of the given
+    /// sizes.
+    pub const fn new(device: &'a str, fields: &'a [usize]) -> Self {
+        let mut size = 0;
+        let mut i = 0;
+        while i < fields.len() {
+            size += fields[i];
+            i += 1;
+        }
+        Self {
+            device,
+            fields,
+            size,
+        }
+    }
+
+    /// A config space of `size` bytes whose fields aren't known, e.g. because
+    /// it's handled by another device.
+    pub const fn opaque(device: &'a str, size: usize) -> Self {
+        Self {
+            device,
+            fields: &[],
+            size,
+        }
+    }
+
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    fn is_boundary(&self, offset: usize) -> bool {
+        let mut boundary = 0;
+        for size in self.fields {
+            if boundary >= offset {
+                break;
+            }
+            boundary += size;
+        }
+        boundary == offset
+    }
+
+    fn within_field(&self, range: &Range<usize>) -> bool {
+        let mut start = 0;
+        for size in self.fields {
+            let end = start + size;
+            if range.start < end {
+                return range.end <= end;
+            }
+            start = end;
+        }
+        false
+    }
+
+    /// Whether an access to `range` covers part of a field and something
+    /// else.
+    fn tears_field(&self, range: &Range<usize>) -> bool {
+        if self.fields.is_empty() || self.within_field(range) {
+            return false;
+        }
+        !self.is_boundary(range.start) || !self.is_boundary(range.end)
+    }
+
+    /// Checks an access of `len` bytes at `offset`, returning the range of the
+    /// config space it covers, or `None` after logging why it's invalid.
+    pub fn access(&self, offset: u64, len: usize) -> Option<Range<usize>> {
+        let range = usize::try_from(offset)
+            .ok()
+            .and_then(|start| Some(start..start.checked_add(len)?))
+            .filter(|range| !range.is_empty() && range.end <= self.size);
+        let Some(range) = range else {
+            warn!(
+                "{}: out of bounds config access (offset={:x}, len={:x})",
+                self.device, offset, len
+            );
+            return None;
+        };
+        if self.tears_field(&range) {
+            warn!(
+                "{}: config access across a field boundary (offset={:x}, len={:x})",
+                self.device, offset, len
+            );
+            return None;
+        }
+        Some(range)
+    }
+
+    /// Reads `config`, the current contents of the config space, at
+    /// `offset` into `data`.
+    pub fn read(&self, config: &[u8], offset: u64, data: &mut [u8]) {
+        debug_assert_eq!(config.len(), self.size);
+        match self.access(offset, data.len()) {
+            Some(range) => data.copy_from_slice(&config[range]),
+            None => data.fill(0),
+        }
+    }
+
+    /// Handles a write to a config space that's read-only for the driver.
+    pub fn write_read_only(&self, offset: u64, data: &[u8]) {
+        warn!(
+            "{}: guest driver attempted to write device config (offset={:x}, len={:x})",
+            self.device,
+            offset,
+            data.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Like virtio_blk_config: a u64, then two u32.
+    const LAYOUT: ConfigLayout = ConfigLayout::new("test", &[8, 4, 4]);
+
+    #[test]
+    fn test_access() {
+        assert_eq!(LAYOUT.size(), 16);
+
+        // Whole fields, and parts of a single field.
+        assert_eq!(LAYOUT.access(0, 8), Some(0..8));
+        assert_eq!(LAYOUT.access(4, 4), Some(4..8));
+        assert_eq!(LAYOUT.access(9, 2), Some(9..11));
+        assert_eq!(LAYOUT.access(8, 8), Some(8..16));
+        assert_eq!(LAYOUT.access(0, 16), Some(0..16));
+
+        // Tearing fields.
+        assert_eq!(LAYOUT.access(4, 8), None);
+        assert_eq!(LAYOUT.access(6, 4), None);
+        assert_eq!(LAYOUT.access(8, 6), None);
+
+        // Out of bounds.
+        assert_eq!(LAYOUT.access(12, 8), None);
+        assert_eq!(LAYOUT.access(16, 1), None);
+        assert_eq!(LAYOUT.access(u64::MAX, 2), None);
+        assert_eq!(LAYOUT.access(0, 0), None);
+
+        let opaque = ConfigLayout::opaque("test", 16);
+        assert_eq!(opaque.access(6, 4), Some(6..10));
+        assert_eq!(opaque.access(12, 8), None);
+    }
+
+    #[test]
+    fn test_read() {
+        let config: Vec<u8> = (0..16).collect();
+        let mut data = [0xff; 4];
+        LAYOUT.read(&config, 4, &mut data);
+        assert_eq!(data, [4, 5, 6, 7]);
+        LAYOUT.read(&config, 6, &mut data);
+        assert_eq!(data, [0; 4]);
+    }
+}
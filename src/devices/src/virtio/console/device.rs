@@ -1,5 +1,3 @@
-use std::cmp;
-use std::io::Write;
 use std::iter::zip;
 use std::mem::{size_of, size_of_val};
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -11,7 +9,8 @@ use utils::eventfd::EventFd;
 use vm_memory::{ByteValued, Bytes, GuestMemoryMmap};
 
 use super::super::{
-    ActivateError, ActivateResult, ConsoleError, DeviceState, Queue as VirtQueue, VirtioDevice,
+    ActivateError, ActivateResult, ConfigLayout, ConsoleError, DeviceState, Queue as VirtQueue,
+    VirtioDevice,
 };
 use super::{defs, defs::control_event, defs::uapi};
 use crate::virtio::console::console_control::{
@@ -29,7 +28,8 @@ pub(crate) const CONTROL_TXQ_INDEX: usize = 3;
 
 pub(crate) const AVAIL_FEATURES: u64 = (1 << uapi::VIRTIO_CONSOLE_F_SIZE as u64)
     | (1 << uapi::VIRTIO_CONSOLE_F_MULTIPORT as u64)
-    | (1 << uapi::VIRTIO_F_VERSION_1 as u64);
+    | (1 << uapi::VIRTIO_F_VERSION_1 as u64)
+    | (1 << uapi::VIRTIO_F_RING_PACKED as u64);
 
 #[repr(C)]
 #[derive(Default)]
@@ -71,6 +71,9 @@ pub struct VirtioConsoleConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioConsoleConfig {}
 
+const CONFIG_LAYOUT: ConfigLayout = ConfigLayout::new("console", &[2, 2, 4, 4]);
+const _: () = assert!(CONFIG_LAYOUT.size() == std::mem::size_of::<VirtioConsoleConfig>());
+
 impl VirtioConsoleConfig {
     pub fn new(cols: u16, rows: u16, max_nr_ports: u32) -> Self {
         VirtioConsoleConfig {
@@ -324,26 +327,12 @@ impl VirtioDevice for Console {
         &self.queue_events
     }
 
-    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_slice = self.config.as_slice();
-        let config_len = config_slice.len() as u64;
-        if offset >= config_len {
-            error!("Failed to read config space");
-            return;
-        }
-        if let Some(end) = offset.checked_add(data.len() as u64) {
-            // This write can't fail, offset and end are checked against config_len.
-            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
-                .unwrap();
-        }
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        CONFIG_LAYOUT.read(self.config.as_slice(), offset, data);
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        warn!(
-            "console: guest driver attempted to write device config (offset={:x}, len={:x})",
-            offset,
-            data.len()
-        );
+        CONFIG_LAYOUT.write_read_only(offset, data);
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
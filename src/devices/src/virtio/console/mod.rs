@@ -20,6 +20,7 @@ mod defs {
         pub const VIRTIO_CONSOLE_F_SIZE: u32 = 0;
         pub const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1;
         pub const VIRTIO_F_VERSION_1: u32 = 32;
+        pub const VIRTIO_F_RING_PACKED: u32 = 34;
         pub const VIRTIO_ID_CONSOLE: u32 = 3;
     }
 
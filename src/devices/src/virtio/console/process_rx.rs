@@ -76,6 +76,7 @@ fn pop_head_blocking<'mem>(
         match queue.pop(mem) {
             Some(descriptor) => break descriptor,
             None => {
+                queue.report_full();
                 interrupt.signal_used_queue();
                 thread::park();
                 log::trace!("rx unparked, queue len {}", queue.len(mem))
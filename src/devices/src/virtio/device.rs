@@ -5,7 +5,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use super::{ActivateResult, InterruptTransport, Queue};
+use super::{ActivateResult, InterruptTransport, PersistError, Queue};
 use crate::virtio::AsAny;
 use utils::eventfd::EventFd;
 use vm_memory::GuestMemoryMmap;
@@ -73,6 +73,14 @@ pub trait VirtioDevice: AsAny + Send {
     /// Returns the device queues event fds.
     fn queue_events(&self) -> &[EventFd];
 
+    /// Overrides the maximum size of every queue of the device, which must
+    /// not be activated yet.
+    fn set_queue_size(&mut self, size: u16) {
+        for queue in self.queues_mut() {
+            queue.set_max_size(size);
+        }
+    }
+
     /// The set of feature bits shifted by `page * 32`.
     fn avail_features_by_page(&self, page: u32) -> u32 {
         let avail_features = self.avail_features();
@@ -132,6 +140,22 @@ pub trait VirtioDevice: AsAny + Send {
     fn shm_region(&self) -> Option<&VirtioShmRegion> {
         None
     }
+
+    /// Returns the state of the device that isn't covered by its features and
+    /// queues, for `Persist::save`. Devices keeping some must encode it with
+    /// `persist::encode_device_state`.
+    fn save_device_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restores a state returned by `save_device_state`, before the device is
+    /// activated.
+    fn restore_device_state(&mut self, state: &[u8]) -> Result<(), PersistError> {
+        if !state.is_empty() {
+            return Err(PersistError::DeviceState);
+        }
+        Ok(())
+    }
 }
 
 pub trait VmmExitObserver: Send {
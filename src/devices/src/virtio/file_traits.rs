@@ -427,6 +427,21 @@ impl FileReadWriteAtVolatile for DiskProperties {
             return Ok(0);
         }
 
+        #[cfg(feature = "http")]
+        if let Some(http) = self.http() {
+            http.fetch(self.file(), offset, bufs.iter().map(|buf| buf.len()).sum())?;
+        }
+
+        #[cfg(feature = "luks")]
+        if let Some(crypt) = self.crypt() {
+            return crypt.read(self.file(), bufs, offset);
+        }
+
+        #[cfg(feature = "verity")]
+        if let Some(verity) = self.verity() {
+            return verity.read(self.file(), bufs, offset);
+        }
+
         let (iovec, _guard) = IoVectorMut::from_volatile_slice(bufs);
         let full_length = iovec
             .len()
@@ -445,6 +460,16 @@ impl FileReadWriteAtVolatile for DiskProperties {
             return Ok(0);
         }
 
+        #[cfg(feature = "http")]
+        if let Some(http) = self.http() {
+            http.fetch(self.file(), offset, bufs.iter().map(|buf| buf.len()).sum())?;
+        }
+
+        #[cfg(feature = "luks")]
+        if let Some(crypt) = self.crypt() {
+            return crypt.write(self.file(), bufs, offset);
+        }
+
         let (iovec, _guard) = IoVector::from_volatile_slice(bufs);
         let full_length = iovec
             .len()
@@ -1,19 +1,22 @@
 #[cfg(target_os = "macos")]
 use crossbeam_channel::Sender;
-use std::cmp;
-use std::io::Write;
 use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::thread::JoinHandle;
 
+use utils::boot_timeline::BootTimeline;
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
 #[cfg(target_os = "macos")]
 use utils::worker_message::WorkerMessage;
-use virtio_bindings::{virtio_config::VIRTIO_F_VERSION_1, virtio_ring::VIRTIO_RING_F_EVENT_IDX};
+use utils::worker_pool::PoolTask;
+use virtio_bindings::{
+    virtio_config::{VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1},
+    virtio_ring::{VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC},
+};
 use vm_memory::{ByteValued, GuestMemoryMmap};
 
 use super::super::{
-    ActivateResult, DeviceState, FsError, Queue as VirtQueue, VirtioDevice, VirtioShmRegion,
+    ActivateError, ActivateResult, ConfigLayout, DeviceState, FsError, Queue as VirtQueue,
+    VirtioDevice, VirtioShmRegion,
 };
 use super::passthrough;
 use super::worker::FsWorker;
@@ -39,6 +42,9 @@ impl Default for VirtioFsConfig {
 
 unsafe impl ByteValued for VirtioFsConfig {}
 
+const CONFIG_LAYOUT: ConfigLayout = ConfigLayout::new("fs", &[36, 4]);
+const _: () = assert!(CONFIG_LAYOUT.size() == std::mem::size_of::<VirtioFsConfig>());
+
 pub struct Fs {
     queues: Vec<VirtQueue>,
     queue_events: Vec<EventFd>,
@@ -48,7 +54,10 @@ pub struct Fs {
     config: VirtioFsConfig,
     shm_region: Option<VirtioShmRegion>,
     passthrough_cfg: passthrough::Config,
-    worker_thread: Option<JoinHandle<()>>,
+    readonly: bool,
+    quota: Option<u64>,
+    threads: usize,
+    worker: Option<PoolTask>,
     worker_stopfd: EventFd,
     exit_code: Arc<AtomicI32>,
     #[cfg(target_os = "macos")]
@@ -68,7 +77,10 @@ impl Fs {
                 .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(FsError::EventFd)?);
         }
 
-        let avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+        let avail_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_F_RING_PACKED)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_RING_F_INDIRECT_DESC);
 
         let tag = fs_id.into_bytes();
         let mut config = VirtioFsConfig::default();
@@ -89,7 +101,10 @@ impl Fs {
             config,
             shm_region: None,
             passthrough_cfg: fs_cfg,
-            worker_thread: None,
+            readonly: false,
+            quota: None,
+            threads: 1,
+            worker: None,
             worker_stopfd: EventFd::new(EFD_NONBLOCK).map_err(FsError::EventFd)?,
             exit_code,
             #[cfg(target_os = "macos")]
@@ -113,6 +128,43 @@ impl Fs {
         self.shm_region = Some(shm_region);
     }
 
+    pub fn set_writeback(&mut self, writeback: bool) {
+        self.passthrough_cfg.writeback = writeback;
+    }
+
+    pub fn set_allow_direct_io(&mut self, allow_direct_io: bool) {
+        self.passthrough_cfg.allow_direct_io = allow_direct_io;
+    }
+
+    pub fn set_fd_cache_size(&mut self, fd_cache_size: usize) {
+        self.passthrough_cfg.fd_cache_size = fd_cache_size;
+    }
+
+    /// Serves `init_binary` as init, instead of the bundled one.
+    pub fn set_init_binary(&mut self, init_binary: Option<Arc<[u8]>>) {
+        self.passthrough_cfg.init_binary = init_binary;
+    }
+
+    /// Records the progress init reports in `boot_timeline`.
+    pub fn set_boot_timeline(&mut self, boot_timeline: Arc<BootTimeline>) {
+        self.passthrough_cfg.boot_timeline = boot_timeline;
+    }
+
+    pub fn set_readonly(&mut self, readonly: bool) {
+        self.readonly = readonly;
+    }
+
+    /// Limits the disk usage of the shared directory to `quota` bytes.
+    pub fn set_quota(&mut self, quota: Option<u64>) {
+        self.quota = quota;
+    }
+
+    /// Serves the requests of the guest on `threads` threads, sharding them by
+    /// inode.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
     pub fn set_export_table(&mut self, export_table: ExportTable) -> u64 {
         static FS_UNIQUE_ID: AtomicU64 = AtomicU64::new(0);
 
@@ -161,31 +213,17 @@ impl VirtioDevice for Fs {
         &self.queue_events
     }
 
-    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_slice = self.config.as_slice();
-        let config_len = config_slice.len() as u64;
-        if offset >= config_len {
-            error!("Failed to read config space");
-            return;
-        }
-        if let Some(end) = offset.checked_add(data.len() as u64) {
-            // This write can't fail, offset and end are checked against config_len.
-            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
-                .unwrap();
-        }
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        CONFIG_LAYOUT.read(self.config.as_slice(), offset, data);
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        warn!(
-            "fs: guest driver attempted to write device config (offset={:x}, len={:x})",
-            offset,
-            data.len()
-        );
+        CONFIG_LAYOUT.write_read_only(offset, data);
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
-        if self.worker_thread.is_some() {
-            panic!("virtio_fs: worker thread already exists");
+        if self.worker.is_some() {
+            panic!("virtio_fs: worker already exists");
         }
 
         let event_idx: bool = (self.acked_features & (1 << VIRTIO_RING_F_EVENT_IDX)) != 0;
@@ -204,12 +242,15 @@ impl VirtioDevice for Fs {
             mem.clone(),
             self.shm_region.clone(),
             self.passthrough_cfg.clone(),
+            self.readonly,
+            self.quota,
+            self.threads,
             self.worker_stopfd.try_clone().unwrap(),
             self.exit_code.clone(),
             #[cfg(target_os = "macos")]
             self.map_sender.clone(),
         );
-        self.worker_thread = Some(worker.run());
+        self.worker = Some(worker.run().map_err(ActivateError::EpollCtl)?);
 
         self.device_state = DeviceState::Activated(mem, interrupt);
         Ok(())
@@ -224,10 +265,10 @@ impl VirtioDevice for Fs {
     }
 
     fn reset(&mut self) -> bool {
-        if let Some(worker) = self.worker_thread.take() {
+        if let Some(worker) = self.worker.take() {
             let _ = self.worker_stopfd.write(1);
             if let Err(e) = worker.join() {
-                error!("error waiting for worker thread: {e:?}");
+                error!("error waiting for worker: {e:?}");
             }
         }
         self.device_state = DeviceState::Inactive;
@@ -0,0 +1,102 @@
+//! Cache of the files the guest closed, so opening them again doesn't cost the
+//! host an `open(2)`.
+//!
+//! Metadata-heavy workloads open and close the same files over and over, and
+//! each open of the guest is an open of the host, which then dominates the
+//! time spent serving them. The closed files are kept open instead, up to a
+//! limit so the host doesn't run out of file descriptors, and handed back to
+//! the next open of the same inode with the same flags. The least recently
+//! closed files are closed first.
+//!
+//! The data of an unlinked file stays on the disk of the host until its file
+//! is evicted, or the guest forgets the inode.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::Mutex;
+
+pub struct FdCache {
+    capacity: usize,
+    /// The files with their inode and open flags, least recently used first.
+    files: Mutex<VecDeque<(u64, i32, File)>>,
+}
+
+impl FdCache {
+    /// A cache keeping up to `capacity` files open, or none if 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            files: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Takes a file of `inode` opened with `flags` out of the cache.
+    pub fn take(&self, inode: u64, flags: i32) -> Option<File> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut files = self.files.lock().unwrap();
+        let pos = files
+            .iter()
+            .rposition(|&(i, f, _)| i == inode && f == flags)?;
+        files.remove(pos).map(|(_, _, file)| file)
+    }
+
+    /// Keeps `file`, of `inode` opened with `flags`, for a later open, closing
+    /// the least recently used file if the cache is full.
+    pub fn put(&self, inode: u64, flags: i32, file: File) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut files = self.files.lock().unwrap();
+        if files.len() == self.capacity {
+            files.pop_front();
+        }
+        files.push_back((inode, flags, file));
+    }
+
+    /// Closes the files of `inode`.
+    pub fn evict(&self, inode: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.files.lock().unwrap().retain(|&(i, _, _)| i != inode);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn test_fd_cache() {
+        let cache = FdCache::new(2);
+        let (a, b, c) = (
+            File::open("/dev/null").unwrap(),
+            File::open("/dev/null").unwrap(),
+            File::open("/dev/null").unwrap(),
+        );
+        let (a_fd, c_fd) = (a.as_raw_fd(), c.as_raw_fd());
+
+        cache.put(1, libc::O_RDONLY, a);
+        assert!(cache.take(1, libc::O_RDWR).is_none());
+        assert_eq!(cache.take(1, libc::O_RDONLY).unwrap().as_raw_fd(), a_fd);
+        assert!(cache.take(1, libc::O_RDONLY).is_none());
+
+        // The least recently used file is evicted.
+        cache.put(1, libc::O_RDONLY, File::open("/dev/null").unwrap());
+        cache.put(2, libc::O_RDONLY, b);
+        cache.put(3, libc::O_RDONLY, c);
+        assert!(cache.take(1, libc::O_RDONLY).is_none());
+        assert_eq!(cache.take(3, libc::O_RDONLY).unwrap().as_raw_fd(), c_fd);
+
+        cache.evict(2);
+        assert!(cache.take(2, libc::O_RDONLY).is_none());
+
+        let cache = FdCache::new(0);
+        cache.put(1, libc::O_RDONLY, File::open("/dev/null").unwrap());
+        assert!(cache.take(1, libc::O_RDONLY).is_none());
+    }
+}
@@ -0,0 +1,28 @@
+//! Handling of the ioctls init reports to the VMM with on the root of the file
+//! system, see `utils::init_protocol`.
+
+use std::io;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use super::passthrough::Config;
+use utils::boot_timeline::BootPhase;
+use utils::init_protocol::InitIoctl;
+use utils::workload_stats::{WorkloadStat, WORKLOAD_STATS};
+
+pub(crate) fn handle(
+    ioctl: InitIoctl,
+    arg: u64,
+    cfg: &Config,
+    exit_code: &AtomicI32,
+) -> io::Result<Vec<u8>> {
+    match ioctl {
+        InitIoctl::ExitCode => exit_code.store(arg as i32, Ordering::SeqCst),
+        InitIoctl::RemoveRootDir => std::fs::remove_dir_all(&cfg.root_dir)?,
+        InitIoctl::WorkloadExec => cfg.boot_timeline.record(BootPhase::WorkloadExec),
+        InitIoctl::WorkloadMaxRss => WORKLOAD_STATS.set(WorkloadStat::MaxRss, arg),
+        InitIoctl::WorkloadUserTime => WORKLOAD_STATS.set(WorkloadStat::UserTime, arg),
+        InitIoctl::WorkloadSysTime => WORKLOAD_STATS.set(WorkloadStat::SysTime, arg),
+        InitIoctl::WorkloadWallTime => WORKLOAD_STATS.set(WorkloadStat::WallTime, arg),
+    }
+    Ok(Vec::new())
+}
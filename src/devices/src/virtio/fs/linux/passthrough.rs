@@ -16,16 +16,21 @@ use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use caps::{has_cap, CapSet, Capability};
-use nix::{request_code_none, request_code_read};
+use nix::request_code_read;
+use utils::boot_timeline::{BootPhase, BootTimeline};
+use utils::init_protocol::InitIoctl;
 
 use vm_memory::ByteValued;
 
+use super::super::fd_cache::FdCache;
 use super::super::filesystem::{
     Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions, GetxattrReply,
     ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
 };
 use super::super::fuse;
+use super::super::init_ioctl;
 use super::super::multikey::MultikeyBTreeMap;
+use super::fs_utils::copy_range;
 
 const CURRENT_DIR_CSTR: &[u8] = b".\0";
 const PARENT_DIR_CSTR: &[u8] = b"..\0";
@@ -57,6 +62,8 @@ struct InodeData {
 struct HandleData {
     inode: Inode,
     file: RwLock<File>,
+    /// The flags the file was opened with, if it can go to the fd cache once released.
+    cache_flags: Option<i32>,
     exported: AtomicBool,
 }
 
@@ -267,6 +274,20 @@ pub struct Config {
     /// The default value for this option is `false`.
     pub writeback: bool,
 
+    /// Whether the files the guest opens with `O_DIRECT` are opened with `O_DIRECT` on the host
+    /// too, so their data bypasses the page cache of the host as well as the guest's, as databases
+    /// expect. The host requires the buffers and offsets of direct I/O to be aligned, which the
+    /// requests of the guest may not be, so the flag is cleared otherwise.
+    ///
+    /// The default value for this option is `false`.
+    pub allow_direct_io: bool,
+
+    /// How many of the files the guest closed are kept open, to be reused when the guest opens
+    /// them again with the same flags. See `FdCache`.
+    ///
+    /// The default value for this option is 0, which disables the cache.
+    pub fd_cache_size: usize,
+
     /// The path of the root directory.
     ///
     /// The default is `/`.
@@ -290,6 +311,14 @@ pub struct Config {
     pub export_fsid: u64,
     /// Table of exported FDs to share with other subsystems.
     pub export_table: Option<ExportTable>,
+
+    /// The binary served as `init.krun`, replacing the bundled init.
+    ///
+    /// The default is `None`, which serves the bundled init.
+    pub init_binary: Option<Arc<[u8]>>,
+
+    /// The boot timeline of the microVM, where init reports its progress.
+    pub boot_timeline: Arc<BootTimeline>,
 }
 
 impl Default for Config {
@@ -299,11 +328,15 @@ impl Default for Config {
             attr_timeout: Duration::from_secs(5),
             cache_policy: Default::default(),
             writeback: false,
+            allow_direct_io: false,
+            fd_cache_size: 0,
             root_dir: String::from("/"),
             xattr: true,
             proc_sfd_rawfd: None,
             export_fsid: 0,
             export_table: None,
+            init_binary: None,
+            boot_timeline: Default::default(),
         }
     }
 }
@@ -338,6 +371,7 @@ pub struct PassthroughFs {
     // `cfg.writeback` is true and `init` was called with `FsOptions::WRITEBACK_CACHE`.
     writeback: AtomicBool,
     announce_submounts: AtomicBool,
+    fd_cache: FdCache,
     my_uid: Option<libc::uid_t>,
     my_gid: Option<libc::gid_t>,
     cap_fowner: bool,
@@ -353,6 +387,11 @@ enum FileOrLink {
 }
 
 impl PassthroughFs {
+    /// The binary served as init, the bundled one unless replaced.
+    fn init_binary(&self) -> &[u8] {
+        self.cfg.init_binary.as_deref().unwrap_or(INIT_BINARY)
+    }
+
     pub fn new(cfg: Config) -> io::Result<PassthroughFs> {
         let fd = if let Some(fd) = cfg.proc_sfd_rawfd {
             fd
@@ -410,6 +449,7 @@ impl PassthroughFs {
 
             writeback: AtomicBool::new(false),
             announce_submounts: AtomicBool::new(false),
+            fd_cache: FdCache::new(cfg.fd_cache_size),
             my_uid,
             my_gid,
             cap_fowner,
@@ -433,7 +473,8 @@ impl PassthroughFs {
         // userspace program opened the file write-only. So we need to ensure that we have opened
         // the file for reading as well as writing.
         let writeback = self.writeback.load(Ordering::Relaxed);
-        if writeback && flags & libc::O_ACCMODE == libc::O_WRONLY {
+        let write_only = flags & libc::O_ACCMODE == libc::O_WRONLY;
+        if writeback && write_only {
             flags &= !libc::O_ACCMODE;
             flags |= libc::O_RDWR;
         }
@@ -448,17 +489,32 @@ impl PassthroughFs {
             flags &= !libc::O_APPEND;
         }
 
+        if !self.cfg.allow_direct_io {
+            flags &= !libc::O_DIRECT;
+        }
+
         // Safe because this doesn't modify any memory and we check the return value. We don't
         // really check `flags` because if the kernel can't handle poorly specified flags then we
         // have much bigger problems. Also, clear the `O_NOFOLLOW` flag if it is set since we need
         // to follow the `/proc/self/fd` symlink to get the file.
-        let fd = unsafe {
+        let open = |flags: i32| unsafe {
             libc::openat(
                 self.proc_self_fd.as_raw_fd(),
                 pathname.as_ptr(),
                 (flags | libc::O_CLOEXEC) & (!libc::O_NOFOLLOW),
             )
         };
+        let mut fd = open(flags);
+        // The file may be writable but not readable. The kernel then can't fill the pages it
+        // caches from the file, so it writes them out whole instead, and the file can be opened
+        // the way the guest asked for.
+        if fd < 0
+            && writeback
+            && write_only
+            && io::Error::last_os_error().raw_os_error() == Some(libc::EACCES)
+        {
+            fd = open((flags & !libc::O_ACCMODE) | libc::O_WRONLY);
+        }
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -690,12 +746,21 @@ impl PassthroughFs {
             // work.
             flags &= !(libc::O_NOATIME as u32);
         }
-        let file = RwLock::new(self.open_inode(inode, flags as i32)?);
+        let flags = flags as i32;
+        let cache_flags =
+            (flags & (libc::O_DIRECTORY | libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC) == 0)
+                .then_some(flags);
+        let file = match cache_flags.and_then(|flags| self.fd_cache.take(inode, flags)) {
+            Some(file) => file,
+            None => self.open_inode(inode, flags)?,
+        };
+        let file = RwLock::new(file);
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
         let data = HandleData {
             inode,
             file,
+            cache_flags,
             exported: Default::default(),
         };
 
@@ -704,12 +769,9 @@ impl PassthroughFs {
         let mut opts = OpenOptions::empty();
         match self.cfg.cache_policy {
             // We only set the direct I/O option on files.
-            CachePolicy::Never => opts.set(
-                OpenOptions::DIRECT_IO,
-                flags & (libc::O_DIRECTORY as u32) == 0,
-            ),
+            CachePolicy::Never => opts.set(OpenOptions::DIRECT_IO, flags & libc::O_DIRECTORY == 0),
             CachePolicy::Always => {
-                if flags & (libc::O_DIRECTORY as u32) == 0 {
+                if flags & libc::O_DIRECTORY == 0 {
                     opts |= OpenOptions::KEEP_CACHE;
                 } else {
                     opts |= OpenOptions::CACHE_DIR;
@@ -737,8 +799,14 @@ impl PassthroughFs {
                 }
 
                 // We don't need to close the file here because that will happen automatically when
-                // the last `Arc` is dropped.
-                e.remove();
+                // the last `Arc` is dropped, unless it goes to the cache.
+                let data = e.remove();
+                if let Some(flags) = data.cache_flags {
+                    if let Ok(data) = Arc::try_unwrap(data) {
+                        self.fd_cache
+                            .put(inode, flags, data.file.into_inner().unwrap());
+                    }
+                }
                 return Ok(());
             }
         }
@@ -944,7 +1012,7 @@ impl FileSystem for PassthroughFs {
 
         if self.init_inode != 0 && name == init_name {
             let mut st: libc::stat64 = unsafe { mem::zeroed() };
-            st.st_size = INIT_BINARY.len() as i64;
+            st.st_size = self.init_binary().len() as i64;
             st.st_ino = self.init_inode;
             st.st_mode = 0o100_755;
 
@@ -964,14 +1032,20 @@ impl FileSystem for PassthroughFs {
     fn forget(&self, _ctx: Context, inode: Inode, count: u64) {
         let mut inodes = self.inodes.write().unwrap();
 
-        forget_one(&mut inodes, inode, count)
+        forget_one(&mut inodes, inode, count);
+        if inodes.get(&inode).is_none() {
+            self.fd_cache.evict(inode);
+        }
     }
 
     fn batch_forget(&self, _ctx: Context, requests: Vec<(Inode, u64)>) {
         let mut inodes = self.inodes.write().unwrap();
 
         for (inode, count) in requests {
-            forget_one(&mut inodes, inode, count)
+            forget_one(&mut inodes, inode, count);
+            if inodes.get(&inode).is_none() {
+                self.fd_cache.evict(inode);
+            }
         }
     }
 
@@ -1077,6 +1151,7 @@ impl FileSystem for PassthroughFs {
         flags: u32,
     ) -> io::Result<(Option<Handle>, OpenOptions)> {
         if inode == self.init_inode {
+            self.cfg.boot_timeline.record(BootPhase::InitStarted);
             Ok((Some(self.init_handle), OpenOptions::empty()))
         } else {
             self.do_open(inode, flags)
@@ -1119,6 +1194,11 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
+        let mut flags = flags as i32;
+        if !self.cfg.allow_direct_io {
+            flags &= !libc::O_DIRECT;
+        }
+
         // Safe because this doesn't modify any memory and we check the return value. We don't
         // really check `flags` because if the kernel can't handle poorly specified flags then we
         // have much bigger problems.
@@ -1126,7 +1206,7 @@ impl FileSystem for PassthroughFs {
             libc::openat(
                 data.file.as_raw_fd(),
                 name.as_ptr(),
-                flags as i32 | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
+                flags | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
                 mode & !(umask & 0o777),
             )
         };
@@ -1143,6 +1223,7 @@ impl FileSystem for PassthroughFs {
         let data = HandleData {
             inode: entry.inode,
             file,
+            cache_flags: None,
             exported: Default::default(),
         };
 
@@ -1178,12 +1259,12 @@ impl FileSystem for PassthroughFs {
             let off: usize = offset
                 .try_into()
                 .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
-            let len = if off + (size as usize) < INIT_BINARY.len() {
+            let len = if off + (size as usize) < self.init_binary().len() {
                 size as usize
             } else {
-                INIT_BINARY.len() - off
+                self.init_binary().len() - off
             };
-            return w.write(&INIT_BINARY[off..(off + len)]);
+            return w.write(&self.init_binary()[off..(off + len)]);
         }
 
         let data = self
@@ -1981,10 +2062,18 @@ impl FileSystem for PassthroughFs {
             )
         };
         if res < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(res as usize)
+            let err = io::Error::last_os_error();
+            // The host copies across file systems, or between the files of the ones without
+            // `copy_file_range` support, only since Linux 5.19 and 5.3 respectively. Copy the data
+            // ourselves then, which still saves the guest from reading and writing it back.
+            return match err.raw_os_error() {
+                Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP) if flags == 0 => {
+                    copy_range(fd_in, offset_in, fd_out, offset_out, len)
+                }
+                _ => Err(err),
+            };
         }
+        Ok(res as usize)
     }
 
     fn setupmapping(
@@ -2034,15 +2123,15 @@ impl FileSystem for PassthroughFs {
                 return Err(io::Error::last_os_error());
             }
 
-            let to_copy = if len as usize > INIT_BINARY.len() {
-                INIT_BINARY.len()
+            let to_copy = if len as usize > self.init_binary().len() {
+                self.init_binary().len()
             } else {
                 len as usize
             };
             unsafe {
                 libc::memcpy(
                     addr as *mut libc::c_void,
-                    INIT_BINARY.as_ptr() as *const _,
+                    self.init_binary().as_ptr() as *const _,
                     to_copy,
                 )
             };
@@ -2122,14 +2211,6 @@ impl FileSystem for PassthroughFs {
             VIRTIO_IOC_EXPORT_FD_SIZE
         ) as u32;
 
-        const VIRTIO_IOC_TYPE_EXIT_CODE: u8 = 2;
-        const VIRTIO_IOC_EXIT_CODE_REQ: u32 =
-            request_code_none!(VIRTIO_IOC_MAGIC, VIRTIO_IOC_TYPE_EXIT_CODE) as u32;
-
-        const VIRTIO_IOC_REMOVE_ROOT_DIR_CODE: u8 = 3;
-        const VIRTIO_IOC_REMOVE_ROOT_DIR_REQ: u32 =
-            request_code_none!(VIRTIO_IOC_MAGIC, VIRTIO_IOC_REMOVE_ROOT_DIR_CODE) as u32;
-
         match cmd {
             VIRTIO_IOC_EXPORT_FD_REQ => {
                 if out_size as usize != VIRTIO_IOC_EXPORT_FD_SIZE {
@@ -2160,15 +2241,10 @@ impl FileSystem for PassthroughFs {
                 ret.extend_from_slice(&handle.to_ne_bytes());
                 Ok(ret)
             }
-            VIRTIO_IOC_EXIT_CODE_REQ => {
-                exit_code.store(arg as i32, Ordering::SeqCst);
-                Ok(Vec::new())
-            }
-            VIRTIO_IOC_REMOVE_ROOT_DIR_REQ => {
-                std::fs::remove_dir_all(&self.cfg.root_dir)?;
-                Ok(Vec::new())
-            }
-            _ => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+            _ => match InitIoctl::from_request(cmd) {
+                Some(ioctl) => init_ioctl::handle(ioctl, arg, &self.cfg, exit_code),
+                None => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+            },
         }
     }
 }
@@ -1,4 +1,8 @@
+use std::fs::File;
 use std::io;
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{FromRawFd, RawFd};
 
 use super::super::super::linux_errno::linux_error;
 
@@ -9,3 +13,37 @@ pub fn ebadf() -> io::Error {
 pub fn einval() -> io::Error {
     linux_error(io::Error::from_raw_os_error(libc::EINVAL))
 }
+
+/// Largest range `copy_range` copies at once.
+const COPY_CHUNK: u64 = 1 << 20;
+
+/// Copies up to `len` bytes at `offset_in` in `fd_in` to `offset_out` in `fd_out` through a
+/// buffer, for the files the host can't copy the data of by itself. Returns the number of bytes
+/// copied, which is short when the end of `fd_in` is reached, or the range larger than the
+/// buffer, the guest calling again for the rest.
+pub fn copy_range(
+    fd_in: RawFd,
+    offset_in: u64,
+    fd_out: RawFd,
+    offset_out: u64,
+    len: u64,
+) -> io::Result<usize> {
+    // Safe because the caller keeps the fds open, and the files don't close them.
+    let file_in = ManuallyDrop::new(unsafe { File::from_raw_fd(fd_in) });
+    let file_out = ManuallyDrop::new(unsafe { File::from_raw_fd(fd_out) });
+
+    let mut buf = vec![0u8; len.min(COPY_CHUNK) as usize];
+    let mut read = 0;
+    while read < buf.len() {
+        match file_in.read_at(&mut buf[read..], offset_in + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(linux_error(e)),
+        }
+    }
+    file_out
+        .write_all_at(&buf[..read], offset_out)
+        .map_err(|e| linux_error(e))?;
+    Ok(read)
+}
@@ -17,18 +17,23 @@ use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use crossbeam_channel::{unbounded, Sender};
+use utils::boot_timeline::{BootPhase, BootTimeline};
+use utils::init_protocol::InitIoctl;
 use utils::worker_message::WorkerMessage;
 
 use crate::virtio::fs::filesystem::SecContext;
 
 use super::super::super::linux_errno::{linux_error, LINUX_ERANGE};
 use super::super::bindings;
+use super::super::fd_cache::FdCache;
 use super::super::filesystem::{
     Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions, GetxattrReply,
     ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
 };
 use super::super::fuse;
+use super::super::init_ioctl;
 use super::super::multikey::MultikeyBTreeMap;
+use super::fs_utils::copy_range;
 
 const INIT_CSTR: &[u8] = b"init.krun\0";
 const XATTR_KEY: &[u8] = b"user.containers.override_stat\0";
@@ -61,6 +66,8 @@ struct DirStream {
 struct HandleData {
     inode: Inode,
     file: RwLock<File>,
+    /// The flags the file was opened with, if it can go to the fd cache once released.
+    cache_flags: Option<i32>,
     dirstream: Mutex<DirStream>,
 }
 
@@ -367,6 +374,19 @@ pub struct Config {
     /// The default value for this option is `false`.
     pub writeback: bool,
 
+    /// Whether the files the guest opens with `O_DIRECT` are opened with `F_NOCACHE` on the host,
+    /// so their data bypasses the page cache of the host as well as the guest's, as databases
+    /// expect. The flag is ignored otherwise.
+    ///
+    /// The default value for this option is `false`.
+    pub allow_direct_io: bool,
+
+    /// How many of the files the guest closed are kept open, to be reused when the guest opens
+    /// them again with the same flags. See `FdCache`.
+    ///
+    /// The default value for this option is 0, which disables the cache.
+    pub fd_cache_size: usize,
+
     /// The path of the root directory.
     ///
     /// The default is `/`.
@@ -390,6 +410,14 @@ pub struct Config {
     pub export_fsid: u64,
     /// Table of exported FDs to share with other subsystems. Not supported for macos.
     pub export_table: Option<ExportTable>,
+
+    /// The binary served as `init.krun`, replacing the bundled init.
+    ///
+    /// The default is `None`, which serves the bundled init.
+    pub init_binary: Option<Arc<[u8]>>,
+
+    /// The boot timeline of the microVM, where init reports its progress.
+    pub boot_timeline: Arc<BootTimeline>,
 }
 
 impl Default for Config {
@@ -399,11 +427,15 @@ impl Default for Config {
             attr_timeout: Duration::from_secs(5),
             cache_policy: Default::default(),
             writeback: false,
+            allow_direct_io: false,
+            fd_cache_size: 0,
             root_dir: String::from("/"),
             xattr: true,
             proc_sfd_rawfd: None,
             export_fsid: 0,
             export_table: None,
+            init_binary: None,
+            boot_timeline: Default::default(),
         }
     }
 }
@@ -428,10 +460,16 @@ pub struct PassthroughFs {
     // `cfg.writeback` is true and `init` was called with `FsOptions::WRITEBACK_CACHE`.
     writeback: AtomicBool,
     announce_submounts: AtomicBool,
+    fd_cache: FdCache,
     cfg: Config,
 }
 
 impl PassthroughFs {
+    /// The binary served as init, the bundled one unless replaced.
+    fn init_binary(&self) -> &[u8] {
+        self.cfg.init_binary.as_deref().unwrap_or(INIT_BINARY)
+    }
+
     pub fn new(cfg: Config) -> io::Result<PassthroughFs> {
         let root = CString::new(cfg.root_dir.as_str()).expect("CString::new failed");
 
@@ -462,6 +500,7 @@ impl PassthroughFs {
 
             writeback: AtomicBool::new(false),
             announce_submounts: AtomicBool::new(false),
+            fd_cache: FdCache::new(cfg.fd_cache_size),
             cfg,
         })
     }
@@ -538,7 +577,6 @@ impl PassthroughFs {
         if fd < 0 {
             return Err(linux_error(io::Error::last_os_error()));
         }
-
         // Safe because we just opened this fd.
         Ok(unsafe { File::from_raw_fd(fd) })
     }
@@ -707,14 +745,31 @@ impl PassthroughFs {
     }
 
     fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
+        let direct = self.direct_io(flags as i32);
         let flags = self.parse_open_flags(flags as i32);
 
-        let file = RwLock::new(self.open_inode(inode, flags)?);
+        // The files with caching disabled aren't cached, so they aren't reused for the opens
+        // without `O_DIRECT`.
+        let cache_flags = (!direct
+            && flags & (libc::O_DIRECTORY | libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC) == 0)
+            .then_some(flags);
+        let file = match cache_flags.and_then(|flags| self.fd_cache.take(inode, flags)) {
+            Some(file) => file,
+            None => {
+                let file = self.open_inode(inode, flags)?;
+                if direct {
+                    set_nocache(file.as_raw_fd())?;
+                }
+                file
+            }
+        };
+        let file = RwLock::new(file);
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
         let data = HandleData {
             inode,
             file,
+            cache_flags,
             dirstream: Mutex::new(DirStream {
                 stream: 0,
                 offset: 0,
@@ -746,8 +801,14 @@ impl PassthroughFs {
         if let btree_map::Entry::Occupied(e) = handles.entry(handle) {
             if e.get().inode == inode {
                 // We don't need to close the file here because that will happen automatically when
-                // the last `Arc` is dropped.
-                e.remove();
+                // the last `Arc` is dropped, unless it goes to the cache.
+                let data = e.remove();
+                if let Some(flags) = data.cache_flags {
+                    if let Ok(data) = Arc::try_unwrap(data) {
+                        self.fd_cache
+                            .put(inode, flags, data.file.into_inner().unwrap());
+                    }
+                }
                 return Ok(());
             }
         }
@@ -788,6 +849,11 @@ impl PassthroughFs {
         }
     }
 
+    /// Whether a file opened with the Linux `flags` is opened for direct I/O.
+    fn direct_io(&self, flags: i32) -> bool {
+        self.cfg.allow_direct_io && flags & bindings::LINUX_O_DIRECT != 0
+    }
+
     fn parse_open_flags(&self, flags: i32) -> i32 {
         let mut mflags: i32 = flags & 0b11;
 
@@ -817,6 +883,16 @@ impl PassthroughFs {
     }
 }
 
+/// Disables the caching of the data of `fd` by the host, which is what
+/// `O_DIRECT` does on Linux.
+fn set_nocache(fd: RawFd) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    if unsafe { libc::fcntl(fd, libc::F_NOCACHE, 1) } < 0 {
+        return Err(linux_error(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
 fn set_secctx(file: StatFile, secctx: SecContext, symlink: bool) -> io::Result<()> {
     let options = if symlink { libc::XATTR_NOFOLLOW } else { 0 };
     let ret = match file {
@@ -975,7 +1051,7 @@ impl FileSystem for PassthroughFs {
 
         if self.init_inode != 0 && name == _init_name {
             let mut st: bindings::stat64 = unsafe { mem::zeroed() };
-            st.st_size = INIT_BINARY.len() as i64;
+            st.st_size = self.init_binary().len() as i64;
             st.st_ino = self.init_inode;
             st.st_mode = 0o100_755;
 
@@ -995,14 +1071,20 @@ impl FileSystem for PassthroughFs {
     fn forget(&self, _ctx: Context, inode: Inode, count: u64) {
         let mut inodes = self.inodes.write().unwrap();
 
-        forget_one(&mut inodes, inode, count)
+        forget_one(&mut inodes, inode, count);
+        if inodes.get(&inode).is_none() {
+            self.fd_cache.evict(inode);
+        }
     }
 
     fn batch_forget(&self, _ctx: Context, requests: Vec<(Inode, u64)>) {
         let mut inodes = self.inodes.write().unwrap();
 
         for (inode, count) in requests {
-            forget_one(&mut inodes, inode, count)
+            forget_one(&mut inodes, inode, count);
+            if inodes.get(&inode).is_none() {
+                self.fd_cache.evict(inode);
+            }
         }
     }
 
@@ -1121,6 +1203,7 @@ impl FileSystem for PassthroughFs {
         flags: u32,
     ) -> io::Result<(Option<Handle>, OpenOptions)> {
         if inode == self.init_inode {
+            self.cfg.boot_timeline.record(BootPhase::InitStarted);
             Ok((Some(self.init_handle), OpenOptions::empty()))
         } else {
             self.do_open(inode, flags)
@@ -1152,6 +1235,7 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
         let c_path = self.name_to_path(parent, name)?;
 
+        let direct = self.direct_io(flags as i32);
         let flags = self.parse_open_flags(flags as i32);
         let hostmode = if (flags & libc::O_DIRECTORY) != 0 {
             0o700
@@ -1182,6 +1266,13 @@ impl FileSystem for PassthroughFs {
             return Err(e);
         }
 
+        if direct {
+            if let Err(e) = set_nocache(fd) {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        }
+
         // Set security context
         if let Some(secctx) = extensions.secctx {
             set_secctx(StatFile::Fd(fd), secctx, false)?
@@ -1196,6 +1287,7 @@ impl FileSystem for PassthroughFs {
         let data = HandleData {
             inode: entry.inode,
             file,
+            cache_flags: None,
             dirstream: Mutex::new(DirStream {
                 stream: 0,
                 offset: 0,
@@ -1234,12 +1326,12 @@ impl FileSystem for PassthroughFs {
             let off: usize = offset
                 .try_into()
                 .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
-            let len = if off + (size as usize) < INIT_BINARY.len() {
+            let len = if off + (size as usize) < self.init_binary().len() {
                 size as usize
             } else {
-                INIT_BINARY.len() - off
+                self.init_binary().len() - off
             };
-            return w.write(&INIT_BINARY[off..(off + len)]);
+            return w.write(&self.init_binary()[off..(off + len)]);
         }
 
         let data = self
@@ -1886,7 +1978,7 @@ impl FileSystem for PassthroughFs {
         _ctx: Context,
         inode: Inode,
         handle: Handle,
-        _mode: u32,
+        mode: u32,
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
@@ -1901,6 +1993,31 @@ impl FileSystem for PassthroughFs {
 
         let fd = data.file.write().unwrap().as_raw_fd();
 
+        let mode = mode as i32;
+        if mode & !(bindings::LINUX_FALLOC_FL_KEEP_SIZE | bindings::LINUX_FALLOC_FL_PUNCH_HOLE) != 0
+        {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::EOPNOTSUPP)));
+        }
+
+        if mode & bindings::LINUX_FALLOC_FL_PUNCH_HOLE != 0 {
+            // Linux requires the size to be kept when punching holes, like macOS does.
+            if mode & bindings::LINUX_FALLOC_FL_KEEP_SIZE == 0 {
+                return Err(linux_error(io::Error::from_raw_os_error(libc::EOPNOTSUPP)));
+            }
+            let punchhole = libc::fpunchhole_t {
+                fp_flags: 0,
+                reserved: 0,
+                fp_offset: offset as libc::off_t,
+                fp_length: length as libc::off_t,
+            };
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe { libc::fcntl(fd, libc::F_PUNCHHOLE, &punchhole) };
+            if res < 0 {
+                return Err(linux_error(io::Error::last_os_error()));
+            }
+            return Ok(());
+        }
+
         let proposed_length = (offset + length) as i64;
         let mut fs = libc::fstore_t {
             fst_flags: libc::F_ALLOCATECONTIG,
@@ -1920,8 +2037,8 @@ impl FileSystem for PassthroughFs {
         }
 
         let st = fstat(fd, true)?;
-        if st.st_size >= proposed_length {
-            // fallocate should not shrink the file. The file is already larger than needed.
+        if st.st_size >= proposed_length || mode & bindings::LINUX_FALLOC_FL_KEEP_SIZE != 0 {
+            // fallocate should not shrink the file, nor grow it with FALLOC_FL_KEEP_SIZE.
             return Ok(());
         }
         let res = unsafe { libc::ftruncate(fd, proposed_length) };
@@ -1973,6 +2090,48 @@ impl FileSystem for PassthroughFs {
         }
     }
 
+    fn copyfilerange(
+        &self,
+        _ctx: Context,
+        inode_in: Inode,
+        handle_in: Handle,
+        offset_in: u64,
+        inode_out: Inode,
+        handle_out: Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        if flags != 0 {
+            return Err(einval());
+        }
+
+        let data_in = self
+            .handles
+            .read()
+            .unwrap()
+            .get(&handle_in)
+            .filter(|hd| hd.inode == inode_in)
+            .cloned()
+            .ok_or_else(ebadf)?;
+        let data_out = self
+            .handles
+            .read()
+            .unwrap()
+            .get(&handle_out)
+            .filter(|hd| hd.inode == inode_out)
+            .cloned()
+            .ok_or_else(ebadf)?;
+
+        // Take just read locks as we're not going to alter the file descriptor offsets.
+        let fd_in = data_in.file.read().unwrap().as_raw_fd();
+        let fd_out = data_out.file.read().unwrap().as_raw_fd();
+
+        // macOS has no `copy_file_range`, and only clones whole files into new ones, so copy the
+        // data ourselves, which still saves the guest from reading and writing it back.
+        copy_range(fd_in, offset_in, fd_out, offset_out, len)
+    }
+
     fn setupmapping(
         &self,
         _ctx: Context,
@@ -2113,21 +2272,9 @@ impl FileSystem for PassthroughFs {
         _out_size: u32,
         exit_code: &Arc<AtomicI32>,
     ) -> io::Result<Vec<u8>> {
-        // We can't use nix::request_code_none here since it's system-dependent
-        // and we need the value from Linux.
-        const VIRTIO_IOC_EXIT_CODE_REQ: u32 = 0x7602;
-        const VIRTIO_IOC_REMOVE_ROOT_DIR_REQ: u32 = 0x7603;
-
-        match cmd {
-            VIRTIO_IOC_EXIT_CODE_REQ => {
-                exit_code.store(arg as i32, Ordering::SeqCst);
-                Ok(Vec::new())
-            }
-            VIRTIO_IOC_REMOVE_ROOT_DIR_REQ => {
-                std::fs::remove_dir_all(&self.cfg.root_dir)?;
-                Ok(Vec::new())
-            }
-            _ => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+        match InitIoctl::from_request(cmd) {
+            Some(ioctl) => init_ioctl::handle(ioctl, arg, &self.cfg, exit_code),
+            None => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
         }
     }
 }
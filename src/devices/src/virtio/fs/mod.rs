@@ -1,9 +1,12 @@
 mod device;
+mod fd_cache;
 #[allow(dead_code)]
 mod filesystem;
 pub mod fuse;
+mod init_ioctl;
 #[allow(dead_code)]
 mod multikey;
+mod quota;
 mod server;
 mod worker;
 
@@ -26,6 +29,10 @@ use super::descriptor_utils;
 pub use self::defs::uapi::VIRTIO_ID_FS as TYPE_FS;
 pub use self::device::Fs;
 pub use self::filesystem::ExportTable;
+#[cfg(fuzzing)]
+pub(crate) use self::filesystem::FileSystem;
+#[cfg(any(target_os = "linux", fuzzing))]
+pub(crate) use self::server::Server;
 
 mod defs {
     pub const FS_DEV_ID: &str = "virtio_fs";
@@ -0,0 +1,127 @@
+//! Byte quota of an export, so a guest sharing a directory of the host can't
+//! fill the disk it's on.
+//!
+//! The disk usage of the export is measured once when the device starts, then
+//! kept up to date from the changes the guest makes. Writes that would grow
+//! the files beyond the quota fail with `EDQUOT`, as they would on a host file
+//! system with quotas. Changes made to the directory by the host while the VM
+//! runs aren't accounted for.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::super::linux_errno::linux_error;
+
+/// Units of `st_blocks`.
+const BLOCK_SIZE: u64 = 512;
+
+pub struct Quota {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl Quota {
+    /// A quota of `limit` bytes for the directory at `root`.
+    pub fn new(limit: u64, root: &Path) -> Self {
+        let used = disk_usage(root);
+        debug!("quota: {} uses {used} of {limit} bytes", root.display());
+        Self {
+            limit,
+            used: AtomicU64::new(used),
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Fails if the export can't grow by `growth` bytes.
+    pub fn check(&self, growth: u64) -> io::Result<()> {
+        if growth > 0 && self.used().saturating_add(growth) > self.limit {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::EDQUOT)));
+        }
+        Ok(())
+    }
+
+    /// Accounts for a file going from `before` to `after` bytes on disk.
+    pub fn update(&self, before: u64, after: u64) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some((used + after).saturating_sub(before))
+            });
+    }
+}
+
+/// The bytes the stat of a file says it uses on disk.
+pub fn blocks_size(st_blocks: i64) -> u64 {
+    st_blocks as u64 * BLOCK_SIZE
+}
+
+/// The bytes the files under `root` use on disk, counting the hard links of a
+/// file once.
+fn disk_usage(root: &Path) -> u64 {
+    let mut seen = HashSet::new();
+    let mut dirs = vec![root.to_path_buf()];
+    let mut used = 0;
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("quota: failed to read {}: {e}", dir.display());
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if metadata.nlink() > 1 && !seen.insert((metadata.dev(), metadata.ino())) {
+                continue;
+            }
+            used += metadata.blocks() * BLOCK_SIZE;
+        }
+    }
+    used
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempdir::TempDir;
+
+    use super::super::super::linux_errno::linux_errno_raw;
+
+    #[test]
+    fn test_quota() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("file");
+        fs::write(&path, vec![1u8; 64 * 1024]).unwrap();
+        fs::hard_link(&path, dir.as_path().join("link")).unwrap();
+
+        let used = fs::metadata(&path).unwrap().blocks() * BLOCK_SIZE;
+        let quota = Quota::new(used + 4096, dir.as_path());
+        assert_eq!(quota.used(), used);
+
+        quota.check(4096).unwrap();
+        assert_eq!(
+            quota.check(4097).unwrap_err().raw_os_error(),
+            Some(linux_errno_raw(libc::EDQUOT))
+        );
+        // Overwriting data needs no room.
+        quota.update(0, 4096);
+        quota.check(0).unwrap();
+
+        quota.update(used + 4096, 0);
+        assert_eq!(quota.used(), 0);
+        quota.update(4096, 0);
+        assert_eq!(quota.used(), 0);
+    }
+}
@@ -26,6 +26,7 @@ use super::filesystem::{
 };
 use super::fs_utils::einval;
 use super::fuse::*;
+use super::quota::{blocks_size, Quota};
 use super::{FsError as Error, Result};
 use crate::virtio::VirtioShmRegion;
 
@@ -68,6 +69,35 @@ impl io::Write for ZCWriter<'_> {
 pub struct Server<F: FileSystem + Sync> {
     fs: F,
     options: AtomicU64,
+    readonly: bool,
+    quota: Option<Quota>,
+}
+
+/// Whether a request with `opcode` always modifies the file system.
+fn modifies(opcode: u32) -> bool {
+    [
+        Opcode::Setattr,
+        Opcode::Symlink,
+        Opcode::Mknod,
+        Opcode::Mkdir,
+        Opcode::Unlink,
+        Opcode::Rmdir,
+        Opcode::Rename,
+        Opcode::Link,
+        Opcode::Write,
+        Opcode::Setxattr,
+        Opcode::Removexattr,
+        Opcode::Create,
+        Opcode::Fallocate,
+        Opcode::Rename2,
+        Opcode::CopyFileRange,
+    ]
+    .iter()
+    .any(|&op| op as u32 == opcode)
+}
+
+fn erofs() -> io::Error {
+    linux_error(io::Error::from_raw_os_error(libc::EROFS))
 }
 
 impl<F: FileSystem + Sync> Server<F> {
@@ -75,9 +105,58 @@ impl<F: FileSystem + Sync> Server<F> {
         Server {
             fs,
             options: AtomicU64::new(FsOptions::empty().bits()),
+            readonly: false,
+            quota: None,
+        }
+    }
+
+    /// Refuses the requests modifying the file system with `EROFS`.
+    pub fn set_readonly(&mut self, readonly: bool) {
+        self.readonly = readonly;
+    }
+
+    /// Refuses the requests growing the files beyond `quota` with `EDQUOT`.
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = Some(quota);
+    }
+
+    /// Checks that growing `inode` to `end` bytes fits in the quota, returning
+    /// the disk usage of the inode for `quota_update`, if there's a quota.
+    fn quota_check(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: Option<u64>,
+        end: u64,
+    ) -> io::Result<Option<u64>> {
+        let Some(quota) = &self.quota else {
+            return Ok(None);
+        };
+        let (st, _) = self.fs.getattr(ctx, inode.into(), handle.map(Into::into))?;
+        quota.check(end.saturating_sub(st.st_size as u64))?;
+        Ok(Some(blocks_size(st.st_blocks)))
+    }
+
+    /// Accounts for the change of the disk usage of `inode` from `before`.
+    fn quota_update(&self, ctx: Context, inode: u64, handle: Option<u64>, before: Option<u64>) {
+        let (Some(quota), Some(before)) = (&self.quota, before) else {
+            return;
+        };
+        if let Ok((st, _)) = self.fs.getattr(ctx, inode.into(), handle.map(Into::into)) {
+            quota.update(before, blocks_size(st.st_blocks));
         }
     }
 
+    /// The disk usage `name` in `parent` frees once removed, if there's a
+    /// quota and it's the last link to a file.
+    fn quota_removed(&self, ctx: Context, parent: u64, name: &CStr) -> Option<u64> {
+        self.quota.as_ref()?;
+        let entry = self.fs.lookup(ctx, parent.into(), name).ok()?;
+        let (nlink, blocks) = (entry.attr.st_nlink, entry.attr.st_blocks);
+        self.fs.forget(ctx, entry.inode.into(), 1);
+        (nlink == 1).then(|| blocks_size(blocks))
+    }
+
     #[allow(clippy::cognitive_complexity)]
     pub fn handle_message(
         &self,
@@ -97,6 +176,16 @@ impl<F: FileSystem + Sync> Server<F> {
             );
         }
         debug!("opcode: {}", in_header.opcode);
+        if self.readonly && modifies(in_header.opcode) {
+            return reply_error(erofs(), in_header.unique, w);
+        }
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!(
+            "fs_request",
+            opcode = in_header.opcode,
+            nodeid = in_header.nodeid
+        )
+        .entered();
         match in_header.opcode {
             x if x == Opcode::Lookup as u32 => self.lookup(in_header, r, w),
             x if x == Opcode::Forget as u32 => self.forget(in_header, r), // No reply.
@@ -256,14 +345,25 @@ impl<F: FileSystem + Sync> Server<F> {
 
         let st: bindings::stat64 = setattr_in.into();
 
-        match self.fs.setattr(
-            Context::from(in_header),
-            in_header.nodeid.into(),
-            st,
-            handle,
-            valid,
-        ) {
+        let ctx = Context::from(in_header);
+        let before = if valid.contains(SetattrValid::SIZE) {
+            let handle = (setattr_in.valid & FATTR_FH != 0).then_some(setattr_in.fh);
+            match self.quota_check(ctx, in_header.nodeid, handle, st.st_size as u64) {
+                Ok(before) => before,
+                Err(e) => return reply_error(e, in_header.unique, w),
+            }
+        } else {
+            None
+        };
+
+        match self
+            .fs
+            .setattr(ctx, in_header.nodeid.into(), st, handle, valid)
+        {
             Ok((st, timeout)) => {
+                if let (Some(quota), Some(before)) = (&self.quota, before) {
+                    quota.update(before, blocks_size(st.st_blocks));
+                }
                 let out = AttrOut {
                     attr_valid: timeout.as_secs(),
                     attr_valid_nsec: timeout.subsec_nanos(),
@@ -402,13 +502,18 @@ impl<F: FileSystem + Sync> Server<F> {
         let mut name = vec![0; namelen];
 
         r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
+        let name = bytes_to_cstr(&name)?;
 
-        match self.fs.unlink(
-            Context::from(in_header),
-            in_header.nodeid.into(),
-            bytes_to_cstr(&name)?,
-        ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+        let ctx = Context::from(in_header);
+        let freed = self.quota_removed(ctx, in_header.nodeid, name);
+
+        match self.fs.unlink(ctx, in_header.nodeid.into(), name) {
+            Ok(()) => {
+                if let (Some(quota), Some(freed)) = (&self.quota, freed) {
+                    quota.update(freed, 0);
+                }
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
             Err(e) => reply_error(e, in_header.unique, w),
         }
     }
@@ -456,16 +561,30 @@ impl<F: FileSystem + Sync> Server<F> {
             .ok_or(Error::MissingParameter)?;
 
         let (oldname, newname) = buf.split_at(split_pos);
+        let newname = bytes_to_cstr(newname)?;
+
+        // Renaming over a file removes it, unless the files are exchanged.
+        let ctx = Context::from(in_header);
+        let freed = if flags & bindings::LINUX_RENAME_EXCHANGE as u32 == 0 {
+            self.quota_removed(ctx, newdir, newname)
+        } else {
+            None
+        };
 
         match self.fs.rename(
-            Context::from(in_header),
+            ctx,
             in_header.nodeid.into(),
             bytes_to_cstr(oldname)?,
             newdir.into(),
-            bytes_to_cstr(newname)?,
+            newname,
             flags,
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Ok(()) => {
+                if let (Some(quota), Some(freed)) = (&self.quota, freed) {
+                    quota.update(freed, 0);
+                }
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
             Err(e) => reply_error(e, in_header.unique, w),
         }
     }
@@ -514,6 +633,14 @@ impl<F: FileSystem + Sync> Server<F> {
     fn open(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
         let OpenIn { flags, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        let open_flags = flags as i32;
+        if self.readonly
+            && (open_flags & libc::O_ACCMODE != libc::O_RDONLY
+                || open_flags & bindings::LINUX_O_TRUNC != 0)
+        {
+            return reply_error(erofs(), in_header.unique, w);
+        }
+
         match self
             .fs
             .open(Context::from(in_header), in_header.nodeid.into(), flags)
@@ -615,8 +742,14 @@ impl<F: FileSystem + Sync> Server<F> {
 
         let data_reader = ZCReader(r);
 
-        match self.fs.write(
-            Context::from(in_header),
+        let ctx = Context::from(in_header);
+        let before = match self.quota_check(ctx, in_header.nodeid, Some(fh), offset + size as u64) {
+            Ok(before) => before,
+            Err(e) => return reply_error(e, in_header.unique, w),
+        };
+
+        let res = self.fs.write(
+            ctx,
             in_header.nodeid.into(),
             fh.into(),
             data_reader,
@@ -626,7 +759,10 @@ impl<F: FileSystem + Sync> Server<F> {
             delayed_write,
             kill_priv,
             flags,
-        ) {
+        );
+        self.quota_update(ctx, in_header.nodeid, Some(fh), before);
+
+        match res {
             Ok(count) => {
                 let out = WriteOut {
                     size: count as u32,
@@ -1262,14 +1398,28 @@ impl<F: FileSystem + Sync> Server<F> {
             ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        match self.fs.fallocate(
-            Context::from(in_header),
+        let ctx = Context::from(in_header);
+        let end = if mode as i32 & bindings::LINUX_FALLOC_FL_PUNCH_HOLE != 0 {
+            0
+        } else {
+            offset + length
+        };
+        let before = match self.quota_check(ctx, in_header.nodeid, Some(fh), end) {
+            Ok(before) => before,
+            Err(e) => return reply_error(e, in_header.unique, w),
+        };
+
+        let res = self.fs.fallocate(
+            ctx,
             in_header.nodeid.into(),
             fh.into(),
             mode,
             offset,
             length,
-        ) {
+        );
+        self.quota_update(ctx, in_header.nodeid, Some(fh), before);
+
+        match res {
             Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
             Err(e) => reply_error(e, in_header.unique, w),
         }
@@ -1308,8 +1458,14 @@ impl<F: FileSystem + Sync> Server<F> {
             ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        match self.fs.copyfilerange(
-            Context::from(in_header),
+        let ctx = Context::from(in_header);
+        let before = match self.quota_check(ctx, nodeid_out, Some(fh_out), off_out + len) {
+            Ok(before) => before,
+            Err(e) => return reply_error(e, in_header.unique, w),
+        };
+
+        let res = self.fs.copyfilerange(
+            ctx,
             in_header.nodeid.into(),
             fh_in.into(),
             off_in,
@@ -1318,7 +1474,10 @@ impl<F: FileSystem + Sync> Server<F> {
             off_out,
             len,
             flags,
-        ) {
+        );
+        self.quota_update(ctx, nodeid_out, Some(fh_out), before);
+
+        match res {
             Ok(count) => {
                 let out = WriteOut {
                     size: count as u32,
@@ -1348,6 +1507,10 @@ impl<F: FileSystem + Sync> Server<F> {
             moffset,
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        if self.readonly && flags & SetupmappingFlags::WRITE.bits() != 0 {
+            return reply_error(erofs(), in_header.unique, w);
+        }
+
         match self.fs.setupmapping(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -3,35 +3,95 @@ use crossbeam_channel::Sender;
 #[cfg(target_os = "macos")]
 use utils::worker_message::WorkerMessage;
 
-use std::os::fd::AsRawFd;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
 use std::sync::atomic::AtomicI32;
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 
-use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use crossbeam_channel::Receiver;
+use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
+use utils::worker_pool::{run_handler, PoolHandler, PoolTask};
 use vm_memory::GuestMemoryMmap;
 
-use super::super::{FsError, Queue};
+use super::super::{DescriptorChain, DetachedChain, FsError, Queue};
 use super::defs::{HPQ_INDEX, REQ_INDEX};
 use super::descriptor_utils::{Reader, Writer};
+use super::fuse::InHeader;
 use super::passthrough::{self, PassthroughFs};
+use super::quota::Quota;
 use super::server::Server;
 use crate::virtio::{InterruptTransport, VirtioShmRegion};
 
-pub struct FsWorker {
-    queues: Vec<Queue>,
-    queue_evts: Vec<EventFd>,
+/// What the threads serving the requests share.
+struct Shared {
+    queues: Vec<Mutex<Queue>>,
     interrupt: InterruptTransport,
     mem: GuestMemoryMmap,
     shm_region: Option<VirtioShmRegion>,
     server: Server<PassthroughFs>,
-    stop_fd: EventFd,
     exit_code: Arc<AtomicI32>,
     #[cfg(target_os = "macos")]
     map_sender: Option<Sender<WorkerMessage>>,
 }
 
+impl Shared {
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn handle_request(&self, queue_index: usize, head: DescriptorChain) {
+        let index = head.index;
+        let reader = Reader::new(&self.mem, head.clone())
+            .map_err(FsError::QueueReader)
+            .unwrap();
+        let writer = Writer::new(&self.mem, head)
+            .map_err(FsError::QueueWriter)
+            .unwrap();
+
+        if let Err(e) = self.server.handle_message(
+            reader,
+            writer,
+            &self.shm_region,
+            &self.exit_code,
+            #[cfg(target_os = "macos")]
+            &self.map_sender,
+        ) {
+            error!("error handling message: {e:?}");
+        }
+
+        let mut queue = self.queues[queue_index].lock().unwrap();
+        if let Err(e) = queue.add_used(&self.mem, index, 0) {
+            error!("failed to add used elements to the queue: {e:?}");
+        }
+
+        if queue.needs_notification(&self.mem).unwrap() {
+            self.interrupt.signal_used_queue();
+        }
+    }
+
+    /// Serves the requests of a shard until its channel is closed.
+    fn serve(&self, receiver: Receiver<(usize, DetachedChain)>) {
+        for (queue_index, head) in receiver {
+            self.handle_request(queue_index, head.attach(&self.mem));
+        }
+    }
+}
+
+/// A thread serving the requests of a subset of the inodes, in the order the
+/// guest sent them.
+struct Shard {
+    sender: crossbeam_channel::Sender<(usize, DetachedChain)>,
+    thread: JoinHandle<()>,
+}
+
+pub struct FsWorker {
+    shared: Arc<Shared>,
+    queue_evts: Vec<EventFd>,
+    shards: Vec<Shard>,
+    num_shards: usize,
+    stop_fd: EventFd,
+}
+
 impl FsWorker {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -41,103 +101,81 @@ impl FsWorker {
         mem: GuestMemoryMmap,
         shm_region: Option<VirtioShmRegion>,
         passthrough_cfg: passthrough::Config,
+        readonly: bool,
+        quota: Option<u64>,
+        num_shards: usize,
         stop_fd: EventFd,
         exit_code: Arc<AtomicI32>,
         #[cfg(target_os = "macos")] map_sender: Option<Sender<WorkerMessage>>,
     ) -> Self {
+        let quota = quota.map(|limit| Quota::new(limit, Path::new(&passthrough_cfg.root_dir)));
+        let mut server = Server::new(PassthroughFs::new(passthrough_cfg).unwrap());
+        server.set_readonly(readonly);
+        if let Some(quota) = quota {
+            server.set_quota(quota);
+        }
+
         Self {
-            queues,
+            shared: Arc::new(Shared {
+                queues: queues.into_iter().map(Mutex::new).collect(),
+                interrupt,
+                mem,
+                shm_region,
+                server,
+                exit_code,
+                #[cfg(target_os = "macos")]
+                map_sender,
+            }),
             queue_evts,
-            interrupt,
-            mem,
-            shm_region,
-            server: Server::new(PassthroughFs::new(passthrough_cfg).unwrap()),
+            shards: Vec::new(),
+            num_shards,
             stop_fd,
-            exit_code,
-            #[cfg(target_os = "macos")]
-            map_sender,
         }
     }
 
-    pub fn run(self) -> thread::JoinHandle<()> {
-        thread::Builder::new()
-            .name("fs worker".into())
-            .spawn(|| self.work())
-            .unwrap()
-    }
-
-    fn work(mut self) {
-        let virtq_hpq_ev_fd = self.queue_evts[HPQ_INDEX].as_raw_fd();
-        let virtq_req_ev_fd = self.queue_evts[REQ_INDEX].as_raw_fd();
-        let stop_ev_fd = self.stop_fd.as_raw_fd();
-
-        let epoll = Epoll::new().unwrap();
-
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            virtq_hpq_ev_fd,
-            &EpollEvent::new(EventSet::IN, virtq_hpq_ev_fd as u64),
-        );
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            virtq_req_ev_fd,
-            &EpollEvent::new(EventSet::IN, virtq_req_ev_fd as u64),
-        );
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            stop_ev_fd,
-            &EpollEvent::new(EventSet::IN, stop_ev_fd as u64),
-        );
-
-        loop {
-            let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
-            match epoll.wait(epoll_events.len(), -1, epoll_events.as_mut_slice()) {
-                Ok(ev_cnt) => {
-                    for event in &epoll_events[0..ev_cnt] {
-                        let source = event.fd();
-                        let event_set = event.event_set();
-                        match event_set {
-                            EventSet::IN if source == virtq_hpq_ev_fd => {
-                                self.handle_event(HPQ_INDEX);
-                            }
-                            EventSet::IN if source == virtq_req_ev_fd => {
-                                self.handle_event(REQ_INDEX);
-                            }
-                            EventSet::IN if source == stop_ev_fd => {
-                                debug!("stopping worker thread");
-                                let _ = self.stop_fd.read();
-                                return;
-                            }
-                            _ => {
-                                log::warn!(
-                                    "Received unknown event: {event_set:?} from fd: {source:?}"
-                                );
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    debug!("failed to consume muxer epoll event: {e}");
-                }
+    pub fn run(mut self) -> io::Result<PoolTask> {
+        // With a single thread, the requests are served from the pool.
+        if self.num_shards > 1 {
+            for i in 0..self.num_shards {
+                let (sender, receiver) = crossbeam_channel::unbounded();
+                let shared = self.shared.clone();
+                let thread = thread::Builder::new()
+                    .name(format!("fs shard {i}"))
+                    .spawn(move || shared.serve(receiver))?;
+                self.shards.push(Shard { sender, thread });
             }
         }
+
+        let fds = [
+            (self.queue_evts[HPQ_INDEX].as_raw_fd(), EventSet::IN),
+            (self.queue_evts[REQ_INDEX].as_raw_fd(), EventSet::IN),
+            (self.stop_fd.as_raw_fd(), EventSet::IN),
+        ];
+        run_handler("fs worker", &fds, Box::new(self))
     }
 
-    fn handle_event(&mut self, queue_index: usize) {
+    fn handle_queue_event(&mut self, queue_index: usize) {
         debug!("Fs: queue event: {queue_index}");
-        if let Err(e) = self.queue_evts[queue_index].read() {
-            error!("Failed to get queue event: {e:?}");
+        match self.queue_evts[queue_index].read() {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => error!("Failed to get queue event: {e:?}"),
         }
 
+        let shared = self.shared.clone();
         loop {
-            self.queues[queue_index]
-                .disable_notification(&self.mem)
+            shared.queues[queue_index]
+                .lock()
+                .unwrap()
+                .disable_notification(&shared.mem)
                 .unwrap();
 
-            self.process_queue(queue_index);
+            self.process_queue(&shared, queue_index);
 
-            if !self.queues[queue_index]
-                .enable_notification(&self.mem)
+            if !shared.queues[queue_index]
+                .lock()
+                .unwrap()
+                .enable_notification(&shared.mem)
                 .unwrap()
             {
                 break;
@@ -145,34 +183,62 @@ impl FsWorker {
         }
     }
 
-    fn process_queue(&mut self, queue_index: usize) {
-        let queue = &mut self.queues[queue_index];
-        while let Some(head) = queue.pop(&self.mem) {
-            let reader = Reader::new(&self.mem, head.clone())
-                .map_err(FsError::QueueReader)
-                .unwrap();
-            let writer = Writer::new(&self.mem, head.clone())
-                .map_err(FsError::QueueWriter)
-                .unwrap();
+    fn process_queue(&self, shared: &Shared, queue_index: usize) {
+        loop {
+            // The queue can't stay locked while serving the request, which
+            // needs it to return the descriptor chain.
+            let Some(head) = shared.queues[queue_index].lock().unwrap().pop(&shared.mem) else {
+                break;
+            };
 
-            if let Err(e) = self.server.handle_message(
-                reader,
-                writer,
-                &self.shm_region,
-                &self.exit_code,
-                #[cfg(target_os = "macos")]
-                &self.map_sender,
-            ) {
-                error!("error handling message: {e:?}");
+            if self.shards.is_empty() {
+                shared.handle_request(queue_index, head);
+                continue;
+            }
+
+            // Requests about the same inode, including those on its handles,
+            // go to the same shard so they are served in order.
+            let nodeid = Reader::new(&shared.mem, head.clone())
+                .ok()
+                .and_then(|mut r| r.read_obj::<InHeader>().ok())
+                .map_or(0, |in_header| in_header.nodeid);
+            let shard = &self.shards[(nodeid % self.shards.len() as u64) as usize];
+            if shard.sender.send((queue_index, head.detach())).is_err() {
+                error!("virtio-fs shard stopped");
             }
+        }
+    }
 
-            if let Err(e) = queue.add_used(&self.mem, head.index, 0) {
-                error!("failed to add used elements to the queue: {e:?}");
+    fn stop_shards(&mut self) {
+        let threads: Vec<_> = self.shards.drain(..).map(|shard| shard.thread).collect();
+        // The shards stop once their channel is closed and drained.
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("virtio-fs shard panicked");
             }
+        }
+    }
+}
 
-            if queue.needs_notification(&self.mem).unwrap() {
-                self.interrupt.signal_used_queue();
+impl PoolHandler for FsWorker {
+    fn handle_event(&mut self, source: RawFd, events: EventSet) -> bool {
+        match events {
+            EventSet::IN if source == self.queue_evts[HPQ_INDEX].as_raw_fd() => {
+                self.handle_queue_event(HPQ_INDEX);
+            }
+            EventSet::IN if source == self.queue_evts[REQ_INDEX].as_raw_fd() => {
+                self.handle_queue_event(REQ_INDEX);
+            }
+            EventSet::IN if source == self.stop_fd.as_raw_fd() => {
+                debug!("stopping worker");
+                let _ = self.stop_fd.read();
+                self.stop_shards();
+                return false;
+            }
+            _ => {
+                log::warn!("Received unknown event: {events:?} from fd: {source:?}");
             }
         }
+        true
     }
 }
@@ -0,0 +1,145 @@
+//! Entry points feeding arbitrary data to the code parsing what the guest
+//! controls, the descriptor chains of the queues and the config space of the
+//! devices, for fuzzers. Only built with `--cfg fuzzing`, as done by
+//! cargo-fuzz, e.g. with a target calling `block_requests(data)` from
+//! `fuzz_target!`.
+//!
+//! The entry points taking descriptor chains copy the data to the start of
+//! the guest memory, where the descriptor table and available ring of the
+//! queue are, so the fuzzer controls both the chains and the buffers they
+//! point to.
+
+use std::sync::Arc;
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::Queue;
+
+const MEM_SIZE: usize = 0x10000;
+const QUEUE_SIZE: u16 = 16;
+// Right after the descriptor table, 16 bytes per descriptor.
+const AVAIL_RING: u64 = QUEUE_SIZE as u64 * 16;
+const USED_RING: u64 = 0x200;
+
+/// Guest memory holding `data`, and a queue laid out at its start. The
+/// first byte of `data` selects the packed layout if its lowest bit is set.
+fn guest_queue(data: &[u8]) -> (GuestMemoryMmap, Queue) {
+    let (packed, data) = match data.split_first() {
+        Some((flags, data)) => (flags & 1 != 0, data),
+        None => (false, data),
+    };
+    let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+    mem.write_slice(&data[..data.len().min(MEM_SIZE)], GuestAddress(0))
+        .unwrap();
+
+    let mut queue = Queue::new(QUEUE_SIZE);
+    queue.set_packed(packed);
+    queue.size = QUEUE_SIZE;
+    queue.ready = true;
+    queue.desc_table = GuestAddress(0);
+    queue.avail_ring = GuestAddress(AVAIL_RING);
+    queue.used_ring = GuestAddress(USED_RING);
+    (mem, queue)
+}
+
+/// Processes the block requests made available in the queue described by
+/// `data`, against a scratch disk.
+#[cfg(feature = "blk")]
+pub fn block_requests(data: &[u8]) {
+    use imago::file::File as ImagoFile;
+    use imago::SyncFormatAccess;
+    use utils::eventfd::EventFd;
+    use utils::tempfile::TempFile;
+
+    use super::block::device::DiskProperties;
+    use super::block::{BlockWorker, CacheType};
+    use crate::legacy::DummyIrqChip;
+    use crate::virtio::InterruptTransport;
+
+    let (mem, queue) = guest_queue(data);
+
+    let file = TempFile::new().unwrap();
+    file.as_file().set_len(0x10000).unwrap();
+    let raw = imago::raw::Raw::open_path_sync(file.as_path(), true).unwrap();
+    let disk_image: SyncFormatAccess<ImagoFile> = SyncFormatAccess::new(raw).unwrap();
+    let disk =
+        DiskProperties::new(Arc::new(disk_image), b"fuzz".to_vec(), CacheType::Unsafe).unwrap();
+
+    let interrupt =
+        InterruptTransport::new(DummyIrqChip::new().into(), "fuzz".into(), Arc::default()).unwrap();
+    let mut worker = BlockWorker::new(
+        queue,
+        EventFd::new(0).unwrap(),
+        interrupt,
+        mem.clone(),
+        disk,
+        EventFd::new(0).unwrap(),
+    );
+    worker.process_queue(&mem);
+}
+
+/// Decodes the FUSE requests made available in the queue described by
+/// `data`, for a file system implementing none of them.
+#[cfg(not(any(feature = "tee", feature = "nitro")))]
+pub fn fuse_requests(data: &[u8]) {
+    use std::sync::atomic::AtomicI32;
+
+    use super::descriptor_utils::{Reader, Writer};
+    use super::fs::{FileSystem, Server};
+
+    struct NullFs;
+
+    impl FileSystem for NullFs {
+        type Inode = u64;
+        type Handle = u64;
+    }
+
+    let (mem, mut queue) = guest_queue(data);
+    let server = Server::new(NullFs);
+    let exit_code = Arc::new(AtomicI32::new(0));
+    while let Some(head) = queue.pop(&mem) {
+        let (Ok(reader), Ok(writer)) = (
+            Reader::new(&mem, head.clone()),
+            Writer::new(&mem, head.clone()),
+        ) else {
+            continue;
+        };
+        let _ = server.handle_message(
+            reader,
+            writer,
+            &None,
+            &exit_code,
+            #[cfg(target_os = "macos")]
+            &None,
+        );
+    }
+}
+
+/// Applies the config space writes encoded in `data`, each as an offset
+/// byte, a length byte and the bytes written, to an input device, reading
+/// the whole config space back after each of them.
+#[cfg(not(feature = "tee"))]
+pub fn input_config(data: &[u8]) {
+    use super::input::{Input, InputEventQueue, InputKind};
+    use super::VirtioDevice;
+
+    // Size of `struct virtio_input_config`.
+    const CONFIG_SIZE: usize = 136;
+
+    let mut input = Input::new(
+        "fuzz".into(),
+        InputKind::Keyboard,
+        Arc::new(InputEventQueue::new().unwrap()),
+    )
+    .unwrap();
+
+    let mut data = data;
+    while let [offset, len, rest @ ..] = data {
+        let len = (*len as usize).min(rest.len());
+        input.write_config(*offset as u64, &rest[..len]);
+        data = &rest[len..];
+
+        let mut config = [0u8; CONFIG_SIZE];
+        input.read_config(0, &mut config);
+    }
+}
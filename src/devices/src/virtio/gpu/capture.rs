@@ -0,0 +1,317 @@
+//! Keeps a copy of what's being shown on each scanout, so it can be saved as
+//! a screenshot or streamed to the embedder, independently of the display
+//! backend in use.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use krun_display::{Rect, ResourceFormat, MAX_DISPLAYS};
+
+use super::display::rgb_offsets;
+
+/// A view of the contents of a scanout, `ResourceFormat::BYTES_PER_PIXEL`
+/// bytes per pixel without padding between lines.
+pub struct Frame<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub format: ResourceFormat,
+    pub data: &'a [u8],
+}
+
+/// Called with the id of the scanout, its new contents and the area that
+/// changed since the previous call.
+pub type FrameCallback = Box<dyn Fn(u32, &Frame, &Rect) + Send + Sync>;
+
+#[derive(Debug)]
+pub enum CaptureError {
+    /// The scanout doesn't exist or isn't showing anything.
+    NoScanout,
+    /// Error writing the screenshot.
+    Write(io::Error),
+}
+
+struct Scanout {
+    width: u32,
+    height: u32,
+    format: ResourceFormat,
+    data: Vec<u8>,
+    /// Area changed since the last frame sent to the callback.
+    damage: Option<Rect>,
+    last_sent: Option<Instant>,
+}
+
+struct FrameStream {
+    callback: FrameCallback,
+    min_interval: Duration,
+}
+
+pub struct ScanoutCapture {
+    scanouts: Mutex<[Option<Scanout>; MAX_DISPLAYS]>,
+    stream: Option<FrameStream>,
+}
+
+fn union(a: Option<Rect>, b: &Rect) -> Rect {
+    let Some(a) = a else {
+        return *b;
+    };
+    let x = a.x.min(b.x);
+    let y = a.y.min(b.y);
+    Rect {
+        x,
+        y,
+        width: (a.x + a.width).max(b.x + b.width) - x,
+        height: (a.y + a.height).max(b.y + b.height) - y,
+    }
+}
+
+impl ScanoutCapture {
+    /// Creates a capture calling `callback`, if any, with every new frame,
+    /// but no more than `max_fps` times per second, unless it's 0. Frames
+    /// arriving faster than that are merged into the next one sent.
+    pub fn new(callback: Option<FrameCallback>, max_fps: u32) -> Self {
+        let min_interval = match max_fps {
+            0 => Duration::ZERO,
+            fps => Duration::from_secs(1) / fps,
+        };
+        Self {
+            scanouts: Mutex::new(Default::default()),
+            stream: callback.map(|callback| FrameStream {
+                callback,
+                min_interval,
+            }),
+        }
+    }
+
+    pub fn configure(&self, scanout_id: u32, width: u32, height: u32, format: ResourceFormat) {
+        let mut scanouts = self.scanouts.lock().unwrap();
+        let Some(scanout) = scanouts.get_mut(scanout_id as usize) else {
+            return;
+        };
+        *scanout = Some(Scanout {
+            width,
+            height,
+            format,
+            data: vec![0; width as usize * height as usize * ResourceFormat::BYTES_PER_PIXEL],
+            damage: None,
+            last_sent: None,
+        });
+    }
+
+    pub fn disable(&self, scanout_id: u32) {
+        if let Some(scanout) = self.scanouts.lock().unwrap().get_mut(scanout_id as usize) {
+            *scanout = None;
+        }
+    }
+
+    /// Updates the `rect` area of the scanout from `data`, which holds the
+    /// whole new frame.
+    pub fn update(&self, scanout_id: u32, data: &[u8], rect: &Rect) {
+        let mut scanouts = self.scanouts.lock().unwrap();
+        let Some(Some(scanout)) = scanouts.get_mut(scanout_id as usize) else {
+            return;
+        };
+        if data.len() < scanout.data.len() {
+            return;
+        }
+
+        // Only copy what changed, the rest is already there.
+        let stride = scanout.width as usize * ResourceFormat::BYTES_PER_PIXEL;
+        let x_end = (rect.x + rect.width).min(scanout.width) as usize;
+        let y_end = (rect.y + rect.height).min(scanout.height) as usize;
+        let (x, y) = (rect.x as usize, rect.y as usize);
+        if x >= x_end || y >= y_end {
+            return;
+        }
+        for line in y..y_end {
+            let start = line * stride + x * ResourceFormat::BYTES_PER_PIXEL;
+            let end = line * stride + x_end * ResourceFormat::BYTES_PER_PIXEL;
+            scanout.data[start..end].copy_from_slice(&data[start..end]);
+        }
+
+        let Some(stream) = &self.stream else {
+            return;
+        };
+        let damage = union(scanout.damage, rect);
+        let now = Instant::now();
+        if let Some(last_sent) = scanout.last_sent {
+            if now.duration_since(last_sent) < stream.min_interval {
+                scanout.damage = Some(damage);
+                return;
+            }
+        }
+        scanout.damage = None;
+        scanout.last_sent = Some(now);
+
+        let frame = Frame {
+            width: scanout.width,
+            height: scanout.height,
+            format: scanout.format,
+            data: &scanout.data,
+        };
+        (stream.callback)(scanout_id, &frame, &damage);
+    }
+
+    /// Saves the current contents of the scanout to `path`, as a PNG image.
+    pub fn screenshot(&self, scanout_id: u32, path: &Path) -> Result<(), CaptureError> {
+        // Encode to memory first, instead of blocking the GPU worker on I/O.
+        let mut png = Vec::new();
+        {
+            let scanouts = self.scanouts.lock().unwrap();
+            let Some(Some(scanout)) = scanouts.get(scanout_id as usize) else {
+                return Err(CaptureError::NoScanout);
+            };
+            encode_png(
+                scanout.width,
+                scanout.height,
+                rgb_offsets(scanout.format),
+                &scanout.data,
+                &mut png,
+            )
+            .map_err(CaptureError::Write)?;
+        }
+
+        let mut file = BufWriter::new(File::create(path).map_err(CaptureError::Write)?);
+        file.write_all(&png)
+            .and_then(|_| file.flush())
+            .map_err(CaptureError::Write)
+    }
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc.sum().to_be_bytes())
+}
+
+/// Writes an 8-bit RGB PNG image, from pixels with their red, green and blue
+/// bytes at `offsets`.
+fn encode_png(
+    width: u32,
+    height: u32,
+    offsets: [usize; 3],
+    data: &[u8],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, truecolour, default compression and filter, no interlace.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    let stride = width as usize * ResourceFormat::BYTES_PER_PIXEL;
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    let mut line = Vec::with_capacity(1 + width as usize * 3);
+    for y in 0..height as usize {
+        line.clear();
+        // No filtering.
+        line.push(0);
+        for pixel in data[y * stride..(y + 1) * stride].chunks_exact(4) {
+            line.extend(offsets.iter().map(|&offset| pixel[offset]));
+        }
+        encoder.write_all(&line)?;
+    }
+    write_chunk(out, b"IDAT", &encoder.finish()?)?;
+    write_chunk(out, b"IEND", &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_encode_png() {
+        // A 2x1 BGRX image, red then blue.
+        let data = [0, 0, 0xff, 0, 0xff, 0, 0, 0];
+        let mut png = Vec::new();
+        encode_png(2, 1, [2, 1, 0], &data, &mut png).unwrap();
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+        // CRC of the IHDR chunk.
+        let mut crc = Crc::new();
+        crc.update(&png[12..29]);
+        assert_eq!(&png[29..33], &crc.sum().to_be_bytes());
+
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let mut pixels = Vec::new();
+        ZlibDecoder::new(&png[41..41 + idat_len])
+            .read_to_end(&mut pixels)
+            .unwrap();
+        assert_eq!(pixels, [0, 0xff, 0, 0, 0, 0, 0xff]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    }
+
+    #[test]
+    fn test_frame_stream() {
+        let damages = Arc::new(Mutex::new(Vec::new()));
+        let callback: FrameCallback = {
+            let damages = damages.clone();
+            Box::new(move |scanout_id, frame, damage| {
+                assert_eq!(scanout_id, 1);
+                assert_eq!(frame.data[0], 0xff);
+                damages
+                    .lock()
+                    .unwrap()
+                    .push((damage.x, damage.y, damage.width, damage.height));
+            })
+        };
+        // Slow enough for the second update to be throttled.
+        let capture = ScanoutCapture::new(Some(callback), 1);
+        capture.configure(1, 4, 4, ResourceFormat::BGRX);
+
+        let data = vec![0xff; 4 * 4 * 4];
+        capture.update(1, &data, &rect(0, 0, 1, 1));
+        capture.update(1, &data, &rect(2, 2, 2, 2));
+        // Unknown or disabled scanouts are ignored.
+        capture.update(0, &data, &rect(0, 0, 4, 4));
+        assert_eq!(*damages.lock().unwrap(), [(0, 0, 1, 1)]);
+
+        // Throttled damage is merged into the next frame.
+        capture.scanouts.lock().unwrap()[1]
+            .as_mut()
+            .unwrap()
+            .last_sent = None;
+        capture.update(1, &data, &rect(1, 0, 1, 1));
+        assert_eq!(*damages.lock().unwrap(), [(0, 0, 1, 1), (1, 0, 3, 4)]);
+    }
+
+    #[test]
+    fn test_partial_update() {
+        let capture = ScanoutCapture::new(None, 0);
+        capture.configure(0, 2, 2, ResourceFormat::BGRX);
+        capture.update(0, &[1; 16], &rect(1, 1, 8, 8));
+
+        let scanouts = capture.scanouts.lock().unwrap();
+        let data = &scanouts[0].as_ref().unwrap().data;
+        assert_eq!(&data[..12], &[0; 12]);
+        assert_eq!(&data[12..], &[1; 4]);
+    }
+}
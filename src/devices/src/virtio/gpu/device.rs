@@ -1,4 +1,3 @@
-use std::io::Write;
 use std::sync::{Arc, Mutex};
 
 use crossbeam_channel::{unbounded, Sender};
@@ -9,12 +8,14 @@ use super::super::{
     fs::ExportTable, ActivateError, ActivateResult, DeviceState, GpuError, Queue as VirtQueue,
     VirtioDevice, VirtioShmRegion,
 };
+use super::capture::ScanoutCapture;
 use super::defs;
 use super::defs::uapi;
 use super::defs::uapi::virtio_gpu_config;
+use super::virtio_gpu::capset_mask;
 use super::worker::Worker;
 use crate::virtio::display::DisplayInfo;
-use crate::virtio::InterruptTransport;
+use crate::virtio::{ConfigLayout, InterruptTransport};
 use krun_display::DisplayBackend;
 #[cfg(target_os = "macos")]
 use utils::worker_message::WorkerMessage;
@@ -26,12 +27,16 @@ pub(crate) const CUR_INDEX: usize = 1;
 
 // Supported features.
 pub(crate) const AVAIL_FEATURES: u64 = (1u64 << uapi::VIRTIO_F_VERSION_1)
+    | (1u64 << uapi::VIRTIO_F_RING_PACKED)
     | (1u64 << uapi::VIRTIO_GPU_F_VIRGL)
     | (1u64 << uapi::VIRTIO_GPU_F_EDID)
     | (1u64 << uapi::VIRTIO_GPU_F_RESOURCE_UUID)
     | (1u64 << uapi::VIRTIO_GPU_F_RESOURCE_BLOB)
     | (1u64 << uapi::VIRTIO_GPU_F_CONTEXT_INIT);
 
+const CONFIG_LAYOUT: ConfigLayout = ConfigLayout::new("gpu", &[4, 4, 4, 4]);
+const _: () = assert!(CONFIG_LAYOUT.size() == std::mem::size_of::<virtio_gpu_config>());
+
 pub struct Gpu {
     pub(crate) queue_ctl: Arc<Mutex<VirtQueue>>,
     pub(crate) queue_cur: Arc<Mutex<VirtQueue>>,
@@ -49,6 +54,7 @@ pub struct Gpu {
     export_table: Option<ExportTable>,
     displays: Box<[DisplayInfo]>,
     display_backend: DisplayBackend<'static>,
+    capture: Option<Arc<ScanoutCapture>>,
 }
 
 impl Gpu {
@@ -85,6 +91,7 @@ impl Gpu {
             export_table: None,
             displays,
             display_backend,
+            capture: None,
         })
     }
 
@@ -121,6 +128,10 @@ impl Gpu {
         self.export_table = Some(export_table);
     }
 
+    pub fn set_capture(&mut self, capture: Arc<ScanoutCapture>) {
+        self.capture = Some(capture);
+    }
+
     /*
     pub fn process_ctl(&mut self) -> bool {
         debug!("gpu: process_ctl()");
@@ -210,33 +221,19 @@ impl VirtioDevice for Gpu {
         &self.queue_events
     }
 
-    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
         let config = virtio_gpu_config {
             events_read: 0,
             events_clear: 0,
             num_scanouts: self.displays.len() as u32,
-            num_capsets: 5,
+            num_capsets: capset_mask(self.virgl_flags).count_ones(),
         };
 
-        let config_slice = config.as_slice();
-        let config_len = config_slice.len() as u64;
-        if offset >= config_len {
-            error!("Failed to read config space");
-            return;
-        }
-        if let Some(end) = offset.checked_add(data.len() as u64) {
-            // This write can't fail, offset and end are checked against config_len.
-            data.write_all(&config_slice[offset as usize..std::cmp::min(end, config_len) as usize])
-                .unwrap();
-        }
+        CONFIG_LAYOUT.read(config.as_slice(), offset, data);
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        warn!(
-            "gpu: guest driver attempted to write device config (offset={:x}, len={:x})",
-            offset,
-            data.len()
-        );
+        CONFIG_LAYOUT.write_read_only(offset, data);
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
@@ -270,6 +267,7 @@ impl VirtioDevice for Gpu {
             self.export_table.take(),
             self.displays.clone(),
             self.display_backend,
+            self.capture.clone(),
         );
         worker.run();
 
@@ -1,7 +1,11 @@
 use super::edid::EdidInfo;
+#[cfg(not(feature = "tee"))]
+use crate::rfb::RfbServer;
 use krun_display::{
     DisplayBackendBasicFramebuffer, DisplayBackendError, DisplayBackendNew, Rect, ResourceFormat,
 };
+#[cfg(not(feature = "tee"))]
+use std::sync::Arc;
 use virtio_bindings::virtio_gpu::VIRTIO_GPU_MAX_SCANOUTS;
 
 #[derive(Clone, Debug)]
@@ -62,6 +66,17 @@ impl DisplayInfo {
 
 pub const MAX_DISPLAYS: usize = VIRTIO_GPU_MAX_SCANOUTS as usize;
 
+/// Returns the offsets of the red, green and blue bytes within the pixels of
+/// `format`.
+pub(crate) fn rgb_offsets(format: ResourceFormat) -> [usize; 3] {
+    match format {
+        ResourceFormat::BGRA | ResourceFormat::BGRX => [2, 1, 0],
+        ResourceFormat::ARGB | ResourceFormat::XRGB => [1, 2, 3],
+        ResourceFormat::RGBA | ResourceFormat::RGBX => [0, 1, 2],
+        ResourceFormat::ABGR | ResourceFormat::XBGR => [3, 2, 1],
+    }
+}
+
 pub struct NoopDisplayBackend;
 
 impl DisplayBackendNew<()> for NoopDisplayBackend {
@@ -100,3 +115,83 @@ impl DisplayBackendBasicFramebuffer for NoopDisplayBackend {
         Err(DisplayBackendError::InvalidScanoutId)
     }
 }
+
+/// Shows the first scanout to the clients of an `RfbServer`.
+#[cfg(not(feature = "tee"))]
+pub struct RfbDisplayBackend {
+    server: Arc<RfbServer>,
+    frame: Option<Vec<u8>>,
+}
+
+#[cfg(not(feature = "tee"))]
+impl DisplayBackendNew<Arc<RfbServer>> for RfbDisplayBackend {
+    fn new(server: Option<&Arc<RfbServer>>) -> Self {
+        Self {
+            server: server
+                .expect(
+                    "The server should have been set by RfbDisplayBackend::into_display_backend",
+                )
+                .clone(),
+            frame: None,
+        }
+    }
+}
+
+#[cfg(not(feature = "tee"))]
+impl DisplayBackendBasicFramebuffer for RfbDisplayBackend {
+    fn configure_scanout(
+        &mut self,
+        scanout_id: u32,
+        _display_width: u32,
+        _display_height: u32,
+        width: u32,
+        height: u32,
+        format: ResourceFormat,
+    ) -> Result<(), DisplayBackendError> {
+        if scanout_id != 0 {
+            return Err(DisplayBackendError::InvalidScanoutId);
+        }
+        self.frame = Some(vec![
+            0;
+            width as usize
+                * height as usize
+                * ResourceFormat::BYTES_PER_PIXEL
+        ]);
+        self.server.configure(width, height, rgb_offsets(format));
+        Ok(())
+    }
+
+    fn disable_scanout(&mut self, scanout_id: u32) -> Result<(), DisplayBackendError> {
+        if scanout_id != 0 {
+            return Err(DisplayBackendError::InvalidScanoutId);
+        }
+        if let Some(frame) = &mut self.frame {
+            frame.fill(0);
+            self.server.update(frame);
+        }
+        self.frame = None;
+        Ok(())
+    }
+
+    fn alloc_frame(&mut self, scanout_id: u32) -> Result<(u32, &mut [u8]), DisplayBackendError> {
+        match &mut self.frame {
+            Some(frame) if scanout_id == 0 => Ok((1, frame.as_mut())),
+            _ => Err(DisplayBackendError::InvalidScanoutId),
+        }
+    }
+
+    fn present_frame(
+        &mut self,
+        scanout_id: u32,
+        _frame_id: u32,
+        _rect: Option<&Rect>,
+    ) -> Result<(), DisplayBackendError> {
+        match &self.frame {
+            Some(frame) if scanout_id == 0 => {
+                self.server.update(frame);
+                Ok(())
+            }
+            _ => Err(DisplayBackendError::InvalidScanoutId),
+        }
+    }
+}
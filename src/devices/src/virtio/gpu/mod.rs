@@ -1,3 +1,4 @@
+pub mod capture;
 mod device;
 pub mod display;
 mod edid;
@@ -21,6 +22,7 @@ mod defs {
         use vm_memory::ByteValued;
 
         pub const VIRTIO_F_VERSION_1: u32 = 32;
+        pub const VIRTIO_F_RING_PACKED: u32 = 34;
         pub const VIRTIO_ID_GPU: u32 = 16;
 
         pub const VIRTIO_GPU_F_VIRGL: u32 = 0;
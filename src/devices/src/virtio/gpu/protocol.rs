@@ -424,6 +424,7 @@ pub const VIRTIO_GPU_CAPSET_VIRGL2: u32 = 2;
 pub const VIRTIO_GPU_CAPSET_GFXSTREAM: u32 = 3;
 pub const VIRTIO_GPU_CAPSET_VENUS: u32 = 4;
 pub const VIRTIO_GPU_CAPSET_CROSS_DOMAIN: u32 = 5;
+pub const VIRTIO_GPU_CAPSET_DRM: u32 = 6;
 
 /* VIRTIO_GPU_CMD_GET_CAPSET_INFO */
 #[derive(Copy, Clone, Debug, Default, FromBytes, IntoBytes, Immutable)]
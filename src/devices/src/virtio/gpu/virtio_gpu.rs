@@ -7,10 +7,13 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use super::super::Queue as VirtQueue;
+use super::capture::ScanoutCapture;
 use super::protocol::GpuResponse::*;
 use super::protocol::{
     GpuResponse, GpuResponsePlaneInfo, VirtioGpuResult, VIRTIO_GPU_BLOB_FLAG_CREATE_GUEST_HANDLE,
-    VIRTIO_GPU_BLOB_MEM_HOST3D, VIRTIO_GPU_MAX_SCANOUTS,
+    VIRTIO_GPU_BLOB_MEM_HOST3D, VIRTIO_GPU_CAPSET_CROSS_DOMAIN, VIRTIO_GPU_CAPSET_DRM,
+    VIRTIO_GPU_CAPSET_VENUS, VIRTIO_GPU_CAPSET_VIRGL, VIRTIO_GPU_CAPSET_VIRGL2,
+    VIRTIO_GPU_MAX_SCANOUTS,
 };
 #[cfg(target_os = "macos")]
 use crossbeam_channel::{unbounded, Sender};
@@ -44,6 +47,29 @@ use crate::virtio::fs::ExportTable;
 use crate::virtio::gpu::protocol::VIRTIO_GPU_FLAG_INFO_RING_IDX;
 use crate::virtio::{InterruptTransport, VirtioShmRegion};
 
+// From virglrenderer.h.
+const VIRGLRENDERER_VENUS: u32 = 1 << 6;
+const VIRGLRENDERER_NO_VIRGL: u32 = 1 << 7;
+const VIRGLRENDERER_DRM: u32 = 1 << 10;
+
+/// Returns the mask of the capsets to expose to the guest for the given
+/// virglrenderer flags, so it only sees the context types that are actually
+/// enabled. In particular, guest Mesa only uses a DRM native context
+/// (amdgpu, freedreno, asahi) if the DRM capset is there.
+pub(crate) fn capset_mask(virgl_flags: u32) -> u64 {
+    let mut mask = 1 << VIRTIO_GPU_CAPSET_CROSS_DOMAIN;
+    if virgl_flags & VIRGLRENDERER_NO_VIRGL == 0 {
+        mask |= (1 << VIRTIO_GPU_CAPSET_VIRGL) | (1 << VIRTIO_GPU_CAPSET_VIRGL2);
+    }
+    if virgl_flags & VIRGLRENDERER_VENUS != 0 {
+        mask |= 1 << VIRTIO_GPU_CAPSET_VENUS;
+    }
+    if virgl_flags & VIRGLRENDERER_DRM != 0 {
+        mask |= 1 << VIRTIO_GPU_CAPSET_DRM;
+    }
+    mask
+}
+
 fn sglist_to_rutabaga_iovecs(
     vecs: &[(GuestAddress, usize)],
     mem: &GuestMemoryMmap,
@@ -154,6 +180,7 @@ pub struct VirtioGpu {
     scanouts: [Option<VirtioGpuScanout>; VIRTIO_GPU_MAX_SCANOUTS as usize],
     displays: Box<[DisplayInfo]>,
     display_backend: DisplayBackendInstance,
+    capture: Option<Arc<ScanoutCapture>>,
 }
 
 impl VirtioGpu {
@@ -220,6 +247,7 @@ impl VirtioGpu {
         export_table: Option<ExportTable>,
         displays: Box<[DisplayInfo]>,
         display_backend: DisplayBackend,
+        capture: Option<Arc<ScanoutCapture>>,
     ) -> Self {
         let xdg_runtime_dir = match env::var("XDG_RUNTIME_DIR") {
             Ok(dir) => dir,
@@ -265,7 +293,7 @@ impl VirtioGpu {
         let builder = RutabagaBuilder::new(
             rutabaga_gfx::RutabagaComponentType::VirglRenderer,
             virgl_flags,
-            0,
+            capset_mask(virgl_flags),
         )
         .set_rutabaga_channels(rutabaga_channels_opt);
         let builder = if let Some(export_table) = export_table {
@@ -292,6 +320,7 @@ impl VirtioGpu {
             scanouts: Default::default(),
             displays,
             display_backend,
+            capture,
             #[cfg(target_os = "macos")]
             map_sender,
         }
@@ -402,6 +431,9 @@ impl VirtioGpu {
         if resource_id == 0 {
             debug!("Disabling scanout {scanout_id:?}");
             *scanout = None;
+            if let Some(capture) = &self.capture {
+                capture.disable(scanout_id);
+            }
             self.display_backend.disable_scanout(scanout_id)?;
             return Ok(OkNoData);
         }
@@ -431,6 +463,9 @@ impl VirtioGpu {
             height,
             format,
         )?;
+        if let Some(capture) = &self.capture {
+            capture.configure(scanout_id, width, height, format);
+        }
 
         *scanout = Some(VirtioGpuScanout { resource_id });
         Ok(OkNoData)
@@ -479,6 +514,9 @@ impl VirtioGpu {
                 log::error!("Failed to read resource {resource_id} for scanout {scanout_id}: {e}");
                 return Err(ErrUnspec);
             }
+            if let Some(capture) = &self.capture {
+                capture.update(scanout_id, buffer, &rect);
+            }
             self.display_backend
                 .present_frame(scanout_id, frame_id, Some(&rect))?
         }
@@ -948,6 +986,16 @@ impl VirtioGpu {
 mod test {
     use crate::virtio::gpu::protocol::VIRTIO_GPU_MAX_SCANOUTS;
 
+    #[test]
+    fn test_capset_mask() {
+        use super::capset_mask;
+
+        // virgl, virgl2 and cross-domain.
+        assert_eq!(capset_mask(0), 0b10_0110);
+        // venus and drm, without virgl.
+        assert_eq!(capset_mask((1 << 6) | (1 << 7) | (1 << 10)), 0b111_0000);
+    }
+
     #[test]
     fn test_virtio_gpu_associated_scanouts() {
         use super::AssociatedScanouts;
@@ -15,6 +15,7 @@ use vm_memory::{GuestAddress, GuestMemoryMmap};
 
 use super::super::descriptor_utils::{Reader, Writer};
 use super::super::{GpuError, Queue as VirtQueue};
+use super::capture::ScanoutCapture;
 use super::protocol::{
     virtio_gpu_ctrl_hdr, virtio_gpu_mem_entry, GpuCommand, GpuResponse, VirtioGpuResult,
 };
@@ -39,6 +40,7 @@ pub struct Worker {
     export_table: Option<ExportTable>,
     displays: Box<[DisplayInfo]>,
     display_backend: DisplayBackend<'static>,
+    capture: Option<Arc<ScanoutCapture>>,
 }
 
 impl Worker {
@@ -54,6 +56,7 @@ impl Worker {
         export_table: Option<ExportTable>,
         displays: Box<[DisplayInfo]>,
         display_backend: DisplayBackend<'static>,
+        capture: Option<Arc<ScanoutCapture>>,
     ) -> Self {
         Self {
             receiver,
@@ -67,6 +70,7 @@ impl Worker {
             export_table,
             displays,
             display_backend,
+            capture,
         }
     }
 
@@ -88,6 +92,7 @@ impl Worker {
             self.export_table.take(),
             self.displays.clone(),
             self.display_backend,
+            self.capture.take(),
         );
 
         loop {
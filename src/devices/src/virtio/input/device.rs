@@ -0,0 +1,408 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use utils::eventfd::EventFd;
+use vm_memory::{ByteValued, Bytes, GuestMemoryMmap};
+
+use super::super::{ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, VirtioDevice};
+use super::defs::uapi;
+use super::{codes, defs, InputError, InputEvent, InputEventQueue, InputKind, TABLET_ABS_MAX};
+use crate::journal::Journal;
+use crate::virtio::{persist, ConfigLayout, InterruptTransport, PersistError};
+
+pub(crate) const EVENT_INDEX: usize = 0;
+pub(crate) const STATUS_INDEX: usize = 1;
+
+pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64;
+
+// select, subsel, size, reserved and the union of the payloads, treated as a
+// single field since what it holds depends on select and subsel.
+const CONFIG_LAYOUT: ConfigLayout =
+    ConfigLayout::new("input", &[1, 1, 1, 5, uapi::CONFIG_PAYLOAD_LEN]);
+const _: () = assert!(CONFIG_LAYOUT.size() == uapi::CONFIG_HEADER_LEN + uapi::CONFIG_PAYLOAD_LEN);
+
+fn set_bit(bitmap: &mut [u8], bit: u16) {
+    bitmap[bit as usize / 8] |= 1 << (bit % 8);
+}
+
+/// What the driver selected in the config space.
+#[derive(Serialize, Deserialize)]
+struct InputState {
+    select: u8,
+    subsel: u8,
+}
+
+pub struct Input {
+    id: String,
+    kind: InputKind,
+    pub(crate) events: Arc<InputEventQueue>,
+    select: u8,
+    subsel: u8,
+    pub(crate) queues: Vec<VirtQueue>,
+    pub(crate) queue_events: Vec<EventFd>,
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    pub(crate) activate_evt: EventFd,
+    pub(crate) device_state: DeviceState,
+    journal: Option<Arc<Journal>>,
+}
+
+impl Input {
+    pub fn new(id: String, kind: InputKind, events: Arc<InputEventQueue>) -> super::Result<Input> {
+        let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
+            .iter()
+            .map(|&max_size| VirtQueue::new(max_size))
+            .collect();
+        let mut queue_events = Vec::new();
+        for _ in 0..queues.len() {
+            queue_events
+                .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(InputError::EventFd)?);
+        }
+
+        Ok(Input {
+            id,
+            kind,
+            events,
+            select: uapi::VIRTIO_INPUT_CFG_UNSET,
+            subsel: 0,
+            queues,
+            queue_events,
+            avail_features: AVAIL_FEATURES,
+            acked_features: 0,
+            activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
+                .map_err(InputError::EventFd)?,
+            device_state: DeviceState::Inactive,
+            journal: None,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the payload of the configuration space for the current
+    /// selection, empty if there's nothing to report for it.
+    fn config_payload(&self) -> Vec<u8> {
+        let mut bitmap = [0u8; uapi::CONFIG_PAYLOAD_LEN];
+        let bitmap_len = |bitmap: &[u8]| bitmap.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+
+        match (self.select, self.kind) {
+            (uapi::VIRTIO_INPUT_CFG_ID_NAME, InputKind::Keyboard) => b"libkrun keyboard".to_vec(),
+            (uapi::VIRTIO_INPUT_CFG_ID_NAME, InputKind::Tablet) => b"libkrun tablet".to_vec(),
+            (uapi::VIRTIO_INPUT_CFG_ID_SERIAL, _) => b"0".to_vec(),
+            (uapi::VIRTIO_INPUT_CFG_ID_DEVIDS, kind) => {
+                let product: u16 = match kind {
+                    InputKind::Keyboard => 1,
+                    InputKind::Tablet => 2,
+                };
+                [uapi::BUS_VIRTUAL, 0, product, 1]
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect()
+            }
+            (uapi::VIRTIO_INPUT_CFG_EV_BITS, InputKind::Keyboard) => {
+                if self.subsel as u16 == codes::EV_KEY {
+                    (1..=codes::KEY_MAX).for_each(|key| set_bit(&mut bitmap, key));
+                }
+                bitmap[..bitmap_len(&bitmap)].to_vec()
+            }
+            (uapi::VIRTIO_INPUT_CFG_EV_BITS, InputKind::Tablet) => {
+                match self.subsel as u16 {
+                    codes::EV_KEY => [codes::BTN_LEFT, codes::BTN_RIGHT, codes::BTN_MIDDLE]
+                        .iter()
+                        .for_each(|btn| set_bit(&mut bitmap, *btn)),
+                    codes::EV_REL => set_bit(&mut bitmap, codes::REL_WHEEL),
+                    codes::EV_ABS => {
+                        set_bit(&mut bitmap, codes::ABS_X);
+                        set_bit(&mut bitmap, codes::ABS_Y);
+                    }
+                    _ => {}
+                }
+                bitmap[..bitmap_len(&bitmap)].to_vec()
+            }
+            (uapi::VIRTIO_INPUT_CFG_ABS_INFO, InputKind::Tablet)
+                if self.subsel as u16 == codes::ABS_X || self.subsel as u16 == codes::ABS_Y =>
+            {
+                // min, max, fuzz, flat and res.
+                [0, TABLET_ABS_MAX, 0, 0, 0]
+                    .iter()
+                    .flat_map(|v: &u32| v.to_le_bytes())
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn config_space(&self) -> [u8; uapi::CONFIG_HEADER_LEN + uapi::CONFIG_PAYLOAD_LEN] {
+        let mut config = [0u8; uapi::CONFIG_HEADER_LEN + uapi::CONFIG_PAYLOAD_LEN];
+        let payload = self.config_payload();
+        let len = payload.len().min(uapi::CONFIG_PAYLOAD_LEN);
+        config[0] = self.select;
+        config[1] = self.subsel;
+        config[2] = len as u8;
+        config[uapi::CONFIG_HEADER_LEN..uapi::CONFIG_HEADER_LEN + len]
+            .copy_from_slice(&payload[..len]);
+        config
+    }
+
+    /// Records the events delivered to `journal`, or takes them from it
+    /// instead of the host when replaying.
+    pub fn set_journal(&mut self, journal: Arc<Journal>) {
+        self.journal = Some(journal);
+    }
+
+    fn replaying(&self) -> bool {
+        self.journal
+            .as_ref()
+            .is_some_and(|journal| journal.is_replaying())
+    }
+
+    fn next_event(&self) -> Option<InputEvent> {
+        match &self.journal {
+            Some(journal) if journal.is_replaying() => {
+                // The events from the host aren't part of the run replayed.
+                while self.events.pop().is_some() {}
+                InputEvent::from_slice(&journal.next_input(&self.id)?).copied()
+            }
+            _ => self.events.pop(),
+        }
+    }
+
+    fn unpop_event(&self, event: InputEvent) {
+        match &self.journal {
+            Some(journal) if journal.is_replaying() => {
+                journal.unread_input(&self.id, event.as_slice().to_vec())
+            }
+            _ => self.events.unpop(event),
+        }
+    }
+
+    /// Hands as many pending events as possible to the guest.
+    pub(crate) fn process_events(&mut self) -> bool {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem, _) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+        while let Some(event) = self.next_event() {
+            let Some(head) = self.queues[EVENT_INDEX].pop(mem) else {
+                // Wait for the guest to provide more buffers.
+                self.unpop_event(event);
+                break;
+            };
+            if let Some(journal) = &self.journal {
+                journal.record_input(&self.id, event.as_slice());
+            }
+
+            let len = if !head.is_write_only() || (head.len as usize) < event.as_slice().len() {
+                error!("input: invalid event buffer");
+                0
+            } else if let Err(e) = mem.write_obj(event, head.addr) {
+                error!("input: failed to write event: {e:?}");
+                0
+            } else {
+                event.as_slice().len() as u32
+            };
+
+            have_used = true;
+            if let Err(e) = self.queues[EVENT_INDEX].add_used(mem, head.index, len) {
+                error!("failed to add used elements to the queue: {e:?}");
+            }
+        }
+
+        have_used
+    }
+
+    /// Discards the status events (LEDs) the guest sends.
+    pub(crate) fn process_status(&mut self) -> bool {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem, _) => mem,
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+        while let Some(head) = self.queues[STATUS_INDEX].pop(mem) {
+            have_used = true;
+            if let Err(e) = self.queues[STATUS_INDEX].add_used(mem, head.index, 0) {
+                error!("failed to add used elements to the queue: {e:?}");
+            }
+        }
+
+        have_used
+    }
+}
+
+impl VirtioDevice for Input {
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features
+    }
+
+    fn device_type(&self) -> u32 {
+        uapi::VIRTIO_ID_INPUT
+    }
+
+    fn device_name(&self) -> &str {
+        "input"
+    }
+
+    fn queues(&self) -> &[VirtQueue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [VirtQueue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_events
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        CONFIG_LAYOUT.read(&self.config_space(), offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let Some(range) = CONFIG_LAYOUT.access(offset, data.len()) else {
+            return;
+        };
+        for (offset, byte) in range.zip(data) {
+            match offset {
+                0 => self.select = *byte,
+                1 => self.subsel = *byte,
+                _ => warn!(
+                    "input: guest driver attempted to write read-only config (offset={offset:x})"
+                ),
+            }
+        }
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
+        if self.queues.len() != defs::NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                defs::NUM_QUEUES,
+                self.queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        if self.activate_evt.write(1).is_err() {
+            error!("Cannot write to activate_evt",);
+            return Err(ActivateError::BadActivate);
+        }
+
+        self.device_state = DeviceState::Activated(mem, interrupt);
+
+        // Start delivering the recorded events right away.
+        if self.replaying() && self.events.evt.write(1).is_err() {
+            error!("Cannot write to input event");
+        }
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn save_device_state(&self) -> Vec<u8> {
+        persist::encode_device_state(&InputState {
+            select: self.select,
+            subsel: self.subsel,
+        })
+    }
+
+    fn restore_device_state(&mut self, state: &[u8]) -> Result<(), PersistError> {
+        let state: InputState = persist::decode_device_state(state)?;
+        self.select = state.select;
+        self.subsel = state.subsel;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(kind: InputKind) -> Input {
+        Input::new(
+            "input0".to_string(),
+            kind,
+            Arc::new(InputEventQueue::new().unwrap()),
+        )
+        .unwrap()
+    }
+
+    fn select(dev: &mut Input, select: u8, subsel: u8) -> Vec<u8> {
+        dev.write_config(0, &[select, subsel]);
+        let mut size = [0u8];
+        dev.read_config(2, &mut size);
+        let mut payload = vec![0u8; size[0] as usize];
+        dev.read_config(uapi::CONFIG_HEADER_LEN as u64, &mut payload);
+        payload
+    }
+
+    #[test]
+    fn test_config_keyboard() {
+        let mut dev = input(InputKind::Keyboard);
+        assert_eq!(
+            select(&mut dev, uapi::VIRTIO_INPUT_CFG_ID_NAME, 0),
+            b"libkrun keyboard"
+        );
+        let keys = select(
+            &mut dev,
+            uapi::VIRTIO_INPUT_CFG_EV_BITS,
+            codes::EV_KEY as u8,
+        );
+        assert_eq!(keys.len(), 16);
+        assert_eq!(keys[0], 0xfe);
+        assert!(select(
+            &mut dev,
+            uapi::VIRTIO_INPUT_CFG_EV_BITS,
+            codes::EV_ABS as u8
+        )
+        .is_empty());
+        assert!(select(&mut dev, uapi::VIRTIO_INPUT_CFG_UNSET, 0).is_empty());
+    }
+
+    #[test]
+    fn test_config_tablet() {
+        let mut dev = input(InputKind::Tablet);
+        assert_eq!(
+            select(
+                &mut dev,
+                uapi::VIRTIO_INPUT_CFG_EV_BITS,
+                codes::EV_ABS as u8
+            ),
+            [0b11]
+        );
+        let buttons = select(
+            &mut dev,
+            uapi::VIRTIO_INPUT_CFG_EV_BITS,
+            codes::EV_KEY as u8,
+        );
+        assert_eq!(buttons.len(), 0x113 / 8 + 1);
+        assert_eq!(buttons[0x110 / 8], 0b111);
+        let abs = select(
+            &mut dev,
+            uapi::VIRTIO_INPUT_CFG_ABS_INFO,
+            codes::ABS_Y as u8,
+        );
+        assert_eq!(abs[4..8], TABLET_ABS_MAX.to_le_bytes());
+        assert!(select(&mut dev, uapi::VIRTIO_INPUT_CFG_ABS_INFO, 2).is_empty());
+        assert_eq!(
+            select(&mut dev, uapi::VIRTIO_INPUT_CFG_ID_DEVIDS, 0),
+            [6, 0, 0, 0, 2, 0, 1, 0]
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::os::unix::io::AsRawFd;
+
+use polly::event_manager::{EventManager, Subscriber};
+use utils::epoll::{EpollEvent, EventSet};
+
+use super::device::{Input, EVENT_INDEX, STATUS_INDEX};
+use crate::virtio::device::VirtioDevice;
+
+impl Input {
+    fn handle_queue_event(&mut self, index: usize) {
+        if let Err(e) = self.queue_events[index].read() {
+            error!("Failed to read input queue event: {e:?}");
+            return;
+        }
+        let have_used = if index == EVENT_INDEX {
+            // New buffers, deliver any events that didn't fit before.
+            self.process_events()
+        } else {
+            self.process_status()
+        };
+        if have_used {
+            self.device_state.signal_used_queue();
+        }
+    }
+
+    fn handle_input_event(&mut self) {
+        if let Err(e) = self.events.evt.read() {
+            error!("Failed to read input event: {e:?}");
+        } else if self.process_events() {
+            self.device_state.signal_used_queue();
+        }
+    }
+
+    fn handle_activate_event(&self, event_manager: &mut EventManager) {
+        debug!("input: activate event");
+        if let Err(e) = self.activate_evt.read() {
+            error!("Failed to consume input activate event: {e:?}");
+        }
+
+        // The subscriber must exist as we previously registered activate_evt via
+        // `interest_list()`.
+        let self_subscriber = event_manager
+            .subscriber(self.activate_evt.as_raw_fd())
+            .unwrap();
+
+        for fd in [
+            self.queue_events[EVENT_INDEX].as_raw_fd(),
+            self.queue_events[STATUS_INDEX].as_raw_fd(),
+            self.events.evt.as_raw_fd(),
+        ] {
+            event_manager
+                .register(
+                    fd,
+                    EpollEvent::new(EventSet::IN, fd as u64),
+                    self_subscriber.clone(),
+                )
+                .unwrap_or_else(|e| {
+                    error!("Failed to register input event with event manager: {e:?}");
+                });
+        }
+
+        event_manager
+            .unregister(self.activate_evt.as_raw_fd())
+            .unwrap_or_else(|e| {
+                error!("Failed to unregister input activate evt: {e:?}");
+            })
+    }
+}
+
+impl Subscriber for Input {
+    fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
+        let source = event.fd();
+        let eventq = self.queue_events[EVENT_INDEX].as_raw_fd();
+        let statusq = self.queue_events[STATUS_INDEX].as_raw_fd();
+        let input = self.events.evt.as_raw_fd();
+        let activate_evt = self.activate_evt.as_raw_fd();
+
+        if self.is_activated() {
+            match source {
+                _ if source == eventq => self.handle_queue_event(EVENT_INDEX),
+                _ if source == statusq => self.handle_queue_event(STATUS_INDEX),
+                _ if source == input => self.handle_input_event(),
+                _ if source == activate_evt => {
+                    self.handle_activate_event(event_manager);
+                }
+                _ => warn!("Unexpected input event received: {source:?}"),
+            }
+        } else {
+            warn!("input: The device is not yet activated. Spurious event received: {source:?}");
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(
+            EventSet::IN,
+            self.activate_evt.as_raw_fd() as u64,
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use vm_memory::{ByteValued, GuestAddress};
+
+    use super::*;
+    use crate::virtio::input::{codes, InputEvent, InputEventQueue, InputKind};
+    use crate::virtio::test_utils::{guest_memory, Buffer, TestInterrupt, TestQueue, Used};
+
+    #[test]
+    fn test_deliver_events() {
+        let events = Arc::new(InputEventQueue::new().unwrap());
+        let mut input = Input::new("input0".into(), InputKind::Keyboard, events.clone()).unwrap();
+        let mem = guest_memory();
+        let mut eventq = TestQueue::new(&mem, GuestAddress(0), 16);
+        input.queues[EVENT_INDEX] = eventq.create_queue();
+        let interrupt = TestInterrupt::new();
+        input.activate(mem.clone(), interrupt.transport()).unwrap();
+
+        let key = InputEvent::new(codes::EV_KEY, 30, 1);
+        let event_len = key.as_slice().len() as u32;
+        let head = eventq.add_chain(&[Buffer::Writable(event_len)]);
+        events.push(&[key, InputEvent::syn()]);
+        input.handle_input_event();
+        assert!(interrupt.take_used_queue());
+        let used = eventq.used();
+        assert_eq!(
+            used,
+            [Used {
+                head,
+                len: event_len
+            }]
+        );
+        assert_eq!(eventq.written(head, event_len), key.as_slice());
+
+        // The SYN_REPORT waits for the guest to provide another buffer.
+        let head = eventq.add_chain(&[Buffer::Writable(event_len)]);
+        input.queue_events[EVENT_INDEX].write(1).unwrap();
+        input.handle_queue_event(EVENT_INDEX);
+        assert!(interrupt.take_used_queue());
+        assert_eq!(
+            eventq.written(head, event_len),
+            InputEvent::syn().as_slice()
+        );
+        assert_eq!(eventq.used().len(), 1);
+    }
+}
@@ -0,0 +1,148 @@
+//! virtio-input devices fed with events injected by the VMM, such as the
+//! ones coming from a remote display client.
+
+mod device;
+mod event_handler;
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use utils::eventfd::EventFd;
+use vm_memory::ByteValued;
+
+pub use self::defs::uapi::VIRTIO_ID_INPUT as TYPE_INPUT;
+pub use self::device::Input;
+
+mod defs {
+    pub const NUM_QUEUES: usize = 2;
+    pub const QUEUE_SIZES: &[u16] = &[64; NUM_QUEUES];
+
+    #[allow(dead_code)]
+    pub mod uapi {
+        pub const VIRTIO_F_VERSION_1: u32 = 32;
+        pub const VIRTIO_ID_INPUT: u32 = 18;
+
+        pub const VIRTIO_INPUT_CFG_UNSET: u8 = 0x00;
+        pub const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+        pub const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+        pub const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+        pub const VIRTIO_INPUT_CFG_PROP_BITS: u8 = 0x10;
+        pub const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+        pub const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+        /// Size of the header of `struct virtio_input_config`.
+        pub const CONFIG_HEADER_LEN: usize = 8;
+        /// Size of the union of `struct virtio_input_config`.
+        pub const CONFIG_PAYLOAD_LEN: usize = 128;
+
+        pub const BUS_VIRTUAL: u16 = 0x06;
+    }
+}
+
+/// Event types and codes from linux/input-event-codes.h.
+pub mod codes {
+    pub const EV_SYN: u16 = 0x00;
+    pub const EV_KEY: u16 = 0x01;
+    pub const EV_REL: u16 = 0x02;
+    pub const EV_ABS: u16 = 0x03;
+
+    pub const SYN_REPORT: u16 = 0;
+
+    pub const REL_WHEEL: u16 = 0x08;
+
+    pub const ABS_X: u16 = 0x00;
+    pub const ABS_Y: u16 = 0x01;
+
+    pub const BTN_LEFT: u16 = 0x110;
+    pub const BTN_RIGHT: u16 = 0x111;
+    pub const BTN_MIDDLE: u16 = 0x112;
+
+    /// Highest key code reported by the keyboard (KEY_COMPOSE).
+    pub const KEY_MAX: u16 = 127;
+}
+
+/// Highest value of the absolute axes of the tablet, whose minimum is 0.
+pub const TABLET_ABS_MAX: u32 = 0x7fff;
+
+/// Maximum number of events kept while the guest isn't consuming them.
+const MAX_PENDING_EVENTS: usize = 1024;
+
+/// `struct virtio_input_event`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct InputEvent {
+    pub type_: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+// SAFETY: InputEvent only contains plain data.
+unsafe impl ByteValued for InputEvent {}
+
+impl InputEvent {
+    pub fn new(type_: u16, code: u16, value: u32) -> Self {
+        Self {
+            type_: type_.to_le(),
+            code: code.to_le(),
+            value: value.to_le(),
+        }
+    }
+
+    pub fn syn() -> Self {
+        Self::new(codes::EV_SYN, codes::SYN_REPORT, 0)
+    }
+}
+
+/// The kind of device, which determines the events it advertises.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputKind {
+    Keyboard,
+    /// An absolute pointing device, with axes ranging from 0 to
+    /// `TABLET_ABS_MAX`.
+    Tablet,
+}
+
+/// Events waiting to be delivered to a device, pushed from other threads.
+pub struct InputEventQueue {
+    events: Mutex<VecDeque<InputEvent>>,
+    evt: EventFd,
+}
+
+impl InputEventQueue {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            events: Mutex::new(VecDeque::new()),
+            evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)?,
+        })
+    }
+
+    /// Queues `events`, which should end with a `SYN_REPORT`, for the guest.
+    pub fn push(&self, events: &[InputEvent]) {
+        let mut queue = self.events.lock().unwrap();
+        queue.extend(events);
+        // Drop the oldest events if the guest isn't keeping up.
+        while queue.len() > MAX_PENDING_EVENTS {
+            queue.pop_front();
+        }
+        drop(queue);
+        if let Err(e) = self.evt.write(1) {
+            error!("Failed to signal input event: {e:?}");
+        }
+    }
+
+    fn pop(&self) -> Option<InputEvent> {
+        self.events.lock().unwrap().pop_front()
+    }
+
+    fn unpop(&self, event: InputEvent) {
+        self.events.lock().unwrap().push_front(event);
+    }
+}
+
+#[derive(Debug)]
+pub enum InputError {
+    /// Failed to create event fd.
+    EventFd(std::io::Error),
+}
+
+type Result<T> = std::result::Result<T, InputError>;
@@ -15,7 +15,9 @@ use super::device_status;
 use super::*;
 use crate::bus::BusDevice;
 use crate::legacy::IrqChip;
+use utils::metrics::{DeviceMetrics, Metrics};
 use utils::{byte_order, eventfd::EventFd};
+use virtio_bindings::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 
 //TODO crosvm uses 0 here, but IIRC virtio specified some other vendor id that should be used
@@ -77,19 +79,25 @@ struct InterruptTransportInner {
     event: EventFd,
     intc: IrqChip,
     irq_line: Option<u32>,
+    metrics: Arc<DeviceMetrics>,
 }
 
 #[derive(Clone)]
 pub struct InterruptTransport(Arc<InterruptTransportInner>);
 
 impl InterruptTransport {
-    pub fn new(intc: IrqChip, log_target: String) -> Result<Self, CreateMmioTransportError> {
+    pub fn new(
+        intc: IrqChip,
+        log_target: String,
+        metrics: Arc<DeviceMetrics>,
+    ) -> Result<Self, CreateMmioTransportError> {
         Ok(Self(Arc::new(InterruptTransportInner {
             log_target,
             status: AtomicUsize::new(0),
             event: EventFd::new(0).map_err(CreateMmioTransportError::CreateInterruptEventFd)?,
             intc,
             irq_line: None,
+            metrics,
         })))
     }
 
@@ -109,6 +117,11 @@ impl InterruptTransport {
         self.0.irq_line
     }
 
+    /// Activity counters of the device this transport belongs to.
+    pub fn metrics(&self) -> &DeviceMetrics {
+        &self.0.metrics
+    }
+
     fn set_irq_line(&mut self, irq_line: u32) {
         debug!(target: &self.0.log_target, "set_irq_line: {irq_line}");
         match Arc::get_mut(&mut self.0) {
@@ -121,8 +134,20 @@ impl InterruptTransport {
         }
     }
 
+    fn set_metrics(&mut self, metrics: Arc<DeviceMetrics>) {
+        match Arc::get_mut(&mut self.0) {
+            None => {
+                error!("Cannot change metrics of activated device");
+            }
+            Some(interrupt) => {
+                interrupt.metrics = metrics;
+            }
+        }
+    }
+
     fn try_signal(&self, status: u32) -> Result<(), crate::Error> {
         self.status().fetch_or(status as usize, Ordering::SeqCst);
+        self.0.metrics.interrupts.inc();
         self.intc()
             .lock()
             .unwrap()
@@ -160,19 +185,15 @@ impl MmioTransport {
         intc: IrqChip,
         device: Arc<Mutex<dyn VirtioDevice>>,
     ) -> Result<MmioTransport, CreateMmioTransportError> {
-        let debug_log_target = format!(
-            "{}[{}]",
-            module_path!(),
-            device
-                .try_lock()
-                .expect(
-                    "Mutex of VirtioDevice should not be locked when calling MmioTransport::new"
-                )
-                .device_name()
-        );
+        let device_name = device
+            .try_lock()
+            .expect("Mutex of VirtioDevice should not be locked when calling MmioTransport::new")
+            .device_name()
+            .to_string();
+        let debug_log_target = format!("{}[{}]", module_path!(), device_name);
 
         Ok(MmioTransport {
-            interrupt: InterruptTransport::new(intc, debug_log_target)?,
+            interrupt: InterruptTransport::new(intc, debug_log_target, Arc::default())?,
             device,
             features_select: 0,
             acked_features_select: 0,
@@ -191,6 +212,14 @@ impl MmioTransport {
         self.interrupt.set_irq_line(irq_line);
     }
 
+    /// Registers the activity counters of the device in `metrics`, the ones
+    /// of its microVM, under `id`.
+    /// NOTE: Can only be called when the device is not activated
+    pub fn set_device_id(&mut self, id: &str, metrics: &Metrics) {
+        let metrics = metrics.device(self.locked_device().device_name(), id);
+        self.interrupt.set_metrics(metrics);
+    }
+
     pub fn interrupt_evt(&self) -> &EventFd {
         self.interrupt.event()
     }
@@ -276,6 +305,7 @@ impl MmioTransport {
     /// a device status bit. If the driver sets the FAILED bit, the driver MUST later reset
     /// the device before attempting to re-initialize.
     #[allow(unused_assignments)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     fn set_device_status(&mut self, status: u32) {
         use device_status::*;
         // match changed bits
@@ -293,6 +323,14 @@ impl MmioTransport {
                 self.device_status = status;
                 let device_activated = self.locked_device().is_activated();
                 if !device_activated {
+                    let metrics = self.interrupt.0.metrics.clone();
+                    let mut device = self.locked_device();
+                    let packed = device.acked_features() & (1 << VIRTIO_F_RING_PACKED) != 0;
+                    for queue in device.queues_mut() {
+                        queue.set_packed(packed);
+                        queue.set_metrics(metrics.clone());
+                    }
+                    drop(device);
                     self.locked_device()
                         .activate(self.mem.clone(), self.interrupt.clone())
                         .expect("Failed to activate device");
@@ -321,6 +359,32 @@ impl MmioTransport {
             }
         }
     }
+
+    /// Kicks the device after the driver made buffers available in `queue`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn notify_queue(&self, queue: u32) {
+        if let Some(eventfd) = self.queue_evts.get(&queue) {
+            self.interrupt.metrics().queue_kicks.inc();
+            eventfd.write(queue as u64).unwrap();
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn read_device_config(&self, offset: u64, data: &mut [u8]) {
+        // Devices leave data untouched on some invalid reads, don't
+        // return what the buffer held before.
+        data.fill(0);
+        self.locked_device().read_config(offset, data)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn write_device_config(&mut self, offset: u64, data: &[u8]) {
+        if self.check_device_status(device_status::DRIVER, device_status::FAILED) {
+            self.locked_device().write_config(offset, data)
+        } else {
+            warn!("can not write to device config data area before driver is ready");
+        }
+    }
 }
 
 impl BusDevice for MmioTransport {
@@ -374,7 +438,7 @@ impl BusDevice for MmioTransport {
                 };
                 byte_order::write_le_u32(data, v);
             }
-            0x100..=0xfff => self.locked_device().read_config(offset - 0x100, data),
+            0x100..=0xfff => self.read_device_config(offset - 0x100, data),
             _ => {
                 warn!(
                     "invalid virtio mmio read: 0x{:x}:0x{:x}",
@@ -417,11 +481,7 @@ impl BusDevice for MmioTransport {
                     0x30 => self.queue_select = v,
                     0x38 => self.update_queue_field(|q| q.size = v as u16),
                     0x44 => self.update_queue_field(|q| q.ready = v == 1),
-                    0x50 => {
-                        if let Some(eventfd) = self.queue_evts.get(&v) {
-                            eventfd.write(v as u64).unwrap();
-                        }
-                    }
+                    0x50 => self.notify_queue(v),
                     0x64 => {
                         if self.check_device_status(device_status::DRIVER_OK, 0) {
                             self.interrupt
@@ -442,13 +502,7 @@ impl BusDevice for MmioTransport {
                     }
                 }
             }
-            0x100..=0xfff => {
-                if self.check_device_status(device_status::DRIVER, device_status::FAILED) {
-                    self.locked_device().write_config(offset - 0x100, data)
-                } else {
-                    warn!("can not write to device config data area before driver is ready");
-                }
-            }
+            0x100..=0xfff => self.write_device_config(offset - 0x100, data),
             _ => {
                 warn!(
                     "invalid virtio mmio write: 0x{:x}:0x{:x}",
@@ -599,6 +653,27 @@ pub(crate) mod tests {
         assert!(!d.with_queue_mut(|q| q.size = 16));
     }
 
+    #[test]
+    fn test_queue_size_override() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let dummy = Arc::new(Mutex::new(DummyDevice::new()));
+        dummy.lock().unwrap().set_queue_size(64);
+        let mut d = MmioTransport::new(m, DummyIrqChip::new().into(), dummy).unwrap();
+
+        let mut buf = [0; 4];
+        d.queue_select = 1;
+        d.read(0, 0x34, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 64);
+        // Survives resets.
+        set_device_status(&mut d, 0);
+        d.read(0, 0x34, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 64);
+
+        assert!(Queue::is_valid_max_size(1024));
+        assert!(!Queue::is_valid_max_size(1000));
+        assert!(!Queue::is_valid_max_size(4));
+    }
+
     #[test]
     fn test_bus_device_read() {
         let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
@@ -17,43 +17,61 @@ pub mod balloon;
 pub mod bindings;
 #[cfg(feature = "blk")]
 pub mod block;
+pub mod config;
 pub mod console;
 pub mod descriptor_utils;
 pub mod device;
 pub mod file_traits;
 #[cfg(not(any(feature = "tee", feature = "nitro")))]
 pub mod fs;
+#[cfg(fuzzing)]
+pub mod fuzz;
 #[cfg(feature = "gpu")]
 pub mod gpu;
+#[cfg(not(feature = "tee"))]
+pub mod input;
 pub mod linux_errno;
 mod mmio;
 #[cfg(feature = "net")]
 pub mod net;
-mod queue;
+pub mod persist;
+pub mod plugin;
+pub(crate) mod queue;
 #[cfg(not(feature = "tee"))]
 pub mod rng;
 #[cfg(feature = "snd")]
 pub mod snd;
+#[cfg(any(test, feature = "test_utils"))]
+pub mod test_utils;
+#[cfg(all(target_os = "linux", not(feature = "tee")))]
+pub mod vdpa;
 pub mod vsock;
 
 #[cfg(not(feature = "tee"))]
 pub use self::balloon::*;
 #[cfg(feature = "blk")]
 pub use self::block::{Block, CacheType};
+pub use self::config::ConfigLayout;
 pub use self::console::*;
 pub use self::device::*;
 #[cfg(not(any(feature = "tee", feature = "nitro")))]
 pub use self::fs::*;
 #[cfg(feature = "gpu")]
 pub use self::gpu::*;
+#[cfg(not(feature = "tee"))]
+pub use self::input::{Input, InputEvent, InputEventQueue, InputKind, TABLET_ABS_MAX};
 pub use self::mmio::*;
 #[cfg(feature = "net")]
 pub use self::net::Net;
-pub use self::queue::{Descriptor, DescriptorChain, Queue};
+pub use self::persist::{Persist, PersistError, StateSnapshot};
+pub use self::plugin::{PluginDevice, VirtioPlugin};
+pub use self::queue::{Descriptor, DescriptorChain, DetachedChain, Queue};
 #[cfg(not(feature = "tee"))]
 pub use self::rng::*;
 #[cfg(feature = "snd")]
 pub use self::snd::Snd;
+#[cfg(all(target_os = "linux", not(feature = "tee")))]
+pub use self::vdpa::Vdpa;
 pub use self::vsock::*;
 
 /// When the driver initializes the device, it lets the device know about the
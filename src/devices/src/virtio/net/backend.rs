@@ -8,6 +8,9 @@ pub enum ConnectError {
     CreateSocket(nix::Error),
     Binding(nix::Error),
     SendingMagic(nix::Error),
+    SpawnSwitch(io::Error),
+    CreateReplayer(io::Error),
+    StartNetem(io::Error),
     // Tap backend errors.
     OpenNetTun(nix::Error),
     TunSetIff(io::Error),
@@ -43,4 +46,25 @@ pub trait NetBackend {
     fn has_unfinished_write(&self) -> bool;
     fn try_finish_write(&mut self, hdr_len: usize, buf: &[u8]) -> Result<(), WriteError>;
     fn raw_socket_fd(&self) -> RawFd;
+
+    /// Whether frames can be sent straight from guest memory with
+    /// `write_frame_iov`, instead of being copied first for `write_frame`.
+    fn can_write_iov(&self) -> bool {
+        false
+    }
+
+    /// Try to write a frame made of the buffers in `iov`. The buffers are no
+    /// longer referenced once this returns.
+    fn write_frame_iov(&mut self, _hdr_len: usize, _iov: &[libc::iovec]) -> Result<(), WriteError> {
+        Err(WriteError::Internal(nix::Error::ENOTSUP))
+    }
+
+    /// A file descriptor becoming readable when frames the backend holds
+    /// back are due, after which `process_timer` must be called, and the
+    /// backend read from and written to again.
+    fn timer_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    fn process_timer(&mut self) {}
 }
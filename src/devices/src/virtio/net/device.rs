@@ -4,27 +4,33 @@
 // Portions Copyright 2017 The Chromium OS Authors. All rights reserved.
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
+use crate::journal::Journal;
 use crate::virtio::net::{Error, Result};
 use crate::virtio::net::{QUEUE_SIZES, RX_INDEX, TX_INDEX};
 use crate::virtio::queue::Error as QueueError;
 use crate::virtio::{
-    ActivateError, ActivateResult, DeviceState, InterruptTransport, Queue, VirtioDevice, TYPE_NET,
+    ActivateError, ActivateResult, ConfigLayout, DeviceState, InterruptTransport, Queue,
+    VirtioDevice, TYPE_NET,
 };
 use crate::Error as DeviceError;
 
 use super::backend::{ReadError, WriteError};
+use super::egress::EgressFilter;
+use super::netem::NetemConfig;
 use super::worker::NetWorker;
 
-use std::cmp;
-use std::io::Write;
 use std::os::fd::RawFd;
 use std::path::PathBuf;
+use std::sync::Arc;
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
-use virtio_bindings::virtio_net::VIRTIO_NET_F_MAC;
+use virtio_bindings::virtio_net::{
+    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MTU, VIRTIO_NET_F_STATUS, VIRTIO_NET_S_LINK_UP,
+};
 use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{ByteValued, GuestMemoryError, GuestMemoryMmap};
 
 const VIRTIO_F_VERSION_1: u32 = 32;
+const VIRTIO_F_RING_PACKED: u32 = 34;
 
 #[derive(Debug)]
 pub enum FrontendError {
@@ -54,17 +60,23 @@ struct VirtioNetConfig {
     mac: [u8; 6],
     status: u16,
     max_virtqueue_pairs: u16,
+    mtu: u16,
 }
 
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioNetConfig {}
 
+const CONFIG_LAYOUT: ConfigLayout = ConfigLayout::new("net", &[6, 2, 2, 2]);
+const _: () = assert!(CONFIG_LAYOUT.size() == std::mem::size_of::<VirtioNetConfig>());
+
 #[derive(Clone)]
 pub enum VirtioNetBackend {
     UnixstreamFd(RawFd),
     UnixstreamPath(PathBuf),
     UnixgramFd(RawFd),
     UnixgramPath(PathBuf, bool),
+    /// Port of the virtual switch at the path, on the VLAN, or untagged if 0.
+    Switch(PathBuf, u16),
     #[cfg(target_os = "linux")]
     Tap(String),
 }
@@ -82,6 +94,10 @@ pub struct Net {
     pub(crate) device_state: DeviceState,
 
     config: VirtioNetConfig,
+
+    journal: Option<Arc<Journal>>,
+    egress_filter: Option<Arc<EgressFilter>>,
+    netem: Option<NetemConfig>,
 }
 
 impl Net {
@@ -94,8 +110,10 @@ impl Net {
     ) -> Result<Self> {
         let avail_features = features as u64
             | (1 << VIRTIO_NET_F_MAC)
+            | (1 << VIRTIO_NET_F_STATUS)
             | (1 << VIRTIO_RING_F_EVENT_IDX)
-            | (1 << VIRTIO_F_VERSION_1);
+            | (1 << VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_F_RING_PACKED);
 
         let mut queue_evts = Vec::new();
         for _ in QUEUE_SIZES.iter() {
@@ -106,8 +124,9 @@ impl Net {
 
         let config = VirtioNetConfig {
             mac,
-            status: 0,
+            status: VIRTIO_NET_S_LINK_UP as u16,
             max_virtqueue_pairs: 0,
+            mtu: 0,
         };
 
         Ok(Net {
@@ -121,6 +140,9 @@ impl Net {
             queue_evts,
             device_state: DeviceState::Inactive,
             config,
+            journal: None,
+            egress_filter: None,
+            netem: None,
         })
     }
 
@@ -128,6 +150,48 @@ impl Net {
     pub fn id(&self) -> &str {
         &self.id
     }
+
+    /// Records the frames received to `journal`, or takes them from it
+    /// instead of the backend when replaying.
+    pub fn set_journal(&mut self, journal: Arc<Journal>) {
+        self.journal = Some(journal);
+    }
+
+    /// Drops the frames the guest sends that `filter` doesn't allow.
+    pub fn set_egress_filter(&mut self, filter: Arc<EgressFilter>) {
+        self.egress_filter = Some(filter);
+    }
+
+    /// Degrades the network as `config` says.
+    pub fn set_netem(&mut self, config: NetemConfig) {
+        self.netem = Some(config);
+    }
+
+    /// Advertises `mtu` as the maximum the guest may use. Must be called
+    /// before the device is activated.
+    pub fn set_mtu(&mut self, mtu: u16) {
+        self.config.mtu = mtu;
+        self.avail_features |= 1 << VIRTIO_NET_F_MTU;
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.config.status & VIRTIO_NET_S_LINK_UP as u16 != 0
+    }
+
+    /// Sets the state of the link reported to the guest, notifying it if it's
+    /// running.
+    pub fn set_link_up(&mut self, up: bool) {
+        if up == self.link_up() {
+            return;
+        }
+        self.config.status ^= VIRTIO_NET_S_LINK_UP as u16;
+
+        if let DeviceState::Activated(_, ref interrupt) = self.device_state {
+            if self.acked_features & (1 << VIRTIO_NET_F_STATUS) != 0 {
+                interrupt.signal_config_change();
+            }
+        }
+    }
 }
 
 impl VirtioDevice for Net {
@@ -163,26 +227,12 @@ impl VirtioDevice for Net {
         &self.queue_evts
     }
 
-    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_slice = self.config.as_slice();
-        let config_len = config_slice.len() as u64;
-        if offset >= config_len {
-            error!("Failed to read config space");
-            return;
-        }
-        if let Some(end) = offset.checked_add(data.len() as u64) {
-            // This write can't fail, offset and end are checked against config_len.
-            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
-                .unwrap();
-        }
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        CONFIG_LAYOUT.read(self.config.as_slice(), offset, data);
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        log::warn!(
-            "Net: guest driver attempted to write device config (offset={:x}, len={:x})",
-            offset,
-            data.len()
-        );
+        CONFIG_LAYOUT.write_read_only(offset, data);
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
@@ -203,9 +253,13 @@ impl VirtioDevice for Net {
             mem.clone(),
             self.acked_features,
             self.cfg_backend.clone(),
+            self.id.clone(),
+            self.journal.clone(),
+            self.egress_filter.clone(),
+            self.netem.clone(),
         ) {
             Ok(worker) => {
-                worker.run();
+                worker.run().map_err(ActivateError::EpollCtl)?;
                 self.device_state = DeviceState::Activated(mem, interrupt);
                 Ok(())
             }
@@ -223,3 +277,31 @@ impl VirtioDevice for Net {
         self.device_state.is_activated()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mtu_and_link_state() {
+        let mut net =
+            Net::new("eth0".into(), VirtioNetBackend::UnixstreamFd(-1), [1; 6], 0).unwrap();
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_MTU), 0);
+        net.set_mtu(9000);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_MTU), 0);
+
+        let mut config = [0u8; 12];
+        net.read_config(0, &mut config);
+        assert_eq!(config[..6], [1; 6]);
+        assert_eq!(u16::from_le_bytes([config[6], config[7]]), 1);
+        assert_eq!(u16::from_le_bytes([config[10], config[11]]), 9000);
+
+        net.set_link_up(false);
+        assert!(!net.link_up());
+        let mut status = [0u8; 2];
+        net.read_config(6, &mut status);
+        assert_eq!(status, [0, 0]);
+        net.set_link_up(true);
+        assert!(net.link_up());
+    }
+}
@@ -0,0 +1,566 @@
+//! Filtering of the frames the guest sends, so what its workloads may reach
+//! can be restricted without a firewall outside of the VMM.
+//!
+//! The IPv4 and IPv6 packets are checked against a table of rules, the first
+//! one matching deciding whether the packet is sent or dropped, and the
+//! default action of the table applying when none does. Other frames, like
+//! ARP ones the guest needs to reach anything at all, always go through.
+//!
+//! VLAN tags and IPv6 extension headers are skipped to find the transport
+//! header. When a rule looks at the protocol or the ports, the packets that
+//! don't have the whole header, like fragments other than the first, are
+//! dropped, as are the ones that can't be parsed.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::backend::{NetBackend, ReadError, WriteError};
+
+const ETH_HDR_LEN: usize = 14;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_8021AD: u16 = 0x88a8;
+
+const IPV4_HDR_LEN: usize = 20;
+const IPV6_HDR_LEN: usize = 40;
+const TCP_HDR_LEN: usize = 20;
+const UDP_HDR_LEN: usize = 8;
+
+const IPPROTO_HOPOPTS: u8 = 0;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ROUTING: u8 = 43;
+const IPPROTO_FRAGMENT: u8 = 44;
+const IPPROTO_AH: u8 = 51;
+const IPPROTO_ICMPV6: u8 = 58;
+const IPPROTO_DSTOPTS: u8 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Any,
+    Tcp,
+    Udp,
+    /// ICMP or ICMPv6.
+    Icmp,
+}
+
+/// A rule of the table, in the "ACTION PROTOCOL ADDRESS[/PREFIX] [PORT[-PORT]]"
+/// format, e.g. "allow tcp 10.0.0.0/8 443" or "deny any ::/0".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    action: Action,
+    protocol: Protocol,
+    addr: IpAddr,
+    prefix: u8,
+    /// Inclusive range of destination ports, for TCP and UDP.
+    ports: Option<(u16, u16)>,
+}
+
+impl Rule {
+    pub fn parse(rule: &str) -> Option<Self> {
+        let mut fields = rule.split_whitespace();
+        let action = match fields.next()? {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            _ => return None,
+        };
+        let protocol = match fields.next()? {
+            "any" => Protocol::Any,
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            "icmp" => Protocol::Icmp,
+            _ => return None,
+        };
+
+        let net = fields.next()?;
+        let (addr, prefix) = match net.split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (net.parse().ok()?, None),
+        };
+        let max_prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return None;
+        }
+
+        let ports = match fields.next() {
+            Some(ports) => {
+                if !matches!(protocol, Protocol::Tcp | Protocol::Udp) {
+                    return None;
+                }
+                let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+                let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                Some((first, last))
+            }
+            None => None,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            action,
+            protocol,
+            addr,
+            prefix,
+            ports,
+        })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn matches(&self, packet: &Packet) -> bool {
+        let protocol = match self.protocol {
+            Protocol::Any => true,
+            Protocol::Tcp => packet.protocol == IPPROTO_TCP,
+            Protocol::Udp => packet.protocol == IPPROTO_UDP,
+            Protocol::Icmp => matches!(packet.protocol, IPPROTO_ICMP | IPPROTO_ICMPV6),
+        };
+        let ports = match (self.ports, packet.port) {
+            (None, _) => true,
+            (Some((first, last)), Some(port)) => (first..=last).contains(&port),
+            (Some(_), None) => false,
+        };
+        protocol && ports && self.contains(packet.dst)
+    }
+}
+
+/// What the rules look at in a packet.
+#[derive(Debug, PartialEq, Eq)]
+struct Packet {
+    protocol: u8,
+    dst: IpAddr,
+    /// The destination port, for TCP and UDP.
+    port: Option<u16>,
+    /// Whether the transport header isn't all there: the packet is a fragment
+    /// other than the first, or a first one cut before the end of the header.
+    partial: bool,
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        buf.get(offset..offset.checked_add(2)?)?.try_into().unwrap(),
+    ))
+}
+
+/// Returns the destination port of the transport header `l4`, and whether
+/// the header is cut short.
+fn port(protocol: u8, l4: &[u8]) -> (Option<u16>, bool) {
+    let hdr_len = match protocol {
+        IPPROTO_TCP => TCP_HDR_LEN,
+        IPPROTO_UDP => UDP_HDR_LEN,
+        _ => return (None, false),
+    };
+    if l4.len() < hdr_len {
+        return (None, true);
+    }
+    (read_u16(l4, 2), false)
+}
+
+/// Parses the IP header of an Ethernet frame, returning `None` if it doesn't
+/// carry an IP packet, and `Some(Err(()))` if it can't be parsed.
+fn parse_frame(frame: &[u8]) -> Option<Result<Packet, ()>> {
+    let mut offset = ETH_HDR_LEN - 2;
+    let Some(mut ethertype) = read_u16(frame, offset) else {
+        return Some(Err(()));
+    };
+    // Any number of stacked 802.1Q and 802.1ad tags.
+    while matches!(ethertype, ETH_P_8021Q | ETH_P_8021AD) {
+        offset += 4;
+        let Some(inner) = read_u16(frame, offset) else {
+            return Some(Err(()));
+        };
+        ethertype = inner;
+    }
+    let ip = &frame[offset + 2..];
+
+    match ethertype {
+        ETH_P_IP => Some(parse_ipv4(ip)),
+        ETH_P_IPV6 => Some(parse_ipv6(ip)),
+        _ => None,
+    }
+}
+
+fn parse_ipv4(ip: &[u8]) -> Result<Packet, ()> {
+    let (Some(&ver_ihl), Some(dst)) = (ip.first(), ip.get(16..20)) else {
+        return Err(());
+    };
+    let ihl = usize::from(ver_ihl & 0xf) * 4;
+    if ihl < IPV4_HDR_LEN || ihl > ip.len() {
+        return Err(());
+    }
+    let protocol = ip[9];
+    let first_fragment = read_u16(ip, 6).unwrap() & 0x1fff == 0;
+    let (port, partial) = if first_fragment {
+        port(protocol, &ip[ihl..])
+    } else {
+        (None, true)
+    };
+    let dst: [u8; 4] = dst.try_into().unwrap();
+    Ok(Packet {
+        protocol,
+        dst: Ipv4Addr::from(dst).into(),
+        port,
+        partial,
+    })
+}
+
+fn parse_ipv6(ip: &[u8]) -> Result<Packet, ()> {
+    let Some(dst) = ip.get(24..40) else {
+        return Err(());
+    };
+    let dst: [u8; 16] = dst.try_into().unwrap();
+    let dst = Ipv6Addr::from(dst).into();
+
+    // Follow the extension headers to the transport one.
+    let mut protocol = ip[6];
+    let mut offset = IPV6_HDR_LEN;
+    while matches!(
+        protocol,
+        IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_FRAGMENT | IPPROTO_DSTOPTS | IPPROTO_AH
+    ) {
+        let (Some(&next), Some(&len)) = (ip.get(offset), ip.get(offset + 1)) else {
+            return Err(());
+        };
+        if protocol == IPPROTO_FRAGMENT && read_u16(ip, offset + 2).ok_or(())? & 0xfff8 != 0 {
+            return Ok(Packet {
+                protocol: next,
+                dst,
+                port: None,
+                partial: true,
+            });
+        }
+        offset += match protocol {
+            IPPROTO_FRAGMENT => 8,
+            IPPROTO_AH => (usize::from(len) + 2) * 4,
+            _ => (usize::from(len) + 1) * 8,
+        };
+        protocol = next;
+    }
+    let l4 = ip.get(offset..).ok_or(())?;
+
+    let (port, partial) = port(protocol, l4);
+    Ok(Packet {
+        protocol,
+        dst,
+        port,
+        partial,
+    })
+}
+
+/// A table of rules, shared by the devices it applies to.
+#[derive(Debug)]
+pub struct EgressFilter {
+    rules: Vec<Rule>,
+    default_action: Action,
+    /// Whether a rule looks at the protocol or the ports, so packets whose
+    /// transport header is missing can't be checked.
+    transport_rules: bool,
+    dropped: AtomicU64,
+}
+
+impl EgressFilter {
+    pub fn new(rules: Vec<Rule>, default_action: Action) -> Self {
+        let transport_rules = rules
+            .iter()
+            .any(|rule| rule.protocol != Protocol::Any || rule.ports.is_some());
+        Self {
+            rules,
+            default_action,
+            transport_rules,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the Ethernet frame `frame` may be sent.
+    pub fn allows(&self, frame: &[u8]) -> bool {
+        let action = match parse_frame(frame) {
+            None => Action::Allow,
+            Some(Err(())) => Action::Deny,
+            Some(Ok(packet)) if packet.partial && self.transport_rules => Action::Deny,
+            Some(Ok(packet)) => self
+                .rules
+                .iter()
+                .find(|rule| rule.matches(&packet))
+                .map_or(self.default_action, |rule| rule.action),
+        };
+        if action == Action::Deny {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        action == Action::Allow
+    }
+
+    /// The number of frames dropped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Sends through `backend` only the frames `filter` allows, the others being
+/// dropped silently.
+///
+/// The frames are always copied out of guest memory, never written from it
+/// with `write_frame_iov`, so they can't change after being checked.
+pub struct Filtered {
+    backend: Box<dyn NetBackend + Send>,
+    filter: Arc<EgressFilter>,
+}
+
+impl Filtered {
+    pub fn new(backend: Box<dyn NetBackend + Send>, filter: Arc<EgressFilter>) -> Self {
+        Self { backend, filter }
+    }
+}
+
+impl NetBackend for Filtered {
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        self.backend.read_frame(buf)
+    }
+
+    fn write_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+        if !self.filter.allows(&buf[hdr_len..]) {
+            log::debug!("egress: dropping frame of {} bytes", buf.len() - hdr_len);
+            return Ok(());
+        }
+        self.backend.write_frame(hdr_len, buf)
+    }
+
+    fn has_unfinished_write(&self) -> bool {
+        self.backend.has_unfinished_write()
+    }
+
+    fn try_finish_write(&mut self, hdr_len: usize, buf: &[u8]) -> Result<(), WriteError> {
+        self.backend.try_finish_write(hdr_len, buf)
+    }
+
+    fn raw_socket_fd(&self) -> RawFd {
+        self.backend.raw_socket_fd()
+    }
+
+    fn timer_fd(&self) -> Option<RawFd> {
+        self.backend.timer_fd()
+    }
+
+    fn process_timer(&mut self) {
+        self.backend.process_timer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_frame(protocol: u8, dst: [u8; 4], port: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; ETH_HDR_LEN + 20 + TCP_HDR_LEN];
+        frame[12..14].copy_from_slice(&ETH_P_IP.to_be_bytes());
+        let ip = &mut frame[ETH_HDR_LEN..];
+        ip[0] = 0x45;
+        ip[9] = protocol;
+        ip[16..20].copy_from_slice(&dst);
+        ip[22..24].copy_from_slice(&port.to_be_bytes());
+        frame
+    }
+
+    /// A TCP packet to port 22 of fd00::1, after the extension headers `ext`,
+    /// given as (type, header) pairs.
+    fn ipv6_frame(ext: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut frame = vec![0u8; ETH_HDR_LEN + IPV6_HDR_LEN];
+        frame[12..14].copy_from_slice(&ETH_P_IPV6.to_be_bytes());
+        frame[ETH_HDR_LEN] = 0x60;
+        frame[ETH_HDR_LEN + 6] = ext.first().map_or(IPPROTO_TCP, |(kind, _)| *kind);
+        frame[ETH_HDR_LEN + 24..ETH_HDR_LEN + 40]
+            .copy_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+        for (i, (_, hdr)) in ext.iter().enumerate() {
+            let next = ext.get(i + 1).map_or(IPPROTO_TCP, |(kind, _)| *kind);
+            frame.push(next);
+            frame.extend_from_slice(&hdr[1..]);
+        }
+        let mut tcp = vec![0u8; TCP_HDR_LEN];
+        tcp[2..4].copy_from_slice(&22u16.to_be_bytes());
+        frame.extend_from_slice(&tcp);
+        frame
+    }
+
+    fn deny_ssh() -> EgressFilter {
+        EgressFilter::new(
+            vec![
+                Rule::parse("deny tcp 0.0.0.0/0 22").unwrap(),
+                Rule::parse("deny tcp ::/0 22").unwrap(),
+            ],
+            Action::Allow,
+        )
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(
+            Rule::parse("allow tcp 10.0.0.0/8 80-443"),
+            Some(Rule {
+                action: Action::Allow,
+                protocol: Protocol::Tcp,
+                addr: Ipv4Addr::new(10, 0, 0, 0).into(),
+                prefix: 8,
+                ports: Some((80, 443)),
+            })
+        );
+        assert_eq!(Rule::parse("deny any fd00::1").unwrap().prefix, 128);
+        for rule in [
+            "",
+            "drop tcp 10.0.0.0/8",
+            "allow sctp 10.0.0.0/8",
+            "allow tcp 10.0.0.0/33",
+            "allow icmp 10.0.0.0/8 80",
+            "allow udp 10.0.0.0/8 443-80",
+            "allow udp 10.0.0.0/8 53 extra",
+        ] {
+            assert_eq!(Rule::parse(rule), None, "{rule}");
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = EgressFilter::new(
+            vec![
+                Rule::parse("allow udp 10.0.2.3 53").unwrap(),
+                Rule::parse("deny any 10.0.0.0/8").unwrap(),
+                Rule::parse("allow tcp 0.0.0.0/0 443").unwrap(),
+            ],
+            Action::Deny,
+        );
+        assert!(filter.allows(&ipv4_frame(IPPROTO_UDP, [10, 0, 2, 3], 53)));
+        assert!(!filter.allows(&ipv4_frame(IPPROTO_UDP, [10, 0, 2, 4], 53)));
+        assert!(!filter.allows(&ipv4_frame(IPPROTO_TCP, [10, 1, 1, 1], 443)));
+        assert!(filter.allows(&ipv4_frame(IPPROTO_TCP, [1, 1, 1, 1], 443)));
+        assert!(!filter.allows(&ipv4_frame(IPPROTO_TCP, [1, 1, 1, 1], 80)));
+        // Fragments have no port.
+        let mut fragment = ipv4_frame(IPPROTO_TCP, [1, 1, 1, 1], 443);
+        fragment[ETH_HDR_LEN + 7] = 1;
+        assert!(!filter.allows(&fragment));
+        assert_eq!(filter.dropped(), 4);
+
+        let mut arp = vec![0u8; 42];
+        arp[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+        assert!(filter.allows(&arp));
+        // A truncated IP packet.
+        assert!(!filter.allows(&ipv4_frame(IPPROTO_TCP, [1, 1, 1, 1], 443)[..20]));
+    }
+
+    #[test]
+    fn test_ipv4_header_length() {
+        let filter = EgressFilter::new(vec![], Action::Allow);
+        let mut frame = ipv4_frame(IPPROTO_TCP, [1, 1, 1, 1], 22);
+        assert!(filter.allows(&frame));
+        // Shorter than the fixed header.
+        frame[ETH_HDR_LEN] = 0x44;
+        assert!(!filter.allows(&frame));
+        // Longer than the packet.
+        frame[ETH_HDR_LEN] = 0x4f;
+        assert!(!filter.allows(&frame));
+    }
+
+    #[test]
+    fn test_ipv6_extension_headers() {
+        let filter = deny_ssh();
+        let options = vec![0u8; 8];
+        let mut ah = vec![0u8; 16];
+        ah[1] = 2;
+        assert!(!filter.allows(&ipv6_frame(&[])));
+        assert!(!filter.allows(&ipv6_frame(&[
+            (IPPROTO_HOPOPTS, options.clone()),
+            (IPPROTO_AH, ah),
+            (IPPROTO_DSTOPTS, options.clone()),
+        ])));
+        // A first fragment with the whole TCP header.
+        assert!(!filter.allows(&ipv6_frame(&[(
+            IPPROTO_FRAGMENT,
+            vec![0, 0, 0, 1, 0, 0, 0, 0]
+        )])));
+        // A header that goes past the end of the packet.
+        let mut frame = ipv6_frame(&[(IPPROTO_DSTOPTS, options)]);
+        frame[ETH_HDR_LEN + IPV6_HDR_LEN + 1] = 0xff;
+        assert!(!filter.allows(&frame));
+        // Only the TCP packets to port 22 are dropped.
+        let mut frame = ipv6_frame(&[]);
+        frame[ETH_HDR_LEN + IPV6_HDR_LEN + 3] = 23;
+        assert!(filter.allows(&frame));
+    }
+
+    #[test]
+    fn test_fragments() {
+        let filter = deny_ssh();
+        // A fragment other than the first.
+        let mut frame = ipv4_frame(IPPROTO_TCP, [1, 1, 1, 1], 80);
+        frame[ETH_HDR_LEN + 7] = 1;
+        assert!(!filter.allows(&frame));
+        assert!(!filter.allows(&ipv6_frame(&[(
+            IPPROTO_FRAGMENT,
+            vec![0, 0, 0, 8, 0, 0, 0, 0]
+        )])));
+        // A first fragment cut in the middle of the TCP header.
+        let mut frame = ipv4_frame(IPPROTO_TCP, [1, 1, 1, 1], 22);
+        frame[ETH_HDR_LEN + 6] = 0x20;
+        frame.truncate(ETH_HDR_LEN + 20 + 8);
+        assert!(!filter.allows(&frame));
+        assert_eq!(filter.dropped(), 3);
+
+        // Without rules on the protocol or the ports, only the address matters.
+        let filter = EgressFilter::new(
+            vec![Rule::parse("deny any 10.0.0.0/8").unwrap()],
+            Action::Allow,
+        );
+        let mut frame = ipv4_frame(IPPROTO_TCP, [1, 1, 1, 1], 80);
+        frame[ETH_HDR_LEN + 7] = 1;
+        assert!(filter.allows(&frame));
+        frame[ETH_HDR_LEN + 16] = 10;
+        assert!(!filter.allows(&frame));
+    }
+
+    #[test]
+    fn test_vlan_tags() {
+        let filter = deny_ssh();
+        let untagged = ipv4_frame(IPPROTO_TCP, [1, 1, 1, 1], 22);
+        for tags in [[ETH_P_8021Q, ETH_P_8021Q], [ETH_P_8021AD, ETH_P_8021Q]] {
+            let mut frame = untagged[..12].to_vec();
+            for tag in tags {
+                frame.extend_from_slice(&tag.to_be_bytes());
+                frame.extend_from_slice(&[0, 1]);
+            }
+            frame.extend_from_slice(&untagged[12..]);
+            assert!(!filter.allows(&frame), "{tags:x?}");
+            // Cut in the middle of the tags.
+            assert!(!filter.allows(&frame[..17]), "{tags:x?}");
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Backends recording the frames received from another backend to a
+//! `Journal`, or replaying them from it.
+
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+
+use super::backend::{NetBackend, ReadError, WriteError};
+use crate::journal::Journal;
+
+/// Records every frame `backend` receives as an input of the device `id`.
+pub struct Recorder {
+    backend: Box<dyn NetBackend + Send>,
+    journal: Arc<Journal>,
+    id: String,
+}
+
+impl Recorder {
+    pub fn new(backend: Box<dyn NetBackend + Send>, journal: Arc<Journal>, id: String) -> Self {
+        Self {
+            backend,
+            journal,
+            id,
+        }
+    }
+}
+
+impl NetBackend for Recorder {
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        let len = self.backend.read_frame(buf)?;
+        self.journal.record_input(&self.id, &buf[..len]);
+        Ok(len)
+    }
+
+    fn write_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+        self.backend.write_frame(hdr_len, buf)
+    }
+
+    fn has_unfinished_write(&self) -> bool {
+        self.backend.has_unfinished_write()
+    }
+
+    fn try_finish_write(&mut self, hdr_len: usize, buf: &[u8]) -> Result<(), WriteError> {
+        self.backend.try_finish_write(hdr_len, buf)
+    }
+
+    fn raw_socket_fd(&self) -> RawFd {
+        self.backend.raw_socket_fd()
+    }
+
+    fn can_write_iov(&self) -> bool {
+        self.backend.can_write_iov()
+    }
+
+    fn write_frame_iov(&mut self, hdr_len: usize, iov: &[libc::iovec]) -> Result<(), WriteError> {
+        self.backend.write_frame_iov(hdr_len, iov)
+    }
+}
+
+/// Feeds the frames recorded for the device `id` to the guest, as fast as it
+/// takes them, and drops the frames it sends, since the peers it talked to
+/// when recording aren't there anymore.
+pub struct Replayer {
+    journal: Arc<Journal>,
+    id: String,
+    // Always readable, so the worker starts reading frames as soon as it's
+    // registered, then again whenever the guest provides more buffers.
+    ready_evt: EventFd,
+}
+
+impl Replayer {
+    pub fn new(journal: Arc<Journal>, id: String) -> std::io::Result<Self> {
+        let ready_evt = EventFd::new(EFD_NONBLOCK)?;
+        ready_evt.write(1)?;
+        Ok(Self {
+            journal,
+            id,
+            ready_evt,
+        })
+    }
+}
+
+impl NetBackend for Replayer {
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        let frame = self
+            .journal
+            .next_input(&self.id)
+            .ok_or(ReadError::NothingRead)?;
+        let len = frame.len().min(buf.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        Ok(len)
+    }
+
+    fn write_frame(&mut self, _hdr_len: usize, _buf: &mut [u8]) -> Result<(), WriteError> {
+        Ok(())
+    }
+
+    fn has_unfinished_write(&self) -> bool {
+        false
+    }
+
+    fn try_finish_write(&mut self, _hdr_len: usize, _buf: &[u8]) -> Result<(), WriteError> {
+        Ok(())
+    }
+
+    fn raw_socket_fd(&self) -> RawFd {
+        self.ready_evt.as_raw_fd()
+    }
+}
@@ -15,6 +15,10 @@ pub const TX_INDEX: usize = 1;
 
 mod backend;
 pub mod device;
+pub mod egress;
+mod journal;
+pub mod netem;
+pub mod switch;
 #[cfg(target_os = "linux")]
 mod tap;
 mod unixgram;
@@ -0,0 +1,361 @@
+//! Emulation of a degraded network, in the style of Linux's netem, so
+//! applications can be tested under bad conditions without changing the
+//! network of the host.
+//!
+//! The frames going through the backend in either direction may be dropped
+//! at random, are sent no faster than the configured rate, and are held for
+//! the configured delay, give or take the jitter, before being sent on. The
+//! order of the frames is kept.
+
+use std::collections::VecDeque;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+
+use super::backend::{NetBackend, ReadError, WriteError};
+use super::MAX_BUFFER_SIZE;
+
+/// Frames held in each direction, beyond which the ones received are
+/// dropped, or the guest made to wait for the ones it sends.
+const QUEUE_LIMIT: usize = 1024;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetemConfig {
+    pub delay: Duration,
+    /// Largest random variation of the delay, either way.
+    pub jitter: Duration,
+    /// Probability of a frame being dropped, in parts per million.
+    pub loss_ppm: u32,
+    /// Bytes per second, unlimited if 0.
+    pub rate: u64,
+}
+
+/// Signals its event fd once the earliest deadline it was armed for passes.
+struct Timer {
+    evt: EventFd,
+    /// The deadline, and whether the timer is stopped.
+    state: Mutex<(Option<Instant>, bool)>,
+    cond: Condvar,
+}
+
+impl Timer {
+    fn start() -> std::io::Result<Arc<Self>> {
+        let timer = Arc::new(Self {
+            evt: EventFd::new(EFD_NONBLOCK)?,
+            state: Mutex::new((None, false)),
+            cond: Condvar::new(),
+        });
+
+        let thread_timer = timer.clone();
+        thread::Builder::new()
+            .name("netem timer".into())
+            .spawn(move || thread_timer.run())?;
+        Ok(timer)
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            state = match *state {
+                (_, true) => return,
+                (None, _) => self.cond.wait(state).unwrap(),
+                (Some(deadline), _) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        state.0 = None;
+                        if let Err(e) = self.evt.write(1) {
+                            error!("netem: failed to signal the timer: {e}");
+                        }
+                        continue;
+                    }
+                    self.cond.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    fn arm(&self, deadline: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if state.0.is_none_or(|current| deadline < current) {
+            state.0 = Some(deadline);
+            self.cond.notify_one();
+        }
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.cond.notify_one();
+    }
+}
+
+/// The frames held in a direction.
+#[derive(Default)]
+struct Link {
+    frames: VecDeque<(Instant, Vec<u8>)>,
+    /// When the last frame is done being sent at the configured rate.
+    busy_until: Option<Instant>,
+}
+
+impl Link {
+    /// Returns when a frame of `len` bytes sent now is due on the other end.
+    fn schedule(&mut self, config: &NetemConfig, len: usize, jitter: f64) -> Instant {
+        let now = Instant::now();
+        let mut sent = now;
+        if config.rate != 0 {
+            let start = self.busy_until.map_or(now, |busy| busy.max(now));
+            sent = start + Duration::from_secs_f64(len as f64 / config.rate as f64);
+            self.busy_until = Some(sent);
+        }
+
+        let delay = config.delay.as_secs_f64() + config.jitter.as_secs_f64() * jitter;
+        let due = sent + Duration::from_secs_f64(delay.max(0.0));
+        // Jitter doesn't reorder frames.
+        self.frames.back().map_or(due, |&(last, _)| due.max(last))
+    }
+
+    fn due(&self, now: Instant) -> bool {
+        self.frames.front().is_some_and(|&(due, _)| due <= now)
+    }
+}
+
+/// Degrades the network `backend` gives access to as `config` says.
+pub struct Netem {
+    backend: Box<dyn NetBackend + Send>,
+    config: NetemConfig,
+    timer: Arc<Timer>,
+    rng: u32,
+    rx: Link,
+    tx: Link,
+    hdr_len: usize,
+    /// Whether the first frame of `tx` was partially written to `backend`.
+    tx_partial: bool,
+    rx_buf: Vec<u8>,
+}
+
+impl Netem {
+    pub fn new(backend: Box<dyn NetBackend + Send>, config: NetemConfig) -> std::io::Result<Self> {
+        Ok(Self {
+            backend,
+            config,
+            timer: Timer::start()?,
+            rng: utils::rand::xor_rng_u32() | 1,
+            rx: Link::default(),
+            tx: Link::default(),
+            hdr_len: 0,
+            tx_partial: false,
+            rx_buf: vec![0; MAX_BUFFER_SIZE],
+        })
+    }
+
+    fn next_random(&mut self) -> u32 {
+        // Xorshift, see https://en.wikipedia.org/wiki/Xorshift.
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+
+    fn lost(&mut self) -> bool {
+        self.config.loss_ppm != 0 && self.next_random() % 1_000_000 < self.config.loss_ppm
+    }
+
+    /// A random number in [-1, 1].
+    fn jitter(&mut self) -> f64 {
+        if self.config.jitter.is_zero() {
+            return 0.0;
+        }
+        f64::from(self.next_random()) / f64::from(u32::MAX) * 2.0 - 1.0
+    }
+
+    /// Reads the frames pending in the backend into `rx`.
+    fn fill_rx(&mut self) -> Result<(), ReadError> {
+        while self.rx.frames.len() < QUEUE_LIMIT {
+            let len = match self.backend.read_frame(&mut self.rx_buf) {
+                Ok(len) => len,
+                Err(ReadError::NothingRead) => break,
+                Err(e) => return Err(e),
+            };
+            if self.lost() {
+                continue;
+            }
+            let jitter = self.jitter();
+            let due = self.rx.schedule(&self.config, len, jitter);
+            self.rx.frames.push_back((due, self.rx_buf[..len].to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Sends the frames of `tx` that are due to the backend.
+    fn flush_tx(&mut self) -> Result<(), WriteError> {
+        while self.tx.due(Instant::now()) {
+            let frame = &mut self.tx.frames.front_mut().unwrap().1;
+            let result = if self.tx_partial {
+                self.backend.try_finish_write(self.hdr_len, frame)
+            } else {
+                self.backend.write_frame(self.hdr_len, frame)
+            };
+            match result {
+                Ok(()) => {
+                    self.tx_partial = false;
+                    self.tx.frames.pop_front();
+                }
+                Err(WriteError::PartialWrite) => {
+                    // The backend signals when it can take the rest.
+                    self.tx_partial = true;
+                    return Ok(());
+                }
+                Err(WriteError::NothingWritten) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(&(due, _)) = self.tx.frames.front() {
+            self.timer.arm(due);
+        }
+        Ok(())
+    }
+}
+
+impl NetBackend for Netem {
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        self.fill_rx()?;
+        if !self.rx.due(Instant::now()) {
+            if let Some(&(due, _)) = self.rx.frames.front() {
+                self.timer.arm(due);
+            }
+            return Err(ReadError::NothingRead);
+        }
+        let (_, frame) = self.rx.frames.pop_front().unwrap();
+        buf[..frame.len()].copy_from_slice(&frame);
+        Ok(frame.len())
+    }
+
+    fn write_frame(&mut self, hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+        self.hdr_len = hdr_len;
+        if self.tx.frames.len() >= QUEUE_LIMIT {
+            return Err(WriteError::NothingWritten);
+        }
+        if !self.lost() {
+            let jitter = self.jitter();
+            let due = self.tx.schedule(&self.config, buf.len(), jitter);
+            self.tx.frames.push_back((due, buf.to_vec()));
+        }
+        self.flush_tx()
+    }
+
+    fn has_unfinished_write(&self) -> bool {
+        false
+    }
+
+    fn try_finish_write(&mut self, _hdr_len: usize, _buf: &[u8]) -> Result<(), WriteError> {
+        self.flush_tx()
+    }
+
+    fn raw_socket_fd(&self) -> RawFd {
+        self.backend.raw_socket_fd()
+    }
+
+    fn timer_fd(&self) -> Option<RawFd> {
+        Some(self.timer.evt.as_raw_fd())
+    }
+
+    fn process_timer(&mut self) {
+        let _ = self.timer.evt.read();
+    }
+}
+
+impl Drop for Netem {
+    fn drop(&mut self) {
+        self.timer.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let config = NetemConfig {
+            delay: Duration::from_millis(100),
+            jitter: Duration::from_millis(50),
+            rate: 1000,
+            ..Default::default()
+        };
+        let mut link = Link::default();
+        let start = Instant::now();
+
+        // Sending 500 bytes at 1000 bytes/s takes 500ms, plus the delay.
+        let due = link.schedule(&config, 500, 0.0);
+        assert!(due >= start + Duration::from_millis(600));
+        link.frames.push_back((due, vec![]));
+        // The next frame waits for the first to be sent, and jitter doesn't
+        // get it ahead of it.
+        let next = link.schedule(&config, 0, -1.0);
+        assert!(next >= due);
+        assert!(next >= start + Duration::from_millis(550));
+        assert!(!link.due(start));
+    }
+
+    struct Sink(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl NetBackend for Sink {
+        fn read_frame(&mut self, _buf: &mut [u8]) -> Result<usize, ReadError> {
+            Err(ReadError::NothingRead)
+        }
+
+        fn write_frame(&mut self, _hdr_len: usize, buf: &mut [u8]) -> Result<(), WriteError> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(())
+        }
+
+        fn has_unfinished_write(&self) -> bool {
+            false
+        }
+
+        fn try_finish_write(&mut self, _hdr_len: usize, _buf: &[u8]) -> Result<(), WriteError> {
+            Ok(())
+        }
+
+        fn raw_socket_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
+    fn sink_netem(config: NetemConfig) -> (Netem, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let netem = Netem::new(Box::new(Sink(sent.clone())), config).unwrap();
+        (netem, sent)
+    }
+
+    #[test]
+    fn test_write() {
+        let (mut netem, sent) = sink_netem(NetemConfig::default());
+        netem.write_frame(0, &mut [1, 2]).unwrap();
+        assert_eq!(*sent.lock().unwrap(), [vec![1, 2]]);
+
+        let (mut netem, sent) = sink_netem(NetemConfig {
+            loss_ppm: 1_000_000,
+            ..Default::default()
+        });
+        netem.write_frame(0, &mut [1, 2]).unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+
+        let (mut netem, sent) = sink_netem(NetemConfig {
+            delay: Duration::from_millis(20),
+            ..Default::default()
+        });
+        netem.write_frame(0, &mut [1, 2]).unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+        // Wait for the timer to fire.
+        let start = Instant::now();
+        while netem.timer.evt.read().is_err() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        netem.try_finish_write(0, &[]).unwrap();
+        assert_eq!(*sent.lock().unwrap(), [vec![1, 2]]);
+    }
+}
@@ -0,0 +1,277 @@
+//! A learning L2 switch connecting the virtio-net devices of microVMs that
+//! run on the same host, without going through the host network stack.
+//!
+//! The switch listens on a unixgram socket. Each device joins it from its
+//! own socket, announcing itself with `SWITCH_MAGIC` followed by the le16
+//! VLAN id of the port (0 for untagged), and then exchanges plain Ethernet
+//! frames with it. Frames are only forwarded between ports of the same VLAN.
+//!
+//! The switch is served by a thread of the first VMM process joining it, and
+//! goes away with it. A later join finds the stale socket and takes over.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::{process, thread};
+
+use nix::sys::socket::{
+    bind, connect, recvfrom, send, sendto, socket, AddressFamily, MsgFlags, SockFlag, SockType,
+    UnixAddr,
+};
+use nix::unistd::unlink;
+
+use super::backend::ConnectError;
+use super::unixgram::Unixgram;
+use super::MAX_BUFFER_SIZE;
+
+pub const SWITCH_MAGIC: [u8; 4] = *b"KSWT";
+
+/// Highest VLAN id that can be assigned to a port.
+pub const MAX_VLAN: u16 = 4094;
+
+const ETH_HLEN: usize = 14;
+
+fn is_multicast(mac: &[u8]) -> bool {
+    mac[0] & 1 != 0
+}
+
+/// Port membership and MAC address table of a switch.
+pub struct Switch<P> {
+    ports: HashMap<P, u16>,
+    macs: HashMap<(u16, [u8; 6]), P>,
+}
+
+impl<P: Clone + Eq + Hash> Default for Switch<P> {
+    fn default() -> Self {
+        Self {
+            ports: HashMap::new(),
+            macs: HashMap::new(),
+        }
+    }
+}
+
+impl<P: Clone + Eq + Hash> Switch<P> {
+    pub fn add_port(&mut self, port: P, vlan: u16) {
+        self.remove_port(&port);
+        self.ports.insert(port, vlan);
+    }
+
+    pub fn remove_port(&mut self, port: &P) {
+        self.ports.remove(port);
+        self.macs.retain(|_, p| p != port);
+    }
+
+    /// Learns the source address of `frame`, received from `src`, and
+    /// returns the ports it must be forwarded to.
+    pub fn route(&mut self, src: &P, frame: &[u8]) -> Vec<P> {
+        let Some(&vlan) = self.ports.get(src) else {
+            return Vec::new();
+        };
+        if frame.len() < ETH_HLEN {
+            return Vec::new();
+        }
+
+        let dst_mac: [u8; 6] = frame[..6].try_into().unwrap();
+        let src_mac: [u8; 6] = frame[6..12].try_into().unwrap();
+        if !is_multicast(&src_mac) {
+            self.macs.insert((vlan, src_mac), src.clone());
+        }
+
+        if !is_multicast(&dst_mac) {
+            if let Some(port) = self.macs.get(&(vlan, dst_mac)) {
+                return if port != src {
+                    vec![port.clone()]
+                } else {
+                    Vec::new()
+                };
+            }
+        }
+
+        // Broadcast, multicast or unknown destination.
+        self.ports
+            .iter()
+            .filter(|(port, port_vlan)| *port != src && **port_vlan == vlan)
+            .map(|(port, _)| port.clone())
+            .collect()
+    }
+}
+
+fn serve(fd: OwnedFd) {
+    let mut switch = Switch::<PathBuf>::default();
+    let mut buf = vec![0u8; MAX_BUFFER_SIZE];
+
+    loop {
+        let (len, addr) = match recvfrom::<UnixAddr>(fd.as_raw_fd(), &mut buf) {
+            Ok((len, Some(addr))) => (len, addr),
+            Ok((_, None)) => continue,
+            Err(nix::Error::EINTR) => continue,
+            Err(e) => {
+                error!("virtual switch stopped: {e}");
+                return;
+            }
+        };
+        let Some(src) = addr.path().map(Path::to_path_buf) else {
+            continue;
+        };
+        let datagram = &buf[..len];
+
+        if len == SWITCH_MAGIC.len() + 2 && datagram[..4] == SWITCH_MAGIC {
+            let vlan = u16::from_le_bytes([datagram[4], datagram[5]]);
+            debug!("virtual switch: port {} joined vlan {vlan}", src.display());
+            switch.add_port(src, vlan);
+            continue;
+        }
+
+        for port in switch.route(&src, datagram) {
+            let Ok(dst) = UnixAddr::new(&port) else {
+                continue;
+            };
+            match sendto(fd.as_raw_fd(), datagram, &dst, MsgFlags::MSG_DONTWAIT) {
+                Ok(_) => {}
+                // The port is congested, drop the frame like a real switch.
+                #[allow(unreachable_patterns)]
+                Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK | nix::Error::ENOBUFS) => {}
+                Err(e) => {
+                    debug!("virtual switch: removing port {}: {e}", port.display());
+                    switch.remove_port(&port);
+                }
+            }
+        }
+    }
+}
+
+/// Serves the switch at `path` from a new thread, unless another process is
+/// already doing it.
+fn host(path: &Path) -> Result<(), ConnectError> {
+    let addr = UnixAddr::new(path).map_err(ConnectError::InvalidAddress)?;
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        SockFlag::empty(),
+        None,
+    )
+    .map_err(ConnectError::CreateSocket)?;
+
+    match bind(fd.as_raw_fd(), &addr) {
+        Ok(()) => {}
+        Err(nix::Error::EADDRINUSE) => {
+            // Check whether it's being served, or left behind by a process
+            // that exited.
+            let probe = socket(
+                AddressFamily::Unix,
+                SockType::Datagram,
+                SockFlag::empty(),
+                None,
+            )
+            .map_err(ConnectError::CreateSocket)?;
+            match connect(probe.as_raw_fd(), &addr) {
+                Err(nix::Error::ECONNREFUSED) => {
+                    _ = unlink(path);
+                    bind(fd.as_raw_fd(), &addr).map_err(ConnectError::Binding)?;
+                }
+                _ => return Ok(()),
+            }
+        }
+        Err(e) => return Err(ConnectError::Binding(e)),
+    }
+
+    thread::Builder::new()
+        .name("virtual switch".into())
+        .spawn(move || serve(fd))
+        .map_err(ConnectError::SpawnSwitch)?;
+    Ok(())
+}
+
+/// Connects a backend to a port of the switch at `path`, on `vlan`, hosting
+/// the switch first if nobody is.
+pub(crate) fn join(path: &Path, vlan: u16) -> Result<Unixgram, ConnectError> {
+    static PORT_INDEX: AtomicU32 = AtomicU32::new(0);
+
+    host(path)?;
+
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        SockFlag::empty(),
+        None,
+    )
+    .map_err(ConnectError::CreateSocket)?;
+    let peer_addr = UnixAddr::new(path).map_err(ConnectError::InvalidAddress)?;
+    let local_path = PathBuf::from(format!(
+        "{}-{}-{}.sock",
+        path.display(),
+        process::id(),
+        PORT_INDEX.fetch_add(1, Ordering::Relaxed)
+    ));
+    let local_addr = UnixAddr::new(&local_path).map_err(ConnectError::InvalidAddress)?;
+    _ = unlink(&local_path);
+    bind(fd.as_raw_fd(), &local_addr).map_err(ConnectError::Binding)?;
+    connect(fd.as_raw_fd(), &peer_addr).map_err(ConnectError::Binding)?;
+
+    let mut hello = SWITCH_MAGIC.to_vec();
+    hello.extend_from_slice(&vlan.to_le_bytes());
+    send(fd.as_raw_fd(), &hello, MsgFlags::empty()).map_err(ConnectError::SendingMagic)?;
+
+    Ok(Unixgram::new(fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(dst: u8, src: u8) -> Vec<u8> {
+        let mut frame = vec![0u8; 60];
+        frame[..6].copy_from_slice(&[2, 0, 0, 0, 0, dst]);
+        frame[6..12].copy_from_slice(&[2, 0, 0, 0, 0, src]);
+        frame
+    }
+
+    fn sorted(mut ports: Vec<u32>) -> Vec<u32> {
+        ports.sort();
+        ports
+    }
+
+    #[test]
+    fn test_learning() {
+        let mut switch = Switch::default();
+        switch.add_port(1, 0);
+        switch.add_port(2, 0);
+        switch.add_port(3, 0);
+
+        // Unknown destination, flooded.
+        assert_eq!(sorted(switch.route(&1, &frame(0xb, 0xa))), vec![2, 3]);
+        // 0xa was learned on port 1.
+        assert_eq!(switch.route(&2, &frame(0xa, 0xb)), vec![1]);
+        assert_eq!(switch.route(&3, &frame(0xb, 0xc)), vec![2]);
+        // Frames for the port they came from are dropped.
+        assert_eq!(switch.route(&1, &frame(0xa, 0xa)), Vec::<u32>::new());
+
+        let mut broadcast = frame(0, 0xa);
+        broadcast[..6].fill(0xff);
+        assert_eq!(sorted(switch.route(&1, &broadcast)), vec![2, 3]);
+
+        // Forgets the addresses of removed ports.
+        switch.remove_port(&2);
+        assert_eq!(switch.route(&1, &frame(0xb, 0xa)), vec![3]);
+        // Unregistered ports and runt frames are ignored.
+        assert_eq!(switch.route(&2, &frame(0xa, 0xb)), Vec::<u32>::new());
+        assert_eq!(switch.route(&1, &[0u8; 10]), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_vlans() {
+        let mut switch = Switch::default();
+        switch.add_port(1, 10);
+        switch.add_port(2, 10);
+        switch.add_port(3, 20);
+        switch.add_port(4, 20);
+
+        assert_eq!(switch.route(&1, &frame(0xb, 0xa)), vec![2]);
+        assert_eq!(switch.route(&3, &frame(0xb, 0xc)), vec![4]);
+        // The same address on another VLAN isn't learned.
+        assert_eq!(switch.route(&4, &frame(0xa, 0xd)), vec![3]);
+        assert_eq!(switch.route(&2, &frame(0xa, 0xb)), vec![1]);
+    }
+}
@@ -2,6 +2,7 @@ use libc::{
     c_char, c_int, ifreq, IFF_NO_PI, IFF_TAP, IFF_VNET_HDR, TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6,
     TUN_F_UFO,
 };
+use nix::errno::Errno;
 use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
 use nix::sys::stat::Mode;
 use nix::unistd::{read, write};
@@ -125,4 +126,24 @@ impl NetBackend for Tap {
     fn raw_socket_fd(&self) -> RawFd {
         self.fd.as_raw_fd()
     }
+
+    fn can_write_iov(&self) -> bool {
+        true
+    }
+
+    /// Try to write a frame to the tap device straight from `iov`. The
+    /// kernel copies the data into an skb before returning.
+    fn write_frame_iov(&mut self, _hdr_len: usize, iov: &[libc::iovec]) -> Result<(), WriteError> {
+        // SAFETY: the caller guarantees the iovecs are valid for reads.
+        let ret = unsafe { libc::writev(self.fd.as_raw_fd(), iov.as_ptr(), iov.len() as c_int) };
+        match Errno::result(ret) {
+            Ok(ret) => {
+                debug!("Written frame from iov, written={ret}");
+                Ok(())
+            }
+            #[allow(unreachable_patterns)]
+            Err(Errno::EAGAIN | Errno::EWOULDBLOCK) => Err(WriteError::NothingWritten),
+            Err(e) => Err(WriteError::Internal(e)),
+        }
+    }
 }
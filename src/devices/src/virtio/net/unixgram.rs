@@ -136,4 +136,45 @@ impl NetBackend for Unixgram {
     fn raw_socket_fd(&self) -> RawFd {
         self.fd.as_raw_fd()
     }
+
+    fn can_write_iov(&self) -> bool {
+        true
+    }
+
+    /// Try to write a frame to the proxy straight from `iov`, skipping the
+    /// virtio-net header.
+    fn write_frame_iov(&mut self, hdr_len: usize, iov: &[libc::iovec]) -> Result<(), WriteError> {
+        let mut skip = hdr_len;
+        let mut frame_len = 0;
+        let mut frame_iov = Vec::with_capacity(iov.len());
+        for v in iov {
+            if skip >= v.iov_len {
+                skip -= v.iov_len;
+                continue;
+            }
+            frame_iov.push(libc::iovec {
+                // SAFETY: skip is within the bounds of the buffer.
+                iov_base: unsafe { (v.iov_base as *mut u8).add(skip) } as *mut libc::c_void,
+                iov_len: v.iov_len - skip,
+            });
+            frame_len += v.iov_len - skip;
+            skip = 0;
+        }
+
+        // SAFETY: msghdr is a plain C struct, all zeroes is a valid value.
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = frame_iov.as_mut_ptr();
+        msg.msg_iovlen = frame_iov.len() as _;
+        // SAFETY: the caller guarantees the iovecs are valid for reads.
+        let ret = unsafe { libc::sendmsg(self.fd.as_raw_fd(), &msg, 0) };
+        match nix::errno::Errno::result(ret) {
+            Ok(ret) => {
+                debug!("Written frame size={frame_len}, written={ret}");
+                Ok(())
+            }
+            #[allow(unreachable_patterns)]
+            Err(nix::Error::EAGAIN | nix::Error::EWOULDBLOCK) => Err(WriteError::NothingWritten),
+            Err(e) => Err(WriteError::Internal(e)),
+        }
+    }
 }
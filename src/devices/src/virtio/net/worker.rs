@@ -1,4 +1,9 @@
+use crate::journal::Journal;
 use crate::virtio::net::backend::ConnectError;
+use crate::virtio::net::egress::{EgressFilter, Filtered};
+use crate::virtio::net::journal::{Recorder, Replayer};
+use crate::virtio::net::netem::{Netem, NetemConfig};
+use crate::virtio::net::switch;
 #[cfg(target_os = "linux")]
 use crate::virtio::net::tap::Tap;
 use crate::virtio::net::unixgram::Unixgram;
@@ -10,12 +15,47 @@ use super::backend::{NetBackend, ReadError, WriteError};
 use super::device::{FrontendError, RxError, TxError, VirtioNetBackend};
 use super::vnet_hdr_len;
 
-use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
-use std::thread;
-use std::{cmp, result};
-use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::{cmp, io, result};
+use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
-use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use utils::worker_pool::{run_handler, PoolHandler, PoolTask};
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+fn connect_backend(
+    cfg_backend: VirtioNetBackend,
+    _vnet_features: u64,
+) -> Result<Box<dyn NetBackend + Send>, ConnectError> {
+    let backend = match cfg_backend {
+        VirtioNetBackend::UnixstreamFd(fd) => {
+            // SAFETY: we need to trust that the library user has configured
+            // the backend with a healthy file descriptor.
+            let owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            Box::new(Unixstream::new(owned_fd)) as Box<dyn NetBackend + Send>
+        }
+        VirtioNetBackend::UnixstreamPath(path) => {
+            Box::new(Unixstream::open(path)?) as Box<dyn NetBackend + Send>
+        }
+        VirtioNetBackend::UnixgramFd(fd) => {
+            // SAFETY: we need to trust that the library user has configured
+            // the backend with a healthy file descriptor.
+            let owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            Box::new(Unixgram::new(owned_fd)) as Box<dyn NetBackend + Send>
+        }
+        VirtioNetBackend::UnixgramPath(path, vfkit_magic) => {
+            Box::new(Unixgram::open(path, vfkit_magic)?) as Box<dyn NetBackend + Send>
+        }
+        VirtioNetBackend::Switch(path, vlan) => {
+            Box::new(switch::join(&path, vlan)?) as Box<dyn NetBackend + Send>
+        }
+        #[cfg(target_os = "linux")]
+        VirtioNetBackend::Tap(tap_name) => {
+            Box::new(Tap::new(tap_name, _vnet_features)?) as Box<dyn NetBackend + Send>
+        }
+    };
+    Ok(backend)
+}
 
 pub struct NetWorker {
     queues: Vec<Queue>,
@@ -41,33 +81,31 @@ impl NetWorker {
         queue_evts: Vec<EventFd>,
         interrupt: InterruptTransport,
         mem: GuestMemoryMmap,
-        _vnet_features: u64,
+        vnet_features: u64,
         cfg_backend: VirtioNetBackend,
+        id: String,
+        journal: Option<Arc<Journal>>,
+        egress_filter: Option<Arc<EgressFilter>>,
+        netem: Option<NetemConfig>,
     ) -> Result<Self, ConnectError> {
-        let backend = match cfg_backend {
-            VirtioNetBackend::UnixstreamFd(fd) => {
-                // SAFETY: we need to trust that the library user has configured
-                // the backend with a healthy file descriptor.
-                let owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
-                Box::new(Unixstream::new(owned_fd)) as Box<dyn NetBackend + Send>
-            }
-            VirtioNetBackend::UnixstreamPath(path) => {
-                Box::new(Unixstream::open(path)?) as Box<dyn NetBackend + Send>
-            }
-            VirtioNetBackend::UnixgramFd(fd) => {
-                // SAFETY: we need to trust that the library user has configured
-                // the backend with a healthy file descriptor.
-                let owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
-                Box::new(Unixgram::new(owned_fd)) as Box<dyn NetBackend + Send>
-            }
-            VirtioNetBackend::UnixgramPath(path, vfkit_magic) => {
-                Box::new(Unixgram::open(path, vfkit_magic)?) as Box<dyn NetBackend + Send>
-            }
-            #[cfg(target_os = "linux")]
-            VirtioNetBackend::Tap(tap_name) => {
-                Box::new(Tap::new(tap_name, _vnet_features)?) as Box<dyn NetBackend + Send>
+        let mut backend = match journal {
+            Some(journal) if journal.is_replaying() => {
+                Box::new(Replayer::new(journal, id).map_err(ConnectError::CreateReplayer)?)
+                    as Box<dyn NetBackend + Send>
             }
+            Some(journal) => Box::new(Recorder::new(
+                connect_backend(cfg_backend, vnet_features)?,
+                journal,
+                id,
+            )),
+            None => connect_backend(cfg_backend, vnet_features)?,
         };
+        if let Some(config) = netem {
+            backend = Box::new(Netem::new(backend, config).map_err(ConnectError::StartNetem)?);
+        }
+        if let Some(filter) = egress_filter {
+            backend = Box::new(Filtered::new(backend, filter));
+        }
 
         Ok(Self {
             queues,
@@ -87,87 +125,26 @@ impl NetWorker {
         })
     }
 
-    pub fn run(self) {
-        thread::Builder::new()
-            .name("virtio-net worker".into())
-            .spawn(|| self.work())
-            .unwrap();
-    }
-
-    fn work(mut self) {
-        let virtq_rx_ev_fd = self.queue_evts[RX_INDEX].as_raw_fd();
-        let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
-        let backend_socket = self.backend.raw_socket_fd();
-
-        let epoll = Epoll::new().unwrap();
-
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            virtq_rx_ev_fd,
-            &EpollEvent::new(EventSet::IN, virtq_rx_ev_fd as u64),
-        );
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            virtq_tx_ev_fd,
-            &EpollEvent::new(EventSet::IN, virtq_tx_ev_fd as u64),
-        );
-        let _ = epoll.ctl(
-            ControlOperation::Add,
-            backend_socket,
-            &EpollEvent::new(
-                EventSet::IN | EventSet::OUT | EventSet::EDGE_TRIGGERED | EventSet::READ_HANG_UP,
-                backend_socket as u64,
+    pub fn run(self) -> io::Result<PoolTask> {
+        let mut fds = vec![
+            (self.queue_evts[RX_INDEX].as_raw_fd(), EventSet::IN),
+            (self.queue_evts[TX_INDEX].as_raw_fd(), EventSet::IN),
+            (
+                self.backend.raw_socket_fd(),
+                EventSet::IN | EventSet::OUT | EventSet::READ_HANG_UP,
             ),
-        );
-
-        loop {
-            let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
-            match epoll.wait(epoll_events.len(), -1, epoll_events.as_mut_slice()) {
-                Ok(ev_cnt) => {
-                    for event in &epoll_events[0..ev_cnt] {
-                        let source = event.fd();
-                        let event_set = event.event_set();
-                        match event_set {
-                            EventSet::IN if source == virtq_rx_ev_fd => {
-                                self.process_rx_queue_event();
-                            }
-                            EventSet::IN if source == virtq_tx_ev_fd => {
-                                self.process_tx_queue_event();
-                            }
-                            _ if source == backend_socket => {
-                                if event_set.contains(EventSet::HANG_UP)
-                                    || event_set.contains(EventSet::READ_HANG_UP)
-                                {
-                                    log::error!("Got {event_set:?} on backend fd, virtio-net will stop working");
-                                    eprintln!("LIBKRUN VIRTIO-NET FATAL: Backend process seems to have quit or crashed! Networking is now disabled!");
-                                } else {
-                                    if event_set.contains(EventSet::IN) {
-                                        self.process_backend_socket_readable()
-                                    }
-
-                                    if event_set.contains(EventSet::OUT) {
-                                        self.process_backend_socket_writeable()
-                                    }
-                                }
-                            }
-                            _ => {
-                                log::warn!(
-                                    "Received unknown event: {event_set:?} from fd: {source:?}"
-                                );
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    debug!("vsock: failed to consume muxer epoll event: {e}");
-                }
-            }
+        ];
+        if let Some(fd) = self.backend.timer_fd() {
+            fds.push((fd, EventSet::IN));
         }
+        run_handler("virtio-net worker", &fds, Box::new(self))
     }
 
     pub(crate) fn process_rx_queue_event(&mut self) {
-        if let Err(e) = self.queue_evts[RX_INDEX].read() {
-            log::error!("Failed to get rx event from queue: {e:?}");
+        match self.queue_evts[RX_INDEX].read() {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => log::error!("Failed to get rx event from queue: {e:?}"),
         }
         if let Err(e) = self.queues[RX_INDEX].disable_notification(&self.mem) {
             error!("error disabling queue notifications: {e:?}");
@@ -183,6 +160,7 @@ impl NetWorker {
     pub(crate) fn process_tx_queue_event(&mut self) {
         match self.queue_evts[TX_INDEX].read() {
             Ok(_) => self.process_tx_loop(),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
             Err(e) => {
                 log::error!("Failed to get tx queue event from queue: {e:?}");
             }
@@ -217,19 +195,21 @@ impl NetWorker {
         }
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     fn process_rx(&mut self) -> result::Result<(), RxError> {
+        let mut signal_queue = false;
+
         // if we have a deferred frame we try to process it first,
         // if that is not possible, we don't continue processing other frames
         if self.rx_has_deferred_frame {
             if self.write_frame_to_guest() {
                 self.rx_has_deferred_frame = false;
+                signal_queue = true;
             } else {
                 return Ok(());
             }
         }
 
-        let mut signal_queue = false;
-
         // Read as many frames as possible.
         let result = loop {
             match self.read_into_rx_frame_buf_from_backend() {
@@ -237,6 +217,7 @@ impl NetWorker {
                     if self.write_frame_to_guest() {
                         signal_queue = true;
                     } else {
+                        self.queues[RX_INDEX].report_full();
                         self.rx_has_deferred_frame = true;
                         break Ok(());
                     }
@@ -248,7 +229,7 @@ impl NetWorker {
 
         // At this point we processed as many Rx frames as possible.
         // We have to wake the guest if at least one descriptor chain has been used.
-        if signal_queue {
+        if signal_queue && self.queues[RX_INDEX].needs_notification(&self.mem).unwrap() {
             self.interrupt
                 .try_signal_used_queue()
                 .map_err(RxError::DeviceError)?;
@@ -276,9 +257,23 @@ impl NetWorker {
         }
     }
 
-    fn process_tx(&mut self) -> result::Result<(), TxError> {
-        let tx_queue = &mut self.queues[TX_INDEX];
+    /// Tries to write the frame described by `self.tx_iovec` straight from
+    /// guest memory. Returns `None` if the buffers can't be accessed
+    /// directly, in which case the frame has to be copied.
+    fn write_frame_iov(&mut self) -> Option<result::Result<(), WriteError>> {
+        let mut iov = Vec::with_capacity(self.tx_iovec.len());
+        for &(addr, len) in &self.tx_iovec {
+            let slice = self.mem.get_slice(addr, len).ok()?;
+            iov.push(libc::iovec {
+                iov_base: slice.ptr_guard().as_ptr() as *mut libc::c_void,
+                iov_len: len,
+            });
+        }
+        Some(self.backend.write_frame_iov(vnet_hdr_len(), &iov))
+    }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn process_tx(&mut self) -> result::Result<(), TxError> {
         if self.backend.has_unfinished_write()
             && self
                 .backend
@@ -291,7 +286,7 @@ impl NetWorker {
 
         let mut raise_irq = false;
 
-        while let Some(head) = tx_queue.pop(&self.mem) {
+        while let Some(head) = self.queues[TX_INDEX].pop(&self.mem) {
             let head_index = head.index;
             let mut read_count = 0;
             let mut next_desc = Some(head);
@@ -307,6 +302,27 @@ impl NetWorker {
                 next_desc = desc.next_descriptor();
             }
 
+            if self.backend.can_write_iov() && read_count <= MAX_BUFFER_SIZE {
+                match self.write_frame_iov() {
+                    Some(Ok(())) => {
+                        self.interrupt.metrics().tx_bytes.add(read_count as u64);
+                        self.queues[TX_INDEX]
+                            .add_used(&self.mem, head_index, 0)
+                            .map_err(TxError::QueueError)?;
+                        raise_irq = true;
+                        continue;
+                    }
+                    Some(Err(WriteError::NothingWritten)) => {
+                        self.queues[TX_INDEX].undo_pop();
+                        break;
+                    }
+                    Some(Err(e)) => return Err(TxError::Backend(e)),
+                    None => {}
+                }
+            }
+
+            let tx_queue = &mut self.queues[TX_INDEX];
+
             // Copy buffer from across multiple descriptors.
             read_count = 0;
             for (desc_addr, desc_len) in self.tx_iovec.drain(..) {
@@ -334,6 +350,7 @@ impl NetWorker {
             {
                 Ok(()) => {
                     self.tx_frame_len = 0;
+                    self.interrupt.metrics().tx_bytes.add(read_count as u64);
                     tx_queue
                         .add_used(&self.mem, head_index, 0)
                         .map_err(TxError::QueueError)?;
@@ -368,7 +385,7 @@ impl NetWorker {
             }
         }
 
-        if raise_irq && tx_queue.needs_notification(&self.mem).unwrap() {
+        if raise_irq && self.queues[TX_INDEX].needs_notification(&self.mem).unwrap() {
             self.interrupt
                 .try_signal_used_queue()
                 .map_err(TxError::DeviceError)?;
@@ -423,6 +440,7 @@ impl NetWorker {
         queue
             .add_used(&self.mem, head_index, used_len)
             .map_err(FrontendError::QueueError)?;
+        self.interrupt.metrics().rx_bytes.add(used_len as u64);
         result
     }
 
@@ -453,3 +471,39 @@ impl NetWorker {
         Ok(())
     }
 }
+
+impl PoolHandler for NetWorker {
+    fn handle_event(&mut self, source: RawFd, events: EventSet) -> bool {
+        match events {
+            EventSet::IN if source == self.queue_evts[RX_INDEX].as_raw_fd() => {
+                self.process_rx_queue_event();
+            }
+            EventSet::IN if source == self.queue_evts[TX_INDEX].as_raw_fd() => {
+                self.process_tx_queue_event();
+            }
+            _ if source == self.backend.raw_socket_fd() => {
+                if events.contains(EventSet::HANG_UP) || events.contains(EventSet::READ_HANG_UP) {
+                    log::error!("Got {events:?} on backend fd, virtio-net will stop working");
+                    eprintln!("LIBKRUN VIRTIO-NET FATAL: Backend process seems to have quit or crashed! Networking is now disabled!");
+                } else {
+                    if events.contains(EventSet::IN) {
+                        self.process_backend_socket_readable()
+                    }
+
+                    if events.contains(EventSet::OUT) {
+                        self.process_backend_socket_writeable()
+                    }
+                }
+            }
+            EventSet::IN if Some(source) == self.backend.timer_fd() => {
+                self.backend.process_timer();
+                self.process_backend_socket_readable();
+                self.process_backend_socket_writeable();
+            }
+            _ => {
+                log::warn!("Received unknown event: {events:?} from fd: {source:?}");
+            }
+        }
+        true
+    }
+}
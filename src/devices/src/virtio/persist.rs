@@ -0,0 +1,234 @@
+//! Serializable state of the virtio devices, as the building block of
+//! snapshots and migration.
+//!
+//! The state of a device is made of what the transport negotiated with the
+//! driver, its features and the state of its queues, and of whatever state
+//! the device keeps internally, which each device saves in a format of its
+//! own through `VirtioDevice::save_device_state`. Everything is encoded with
+//! bincode, after the version of the format, which must be bumped whenever
+//! the state of any device changes.
+//!
+//! A state is restored to a device of the same type and configuration, before
+//! it's activated, the transport activating it once restored.
+
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+use super::VirtioDevice;
+
+/// Version of the encoding of `StateSnapshot`.
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum PersistError {
+    /// The state was saved by an incompatible version.
+    Version(u32),
+    /// The state can't be encoded or decoded.
+    Encoding(bincode::Error),
+    /// The state is of a device of another type.
+    DeviceType(u32),
+    /// The state has a different number of queues than the device.
+    QueueCount(usize),
+    /// The state has features the device doesn't offer.
+    Features(u64),
+    /// The device is already activated.
+    Activated,
+    /// The internal state of the device is invalid.
+    DeviceState,
+}
+
+impl Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PersistError::*;
+
+        match self {
+            Version(version) => write!(f, "unsupported device state version {version}"),
+            Encoding(e) => write!(f, "failed to encode or decode the device state: {e}"),
+            DeviceType(device_type) => write!(f, "state of a device of type {device_type}"),
+            QueueCount(count) => write!(f, "state of a device with {count} queues"),
+            Features(features) => write!(f, "state with unsupported features {features:#x}"),
+            Activated => write!(f, "device already activated"),
+            DeviceState => write!(f, "invalid internal device state"),
+        }
+    }
+}
+
+/// State of a `Queue`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueState {
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    pub next_avail: u16,
+    pub next_used: u16,
+    pub event_idx_enabled: bool,
+    pub num_added: u16,
+    pub packed: bool,
+    pub avail_wrap_counter: bool,
+    pub used_wrap_counter: bool,
+    pub signalled_used: u16,
+    pub chain_lens: Vec<u16>,
+}
+
+/// State of a virtio device.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub device_type: u32,
+    pub acked_features: u64,
+    pub queues: Vec<QueueState>,
+    /// Internal state of the device, from `VirtioDevice::save_device_state`.
+    pub device: Vec<u8>,
+}
+
+impl StateSnapshot {
+    pub fn encode(&self) -> Result<Vec<u8>, PersistError> {
+        let mut buf = STATE_VERSION.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut buf, self).map_err(PersistError::Encoding)?;
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, PersistError> {
+        let Some((version, state)) = buf.split_first_chunk::<4>() else {
+            return Err(PersistError::Version(0));
+        };
+        let version = u32::from_le_bytes(*version);
+        if version != STATE_VERSION {
+            return Err(PersistError::Version(version));
+        }
+        bincode::deserialize(state).map_err(PersistError::Encoding)
+    }
+}
+
+/// Encodes `state`, the internal state of a device, for
+/// `VirtioDevice::save_device_state`.
+pub fn encode_device_state<T: Serialize>(state: &T) -> Vec<u8> {
+    // Serializing plain data to memory can't fail.
+    bincode::serialize(state).unwrap()
+}
+
+/// Decodes the internal state of a device, for
+/// `VirtioDevice::restore_device_state`.
+pub fn decode_device_state<'a, T: Deserialize<'a>>(state: &'a [u8]) -> Result<T, PersistError> {
+    bincode::deserialize(state).map_err(|_| PersistError::DeviceState)
+}
+
+/// Saving and restoring the state of a device.
+pub trait Persist {
+    fn save(&self) -> StateSnapshot;
+
+    fn restore(&mut self, state: &StateSnapshot) -> Result<(), PersistError>;
+}
+
+impl<T: VirtioDevice + ?Sized> Persist for T {
+    fn save(&self) -> StateSnapshot {
+        StateSnapshot {
+            device_type: self.device_type(),
+            acked_features: self.acked_features(),
+            queues: self.queues().iter().map(|queue| queue.state()).collect(),
+            device: self.save_device_state(),
+        }
+    }
+
+    fn restore(&mut self, state: &StateSnapshot) -> Result<(), PersistError> {
+        if self.is_activated() {
+            return Err(PersistError::Activated);
+        }
+        if state.device_type != self.device_type() {
+            return Err(PersistError::DeviceType(state.device_type));
+        }
+        if state.queues.len() != self.queues().len() {
+            return Err(PersistError::QueueCount(state.queues.len()));
+        }
+        let unsupported = state.acked_features & !self.avail_features();
+        if unsupported != 0 {
+            return Err(PersistError::Features(unsupported));
+        }
+
+        self.restore_device_state(&state.device)?;
+        self.set_acked_features(state.acked_features);
+        for (queue, state) in self.queues_mut().iter_mut().zip(&state.queues) {
+            queue.set_state(state);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(feature = "tee")))]
+mod tests {
+    use super::*;
+
+    use std::num::Wrapping;
+    use std::sync::Arc;
+
+    use vm_memory::GuestAddress;
+
+    use crate::virtio::{Input, InputEventQueue, InputKind};
+
+    fn input() -> Input {
+        Input::new(
+            "input0".into(),
+            InputKind::Keyboard,
+            Arc::new(InputEventQueue::new().unwrap()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_save_restore() {
+        let mut device = input();
+        device.set_acked_features(device.avail_features());
+        device.write_config(0, &[1, 0]);
+        let queue = &mut device.queues_mut()[0];
+        queue.size = 16;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0x1000);
+        queue.next_avail = Wrapping(3);
+
+        let saved = device.save();
+        let state = StateSnapshot::decode(&saved.encode().unwrap()).unwrap();
+        assert_eq!(state, saved);
+
+        let mut restored = input();
+        restored.restore(&state).unwrap();
+        assert_eq!(restored.save(), saved);
+        assert_eq!(restored.queues()[0].desc_table, GuestAddress(0x1000));
+        let mut config = [0u8; 2];
+        restored.read_config(0, &mut config);
+        assert_eq!(config, [1, 0]);
+    }
+
+    #[test]
+    fn test_invalid_state() {
+        let mut state = input().save();
+        let mut buf = state.encode().unwrap();
+        buf[0] += 1;
+        assert!(matches!(
+            StateSnapshot::decode(&buf),
+            Err(PersistError::Version(2))
+        ));
+        assert!(matches!(
+            StateSnapshot::decode(&buf[4..6]),
+            Err(PersistError::Version(_))
+        ));
+
+        state.acked_features |= 1 << 63;
+        assert!(matches!(
+            input().restore(&state),
+            Err(PersistError::Features(_))
+        ));
+        state.queues.pop();
+        assert!(matches!(
+            input().restore(&state),
+            Err(PersistError::QueueCount(1))
+        ));
+        state.device_type += 1;
+        assert!(matches!(
+            input().restore(&state),
+            Err(PersistError::DeviceType(_))
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+//! Adapter for plugins implemented in C, through the `krun_virtio_device_ops`
+//! callback table of libkrun.h, which this must be kept in sync with.
+
+use std::ffi::c_void;
+
+use vm_memory::VolatileSlice;
+
+use super::VirtioPlugin;
+use crate::virtio::Queue;
+
+pub type ReadConfigFn =
+    unsafe extern "C" fn(opaque: *mut c_void, offset: u64, data: *mut u8, len: u32);
+pub type WriteConfigFn =
+    unsafe extern "C" fn(opaque: *mut c_void, offset: u64, data: *const u8, len: u32);
+pub type SetFeaturesFn = unsafe extern "C" fn(opaque: *mut c_void, features: u64);
+pub type ProcessRequestFn = unsafe extern "C" fn(
+    opaque: *mut c_void,
+    queue: u32,
+    readable: *const libc::iovec,
+    num_readable: u32,
+    writable: *const libc::iovec,
+    num_writable: u32,
+) -> i32;
+pub type ResetFn = unsafe extern "C" fn(opaque: *mut c_void);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VirtioDeviceOps {
+    pub device_type: u32,
+    pub num_queues: u16,
+    pub queue_size: u16,
+    pub features: u64,
+    pub read_config: Option<ReadConfigFn>,
+    pub write_config: Option<WriteConfigFn>,
+    pub set_features: Option<SetFeaturesFn>,
+    pub process_request: Option<ProcessRequestFn>,
+    pub reset: Option<ResetFn>,
+}
+
+pub struct CPlugin {
+    ops: VirtioDeviceOps,
+    opaque: *mut c_void,
+}
+
+// SAFETY: the embedder is required to accept calls from any thread, with
+// `opaque` as the only argument shared between them.
+unsafe impl Send for CPlugin {}
+
+fn to_iovecs(slices: &[VolatileSlice]) -> Vec<libc::iovec> {
+    slices
+        .iter()
+        .map(|slice| libc::iovec {
+            iov_base: slice.ptr_guard_mut().as_ptr() as *mut c_void,
+            iov_len: slice.len(),
+        })
+        .collect()
+}
+
+impl CPlugin {
+    /// Checks the callback table provides everything that's required.
+    pub fn new(ops: VirtioDeviceOps, opaque: *mut c_void) -> Option<Self> {
+        if ops.num_queues == 0
+            || !Queue::is_valid_max_size(ops.queue_size)
+            || ops.read_config.is_none()
+            || ops.process_request.is_none()
+        {
+            return None;
+        }
+        Some(Self { ops, opaque })
+    }
+}
+
+impl VirtioPlugin for CPlugin {
+    fn device_type(&self) -> u32 {
+        self.ops.device_type
+    }
+
+    fn queue_sizes(&self) -> Vec<u16> {
+        vec![self.ops.queue_size; self.ops.num_queues as usize]
+    }
+
+    fn avail_features(&self) -> u64 {
+        self.ops.features
+    }
+
+    fn set_acked_features(&mut self, features: u64) {
+        if let Some(set_features) = self.ops.set_features {
+            unsafe { set_features(self.opaque, features) };
+        }
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let read_config = self.ops.read_config.unwrap();
+        unsafe { read_config(self.opaque, offset, data.as_mut_ptr(), data.len() as u32) };
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if let Some(write_config) = self.ops.write_config {
+            unsafe { write_config(self.opaque, offset, data.as_ptr(), data.len() as u32) };
+        }
+    }
+
+    fn process_request(
+        &mut self,
+        queue: usize,
+        readable: &[VolatileSlice],
+        writable: &[VolatileSlice],
+    ) -> u32 {
+        let process_request = self.ops.process_request.unwrap();
+        let readable = to_iovecs(readable);
+        let writable = to_iovecs(writable);
+        let ret = unsafe {
+            process_request(
+                self.opaque,
+                queue as u32,
+                readable.as_ptr(),
+                readable.len() as u32,
+                writable.as_ptr(),
+                writable.len() as u32,
+            )
+        };
+        if ret < 0 {
+            error!("plugin: error processing request: {ret}");
+            return 0;
+        }
+        // Don't trust the plugin to report more than it could write.
+        let max_len: usize = writable.iter().map(|iov| iov.iov_len).sum();
+        (ret as usize).min(max_len) as u32
+    }
+
+    fn reset(&mut self) {
+        if let Some(reset) = self.ops.reset {
+            unsafe { reset(self.opaque) };
+        }
+    }
+}
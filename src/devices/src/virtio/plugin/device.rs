@@ -0,0 +1,265 @@
+use std::sync::{Arc, Mutex};
+
+use utils::eventfd::EventFd;
+use vm_memory::{GuestMemory, GuestMemoryMmap};
+
+use super::super::{ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, VirtioDevice};
+use super::{PluginError, VirtioPlugin};
+use crate::virtio::InterruptTransport;
+
+const VIRTIO_F_VERSION_1: u32 = 32;
+
+pub struct PluginDevice {
+    pub(crate) plugin: Arc<Mutex<dyn VirtioPlugin>>,
+    pub(crate) id: String,
+    pub(crate) device_type: u32,
+    pub(crate) queues: Vec<VirtQueue>,
+    pub(crate) queue_events: Vec<EventFd>,
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    pub(crate) activate_evt: EventFd,
+    pub(crate) device_state: DeviceState,
+}
+
+impl PluginDevice {
+    pub fn new(id: String, plugin: Arc<Mutex<dyn VirtioPlugin>>) -> super::Result<PluginDevice> {
+        let (device_type, queue_sizes, features) = {
+            let plugin = plugin.lock().unwrap();
+            (
+                plugin.device_type(),
+                plugin.queue_sizes(),
+                plugin.avail_features(),
+            )
+        };
+        if queue_sizes.is_empty() {
+            return Err(PluginError::NoQueues);
+        }
+
+        let queues: Vec<VirtQueue> = queue_sizes
+            .iter()
+            .map(|&max_size| VirtQueue::new(max_size))
+            .collect();
+        let mut queue_events = Vec::new();
+        for _ in 0..queues.len() {
+            queue_events
+                .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(PluginError::EventFd)?);
+        }
+
+        Ok(PluginDevice {
+            plugin,
+            id,
+            device_type,
+            queues,
+            queue_events,
+            avail_features: features | (1 << VIRTIO_F_VERSION_1),
+            acked_features: 0,
+            activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
+                .map_err(PluginError::EventFd)?,
+            device_state: DeviceState::Inactive,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn process_queue(&mut self, queue_index: usize) -> bool {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem, _) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut plugin = self.plugin.lock().unwrap();
+        let mut have_used = false;
+
+        while let Some(head) = self.queues[queue_index].pop(mem) {
+            let index = head.index;
+            let mut readable = Vec::new();
+            let mut writable = Vec::new();
+            let mut valid = true;
+            for desc in head.into_iter() {
+                match mem.get_slice(desc.addr, desc.len as usize) {
+                    Ok(slice) if desc.is_write_only() => writable.push(slice),
+                    Ok(slice) => readable.push(slice),
+                    Err(e) => {
+                        error!("{}: invalid descriptor: {e:?}", self.id);
+                        valid = false;
+                        break;
+                    }
+                }
+            }
+
+            let written = if valid {
+                plugin.process_request(queue_index, &readable, &writable)
+            } else {
+                0
+            };
+
+            have_used = true;
+            if let Err(e) = self.queues[queue_index].add_used(mem, index, written) {
+                error!("failed to add used elements to the queue: {e:?}");
+            }
+        }
+
+        have_used
+    }
+}
+
+impl VirtioDevice for PluginDevice {
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+        self.plugin
+            .lock()
+            .unwrap()
+            .set_acked_features(acked_features);
+    }
+
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn device_name(&self) -> &str {
+        "plugin"
+    }
+
+    fn queues(&self) -> &[VirtQueue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [VirtQueue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_events
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.plugin.lock().unwrap().read_config(offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        self.plugin.lock().unwrap().write_config(offset, data);
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
+        if self.activate_evt.write(1).is_err() {
+            error!("Cannot write to activate_evt",);
+            return Err(ActivateError::BadActivate);
+        }
+
+        self.device_state = DeviceState::Activated(mem, interrupt);
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn reset(&mut self) -> bool {
+        // Like for the builtin devices, the queue events stay registered, and
+        // the guest memory won't change, so only the plugin needs to know.
+        self.plugin.lock().unwrap().reset();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{Bytes, GuestAddress, VolatileSlice};
+
+    use crate::legacy::DummyIrqChip;
+    use crate::virtio::queue::tests::VirtQueue as GuestQueue;
+    use crate::virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    /// Copies the request into the response, and remembers its length.
+    #[derive(Default)]
+    struct EchoPlugin {
+        lengths: Vec<usize>,
+    }
+
+    impl VirtioPlugin for EchoPlugin {
+        fn device_type(&self) -> u32 {
+            42
+        }
+
+        fn queue_sizes(&self) -> Vec<u16> {
+            vec![16, 16]
+        }
+
+        fn avail_features(&self) -> u64 {
+            1
+        }
+
+        fn read_config(&self, _offset: u64, data: &mut [u8]) {
+            data.fill(0xaa);
+        }
+
+        fn process_request(
+            &mut self,
+            queue: usize,
+            readable: &[VolatileSlice],
+            writable: &[VolatileSlice],
+        ) -> u32 {
+            assert_eq!(queue, 1);
+            let mut buf = vec![0u8; readable[0].len()];
+            readable[0].copy_to(&mut buf[..]);
+            writable[0].copy_from(&buf);
+            self.lengths.push(buf.len());
+            buf.len() as u32
+        }
+    }
+
+    #[test]
+    fn test_process_queue() {
+        let plugin = Arc::new(Mutex::new(EchoPlugin::default()));
+        let mut device = PluginDevice::new("virtio_plugin0".into(), plugin.clone()).unwrap();
+        assert_eq!(device.device_type(), 42);
+        assert_eq!(device.avail_features(), 1 | (1 << VIRTIO_F_VERSION_1));
+        assert_eq!(device.queues().len(), 2);
+        let mut config = [0u8; 4];
+        device.read_config(0, &mut config);
+        assert_eq!(config, [0xaa; 4]);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = GuestQueue::new(GuestAddress(0), &mem, 16);
+        device.queues[1] = vq.create_queue();
+        device
+            .activate(
+                mem.clone(),
+                InterruptTransport::new(
+                    DummyIrqChip::new().into(),
+                    "plugin".into(),
+                    Arc::default(),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        mem.write_slice(b"ping", GuestAddress(0x1000)).unwrap();
+        vq.dtable[0].set(0x1000, 4, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x2000, 8, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        assert!(device.process_queue(1));
+        assert!(!device.process_queue(1));
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(plugin.lock().unwrap().lengths, [4]);
+        let mut response = [0u8; 4];
+        mem.read_slice(&mut response, GuestAddress(0x2000)).unwrap();
+        assert_eq!(&response, b"ping");
+    }
+}
@@ -0,0 +1,92 @@
+use std::os::unix::io::AsRawFd;
+
+use polly::event_manager::{EventManager, Subscriber};
+use utils::epoll::{EpollEvent, EventSet};
+
+use super::device::PluginDevice;
+use crate::virtio::device::VirtioDevice;
+
+impl PluginDevice {
+    pub(crate) fn handle_queue_event(&mut self, queue_index: usize, event: &EpollEvent) {
+        debug!("{}: queue {queue_index} event", self.id);
+
+        let event_set = event.event_set();
+        if event_set != EventSet::IN {
+            warn!("{}: queue unexpected event {event_set:?}", self.id);
+            return;
+        }
+
+        if let Err(e) = self.queue_events[queue_index].read() {
+            error!("Failed to read queue event: {e:?}");
+        } else if self.process_queue(queue_index) {
+            self.device_state.signal_used_queue();
+        }
+    }
+
+    fn handle_activate_event(&self, event_manager: &mut EventManager) {
+        debug!("{}: activate event", self.id);
+        if let Err(e) = self.activate_evt.read() {
+            error!("Failed to consume {} activate event: {e:?}", self.id);
+        }
+
+        // The subscriber must exist as we previously registered activate_evt via
+        // `interest_list()`.
+        let self_subscriber = event_manager
+            .subscriber(self.activate_evt.as_raw_fd())
+            .unwrap();
+
+        for queue_event in self.queue_events.iter() {
+            event_manager
+                .register(
+                    queue_event.as_raw_fd(),
+                    EpollEvent::new(EventSet::IN, queue_event.as_raw_fd() as u64),
+                    self_subscriber.clone(),
+                )
+                .unwrap_or_else(|e| {
+                    error!(
+                        "Failed to register {} queue with event manager: {e:?}",
+                        self.id
+                    );
+                });
+        }
+
+        event_manager
+            .unregister(self.activate_evt.as_raw_fd())
+            .unwrap_or_else(|e| {
+                error!("Failed to unregister {} activate evt: {e:?}", self.id);
+            })
+    }
+}
+
+impl Subscriber for PluginDevice {
+    fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
+        let source = event.fd();
+        let activate_evt = self.activate_evt.as_raw_fd();
+
+        if self.is_activated() {
+            if source == activate_evt {
+                self.handle_activate_event(event_manager);
+            } else if let Some(queue_index) = self
+                .queue_events
+                .iter()
+                .position(|queue_event| queue_event.as_raw_fd() == source)
+            {
+                self.handle_queue_event(queue_index, event);
+            } else {
+                warn!("Unexpected {} event received: {source:?}", self.id);
+            }
+        } else {
+            warn!(
+                "{}: The device is not yet activated. Spurious event received: {source:?}",
+                self.id
+            );
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(
+            EventSet::IN,
+            self.activate_evt.as_raw_fd() as u64,
+        )]
+    }
+}
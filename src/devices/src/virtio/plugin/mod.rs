@@ -0,0 +1,58 @@
+//! Virtio devices implemented outside of libkrun.
+//!
+//! A plugin only provides the device specific parts: its type, features and
+//! configuration space, and how to handle the requests the guest makes
+//! available in each queue. `PluginDevice` takes care of the virtqueues,
+//! the registration with the event manager and the interrupts, the same way
+//! it's done for the builtin devices.
+
+mod c_ops;
+mod device;
+mod event_handler;
+
+use vm_memory::VolatileSlice;
+
+pub use self::c_ops::{CPlugin, VirtioDeviceOps};
+pub use self::device::PluginDevice;
+
+/// The device specific parts of a virtio device implemented by the embedder.
+pub trait VirtioPlugin: Send {
+    /// The virtio device type, as defined by the specification.
+    fn device_type(&self) -> u32;
+
+    /// The maximum size of each of the queues of the device.
+    fn queue_sizes(&self) -> Vec<u16>;
+
+    /// The device specific features. VIRTIO_F_VERSION_1 is always offered.
+    fn avail_features(&self) -> u64;
+
+    /// Called with the features acknowledged by the driver.
+    fn set_acked_features(&mut self, _features: u64) {}
+
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// Handles a request made available by the driver in `queue`, made of the
+    /// buffers the device can read from and the ones it can write to, in the
+    /// order they were chained. Returns the number of bytes written.
+    fn process_request(
+        &mut self,
+        queue: usize,
+        readable: &[VolatileSlice],
+        writable: &[VolatileSlice],
+    ) -> u32;
+
+    /// Called when the driver resets the device.
+    fn reset(&mut self) {}
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    /// Failed to create event fd.
+    EventFd(std::io::Error),
+    /// The plugin doesn't have any queues.
+    NoQueues,
+}
+
+type Result<T> = std::result::Result<T, PluginError>;
@@ -9,12 +9,20 @@ use std::cmp::min;
 use std::fmt::{self, Debug, Display};
 use std::num::Wrapping;
 use std::sync::atomic::{fence, Ordering};
-use virtio_bindings::virtio_ring::VRING_USED_F_NO_NOTIFY;
+use std::sync::Arc;
+use utils::metrics::DeviceMetrics;
+use virtio_bindings::virtio_ring::{
+    VRING_AVAIL_F_NO_INTERRUPT, VRING_PACKED_DESC_F_AVAIL, VRING_PACKED_DESC_F_USED,
+    VRING_PACKED_EVENT_FLAG_DESC, VRING_PACKED_EVENT_FLAG_DISABLE, VRING_PACKED_EVENT_FLAG_ENABLE,
+    VRING_PACKED_EVENT_F_WRAP_CTR, VRING_USED_F_NO_NOTIFY,
+};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
     VolatileMemoryError,
 };
 
+use super::persist::QueueState;
+
 /// Size of used ring header: flags (u16) + idx (u16)
 pub(crate) const VIRTQ_USED_RING_HEADER_SIZE: u64 = 4;
 
@@ -29,6 +37,18 @@ pub(crate) const VIRTQ_AVAIL_ELEMENT_SIZE: u64 = 2;
 
 pub(super) const VIRTQ_DESC_F_NEXT: u16 = 0x1;
 pub(super) const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+pub(super) const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
+
+/// Size of a descriptor, for both the split and the packed layouts.
+const VIRTQ_DESC_SIZE: u64 = 16;
+
+/// Offset of the `flags` field in a packed descriptor, which the driver
+/// writes last when making a descriptor available.
+const VIRTQ_PACKED_DESC_FLAGS_OFFSET: u64 = 14;
+
+/// Descriptor flags which aren't specific to the packed layout.
+const VIRTQ_PACKED_DESC_F_MASK: u16 =
+    !((1 << VRING_PACKED_DESC_F_AVAIL) | (1 << VRING_PACKED_DESC_F_USED));
 
 /// Virtio Queue related errors.
 #[allow(clippy::enum_variant_names)]
@@ -192,12 +212,29 @@ pub struct Descriptor {
 
 unsafe impl ByteValued for Descriptor {}
 
+/// A descriptor from a packed virtqueue (`struct pvirtq_desc`).
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct PackedDescriptor {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: u16,
+}
+
+unsafe impl ByteValued for PackedDescriptor {}
+
 /// A virtio descriptor chain.
 #[derive(Clone)]
 pub struct DescriptorChain<'a> {
     desc_table: GuestAddress,
     queue_size: u16,
     ttl: u16, // used to prevent infinite chain cycles
+    packed: bool,
+    /// The descriptor lives in an indirect table, in which case `desc_table`
+    /// and `queue_size` describe that table, and `index` stays the index of
+    /// the head of the chain in the queue.
+    indirect: bool,
 
     /// Reference to guest memory
     pub mem: &'a GuestMemoryMmap,
@@ -247,6 +284,8 @@ impl<'a> DescriptorChain<'a> {
             desc_table,
             queue_size,
             ttl: queue_size,
+            packed: false,
+            indirect: false,
             index,
             addr: GuestAddress(desc.addr),
             len: desc.len,
@@ -261,6 +300,130 @@ impl<'a> DescriptorChain<'a> {
         }
     }
 
+    /// Reads the descriptor at position `pos` of a packed ring. In that
+    /// layout, the descriptors of a chain are laid out one after the other
+    /// and all of them are identified by the buffer `id` of the chain, so the
+    /// caller must have walked the chain beforehand and pass its `id` and the
+    /// number of descriptors left in it as `ttl`.
+    fn checked_new_packed(
+        mem: &'a GuestMemoryMmap,
+        desc_ring: GuestAddress,
+        queue_size: u16,
+        pos: u16,
+        id: u16,
+        ttl: u16,
+    ) -> Option<DescriptorChain<'a>> {
+        if pos >= queue_size || id >= queue_size {
+            return None;
+        }
+
+        let desc_addr = mem.checked_offset(desc_ring, (pos as usize) * 16)?;
+        mem.checked_offset(desc_addr, 16)?;
+
+        let desc = match mem.read_obj::<PackedDescriptor>(desc_addr) {
+            Ok(ret) => ret,
+            Err(_) => {
+                error!("Failed to read from memory");
+                return None;
+            }
+        };
+        Some(DescriptorChain {
+            mem,
+            desc_table: desc_ring,
+            queue_size,
+            ttl,
+            packed: true,
+            indirect: false,
+            index: id,
+            addr: GuestAddress(desc.addr),
+            len: desc.len,
+            flags: desc.flags & VIRTQ_PACKED_DESC_F_MASK,
+            next: (pos + 1) % queue_size,
+        })
+    }
+
+    /// If this descriptor refers to an indirect descriptor table, returns the
+    /// chain held in that table instead. Otherwise, returns the descriptor
+    /// unchanged.
+    fn resolve_indirect(self) -> Option<DescriptorChain<'a>> {
+        if self.flags & VIRTQ_DESC_F_INDIRECT == 0 {
+            return Some(self);
+        }
+
+        let len = u64::from(self.len);
+        if self.flags & VIRTQ_DESC_F_NEXT != 0
+            || len == 0
+            || len % VIRTQ_DESC_SIZE != 0
+            || len / VIRTQ_DESC_SIZE > u64::from(u16::MAX)
+        {
+            error!("Invalid indirect descriptor");
+            return None;
+        }
+
+        let table_size = (len / VIRTQ_DESC_SIZE) as u16;
+        self.checked_new_indirect(self.addr, table_size, 0, table_size)
+    }
+
+    /// Reads the descriptor at position `pos` of the indirect table at
+    /// `table`, which holds `table_size` descriptors.
+    fn checked_new_indirect(
+        &self,
+        table: GuestAddress,
+        table_size: u16,
+        pos: u16,
+        ttl: u16,
+    ) -> Option<DescriptorChain<'a>> {
+        if pos >= table_size {
+            return None;
+        }
+
+        let desc_addr = self.mem.checked_offset(table, (pos as usize) * 16)?;
+        self.mem.checked_offset(desc_addr, 16)?;
+
+        let desc = if self.packed {
+            // Packed indirect tables hold the chain in order and without
+            // using the NEXT flag.
+            let desc = self.mem.read_obj::<PackedDescriptor>(desc_addr).ok()?;
+            let mut flags = desc.flags & VIRTQ_PACKED_DESC_F_MASK & !VIRTQ_DESC_F_NEXT;
+            if ttl > 1 {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            Descriptor {
+                addr: desc.addr,
+                len: desc.len,
+                flags,
+                next: pos.wrapping_add(1),
+            }
+        } else {
+            self.mem.read_obj::<Descriptor>(desc_addr).ok()?
+        };
+
+        if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+            error!("Nested indirect descriptor");
+            return None;
+        }
+
+        let chain = DescriptorChain {
+            mem: self.mem,
+            desc_table: table,
+            queue_size: table_size,
+            ttl,
+            packed: self.packed,
+            indirect: true,
+            index: self.index,
+            addr: GuestAddress(desc.addr),
+            len: desc.len,
+            flags: desc.flags,
+            next: desc.next,
+        };
+
+        if chain.is_valid() {
+            Some(chain)
+        } else {
+            None
+        }
+    }
+
     fn is_valid(&self) -> bool {
         !self.has_next() || self.next < self.queue_size
     }
@@ -291,6 +454,29 @@ impl<'a> DescriptorChain<'a> {
     /// Note that this is distinct from the next descriptor chain returned by `AvailIter`, which is
     /// the head of the next _available_ descriptor chain.
     pub fn next_descriptor(&self) -> Option<DescriptorChain<'a>> {
+        if self.indirect {
+            return if self.has_next() {
+                self.checked_new_indirect(self.desc_table, self.queue_size, self.next, self.ttl - 1)
+            } else {
+                None
+            };
+        }
+
+        if self.packed {
+            return if self.has_next() {
+                DescriptorChain::checked_new_packed(
+                    self.mem,
+                    self.desc_table,
+                    self.queue_size,
+                    self.next,
+                    self.index,
+                    self.ttl - 1,
+                )
+            } else {
+                None
+            };
+        }
+
         if self.has_next() {
             DescriptorChain::checked_new(self.mem, self.desc_table, self.queue_size, self.next).map(
                 |mut c| {
@@ -317,8 +503,81 @@ impl<'a> DescriptorChain<'a> {
             next: self.next,
         }
     }
+
+    /// Detaches the chain from the guest memory, so another thread can walk
+    /// it with its own reference to the memory.
+    pub fn detach(&self) -> DetachedChain {
+        DetachedChain {
+            desc_table: self.desc_table,
+            queue_size: self.queue_size,
+            ttl: self.ttl,
+            packed: self.packed,
+            indirect: self.indirect,
+            index: self.index,
+            addr: self.addr,
+            len: self.len,
+            flags: self.flags,
+            next: self.next,
+        }
+    }
+}
+
+/// A descriptor chain detached from the guest memory.
+#[derive(Clone, Debug)]
+pub struct DetachedChain {
+    desc_table: GuestAddress,
+    queue_size: u16,
+    ttl: u16,
+    packed: bool,
+    indirect: bool,
+    index: u16,
+    addr: GuestAddress,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+impl DetachedChain {
+    /// Index into the descriptor table of the head of the chain.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Attaches the chain to `mem`, the memory it was detached from.
+    pub fn attach(self, mem: &GuestMemoryMmap) -> DescriptorChain<'_> {
+        DescriptorChain {
+            desc_table: self.desc_table,
+            queue_size: self.queue_size,
+            ttl: self.ttl,
+            packed: self.packed,
+            indirect: self.indirect,
+            mem,
+            index: self.index,
+            addr: self.addr,
+            len: self.len,
+            flags: self.flags,
+            next: self.next,
+        }
+    }
 }
 
+/// Activity counters of the device owning a queue. Two queues compare equal
+/// if they report to the same counters.
+#[derive(Clone, Debug, Default)]
+struct QueueMetrics(Option<Arc<DeviceMetrics>>);
+
+impl PartialEq for QueueMetrics {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for QueueMetrics {}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 /// A virtio queue's parameters.
 pub struct Queue {
@@ -349,9 +608,37 @@ pub struct Queue {
     /// The number of descriptor chains placed in the used ring via `add_used`
     /// since the last time `needs_notification` was called on the associated queue.
     num_added: Wrapping<u16>,
+
+    /// VIRTIO_F_RING_PACKED negotiated. With the packed layout, `desc_table`
+    /// points to the descriptor ring, `avail_ring` to the driver event
+    /// suppression area and `used_ring` to the device event suppression area,
+    /// while `next_avail` and `next_used` are positions in the descriptor ring.
+    packed: bool,
+    avail_wrap_counter: bool,
+    used_wrap_counter: bool,
+    /// Position and wrap counter before the last `pop`, for `undo_pop`.
+    last_avail: (Wrapping<u16>, bool),
+    /// Position in the descriptor ring the last time the driver was notified.
+    signalled_used: u16,
+    /// Number of descriptors of each in-flight chain, indexed by buffer id.
+    chain_lens: Vec<u16>,
+
+    metrics: QueueMetrics,
 }
 
 impl Queue {
+    /// Largest queue size allowed by the virtio spec.
+    pub const MAX_SIZE: u16 = 32768;
+    /// Smallest queue size that can be configured, leaving room in block
+    /// requests for a few data segments.
+    pub const MIN_SIZE: u16 = 8;
+
+    /// Returns whether `size` can be the maximum size of a queue, which must
+    /// be a power of two for split queues.
+    pub fn is_valid_max_size(size: u16) -> bool {
+        size.is_power_of_two() && (Self::MIN_SIZE..=Self::MAX_SIZE).contains(&size)
+    }
+
     /// Constructs an empty virtio queue with the given `max_size`.
     pub fn new(max_size: u16) -> Queue {
         Queue {
@@ -365,6 +652,82 @@ impl Queue {
             next_used: Wrapping(0),
             event_idx_enabled: false,
             num_added: Wrapping(0),
+            packed: false,
+            avail_wrap_counter: true,
+            used_wrap_counter: true,
+            last_avail: (Wrapping(0), true),
+            signalled_used: 0,
+            chain_lens: Vec::new(),
+            metrics: QueueMetrics::default(),
+        }
+    }
+
+    /// Selects the packed (VIRTIO_F_RING_PACKED) or the split layout. Must be
+    /// called before the queue is used.
+    pub fn set_packed(&mut self, packed: bool) {
+        self.packed = packed;
+        self.chain_lens = if packed {
+            vec![1; self.max_size as usize]
+        } else {
+            Vec::new()
+        };
+    }
+
+    /// Sets the counters updated as descriptor chains are used.
+    pub fn set_metrics(&mut self, metrics: Arc<DeviceMetrics>) {
+        self.metrics = QueueMetrics(Some(metrics));
+    }
+
+    /// Returns the state of the queue, to be saved.
+    pub fn state(&self) -> QueueState {
+        QueueState {
+            max_size: self.max_size,
+            size: self.size,
+            ready: self.ready,
+            desc_table: self.desc_table.0,
+            avail_ring: self.avail_ring.0,
+            used_ring: self.used_ring.0,
+            next_avail: self.next_avail.0,
+            next_used: self.next_used.0,
+            event_idx_enabled: self.event_idx_enabled,
+            num_added: self.num_added.0,
+            packed: self.packed,
+            avail_wrap_counter: self.avail_wrap_counter,
+            used_wrap_counter: self.used_wrap_counter,
+            signalled_used: self.signalled_used,
+            chain_lens: self.chain_lens.clone(),
+        }
+    }
+
+    /// Restores a state returned by `state`. The metrics are kept.
+    pub fn set_state(&mut self, state: &QueueState) {
+        self.max_size = state.max_size;
+        self.size = state.size;
+        self.ready = state.ready;
+        self.desc_table = GuestAddress(state.desc_table);
+        self.avail_ring = GuestAddress(state.avail_ring);
+        self.used_ring = GuestAddress(state.used_ring);
+        self.next_avail = Wrapping(state.next_avail);
+        self.next_used = Wrapping(state.next_used);
+        self.event_idx_enabled = state.event_idx_enabled;
+        self.num_added = Wrapping(state.num_added);
+        self.packed = state.packed;
+        self.avail_wrap_counter = state.avail_wrap_counter;
+        self.used_wrap_counter = state.used_wrap_counter;
+        self.last_avail = (self.next_avail, self.avail_wrap_counter);
+        self.signalled_used = state.signalled_used;
+        self.chain_lens = state.chain_lens.clone();
+        if self.packed {
+            // Buffer ids index it, don't trust the state to be large enough.
+            self.chain_lens.resize(self.max_size as usize, 1);
+        }
+    }
+
+    /// Records that the device couldn't hand data over to the guest because
+    /// the driver hasn't made any buffers available.
+    pub fn report_full(&self) {
+        if let Some(metrics) = &self.metrics.0 {
+            metrics.queue_full.inc();
         }
     }
 
@@ -372,6 +735,11 @@ impl Queue {
         self.max_size
     }
 
+    /// Changes the maximum size of the queue, before the driver sets it up.
+    pub fn set_max_size(&mut self, max_size: u16) {
+        self.max_size = max_size;
+    }
+
     /// Return the actual size of the queue, as the driver may not set up a
     /// queue as big as the device allows.
     pub fn actual_size(&self) -> u16 {
@@ -379,6 +747,10 @@ impl Queue {
     }
 
     pub fn is_valid(&self, mem: &GuestMemoryMmap) -> bool {
+        if self.packed {
+            return self.is_valid_packed(mem);
+        }
+
         let queue_size = u64::from(self.actual_size());
         let desc_table = self.desc_table;
         let desc_table_size = 16 * queue_size;
@@ -437,12 +809,88 @@ impl Queue {
         }
     }
 
+    fn is_valid_packed(&self, mem: &GuestMemoryMmap) -> bool {
+        let desc_ring_size = VIRTQ_DESC_SIZE * u64::from(self.actual_size());
+        let in_range = |addr: GuestAddress, size: u64| {
+            addr.checked_add(size)
+                .is_some_and(|v| mem.address_in_range(v))
+        };
+        if !self.ready {
+            error!("attempt to use virtio queue that is not marked ready");
+            false
+        } else if self.size > self.max_size || self.size == 0 {
+            error!("virtio queue with invalid size: {}", self.size);
+            false
+        } else if !in_range(self.desc_table, desc_ring_size) {
+            error!(
+                "virtio queue descriptor ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
+                self.desc_table.raw_value(),
+                desc_ring_size
+            );
+            false
+        } else if !in_range(self.avail_ring, 4) || !in_range(self.used_ring, 4) {
+            error!("virtio queue event suppression areas go out of bounds");
+            false
+        } else if self.desc_table.raw_value() & 0xf != 0 {
+            error!("virtio queue descriptor ring breaks alignment contraints");
+            false
+        } else if self.avail_ring.raw_value() & 0x3 != 0 || self.used_ring.raw_value() & 0x3 != 0 {
+            error!("virtio queue event suppression areas break alignment contraints");
+            false
+        } else {
+            true
+        }
+    }
+
     /// Returns the number of yet-to-be-popped descriptor chains in the avail ring.
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self, mem: &GuestMemoryMmap) -> u16 {
+        if self.packed {
+            return self.len_packed(mem);
+        }
+
         (self.avail_idx(mem, Ordering::Acquire).unwrap() - self.next_avail).0
     }
 
+    // The packed layout doesn't keep an index of available descriptors, so
+    // the chains have to be walked to count them.
+    fn len_packed(&self, mem: &GuestMemoryMmap) -> u16 {
+        let size = self.actual_size();
+        let mut pos = self.next_avail.0;
+        let mut wrap = self.avail_wrap_counter;
+        let mut chains = 0;
+        let mut descs = 0;
+        while descs < size && self.is_packed_desc_avail(mem, pos, wrap) {
+            let flags = self.packed_desc_flags(mem, pos);
+            descs += 1;
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                chains += 1;
+            }
+            pos += 1;
+            if pos >= size {
+                pos = 0;
+                wrap = !wrap;
+            }
+        }
+        chains
+    }
+
+    fn packed_desc_flags(&self, mem: &GuestMemoryMmap, pos: u16) -> u16 {
+        self.desc_table
+            .checked_add(u64::from(pos) * VIRTQ_DESC_SIZE + VIRTQ_PACKED_DESC_FLAGS_OFFSET)
+            .and_then(|addr| mem.load(addr, Ordering::Acquire).ok())
+            .unwrap_or(0)
+    }
+
+    // A packed descriptor is available when its AVAIL flag matches the wrap
+    // counter of the driver and its USED flag doesn't.
+    fn is_packed_desc_avail(&self, mem: &GuestMemoryMmap, pos: u16, wrap: bool) -> bool {
+        let flags = self.packed_desc_flags(mem, pos);
+        let avail = flags & (1 << VRING_PACKED_DESC_F_AVAIL) != 0;
+        let used = flags & (1 << VRING_PACKED_DESC_F_USED) != 0;
+        avail == wrap && used != wrap
+    }
+
     /// Checks if the driver has made any descriptor chains available in the avail ring.
     pub fn is_empty(&self, mem: &GuestMemoryMmap) -> bool {
         self.len(mem) == 0
@@ -450,6 +898,10 @@ impl Queue {
 
     /// Pop the first available descriptor chain from the avail ring.
     pub fn pop<'b>(&mut self, mem: &'b GuestMemoryMmap) -> Option<DescriptorChain<'b>> {
+        if self.packed {
+            return self.pop_packed(mem);
+        }
+
         if self.len(mem) == 0 || self.actual_size() == 0 {
             return None;
         }
@@ -490,12 +942,70 @@ impl Queue {
             .unwrap();
 
         DescriptorChain::checked_new(mem, self.desc_table, self.actual_size(), desc_index)
+            .and_then(DescriptorChain::resolve_indirect)
             .inspect(|_| self.next_avail += Wrapping(1))
     }
 
+    fn pop_packed<'b>(&mut self, mem: &'b GuestMemoryMmap) -> Option<DescriptorChain<'b>> {
+        let size = self.actual_size();
+        if size == 0 || !self.is_packed_desc_avail(mem, self.next_avail.0, self.avail_wrap_counter)
+        {
+            return None;
+        }
+
+        // Make sure we don't read the rest of the descriptors before their flags.
+        fence(Ordering::Acquire);
+
+        // Walk the chain to find its length and its buffer id, which is only
+        // set in the last descriptor.
+        let head = self.next_avail.0;
+        let mut pos = head;
+        let mut wrap = self.avail_wrap_counter;
+        let mut len = 0;
+        let id = loop {
+            let desc: PackedDescriptor = match self
+                .desc_table
+                .checked_add(u64::from(pos) * VIRTQ_DESC_SIZE)
+                .map(|addr| mem.read_obj(addr))
+            {
+                Some(Ok(desc)) => desc,
+                _ => {
+                    error!("Failed to read packed descriptor at position {pos}");
+                    return None;
+                }
+            };
+            len += 1;
+            pos += 1;
+            if pos >= size {
+                pos = 0;
+                wrap = !wrap;
+            }
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break desc.id;
+            }
+            if len >= size {
+                error!("packed descriptor chain longer than the queue");
+                return None;
+            }
+        };
+
+        let chain = DescriptorChain::checked_new_packed(mem, self.desc_table, size, head, id, len)?
+            .resolve_indirect()?;
+        self.last_avail = (self.next_avail, self.avail_wrap_counter);
+        self.next_avail = Wrapping(pos);
+        self.avail_wrap_counter = wrap;
+        self.chain_lens[id as usize] = len;
+        Some(chain)
+    }
+
     /// Undo the effects of the last `self.pop()` call.
     /// The caller can use this, if it was unable to consume the last popped descriptor chain.
     pub fn undo_pop(&mut self) {
+        if self.packed {
+            (self.next_avail, self.avail_wrap_counter) = self.last_avail;
+            return;
+        }
+
         self.next_avail -= Wrapping(1);
     }
 
@@ -510,6 +1020,10 @@ impl Queue {
             return Err(Error::InvalidDescriptorIndex);
         }
 
+        if self.packed {
+            return self.add_used_packed(mem, head_index, len);
+        }
+
         let next_used_index = u64::from(self.next_used.0 % self.size);
         // This can not overflow an u64 since it is working with relatively small numbers compared
         // to u64::MAX.
@@ -523,6 +1037,9 @@ impl Queue {
 
         self.next_used += Wrapping(1);
         self.num_added += Wrapping(1);
+        if let Some(metrics) = &self.metrics.0 {
+            metrics.descriptors.inc();
+        }
 
         mem.store(
             self.next_used.0,
@@ -534,6 +1051,51 @@ impl Queue {
         .map_err(Error::GuestMemory)
     }
 
+    fn add_used_packed(&mut self, mem: &GuestMemoryMmap, id: u16, len: u32) -> Result<(), Error> {
+        let addr = self
+            .desc_table
+            .checked_add(u64::from(self.next_used.0) * VIRTQ_DESC_SIZE)
+            .ok_or(Error::AddressOverflow)?;
+        let desc = PackedDescriptor {
+            addr: 0,
+            len,
+            id,
+            flags: 0,
+        };
+        // Write everything except the flags, which hand the descriptor over
+        // to the driver and must be written last.
+        mem.write_slice(
+            &desc.as_slice()[..VIRTQ_PACKED_DESC_FLAGS_OFFSET as usize],
+            addr,
+        )
+        .map_err(Error::GuestMemory)?;
+
+        let mut flags = if len > 0 { VIRTQ_DESC_F_WRITE } else { 0 };
+        if self.used_wrap_counter {
+            flags |= (1 << VRING_PACKED_DESC_F_AVAIL) | (1 << VRING_PACKED_DESC_F_USED);
+        }
+        mem.store(
+            flags,
+            addr.checked_add(VIRTQ_PACKED_DESC_FLAGS_OFFSET)
+                .ok_or(Error::AddressOverflow)?,
+            Ordering::Release,
+        )
+        .map_err(Error::GuestMemory)?;
+
+        // The device skips as many descriptors as the chain had.
+        let mut next_used = self.next_used.0 + self.chain_lens[id as usize];
+        if next_used >= self.actual_size() {
+            next_used -= self.actual_size();
+            self.used_wrap_counter = !self.used_wrap_counter;
+        }
+        self.next_used = Wrapping(next_used);
+        self.num_added += Wrapping(1);
+        if let Some(metrics) = &self.metrics.0 {
+            metrics.descriptors.inc();
+        }
+        Ok(())
+    }
+
     // Return the value present in the used_event field of the avail ring.
     //
     // If the VIRTIO_F_EVENT_IDX feature bit is not negotiated, the flags field in the available
@@ -599,6 +1161,10 @@ impl Queue {
     // Every access in this method uses `Relaxed` ordering because a fence is added by the caller
     // when appropriate.
     fn set_notification(&mut self, mem: &GuestMemoryMmap, enable: bool) -> Result<(), Error> {
+        if self.packed {
+            return self.set_notification_packed(mem, enable);
+        }
+
         if enable {
             if self.event_idx_enabled {
                 // We call `set_avail_event` using the `next_avail` value, instead of reading
@@ -617,6 +1183,40 @@ impl Queue {
         }
     }
 
+    // With the packed layout, the device event suppression area tells the
+    // driver whether it has to notify us.
+    fn set_notification_packed(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        enable: bool,
+    ) -> Result<(), Error> {
+        let flags = if !enable {
+            if self.event_idx_enabled {
+                // As with the split layout, the descriptor based suppression
+                // only triggers once.
+                return Ok(());
+            }
+            VRING_PACKED_EVENT_FLAG_DISABLE
+        } else if self.event_idx_enabled {
+            let off_wrap = self.next_avail.0
+                | (u16::from(self.avail_wrap_counter) << VRING_PACKED_EVENT_F_WRAP_CTR);
+            mem.store(off_wrap, self.used_ring, Ordering::Relaxed)
+                .map_err(Error::GuestMemory)?;
+            VRING_PACKED_EVENT_FLAG_DESC
+        } else {
+            VRING_PACKED_EVENT_FLAG_ENABLE
+        };
+
+        mem.store(
+            flags as u16,
+            self.used_ring
+                .checked_add(2)
+                .ok_or(Error::AddressOverflow)?,
+            Ordering::Relaxed,
+        )
+        .map_err(Error::GuestMemory)
+    }
+
     // TODO: Turn this into a doc comment/example.
     // With the current implementation, a common way of consuming entries from the available ring
     // while also leveraging notification suppression is to use a loop, for example:
@@ -648,6 +1248,10 @@ impl Queue {
         // entries. There are situations where we intentionally avoid processing everything in the
         // available ring (which will cause this method to return `true`), but in that case we'll
         // probably not re-enable notifications as we already know there are pending entries.
+        if self.packed {
+            return Ok(self.is_packed_desc_avail(mem, self.next_avail.0, self.avail_wrap_counter));
+        }
+
         self.avail_idx(mem, Ordering::Relaxed)
             .map(|idx| idx != self.next_avail)
     }
@@ -662,7 +1266,9 @@ impl Queue {
         // Complete all the writes in add_used() before reading the event.
         fence(Ordering::SeqCst);
 
-        // The VRING_AVAIL_F_NO_INTERRUPT flag isn't supported yet.
+        if self.packed {
+            return self.needs_notification_packed(mem);
+        }
 
         // When the `EVENT_IDX` feature is negotiated, the driver writes into `used_event`
         // a value that's used by the device to determine whether a notification must
@@ -686,14 +1292,52 @@ impl Queue {
             return Ok(used_idx - used_event - Wrapping(1) < used_idx - old);
         }
 
-        Ok(true)
+        // Without `EVENT_IDX`, the driver can only ask us to refrain from interrupting it
+        // altogether through the `flags` field of the avail ring.
+        let flags: u16 = mem
+            .load(self.avail_ring, Ordering::Relaxed)
+            .map_err(Error::GuestMemory)?;
+        Ok(flags & VRING_AVAIL_F_NO_INTERRUPT as u16 == 0)
+    }
+
+    // With the packed layout, the driver event suppression area tells whether
+    // the driver wants to be notified, and with VIRTIO_F_EVENT_IDX after
+    // which descriptor.
+    fn needs_notification_packed(&mut self, mem: &GuestMemoryMmap) -> Result<bool, Error> {
+        let old = Wrapping(self.signalled_used);
+        let new = Wrapping(self.next_used.0);
+        self.signalled_used = new.0;
+        self.num_added = Wrapping(0);
+
+        let flags: u16 = mem
+            .load(
+                self.avail_ring
+                    .checked_add(2)
+                    .ok_or(Error::AddressOverflow)?,
+                Ordering::Relaxed,
+            )
+            .map_err(Error::GuestMemory)?;
+        match u32::from(flags) {
+            VRING_PACKED_EVENT_FLAG_DISABLE => return Ok(false),
+            VRING_PACKED_EVENT_FLAG_DESC if self.event_idx_enabled => {}
+            _ => return Ok(true),
+        }
+
+        let off_wrap: u16 = mem
+            .load(self.avail_ring, Ordering::Relaxed)
+            .map_err(Error::GuestMemory)?;
+        let mut event = Wrapping(off_wrap & !(1 << VRING_PACKED_EVENT_F_WRAP_CTR));
+        if (off_wrap >> VRING_PACKED_EVENT_F_WRAP_CTR != 0) != self.used_wrap_counter {
+            event -= Wrapping(self.actual_size());
+        }
+        Ok(new - event - Wrapping(1) < new - old)
     }
 
     /// Goes back one position in the available descriptor chain offered by the driver.
     /// Rust does not support bidirectional iterators. This is the only way to revert the effect
     /// of an iterator increment on the queue.
     pub fn go_to_previous_position(&mut self) {
-        self.next_avail -= Wrapping(1);
+        self.undo_pop();
     }
 
     /// Fetch the available ring index (`virtq_avail->idx`) from guest memory.
@@ -998,6 +1642,13 @@ pub(crate) mod tests {
             assert_eq!(c.next, 1);
 
             assert!(c.next_descriptor().unwrap().next_descriptor().is_none());
+
+            // A detached chain walks the same descriptors once attached again.
+            let detached = c.detach();
+            assert_eq!(detached.index(), 0);
+            let c = detached.attach(m);
+            assert_eq!(c.addr, GuestAddress(0x1000));
+            assert_eq!(c.next_descriptor().unwrap().addr, GuestAddress(0x2000));
         }
     }
 
@@ -1139,4 +1790,185 @@ pub(crate) mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_indirect_descriptors() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+
+        // An indirect table of three descriptors, chained out of order.
+        let table = GuestAddress(0x8000);
+        let descs = [
+            (0x1000, VIRTQ_DESC_F_NEXT, 2),
+            (0x3000, VIRTQ_DESC_F_WRITE, 0),
+            (0x2000, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 1),
+        ];
+        for (i, (addr, flags, next)) in descs.into_iter().enumerate() {
+            let desc = Descriptor {
+                addr,
+                len: 0x100,
+                flags,
+                next,
+            };
+            m.write_obj(desc, table.unchecked_add(i as u64 * VIRTQ_DESC_SIZE))
+                .unwrap();
+        }
+
+        vq.dtable[5].set(
+            table.0,
+            3 * VIRTQ_DESC_SIZE as u32,
+            VIRTQ_DESC_F_INDIRECT,
+            0,
+        );
+        vq.avail.ring[0].set(5);
+        vq.avail.idx.set(1);
+
+        let c = q.pop(m).unwrap();
+        let descs: Vec<_> = c.into_iter().map(|d| (d.index, d.addr.0)).collect();
+        assert_eq!(descs, vec![(5, 0x1000), (5, 0x2000), (5, 0x3000)]);
+
+        // Tables whose size isn't a multiple of a descriptor are rejected.
+        vq.dtable[6].set(table.0, 0x18, VIRTQ_DESC_F_INDIRECT, 0);
+        vq.avail.ring[1].set(6);
+        vq.avail.idx.set(2);
+        assert!(q.pop(m).is_none());
+
+        // With the packed layout, the table holds the chain in order.
+        let mut q = Queue::new(4);
+        q.size = 4;
+        q.ready = true;
+        q.desc_table = GuestAddress(0);
+        q.avail_ring = GuestAddress(0x100);
+        q.used_ring = GuestAddress(0x110);
+        q.set_packed(true);
+        for i in 0..2u64 {
+            let desc = PackedDescriptor {
+                addr: 0x1000 * (i + 1),
+                len: 0x100,
+                id: 0,
+                flags: 0,
+            };
+            m.write_obj(desc, table.unchecked_add(i * VIRTQ_DESC_SIZE))
+                .unwrap();
+        }
+        let desc = PackedDescriptor {
+            addr: table.0,
+            len: 2 * VIRTQ_DESC_SIZE as u32,
+            id: 3,
+            flags: VIRTQ_DESC_F_INDIRECT | (1 << VRING_PACKED_DESC_F_AVAIL),
+        };
+        m.write_obj(desc, GuestAddress(0)).unwrap();
+
+        let c = q.pop(m).unwrap();
+        let descs: Vec<_> = c.into_iter().map(|d| (d.index, d.addr.0)).collect();
+        assert_eq!(descs, vec![(3, 0x1000), (3, 0x2000)]);
+        q.add_used(m, 3, 0).unwrap();
+        assert_eq!(q.next_used.0, 1);
+    }
+
+    #[test]
+    fn test_needs_notification() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+
+        q.add_used(m, 1, 0x1000).unwrap();
+        assert!(q.needs_notification(m).unwrap());
+        vq.avail.flags.set(VRING_AVAIL_F_NO_INTERRUPT as u16);
+        assert!(!q.needs_notification(m).unwrap());
+
+        // With EVENT_IDX the flags are ignored, and the driver is only
+        // interrupted once `used_event` has been crossed.
+        q.set_event_idx(true);
+        vq.avail.event.set(2);
+        q.add_used(m, 2, 0x1000).unwrap();
+        assert!(!q.needs_notification(m).unwrap());
+        q.add_used(m, 3, 0x1000).unwrap();
+        q.add_used(m, 4, 0x1000).unwrap();
+        assert!(q.needs_notification(m).unwrap());
+        q.add_used(m, 5, 0x1000).unwrap();
+        assert!(!q.needs_notification(m).unwrap());
+    }
+
+    #[test]
+    fn test_packed_queue_processing() {
+        const AVAIL: u16 = 1 << VRING_PACKED_DESC_F_AVAIL;
+        const USED: u16 = 1 << VRING_PACKED_DESC_F_USED;
+
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let set_desc = |pos: u64, id: u16, flags: u16| {
+            let desc = PackedDescriptor {
+                addr: 0x1000 * (pos + 1),
+                len: 0x1000,
+                id,
+                flags,
+            };
+            m.write_obj(desc, GuestAddress(pos * VIRTQ_DESC_SIZE))
+                .unwrap();
+        };
+        let get_desc = |pos: u64| -> PackedDescriptor {
+            m.read_obj(GuestAddress(pos * VIRTQ_DESC_SIZE)).unwrap()
+        };
+
+        let mut q = Queue::new(4);
+        q.size = 4;
+        q.ready = true;
+        q.desc_table = GuestAddress(0);
+        q.avail_ring = GuestAddress(0x100);
+        q.used_ring = GuestAddress(0x110);
+        q.set_packed(true);
+        assert!(q.is_valid(m));
+
+        // The chains are (0, 1) with buffer id 3, and (2) with buffer id 0.
+        set_desc(0, 0, AVAIL | VIRTQ_DESC_F_NEXT);
+        set_desc(1, 3, AVAIL | VIRTQ_DESC_F_WRITE);
+        set_desc(2, 0, AVAIL);
+        assert_eq!(q.len(m), 2);
+
+        let d = q.pop(m).unwrap();
+        assert_eq!(d.index, 3);
+        assert_eq!(d.addr, GuestAddress(0x1000));
+        assert_eq!(d.flags, VIRTQ_DESC_F_NEXT);
+        let d = d.next_descriptor().unwrap();
+        assert_eq!(d.index, 3);
+        assert!(d.is_write_only());
+        assert!(d.next_descriptor().is_none());
+
+        assert_eq!(q.pop(m).unwrap().index, 0);
+        assert!(q.pop(m).is_none());
+        q.undo_pop();
+        assert_eq!(q.len(m), 1);
+        assert_eq!(q.pop(m).unwrap().index, 0);
+
+        // Used descriptors are written in place, skipping the length of the chain.
+        q.add_used(m, 3, 0x10).unwrap();
+        let d = get_desc(0);
+        assert_eq!((d.id, d.len), (3, 0x10));
+        assert_eq!(d.flags, AVAIL | USED | VIRTQ_DESC_F_WRITE);
+        q.add_used(m, 0, 0).unwrap();
+        assert_eq!(get_desc(2).flags, AVAIL | USED);
+
+        // A chain wrapping around the end of the ring, whose second
+        // descriptor is made available with the wrap counter flipped.
+        set_desc(3, 0, AVAIL | VIRTQ_DESC_F_NEXT);
+        set_desc(0, 1, USED);
+        let d = q.pop(m).unwrap();
+        assert_eq!(d.index, 1);
+        assert_eq!(d.next_descriptor().unwrap().addr, GuestAddress(0x1000));
+        assert!(q.pop(m).is_none());
+
+        q.add_used(m, 1, 0).unwrap();
+        assert_eq!(get_desc(3).flags, AVAIL | USED);
+        assert_eq!(q.next_used.0, 1);
+        assert!(!q.used_wrap_counter);
+
+        // Notifications follow the driver event suppression flags.
+        m.write_obj(VRING_PACKED_EVENT_FLAG_DISABLE as u16, GuestAddress(0x102))
+            .unwrap();
+        assert!(!q.needs_notification(m).unwrap());
+        m.write_obj(VRING_PACKED_EVENT_FLAG_ENABLE as u16, GuestAddress(0x102))
+            .unwrap();
+        assert!(q.needs_notification(m).unwrap());
+    }
 }
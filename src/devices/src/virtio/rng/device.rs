@@ -1,4 +1,5 @@
-use rand::{rngs::OsRng, TryRngCore};
+use rand::rngs::{OsRng, StdRng};
+use rand::{RngCore, SeedableRng, TryRngCore};
 use utils::eventfd::EventFd;
 use vm_memory::{Bytes, GuestMemoryMmap};
 
@@ -6,13 +7,17 @@ use super::super::{
     ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, RngError, VirtioDevice,
 };
 use super::{defs, defs::uapi};
-use crate::virtio::InterruptTransport;
+use crate::virtio::{ConfigLayout, InterruptTransport};
 
 // Request queue.
 pub(crate) const REQ_INDEX: usize = 0;
 
 // Supported features.
-pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64;
+pub(crate) const AVAIL_FEATURES: u64 =
+    (1 << uapi::VIRTIO_F_VERSION_1 as u64) | (1 << uapi::VIRTIO_F_RING_PACKED as u64);
+
+// The device has no config space.
+const CONFIG_LAYOUT: ConfigLayout = ConfigLayout::new("rng", &[]);
 
 pub struct Rng {
     pub(crate) queues: Vec<VirtQueue>,
@@ -21,6 +26,8 @@ pub struct Rng {
     pub(crate) acked_features: u64,
     pub(crate) activate_evt: EventFd,
     pub(crate) device_state: DeviceState,
+    /// Generator used instead of the host's, for deterministic runs.
+    seeded: Option<StdRng>,
 }
 
 impl Rng {
@@ -38,6 +45,7 @@ impl Rng {
             acked_features: 0,
             activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(RngError::EventFd)?,
             device_state: DeviceState::Inactive,
+            seeded: None,
         })
     }
 
@@ -49,6 +57,13 @@ impl Rng {
         Self::with_queues(queues)
     }
 
+    /// Creates a device whose output only depends on `seed`.
+    pub fn with_seed(seed: u64) -> super::Result<Rng> {
+        let mut rng = Self::new()?;
+        rng.seeded = Some(StdRng::seed_from_u64(seed));
+        Ok(rng)
+    }
+
     pub fn id(&self) -> &str {
         defs::RNG_DEV_ID
     }
@@ -68,7 +83,14 @@ impl Rng {
             let mut written = 0;
             for desc in head.into_iter() {
                 let mut rand_bytes = vec![0u8; desc.len as usize];
-                if let Err(e) = OsRng.try_fill_bytes(&mut rand_bytes) {
+                let filled = match self.seeded.as_mut() {
+                    Some(seeded) => {
+                        seeded.fill_bytes(&mut rand_bytes);
+                        Ok(())
+                    }
+                    None => OsRng.try_fill_bytes(&mut rand_bytes),
+                };
+                if let Err(e) = filled {
                     error!("Failed to fill buffer with random data: {e:?}");
                     self.queues[REQ_INDEX].go_to_previous_position();
                     break;
@@ -124,16 +146,12 @@ impl VirtioDevice for Rng {
         &self.queue_events
     }
 
-    fn read_config(&self, _offset: u64, _data: &mut [u8]) {
-        error!("rng: invalid request to read config space");
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        CONFIG_LAYOUT.read(&[], offset, data);
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        warn!(
-            "rng: guest driver attempted to write device config (offset={:x}, len={:x})",
-            offset,
-            data.len()
-        );
+        CONFIG_LAYOUT.write_read_only(offset, data);
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
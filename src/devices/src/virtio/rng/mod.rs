@@ -11,6 +11,7 @@ mod defs {
 
     pub mod uapi {
         pub const VIRTIO_F_VERSION_1: u32 = 32;
+        pub const VIRTIO_F_RING_PACKED: u32 = 34;
         pub const VIRTIO_ID_RNG: u32 = 4;
     }
 }
@@ -6,7 +6,7 @@ mod pipewire;
 use std::sync::{Arc, RwLock};
 
 use self::pipewire::PwBackend;
-use super::{stream::Stream, BackendType, Result, VirtioSndPcmSetParams};
+use super::{jack::Jacks, stream::Stream, BackendType, Result, VirtioSndPcmSetParams};
 
 pub trait AudioBackend {
     fn write(&self, stream_id: u32) -> Result<()>;
@@ -41,10 +41,11 @@ pub trait AudioBackend {
 pub fn alloc_audio_backend(
     backend: BackendType,
     streams: Arc<RwLock<Vec<Stream>>>,
+    jacks: Arc<Jacks>,
 ) -> Result<Box<dyn AudioBackend + Send + Sync>> {
     log::trace!("allocating audio backend {backend:?}");
     match backend {
-        BackendType::Pipewire => Ok(Box::new(PwBackend::new(streams))),
+        BackendType::Pipewire => Ok(Box::new(PwBackend::new(streams, jacks))),
     }
 }
 
@@ -59,7 +60,7 @@ mod tests {
         crate::init_logger();
         {
             let v = BackendType::Null;
-            let value = alloc_audio_backend(v, Default::default()).unwrap();
+            let value = alloc_audio_backend(v, Default::default(), Default::default()).unwrap();
             assert_eq!(TypeId::of::<NullBackend>(), value.as_any().type_id());
         }
         #[cfg(all(feature = "pw-backend", target_env = "gnu"))]
@@ -68,13 +69,13 @@ mod tests {
 
             let _test_harness = PipewireTestHarness::new();
             let v = BackendType::Pipewire;
-            let value = alloc_audio_backend(v, Default::default()).unwrap();
+            let value = alloc_audio_backend(v, Default::default(), Default::default()).unwrap();
             assert_eq!(TypeId::of::<PwBackend>(), value.as_any().type_id());
         }
         #[cfg(all(feature = "alsa-backend", target_env = "gnu"))]
         {
             let v = BackendType::Alsa;
-            let value = alloc_audio_backend(v, Default::default()).unwrap();
+            let value = alloc_audio_backend(v, Default::default(), Default::default()).unwrap();
             assert_eq!(TypeId::of::<AlsaBackend>(), value.as_any().type_id());
         }
     }
@@ -2,17 +2,23 @@
 // SPDX-License-Identifier: Apache-2.0 or BSD-3-Clause
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::TryFrom,
-    mem::size_of,
     ptr,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
 };
 
 use log::debug;
 use pw::{
-    context::Context, core::Core, properties::properties, spa, sys::PW_ID_CORE,
+    context::Context,
+    core::Core,
+    properties::properties,
+    registry::{Listener as RegistryListener, Registry},
+    spa,
+    stream::StreamState,
+    sys::PW_ID_CORE,
     thread_loop::ThreadLoop,
+    types::ObjectType,
 };
 use spa::{
     param::{
@@ -35,6 +41,7 @@ use spa::{
 };
 
 use super::super::{
+    jack::Jacks,
     stream::{Error as StreamError, PCMState},
     virtio_sound::{
         VirtioSndPcmSetParams, VIRTIO_SND_PCM_FMT_A_LAW, VIRTIO_SND_PCM_FMT_FLOAT,
@@ -80,10 +87,82 @@ pub struct PwBackend {
     context: Context,
     pub stream_hash: RwLock<HashMap<u32, pw::stream::Stream>>,
     pub stream_listener: RwLock<HashMap<u32, pw::stream::StreamListener<i32>>>,
+    /// Streams that lost their connection to the host device, and must be
+    /// connected again to keep the guest audio flowing.
+    disconnected: Arc<Mutex<HashSet<u32>>>,
+    _registry_listener: RegistryListener,
+    _registry: Registry,
+}
+
+/// Returns the size in bytes of a sample in the `format` of the guest.
+fn sample_size(format: u8) -> u32 {
+    match format {
+        VIRTIO_SND_PCM_FMT_MU_LAW
+        | VIRTIO_SND_PCM_FMT_A_LAW
+        | VIRTIO_SND_PCM_FMT_S8
+        | VIRTIO_SND_PCM_FMT_U8 => 1,
+        VIRTIO_SND_PCM_FMT_S16 | VIRTIO_SND_PCM_FMT_U16 => 2,
+        VIRTIO_SND_PCM_FMT_S18_3
+        | VIRTIO_SND_PCM_FMT_U18_3
+        | VIRTIO_SND_PCM_FMT_S20_3
+        | VIRTIO_SND_PCM_FMT_U20_3
+        | VIRTIO_SND_PCM_FMT_S24_3
+        | VIRTIO_SND_PCM_FMT_U24_3 => 3,
+        VIRTIO_SND_PCM_FMT_FLOAT64 => 8,
+        _ => 4,
+    }
+}
+
+/// Keeps the jacks of the guest connected while the host has audio devices
+/// of the same direction.
+fn watch_devices(core: &Core, jacks: Arc<Jacks>) -> (Registry, RegistryListener) {
+    let registry = core.get_registry().expect("failed to get the registry");
+    let devices: Arc<Mutex<HashMap<u32, Direction>>> = Default::default();
+
+    let update = {
+        let devices = devices.clone();
+        move || {
+            let devices = devices.lock().unwrap();
+            for direction in [Direction::Output, Direction::Input] {
+                jacks.set_connected(direction, devices.values().any(|d| *d == direction));
+            }
+        }
+    };
+    let update = Arc::new(update);
+
+    let listener = registry
+        .add_listener_local()
+        .global({
+            let devices = devices.clone();
+            let update = update.clone();
+            move |global| {
+                if global.type_ != ObjectType::Node {
+                    return;
+                }
+                let direction = match global
+                    .props
+                    .and_then(|props| props.get(*pw::keys::MEDIA_CLASS))
+                {
+                    Some("Audio/Sink") => Direction::Output,
+                    Some("Audio/Source") => Direction::Input,
+                    _ => return,
+                };
+                devices.lock().unwrap().insert(global.id, direction);
+                update();
+            }
+        })
+        .global_remove(move |id| {
+            if devices.lock().unwrap().remove(&id).is_some() {
+                update();
+            }
+        })
+        .register();
+
+    (registry, listener)
 }
 
 impl PwBackend {
-    pub fn new(stream_params: Arc<RwLock<Vec<Stream>>>) -> Self {
+    pub fn new(stream_params: Arc<RwLock<Vec<Stream>>>, jacks: Arc<Jacks>) -> Self {
         pw::init();
 
         // SAFETY: safe as the thread loop cannot access objects associated
@@ -114,6 +193,7 @@ impl PwBackend {
             .register();
 
         thread_loop.wait();
+        let (registry, registry_listener) = watch_devices(&core, jacks);
         lock_guard.unlock();
 
         log::trace!("pipewire backend running");
@@ -125,8 +205,337 @@ impl PwBackend {
             context,
             stream_hash: RwLock::new(HashMap::new()),
             stream_listener: RwLock::new(HashMap::new()),
+            disconnected: Default::default(),
+            _registry_listener: registry_listener,
+            _registry: registry,
         }
     }
+
+    /// Connects the stream again if the host device it was using went away,
+    /// e.g. after unplugging headphones, so it moves to the new default one.
+    fn reconnect_if_needed(&self, stream_id: u32) -> Result<()> {
+        if !self.disconnected.lock().unwrap().contains(&stream_id) {
+            return Ok(());
+        }
+        let state = self.stream_params.read().unwrap()[stream_id as usize].state;
+        if !matches!(state, PCMState::Start | PCMState::Prepare) {
+            return Ok(());
+        }
+
+        debug!("reconnecting pipewire stream {stream_id}");
+        self.connect(stream_id)?;
+        if state == PCMState::Start {
+            let lock_guard = self.thread_loop.lock();
+            let stream_hash = self.stream_hash.read().unwrap();
+            if let Some(stream) = stream_hash.get(&stream_id) {
+                stream.set_active(true).expect("could not start stream");
+            }
+            lock_guard.unlock();
+        }
+        Ok(())
+    }
+
+    /// Creates the PipeWire stream for `stream_id` with the parameters set by
+    /// the guest, replacing the previous one.
+    fn connect(&self, stream_id: u32) -> Result<()> {
+        self.disconnected.lock().unwrap().remove(&stream_id);
+        let mut stream_hash = self.stream_hash.write().unwrap();
+        let mut stream_listener = self.stream_listener.write().unwrap();
+        let lock_guard = self.thread_loop.lock();
+        let stream_params = self.stream_params.read().unwrap();
+
+        let params = &stream_params[stream_id as usize].params;
+
+        if let Some(stream) = stream_hash.remove(&stream_id) {
+            stream_listener.remove(&stream_id);
+            if let Err(err) = stream.disconnect() {
+                log::error!("Stream {stream_id} disconnect {err}");
+                return Err(Error::Stream(StreamError::CouldNotDisconnectStream));
+            }
+        }
+
+        let mut pos: [u32; 64] = [SPA_AUDIO_CHANNEL_UNKNOWN; 64];
+
+        match params.channels {
+            6 => {
+                pos[0] = SPA_AUDIO_CHANNEL_FL;
+                pos[1] = SPA_AUDIO_CHANNEL_FR;
+                pos[2] = SPA_AUDIO_CHANNEL_FC;
+                pos[3] = SPA_AUDIO_CHANNEL_LFE;
+                pos[4] = SPA_AUDIO_CHANNEL_RL;
+                pos[5] = SPA_AUDIO_CHANNEL_RR;
+            }
+            5 => {
+                pos[0] = SPA_AUDIO_CHANNEL_FL;
+                pos[1] = SPA_AUDIO_CHANNEL_FR;
+                pos[2] = SPA_AUDIO_CHANNEL_FC;
+                pos[3] = SPA_AUDIO_CHANNEL_LFE;
+                pos[4] = SPA_AUDIO_CHANNEL_RC;
+            }
+            4 => {
+                pos[0] = SPA_AUDIO_CHANNEL_FL;
+                pos[1] = SPA_AUDIO_CHANNEL_FR;
+                pos[2] = SPA_AUDIO_CHANNEL_FC;
+                pos[3] = SPA_AUDIO_CHANNEL_RC;
+            }
+            3 => {
+                pos[0] = SPA_AUDIO_CHANNEL_FL;
+                pos[1] = SPA_AUDIO_CHANNEL_FR;
+                pos[2] = SPA_AUDIO_CHANNEL_LFE;
+            }
+            2 => {
+                pos[0] = SPA_AUDIO_CHANNEL_FL;
+                pos[1] = SPA_AUDIO_CHANNEL_FR;
+            }
+            1 => {
+                pos[0] = SPA_AUDIO_CHANNEL_MONO;
+            }
+            _ => {
+                return Err(Error::ChannelNotSupported(params.channels));
+            }
+        }
+
+        let info = spa_audio_info_raw {
+            format: match params.format {
+                VIRTIO_SND_PCM_FMT_MU_LAW => SPA_AUDIO_FORMAT_ULAW,
+                VIRTIO_SND_PCM_FMT_A_LAW => SPA_AUDIO_FORMAT_ALAW,
+                VIRTIO_SND_PCM_FMT_S8 => SPA_AUDIO_FORMAT_S8,
+                VIRTIO_SND_PCM_FMT_U8 => SPA_AUDIO_FORMAT_U8,
+                VIRTIO_SND_PCM_FMT_S16 => SPA_AUDIO_FORMAT_S16,
+                VIRTIO_SND_PCM_FMT_U16 => SPA_AUDIO_FORMAT_U16,
+                VIRTIO_SND_PCM_FMT_S18_3 => SPA_AUDIO_FORMAT_S18_LE,
+                VIRTIO_SND_PCM_FMT_U18_3 => SPA_AUDIO_FORMAT_U18_LE,
+                VIRTIO_SND_PCM_FMT_S20_3 => SPA_AUDIO_FORMAT_S20_LE,
+                VIRTIO_SND_PCM_FMT_U20_3 => SPA_AUDIO_FORMAT_U20_LE,
+                VIRTIO_SND_PCM_FMT_S24_3 => SPA_AUDIO_FORMAT_S24_LE,
+                VIRTIO_SND_PCM_FMT_U24_3 => SPA_AUDIO_FORMAT_U24_LE,
+                VIRTIO_SND_PCM_FMT_S20 => SPA_AUDIO_FORMAT_S20,
+                VIRTIO_SND_PCM_FMT_U20 => SPA_AUDIO_FORMAT_U20,
+                VIRTIO_SND_PCM_FMT_S24 => SPA_AUDIO_FORMAT_S24,
+                VIRTIO_SND_PCM_FMT_U24 => SPA_AUDIO_FORMAT_U24,
+                VIRTIO_SND_PCM_FMT_S32 => SPA_AUDIO_FORMAT_S32,
+                VIRTIO_SND_PCM_FMT_U32 => SPA_AUDIO_FORMAT_U32,
+                VIRTIO_SND_PCM_FMT_FLOAT => SPA_AUDIO_FORMAT_F32,
+                VIRTIO_SND_PCM_FMT_FLOAT64 => SPA_AUDIO_FORMAT_F64,
+                _ => SPA_AUDIO_FORMAT_UNKNOWN,
+            },
+            rate: match params.rate {
+                VIRTIO_SND_PCM_RATE_5512 => 5512,
+                VIRTIO_SND_PCM_RATE_8000 => 8000,
+                VIRTIO_SND_PCM_RATE_11025 => 11025,
+                VIRTIO_SND_PCM_RATE_16000 => 16000,
+                VIRTIO_SND_PCM_RATE_22050 => 22050,
+                VIRTIO_SND_PCM_RATE_32000 => 32000,
+                VIRTIO_SND_PCM_RATE_44100 => 44100,
+                VIRTIO_SND_PCM_RATE_48000 => 48000,
+                VIRTIO_SND_PCM_RATE_64000 => 64000,
+                VIRTIO_SND_PCM_RATE_88200 => 88200,
+                VIRTIO_SND_PCM_RATE_96000 => 96000,
+                VIRTIO_SND_PCM_RATE_176400 => 176400,
+                VIRTIO_SND_PCM_RATE_192000 => 192000,
+                VIRTIO_SND_PCM_RATE_384000 => 384000,
+                _ => 44100,
+            },
+            flags: 0,
+            channels: u32::from(params.channels),
+            position: pos,
+        };
+
+        let mut audio_info = AudioInfoRaw::new();
+        // Use the format negotiated by the guest, PipeWire converts it to
+        // whatever the host device needs, even if it changes mid-stream.
+        audio_info.set_format(AudioFormat::from_raw(info.format));
+        audio_info.set_rate(info.rate);
+        audio_info.set_channels(info.channels);
+        audio_info.set_position(pos);
+
+        let values: Vec<u8> = PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &Value::Object(Object {
+                type_: SPA_TYPE_OBJECT_Format,
+                id: SPA_PARAM_EnumFormat,
+                properties: audio_info.into(),
+            }),
+        )
+        .unwrap()
+        .0
+        .into_inner();
+
+        let value_clone = values.clone();
+
+        let mut param = [Pod::from_bytes(&values).unwrap()];
+
+        let direction = stream_params[stream_id as usize].direction;
+
+        let media_category = match direction {
+            Direction::Input => "Capture",
+            Direction::Output => "Playback",
+        };
+        let stream_name = match direction {
+            Direction::Input => "audio-input",
+            Direction::Output => "audio-output",
+        };
+
+        let props = properties! {
+            *pw::keys::MEDIA_TYPE => "Audio",
+            *pw::keys::MEDIA_CATEGORY => media_category,
+        };
+
+        let stream = pw::stream::Stream::new(&self.core, stream_name, props)
+            .expect("could not create new stream");
+
+        let streams = self.stream_params.clone();
+        let disconnected = self.disconnected.clone();
+        let frame_size = info.channels * sample_size(params.format);
+
+        let listener_stream = stream
+            .add_local_listener()
+            .state_changed(move |_, _, old, new| {
+                debug!("State changed: {old:?} -> {new:?}");
+                if matches!(new, StreamState::Error(_) | StreamState::Unconnected) {
+                    disconnected.lock().unwrap().insert(stream_id);
+                }
+            })
+            .param_changed(move |stream, _data, id, param| {
+                let Some(_param) = param else {
+                    return;
+                };
+                if id != ParamType::Format.as_raw() {
+                    return;
+                }
+                let mut param = [Pod::from_bytes(&value_clone).unwrap()];
+
+                //callback to negotiate new set of streams
+                stream
+                    .update_params(&mut param)
+                    .expect("could not update params");
+            })
+            .process(move |stream, _data| match stream.dequeue_buffer() {
+                None => debug!("No buffer recieved"),
+                Some(mut buf) => {
+                    match direction {
+                        Direction::Input => {
+                            let datas = buf.datas_mut();
+                            let data = &mut datas[0];
+                            let mut n_samples = data.chunk().size() as usize;
+                            let Some(slice) = data.data() else {
+                                return;
+                            };
+                            let mut streams = streams.write().unwrap();
+                            let stream = streams
+                                .get_mut(stream_id as usize)
+                                .expect("Stream does not exist");
+
+                            let mut start = 0;
+                            while n_samples > 0 {
+                                let Some(buffer) = stream.buffers.front_mut() else {
+                                    return;
+                                };
+
+                                let avail = usize::try_from(buffer.desc_len())
+                                    .unwrap()
+                                    .saturating_sub(buffer.pos);
+                                let n_bytes = n_samples.min(avail);
+                                let p = &slice[start..start + n_bytes];
+
+                                if buffer
+                                    .write_input(p)
+                                    .expect("Could not write data to guest memory")
+                                    == 0
+                                {
+                                    break;
+                                }
+
+                                n_samples -= n_bytes;
+                                start += n_bytes;
+
+                                if buffer.pos >= buffer.desc_len() as usize {
+                                    stream.buffers.pop_front();
+                                }
+                            }
+                        }
+                        Direction::Output => {
+                            let datas = buf.datas_mut();
+                            let data = &mut datas[0];
+                            let n_bytes = if let Some(slice) = data.data() {
+                                let mut n_bytes = slice.len();
+                                let mut streams = streams.write().unwrap();
+                                let streams = streams
+                                    .get_mut(stream_id as usize)
+                                    .expect("Stream does not exist");
+                                let Some(buffer) = streams.buffers.front_mut() else {
+                                    return;
+                                };
+
+                                let mut start = buffer.pos;
+
+                                let avail = usize::try_from(buffer.desc_len())
+                                    .unwrap()
+                                    .saturating_sub(start);
+
+                                if avail < n_bytes {
+                                    n_bytes = avail;
+                                }
+                                let p = &mut slice[0..n_bytes];
+                                if avail == 0 {
+                                    // SAFETY: We have assured above that the pointer is not
+                                    // null
+                                    // safe to zero-initialize the pointer.
+                                    unsafe {
+                                        // pad with silence
+                                        ptr::write_bytes(p.as_mut_ptr(), 0, n_bytes);
+                                    }
+                                } else {
+                                    // read_output() always reads (buffer.desc_len() -
+                                    // buffer.pos) bytes
+                                    buffer
+                                        .read_output(p)
+                                        .expect("failed to read buffer from guest");
+
+                                    start += n_bytes;
+
+                                    buffer.pos = start;
+
+                                    if start >= buffer.desc_len() as usize {
+                                        streams.buffers.pop_front();
+                                    }
+                                }
+                                n_bytes
+                            } else {
+                                0
+                            };
+                            let chunk = data.chunk_mut();
+                            *chunk.offset_mut() = 0;
+                            *chunk.stride_mut() = i32::try_from(frame_size).unwrap();
+                            *chunk.size_mut() = u32::try_from(n_bytes).unwrap();
+                        }
+                    };
+                }
+            })
+            .register()
+            .expect("failed to register stream listener");
+
+        stream_listener.insert(stream_id, listener_stream);
+
+        stream
+            .connect(
+                stream_params[stream_id as usize].direction.into(),
+                Some(pw::constants::ID_ANY),
+                pw::stream::StreamFlags::RT_PROCESS
+                    | pw::stream::StreamFlags::AUTOCONNECT
+                    | pw::stream::StreamFlags::INACTIVE
+                    | pw::stream::StreamFlags::MAP_BUFFERS,
+                &mut param,
+            )
+            .expect("could not connect to the stream");
+
+        // insert created stream in a hash table
+        stream_hash.insert(stream_id, stream);
+
+        lock_guard.unlock();
+
+        Ok(())
+    }
 }
 
 impl Drop for PwBackend {
@@ -137,6 +546,7 @@ impl Drop for PwBackend {
 
 impl AudioBackend for PwBackend {
     fn write(&self, stream_id: u32) -> Result<()> {
+        self.reconnect_if_needed(stream_id)?;
         if !matches!(
             self.stream_params.read().unwrap()[stream_id as usize].state,
             PCMState::Start | PCMState::Prepare
@@ -200,298 +610,8 @@ impl AudioBackend for PwBackend {
         if let Err(err) = prepare_result {
             log::error!("Stream {stream_id} prepare {err}");
             return Err(Error::Stream(err));
-        } else {
-            let mut stream_hash = self.stream_hash.write().unwrap();
-            let mut stream_listener = self.stream_listener.write().unwrap();
-            let lock_guard = self.thread_loop.lock();
-            let stream_params = self.stream_params.read().unwrap();
-
-            let params = &stream_params[stream_id as usize].params;
-
-            if let Some(stream) = stream_hash.remove(&stream_id) {
-                stream_listener.remove(&stream_id);
-                if let Err(err) = stream.disconnect() {
-                    log::error!("Stream {stream_id} disconnect {err}");
-                    return Err(Error::Stream(StreamError::CouldNotDisconnectStream));
-                }
-            }
-
-            let mut pos: [u32; 64] = [SPA_AUDIO_CHANNEL_UNKNOWN; 64];
-
-            match params.channels {
-                6 => {
-                    pos[0] = SPA_AUDIO_CHANNEL_FL;
-                    pos[1] = SPA_AUDIO_CHANNEL_FR;
-                    pos[2] = SPA_AUDIO_CHANNEL_FC;
-                    pos[3] = SPA_AUDIO_CHANNEL_LFE;
-                    pos[4] = SPA_AUDIO_CHANNEL_RL;
-                    pos[5] = SPA_AUDIO_CHANNEL_RR;
-                }
-                5 => {
-                    pos[0] = SPA_AUDIO_CHANNEL_FL;
-                    pos[1] = SPA_AUDIO_CHANNEL_FR;
-                    pos[2] = SPA_AUDIO_CHANNEL_FC;
-                    pos[3] = SPA_AUDIO_CHANNEL_LFE;
-                    pos[4] = SPA_AUDIO_CHANNEL_RC;
-                }
-                4 => {
-                    pos[0] = SPA_AUDIO_CHANNEL_FL;
-                    pos[1] = SPA_AUDIO_CHANNEL_FR;
-                    pos[2] = SPA_AUDIO_CHANNEL_FC;
-                    pos[3] = SPA_AUDIO_CHANNEL_RC;
-                }
-                3 => {
-                    pos[0] = SPA_AUDIO_CHANNEL_FL;
-                    pos[1] = SPA_AUDIO_CHANNEL_FR;
-                    pos[2] = SPA_AUDIO_CHANNEL_LFE;
-                }
-                2 => {
-                    pos[0] = SPA_AUDIO_CHANNEL_FL;
-                    pos[1] = SPA_AUDIO_CHANNEL_FR;
-                }
-                1 => {
-                    pos[0] = SPA_AUDIO_CHANNEL_MONO;
-                }
-                _ => {
-                    return Err(Error::ChannelNotSupported(params.channels));
-                }
-            }
-
-            let info = spa_audio_info_raw {
-                format: match params.format {
-                    VIRTIO_SND_PCM_FMT_MU_LAW => SPA_AUDIO_FORMAT_ULAW,
-                    VIRTIO_SND_PCM_FMT_A_LAW => SPA_AUDIO_FORMAT_ALAW,
-                    VIRTIO_SND_PCM_FMT_S8 => SPA_AUDIO_FORMAT_S8,
-                    VIRTIO_SND_PCM_FMT_U8 => SPA_AUDIO_FORMAT_U8,
-                    VIRTIO_SND_PCM_FMT_S16 => SPA_AUDIO_FORMAT_S16,
-                    VIRTIO_SND_PCM_FMT_U16 => SPA_AUDIO_FORMAT_U16,
-                    VIRTIO_SND_PCM_FMT_S18_3 => SPA_AUDIO_FORMAT_S18_LE,
-                    VIRTIO_SND_PCM_FMT_U18_3 => SPA_AUDIO_FORMAT_U18_LE,
-                    VIRTIO_SND_PCM_FMT_S20_3 => SPA_AUDIO_FORMAT_S20_LE,
-                    VIRTIO_SND_PCM_FMT_U20_3 => SPA_AUDIO_FORMAT_U20_LE,
-                    VIRTIO_SND_PCM_FMT_S24_3 => SPA_AUDIO_FORMAT_S24_LE,
-                    VIRTIO_SND_PCM_FMT_U24_3 => SPA_AUDIO_FORMAT_U24_LE,
-                    VIRTIO_SND_PCM_FMT_S20 => SPA_AUDIO_FORMAT_S20,
-                    VIRTIO_SND_PCM_FMT_U20 => SPA_AUDIO_FORMAT_U20,
-                    VIRTIO_SND_PCM_FMT_S24 => SPA_AUDIO_FORMAT_S24,
-                    VIRTIO_SND_PCM_FMT_U24 => SPA_AUDIO_FORMAT_U24,
-                    VIRTIO_SND_PCM_FMT_S32 => SPA_AUDIO_FORMAT_S32,
-                    VIRTIO_SND_PCM_FMT_U32 => SPA_AUDIO_FORMAT_U32,
-                    VIRTIO_SND_PCM_FMT_FLOAT => SPA_AUDIO_FORMAT_F32,
-                    VIRTIO_SND_PCM_FMT_FLOAT64 => SPA_AUDIO_FORMAT_F64,
-                    _ => SPA_AUDIO_FORMAT_UNKNOWN,
-                },
-                rate: match params.rate {
-                    VIRTIO_SND_PCM_RATE_5512 => 5512,
-                    VIRTIO_SND_PCM_RATE_8000 => 8000,
-                    VIRTIO_SND_PCM_RATE_11025 => 11025,
-                    VIRTIO_SND_PCM_RATE_16000 => 16000,
-                    VIRTIO_SND_PCM_RATE_22050 => 22050,
-                    VIRTIO_SND_PCM_RATE_32000 => 32000,
-                    VIRTIO_SND_PCM_RATE_44100 => 44100,
-                    VIRTIO_SND_PCM_RATE_48000 => 48000,
-                    VIRTIO_SND_PCM_RATE_64000 => 64000,
-                    VIRTIO_SND_PCM_RATE_88200 => 88200,
-                    VIRTIO_SND_PCM_RATE_96000 => 96000,
-                    VIRTIO_SND_PCM_RATE_176400 => 176400,
-                    VIRTIO_SND_PCM_RATE_192000 => 192000,
-                    VIRTIO_SND_PCM_RATE_384000 => 384000,
-                    _ => 44100,
-                },
-                flags: 0,
-                channels: u32::from(params.channels),
-                position: pos,
-            };
-
-            let mut audio_info = AudioInfoRaw::new();
-            audio_info.set_format(AudioFormat::S16LE);
-            audio_info.set_rate(info.rate);
-            audio_info.set_channels(info.channels);
-            audio_info.set_position(pos);
-
-            let values: Vec<u8> = PodSerializer::serialize(
-                std::io::Cursor::new(Vec::new()),
-                &Value::Object(Object {
-                    type_: SPA_TYPE_OBJECT_Format,
-                    id: SPA_PARAM_EnumFormat,
-                    properties: audio_info.into(),
-                }),
-            )
-            .unwrap()
-            .0
-            .into_inner();
-
-            let value_clone = values.clone();
-
-            let mut param = [Pod::from_bytes(&values).unwrap()];
-
-            let direction = stream_params[stream_id as usize].direction;
-
-            let media_category = match direction {
-                Direction::Input => "Capture",
-                Direction::Output => "Playback",
-            };
-            let stream_name = match direction {
-                Direction::Input => "audio-input",
-                Direction::Output => "audio-output",
-            };
-
-            let props = properties! {
-                *pw::keys::MEDIA_TYPE => "Audio",
-                *pw::keys::MEDIA_CATEGORY => media_category,
-            };
-
-            let stream = pw::stream::Stream::new(&self.core, stream_name, props)
-                .expect("could not create new stream");
-
-            let streams = self.stream_params.clone();
-
-            let listener_stream = stream
-                .add_local_listener()
-                .state_changed(|_, _, old, new| {
-                    debug!("State changed: {old:?} -> {new:?}");
-                })
-                .param_changed(move |stream, _data, id, param| {
-                    let Some(_param) = param else {
-                        return;
-                    };
-                    if id != ParamType::Format.as_raw() {
-                        return;
-                    }
-                    let mut param = [Pod::from_bytes(&value_clone).unwrap()];
-
-                    //callback to negotiate new set of streams
-                    stream
-                        .update_params(&mut param)
-                        .expect("could not update params");
-                })
-                .process(move |stream, _data| match stream.dequeue_buffer() {
-                    None => debug!("No buffer recieved"),
-                    Some(mut buf) => {
-                        match direction {
-                            Direction::Input => {
-                                let datas = buf.datas_mut();
-                                let data = &mut datas[0];
-                                let mut n_samples = data.chunk().size() as usize;
-                                let Some(slice) = data.data() else {
-                                    return;
-                                };
-                                let mut streams = streams.write().unwrap();
-                                let stream = streams
-                                    .get_mut(stream_id as usize)
-                                    .expect("Stream does not exist");
-
-                                let mut start = 0;
-                                while n_samples > 0 {
-                                    let Some(buffer) = stream.buffers.front_mut() else {
-                                        return;
-                                    };
-
-                                    let avail = usize::try_from(buffer.desc_len())
-                                        .unwrap()
-                                        .saturating_sub(buffer.pos);
-                                    let n_bytes = n_samples.min(avail);
-                                    let p = &slice[start..start + n_bytes];
-
-                                    if buffer
-                                        .write_input(p)
-                                        .expect("Could not write data to guest memory")
-                                        == 0
-                                    {
-                                        break;
-                                    }
-
-                                    n_samples -= n_bytes;
-                                    start += n_bytes;
-
-                                    if buffer.pos >= buffer.desc_len() as usize {
-                                        stream.buffers.pop_front();
-                                    }
-                                }
-                            }
-                            Direction::Output => {
-                                let datas = buf.datas_mut();
-                                let frame_size = info.channels * size_of::<i16>() as u32;
-                                let data = &mut datas[0];
-                                let n_bytes = if let Some(slice) = data.data() {
-                                    let mut n_bytes = slice.len();
-                                    let mut streams = streams.write().unwrap();
-                                    let streams = streams
-                                        .get_mut(stream_id as usize)
-                                        .expect("Stream does not exist");
-                                    let Some(buffer) = streams.buffers.front_mut() else {
-                                        return;
-                                    };
-
-                                    let mut start = buffer.pos;
-
-                                    let avail = usize::try_from(buffer.desc_len())
-                                        .unwrap()
-                                        .saturating_sub(start);
-
-                                    if avail < n_bytes {
-                                        n_bytes = avail;
-                                    }
-                                    let p = &mut slice[0..n_bytes];
-                                    if avail == 0 {
-                                        // SAFETY: We have assured above that the pointer is not
-                                        // null
-                                        // safe to zero-initialize the pointer.
-                                        unsafe {
-                                            // pad with silence
-                                            ptr::write_bytes(p.as_mut_ptr(), 0, n_bytes);
-                                        }
-                                    } else {
-                                        // read_output() always reads (buffer.desc_len() -
-                                        // buffer.pos) bytes
-                                        buffer
-                                            .read_output(p)
-                                            .expect("failed to read buffer from guest");
-
-                                        start += n_bytes;
-
-                                        buffer.pos = start;
-
-                                        if start >= buffer.desc_len() as usize {
-                                            streams.buffers.pop_front();
-                                        }
-                                    }
-                                    n_bytes
-                                } else {
-                                    0
-                                };
-                                let chunk = data.chunk_mut();
-                                *chunk.offset_mut() = 0;
-                                *chunk.stride_mut() = i32::try_from(frame_size).unwrap();
-                                *chunk.size_mut() = u32::try_from(n_bytes).unwrap();
-                            }
-                        };
-                    }
-                })
-                .register()
-                .expect("failed to register stream listener");
-
-            stream_listener.insert(stream_id, listener_stream);
-
-            stream
-                .connect(
-                    stream_params[stream_id as usize].direction.into(),
-                    Some(pw::constants::ID_ANY),
-                    pw::stream::StreamFlags::RT_PROCESS
-                        | pw::stream::StreamFlags::AUTOCONNECT
-                        | pw::stream::StreamFlags::INACTIVE
-                        | pw::stream::StreamFlags::MAP_BUFFERS,
-                    &mut param,
-                )
-                .expect("could not connect to the stream");
-
-            // insert created stream in a hash table
-            stream_hash.insert(stream_id, stream);
-
-            lock_guard.unlock();
         }
-
-        Ok(())
+        self.connect(stream_id)
     }
 
     fn release(&self, stream_id: u32) -> Result<()> {
@@ -595,7 +715,7 @@ mod tests {
 
         let _test_harness = PipewireTestHarness::new();
 
-        let pw_backend = PwBackend::new(stream_params);
+        let pw_backend = PwBackend::new(stream_params, Default::default());
         assert_eq!(pw_backend.stream_hash.read().unwrap().len(), 0);
         assert_eq!(pw_backend.stream_listener.read().unwrap().len(), 0);
         // set up minimal configuration for test
@@ -623,7 +743,7 @@ mod tests {
 
         let _test_harness = PipewireTestHarness::new();
 
-        let pw_backend = PwBackend::new(stream_params);
+        let pw_backend = PwBackend::new(stream_params, Default::default());
 
         let request = VirtioSndPcmSetParams::default();
         let res = pw_backend.set_parameters(0, request);
@@ -1,4 +1,3 @@
-use std::io::Write;
 use std::thread::JoinHandle;
 
 use utils::eventfd::EventFd;
@@ -6,14 +5,19 @@ use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{ByteValued, GuestMemoryMmap};
 
 use super::super::{ActivateError, ActivateResult, Queue as VirtQueue, VirtioDevice};
+use super::jack::NUM_JACKS;
 use super::virtio_sound::VirtioSoundConfig;
 use super::worker::SndWorker;
 use super::{defs, defs::uapi, defs::QUEUE_INDEXES, Error};
 
-use crate::virtio::{DeviceState, InterruptTransport};
+use crate::virtio::{ConfigLayout, DeviceState, InterruptTransport};
 
 // Supported features.
-pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64;
+pub(crate) const AVAIL_FEATURES: u64 =
+    (1 << uapi::VIRTIO_F_VERSION_1 as u64) | (1 << uapi::VIRTIO_F_RING_PACKED as u64);
+
+const CONFIG_LAYOUT: ConfigLayout = ConfigLayout::new("snd", &[4, 4, 4]);
+const _: () = assert!(CONFIG_LAYOUT.size() == std::mem::size_of::<VirtioSoundConfig>());
 
 pub struct Snd {
     pub(crate) queues: Vec<VirtQueue>,
@@ -94,32 +98,18 @@ impl VirtioDevice for Snd {
         &self.queue_events
     }
 
-    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
         let config = VirtioSoundConfig {
-            jacks: 0.into(),
+            jacks: (NUM_JACKS as u32).into(),
             streams: 2.into(),
             chmaps: 1.into(),
         };
 
-        let config_slice = config.as_slice();
-        let config_len = config_slice.len() as u64;
-        if offset >= config_len {
-            error!("Failed to read config space");
-            return;
-        }
-        if let Some(end) = offset.checked_add(data.len() as u64) {
-            // This write can't fail, offset and end are checked against config_len.
-            data.write_all(&config_slice[offset as usize..std::cmp::min(end, config_len) as usize])
-                .unwrap();
-        }
+        CONFIG_LAYOUT.read(config.as_slice(), offset, data);
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        warn!(
-            "snd: guest driver attempted to write device config (offset={:x}, len={:x})",
-            offset,
-            data.len()
-        );
+        CONFIG_LAYOUT.write_read_only(offset, data);
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
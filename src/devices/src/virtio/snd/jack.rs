@@ -0,0 +1,186 @@
+//! Jacks exposed to the guest, one for each stream direction, and the event
+//! queue used to notify it when they get connected or disconnected.
+//!
+//! The host has no notion of jacks shared by every backend, so a jack is
+//! considered connected while the host has at least one audio device of its
+//! direction, and the backend reports when that changes, e.g. when plugging in
+//! or removing USB or Bluetooth headphones.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+
+use vm_memory::{ByteValued, Bytes, GuestAddress};
+
+use super::virtio_sound::{
+    VirtioSoundEvent, VirtioSoundHeader, VirtioSoundJackInfo, VIRTIO_SND_EVT_JACK_CONNECTED,
+    VIRTIO_SND_EVT_JACK_DISCONNECTED,
+};
+use super::{Direction, Vring};
+
+pub const NUM_JACKS: usize = 2;
+
+// HDA pin default configuration: jack connectivity, 1/8" connection type and
+// default device, so the guest driver knows what the jack is for.
+const HDA_DEFCONF_HEADPHONE: u32 = (0x2 << 20) | (0x1 << 16);
+const HDA_DEFCONF_MIC: u32 = (0xa << 20) | (0x1 << 16);
+
+// HDA pin capabilities: presence detection, output and input.
+const HDA_PINCAP_PRES_DETECT: u32 = 1 << 2;
+const HDA_PINCAP_OUT: u32 = 1 << 4;
+const HDA_PINCAP_IN: u32 = 1 << 5;
+
+fn jack_id(direction: Direction) -> usize {
+    match direction {
+        Direction::Output => 0,
+        Direction::Input => 1,
+    }
+}
+
+/// Event buffers made available by the guest, and events waiting for one.
+#[derive(Default)]
+struct EventQueue {
+    vring: Option<Arc<Mutex<Vring>>>,
+    buffers: VecDeque<(u16, GuestAddress)>,
+    pending: VecDeque<VirtioSoundEvent>,
+}
+
+impl EventQueue {
+    /// Pairs the oldest pending event with the oldest available buffer.
+    fn next(&mut self) -> Option<((u16, GuestAddress), VirtioSoundEvent)> {
+        if self.buffers.is_empty() || self.pending.is_empty() {
+            return None;
+        }
+        Some((self.buffers.pop_front()?, self.pending.pop_front()?))
+    }
+
+    fn flush(&mut self) {
+        let Some(vring_lock) = self.vring.clone() else {
+            return;
+        };
+        let mut vring = vring_lock.lock().unwrap();
+        let mem = vring.mem.clone();
+        let mut used = false;
+        while let Some(((head_index, addr), event)) = self.next() {
+            if let Err(err) = mem.write_obj(event, addr) {
+                log::error!("Error::DescriptorWriteFailed: {err}");
+                continue;
+            }
+            if let Err(err) = vring
+                .queue
+                .add_used(&mem, head_index, event.as_slice().len() as u32)
+            {
+                log::error!("Error adding used descriptors: {err}");
+                continue;
+            }
+            used = true;
+        }
+        if used {
+            vring.signal_used_queue();
+        }
+    }
+}
+
+pub struct Jacks {
+    info: RwLock<Vec<VirtioSoundJackInfo>>,
+    events: Mutex<EventQueue>,
+}
+
+impl Default for Jacks {
+    fn default() -> Self {
+        let jack = |defconf, caps| VirtioSoundJackInfo {
+            hda_reg_defconf: defconf,
+            hda_reg_caps: caps,
+            ..Default::default()
+        };
+        let mut info = vec![VirtioSoundJackInfo::default(); NUM_JACKS];
+        info[jack_id(Direction::Output)] = jack(
+            HDA_DEFCONF_HEADPHONE.into(),
+            (HDA_PINCAP_PRES_DETECT | HDA_PINCAP_OUT).into(),
+        );
+        info[jack_id(Direction::Input)] = jack(
+            HDA_DEFCONF_MIC.into(),
+            (HDA_PINCAP_PRES_DETECT | HDA_PINCAP_IN).into(),
+        );
+
+        Self {
+            info: RwLock::new(info),
+            events: Mutex::new(EventQueue::default()),
+        }
+    }
+}
+
+impl Jacks {
+    pub fn info(&self) -> RwLockReadGuard<'_, Vec<VirtioSoundJackInfo>> {
+        self.info.read().unwrap()
+    }
+
+    /// Records a new connection state for the jack of `direction`, notifying
+    /// the guest if it changed.
+    pub fn set_connected(&self, direction: Direction, connected: bool) {
+        let id = jack_id(direction);
+        {
+            let mut info = self.info.write().unwrap();
+            if (info[id].connected != 0) == connected {
+                return;
+            }
+            info[id].connected = connected as u8;
+        }
+        debug!("snd: jack {id} connected: {connected}");
+
+        let code = if connected {
+            VIRTIO_SND_EVT_JACK_CONNECTED
+        } else {
+            VIRTIO_SND_EVT_JACK_DISCONNECTED
+        };
+        let mut events = self.events.lock().unwrap();
+        events.pending.push_back(VirtioSoundEvent {
+            hdr: VirtioSoundHeader { code: code.into() },
+            data: (id as u32).into(),
+        });
+        events.flush();
+    }
+
+    /// Makes a buffer from the event queue available for notifications.
+    pub fn add_buffer(&self, vring: &Arc<Mutex<Vring>>, head_index: u16, addr: GuestAddress) {
+        let mut events = self.events.lock().unwrap();
+        events.vring.get_or_insert_with(|| vring.clone());
+        events.buffers.push_back((head_index, addr));
+        events.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jack_events() {
+        let jacks = Jacks::default();
+        assert_eq!(jacks.info().len(), NUM_JACKS);
+
+        jacks.set_connected(Direction::Output, true);
+        // Unchanged states aren't notified.
+        jacks.set_connected(Direction::Output, true);
+        jacks.set_connected(Direction::Input, false);
+        jacks.set_connected(Direction::Input, true);
+        assert_eq!(jacks.info()[0].connected, 1);
+        assert_eq!(jacks.info()[1].connected, 1);
+
+        let mut events = jacks.events.lock().unwrap();
+        assert!(events.next().is_none());
+        events.buffers.push_back((3, GuestAddress(0x1000)));
+        events.buffers.push_back((4, GuestAddress(0x2000)));
+        events.buffers.push_back((5, GuestAddress(0x3000)));
+
+        let ((head_index, _), event) = events.next().unwrap();
+        assert_eq!(head_index, 3);
+        assert_eq!(event.hdr.code, VIRTIO_SND_EVT_JACK_CONNECTED.into());
+        assert_eq!(event.data, 0.into());
+        let ((head_index, _), event) = events.next().unwrap();
+        assert_eq!(head_index, 4);
+        assert_eq!(event.data, 1.into());
+        // The last buffer is kept for the next event.
+        assert!(events.next().is_none());
+        assert_eq!(events.buffers.len(), 1);
+    }
+}
@@ -5,6 +5,7 @@ use std::{
 
 mod audio_backends;
 mod device;
+mod jack;
 pub mod stream;
 #[allow(dead_code)]
 mod virtio_sound;
@@ -36,7 +37,8 @@ mod defs {
     pub const SUPPORTED_FORMATS: u64 = (1 << VIRTIO_SND_PCM_FMT_U8)
         | (1 << VIRTIO_SND_PCM_FMT_S16)
         | (1 << VIRTIO_SND_PCM_FMT_S24)
-        | (1 << VIRTIO_SND_PCM_FMT_S32);
+        | (1 << VIRTIO_SND_PCM_FMT_S32)
+        | (1 << VIRTIO_SND_PCM_FMT_FLOAT);
 
     pub const SUPPORTED_RATES: u64 = (1 << VIRTIO_SND_PCM_RATE_8000)
         | (1 << VIRTIO_SND_PCM_RATE_11025)
@@ -44,10 +46,14 @@ mod defs {
         | (1 << VIRTIO_SND_PCM_RATE_22050)
         | (1 << VIRTIO_SND_PCM_RATE_32000)
         | (1 << VIRTIO_SND_PCM_RATE_44100)
-        | (1 << VIRTIO_SND_PCM_RATE_48000);
+        | (1 << VIRTIO_SND_PCM_RATE_48000)
+        | (1 << VIRTIO_SND_PCM_RATE_88200)
+        | (1 << VIRTIO_SND_PCM_RATE_96000)
+        | (1 << VIRTIO_SND_PCM_RATE_192000);
 
     pub mod uapi {
         pub const VIRTIO_F_VERSION_1: u32 = 32;
+        pub const VIRTIO_F_RING_PACKED: u32 = 34;
         pub const VIRTIO_ID_SND: u32 = 25;
     }
 }
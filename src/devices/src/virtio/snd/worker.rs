@@ -11,6 +11,7 @@ use vm_memory::{ByteValued, Bytes, GuestMemoryMmap};
 use super::super::Queue;
 use super::audio_backends::{alloc_audio_backend, AudioBackend};
 use super::defs::{CTL_INDEX, EVT_INDEX, QUEUE_INDEXES, RXQ_INDEX, TXQ_INDEX};
+use super::jack::Jacks;
 use super::stream::{Error as StreamError, Stream};
 use super::virtio_sound::{
     VirtioSndPcmSetParams, VirtioSoundHeader, VirtioSoundPcmHeader, VirtioSoundPcmInfo,
@@ -19,7 +20,7 @@ use super::virtio_sound::{
     VIRTIO_SND_S_OK,
 };
 use super::{
-    BackendType, Direction, Error, VirtioSoundChmapInfo, VirtioSoundJackInfo, Vring,
+    BackendType, Direction, Error, VirtioSoundChmapInfo, VirtioSoundEvent, Vring,
     VIRTIO_SND_CHMAP_FL, VIRTIO_SND_CHMAP_FR, VIRTIO_SND_CHMAP_MAX_SIZE, VIRTIO_SND_CHMAP_NONE,
 };
 use crate::virtio::snd::stream::Buffer;
@@ -34,7 +35,7 @@ pub struct SndWorker {
     streams: Arc<RwLock<Vec<Stream>>>,
     streams_no: usize,
     chmaps: Arc<RwLock<Vec<VirtioSoundChmapInfo>>>,
-    jacks: Arc<RwLock<Vec<VirtioSoundJackInfo>>>,
+    jacks: Arc<Jacks>,
     audio_backend: RwLock<Box<dyn AudioBackend + Send + Sync>>,
     stop_fd: EventFd,
 }
@@ -62,7 +63,7 @@ impl SndWorker {
         ];
         let streams_no = streams.len();
         let streams = Arc::new(RwLock::new(streams));
-        let jacks: Arc<RwLock<Vec<VirtioSoundJackInfo>>> = Arc::new(RwLock::new(Vec::new()));
+        let jacks = Arc::new(Jacks::default());
         let mut positions = [VIRTIO_SND_CHMAP_NONE; VIRTIO_SND_CHMAP_MAX_SIZE];
         positions[0] = VIRTIO_SND_CHMAP_FL;
         positions[1] = VIRTIO_SND_CHMAP_FR;
@@ -82,8 +83,9 @@ impl SndWorker {
         ];
         let chmaps: Arc<RwLock<Vec<VirtioSoundChmapInfo>>> = Arc::new(RwLock::new(chmaps_info));
 
-        let audio_backend =
-            RwLock::new(alloc_audio_backend(BackendType::Pipewire, streams.clone()).unwrap());
+        let audio_backend = RwLock::new(
+            alloc_audio_backend(BackendType::Pipewire, streams.clone(), jacks.clone()).unwrap(),
+        );
 
         let mut vrings: Vec<Arc<Mutex<Vring>>> = Vec::new();
 
@@ -324,7 +326,7 @@ impl SndWorker {
 
                 let start_id = u32::from(request.start_id) as usize;
                 let count = u32::from(request.count) as usize;
-                let jacks = self.jacks.read().unwrap();
+                let jacks = self.jacks.info();
                 if jacks.len() <= start_id || jacks.len() < start_id + count {
                     resp.code = VIRTIO_SND_S_BAD_MSG.into();
                 } else {
@@ -524,10 +526,20 @@ impl SndWorker {
 
     fn process_evt(
         &self,
-        _vring_lock: &Arc<Mutex<Vring>>,
-        _head: DescriptorChain,
+        vring_lock: &Arc<Mutex<Vring>>,
+        head: DescriptorChain,
     ) -> result::Result<(), Error> {
-        error!("virtio_snd: unimplemented process_evt");
+        if !head.is_write_only() {
+            return Err(Error::UnexpectedReadableDescriptor(0));
+        }
+        if (head.len as usize) < size_of::<VirtioSoundEvent>() {
+            return Err(Error::UnexpectedDescriptorSize(
+                size_of::<VirtioSoundEvent>(),
+                head.len,
+            ));
+        }
+        // Kept until there's a jack event to report.
+        self.jacks.add_buffer(vring_lock, head.index, head.addr);
         Ok(())
     }
 
@@ -0,0 +1,266 @@
+//! Harness driving virtio devices from tests the way a guest driver would,
+//! so their request paths can be tested without booting one.
+//!
+//! `TestQueue` lays out a split queue in guest memory, builds descriptor
+//! chains pointing to buffers it allocates right after it, makes them
+//! available, and reads back what the device used. `TestInterrupt` records
+//! the interrupts a device raises.
+//!
+//! Built for the tests of this crate, and for those of other crates with the
+//! `test_utils` feature.
+
+use std::num::Wrapping;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use virtio_bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::{InterruptTransport, Queue, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING};
+use crate::legacy::DummyIrqChip;
+
+/// Size of the memory returned by `guest_memory`.
+pub const MEM_SIZE: usize = 0x100000;
+
+/// Guest memory of `MEM_SIZE` bytes, starting at 0.
+pub fn guest_memory() -> GuestMemoryMmap {
+    GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap()
+}
+
+/// A buffer of a descriptor chain.
+pub enum Buffer<'a> {
+    /// Holding the given data, for the device to read.
+    Readable(&'a [u8]),
+    /// Of the given length, for the device to write.
+    Writable(u32),
+}
+
+/// An element of the used ring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Used {
+    pub head: u16,
+    pub len: u32,
+}
+
+/// The driver side of a split queue.
+pub struct TestQueue<'a> {
+    mem: &'a GuestMemoryMmap,
+    size: u16,
+    desc_table: GuestAddress,
+    avail_ring: GuestAddress,
+    used_ring: GuestAddress,
+    next_desc: u16,
+    avail_idx: Wrapping<u16>,
+    used_idx: Wrapping<u16>,
+    next_buffer: GuestAddress,
+}
+
+impl<'a> TestQueue<'a> {
+    /// Lays out a queue of `size` elements at `start`, its buffers being
+    /// allocated right after it.
+    pub fn new(mem: &'a GuestMemoryMmap, start: GuestAddress, size: u16) -> Self {
+        assert!(size.is_power_of_two());
+        assert_eq!(start.0 % 16, 0);
+
+        let desc_table = start;
+        let avail_ring = desc_table.unchecked_add(16 * u64::from(size));
+        // flags, idx, the ring and used_event.
+        let avail_end = avail_ring.unchecked_add(6 + 2 * u64::from(size));
+        let used_ring = GuestAddress(avail_end.0.next_multiple_of(4));
+        let used_end = used_ring.unchecked_add(6 + 8 * u64::from(size));
+
+        for addr in [avail_ring, used_ring] {
+            mem.write_obj(0u32, addr).unwrap();
+        }
+        Self {
+            mem,
+            size,
+            desc_table,
+            avail_ring,
+            used_ring,
+            next_desc: 0,
+            avail_idx: Wrapping(0),
+            used_idx: Wrapping(0),
+            next_buffer: GuestAddress(used_end.0.next_multiple_of(16)),
+        }
+    }
+
+    /// Returns a queue the driver set up as described by this.
+    pub fn create_queue(&self) -> Queue {
+        let mut queue = Queue::new(self.size);
+        queue.size = self.size;
+        queue.ready = true;
+        queue.desc_table = self.desc_table;
+        queue.avail_ring = self.avail_ring;
+        queue.used_ring = self.used_ring;
+        queue
+    }
+
+    fn desc_addr(&self, index: u16) -> GuestAddress {
+        self.desc_table.unchecked_add(16 * u64::from(index))
+    }
+
+    /// Builds a chain of `buffers` and makes it available, returning the
+    /// index of its head.
+    pub fn add_chain(&mut self, buffers: &[Buffer]) -> u16 {
+        assert!(!buffers.is_empty());
+        let head = self.next_desc;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let index = self.next_desc;
+            self.next_desc = (self.next_desc + 1) % self.size;
+
+            let (len, mut flags) = match buffer {
+                Buffer::Readable(data) => {
+                    self.mem.write_slice(data, self.next_buffer).unwrap();
+                    (data.len() as u32, 0)
+                }
+                Buffer::Writable(len) => (*len, VRING_DESC_F_WRITE as u16),
+            };
+            if i + 1 < buffers.len() {
+                flags |= VRING_DESC_F_NEXT as u16;
+            }
+
+            let desc = self.desc_addr(index);
+            self.mem.write_obj(self.next_buffer.0, desc).unwrap();
+            self.mem.write_obj(len, desc.unchecked_add(8)).unwrap();
+            self.mem.write_obj(flags, desc.unchecked_add(12)).unwrap();
+            self.mem
+                .write_obj(self.next_desc, desc.unchecked_add(14))
+                .unwrap();
+            self.next_buffer =
+                GuestAddress((self.next_buffer.0 + u64::from(len)).next_multiple_of(16));
+            assert!(self.next_buffer.0 <= MEM_SIZE as u64);
+        }
+
+        let slot = u64::from(self.avail_idx.0 % self.size);
+        self.mem
+            .write_obj(head, self.avail_ring.unchecked_add(4 + 2 * slot))
+            .unwrap();
+        self.avail_idx += 1;
+        self.mem
+            .write_obj(self.avail_idx.0, self.avail_ring.unchecked_add(2))
+            .unwrap();
+        head
+    }
+
+    /// Returns the elements the device added to the used ring since the last
+    /// call.
+    pub fn used(&mut self) -> Vec<Used> {
+        let idx: u16 = self.mem.read_obj(self.used_ring.unchecked_add(2)).unwrap();
+        let mut used = Vec::new();
+        while self.used_idx.0 != idx {
+            let elem = self
+                .used_ring
+                .unchecked_add(4 + 8 * u64::from(self.used_idx.0 % self.size));
+            let head: u32 = self.mem.read_obj(elem).unwrap();
+            let len = self.mem.read_obj(elem.unchecked_add(4)).unwrap();
+            used.push(Used {
+                head: head as u16,
+                len,
+            });
+            self.used_idx += 1;
+        }
+        used
+    }
+
+    /// Returns the first `len` bytes written to the writable buffers of the
+    /// chain starting at `head`.
+    pub fn written(&self, head: u16, len: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut index = head;
+        loop {
+            let desc = self.desc_addr(index);
+            let addr: u64 = self.mem.read_obj(desc).unwrap();
+            let desc_len: u32 = self.mem.read_obj(desc.unchecked_add(8)).unwrap();
+            let flags: u16 = self.mem.read_obj(desc.unchecked_add(12)).unwrap();
+            if flags & VRING_DESC_F_WRITE as u16 != 0 {
+                let mut buf = vec![0u8; desc_len.min(len - data.len() as u32) as usize];
+                self.mem.read_slice(&mut buf, GuestAddress(addr)).unwrap();
+                data.extend(buf);
+            }
+            if flags & VRING_DESC_F_NEXT as u16 == 0 || data.len() == len as usize {
+                return data;
+            }
+            index = self.mem.read_obj(desc.unchecked_add(14)).unwrap();
+        }
+    }
+}
+
+/// Records the interrupts raised through its transport.
+pub struct TestInterrupt {
+    transport: InterruptTransport,
+}
+
+impl TestInterrupt {
+    pub fn new() -> Self {
+        let transport =
+            InterruptTransport::new(DummyIrqChip::new().into(), "test".into(), Arc::default())
+                .unwrap();
+        Self { transport }
+    }
+
+    /// The transport to activate the device with.
+    pub fn transport(&self) -> InterruptTransport {
+        self.transport.clone()
+    }
+
+    fn take(&self, status: u32) -> bool {
+        let previous = self
+            .transport
+            .status()
+            .fetch_and(!(status as usize), Ordering::SeqCst);
+        previous & status as usize != 0
+    }
+
+    /// Whether the device signaled used buffers since the last call.
+    pub fn take_used_queue(&self) -> bool {
+        self.take(VIRTIO_MMIO_INT_VRING)
+    }
+
+    /// Whether the device signaled a config change since the last call.
+    pub fn take_config_change(&self) -> bool {
+        self.take(VIRTIO_MMIO_INT_CONFIG)
+    }
+}
+
+impl Default for TestInterrupt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue() {
+        let mem = guest_memory();
+        let mut tq = TestQueue::new(&mem, GuestAddress(0), 16);
+        let mut queue = tq.create_queue();
+        assert!(queue.is_valid(&mem));
+
+        let head = tq.add_chain(&[Buffer::Readable(b"request"), Buffer::Writable(8)]);
+        let chain = queue.pop(&mem).unwrap();
+        assert_eq!(chain.index, head);
+        assert!(!chain.is_write_only());
+        let mut request = [0u8; 7];
+        mem.read_slice(&mut request, chain.addr).unwrap();
+        assert_eq!(&request, b"request");
+        let response = chain.next_descriptor().unwrap();
+        assert!(response.is_write_only());
+        mem.write_slice(b"response", response.addr).unwrap();
+        queue.add_used(&mem, head, 6).unwrap();
+        assert!(queue.pop(&mem).is_none());
+
+        assert_eq!(tq.used(), [Used { head, len: 6 }]);
+        assert!(tq.used().is_empty());
+        assert_eq!(tq.written(head, 6), b"respon");
+
+        let interrupt = TestInterrupt::new();
+        interrupt.transport().signal_used_queue();
+        assert!(interrupt.take_used_queue());
+        assert!(!interrupt.take_used_queue());
+        assert!(!interrupt.take_config_change());
+    }
+}
@@ -0,0 +1,286 @@
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use utils::eventfd::EventFd;
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use super::super::{ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, VirtioDevice};
+use super::vhost::*;
+use super::{Result, VdpaError};
+use crate::virtio::{ConfigLayout, InterruptTransport};
+
+const VIRTIO_F_ACCESS_PLATFORM: u32 = 33;
+const VIRTIO_F_RING_PACKED: u32 = 34;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+
+pub struct Vdpa {
+    id: String,
+    file: File,
+    device_id: u32,
+    device_features: u64,
+    config_size: u32,
+    /// Whether guest memory is already mapped in the IOTLB. The mappings
+    /// outlive device resets.
+    mapped: bool,
+    pub(crate) queues: Vec<VirtQueue>,
+    pub(crate) queue_events: Vec<EventFd>,
+    pub(crate) call_events: Vec<EventFd>,
+    pub(crate) config_evt: EventFd,
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    pub(crate) activate_evt: EventFd,
+    pub(crate) device_state: DeviceState,
+}
+
+impl Vdpa {
+    /// Opens the vhost-vdpa device at `path` (usually `/dev/vhost-vdpa-N`).
+    pub fn new(id: String, path: &Path) -> Result<Vdpa> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(VdpaError::Open)?;
+        let fd = file.as_raw_fd();
+
+        let mut device_id = 0u32;
+        let mut device_features = 0u64;
+        let mut backend_features = 0u64;
+        let mut vqs_count = 0u32;
+        let mut vring_num = 0u16;
+        let mut config_size = 0u32;
+        // SAFETY: fd is a valid vhost-vdpa device and all the pointers are
+        // valid for the duration of the calls.
+        unsafe {
+            vhost_set_owner(fd).map_err(|e| VdpaError::Vhost("SET_OWNER", e))?;
+            vhost_get_backend_features(fd, &mut backend_features)
+                .map_err(|e| VdpaError::Vhost("GET_BACKEND_FEATURES", e))?;
+            if backend_features & VHOST_BACKEND_F_IOTLB_MSG_V2 == 0 {
+                return Err(VdpaError::Vhost(
+                    "GET_BACKEND_FEATURES",
+                    nix::Error::ENOTSUP,
+                ));
+            }
+            vhost_set_backend_features(fd, &VHOST_BACKEND_F_IOTLB_MSG_V2)
+                .map_err(|e| VdpaError::Vhost("SET_BACKEND_FEATURES", e))?;
+            vhost_vdpa_set_status(fd, &0).map_err(|e| VdpaError::Vhost("SET_STATUS", e))?;
+            vhost_vdpa_get_device_id(fd, &mut device_id)
+                .map_err(|e| VdpaError::Vhost("GET_DEVICE_ID", e))?;
+            vhost_get_features(fd, &mut device_features)
+                .map_err(|e| VdpaError::Vhost("GET_FEATURES", e))?;
+            vhost_vdpa_get_vqs_count(fd, &mut vqs_count)
+                .map_err(|e| VdpaError::Vhost("GET_VQS_COUNT", e))?;
+            vhost_vdpa_get_vring_num(fd, &mut vring_num)
+                .map_err(|e| VdpaError::Vhost("GET_VRING_NUM", e))?;
+            vhost_vdpa_get_config_size(fd, &mut config_size)
+                .map_err(|e| VdpaError::Vhost("GET_CONFIG_SIZE", e))?;
+        }
+
+        let mut queues = Vec::new();
+        let mut queue_events = Vec::new();
+        let mut call_events = Vec::new();
+        for _ in 0..vqs_count {
+            queues.push(VirtQueue::new(vring_num));
+            queue_events
+                .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(VdpaError::EventFd)?);
+            call_events
+                .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(VdpaError::EventFd)?);
+        }
+
+        // The guest sees guest physical addresses as IOVAs, and we don't
+        // emulate packed rings on the transport.
+        let avail_features = device_features
+            & !((1 << VIRTIO_F_ACCESS_PLATFORM as u64) | (1 << VIRTIO_F_RING_PACKED as u64));
+
+        Ok(Vdpa {
+            id,
+            file,
+            device_id,
+            device_features,
+            config_size,
+            mapped: false,
+            queues,
+            queue_events,
+            call_events,
+            config_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(VdpaError::EventFd)?,
+            avail_features,
+            acked_features: 0,
+            activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(VdpaError::EventFd)?,
+            device_state: DeviceState::Inactive,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn set_status(&self, status: u8) -> nix::Result<()> {
+        // SAFETY: the fd is valid and the pointer is valid for the call.
+        unsafe { vhost_vdpa_set_status(self.file.as_raw_fd(), &status) }.map(|_| ())
+    }
+
+    fn setup(
+        &mut self,
+        mem: &GuestMemoryMmap,
+    ) -> std::result::Result<(), (&'static str, nix::Error)> {
+        let fd = self.file.as_raw_fd();
+
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER)
+            .map_err(|e| ("SET_STATUS", e))?;
+        let features =
+            self.acked_features | (self.device_features & (1 << VIRTIO_F_ACCESS_PLATFORM as u64));
+        // SAFETY: the fd is valid and the pointer is valid for the call.
+        unsafe { vhost_set_features(fd, &features) }.map_err(|e| ("SET_FEATURES", e))?;
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK)
+            .map_err(|e| ("SET_STATUS", e))?;
+
+        if !self.mapped {
+            for region in mem.iter() {
+                let host_addr = mem.get_host_address(region.start_addr()).unwrap();
+                iotlb_update(
+                    &mut self.file,
+                    region.start_addr().0,
+                    region.len(),
+                    host_addr as u64,
+                )
+                .map_err(|e| ("IOTLB_UPDATE", e))?;
+            }
+            self.mapped = true;
+        }
+
+        for (index, queue) in self.queues.iter().enumerate() {
+            if !queue.ready {
+                continue;
+            }
+            let index = index as u32;
+            let num = VhostVringState {
+                index,
+                num: queue.actual_size() as u32,
+            };
+            let base = VhostVringState { index, num: 0 };
+            let addr = VhostVringAddr {
+                index,
+                desc_user_addr: queue.desc_table.0,
+                used_user_addr: queue.used_ring.0,
+                avail_user_addr: queue.avail_ring.0,
+                ..Default::default()
+            };
+            let kick = VhostVringFile {
+                index,
+                fd: self.queue_events[index as usize].as_raw_fd(),
+            };
+            let call = VhostVringFile {
+                index,
+                fd: self.call_events[index as usize].as_raw_fd(),
+            };
+            let enable = VhostVringState { index, num: 1 };
+            // SAFETY: the fd is valid and the pointers are valid for the
+            // duration of the calls.
+            unsafe {
+                vhost_set_vring_num(fd, &num).map_err(|e| ("SET_VRING_NUM", e))?;
+                vhost_set_vring_base(fd, &base).map_err(|e| ("SET_VRING_BASE", e))?;
+                vhost_set_vring_addr(fd, &addr).map_err(|e| ("SET_VRING_ADDR", e))?;
+                vhost_set_vring_kick(fd, &kick).map_err(|e| ("SET_VRING_KICK", e))?;
+                vhost_set_vring_call(fd, &call).map_err(|e| ("SET_VRING_CALL", e))?;
+                vhost_vdpa_set_vring_enable(fd, &enable).map_err(|e| ("SET_VRING_ENABLE", e))?;
+            }
+        }
+
+        let config_fd = self.config_evt.as_raw_fd();
+        // SAFETY: the fd is valid and the pointer is valid for the call.
+        unsafe { vhost_vdpa_set_config_call(fd, &config_fd) }
+            .map_err(|e| ("SET_CONFIG_CALL", e))?;
+
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK)
+            .map_err(|e| ("SET_STATUS", e))
+    }
+}
+
+impl VirtioDevice for Vdpa {
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features
+    }
+
+    fn device_type(&self) -> u32 {
+        self.device_id
+    }
+
+    fn device_name(&self) -> &str {
+        "vdpa"
+    }
+
+    fn queues(&self) -> &[VirtQueue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [VirtQueue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_events
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let layout = ConfigLayout::opaque("vdpa", self.config_size as usize);
+        let Some(range) = layout.access(offset, data.len()) else {
+            data.fill(0);
+            return;
+        };
+        if let Err(e) = get_config(&self.file, range.start as u32, data) {
+            error!("vdpa: failed to read device config: {e}");
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let layout = ConfigLayout::opaque("vdpa", self.config_size as usize);
+        let Some(range) = layout.access(offset, data.len()) else {
+            return;
+        };
+        if let Err(e) = set_config(&self.file, range.start as u32, data) {
+            error!("vdpa: failed to write device config: {e}");
+        }
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
+        if let Err((op, e)) = self.setup(&mem) {
+            error!("vdpa: failed to set up the device, {op}: {e}");
+            return Err(ActivateError::BadActivate);
+        }
+
+        if self.activate_evt.write(1).is_err() {
+            error!("Cannot write to activate_evt",);
+            return Err(ActivateError::BadActivate);
+        }
+
+        self.device_state = DeviceState::Activated(mem, interrupt);
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn reset(&mut self) -> bool {
+        // The call and config eventfds stay registered, so we can be
+        // activated again right away.
+        if let Err(e) = self.set_status(0) {
+            error!("vdpa: failed to reset the device: {e}");
+            return false;
+        }
+        true
+    }
+}
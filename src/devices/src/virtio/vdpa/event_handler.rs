@@ -0,0 +1,97 @@
+use std::os::unix::io::AsRawFd;
+
+use polly::event_manager::{EventManager, Subscriber};
+use utils::epoll::{EpollEvent, EventSet};
+
+use super::device::Vdpa;
+use crate::virtio::device::VirtioDevice;
+use crate::virtio::DeviceState;
+
+impl Vdpa {
+    fn handle_call_event(&self, index: usize) {
+        if let Err(e) = self.call_events[index].read() {
+            error!("Failed to read vdpa call event: {e:?}");
+        } else {
+            self.device_state.signal_used_queue();
+        }
+    }
+
+    fn handle_config_event(&self) {
+        if let Err(e) = self.config_evt.read() {
+            error!("Failed to read vdpa config event: {e:?}");
+        } else if let DeviceState::Activated(_, ref interrupt) = self.device_state {
+            interrupt.signal_config_change();
+        }
+    }
+
+    fn handle_activate_event(&self, event_manager: &mut EventManager) {
+        debug!("vdpa: activate event");
+        if let Err(e) = self.activate_evt.read() {
+            error!("Failed to consume vdpa activate event: {e:?}");
+        }
+
+        // The subscriber must exist as we previously registered activate_evt via
+        // `interest_list()`.
+        let self_subscriber = event_manager
+            .subscriber(self.activate_evt.as_raw_fd())
+            .unwrap();
+
+        for fd in self
+            .call_events
+            .iter()
+            .chain(Some(&self.config_evt))
+            .map(|evt| evt.as_raw_fd())
+        {
+            event_manager
+                .register(
+                    fd,
+                    EpollEvent::new(EventSet::IN, fd as u64),
+                    self_subscriber.clone(),
+                )
+                .unwrap_or_else(|e| {
+                    error!("Failed to register vdpa event with event manager: {e:?}");
+                });
+        }
+
+        event_manager
+            .unregister(self.activate_evt.as_raw_fd())
+            .unwrap_or_else(|e| {
+                error!("Failed to unregister vdpa activate evt: {e:?}");
+            })
+    }
+}
+
+impl Subscriber for Vdpa {
+    fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
+        let source = event.fd();
+        let config = self.config_evt.as_raw_fd();
+        let activate_evt = self.activate_evt.as_raw_fd();
+
+        if self.is_activated() {
+            if let Some(index) = self
+                .call_events
+                .iter()
+                .position(|evt| evt.as_raw_fd() == source)
+            {
+                self.handle_call_event(index);
+                return;
+            }
+            match source {
+                _ if source == config => self.handle_config_event(),
+                _ if source == activate_evt => {
+                    self.handle_activate_event(event_manager);
+                }
+                _ => warn!("Unexpected vdpa event received: {source:?}"),
+            }
+        } else {
+            warn!("vdpa: The device is not yet activated. Spurious event received: {source:?}");
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(
+            EventSet::IN,
+            self.activate_evt.as_raw_fd() as u64,
+        )]
+    }
+}
@@ -0,0 +1,24 @@
+//! Virtio devices backed by a vhost-vdpa character device.
+//!
+//! The data path is handled entirely by the vDPA device: the guest kicks it
+//! directly through the queue ioeventfds, and it accesses guest memory
+//! through the IOTLB mappings we set up on activation. We only need to relay
+//! the configuration space and the used queue and config change interrupts.
+
+mod device;
+mod event_handler;
+mod vhost;
+
+pub use self::device::Vdpa;
+
+#[derive(Debug)]
+pub enum VdpaError {
+    /// Failed to create event fd.
+    EventFd(std::io::Error),
+    /// Failed to open the vhost-vdpa device.
+    Open(std::io::Error),
+    /// A vhost ioctl on the device failed.
+    Vhost(&'static str, nix::Error),
+}
+
+type Result<T> = std::result::Result<T, VdpaError>;
@@ -0,0 +1,167 @@
+//! Bindings for the vhost-vdpa ioctls, from linux/vhost.h and
+//! linux/vhost_types.h.
+
+use std::io::Write;
+use std::mem;
+use std::os::fd::AsRawFd;
+
+use nix::errno::Errno;
+use nix::{ioctl_none, ioctl_read, ioctl_write_ptr};
+
+const VHOST_VIRTIO: u8 = 0xAF;
+
+pub const VHOST_BACKEND_F_IOTLB_MSG_V2: u64 = 1 << 0x1;
+
+const VHOST_IOTLB_MSG_V2: u32 = 0x2;
+const VHOST_IOTLB_UPDATE: u8 = 2;
+const VHOST_ACCESS_RW: u8 = 0x3;
+
+#[repr(C)]
+#[derive(Default)]
+pub struct VhostVringState {
+    pub index: u32,
+    pub num: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct VhostVringFile {
+    pub index: u32,
+    pub fd: i32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct VhostVringAddr {
+    pub index: u32,
+    pub flags: u32,
+    pub desc_user_addr: u64,
+    pub used_user_addr: u64,
+    pub avail_user_addr: u64,
+    pub log_guest_addr: u64,
+}
+
+/// Header of `struct vhost_vdpa_config`, followed by `len` bytes of data.
+#[repr(C)]
+struct VhostVdpaConfig {
+    off: u32,
+    len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VhostIotlbMsg {
+    iova: u64,
+    size: u64,
+    uaddr: u64,
+    perm: u8,
+    type_: u8,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VhostMsgV2 {
+    type_: u32,
+    asid: u32,
+    iotlb: VhostIotlbMsg,
+    padding: [u8; 32],
+}
+
+ioctl_read!(vhost_get_features, VHOST_VIRTIO, 0x00, u64);
+ioctl_write_ptr!(vhost_set_features, VHOST_VIRTIO, 0x00, u64);
+ioctl_none!(vhost_set_owner, VHOST_VIRTIO, 0x01);
+ioctl_write_ptr!(vhost_set_vring_num, VHOST_VIRTIO, 0x10, VhostVringState);
+ioctl_write_ptr!(vhost_set_vring_addr, VHOST_VIRTIO, 0x11, VhostVringAddr);
+ioctl_write_ptr!(vhost_set_vring_base, VHOST_VIRTIO, 0x12, VhostVringState);
+ioctl_write_ptr!(vhost_set_vring_kick, VHOST_VIRTIO, 0x20, VhostVringFile);
+ioctl_write_ptr!(vhost_set_vring_call, VHOST_VIRTIO, 0x21, VhostVringFile);
+ioctl_write_ptr!(vhost_set_backend_features, VHOST_VIRTIO, 0x25, u64);
+ioctl_read!(vhost_get_backend_features, VHOST_VIRTIO, 0x26, u64);
+ioctl_read!(vhost_vdpa_get_device_id, VHOST_VIRTIO, 0x70, u32);
+ioctl_write_ptr!(vhost_vdpa_set_status, VHOST_VIRTIO, 0x72, u8);
+ioctl_read!(vhost_vdpa_get_config, VHOST_VIRTIO, 0x73, VhostVdpaConfig);
+ioctl_write_ptr!(vhost_vdpa_set_config, VHOST_VIRTIO, 0x74, VhostVdpaConfig);
+ioctl_write_ptr!(
+    vhost_vdpa_set_vring_enable,
+    VHOST_VIRTIO,
+    0x75,
+    VhostVringState
+);
+ioctl_read!(vhost_vdpa_get_vring_num, VHOST_VIRTIO, 0x76, u16);
+ioctl_write_ptr!(vhost_vdpa_set_config_call, VHOST_VIRTIO, 0x77, i32);
+ioctl_read!(vhost_vdpa_get_config_size, VHOST_VIRTIO, 0x79, u32);
+ioctl_read!(vhost_vdpa_get_vqs_count, VHOST_VIRTIO, 0x80, u32);
+
+/// Copies `data.len()` bytes of the configuration space of the device,
+/// starting at `offset`, into `data`.
+pub fn get_config(fd: &impl AsRawFd, offset: u32, data: &mut [u8]) -> nix::Result<()> {
+    // Backed by u32s to keep the header aligned.
+    let mut buf = vec![0u32; (mem::size_of::<VhostVdpaConfig>() + data.len()).div_ceil(4)];
+    let hdr = buf.as_mut_ptr() as *mut VhostVdpaConfig;
+    // SAFETY: buf is large enough for the header and the data, and the
+    // kernel won't write more than `len` bytes past the header.
+    unsafe {
+        (*hdr).off = offset;
+        (*hdr).len = data.len() as u32;
+        vhost_vdpa_get_config(fd.as_raw_fd(), hdr)?;
+        let src = (hdr as *const u8).add(mem::size_of::<VhostVdpaConfig>());
+        std::ptr::copy_nonoverlapping(src, data.as_mut_ptr(), data.len());
+    }
+    Ok(())
+}
+
+/// Writes `data` to the configuration space of the device at `offset`.
+pub fn set_config(fd: &impl AsRawFd, offset: u32, data: &[u8]) -> nix::Result<()> {
+    let mut buf = vec![0u32; (mem::size_of::<VhostVdpaConfig>() + data.len()).div_ceil(4)];
+    let hdr = buf.as_mut_ptr() as *mut VhostVdpaConfig;
+    // SAFETY: buf is large enough for the header and the data.
+    unsafe {
+        (*hdr).off = offset;
+        (*hdr).len = data.len() as u32;
+        let dst = (hdr as *mut u8).add(mem::size_of::<VhostVdpaConfig>());
+        std::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+        vhost_vdpa_set_config(fd.as_raw_fd(), hdr)?;
+    }
+    Ok(())
+}
+
+/// Maps `size` bytes of host memory at `uaddr` at `iova` in the IOTLB of the
+/// device.
+pub fn iotlb_update(file: &mut impl Write, iova: u64, size: u64, uaddr: u64) -> nix::Result<()> {
+    let msg = VhostMsgV2 {
+        type_: VHOST_IOTLB_MSG_V2,
+        iotlb: VhostIotlbMsg {
+            iova,
+            size,
+            uaddr,
+            perm: VHOST_ACCESS_RW,
+            type_: VHOST_IOTLB_UPDATE,
+        },
+        ..Default::default()
+    };
+    // SAFETY: VhostMsgV2 is a plain C struct, valid for reads of its size.
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            &msg as *const VhostMsgV2 as *const u8,
+            mem::size_of::<VhostMsgV2>(),
+        )
+    };
+    file.write_all(bytes)
+        .map_err(|e| Errno::from_raw(e.raw_os_error().unwrap_or(libc::EIO)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_struct_sizes() {
+        // These must match the kernel ABI, since they're part of the
+        // request codes or read by the kernel as is.
+        assert_eq!(mem::size_of::<VhostVringState>(), 8);
+        assert_eq!(mem::size_of::<VhostVringFile>(), 8);
+        assert_eq!(mem::size_of::<VhostVringAddr>(), 40);
+        assert_eq!(mem::size_of::<VhostVdpaConfig>(), 8);
+        assert_eq!(mem::size_of::<VhostMsgV2>(), 72);
+    }
+}
@@ -6,20 +6,23 @@
 // found in the THIRD-PARTY file.
 
 use std::collections::HashMap;
+use std::os::fd::OwnedFd;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
-use utils::byte_order;
 use utils::eventfd::EventFd;
+use utils::metrics::Metrics;
 use vm_memory::GuestMemoryMmap;
 
 use super::super::{
     ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, VirtioDevice, VsockError,
 };
+use super::flows::FlowTable;
+use super::http_proxy::HttpProxyConfig;
 use super::muxer::VsockMuxer;
 use super::packet::VsockPacket;
 use super::{defs, defs::uapi};
-use crate::virtio::InterruptTransport;
+use crate::virtio::{ConfigLayout, InterruptTransport};
 
 pub(crate) const RXQ_INDEX: usize = 0;
 pub(crate) const TXQ_INDEX: usize = 1;
@@ -29,10 +32,15 @@ pub(crate) const EVQ_INDEX: usize = 2;
 /// - VIRTIO_F_VERSION_1: the device conforms to at least version 1.0 of the VirtIO spec.
 /// - VIRTIO_F_IN_ORDER: the device returns used buffers in the same order that the driver makes
 ///   them available.
+/// - VIRTIO_F_RING_PACKED: the device supports the packed virtqueue layout.
 pub(crate) const AVAIL_FEATURES: u64 = (1 << uapi::VIRTIO_F_VERSION_1 as u64)
     | (1 << uapi::VIRTIO_F_IN_ORDER as u64)
+    | (1 << uapi::VIRTIO_F_RING_PACKED as u64)
     | (1 << uapi::VIRTIO_VSOCK_F_DGRAM);
 
+// The guest_cid field of virtio_vsock_config.
+const CONFIG_LAYOUT: ConfigLayout = ConfigLayout::new("vsock", &[8]);
+
 pub struct Vsock {
     cid: u64,
     pub(crate) muxer: VsockMuxer,
@@ -50,8 +58,11 @@ impl Vsock {
     pub(crate) fn with_queues(
         cid: u64,
         host_port_map: Option<HashMap<u16, u16>>,
+        unix_socket_map: Option<HashMap<String, PathBuf>>,
+        listen_fds: HashMap<u16, OwnedFd>,
         queues: Vec<VirtQueue>,
         unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
+        sibling_dir: Option<PathBuf>,
     ) -> super::Result<Vsock> {
         let mut queue_events = Vec::new();
         for _ in 0..queues.len() {
@@ -64,7 +75,14 @@ impl Vsock {
 
         Ok(Vsock {
             cid,
-            muxer: VsockMuxer::new(cid, host_port_map, unix_ipc_port_map),
+            muxer: VsockMuxer::new(
+                cid,
+                host_port_map,
+                unix_socket_map,
+                listen_fds,
+                unix_ipc_port_map,
+                sibling_dir,
+            ),
             queue_rx,
             queue_tx,
             queues,
@@ -77,30 +95,65 @@ impl Vsock {
         })
     }
 
-    /// Create a new virtio-vsock device with the given VM CID.
+    /// Create a new virtio-vsock device with the given VM CID. If `sibling_dir`
+    /// is set, stream connections to other CIDs are routed to the microVMs
+    /// sharing that directory. The guest UNIX sockets listening on the names
+    /// in `unix_socket_map` are exposed on the host paths they're mapped to.
+    /// The guest listening on a TCP port in `listen_fds` accepts the
+    /// connections of the socket it's mapped to, already bound by the caller.
     pub fn new(
         cid: u64,
         host_port_map: Option<HashMap<u16, u16>>,
+        unix_socket_map: Option<HashMap<String, PathBuf>>,
+        listen_fds: HashMap<u16, OwnedFd>,
         unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
+        sibling_dir: Option<PathBuf>,
     ) -> super::Result<Vsock> {
         let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
             .iter()
             .map(|&max_size| VirtQueue::new(max_size))
             .collect();
-        Self::with_queues(cid, host_port_map, queues, unix_ipc_port_map)
+        Self::with_queues(
+            cid,
+            host_port_map,
+            unix_socket_map,
+            listen_fds,
+            queues,
+            unix_ipc_port_map,
+            sibling_dir,
+        )
     }
 
     pub fn id(&self) -> &str {
         defs::VSOCK_DEV_ID
     }
 
+    /// Routes the TCP connections of the guest to the ports of `http_proxy`
+    /// through it.
+    pub fn set_http_proxy(&mut self, http_proxy: HttpProxyConfig) {
+        self.muxer.set_http_proxy(http_proxy);
+    }
+
+    /// Sets the metrics of the microVM, where the TSI connection statistics
+    /// are counted.
+    /// NOTE: Can only be called when the device is not activated
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.muxer.set_metrics(metrics);
+    }
+
     pub fn cid(&self) -> u64 {
         self.cid
     }
 
+    /// The flows proxied by TSI for this device.
+    pub fn flow_table(&self) -> FlowTable {
+        self.muxer.flow_table()
+    }
+
     /// Walk the driver-provided RX queue buffers and attempt to fill them up with any data that we
     /// have pending. Return `true` if descriptors have been added to the used ring, and `false`
     /// otherwise.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     pub fn process_stream_rx(&mut self) -> bool {
         debug!("vsock: process_stream_rx()");
         let mem = match self.device_state {
@@ -144,6 +197,7 @@ impl Vsock {
 
     /// Walk the driver-provided TX queue buffers, package them up as vsock packets, and process
     /// them. Return `true` if descriptors have been added to the used ring, and `false` otherwise.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     pub fn process_stream_tx(&mut self) -> bool {
         debug!("vsock::process_stream_tx()");
         let mem = match self.device_state {
@@ -226,28 +280,11 @@ impl VirtioDevice for Vsock {
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
-        match offset {
-            0 if data.len() == 8 => byte_order::write_le_u64(data, self.cid()),
-            0 if data.len() == 4 => {
-                byte_order::write_le_u32(data, (self.cid() & 0xffff_ffff) as u32)
-            }
-            4 if data.len() == 4 => {
-                byte_order::write_le_u32(data, ((self.cid() >> 32) & 0xffff_ffff) as u32)
-            }
-            _ => warn!(
-                "vsock: virtio-vsock received invalid read request of {} bytes at offset {}",
-                data.len(),
-                offset
-            ),
-        }
+        CONFIG_LAYOUT.read(&self.cid().to_le_bytes(), offset, data);
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        warn!(
-            "vsock: guest driver attempted to write device config (offset={:x}, len={:x})",
-            offset,
-            data.len()
-        );
+        CONFIG_LAYOUT.write_read_only(offset, data);
     }
 
     fn activate(&mut self, mem: GuestMemoryMmap, interrupt: InterruptTransport) -> ActivateResult {
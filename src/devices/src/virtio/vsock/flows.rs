@@ -0,0 +1,211 @@
+//! Table of the flows TSI is proxying, for diagnostics.
+//!
+//! Every muxer hands out a `FlowTable` of its proxies, so the state of each
+//! flow, its addresses on the host and how much data went through it can be
+//! looked up from any thread, e.g. to find out why a connection is hanging in
+//! the guest without tracing the host process.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use nix::sys::socket::{getpeername, getsockname, SockaddrStorage};
+
+use super::muxer::ProxyMap;
+use super::proxy::{Proxy, ProxyStatus};
+
+/// Counters of a flow.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlowStats {
+    /// Bytes received from the host side and handed to the guest.
+    pub rx_bytes: u64,
+    /// Bytes sent by the guest to the host side.
+    pub tx_bytes: u64,
+    pub errors: u64,
+    /// The last error, as the errno reported to the guest.
+    pub last_error: Option<i32>,
+}
+
+impl FlowStats {
+    /// Records an error, `errno` being negative as returned to the guest, or
+    /// 0 if there's none to report.
+    pub fn error(&mut self, errno: i32) {
+        self.errors += 1;
+        if errno != 0 {
+            self.last_error = Some(-errno);
+        }
+    }
+}
+
+/// State of a flow.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlowInfo {
+    pub id: u64,
+    /// "tcp", "udp", "unix", "sibling" or "unix-listener".
+    pub kind: &'static str,
+    pub status: ProxyStatus,
+    /// The vsock port of the guest end of the flow, 0 if it has none yet.
+    pub guest_port: u32,
+    /// The local address of the host socket.
+    pub local: Option<String>,
+    /// The address of the peer of the host socket.
+    pub remote: Option<String>,
+    pub stats: FlowStats,
+}
+
+impl FlowInfo {
+    /// The state of the flow `id` proxied through `socket`, whose addresses
+    /// are looked up.
+    pub fn new(
+        id: u64,
+        kind: &'static str,
+        status: ProxyStatus,
+        guest_port: u32,
+        socket: &impl AsRawFd,
+        stats: &FlowStats,
+    ) -> Self {
+        let fd = socket.as_raw_fd();
+        let addr = |addr: nix::Result<SockaddrStorage>| addr.ok().map(|addr| addr.to_string());
+        Self {
+            id,
+            kind,
+            status,
+            guest_port,
+            local: addr(getsockname(fd)),
+            remote: addr(getpeername(fd)),
+            stats: stats.clone(),
+        }
+    }
+}
+
+type WeakProxyMap = Weak<RwLock<HashMap<u64, Mutex<Box<dyn Proxy>>>>>;
+
+/// The proxies of a muxer, without keeping them alive.
+#[derive(Clone)]
+pub struct FlowTable(WeakProxyMap);
+
+impl FlowTable {
+    pub(crate) fn new(proxy_map: &ProxyMap) -> Self {
+        FlowTable(Arc::downgrade(proxy_map))
+    }
+
+    /// Returns the flows of the muxer ordered by id, or none once it's gone.
+    pub fn flows(&self) -> Vec<FlowInfo> {
+        let Some(map) = self.0.upgrade() else {
+            return Vec::new();
+        };
+
+        let mut flows: Vec<FlowInfo> = map
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|proxy| proxy.lock().unwrap().flow_info())
+            .collect();
+        flows.sort_by_key(|flow| flow.id);
+        flows
+    }
+}
+
+fn json_string(out: &mut String, value: Option<&str>) {
+    let Some(value) = value else {
+        out.push_str("null");
+        return;
+    };
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Serializes `flows` as a JSON array.
+pub fn to_json(flows: &[FlowInfo]) -> String {
+    let mut out = String::from("[");
+    for (i, flow) in flows.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"id\":{},\"type\":\"{}\",\"status\":\"{}\",\"guest_port\":{},\"local\":",
+            flow.id,
+            flow.kind,
+            flow.status.as_str(),
+            flow.guest_port
+        );
+        json_string(&mut out, flow.local.as_deref());
+        out.push_str(",\"remote\":");
+        json_string(&mut out, flow.remote.as_deref());
+        let _ = write!(
+            out,
+            ",\"rx_bytes\":{},\"tx_bytes\":{},\"errors\":{},\"last_error\":",
+            flow.stats.rx_bytes, flow.stats.tx_bytes, flow.stats.errors
+        );
+        match flow.stats.last_error {
+            Some(errno) => {
+                let _ = write!(out, "{errno}");
+            }
+            None => out.push_str("null"),
+        }
+        out.push('}');
+    }
+    out.push(']');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let mut stats = FlowStats {
+            rx_bytes: 10,
+            tx_bytes: 20,
+            ..Default::default()
+        };
+        stats.error(-libc::ECONNREFUSED);
+        stats.error(0);
+        let flows = [
+            FlowInfo {
+                id: 1,
+                kind: "tcp",
+                status: ProxyStatus::Connected,
+                guest_port: 1030,
+                local: Some("127.0.0.1:4000".into()),
+                remote: Some("10.0.0.1:80".into()),
+                stats,
+            },
+            FlowInfo {
+                id: 2,
+                kind: "unix",
+                status: ProxyStatus::Connecting,
+                guest_port: 0,
+                local: None,
+                remote: Some("/tmp/a\"b".into()),
+                stats: FlowStats::default(),
+            },
+        ];
+        assert_eq!(
+            to_json(&flows),
+            format!(
+                "[{{\"id\":1,\"type\":\"tcp\",\"status\":\"connected\",\"guest_port\":1030,\
+                 \"local\":\"127.0.0.1:4000\",\"remote\":\"10.0.0.1:80\",\"rx_bytes\":10,\
+                 \"tx_bytes\":20,\"errors\":2,\"last_error\":{}}},\
+                 {{\"id\":2,\"type\":\"unix\",\"status\":\"connecting\",\"guest_port\":0,\
+                 \"local\":null,\"remote\":\"/tmp/a\\\"b\",\"rx_bytes\":0,\"tx_bytes\":0,\
+                 \"errors\":0,\"last_error\":null}}]",
+                libc::ECONNREFUSED
+            )
+        );
+        assert_eq!(to_json(&[]), "[]");
+    }
+}
@@ -0,0 +1,158 @@
+//! Socket options set by the guest on TSI sockets, applied to the sockets
+//! proxying them on the host.
+//!
+//! The guest sends them with Linux's values for the levels and names, which
+//! are translated to the host's. Only plain integer options that change how
+//! the socket behaves on the network are passed through, the rest keep
+//! applying to the guest socket only.
+
+use std::os::fd::AsRawFd;
+
+use nix::errno::Errno;
+
+#[cfg(target_os = "macos")]
+use super::super::linux_errno::linux_errno_raw;
+use super::packet::TsiSetsockoptReq;
+
+// Levels and options, as defined by Linux.
+const LINUX_SOL_SOCKET: i32 = 1;
+const LINUX_SO_REUSEADDR: i32 = 2;
+const LINUX_SO_BROADCAST: i32 = 6;
+const LINUX_SO_KEEPALIVE: i32 = 9;
+const LINUX_SO_REUSEPORT: i32 = 15;
+
+const LINUX_IPPROTO_IP: i32 = 0;
+const LINUX_IP_TOS: i32 = 1;
+
+const LINUX_IPPROTO_TCP: i32 = 6;
+const LINUX_TCP_NODELAY: i32 = 1;
+const LINUX_TCP_KEEPIDLE: i32 = 4;
+const LINUX_TCP_KEEPINTVL: i32 = 5;
+const LINUX_TCP_KEEPCNT: i32 = 6;
+
+#[cfg(target_os = "linux")]
+const TCP_KEEPIDLE: i32 = libc::TCP_KEEPIDLE;
+#[cfg(target_os = "macos")]
+const TCP_KEEPIDLE: i32 = libc::TCP_KEEPALIVE;
+
+/// Translates the level and name of a guest option to the host's, returning
+/// `None` if it isn't passed through.
+fn host_option(level: i32, name: i32) -> Option<(i32, i32)> {
+    let name = match (level, name) {
+        (LINUX_SOL_SOCKET, LINUX_SO_REUSEADDR) => libc::SO_REUSEADDR,
+        (LINUX_SOL_SOCKET, LINUX_SO_BROADCAST) => libc::SO_BROADCAST,
+        (LINUX_SOL_SOCKET, LINUX_SO_KEEPALIVE) => libc::SO_KEEPALIVE,
+        (LINUX_SOL_SOCKET, LINUX_SO_REUSEPORT) => libc::SO_REUSEPORT,
+        (LINUX_IPPROTO_IP, LINUX_IP_TOS) => libc::IP_TOS,
+        (LINUX_IPPROTO_TCP, LINUX_TCP_NODELAY) => libc::TCP_NODELAY,
+        (LINUX_IPPROTO_TCP, LINUX_TCP_KEEPIDLE) => TCP_KEEPIDLE,
+        (LINUX_IPPROTO_TCP, LINUX_TCP_KEEPINTVL) => libc::TCP_KEEPINTVL,
+        (LINUX_IPPROTO_TCP, LINUX_TCP_KEEPCNT) => libc::TCP_KEEPCNT,
+        _ => return None,
+    };
+    let level = match level {
+        LINUX_SOL_SOCKET => libc::SOL_SOCKET,
+        LINUX_IPPROTO_IP => libc::IPPROTO_IP,
+        _ => libc::IPPROTO_TCP,
+    };
+    Some((level, name))
+}
+
+/// Applies the option of `req` to `socket`, returning 0 on success, or the
+/// negative errno to report to the guest.
+pub fn apply(socket: &impl AsRawFd, req: &TsiSetsockoptReq) -> i32 {
+    let Some((level, name)) = host_option(req.level, req.optname) else {
+        debug!(
+            "vsock: ignoring socket option: level={}, optname={}",
+            req.level, req.optname
+        );
+        return 0;
+    };
+
+    // SAFETY: the value is a plain integer, and its size is given.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &req.value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&req.value) as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        return 0;
+    }
+
+    let err = Errno::last_raw();
+    debug!(
+        "vsock: couldn't set socket option: level={}, optname={}, err={}",
+        req.level, req.optname, err
+    );
+    #[cfg(target_os = "macos")]
+    let errno = -linux_errno_raw(err);
+    #[cfg(target_os = "linux")]
+    let errno = -err;
+    errno
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    fn req(level: i32, optname: i32, value: i32) -> TsiSetsockoptReq {
+        TsiSetsockoptReq {
+            peer_port: 0,
+            local_port: 0,
+            level,
+            optname,
+            value,
+        }
+    }
+
+    fn get(socket: &impl AsRawFd, level: i32, name: i32) -> i32 {
+        let mut value: i32 = 0;
+        let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        value
+    }
+
+    #[test]
+    fn test_apply() {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        assert_eq!(
+            apply(&socket, &req(LINUX_IPPROTO_TCP, LINUX_TCP_NODELAY, 1)),
+            0
+        );
+        assert_ne!(get(&socket, libc::IPPROTO_TCP, libc::TCP_NODELAY), 0);
+        assert_eq!(
+            apply(&socket, &req(LINUX_IPPROTO_TCP, LINUX_TCP_KEEPCNT, 7)),
+            0
+        );
+        assert_eq!(get(&socket, libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 7);
+        assert_eq!(
+            apply(&socket, &req(LINUX_SOL_SOCKET, LINUX_SO_KEEPALIVE, 1)),
+            0
+        );
+        assert_ne!(get(&socket, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+
+        // Not passed through.
+        assert_eq!(apply(&socket, &req(LINUX_SOL_SOCKET, 42, 1)), 0);
+        // Rejected by the host.
+        assert_eq!(
+            apply(&socket, &req(LINUX_IPPROTO_TCP, LINUX_TCP_KEEPCNT, -1)),
+            -libc::EINVAL
+        );
+    }
+}
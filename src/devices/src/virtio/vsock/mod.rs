@@ -7,6 +7,9 @@
 
 mod device;
 mod event_handler;
+pub mod flows;
+mod guest_sockopt;
+pub mod http_proxy;
 mod muxer;
 mod muxer_rxq;
 mod muxer_thread;
@@ -14,14 +17,15 @@ mod muxer_thread;
 mod packet;
 mod proxy;
 mod reaper;
+mod sibling;
 mod tcp;
-#[cfg(target_os = "macos")]
 mod timesync;
 mod udp;
 mod unix;
 
 pub use self::defs::uapi::VIRTIO_ID_VSOCK as TYPE_VSOCK;
 pub use self::device::Vsock;
+pub use self::timesync::request_time_sync;
 
 use vm_memory::GuestMemoryError;
 
@@ -58,6 +62,12 @@ mod defs {
     pub const TSI_LISTEN: u32 = 1029;
     pub const TSI_ACCEPT: u32 = 1030;
     pub const TSI_PROXY_RELEASE: u32 = 1031;
+    /// A socket option set by the guest, applied to the host socket without
+    /// a response, as the guest keeps applying it to its own socket.
+    pub const TSI_SETSOCKOPT: u32 = 1032;
+    /// Like TSI_LISTEN, for a guest socket bound to a UNIX socket name, which
+    /// is exposed on the host path it's mapped to.
+    pub const TSI_LISTEN_UNIX: u32 = 1033;
 
     pub mod uapi {
 
@@ -69,6 +79,7 @@ mod defs {
         pub const VIRTIO_F_IN_ORDER: usize = 35;
         /// The device conforms to the virtio spec version 1.0.
         pub const VIRTIO_F_VERSION_1: u32 = 32;
+        pub const VIRTIO_F_RING_PACKED: u32 = 34;
         /// The device supports DGRAM.
         pub const VIRTIO_VSOCK_F_DGRAM: u32 = 3;
 
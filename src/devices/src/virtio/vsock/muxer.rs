@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::os::fd::OwnedFd;
 use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
@@ -6,19 +7,22 @@ use std::sync::{Arc, Mutex, RwLock};
 use super::super::Queue as VirtQueue;
 use super::defs;
 use super::defs::uapi;
+use super::flows::FlowTable;
+use super::http_proxy::HttpProxyConfig;
 use super::muxer_rxq::{rx_to_pkt, MuxerRxQ};
 use super::muxer_thread::MuxerThread;
 use super::packet::{TsiConnectReq, TsiGetnameRsp, VsockPacket};
-use super::proxy::{Proxy, ProxyRemoval, ProxyUpdate};
+use super::proxy::{Proxy, ProxyRemoval, ProxyStatus, ProxyUpdate};
 use super::reaper::ReaperThread;
+use super::sibling;
 use super::tcp::TcpProxy;
-#[cfg(target_os = "macos")]
 use super::timesync::TimesyncThread;
 use super::udp::UdpProxy;
 use super::unix::UnixProxy;
 use super::VsockError;
 use crossbeam_channel::{unbounded, Sender};
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::metrics::Metrics;
 use vm_memory::GuestMemoryMmap;
 
 use crate::virtio::InterruptTransport;
@@ -89,6 +93,7 @@ pub fn push_packet(
             }
         }
     } else {
+        queue.report_full();
         error!("couldn't push pkt to queue, adding it to rxq");
         drop(queue);
         rxq_mutex.lock().unwrap().push(rx);
@@ -98,6 +103,9 @@ pub fn push_packet(
 pub struct VsockMuxer {
     cid: u64,
     host_port_map: Option<HashMap<u16, u16>>,
+    unix_socket_map: Option<HashMap<String, PathBuf>>,
+    listen_fds: HashMap<u16, OwnedFd>,
+    http_proxy: Option<Arc<HttpProxyConfig>>,
     queue: Option<Arc<Mutex<VirtQueue>>>,
     mem: Option<GuestMemoryMmap>,
     rxq: Arc<Mutex<MuxerRxQ>>,
@@ -106,28 +114,51 @@ pub struct VsockMuxer {
     proxy_map: ProxyMap,
     reaper_sender: Option<Sender<u64>>,
     unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
+    sibling_dir: Option<PathBuf>,
+    metrics: Arc<Metrics>,
 }
 
 impl VsockMuxer {
     pub(crate) fn new(
         cid: u64,
         host_port_map: Option<HashMap<u16, u16>>,
+        unix_socket_map: Option<HashMap<String, PathBuf>>,
+        listen_fds: HashMap<u16, OwnedFd>,
         unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
+        sibling_dir: Option<PathBuf>,
     ) -> Self {
+        let proxy_map = Arc::new(RwLock::new(HashMap::new()));
         VsockMuxer {
             cid,
             host_port_map,
+            unix_socket_map,
+            listen_fds,
+            http_proxy: None,
             queue: None,
             mem: None,
             rxq: Arc::new(Mutex::new(MuxerRxQ::new())),
             epoll: Epoll::new().unwrap(),
             interrupt: None,
-            proxy_map: Arc::new(RwLock::new(HashMap::new())),
+            proxy_map,
             reaper_sender: None,
             unix_ipc_port_map,
+            sibling_dir,
+            metrics: Arc::new(Metrics::new()),
         }
     }
 
+    pub(crate) fn set_http_proxy(&mut self, http_proxy: HttpProxyConfig) {
+        self.http_proxy = Some(Arc::new(http_proxy));
+    }
+
+    pub(crate) fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    pub(crate) fn flow_table(&self) -> FlowTable {
+        FlowTable::new(&self.proxy_map)
+    }
+
     pub(crate) fn activate(
         &mut self,
         mem: GuestMemoryMmap,
@@ -138,12 +169,8 @@ impl VsockMuxer {
         self.mem = Some(mem.clone());
         self.interrupt = Some(interrupt.clone());
 
-        #[cfg(target_os = "macos")]
-        {
-            let timesync =
-                TimesyncThread::new(self.cid, mem.clone(), queue.clone(), interrupt.clone());
-            timesync.run();
-        }
+        let timesync = TimesyncThread::new(self.cid, mem.clone(), queue.clone(), interrupt.clone());
+        timesync.run();
 
         let (sender, receiver) = unbounded();
 
@@ -157,6 +184,8 @@ impl VsockMuxer {
             interrupt.clone(),
             sender.clone(),
             self.unix_ipc_port_map.clone().unwrap_or_default(),
+            self.sibling_dir.clone(),
+            self.metrics.clone(),
         );
         thread.run();
 
@@ -256,8 +285,12 @@ impl VsockMuxer {
                         mem.clone(),
                         queue.clone(),
                         self.rxq.clone(),
+                        self.metrics.clone(),
                     ) {
-                        Ok(proxy) => {
+                        Ok(mut proxy) => {
+                            if let Some(http_proxy) = &self.http_proxy {
+                                proxy.set_http_proxy(http_proxy.clone());
+                            }
                             self.proxy_map
                                 .write()
                                 .unwrap()
@@ -355,12 +388,30 @@ impl VsockMuxer {
         if let Some(req) = pkt.read_listen_req() {
             let id = ((req.peer_port as u64) << 32) | (defs::TSI_PROXY_PORT as u64);
             debug!("vsock: DGRAM listen request: id={id}");
-            let update = self
-                .proxy_map
-                .read()
-                .unwrap()
-                .get(&id)
-                .map(|proxy| proxy.lock().unwrap().listen(pkt, req, &self.host_port_map));
+            let update = self.proxy_map.read().unwrap().get(&id).map(|proxy| {
+                proxy
+                    .lock()
+                    .unwrap()
+                    .listen(pkt, req, &self.host_port_map, &self.listen_fds)
+            });
+
+            if let Some(update) = update {
+                self.process_proxy_update(id, update);
+            }
+        }
+    }
+
+    fn process_listen_unix_request(&self, pkt: &VsockPacket) {
+        debug!("vsock: DGRAM listen unix request: src={}", pkt.src_port());
+        if let Some(req) = pkt.read_listen_unix_req() {
+            let id = ((req.peer_port as u64) << 32) | (defs::TSI_PROXY_PORT as u64);
+            debug!("vsock: DGRAM listen unix request: id={id}");
+            let update = self.proxy_map.read().unwrap().get(&id).map(|proxy| {
+                proxy
+                    .lock()
+                    .unwrap()
+                    .listen_unix(pkt, req, &self.unix_socket_map)
+            });
 
             if let Some(update) = update {
                 self.process_proxy_update(id, update);
@@ -415,6 +466,20 @@ impl VsockMuxer {
         );
     }
 
+    fn process_setsockopt(&self, pkt: &VsockPacket) {
+        if let Some(req) = pkt.read_setsockopt_req() {
+            let id = ((req.peer_port as u64) << 32) | (req.local_port as u64);
+            debug!(
+                "vsock: setsockopt request: id={}, level={}, optname={}",
+                id, req.level, req.optname
+            );
+
+            if let Some(proxy) = self.proxy_map.read().unwrap().get(&id) {
+                proxy.lock().unwrap().setsockopt(&req);
+            }
+        }
+    }
+
     fn process_dgram_rw(&self, pkt: &VsockPacket) {
         debug!("vsock: DGRAM OP_RW");
         let id = ((pkt.src_port() as u64) << 32) | (defs::TSI_PROXY_PORT as u64);
@@ -453,6 +518,8 @@ impl VsockMuxer {
             defs::TSI_LISTEN => self.process_listen_request(pkt),
             defs::TSI_ACCEPT => self.process_accept_request(pkt),
             defs::TSI_PROXY_RELEASE => self.process_proxy_release(pkt),
+            defs::TSI_SETSOCKOPT => self.process_setsockopt(pkt),
+            defs::TSI_LISTEN_UNIX => self.process_listen_unix_request(pkt),
             _ => {
                 if pkt.op() == uapi::VSOCK_OP_RW {
                     self.process_dgram_rw(pkt);
@@ -474,6 +541,47 @@ impl VsockMuxer {
             if let Some(update) = proxy.lock().unwrap().confirm_connect(pkt) {
                 self.process_proxy_update(id, update);
             }
+        } else if pkt.dst_cid() != uapi::VSOCK_HOST_CID {
+            let (Some(dir), Some(mem), Some(queue)) = (&self.sibling_dir, &self.mem, &self.queue)
+            else {
+                return;
+            };
+            let mut unix = match UnixProxy::new_sibling(
+                id,
+                self.cid,
+                pkt.dst_port(),
+                pkt.src_port(),
+                pkt.dst_cid(),
+                mem.clone(),
+                queue.clone(),
+                self.rxq.clone(),
+                sibling::socket_path(dir, pkt.dst_cid()),
+            ) {
+                Ok(unix) => unix,
+                Err(e) => {
+                    debug!("error creating sibling proxy: {e:?}");
+                    return;
+                }
+            };
+            let tsi = TsiConnectReq {
+                peer_port: 0,
+                addr: Ipv4Addr::new(0, 0, 0, 0),
+                port: 0,
+            };
+            let update = unix.connect(pkt, tsi);
+            if unix.status() == ProxyStatus::Connected {
+                unix.confirm_connect(pkt);
+            } else {
+                // Nobody is listening on that CID.
+                let rx = MuxerRx::Reset {
+                    local_port: pkt.dst_port(),
+                    peer_port: pkt.src_port(),
+                };
+                push_packet(self.cid, rx, &self.rxq, queue, mem);
+                return;
+            }
+            proxy_map.insert(id, Mutex::new(Box::new(unix)));
+            self.process_proxy_update(id, update);
         } else if let Some(ref mut ipc_map) = &mut self.unix_ipc_port_map {
             if let Some((path, listen)) = ipc_map.get(&pkt.dst_port()) {
                 let mem = self.mem.as_ref().unwrap();
@@ -624,7 +732,10 @@ impl VsockMuxer {
             pkt.op()
         );
 
-        if pkt.dst_cid() != uapi::VSOCK_HOST_CID {
+        let is_sibling = self.sibling_dir.is_some()
+            && pkt.dst_cid() > uapi::VSOCK_HOST_CID
+            && pkt.dst_cid() != self.cid;
+        if pkt.dst_cid() != uapi::VSOCK_HOST_CID && !is_sibling {
             debug!(
                 "vsock: dropping guest packet for unknown CID: {:?}",
                 pkt.hdr()
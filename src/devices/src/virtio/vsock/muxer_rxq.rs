@@ -21,6 +21,7 @@ use super::defs;
 use super::defs::uapi;
 use super::muxer::MuxerRx;
 use super::packet::{TsiAcceptRsp, TsiConnectRsp, TsiListenRsp, VsockPacket};
+use super::sibling;
 
 /// The muxer RX queue.
 pub struct MuxerRxQ {
@@ -224,4 +225,7 @@ pub fn rx_to_pkt(cid: u64, rx: MuxerRx, pkt: &mut VsockPacket) {
             pkt.set_len(pkt.buf().unwrap().len() as u32);
         }
     }
+
+    let src_cid = sibling::peer_cid(pkt.dst_port(), pkt.src_port(), uapi::VSOCK_HOST_CID);
+    pkt.set_src_cid(src_cid);
 }
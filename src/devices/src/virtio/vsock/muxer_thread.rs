@@ -11,11 +11,13 @@ use super::proxy::{NewProxyType, Proxy, ProxyRemoval, ProxyUpdate};
 use super::tcp::TcpProxy;
 
 use crate::virtio::vsock::defs;
+use crate::virtio::vsock::sibling;
 use crate::virtio::vsock::unix::{UnixAcceptorProxy, UnixProxy};
 use crate::virtio::InterruptTransport;
 use crossbeam_channel::Sender;
 use rand::{rng, rngs::ThreadRng, Rng};
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::metrics::Metrics;
 use vm_memory::GuestMemoryMmap;
 
 pub struct MuxerThread {
@@ -28,6 +30,8 @@ pub struct MuxerThread {
     interrupt: InterruptTransport,
     reaper_sender: Sender<u64>,
     unix_ipc_port_map: HashMap<u32, (PathBuf, bool)>,
+    sibling_dir: Option<PathBuf>,
+    metrics: Arc<Metrics>,
 }
 
 impl MuxerThread {
@@ -42,6 +46,8 @@ impl MuxerThread {
         interrupt: InterruptTransport,
         reaper_sender: Sender<u64>,
         unix_ipc_port_map: HashMap<u32, (PathBuf, bool)>,
+        sibling_dir: Option<PathBuf>,
+        metrics: Arc<Metrics>,
     ) -> Self {
         MuxerThread {
             cid,
@@ -53,6 +59,8 @@ impl MuxerThread {
             interrupt,
             reaper_sender,
             unix_ipc_port_map,
+            sibling_dir,
+            metrics,
         }
     }
 
@@ -120,6 +128,7 @@ impl MuxerThread {
                     self.mem.clone(),
                     self.queue.clone(),
                     self.rxq.clone(),
+                    self.metrics.clone(),
                 )),
                 NewProxyType::Unix => Box::new(UnixProxy::new_reverse(
                     new_id,
@@ -131,6 +140,20 @@ impl MuxerThread {
                     self.queue.clone(),
                     self.rxq.clone(),
                 )),
+                NewProxyType::Sibling(cid) => {
+                    let mut proxy = UnixProxy::new_reverse(
+                        new_id,
+                        self.cid,
+                        local_port,
+                        peer_port,
+                        accept_fd,
+                        self.mem.clone(),
+                        self.queue.clone(),
+                        self.rxq.clone(),
+                    );
+                    proxy.mark_sibling(peer_port, local_port, cid);
+                    Box::new(proxy)
+                }
             };
             self.proxy_map
                 .write()
@@ -171,9 +194,34 @@ impl MuxerThread {
         }
     }
 
+    fn create_sibling_socket(&self) {
+        let Some(dir) = &self.sibling_dir else {
+            return;
+        };
+        // No guest can listen on VMADDR_PORT_ANY, so this doesn't collide
+        // with the ids of the other acceptors.
+        let id = ((u32::MAX as u64) << 32) | (defs::TSI_PROXY_PORT as u64);
+        let path = sibling::socket_path(dir, self.cid);
+        let proxy = match UnixAcceptorProxy::new_sibling(id, &path) {
+            Ok(proxy) => proxy,
+            Err(e) => {
+                warn!("Failed to create sibling socket at {path:?}: {e:?}");
+                return;
+            }
+        };
+        self.proxy_map
+            .write()
+            .unwrap()
+            .insert(id, Mutex::new(Box::new(proxy)));
+        if let Some(proxy) = self.proxy_map.read().unwrap().get(&id) {
+            self.update_polling(id, proxy.lock().unwrap().as_raw_fd(), EventSet::IN);
+        };
+    }
+
     fn work(self) {
         let mut thread_rng = rng();
         self.create_lisening_ipc_sockets();
+        self.create_sibling_socket();
         loop {
             let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
             match self
@@ -154,6 +154,16 @@ pub struct TsiListenReq {
     pub backlog: i32,
 }
 
+#[derive(Debug)]
+pub struct TsiListenUnixReq {
+    pub peer_port: u32,
+    pub vm_port: u32,
+    pub backlog: i32,
+    /// The name the guest socket is bound to, starting with '@' if it's
+    /// abstract.
+    pub name: String,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct TsiListenRsp {
@@ -179,6 +189,16 @@ pub struct TsiReleaseReq {
     pub local_port: u32,
 }
 
+#[repr(C)]
+#[derive(Debug)]
+pub struct TsiSetsockoptReq {
+    pub peer_port: u32,
+    pub local_port: u32,
+    pub level: i32,
+    pub optname: i32,
+    pub value: i32,
+}
+
 /// The vsock packet, implemented as a wrapper over a virtq descriptor chain:
 /// - the chain head, holding the packet header; and
 /// - (an optional) data/buffer descriptor, only present for data packets (VSOCK_OP_RW).
@@ -587,6 +607,29 @@ impl VsockPacket {
         }
     }
 
+    pub fn read_listen_unix_req(&self) -> Option<TsiListenUnixReq> {
+        let len = std::cmp::min(self.len() as usize, self.buf_size);
+        if len <= 12 {
+            return None;
+        }
+        let buf = &self.buf().unwrap()[..len];
+        // A sun_path, abstract if it starts with a null byte.
+        let name = match &buf[12..] {
+            [0, name @ ..] => format!("@{}", std::str::from_utf8(name).ok()?),
+            path => {
+                let end = path.iter().position(|b| *b == 0).unwrap_or(path.len());
+                std::str::from_utf8(&path[..end]).ok()?.to_string()
+            }
+        };
+
+        Some(TsiListenUnixReq {
+            peer_port: byte_order::read_le_u32(&buf[0..]),
+            vm_port: byte_order::read_le_u32(&buf[4..]),
+            backlog: byte_order::read_le_u32(&buf[8..]) as i32,
+            name,
+        })
+    }
+
     pub fn write_listen_rsp(&mut self, rsp: TsiListenRsp) {
         if self.buf_size >= 4 {
             if let Some(buf) = self.buf_mut() {
@@ -627,6 +670,21 @@ impl VsockPacket {
         }
     }
 
+    pub fn read_setsockopt_req(&self) -> Option<TsiSetsockoptReq> {
+        if self.buf_size >= 20 {
+            let buf = self.buf().unwrap();
+            Some(TsiSetsockoptReq {
+                peer_port: byte_order::read_le_u32(&buf[0..]),
+                local_port: byte_order::read_le_u32(&buf[4..]),
+                level: byte_order::read_le_u32(&buf[8..]) as i32,
+                optname: byte_order::read_le_u32(&buf[12..]) as i32,
+                value: byte_order::read_le_u32(&buf[16..]) as i32,
+            })
+        } else {
+            None
+        }
+    }
+
     pub fn write_time_sync(&mut self, time: u64) {
         if self.buf_size >= 8 {
             if let Some(buf) = self.buf_mut() {
@@ -635,3 +693,44 @@ impl VsockPacket {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::virtio::test_utils::{guest_memory, Buffer, TestQueue};
+
+    fn listen_unix_req(path: &[u8]) -> Option<TsiListenUnixReq> {
+        let mut data = Vec::new();
+        data.extend(7u32.to_le_bytes());
+        data.extend(1024u32.to_le_bytes());
+        data.extend(5u32.to_le_bytes());
+        data.extend(path);
+        let mut hdr = [0u8; VSOCK_PKT_HDR_SIZE];
+        hdr[HDROFF_LEN..HDROFF_LEN + 4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+
+        let mem = guest_memory();
+        let mut tq = TestQueue::new(&mem, GuestAddress(0), 16);
+        let mut queue = tq.create_queue();
+        tq.add_chain(&[Buffer::Readable(&hdr), Buffer::Readable(&data)]);
+        let head = queue.pop(&mem).unwrap();
+        VsockPacket::from_tx_virtq_head(&head)
+            .unwrap()
+            .read_listen_unix_req()
+    }
+
+    #[test]
+    fn test_read_listen_unix_req() {
+        let req = listen_unix_req(b"/run/containerd.sock\0\0\0").unwrap();
+        assert_eq!(req.peer_port, 7);
+        assert_eq!(req.vm_port, 1024);
+        assert_eq!(req.backlog, 5);
+        assert_eq!(req.name, "/run/containerd.sock");
+
+        let req = listen_unix_req(b"\0containerd").unwrap();
+        assert_eq!(req.name, "@containerd");
+
+        assert!(listen_unix_req(b"").is_none());
+        assert!(listen_unix_req(b"\xff").is_none());
+    }
+}
@@ -2,9 +2,14 @@ use std::collections::HashMap;
 use std::fmt;
 use std::os::fd::OwnedFd;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
 
+use super::flows::FlowInfo;
 use super::muxer::MuxerRx;
-use super::packet::{TsiAcceptReq, TsiConnectReq, TsiListenReq, TsiSendtoAddr, VsockPacket};
+use super::packet::{
+    TsiAcceptReq, TsiConnectReq, TsiListenReq, TsiListenUnixReq, TsiSendtoAddr, TsiSetsockoptReq,
+    VsockPacket,
+};
 use utils::epoll::EventSet;
 
 #[derive(Debug)]
@@ -34,6 +39,21 @@ pub enum ProxyStatus {
     WaitingOnAccept,
 }
 
+impl ProxyStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProxyStatus::Idle => "idle",
+            ProxyStatus::Connecting => "connecting",
+            ProxyStatus::Connected => "connected",
+            ProxyStatus::Listening => "listening",
+            ProxyStatus::Closed => "closed",
+            ProxyStatus::WaitingCreditUpdate => "waiting_credit_update",
+            ProxyStatus::ReverseInit => "reverse_init",
+            ProxyStatus::WaitingOnAccept => "waiting_on_accept",
+        }
+    }
+}
+
 #[derive(Default)]
 pub enum ProxyRemoval {
     #[default]
@@ -47,6 +67,8 @@ pub enum NewProxyType {
     #[default]
     Tcp,
     Unix,
+    /// A connection from the sibling with this CID.
+    Sibling(u64),
 }
 
 #[derive(Default)]
@@ -82,7 +104,16 @@ pub trait Proxy: Send + AsRawFd {
         pkt: &VsockPacket,
         req: TsiListenReq,
         host_port_map: &Option<HashMap<u16, u16>>,
+        listen_fds: &HashMap<u16, OwnedFd>,
     ) -> ProxyUpdate;
+    fn listen_unix(
+        &mut self,
+        _pkt: &VsockPacket,
+        _req: TsiListenUnixReq,
+        _unix_socket_map: &Option<HashMap<String, PathBuf>>,
+    ) -> ProxyUpdate {
+        ProxyUpdate::default()
+    }
     fn accept(&mut self, req: TsiAcceptReq) -> ProxyUpdate;
     fn update_peer_credit(&mut self, pkt: &VsockPacket) -> ProxyUpdate;
     fn push_op_request(&self) {}
@@ -90,6 +121,11 @@ pub trait Proxy: Send + AsRawFd {
     fn enqueue_accept(&mut self) {}
     fn push_accept_rsp(&self, _result: i32) {}
     fn shutdown(&mut self, _pkt: &VsockPacket) {}
+    fn setsockopt(&mut self, _req: &TsiSetsockoptReq) {}
     fn release(&mut self) -> ProxyUpdate;
     fn process_event(&mut self, evset: EventSet) -> ProxyUpdate;
+    /// The state of the flow, for diagnostics.
+    fn flow_info(&self) -> Option<FlowInfo> {
+        None
+    }
 }
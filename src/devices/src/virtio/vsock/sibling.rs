@@ -0,0 +1,83 @@
+//! Routing of vsock stream connections between microVMs running on the same
+//! host.
+//!
+//! Every microVM taking part listens on `<dir>/<cid>.sock`. A connection from
+//! the guest to a sibling CID is proxied to the socket of that sibling,
+//! starting with a header made of the le32 CID of the guest and the le32 port
+//! it's connecting to, so the sibling can forward it to its own guest.
+//!
+//! Since the guest expects the packets of these connections to come from the
+//! sibling CID instead of the host, the peer of each of them is recorded here,
+//! keyed by its guest and remote ports.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+pub const HEADER_LEN: usize = 8;
+
+static PEERS: RwLock<BTreeMap<(u32, u32), u64>> = RwLock::new(BTreeMap::new());
+
+pub fn socket_path(dir: &Path, cid: u64) -> PathBuf {
+    dir.join(format!("{cid}.sock"))
+}
+
+pub fn encode_header(cid: u64, port: u32) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&(cid as u32).to_le_bytes());
+    header[4..].copy_from_slice(&port.to_le_bytes());
+    header
+}
+
+/// Returns the CID of the sibling and the port of its guest.
+pub fn decode_header(header: &[u8; HEADER_LEN]) -> (u64, u32) {
+    let cid = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let port = u32::from_le_bytes(header[4..].try_into().unwrap());
+    (cid as u64, port)
+}
+
+pub fn register(guest_port: u32, remote_port: u32, cid: u64) {
+    PEERS
+        .write()
+        .unwrap()
+        .insert((guest_port, remote_port), cid);
+}
+
+pub fn unregister(guest_port: u32, remote_port: u32) {
+    PEERS.write().unwrap().remove(&(guest_port, remote_port));
+}
+
+/// Returns the CID the packets of the connection between `guest_port` and
+/// `remote_port` must come from.
+pub fn peer_cid(guest_port: u32, remote_port: u32, default: u64) -> u64 {
+    let peers = PEERS.read().unwrap();
+    if peers.is_empty() {
+        return default;
+    }
+    peers
+        .get(&(guest_port, remote_port))
+        .copied()
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let header = encode_header(5, 1234);
+        assert_eq!(header, [5, 0, 0, 0, 0xd2, 0x04, 0, 0]);
+        assert_eq!(decode_header(&header), (5, 1234));
+    }
+
+    #[test]
+    fn test_peers() {
+        assert_eq!(peer_cid(40000, 5000, 2), 2);
+        register(40000, 5000, 7);
+        assert_eq!(peer_cid(40000, 5000, 2), 7);
+        assert_eq!(peer_cid(5000, 40000, 2), 2);
+        unregister(40000, 5000);
+        assert_eq!(peer_cid(40000, 5000, 2), 2);
+    }
+}
@@ -1,31 +1,40 @@
 use std::collections::HashMap;
+use std::fs;
 use std::net::{Ipv4Addr, SocketAddrV4};
 use std::num::Wrapping;
 use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use nix::errno::Errno;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::sys::socket::{
     accept, bind, connect, getpeername, listen, recv, send, setsockopt, shutdown, socket, sockopt,
-    AddressFamily, Backlog, MsgFlags, Shutdown, SockFlag, SockType, SockaddrIn,
+    AddressFamily, Backlog, MsgFlags, Shutdown, SockFlag, SockType, SockaddrIn, UnixAddr,
 };
+use nix::unistd::unlink;
 
 #[cfg(target_os = "macos")]
 use super::super::linux_errno::linux_errno_raw;
 use super::super::Queue as VirtQueue;
 use super::defs;
 use super::defs::uapi;
+use super::flows::{FlowInfo, FlowStats};
+use super::guest_sockopt;
+use super::http_proxy::{self, ConnectResponse, HttpProxyConfig, ResponseBuf};
 use super::muxer::{push_packet, MuxerRx};
 use super::muxer_rxq::MuxerRxQ;
 use super::packet::{
-    TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, VsockPacket,
+    TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiListenUnixReq, TsiSendtoAddr,
+    TsiSetsockoptReq, VsockPacket,
 };
 use super::proxy::{
     NewProxyType, Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt,
 };
 use utils::epoll::EventSet;
+use utils::metrics::Metrics;
 
 use vm_memory::GuestMemoryMmap;
 
@@ -48,6 +57,105 @@ pub struct TcpProxy {
     peer_fwd_cnt: Wrapping<u32>,
     push_cnt: Wrapping<u32>,
     pending_accepts: u64,
+    stats: FlowStats,
+    metrics: Arc<Metrics>,
+    /// The UNIX socket listening for the guest, removed with the proxy.
+    unix_socket: Option<UnixListenPath>,
+    http_proxy: Option<Arc<HttpProxyConfig>>,
+    /// The address the guest connected to, when tunneled through the HTTP
+    /// proxy.
+    tunnel_dst: Option<SocketAddrV4>,
+    /// Whether the proxy hasn't answered the CONNECT request yet.
+    tunnel_pending: bool,
+    /// The part of the answer to the CONNECT request received so far.
+    tunnel_response: ResponseBuf,
+}
+
+/// The device and inode numbers of the UNIX sockets bound by the proxies of
+/// this process.
+static UNIX_LISTENERS: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+
+/// A UNIX socket bound on the host for the guest to listen on. It's
+/// identified by the device and inode numbers recorded at bind time, so the
+/// path is only removed if it still refers to this socket.
+struct UnixListenPath {
+    path: PathBuf,
+    id: (u64, u64),
+}
+
+impl UnixListenPath {
+    /// Checks that `path` can be bound, removing the socket left there by a
+    /// previous run if nothing listens on it anymore.
+    fn prepare(path: &Path) -> Result<(), Errno> {
+        let Ok(meta) = fs::symlink_metadata(path) else {
+            return Ok(());
+        };
+        if !meta.file_type().is_socket()
+            || UNIX_LISTENERS
+                .lock()
+                .unwrap()
+                .contains(&(meta.dev(), meta.ino()))
+            || Self::has_listener(path)
+        {
+            return Err(Errno::EADDRINUSE);
+        }
+        unlink(path)
+    }
+
+    /// Whether some other process is listening on the socket at `path`.
+    fn has_listener(path: &Path) -> bool {
+        let fd = match socket(
+            AddressFamily::Unix,
+            SockType::Stream,
+            SockFlag::empty(),
+            None,
+        ) {
+            Ok(fd) => fd,
+            Err(_) => return true,
+        };
+        set_nonblocking(&fd, 0);
+        !matches!(
+            UnixAddr::new(path).and_then(|addr| connect(fd.as_raw_fd(), &addr)),
+            Err(Errno::ECONNREFUSED | Errno::ENOENT)
+        )
+    }
+
+    /// Records the socket just bound at `path`.
+    fn bound(path: &Path) -> Result<Self, Errno> {
+        let meta = fs::symlink_metadata(path)
+            .map_err(|e| Errno::from_raw(e.raw_os_error().unwrap_or(libc::EIO)))?;
+        let id = (meta.dev(), meta.ino());
+        UNIX_LISTENERS.lock().unwrap().push(id);
+        Ok(Self {
+            path: path.to_path_buf(),
+            id,
+        })
+    }
+}
+
+impl Drop for UnixListenPath {
+    fn drop(&mut self) {
+        UNIX_LISTENERS.lock().unwrap().retain(|id| *id != self.id);
+        if fs::symlink_metadata(&self.path).is_ok_and(|meta| (meta.dev(), meta.ino()) == self.id) {
+            _ = unlink(&self.path);
+        }
+    }
+}
+
+// macOS forces us to do this instead of just using SockFlag::SOCK_NONBLOCK when
+// creating the socket.
+fn set_nonblocking(fd: &OwnedFd, id: u64) {
+    match fcntl(fd, FcntlArg::F_GETFL) {
+        Ok(flags) => match OFlag::from_bits(flags) {
+            Some(flags) => {
+                if let Err(e) = fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK)) {
+                    warn!("error switching to non-blocking: id={id}, err={e}");
+                }
+            }
+            None => error!("invalid fd flags id={id}"),
+        },
+        Err(e) => error!("couldn't obtain fd flags id={id}, err={e}"),
+    };
 }
 
 impl TcpProxy {
@@ -61,6 +169,7 @@ impl TcpProxy {
         mem: GuestMemoryMmap,
         queue: Arc<Mutex<VirtQueue>>,
         rxq: Arc<Mutex<MuxerRxQ>>,
+        metrics: Arc<Metrics>,
     ) -> Result<Self, ProxyError> {
         let fd = socket(
             AddressFamily::Inet,
@@ -70,18 +179,7 @@ impl TcpProxy {
         )
         .map_err(ProxyError::CreatingSocket)?;
 
-        // macOS forces us to do this here instead of just using SockFlag::SOCK_NONBLOCK above.
-        match fcntl(&fd, FcntlArg::F_GETFL) {
-            Ok(flags) => match OFlag::from_bits(flags) {
-                Some(flags) => {
-                    if let Err(e) = fcntl(&fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK)) {
-                        warn!("error switching to non-blocking: id={id}, err={e}");
-                    }
-                }
-                None => error!("invalid fd flags id={id}"),
-            },
-            Err(e) => error!("couldn't obtain fd flags id={id}, err={e}"),
-        };
+        set_nonblocking(&fd, id);
 
         setsockopt(&fd, sockopt::ReusePort, &true).map_err(ProxyError::SettingReusePort)?;
         #[cfg(target_os = "macos")]
@@ -118,6 +216,13 @@ impl TcpProxy {
             peer_fwd_cnt: Wrapping(0),
             push_cnt: Wrapping(0),
             pending_accepts: 0,
+            stats: FlowStats::default(),
+            metrics,
+            unix_socket: None,
+            http_proxy: None,
+            tunnel_dst: None,
+            tunnel_pending: false,
+            tunnel_response: ResponseBuf::default(),
         })
     }
 
@@ -132,6 +237,7 @@ impl TcpProxy {
         mem: GuestMemoryMmap,
         queue: Arc<Mutex<VirtQueue>>,
         rxq: Arc<Mutex<MuxerRxQ>>,
+        metrics: Arc<Metrics>,
     ) -> Self {
         debug!("new_reverse: id={id} local_port={local_port} peer_port={peer_port}");
         TcpProxy {
@@ -153,6 +259,13 @@ impl TcpProxy {
             peer_fwd_cnt: Wrapping(0),
             push_cnt: Wrapping(0),
             pending_accepts: 0,
+            stats: FlowStats::default(),
+            metrics,
+            unix_socket: None,
+            http_proxy: None,
+            tunnel_dst: None,
+            tunnel_pending: false,
+            tunnel_response: ResponseBuf::default(),
         }
     }
 
@@ -171,11 +284,20 @@ impl TcpProxy {
             .set_fwd_cnt(self.tx_cnt.0);
     }
 
-    fn try_listen(&mut self, req: &TsiListenReq, host_port_map: &Option<HashMap<u16, u16>>) -> i32 {
+    fn try_listen(
+        &mut self,
+        req: &TsiListenReq,
+        host_port_map: &Option<HashMap<u16, u16>>,
+        listen_fds: &HashMap<u16, OwnedFd>,
+    ) -> i32 {
         if self.status == ProxyStatus::Listening || self.status == ProxyStatus::WaitingOnAccept {
             return 0;
         }
 
+        if let Some(listen_fd) = listen_fds.get(&req.port) {
+            return self.listen_on_fd(listen_fd, req.backlog);
+        }
+
         let port = if let Some(port_map) = host_port_map {
             if let Some(port) = port_map.get(&req.port) {
                 *port
@@ -228,6 +350,70 @@ impl TcpProxy {
         }
     }
 
+    /// Listens on a socket bound by whoever started us, e.g. to a privileged
+    /// port, instead of binding one.
+    fn listen_on_fd(&mut self, listen_fd: &OwnedFd, backlog: i32) -> i32 {
+        let fd = match listen_fd.try_clone() {
+            Ok(fd) => fd,
+            Err(e) => {
+                warn!("tcp: proxy: id={} err={}", self.id, e);
+                return -e.raw_os_error().unwrap_or(libc::EIO);
+            }
+        };
+        set_nonblocking(&fd, self.id);
+        self.fd = fd;
+
+        match Backlog::new(backlog).and_then(|backlog| listen(&self.fd, backlog)) {
+            Ok(_) => {
+                debug!("tcp: proxy: id={} listening on a passed socket", self.id);
+                0
+            }
+            Err(e) => {
+                warn!("tcp: proxy: id={} err={}", self.id, e);
+                #[cfg(target_os = "macos")]
+                let errno = -linux_errno_raw(e as i32);
+                #[cfg(target_os = "linux")]
+                let errno = -(e as i32);
+                errno
+            }
+        }
+    }
+
+    fn try_listen_unix(
+        &mut self,
+        req: &TsiListenUnixReq,
+        unix_socket_map: &Option<HashMap<String, PathBuf>>,
+    ) -> Result<(), Errno> {
+        if self.status == ProxyStatus::Listening || self.status == ProxyStatus::WaitingOnAccept {
+            return Ok(());
+        }
+
+        let Some(path) = unix_socket_map.as_ref().and_then(|map| map.get(&req.name)) else {
+            return Err(Errno::EPERM);
+        };
+
+        let fd = socket(
+            AddressFamily::Unix,
+            SockType::Stream,
+            SockFlag::empty(),
+            None,
+        )?;
+        set_nonblocking(&fd, self.id);
+        UnixListenPath::prepare(path)?;
+        bind(fd.as_raw_fd(), &UnixAddr::new(path)?)?;
+        self.unix_socket = Some(UnixListenPath::bound(path)?);
+        listen(&fd, Backlog::new(req.backlog)?)?;
+
+        debug!(
+            "tcp: proxy: id={} listening on {} for {}",
+            self.id,
+            path.display(),
+            req.name
+        );
+        self.fd = fd;
+        Ok(())
+    }
+
     fn peer_avail_credit(&self) -> usize {
         (Wrapping(self.peer_buf_alloc) - (self.rx_cnt - self.peer_fwd_cnt)).0 as usize
     }
@@ -287,15 +473,21 @@ impl TcpProxy {
                     }
                     RecvPkt::Read(cnt) => {
                         self.rx_cnt += Wrapping(cnt as u32);
+                        self.metrics.tsi.rx_bytes.add(cnt as u64);
+                        self.stats.rx_bytes += cnt as u64;
                         self.init_data_pkt(&mut pkt);
                         pkt.set_len(cnt as u32);
                         pkt.hdr().len() + cnt
                     }
                     RecvPkt::Close => {
+                        self.metrics.tsi.connections_closed.inc();
                         self.status = ProxyStatus::Closed;
                         0
                     }
-                    RecvPkt::Error => 0,
+                    RecvPkt::Error => {
+                        self.stats.error(0);
+                        0
+                    }
                 },
                 Err(e) => {
                     debug!("vsock: tcp: recv_pkt: RX queue error: {e:?}");
@@ -352,6 +544,91 @@ impl TcpProxy {
         push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
     }
 
+    /// Routes the connections to the ports of `http_proxy` through it.
+    pub fn set_http_proxy(&mut self, http_proxy: Arc<HttpProxyConfig>) {
+        self.http_proxy = Some(http_proxy);
+    }
+
+    /// Sends the CONNECT request for the tunnel to `tunnel_dst`, once
+    /// connected to the proxy. Returns the errno to report to the guest if it
+    /// can't.
+    fn send_connect_request(&mut self) -> Result<(), i32> {
+        let dst = self.tunnel_dst.unwrap();
+        let request = self
+            .http_proxy
+            .as_ref()
+            .unwrap()
+            .connect_request(*dst.ip(), dst.port());
+        match send(self.fd.as_raw_fd(), &request, MsgFlags::empty()) {
+            Ok(sent) if sent == request.len() => {
+                self.tunnel_pending = true;
+                Ok(())
+            }
+            Ok(_) => Err(-libc::ECONNREFUSED),
+            Err(e) => {
+                #[cfg(target_os = "macos")]
+                let errno = -linux_errno_raw(e as i32);
+                #[cfg(target_os = "linux")]
+                let errno = -(e as i32);
+                Err(errno)
+            }
+        }
+    }
+
+    /// Reads the response of the proxy to the CONNECT request, leaving the
+    /// tunneled data that may follow it in the socket. Returns whether the
+    /// tunnel is open, or `None` if the response is incomplete.
+    fn recv_connect_response(&mut self) -> Option<Result<(), i32>> {
+        let mut buf = [0u8; http_proxy::MAX_RESPONSE_HEADER];
+        let room = self.tunnel_response.room();
+        let len = match recv(self.fd.as_raw_fd(), &mut buf[..room], MsgFlags::MSG_PEEK) {
+            Ok(0) => return Some(Err(-libc::ECONNREFUSED)),
+            Ok(len) => len,
+            Err(Errno::EAGAIN) => return None,
+            Err(e) => {
+                #[cfg(target_os = "macos")]
+                let errno = -linux_errno_raw(e as i32);
+                #[cfg(target_os = "linux")]
+                let errno = -(e as i32);
+                return Some(Err(errno));
+            }
+        };
+
+        let (response, consumed) = self.tunnel_response.push(&buf[..len]);
+        if !matches!(response, ConnectResponse::Refused(_))
+            && recv(self.fd.as_raw_fd(), &mut buf[..consumed], MsgFlags::empty()) != Ok(consumed)
+        {
+            return Some(Err(-libc::EIO));
+        }
+
+        match response {
+            ConnectResponse::Incomplete => None,
+            ConnectResponse::Established(_) => {
+                self.tunnel_pending = false;
+                Some(Ok(()))
+            }
+            ConnectResponse::Refused(errno) => {
+                warn!(
+                    "tcp: proxy: id={} HTTP proxy refused the tunnel to {}",
+                    self.id,
+                    self.tunnel_dst.unwrap()
+                );
+                Some(Err(-errno))
+            }
+        }
+    }
+
+    /// Reports to the guest that its connection failed with `errno`.
+    fn fail_connect(&mut self, errno: i32, update: &mut ProxyUpdate) {
+        self.metrics.tsi.connections_failed.inc();
+        self.stats.error(errno);
+        self.push_connect_rsp(errno);
+        self.status = ProxyStatus::Closed;
+        update.polling = Some((self.id, self.fd.as_raw_fd(), EventSet::empty()));
+        update.signal_queue = true;
+        update.remove_proxy = ProxyRemoval::Deferred;
+    }
+
     fn switch_to_connected(&mut self) {
         self.status = ProxyStatus::Connected;
         match fcntl(&self.fd, FcntlArg::F_GETFL) {
@@ -380,12 +657,34 @@ impl Proxy for TcpProxy {
     fn connect(&mut self, _pkt: &VsockPacket, req: TsiConnectReq) -> ProxyUpdate {
         let mut update = ProxyUpdate::default();
 
-        let result = match connect(
-            self.fd.as_raw_fd(),
-            &SockaddrIn::from(SocketAddrV4::new(req.addr, req.port)),
-        ) {
+        let mut dst = SocketAddrV4::new(req.addr, req.port);
+        if let Some(http_proxy) = &self.http_proxy {
+            if http_proxy.proxies(req.port) {
+                debug!(
+                    "vsock: connect: tunneling {dst} through {}",
+                    http_proxy.addr
+                );
+                self.tunnel_dst = Some(dst);
+                dst = http_proxy.addr;
+            }
+        }
+
+        let result = match connect(self.fd.as_raw_fd(), &SockaddrIn::from(dst)) {
+            Ok(()) if self.tunnel_dst.is_some() => match self.send_connect_request() {
+                Ok(()) => {
+                    self.status = ProxyStatus::Connecting;
+                    update.polling = Some((self.id, self.fd.as_raw_fd(), EventSet::IN));
+                    return update;
+                }
+                Err(errno) => {
+                    self.metrics.tsi.connections_failed.inc();
+                    self.stats.error(errno);
+                    errno
+                }
+            },
             Ok(()) => {
                 debug!("vsock: connect: Connected");
+                self.metrics.tsi.connections_opened.inc();
                 self.switch_to_connected();
                 0
             }
@@ -396,10 +695,12 @@ impl Proxy for TcpProxy {
             }
             Err(e) => {
                 debug!("vsock: TcpProxy: Error connecting: {e}");
+                self.metrics.tsi.connections_failed.inc();
                 #[cfg(target_os = "macos")]
                 let errno = -linux_errno_raw(Errno::last_raw());
                 #[cfg(target_os = "linux")]
                 let errno = -Errno::last_raw();
+                self.stats.error(errno);
                 errno
             }
         };
@@ -449,7 +750,11 @@ impl Proxy for TcpProxy {
     fn getpeername(&mut self, pkt: &VsockPacket) {
         debug!("getpeername: id={}", self.id);
 
-        let (result, addr, port) = match getpeername::<SockaddrIn>(self.fd.as_raw_fd()) {
+        let peer = match self.tunnel_dst {
+            Some(dst) => Ok(SockaddrIn::from(dst)),
+            None => getpeername::<SockaddrIn>(self.fd.as_raw_fd()),
+        };
+        let (result, addr, port) = match peer {
             Ok(name) => {
                 let addr = name.ip();
                 (0, addr, name.port())
@@ -493,6 +798,8 @@ impl Proxy for TcpProxy {
                         error!("couldn't set everything: buf={}, sent={}", buf.len(), sent);
                     }
                     self.tx_cnt += Wrapping(sent as u32);
+                    self.metrics.tsi.tx_bytes.add(sent as u64);
+                    self.stats.tx_bytes += sent as u64;
                     sent as i32
                 }
                 Err(err) => {
@@ -500,6 +807,7 @@ impl Proxy for TcpProxy {
                     let errno = -linux_errno_raw(err as i32);
                     #[cfg(target_os = "linux")]
                     let errno = -(err as i32);
+                    self.stats.error(errno);
                     errno
                 }
             }
@@ -538,6 +846,7 @@ impl Proxy for TcpProxy {
         pkt: &VsockPacket,
         req: TsiListenReq,
         host_port_map: &Option<HashMap<u16, u16>>,
+        listen_fds: &HashMap<u16, OwnedFd>,
     ) -> ProxyUpdate {
         debug!(
             "listen: id={} addr={}, port={}, vm_port={} backlog={}",
@@ -545,7 +854,7 @@ impl Proxy for TcpProxy {
         );
         let mut update = ProxyUpdate::default();
 
-        let result = self.try_listen(&req, host_port_map);
+        let result = self.try_listen(&req, host_port_map, listen_fds);
 
         // This packet goes to the control port (DGRAM).
         let rx = MuxerRx::ListenResponse {
@@ -556,6 +865,50 @@ impl Proxy for TcpProxy {
         push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
 
         if result == 0 {
+            self.metrics.tsi.listeners.inc();
+            self.peer_port = req.vm_port;
+            self.status = ProxyStatus::Listening;
+            update.polling = Some((self.id, self.fd.as_raw_fd(), EventSet::IN));
+        }
+
+        update
+    }
+
+    fn listen_unix(
+        &mut self,
+        pkt: &VsockPacket,
+        req: TsiListenUnixReq,
+        unix_socket_map: &Option<HashMap<String, PathBuf>>,
+    ) -> ProxyUpdate {
+        debug!(
+            "listen_unix: id={} name={}, vm_port={} backlog={}",
+            self.id, req.name, req.vm_port, req.backlog
+        );
+        let mut update = ProxyUpdate::default();
+
+        let result = match self.try_listen_unix(&req, unix_socket_map) {
+            Ok(()) => 0,
+            Err(e) => {
+                warn!("tcp: proxy: id={} err={}", self.id, e);
+                #[cfg(target_os = "macos")]
+                let errno = -linux_errno_raw(e as i32);
+                #[cfg(target_os = "linux")]
+                let errno = -(e as i32);
+                self.stats.error(errno);
+                errno
+            }
+        };
+
+        // This packet goes to the control port (DGRAM).
+        let rx = MuxerRx::ListenResponse {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+            result,
+        };
+        push_packet(self.cid, rx, &self.rxq, &self.queue, &self.mem);
+
+        if result == 0 {
+            self.metrics.tsi.listeners.inc();
             self.peer_port = req.vm_port;
             self.status = ProxyStatus::Listening;
             update.polling = Some((self.id, self.fd.as_raw_fd(), EventSet::IN));
@@ -688,6 +1041,12 @@ impl Proxy for TcpProxy {
         } else {
             ProxyRemoval::Deferred
         };
+        if matches!(
+            self.status,
+            ProxyStatus::Connected | ProxyStatus::WaitingCreditUpdate
+        ) {
+            self.metrics.tsi.connections_closed.inc();
+        }
         ProxyUpdate {
             remove_proxy,
             ..Default::default()
@@ -700,8 +1059,16 @@ impl Proxy for TcpProxy {
         if evset.contains(EventSet::HANG_UP) {
             debug!("process_event: HANG_UP");
             if self.status == ProxyStatus::Connecting {
+                self.metrics.tsi.connections_failed.inc();
+                self.stats.error(-libc::ECONNREFUSED);
                 self.push_connect_rsp(-libc::ECONNREFUSED);
             } else {
+                if matches!(
+                    self.status,
+                    ProxyStatus::Connected | ProxyStatus::WaitingCreditUpdate
+                ) {
+                    self.metrics.tsi.connections_closed.inc();
+                }
                 self.push_reset();
             }
 
@@ -745,11 +1112,26 @@ impl Proxy for TcpProxy {
                     debug!("process_event: WaitingCreditUpdate");
                     update.polling = Some((self.id(), self.fd.as_raw_fd(), EventSet::empty()));
                 }
+            } else if self.status == ProxyStatus::Connecting && self.tunnel_pending {
+                match self.recv_connect_response() {
+                    None => {}
+                    Some(Ok(())) => {
+                        self.metrics.tsi.connections_opened.inc();
+                        self.switch_to_connected();
+                        self.push_connect_rsp(0);
+                        update.signal_queue = true;
+                        // Like when connecting directly, wait for OP_REQUEST.
+                        update.polling = Some((self.id(), self.fd.as_raw_fd(), EventSet::empty()));
+                    }
+                    Some(Err(errno)) => self.fail_connect(errno, &mut update),
+                }
+                return update;
             } else if self.status == ProxyStatus::Listening
                 || self.status == ProxyStatus::WaitingOnAccept
             {
                 match accept(self.fd.as_raw_fd()) {
                     Ok(accept_fd) => {
+                        self.metrics.tsi.connections_accepted.inc();
                         // Safe because we've just obtained the FD from the `accept` call above.
                         let new_fd = unsafe { OwnedFd::from_raw_fd(accept_fd) };
                         update.new_proxy = Some((self.peer_port, new_fd, NewProxyType::Tcp));
@@ -768,7 +1150,17 @@ impl Proxy for TcpProxy {
 
         if evset.contains(EventSet::OUT) {
             debug!("process_event: OUT");
-            if self.status == ProxyStatus::Connecting {
+            if self.status == ProxyStatus::Connecting && self.tunnel_dst.is_some() {
+                if !self.tunnel_pending {
+                    match self.send_connect_request() {
+                        Ok(()) => {
+                            update.polling = Some((self.id(), self.fd.as_raw_fd(), EventSet::IN));
+                        }
+                        Err(errno) => self.fail_connect(errno, &mut update),
+                    }
+                }
+            } else if self.status == ProxyStatus::Connecting {
+                self.metrics.tsi.connections_opened.inc();
                 self.switch_to_connected();
                 self.push_connect_rsp(0);
                 update.signal_queue = true;
@@ -782,6 +1174,24 @@ impl Proxy for TcpProxy {
 
         update
     }
+
+    fn setsockopt(&mut self, req: &TsiSetsockoptReq) {
+        let ret = guest_sockopt::apply(&self.fd, req);
+        if ret != 0 {
+            self.stats.error(ret);
+        }
+    }
+
+    fn flow_info(&self) -> Option<FlowInfo> {
+        Some(FlowInfo::new(
+            self.id,
+            "tcp",
+            self.status,
+            self.local_port,
+            &self.fd,
+            &self.stats,
+        ))
+    }
 }
 
 impl AsRawFd for TcpProxy {
@@ -789,3 +1199,41 @@ impl AsRawFd for TcpProxy {
         self.fd.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_unix_listen_path_ownership() {
+        let dir = std::env::temp_dir().join(format!("libkrun-tcp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sock");
+        _ = fs::remove_file(&path);
+
+        // Someone else is listening on it.
+        let listener = UnixListener::bind(&path).unwrap();
+        assert_eq!(UnixListenPath::prepare(&path), Err(Errno::EADDRINUSE));
+
+        // Left behind by a listener that's gone.
+        drop(listener);
+        assert_eq!(UnixListenPath::prepare(&path), Ok(()));
+        assert!(!path.exists());
+
+        // Ours, so a second listen is refused.
+        let listener = UnixListener::bind(&path).unwrap();
+        let ours = UnixListenPath::bound(&path).unwrap();
+        drop(listener);
+        assert_eq!(UnixListenPath::prepare(&path), Err(Errno::EADDRINUSE));
+
+        // Replaced by someone else, who keeps it.
+        let other = dir.join("other");
+        let _listener = UnixListener::bind(&other).unwrap();
+        fs::rename(&other, &path).unwrap();
+        drop(ours);
+        assert!(path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
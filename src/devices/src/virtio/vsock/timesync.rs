@@ -1,4 +1,4 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time;
 
@@ -13,6 +13,17 @@ const UPDATE_INTERVAL: u64 = 60 * 1000 * 1000 * 1000;
 const SLEEP_NSECS: u64 = 2 * 1000 * 1000 * 1000;
 const TSYNC_PORT: u32 = 123;
 
+/// Set when the guest clock needs to be synchronized right away.
+static SYNC_REQUESTED: Mutex<bool> = Mutex::new(false);
+static SYNC_REQUESTED_CV: Condvar = Condvar::new();
+
+/// Asks the timesync thread to send the host time to the guest right away,
+/// for instance because the vCPUs have just been resumed after a pause.
+pub fn request_time_sync() {
+    *SYNC_REQUESTED.lock().unwrap() = true;
+    SYNC_REQUESTED_CV.notify_all();
+}
+
 pub struct TimesyncThread {
     cid: u64,
     mem: GuestMemoryMmap,
@@ -59,23 +70,34 @@ impl TimesyncThread {
     }
 
     fn work(&mut self) {
+        // On Linux, kvmclock keeps the guest clock right across host naps, so
+        // the guest only needs to be told about the time on request.
+        let periodic = cfg!(target_os = "macos");
         let mut last_update = 0u64;
         let mut last_awake = utils::time::get_time(utils::time::ClockType::Real);
         loop {
             let now = utils::time::get_time(utils::time::ClockType::Real);
+            let requested = std::mem::take(&mut *SYNC_REQUESTED.lock().unwrap());
             /*
-             * We send a time sync packet if we slept for 3 times more
-             * nanoseconds than expected (which is an indication the
-             * system forced us to take a long nap), or if UPDATE_INTERVAL
-             * has been reached.
+             * We send a time sync packet if it was explicitly requested, if we
+             * slept for 3 times more nanoseconds than expected (which is an
+             * indication the system forced us to take a long nap), or if
+             * UPDATE_INTERVAL has been reached.
              */
-            if (now - last_awake) >= (SLEEP_NSECS * 3) || (now - last_update) >= UPDATE_INTERVAL {
+            if requested
+                || (periodic
+                    && ((now - last_awake) >= (SLEEP_NSECS * 3)
+                        || (now - last_update) >= UPDATE_INTERVAL))
+            {
                 self.send_time(now);
                 last_update = now;
             }
 
             last_awake = utils::time::get_time(utils::time::ClockType::Real);
-            thread::sleep(time::Duration::from_nanos(SLEEP_NSECS));
+            let guard = SYNC_REQUESTED.lock().unwrap();
+            let _unused = SYNC_REQUESTED_CV
+                .wait_timeout_while(guard, time::Duration::from_nanos(SLEEP_NSECS), |r| !*r)
+                .unwrap();
         }
     }
 
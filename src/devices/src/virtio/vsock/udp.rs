@@ -16,10 +16,13 @@ use super::super::linux_errno::linux_errno_raw;
 use super::super::Queue as VirtQueue;
 use super::defs;
 use super::defs::uapi;
+use super::flows::{FlowInfo, FlowStats};
+use super::guest_sockopt;
 use super::muxer::{push_packet, MuxerRx};
 use super::muxer_rxq::MuxerRxQ;
 use super::packet::{
-    TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, VsockPacket,
+    TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, TsiSetsockoptReq,
+    VsockPacket,
 };
 use super::proxy::{Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt};
 use utils::epoll::EventSet;
@@ -42,6 +45,7 @@ pub struct UdpProxy {
     tx_cnt: Wrapping<u32>,
     peer_buf_alloc: u32,
     peer_fwd_cnt: Wrapping<u32>,
+    stats: FlowStats,
 }
 
 impl UdpProxy {
@@ -105,6 +109,7 @@ impl UdpProxy {
             tx_cnt: Wrapping(0),
             peer_buf_alloc: 0,
             peer_fwd_cnt: Wrapping(0),
+            stats: FlowStats::default(),
         })
     }
 
@@ -193,6 +198,7 @@ impl UdpProxy {
                     }
                     RecvPkt::Read(cnt) => {
                         self.rx_cnt += Wrapping(cnt as u32);
+                        self.stats.rx_bytes += cnt as u64;
                         self.init_pkt(&mut pkt);
                         pkt.set_len(cnt as u32);
                         pkt.hdr().len() + cnt
@@ -201,7 +207,10 @@ impl UdpProxy {
                         self.status = ProxyStatus::Closed;
                         0
                     }
-                    RecvPkt::Error => 0,
+                    RecvPkt::Error => {
+                        self.stats.error(0);
+                        0
+                    }
                 },
                 Err(e) => {
                     debug!("vsock: tcp: recv_pkt: RX queue error: {e:?}");
@@ -252,6 +261,7 @@ impl Proxy for UdpProxy {
                 let errno = -linux_errno_raw(e as i32);
                 #[cfg(target_os = "linux")]
                 let errno = -(e as i32);
+                self.stats.error(errno);
                 errno
             }
         };
@@ -306,9 +316,13 @@ impl Proxy for UdpProxy {
             match send(self.fd.as_raw_fd(), buf, flags) {
                 Ok(sent) => {
                     self.tx_cnt += Wrapping(sent as u32);
+                    self.stats.tx_bytes += sent as u64;
                     sent as i32
                 }
-                Err(err) => -(err as i32),
+                Err(err) => {
+                    self.stats.error(-(err as i32));
+                    -(err as i32)
+                }
             }
         } else {
             -libc::EINVAL
@@ -357,8 +371,12 @@ impl Proxy for UdpProxy {
                 match sendto(self.fd.as_raw_fd(), buf, &addr, flags) {
                     Ok(sent) => {
                         self.tx_cnt += Wrapping(sent as u32);
+                        self.stats.tx_bytes += sent as u64;
+                    }
+                    Err(err) => {
+                        debug!("error in sendto: {err}");
+                        self.stats.error(-(err as i32));
                     }
-                    Err(err) => debug!("error in sendto: {err}"),
                 }
             } else {
                 debug!("vsock: udp_proxy: sendto_data pkt without buffer");
@@ -373,6 +391,7 @@ impl Proxy for UdpProxy {
         _pkt: &VsockPacket,
         _req: TsiListenReq,
         _host_port_map: &Option<HashMap<u16, u16>>,
+        _listen_fds: &HashMap<u16, OwnedFd>,
     ) -> ProxyUpdate {
         ProxyUpdate::default()
     }
@@ -452,6 +471,24 @@ impl Proxy for UdpProxy {
 
         update
     }
+
+    fn setsockopt(&mut self, req: &TsiSetsockoptReq) {
+        let ret = guest_sockopt::apply(&self.fd, req);
+        if ret != 0 {
+            self.stats.error(ret);
+        }
+    }
+
+    fn flow_info(&self) -> Option<FlowInfo> {
+        Some(FlowInfo::new(
+            self.id,
+            "udp",
+            self.status,
+            self.local_port,
+            &self.fd,
+            &self.stats,
+        ))
+    }
 }
 
 impl AsRawFd for UdpProxy {
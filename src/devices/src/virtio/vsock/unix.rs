@@ -6,9 +6,11 @@ use super::{
 use nix::errno::Errno;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::sys::socket::{
-    accept, bind, connect, listen, recv, send, shutdown, socket, AddressFamily, Backlog, MsgFlags,
-    Shutdown, SockFlag, SockType, UnixAddr,
+    accept, bind, connect, listen, recv, send, setsockopt, shutdown, socket, sockopt,
+    AddressFamily, Backlog, MsgFlags, Shutdown, SockFlag, SockType, UnixAddr,
 };
+use nix::sys::time::TimeVal;
+use nix::unistd::unlink;
 use std::collections::HashMap;
 use std::num::Wrapping;
 use std::os::fd::{FromRawFd, OwnedFd};
@@ -19,10 +21,12 @@ use std::sync::{Arc, Mutex};
 #[cfg(target_os = "macos")]
 use super::super::linux_errno::linux_errno_raw;
 use super::super::Queue as VirtQueue;
+use super::flows::{FlowInfo, FlowStats};
 use super::muxer::{push_packet, MuxerRx};
 use super::muxer_rxq::MuxerRxQ;
 use super::packet::{TsiAcceptReq, TsiConnectReq, TsiListenReq, TsiSendtoAddr, VsockPacket};
 use super::proxy::{NewProxyType, Proxy, ProxyError, ProxyStatus, ProxyUpdate};
+use super::sibling;
 use utils::epoll::EventSet;
 
 use vm_memory::GuestMemoryMmap;
@@ -45,6 +49,11 @@ pub struct UnixProxy {
     last_tx_cnt_sent: Wrapping<u32>,
     push_cnt: Wrapping<u32>,
     rx_cnt: Wrapping<u32>,
+    /// Whether the peer is a sibling microVM instead of the host.
+    sibling: bool,
+    /// Sent right after connecting.
+    preamble: Option<[u8; sibling::HEADER_LEN]>,
+    stats: FlowStats,
 }
 
 fn proxy_fd_create(id: u64) -> Result<OwnedFd, ProxyError> {
@@ -119,9 +128,38 @@ impl UnixProxy {
             last_tx_cnt_sent: Wrapping(0),
             push_cnt: Wrapping(0),
             rx_cnt: Wrapping(0),
+            sibling: false,
+            preamble: None,
+            stats: FlowStats::default(),
         })
     }
 
+    /// Creates a proxy for a connection from `guest_port` to `remote_port`
+    /// of the sibling with `peer_cid`, whose socket is at `path`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_sibling(
+        id: u64,
+        cid: u64,
+        remote_port: u32,
+        guest_port: u32,
+        peer_cid: u64,
+        mem: GuestMemoryMmap,
+        queue: Arc<Mutex<VirtQueue>>,
+        rxq: Arc<Mutex<MuxerRxQ>>,
+        path: PathBuf,
+    ) -> Result<Self, ProxyError> {
+        let mut proxy = Self::new(id, cid, remote_port, guest_port, mem, queue, rxq, path)?;
+        proxy.preamble = Some(sibling::encode_header(cid, remote_port));
+        proxy.mark_sibling(guest_port, remote_port, peer_cid);
+        Ok(proxy)
+    }
+
+    /// Records that the peer of this connection is the sibling with `cid`.
+    pub fn mark_sibling(&mut self, guest_port: u32, remote_port: u32, cid: u64) {
+        sibling::register(guest_port, remote_port, cid);
+        self.sibling = true;
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn new_reverse(
         id: u64,
@@ -152,6 +190,9 @@ impl UnixProxy {
             peer_fwd_cnt: Wrapping(0),
             push_cnt: Wrapping(0),
             path: Default::default(),
+            sibling: false,
+            preamble: None,
+            stats: FlowStats::default(),
         }
     }
 
@@ -168,6 +209,12 @@ impl UnixProxy {
             },
             Err(e) => error!("couldn't obtain fd flags id={}, err={}", self.id, e),
         };
+
+        if let Some(preamble) = self.preamble.take() {
+            if let Err(e) = send(self.fd.as_raw_fd(), &preamble, MsgFlags::empty()) {
+                warn!("error sending sibling header: id={}, err={}", self.id, e);
+            }
+        }
     }
 
     fn push_connect_rsp(&self, result: i32) {
@@ -258,6 +305,7 @@ impl UnixProxy {
                     }
                     RecvPkt::Read(cnt) => {
                         self.rx_cnt += Wrapping(cnt as u32);
+                        self.stats.rx_bytes += cnt as u64;
                         self.init_data_pkt(&mut pkt);
                         pkt.set_len(cnt as u32);
                         pkt.hdr().len() + cnt
@@ -266,7 +314,10 @@ impl UnixProxy {
                         self.status = ProxyStatus::Closed;
                         0
                     }
-                    RecvPkt::Error => 0,
+                    RecvPkt::Error => {
+                        self.stats.error(0);
+                        0
+                    }
                 },
                 Err(e) => {
                     debug!("vsock: tcp: recv_pkt: RX queue error: {e:?}");
@@ -300,8 +351,14 @@ impl UnixProxy {
             self.id, self.local_port, self.peer_port
         );
 
+        let src_cid = if self.sibling {
+            sibling::peer_cid(self.peer_port, self.local_port, uapi::VSOCK_HOST_CID)
+        } else {
+            uapi::VSOCK_HOST_CID
+        };
+
         pkt.set_op(uapi::VSOCK_OP_RW)
-            .set_src_cid(uapi::VSOCK_HOST_CID)
+            .set_src_cid(src_cid)
             .set_dst_cid(self.cid)
             .set_src_port(self.local_port)
             .set_dst_port(self.peer_port)
@@ -311,6 +368,14 @@ impl UnixProxy {
     }
 }
 
+impl Drop for UnixProxy {
+    fn drop(&mut self) {
+        if self.sibling {
+            sibling::unregister(self.peer_port, self.local_port);
+        }
+    }
+}
+
 impl Proxy for UnixProxy {
     fn id(&self) -> u64 {
         self.id
@@ -342,6 +407,7 @@ impl Proxy for UnixProxy {
                 let errno = -linux_errno_raw(Errno::last_raw());
                 #[cfg(target_os = "linux")]
                 let errno = -Errno::last_raw();
+                self.stats.error(errno);
                 errno
             }
         };
@@ -403,6 +469,7 @@ impl Proxy for UnixProxy {
                         error!("couldn't set everything: buf={}, sent={}", buf.len(), sent);
                     }
                     self.tx_cnt += Wrapping(sent as u32);
+                    self.stats.tx_bytes += sent as u64;
                     sent as i32
                 }
                 Err(err) => {
@@ -411,6 +478,7 @@ impl Proxy for UnixProxy {
 
                     #[cfg(target_os = "linux")]
                     let errno = -(err as i32);
+                    self.stats.error(errno);
                     errno
                 }
             }
@@ -451,6 +519,7 @@ impl Proxy for UnixProxy {
         _pkt: &VsockPacket,
         _req: TsiListenReq,
         _host_port_map: &Option<HashMap<u16, u16>>,
+        _listen_fds: &HashMap<u16, OwnedFd>,
     ) -> ProxyUpdate {
         todo!();
     }
@@ -551,6 +620,7 @@ impl Proxy for UnixProxy {
             debug!("process_event: HANG_UP");
 
             if self.status == ProxyStatus::Connecting {
+                self.stats.error(-libc::ECONNREFUSED);
                 self.push_connect_rsp(-libc::ECONNREFUSED);
             } else {
                 self.push_reset();
@@ -616,6 +686,17 @@ impl Proxy for UnixProxy {
 
         update
     }
+
+    fn flow_info(&self) -> Option<FlowInfo> {
+        Some(FlowInfo::new(
+            self.id,
+            if self.sibling { "sibling" } else { "unix" },
+            self.status,
+            self.local_port,
+            &self.fd,
+            &self.stats,
+        ))
+    }
 }
 
 impl AsRawFd for UnixProxy {
@@ -628,6 +709,10 @@ pub struct UnixAcceptorProxy {
     id: u64,
     fd: OwnedFd,
     peer_port: u32,
+    /// Whether connections come from siblings, and start with a header
+    /// telling the guest port to connect to.
+    sibling: bool,
+    stats: FlowStats,
 }
 
 impl UnixAcceptorProxy {
@@ -646,7 +731,36 @@ impl UnixAcceptorProxy {
         .map_err(ProxyError::CreatingSocket)?;
         listen(&fd, Backlog::new(5).map_err(ProxyError::CreatingSocket)?)
             .map_err(ProxyError::CreatingSocket)?;
-        Ok(UnixAcceptorProxy { id, fd, peer_port })
+        Ok(UnixAcceptorProxy {
+            id,
+            fd,
+            peer_port,
+            sibling: false,
+            stats: FlowStats::default(),
+        })
+    }
+
+    /// Creates the proxy accepting connections from the siblings at `path`,
+    /// replacing the socket of a previous microVM with the same CID.
+    pub fn new_sibling(id: u64, path: &PathBuf) -> Result<Self, ProxyError> {
+        _ = unlink(path);
+        let mut proxy = Self::new(id, path, 0)?;
+        proxy.sibling = true;
+        Ok(proxy)
+    }
+
+    /// Reads the header sent by a sibling connecting to us, returning its CID
+    /// and the guest port.
+    fn read_sibling_header(fd: &OwnedFd) -> Option<(u64, u32)> {
+        // The sibling sends it right after connecting.
+        setsockopt(fd, sockopt::ReceiveTimeout, &TimeVal::new(1, 0)).ok()?;
+        let mut header = [0u8; sibling::HEADER_LEN];
+        let ret = recv(fd.as_raw_fd(), &mut header, MsgFlags::MSG_WAITALL);
+        setsockopt(fd, sockopt::ReceiveTimeout, &TimeVal::new(0, 0)).ok()?;
+        match ret {
+            Ok(sibling::HEADER_LEN) => Some(sibling::decode_header(&header)),
+            _ => None,
+        }
     }
 }
 
@@ -674,6 +788,7 @@ impl Proxy for UnixAcceptorProxy {
         _: &VsockPacket,
         _: TsiListenReq,
         _: &Option<HashMap<u16, u16>>,
+        _: &HashMap<u16, OwnedFd>,
     ) -> ProxyUpdate {
         unreachable!()
     }
@@ -704,14 +819,34 @@ impl Proxy for UnixAcceptorProxy {
                 Ok(accept_fd) => {
                     // Safe because we've just obtained the FD from the `accept` call above.
                     let new_fd = unsafe { OwnedFd::from_raw_fd(accept_fd) };
-                    update.new_proxy = Some((self.peer_port, new_fd, NewProxyType::Unix));
+                    if !self.sibling {
+                        update.new_proxy = Some((self.peer_port, new_fd, NewProxyType::Unix));
+                    } else if let Some((cid, port)) = Self::read_sibling_header(&new_fd) {
+                        update.new_proxy = Some((port, new_fd, NewProxyType::Sibling(cid)));
+                    } else {
+                        warn!("invalid header from sibling: id={}", self.id);
+                    }
+                }
+                Err(e) => {
+                    warn!("error accepting connection: id={}, err={}", self.id, e);
+                    self.stats.error(-(e as i32));
                 }
-                Err(e) => warn!("error accepting connection: id={}, err={}", self.id, e),
             };
             update.signal_queue = true;
         }
         update
     }
+
+    fn flow_info(&self) -> Option<FlowInfo> {
+        Some(FlowInfo::new(
+            self.id,
+            "unix-listener",
+            ProxyStatus::WaitingOnAccept,
+            self.peer_port,
+            &self.fd,
+            &self.stats,
+        ))
+    }
 }
 
 impl AsRawFd for UnixAcceptorProxy {
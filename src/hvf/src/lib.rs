@@ -12,6 +12,8 @@ pub mod bindings;
 #[macro_use]
 extern crate log;
 
+mod mmio_insn;
+
 use bindings::*;
 
 #[cfg(target_arch = "aarch64")]
@@ -20,7 +22,7 @@ use std::arch::asm;
 use std::convert::TryInto;
 use std::fmt::{Display, Formatter};
 use std::sync::{Arc, LazyLock};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
 use arch::aarch64::sysreg::{sys_reg_name, SYSREG_MASK};
@@ -38,6 +40,15 @@ const TMR_CTL_ENABLE: u64 = 1 << 0;
 const TMR_CTL_IMASK: u64 = 1 << 1;
 const TMR_CTL_ISTATUS: u64 = 1 << 2;
 
+const SMCCC_RET_NOT_SUPPORTED: u64 = -1i64 as u64;
+
+// KVM's vendor hypervisor service UID, 28b46fb6-2ec5-11e9-a9ca-4b564d003a74,
+// as returned in x0-x3 by ARM_SMCCC_VENDOR_HYP_CALL_UID. Advertising it lets
+// Linux guests use the ptp_kvm driver, which we implement below.
+const KVM_VENDOR_HYP_UID: [u64; 4] = [0xb66f_b428, 0xe911_c52e, 0x564b_caa9, 0x743a_004d];
+const KVM_VENDOR_HYP_FEATURES: u64 = 1 << 0;
+const KVM_VENDOR_HYP_PTP: u64 = 1 << 1;
+
 const PSR_MODE_EL1H: u64 = 0x0000_0005;
 const PSR_MODE_EL2H: u64 = 0x0000_0009;
 const PSR_F_BIT: u64 = 0x0000_0040;
@@ -47,6 +58,9 @@ const PSR_D_BIT: u64 = 0x0000_0200;
 const PSTATE_EL1_FAULT_BITS_64: u64 = PSR_MODE_EL1H | PSR_A_BIT | PSR_F_BIT | PSR_I_BIT | PSR_D_BIT;
 const PSTATE_EL2_FAULT_BITS_64: u64 = PSR_MODE_EL2H | PSR_A_BIT | PSR_F_BIT | PSR_I_BIT | PSR_D_BIT;
 
+// RES1 bits of SCTLR_EL1, with the MMU and caches off.
+const SCTLR_EL1_RESET: u64 = 0x30d0_0800;
+
 const HCR_TLOR: u64 = 1 << 35;
 const HCR_RW: u64 = 1 << 31;
 const HCR_TSW: u64 = 1 << 22;
@@ -299,6 +313,7 @@ impl HvfVm {
 pub enum VcpuExit<'a> {
     Breakpoint,
     Canceled,
+    CpuOff,
     CpuOn(u64, u64, u64),
     HypervisorCall,
     MmioRead(u64, &'a mut [u8]),
@@ -307,6 +322,7 @@ pub enum VcpuExit<'a> {
     SecureMonitorCall,
     Shutdown,
     SystemRegister,
+    SystemSuspend(u64, u64),
     VtimerActivated,
     WaitForEvent,
     WaitForEventExpired,
@@ -317,8 +333,14 @@ struct MmioRead {
     addr: u64,
     len: usize,
     srt: u32,
+    sign_extend: bool,
+    sf: bool,
 }
 
+/// Reads guest memory at the given guest physical address, returning whether
+/// the whole buffer could be filled.
+pub type GuestMemoryReader = Box<dyn Fn(u64, &mut [u8]) -> bool + Send>;
+
 pub struct HvfVcpu<'a> {
     vcpuid: hv_vcpu_t,
     vcpu_exit: &'a hv_vcpu_exit_t,
@@ -328,6 +350,7 @@ pub struct HvfVcpu<'a> {
     pending_advance_pc: bool,
     vtimer_masked: bool,
     nested_enabled: bool,
+    guest_mem_reader: Option<GuestMemoryReader>,
 }
 
 impl HvfVcpu<'_> {
@@ -375,9 +398,41 @@ impl HvfVcpu<'_> {
             pending_advance_pc: false,
             vtimer_masked: false,
             nested_enabled,
+            guest_mem_reader: None,
         })
     }
 
+    /// Lets the vCPU read guest memory, which is needed to emulate MMIO
+    /// accesses the hardware doesn't describe in the exception syndrome.
+    pub fn set_guest_memory_reader(&mut self, reader: GuestMemoryReader) {
+        self.guest_mem_reader = Some(reader);
+    }
+
+    /// Reads the instruction at the guest virtual address `pc`.
+    fn fetch_insn(&self, pc: u64) -> Result<Option<u32>, Error> {
+        let Some(reader) = &self.guest_mem_reader else {
+            return Ok(None);
+        };
+
+        let read_u64 = |addr| {
+            let mut buf = [0u8; 8];
+            reader(addr, &mut buf).then(|| u64::from_le_bytes(buf))
+        };
+        let ipa = mmio_insn::translate(
+            pc,
+            self.read_sys_reg(hv_sys_reg_t_HV_SYS_REG_SCTLR_EL1)?,
+            self.read_sys_reg(hv_sys_reg_t_HV_SYS_REG_TCR_EL1)?,
+            self.read_sys_reg(hv_sys_reg_t_HV_SYS_REG_TTBR0_EL1)?,
+            self.read_sys_reg(hv_sys_reg_t_HV_SYS_REG_TTBR1_EL1)?,
+            read_u64,
+        );
+
+        Ok(ipa.and_then(|ipa| {
+            let mut buf = [0u8; 4];
+            reader(ipa, &mut buf).then(|| u32::from_le_bytes(buf))
+        }))
+    }
+
     pub fn set_initial_state(&self, entry_addr: u64, fdt_addr: u64) -> Result<(), Error> {
         if self.nested_enabled {
             let ret = unsafe {
@@ -473,6 +528,26 @@ impl HvfVcpu<'_> {
         Ok(())
     }
 
+    /// Restarts the vCPU at `entry`, with `context_id` in x0 and the MMU off,
+    /// as PSCI requires when turning a CPU back on or resuming from suspend.
+    pub fn power_on(&mut self, entry: u64, context_id: u64) -> Result<(), Error> {
+        // The call that turned it off doesn't return.
+        self.pending_advance_pc = false;
+
+        let ret = unsafe {
+            hv_vcpu_set_sys_reg(
+                self.vcpuid,
+                hv_sys_reg_t_HV_SYS_REG_SCTLR_EL1,
+                SCTLR_EL1_RESET,
+            )
+        };
+        if ret != HV_SUCCESS {
+            return Err(Error::VcpuInitialRegisters);
+        }
+
+        self.set_initial_state(entry, context_id)
+    }
+
     pub fn id(&self) -> u64 {
         self.vcpuid
     }
@@ -523,12 +598,47 @@ impl HvfVcpu<'_> {
         }
     }
 
+    /// Implements the KVM_HC_PTP hypercall: returns the host wall clock time
+    /// in nanoseconds along with the guest counter value at that same instant,
+    /// so the guest can cross-timestamp both with sub-microsecond accuracy.
+    fn handle_ptp_request(&self) -> Result<(), Error> {
+        // x1 selects the virtual or the physical counter, but since we never
+        // set a vtimer offset both of them match the host counter.
+        let counter = unsafe { mach_absolute_time() };
+        let wall_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+
+        self.write_reg(hv_reg_t_HV_REG_X0, wall_ns >> 32)?;
+        self.write_reg(hv_reg_t_HV_REG_X1, wall_ns & 0xffff_ffff)?;
+        self.write_reg(hv_reg_t_HV_REG_X2, counter >> 32)?;
+        self.write_reg(hv_reg_t_HV_REG_X3, counter & 0xffff_ffff)
+    }
+
     fn handle_psci_request(&self) -> Result<VcpuExit<'_>, Error> {
         match self.read_reg(hv_reg_t_HV_REG_X0)? {
+            0x8000_0000 /* ARM_SMCCC_VERSION_FUNC_ID */ => {
+                self.write_reg(hv_reg_t_HV_REG_X0, 0x1_0001)?;
+                Ok(VcpuExit::PsciHandled)
+            },
             0x8400_0000 /* QEMU_PSCI_0_2_FN_PSCI_VERSION */ => {
-                self.write_reg(hv_reg_t_HV_REG_X0, 2)?;
+                // PSCI 1.0, required for the guest to discover SMCCC 1.1
+                // through PSCI_FEATURES.
+                self.write_reg(hv_reg_t_HV_REG_X0, 0x1_0000)?;
+                Ok(VcpuExit::PsciHandled)
+            },
+            0x8400_000a /* QEMU_PSCI_1_0_FN_PSCI_FEATURES */ => {
+                let ret = match self.read_reg(hv_reg_t_HV_REG_X1)? {
+                    0x8000_0000 | 0x8400_0000 | 0x8400_0002 | 0x8400_0006 | 0x8400_0008
+                    | 0x8400_0009 | 0x8400_000a | 0xc400_0003 | 0xc400_000e => 0,
+                    _ => SMCCC_RET_NOT_SUPPORTED,
+                };
+                self.write_reg(hv_reg_t_HV_REG_X0, ret)?;
                 Ok(VcpuExit::PsciHandled)
             },
+            0x8400_0002 /* QEMU_PSCI_0_2_FN_CPU_OFF */ => {
+                Ok(VcpuExit::CpuOff)
+            },
             0x8400_0006 /* QEMU_PSCI_0_2_FN_MIGRATE_INFO_TYPE */ => {
                 self.write_reg(hv_reg_t_HV_REG_X0, 2)?;
                 Ok(VcpuExit::PsciHandled)
@@ -546,7 +656,36 @@ impl HvfVcpu<'_> {
                 self.write_reg(hv_reg_t_HV_REG_X0, 0)?;
                 Ok(VcpuExit::CpuOn(mpidr, entry, context_id))
             }
-            val => panic!("Unexpected val={val}")
+            0xc400_000e /* QEMU_PSCI_1_0_FN64_SYSTEM_SUSPEND */ => {
+                let entry = self.read_reg(hv_reg_t_HV_REG_X1)?;
+                let context_id = self.read_reg(hv_reg_t_HV_REG_X2)?;
+                Ok(VcpuExit::SystemSuspend(entry, context_id))
+            }
+            0x8600_ff01 /* ARM_SMCCC_VENDOR_HYP_CALL_UID_FUNC_ID */ => {
+                for (i, word) in KVM_VENDOR_HYP_UID.iter().enumerate() {
+                    self.write_reg(hv_reg_t_HV_REG_X0 + i as u32, *word)?;
+                }
+                Ok(VcpuExit::PsciHandled)
+            },
+            0x8600_0000 /* ARM_SMCCC_VENDOR_HYP_KVM_FEATURES_FUNC_ID */ => {
+                self.write_reg(hv_reg_t_HV_REG_X0, KVM_VENDOR_HYP_FEATURES | KVM_VENDOR_HYP_PTP)?;
+                for reg in [hv_reg_t_HV_REG_X1, hv_reg_t_HV_REG_X2, hv_reg_t_HV_REG_X3] {
+                    self.write_reg(reg, 0)?;
+                }
+                Ok(VcpuExit::PsciHandled)
+            },
+            0x8600_0001 /* ARM_SMCCC_VENDOR_HYP_KVM_PTP_FUNC_ID */ => {
+                self.handle_ptp_request()?;
+                Ok(VcpuExit::PsciHandled)
+            },
+            val => {
+                // With SMCCC 1.1 guests probe for optional services (TRNG,
+                // errata workarounds...), which must be answered rather
+                // than treated as fatal.
+                debug!("unsupported SMCCC call: 0x{val:x}");
+                self.write_reg(hv_reg_t_HV_REG_X0, SMCCC_RET_NOT_SUPPORTED)?;
+                Ok(VcpuExit::PsciHandled)
+            }
         }
     }
 
@@ -565,6 +704,13 @@ impl HvfVcpu<'_> {
                         mmio_read.addr, mmio_read.len
                     ),
                 };
+                let val = if mmio_read.sign_extend && mmio_read.len < 8 {
+                    let shift = 64 - 8 * mmio_read.len;
+                    (((val << shift) as i64) >> shift) as u64
+                } else {
+                    val
+                };
+                let val = if mmio_read.sf { val } else { val & 0xffff_ffff };
 
                 self.write_reg(mmio_read.srt, val)?;
             }
@@ -619,6 +765,8 @@ impl HvfVcpu<'_> {
                 let sas: u32 = ((syndrome >> 22) & 3) as u32;
                 let len: usize = (1 << sas) as usize;
                 let srt: u32 = ((syndrome >> 16) & 0x1f) as u32;
+                let sse: bool = ((syndrome >> 21) & 1) != 0;
+                let sf: bool = ((syndrome >> 15) & 1) != 0;
                 let cm: u32 = ((syndrome >> 8) & 0x1) as u32;
 
                 debug!(
@@ -629,7 +777,25 @@ impl HvfVcpu<'_> {
                 let pa = self.vcpu_exit.exception.physical_address;
                 self.pending_advance_pc = true;
 
-                if iswrite {
+                let access = if isv {
+                    mmio_insn::MmioAccess {
+                        is_write: iswrite,
+                        len,
+                        rt: srt,
+                        sign_extend: sse,
+                        sf,
+                        writeback: None,
+                    }
+                } else {
+                    let pc = self.read_reg(hv_reg_t_HV_REG_PC)?;
+                    match self.fetch_insn(pc)?.and_then(mmio_insn::decode) {
+                        Some(access) => access,
+                        None => panic!("unsupported MMIO instruction at pc=0x{pc:x} pa=0x{pa:x}"),
+                    }
+                };
+
+                let (len, srt) = (access.len, access.rt);
+                if access.is_write {
                     let val = if srt < 31 {
                         self.read_reg(hv_reg_t_HV_REG_X0 + srt)?
                     } else {
@@ -638,14 +804,28 @@ impl HvfVcpu<'_> {
 
                     match len {
                         1 => self.mmio_buf[0..1].copy_from_slice(&(val as u8).to_le_bytes()),
+                        2 => self.mmio_buf[0..2].copy_from_slice(&(val as u16).to_le_bytes()),
                         4 => self.mmio_buf[0..4].copy_from_slice(&(val as u32).to_le_bytes()),
                         8 => self.mmio_buf[0..8].copy_from_slice(&val.to_le_bytes()),
                         _ => panic!("unsupported mmio len={len}"),
                     };
+                }
+
+                if let Some((rn, offset)) = access.writeback {
+                    let base = self.read_reg(hv_reg_t_HV_REG_X0 + rn)?;
+                    self.write_reg(hv_reg_t_HV_REG_X0 + rn, base.wrapping_add(offset as u64))?;
+                }
 
+                if access.is_write {
                     Ok(VcpuExit::MmioWrite(pa, &self.mmio_buf[0..len]))
                 } else {
-                    self.pending_mmio_read = Some(MmioRead { addr: pa, srt, len });
+                    self.pending_mmio_read = Some(MmioRead {
+                        addr: pa,
+                        srt,
+                        len,
+                        sign_extend: access.sign_extend,
+                        sf: access.sf,
+                    });
                     Ok(VcpuExit::MmioRead(pa, &mut self.mmio_buf[0..len]))
                 }
             }
@@ -0,0 +1,230 @@
+//! Decoding of the guest instructions behind data aborts that don't carry a
+//! valid instruction syndrome.
+//!
+//! The hardware only describes the access in ESR_EL2 (ISV set) for single
+//! register loads and stores without writeback. For anything else, such as
+//! the pre/post-indexed forms compilers like to emit for loops, we need to
+//! fetch the instruction from guest memory and decode it ourselves.
+
+/// A load or store to be emulated as an MMIO access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MmioAccess {
+    pub is_write: bool,
+    pub len: usize,
+    /// Register being transferred. 31 is the zero register.
+    pub rt: u32,
+    /// Whether the loaded value must be sign extended.
+    pub sign_extend: bool,
+    /// Whether the destination of a load is a 64-bit register.
+    pub sf: bool,
+    /// Base register and the offset to add to it after the access.
+    pub writeback: Option<(u32, i64)>,
+}
+
+/// Decodes a general purpose register load or store. Returns `None` for
+/// anything else, including the SIMD&FP, pair, exclusive and atomic forms.
+pub fn decode(insn: u32) -> Option<MmioAccess> {
+    let size = insn >> 30;
+    let opc = (insn >> 22) & 0x3;
+    let rn = (insn >> 5) & 0x1f;
+    let rt = insn & 0x1f;
+
+    let writeback = if insn & 0x3f20_0000 == 0x3800_0000 {
+        // Load/store register (unscaled immediate, post-indexed,
+        // unprivileged or pre-indexed).
+        let imm9 = (((insn << 11) as i32) >> 23) as i64;
+        match (insn >> 10) & 0x3 {
+            0b01 | 0b11 => Some((rn, imm9)),
+            _ => None,
+        }
+    } else if insn & 0x3f00_0000 == 0x3900_0000 || insn & 0x3f20_0c00 == 0x3820_0800 {
+        // Load/store register (unsigned immediate or register offset).
+        None
+    } else {
+        return None;
+    };
+
+    // Writeback to SP or to the transferred register is either pointless
+    // for MMIO or UNPREDICTABLE.
+    if let Some((rn, _)) = writeback {
+        if rn == 31 || rn == rt {
+            return None;
+        }
+    }
+
+    let (is_write, sign_extend, sf) = match (opc, size) {
+        (0b00, _) => (true, false, false),
+        (0b01, _) => (false, false, size == 3),
+        (0b10, 0..=2) => (false, true, true),
+        (0b11, 0..=1) => (false, true, false),
+        // PRFM and unallocated encodings.
+        _ => return None,
+    };
+
+    Some(MmioAccess {
+        is_write,
+        len: 1 << size,
+        rt,
+        sign_extend,
+        sf,
+        writeback,
+    })
+}
+
+/// Translates the guest virtual address `va` to a guest physical address by
+/// walking the EL1 stage 1 translation tables, reading 64-bit descriptors
+/// from guest memory with `read`. Only the 4KB translation granule is
+/// supported.
+pub fn translate(
+    va: u64,
+    sctlr: u64,
+    tcr: u64,
+    ttbr0: u64,
+    ttbr1: u64,
+    read: impl Fn(u64) -> Option<u64>,
+) -> Option<u64> {
+    const OA_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+    // MMU disabled.
+    if sctlr & 1 == 0 {
+        return Some(va);
+    }
+
+    let (tsz, ttbr) = if (va >> 55) & 1 == 0 {
+        if (tcr >> 14) & 0x3 != 0b00 {
+            return None;
+        }
+        (tcr & 0x3f, ttbr0)
+    } else {
+        if (tcr >> 30) & 0x3 != 0b10 {
+            return None;
+        }
+        ((tcr >> 16) & 0x3f, ttbr1)
+    };
+
+    let va_bits = 64u64
+        .checked_sub(tsz)
+        .filter(|bits| (25..=48).contains(bits))?;
+    let top = va >> va_bits;
+    if top != 0 && top != u64::MAX >> va_bits {
+        return None;
+    }
+
+    let levels = (va_bits - 12).div_ceil(9);
+    let mut table = ttbr & OA_MASK;
+    for level in (4 - levels)..4 {
+        let shift = 12 + 9 * (3 - level);
+        let index = (va >> shift) & 0x1ff & ((1 << (va_bits - shift)) - 1);
+        let desc = read(table + index * 8)?;
+
+        if desc & 1 == 0 {
+            return None;
+        }
+        let is_table_or_page = desc & 2 != 0;
+        if level == 3 || !is_table_or_page {
+            // Blocks are only allowed at levels 1 and 2.
+            if (level == 3) != is_table_or_page || level == 0 {
+                return None;
+            }
+            let offset_mask = (1 << shift) - 1;
+            return Some((desc & OA_MASK & !offset_mask) | (va & offset_mask));
+        }
+        table = desc & OA_MASK;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_decode() {
+        // str w1, [x2], #4
+        assert_eq!(
+            decode(0xb800_4441),
+            Some(MmioAccess {
+                is_write: true,
+                len: 4,
+                rt: 1,
+                sign_extend: false,
+                sf: false,
+                writeback: Some((2, 4)),
+            })
+        );
+        // ldrb w3, [x4, #-1]!
+        assert_eq!(
+            decode(0x385f_fc83),
+            Some(MmioAccess {
+                is_write: false,
+                len: 1,
+                rt: 3,
+                sign_extend: false,
+                sf: false,
+                writeback: Some((4, -1)),
+            })
+        );
+        // ldrsh x0, [x1, #2]
+        assert_eq!(
+            decode(0x7980_0420),
+            Some(MmioAccess {
+                is_write: false,
+                len: 2,
+                rt: 0,
+                sign_extend: true,
+                sf: true,
+                writeback: None,
+            })
+        );
+        // ldr x5, [x6, x7]
+        assert_eq!(decode(0xf867_68c5).map(|a| (a.len, a.sf)), Some((8, true)));
+
+        // ldr x0, [x0], #8
+        assert_eq!(decode(0xf840_8400), None);
+        // stp x0, x1, [x2]
+        assert_eq!(decode(0xa900_0440), None);
+        // ldr q0, [x1]
+        assert_eq!(decode(0x3dc0_0020), None);
+    }
+
+    #[test]
+    fn test_translate() {
+        // 48-bit VAs with a 4KB granule for both halves.
+        let tcr = 16 | (16 << 16) | (0b10 << 30);
+        let mut mem = HashMap::new();
+        // TTBR1: level 0 -> level 1 -> 2MB block at level 2.
+        let va = 0xffff_0000_1234_5678u64;
+        mem.insert(0x1000, 0x2003);
+        mem.insert(0x2000, 0x3003);
+        mem.insert(0x3000 + 0x91 * 8, 0x8020_0001);
+        // TTBR0: down to a 4KB page.
+        mem.insert(0x4000, 0x5003);
+        mem.insert(0x5000, 0x6003);
+        mem.insert(0x6000, 0x7003);
+        mem.insert(0x7000 + 8, 0x9000_0003);
+
+        let read = |addr| mem.get(&addr).copied();
+        assert_eq!(
+            translate(va, 1, tcr, 0x4000, 0x1000, read),
+            Some(0x8020_0000 | (va & 0x1f_ffff))
+        );
+        assert_eq!(
+            translate(0x1abc, 1, tcr, 0x4000, 0x1000, read),
+            Some(0x9000_0abc)
+        );
+        assert_eq!(translate(0x2abc, 1, tcr, 0x4000, 0x1000, read), None);
+        assert_eq!(
+            translate(0x2abc, 0, tcr, 0x4000, 0x1000, read),
+            Some(0x2abc)
+        );
+        // Doesn't fit in 48 bits.
+        assert_eq!(translate(1 << 50, 1, tcr, 0x4000, 0x1000, read), None);
+        // 64KB granule.
+        assert_eq!(
+            translate(0x1abc, 1, tcr | (1 << 14), 0x4000, 0x1000, read),
+            None
+        );
+    }
+}
@@ -2,67 +2,111 @@
 extern crate log;
 
 use crossbeam_channel::unbounded;
+#[cfg(all(target_os = "linux", not(any(feature = "tee", feature = "nitro"))))]
+use devices::vhost_user::{self, fs::FsBackend};
 #[cfg(feature = "blk")]
 use devices::virtio::block::ImageType;
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::display::DisplayInfo;
 #[cfg(feature = "net")]
 use devices::virtio::net::device::VirtioNetBackend;
+#[cfg(feature = "net")]
+use devices::virtio::net::egress;
+#[cfg(feature = "net")]
+use devices::virtio::net::netem::NetemConfig;
+#[cfg(feature = "net")]
+use devices::virtio::net::switch;
+use devices::virtio::plugin::{CPlugin, VirtioDeviceOps};
+use devices::virtio::vsock::flows::{self, FlowTable};
+use devices::virtio::vsock::http_proxy::{self, HttpProxyConfig};
 #[cfg(feature = "blk")]
 use devices::virtio::CacheType;
+#[cfg(feature = "net")]
+use devices::virtio::Net;
+use devices::virtio::Queue;
 use env_logger::{Env, Target};
 #[cfg(feature = "gpu")]
 use krun_display::DisplayBackend;
 use libc::c_char;
-#[cfg(feature = "net")]
 use libc::c_int;
 use libc::size_t;
 use once_cell::sync::Lazy;
 use polly::event_manager::EventManager;
-#[cfg(all(feature = "blk", not(feature = "tee")))]
-use rand::distr::{Alphanumeric, SampleString};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::env;
-#[cfg(target_os = "linux")]
-use std::ffi::CString;
-use std::ffi::{c_void, CStr};
+use std::ffi::{c_void, CStr, CString};
 use std::fs::File;
+use std::io::{self, Write};
+use std::net::IpAddr;
 #[cfg(target_os = "linux")]
 use std::os::fd::AsRawFd;
-use std::os::fd::{FromRawFd, RawFd};
-use std::path::PathBuf;
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::slice;
-use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 use std::sync::LazyLock;
 use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use utils::agent::{self, AgentClient, ExecSpec, AGENT_PORT, EXEC_HOOK_PORT};
+use utils::boot_timeline::{BootPhase, BootTimeline};
 use utils::eventfd::EventFd;
-use vmm::resources::{ConsoleConfig, ConsoleType, VmResources};
+#[cfg(feature = "net")]
+use utils::gvproxy::{GvproxyClient, Protocol};
+use utils::host_sleep::{self, HostSleepEvent};
+use utils::init_protocol::{InitEnv, INIT_PATH, PROTOCOL_VERSION};
+use utils::metrics::Metrics;
+use utils::mkfs::{self, FsType};
+use utils::scratch::ScratchDir;
+use utils::workload_stats::WORKLOAD_STATS;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use vmm::guest_sleep::GUEST_SLEEP;
+#[cfg(not(feature = "tee"))]
+use vmm::measured_boot::MeasuredBootConfig;
+use vmm::measured_boot::MEASUREMENT_LOG;
+use vmm::resources::{ConsoleConfig, ConsoleType, VmResources, WorkloadStdioConfig, WorkloadTty};
 #[cfg(feature = "blk")]
-use vmm::vmm_config::block::{BlockDeviceConfig, BlockRootConfig};
+use vmm::vmm_config::block::{
+    BlockDeviceConfig, BlockRootConfig, LuksPassphrase, OverlayConfig, VerityConfig, ZonedConfig,
+};
 #[cfg(not(feature = "tee"))]
 use vmm::vmm_config::external_kernel::{ExternalKernel, KernelFormat};
 #[cfg(not(feature = "tee"))]
 use vmm::vmm_config::firmware::FirmwareConfig;
 #[cfg(not(feature = "tee"))]
 use vmm::vmm_config::fs::FsDeviceConfig;
+use vmm::vmm_config::guest_memory::{GuestMemoryBacking, GuestMemoryRegionConfig};
 use vmm::vmm_config::kernel_bundle::KernelBundle;
 #[cfg(feature = "tee")]
 use vmm::vmm_config::kernel_bundle::{InitrdBundle, QbootBundle};
 use vmm::vmm_config::kernel_cmdline::{KernelCmdlineConfig, DEFAULT_KERNEL_CMDLINE};
-use vmm::vmm_config::machine_config::VmConfig;
+use vmm::vmm_config::machine_config::{CoreScheduling, DeterministicConfig, VmConfig};
 #[cfg(feature = "net")]
 use vmm::vmm_config::net::NetworkInterfaceConfig;
 use vmm::vmm_config::vsock::VsockDeviceConfig;
+use vmm::Vmm;
 
 #[cfg(feature = "nitro")]
 use nitro::enclaves::NitroEnclave;
 
+#[cfg(all(feature = "gpu", not(feature = "tee")))]
+use devices::rfb::RfbServer;
+#[cfg(feature = "gpu")]
+use devices::virtio::capture::{CaptureError, FrameCallback, ScanoutCapture};
+#[cfg(all(feature = "gpu", not(feature = "tee")))]
+use devices::virtio::display::RfbDisplayBackend;
 #[cfg(feature = "gpu")]
 use devices::virtio::display::{DisplayInfoEdid, PhysicalSize, MAX_DISPLAYS};
+#[cfg(all(feature = "gpu", not(feature = "tee")))]
+use devices::virtio::{InputEventQueue, InputKind};
+#[cfg(all(feature = "gpu", not(feature = "tee")))]
+use krun_display::IntoDisplayBackend;
 #[cfg(feature = "nitro")]
 use nitro_enclaves::launch::StartFlags;
+use std::sync::Arc;
 
 // Value returned on success. We use libc's errors otherwise.
 const KRUN_SUCCESS: i32 = 0;
@@ -79,8 +123,27 @@ const KRUNFW_NAME: &str = "libkrunfw-tdx.so.4";
 #[cfg(target_os = "macos")]
 const KRUNFW_NAME: &str = "libkrunfw.4.dylib";
 
-// Path to the init binary to be executed inside the VM.
-const INIT_PATH: &str = "/init.krun";
+// Host directory holding the Rosetta runtime for Linux, the virtio-fs tag
+// it's shared with and where init mounts it in the guest.
+#[cfg(not(feature = "tee"))]
+const ROSETTA_DIR: &str = "/Library/Apple/usr/libexec/oah/RosettaLinux";
+#[cfg(not(feature = "tee"))]
+const ROSETTA_TAG: &str = "rosetta";
+const ROSETTA_MOUNTPOINT: &str = "/run/rosetta";
+
+// The virtio-fs tag the directory of additional kernel modules is shared
+// with, and where init mounts it in the guest while loading them.
+#[cfg(not(feature = "tee"))]
+const MODULES_TAG: &str = "krun-modules";
+const MODULES_MOUNTPOINT: &str = "/run/krun-modules";
+
+// How long to wait for the guest agent to answer requests other than exec.
+const AGENT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to wait for gvproxy to answer the requests made on its API socket.
+#[cfg(feature = "net")]
+const GVPROXY_TIMEOUT: Duration = Duration::from_secs(10);
+// How long the host can be kept from sleeping while the guest suspends.
+const GUEST_SUSPEND_TIMEOUT: Duration = Duration::from_secs(10);
 
 static KRUNFW: LazyLock<Option<libloading::Library>> =
     LazyLock::new(|| unsafe { libloading::Library::new(KRUNFW_NAME).ok() });
@@ -125,6 +188,38 @@ enum LegacyNetworkConfig {
     VirtioNetGvproxy(PathBuf),
 }
 
+// Where init mounts a volume from, see krun_add_mount().
+enum MountSource {
+    // A virtio-fs device, by tag.
+    #[cfg(not(feature = "tee"))]
+    Virtiofs(String),
+    Tmpfs,
+    // A disk image, by block id.
+    #[cfg(feature = "blk")]
+    Image(String),
+}
+
+struct GuestMount {
+    source: MountSource,
+    target: String,
+    options: String,
+}
+
+// The name the guest gives to the virtio-blk device at "index".
+#[cfg(feature = "blk")]
+fn guest_disk_name(mut index: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'a' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    name.reverse();
+    format!("/dev/vd{}", String::from_utf8(name).unwrap())
+}
+
 #[derive(Default)]
 struct ContextConfig {
     krunfw: Option<KrunfwBindings>,
@@ -134,12 +229,39 @@ struct ContextConfig {
     env: Option<String>,
     args: Option<String>,
     rlimits: Option<String>,
+    // Directories made writable over the read-only root, if it is.
+    ephemeral_dirs: Option<Vec<String>>,
+    // Kernel modules to load, as "MODULE[:PARAMS]".
+    kernel_modules: Vec<String>,
+    kernel_modules_dir: bool,
+    sysctls: Vec<(String, String)>,
+    workload_limits: Option<String>,
+    // io.max limits of the workload cgroup, by guest block device name.
+    workload_io_limits: Vec<(String, String)>,
+    delegate_workload_cgroup: bool,
+    lockdown: Option<&'static str>,
+    kdump: Option<String>,
+    workload_user: Option<String>,
+    host_entries: Vec<(String, IpAddr)>,
+    dns_servers: Vec<IpAddr>,
+    dns_search: Vec<String>,
     #[cfg(feature = "net")]
     legacy_net_cfg: Option<LegacyNetworkConfig>,
     #[cfg(feature = "net")]
     legacy_mac: Option<[u8; 6]>,
+    #[cfg(feature = "net")]
+    net_mtu: Option<u16>,
+    #[cfg(feature = "net")]
+    net_egress_rules: Vec<egress::Rule>,
+    #[cfg(feature = "net")]
+    net_egress_default: Option<egress::Action>,
+    #[cfg(feature = "net")]
+    net_netem: HashMap<u32, NetemConfig>,
     net_index: u8,
     tsi_port_map: Option<HashMap<u16, u16>>,
+    tsi_unix_socket_map: Option<HashMap<String, PathBuf>>,
+    tsi_listen_fds: HashMap<u16, RawFd>,
+    tsi_http_proxy: Option<HttpProxyConfig>,
     #[cfg(feature = "blk")]
     block_cfgs: Vec<BlockDeviceConfig>,
     #[cfg(feature = "blk")]
@@ -148,16 +270,47 @@ struct ContextConfig {
     data_block_cfg: Option<BlockDeviceConfig>,
     #[cfg(feature = "blk")]
     block_root: Option<BlockRootConfig>,
+    // The disk images of the mounts, after all the other disks.
+    #[cfg(feature = "blk")]
+    mount_block_cfgs: Vec<BlockDeviceConfig>,
+    mounts: Vec<GuestMount>,
     #[cfg(feature = "tee")]
     tee_config_file: Option<PathBuf>,
+    // The block id, guest path and sealing fields of the sealed disk.
+    #[cfg(feature = "amd-sev")]
+    sealed_disk: Option<(String, String, u64)>,
     unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
+    vsock_cid: Option<u32>,
+    vsock_sibling_dir: Option<PathBuf>,
     shutdown_efd: Option<EventFd>,
     gpu_virgl_flags: Option<u32>,
     gpu_shm_size: Option<usize>,
     enable_snd: bool,
     console_output: Option<PathBuf>,
+    metrics_socket: Option<PathBuf>,
+    print_boot_timeline: bool,
+    workload_stats_path: Option<PathBuf>,
+    device_worker_threads: Option<usize>,
+    io_uring: bool,
+    #[cfg(feature = "tracing")]
+    trace_file: Option<PathBuf>,
     vmm_uid: Option<libc::uid_t>,
     vmm_gid: Option<libc::gid_t>,
+    rosetta: bool,
+    agent: bool,
+    exec_hook: Option<ExecHook>,
+    ssh_keys: Option<SshKeys>,
+    fido: Option<FidoBridge>,
+    clipboard_sync: Option<ClipboardSync>,
+    host_sleep_sync: bool,
+    scratch_dir: Option<ScratchDir>,
+    cleanup_supervisor: bool,
+    supervisor: Option<SupervisorConfig>,
+    forwarded_signals: Vec<c_int>,
+    #[cfg(all(feature = "gpu", not(feature = "tee")))]
+    vnc: Option<(Arc<RfbServer>, PathBuf)>,
+    #[cfg(feature = "gpu")]
+    frame_stream: Option<(FrameCallback, u32)>,
     #[cfg(feature = "nitro")]
     nitro_image_path: Option<PathBuf>,
     #[cfg(feature = "nitro")]
@@ -241,6 +394,62 @@ impl ContextConfig {
         self.rlimits = Some(rlimits);
     }
 
+    fn get_workload_user(&self) -> String {
+        match &self.workload_user {
+            Some(user) => format!("KRUN_USER={user}"),
+            None => "".to_string(),
+        }
+    }
+
+    fn get_network_names(&self) -> String {
+        let mut vars = Vec::new();
+        if !self.host_entries.is_empty() {
+            let entries: Vec<String> = self
+                .host_entries
+                .iter()
+                .map(|(name, ip)| format!("{name}={ip}"))
+                .collect();
+            vars.push(format!("KRUN_HOSTS={}", entries.join(",")));
+        }
+        if !self.dns_servers.is_empty() {
+            let servers: Vec<String> = self.dns_servers.iter().map(|ip| ip.to_string()).collect();
+            vars.push(format!("KRUN_DNS={}", servers.join(",")));
+        }
+        if !self.dns_search.is_empty() {
+            vars.push(format!("KRUN_DNS_SEARCH={}", self.dns_search.join(",")));
+        }
+        vars.join(" ")
+    }
+
+    fn get_workload_limits(&self) -> String {
+        let mut limits: Vec<String> = self.workload_limits.iter().cloned().collect();
+        limits.extend(
+            self.workload_io_limits
+                .iter()
+                .map(|(device, limit)| format!("io:{device}/{limit}")),
+        );
+        if self.delegate_workload_cgroup {
+            limits.push("delegate:1".to_string());
+        }
+
+        if limits.is_empty() {
+            "".to_string()
+        } else {
+            format!("KRUN_CGROUP={}", limits.join(","))
+        }
+    }
+
+    fn get_lockdown(&self) -> String {
+        match self.lockdown {
+            Some(mode) => format!("module.sig_enforce=1 lockdown={mode} KRUN_LOCKDOWN={mode}"),
+            None => "".to_string(),
+        }
+    }
+
+    fn get_kdump(&self) -> String {
+        self.kdump.clone().unwrap_or_default()
+    }
+
     fn get_rlimits(&self) -> String {
         match &self.rlimits {
             Some(rlimits) => format!("KRUN_RLIMITS={rlimits}"),
@@ -248,6 +457,79 @@ impl ContextConfig {
         }
     }
 
+    fn get_ephemeral(&self) -> String {
+        match &self.ephemeral_dirs {
+            Some(dirs) => format!("KRUN_EPHEMERAL={}", dirs.join(",")),
+            None => "".to_string(),
+        }
+    }
+
+    fn get_kernel_modules(&self) -> String {
+        if self.kernel_modules.is_empty() {
+            return "".to_string();
+        }
+
+        let modules = format!("KRUN_MODULES=\"{}\"", self.kernel_modules.join(","));
+        if self.kernel_modules_dir {
+            format!("KRUN_MODULES_DIR={MODULES_MOUNTPOINT} {modules}")
+        } else {
+            modules
+        }
+    }
+
+    fn get_sysctls(&self) -> String {
+        if self.sysctls.is_empty() {
+            return "".to_string();
+        }
+
+        let sysctls: Vec<String> = self
+            .sysctls
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+        format!("KRUN_SYSCTL=\"{}\"", sysctls.join(","))
+    }
+
+    fn get_rosetta(&self) -> String {
+        if self.rosetta {
+            format!("KRUN_ROSETTA={ROSETTA_MOUNTPOINT}")
+        } else {
+            "".to_string()
+        }
+    }
+
+    fn get_agent(&self) -> String {
+        if self.agent {
+            "KRUN_AGENT=1".to_string()
+        } else {
+            "".to_string()
+        }
+    }
+
+    fn get_exec_hook(&self) -> String {
+        if self.exec_hook.is_some() {
+            "KRUN_EXEC_HOOK=1".to_string()
+        } else {
+            "".to_string()
+        }
+    }
+
+    fn get_ssh(&self) -> String {
+        if self.ssh_keys.is_some() {
+            "KRUN_SSHD=1".to_string()
+        } else {
+            "".to_string()
+        }
+    }
+
+    fn get_fido(&self) -> String {
+        if self.fido.is_some() {
+            "KRUN_FIDO=1".to_string()
+        } else {
+            "".to_string()
+        }
+    }
+
     #[cfg(feature = "blk")]
     fn add_block_cfg(&mut self, block_cfg: BlockDeviceConfig) {
         self.block_cfgs.push(block_cfg);
@@ -263,6 +545,16 @@ impl ContextConfig {
         self.data_block_cfg = Some(block_cfg);
     }
 
+    #[cfg(feature = "blk")]
+    fn find_block_cfg(&mut self, block_id: &str) -> Option<&mut BlockDeviceConfig> {
+        self.block_cfgs
+            .iter_mut()
+            .chain(self.root_block_cfg.iter_mut())
+            .chain(self.data_block_cfg.iter_mut())
+            .chain(self.mount_block_cfgs.iter_mut())
+            .find(|cfg| cfg.block_id == block_id)
+    }
+
     #[cfg(feature = "blk")]
     fn get_block_cfg(&self) -> Vec<BlockDeviceConfig> {
         // For backwards compat, when cfgs is empty (the new API is not used), this needs to be
@@ -270,14 +562,62 @@ impl ContextConfig {
         // need to discard redundant calls. So we have simple setters above and fix up here.
         //
         // When the new API is used, this is simpler.
-        if self.block_cfgs.is_empty() {
+        let mut block_cfgs: Vec<BlockDeviceConfig> = if self.block_cfgs.is_empty() {
             [&self.root_block_cfg, &self.data_block_cfg]
                 .into_iter()
                 .filter_map(|cfg| cfg.clone())
                 .collect()
         } else {
             self.block_cfgs.clone()
+        };
+        block_cfgs.extend(self.mount_block_cfgs.iter().cloned());
+        block_cfgs
+    }
+
+    fn get_sealed_disk(&self) -> String {
+        #[cfg(feature = "amd-sev")]
+        if let Some((block_id, guest_path, fields)) = &self.sealed_disk {
+            let index = self
+                .get_block_cfg()
+                .iter()
+                .position(|cfg| &cfg.block_id == block_id)
+                .unwrap();
+            return format!(
+                "KRUN_SEALED_DISK={}:{guest_path}:{fields:#x}",
+                guest_disk_name(index)
+            );
+        }
+        "".to_string()
+    }
+
+    fn get_mounts(&self) -> String {
+        if self.mounts.is_empty() {
+            return "".to_string();
         }
+
+        #[cfg(feature = "blk")]
+        let block_cfgs = self.get_block_cfg();
+        let mounts: Vec<String> = self
+            .mounts
+            .iter()
+            .map(|mount| {
+                let source = match &mount.source {
+                    #[cfg(not(feature = "tee"))]
+                    MountSource::Virtiofs(tag) => format!("virtiofs:{tag}"),
+                    MountSource::Tmpfs => "tmpfs:tmpfs".to_string(),
+                    #[cfg(feature = "blk")]
+                    MountSource::Image(block_id) => {
+                        let index = block_cfgs
+                            .iter()
+                            .position(|cfg| &cfg.block_id == block_id)
+                            .unwrap();
+                        format!("image:{}", guest_disk_name(index))
+                    }
+                };
+                format!("{source}:{}:{}", mount.target, mount.options)
+            })
+            .collect();
+        format!("KRUN_MOUNTS=\"{}\"", mounts.join(";"))
     }
 
     #[cfg(feature = "net")]
@@ -294,6 +634,37 @@ impl ContextConfig {
         Ok(())
     }
 
+    fn add_unix_socket_map(&mut self, guest_name: String, host_path: PathBuf) -> Result<(), ()> {
+        if self.net_index != 0 {
+            return Err(());
+        }
+
+        self.tsi_unix_socket_map
+            .get_or_insert_with(HashMap::new)
+            .insert(guest_name, host_path);
+        Ok(())
+    }
+
+    fn set_http_proxy(&mut self, http_proxy: Option<HttpProxyConfig>) -> Result<(), ()> {
+        if self.net_index != 0 {
+            return Err(());
+        }
+
+        self.tsi_http_proxy = http_proxy;
+        Ok(())
+    }
+
+    fn add_listen_fd(&mut self, guest_port: u16, fd: RawFd) -> Result<(), ()> {
+        if self.net_index != 0 {
+            return Err(());
+        }
+
+        if let Some(old_fd) = self.tsi_listen_fds.insert(guest_port, fd) {
+            unsafe { libc::close(old_fd) };
+        }
+        Ok(())
+    }
+
     #[cfg(feature = "tee")]
     fn set_tee_config_file(&mut self, filepath: PathBuf) {
         self.tee_config_file = Some(filepath);
@@ -322,6 +693,24 @@ impl ContextConfig {
         self.gpu_shm_size = Some(shm_size);
     }
 
+    /// The scratch directory of the context, which is created on first use.
+    fn scratch_dir(&mut self) -> io::Result<&ScratchDir> {
+        if self.scratch_dir.is_none() {
+            let mut dir = ScratchDir::create("krun")
+                .inspect_err(|e| error!("Error creating the scratch directory: {e}"))?;
+            if self.cleanup_supervisor {
+                dir.supervise()
+                    .inspect_err(|e| error!("Error spawning the cleanup supervisor: {e}"))?;
+            }
+            self.scratch_dir = Some(dir);
+        }
+        Ok(self.scratch_dir.as_ref().unwrap())
+    }
+
+    fn scratch_path(&mut self, name: &str) -> io::Result<PathBuf> {
+        Ok(self.scratch_dir()?.join(name))
+    }
+
     fn set_vmm_uid(&mut self, vmm_uid: libc::uid_t) {
         self.vmm_uid = Some(vmm_uid);
     }
@@ -388,6 +777,82 @@ fn with_cfg(ctx_id: u32, f: impl FnOnce(&mut ContextConfig) -> i32) -> i32 {
 
 static CTX_MAP: Lazy<Mutex<HashMap<u32, ContextConfig>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 static CTX_IDS: AtomicI32 = AtomicI32::new(0);
+// Contexts that have been consumed by krun_start_enter() and are now running.
+static RUNNING_CTXS: Lazy<Mutex<HashSet<u32>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+// Host side UNIX sockets of the guest agents, by context ID.
+static AGENT_SOCKETS: Lazy<Mutex<HashMap<u32, PathBuf>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// API sockets of the gvproxy instances serving the network, by context ID.
+#[cfg(feature = "net")]
+static GVPROXY_API_SOCKETS: Lazy<Mutex<HashMap<u32, PathBuf>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// Network devices of the running contexts, in the order they were added, for
+// krun_set_net_link_state().
+#[cfg(feature = "net")]
+type NetDevices = HashMap<u32, Vec<Arc<Mutex<Net>>>>;
+#[cfg(feature = "net")]
+static NET_DEVICES: Lazy<Mutex<NetDevices>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// TSI flows of the running contexts, for krun_get_tsi_flows_json().
+static TSI_FLOWS: Lazy<Mutex<HashMap<u32, FlowTable>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Metrics of the running contexts, for krun_get_metrics_json().
+static VM_METRICS: Lazy<Mutex<HashMap<u32, Arc<Metrics>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// Boot timelines of the running contexts, for krun_get_boot_timeline_json().
+static BOOT_TIMELINES: Lazy<Mutex<HashMap<u32, Arc<BootTimeline>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// VMMs and shutdown events of the contexts running in this process, and
+// VMMs running in supervised children, for krun_shutdown().
+static RUNNING_VMMS: Lazy<Mutex<HashMap<u32, Arc<Mutex<Vmm>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static SHUTDOWN_EVENTS: Lazy<Mutex<HashMap<u32, EventFd>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static SUPERVISED_VMMS: Lazy<Mutex<HashMap<u32, libc::pid_t>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// Guest memory of the running contexts, for krun_read_guest_mem() and
+// krun_write_guest_mem().
+static GUEST_MEMORY: Lazy<Mutex<HashMap<u32, GuestMemoryMmap>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+#[cfg(feature = "gpu")]
+static SCANOUT_CAPTURES: Lazy<Mutex<HashMap<u32, Arc<ScanoutCapture>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// Exit statuses of the processes started with krun_exec(), by context ID and
+// guest PID.
+type ExecStatusMap = HashMap<(u32, i32), crossbeam_channel::Receiver<i32>>;
+static EXEC_STATUS: Lazy<Mutex<ExecStatusMap>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Copies `json` as a null-terminated string into the caller-provided buffer,
+/// returning its length or a negative error number.
+unsafe fn write_json_to_buf(json: String, c_buf: *mut c_char, buf_len: size_t) -> i32 {
+    if c_buf.is_null() {
+        return -libc::EINVAL;
+    }
+
+    if json.len() >= buf_len {
+        return -libc::ENOSPC;
+    }
+
+    let buf = slice::from_raw_parts_mut(c_buf as *mut u8, buf_len);
+    buf[..json.len()].copy_from_slice(json.as_bytes());
+    buf[json.len()] = 0;
+
+    json.len() as i32
+}
+
+fn agent_socket(ctx_id: u32) -> Option<PathBuf> {
+    AGENT_SOCKETS.lock().unwrap().get(&ctx_id).cloned()
+}
+
+#[cfg(feature = "net")]
+fn gvproxy_api_socket(ctx_id: u32) -> Option<PathBuf> {
+    GVPROXY_API_SOCKETS.lock().unwrap().get(&ctx_id).cloned()
+}
+
+fn io_error_to_errno(e: io::Error) -> i32 {
+    -e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+fn ctx_exists(ctx_id: u32) -> bool {
+    CTX_MAP.lock().unwrap().contains_key(&ctx_id) || RUNNING_CTXS.lock().unwrap().contains(&ctx_id)
+}
 
 fn log_level_to_filter_str(level: u32) -> &'static str {
     match level {
@@ -400,11 +865,29 @@ fn log_level_to_filter_str(level: u32) -> &'static str {
     }
 }
 
+// Whether the logger of the process was set by krun_set_log_level() or
+// krun_init_log(), which does it only once for all the contexts.
+static LOG_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+fn init_logger(mut builder: env_logger::Builder) -> i32 {
+    match builder.try_init() {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("Failed to set the logger: {e}");
+            -libc::EEXIST
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn krun_set_log_level(level: u32) -> i32 {
+    if LOG_INITIALIZED.swap(true, Ordering::SeqCst) {
+        return -libc::EEXIST;
+    }
     let filter = log_level_to_filter_str(level);
-    env_logger::Builder::from_env(Env::default().default_filter_or(filter)).init();
-    KRUN_SUCCESS
+    init_logger(env_logger::Builder::from_env(
+        Env::default().default_filter_or(filter),
+    ))
 }
 
 mod log_defs {
@@ -417,6 +900,10 @@ mod log_defs {
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_init_log(target: RawFd, level: u32, style: u32, options: u32) -> i32 {
+    if LOG_INITIALIZED.load(Ordering::SeqCst) {
+        return -libc::EEXIST;
+    }
+
     let target = match target {
         ..-1 => return -libc::EINVAL,
         -1 => Target::default(),
@@ -452,9 +939,11 @@ pub unsafe extern "C" fn krun_init_log(target: RawFd, level: u32, style: u32, op
         builder.parse_filters(filter).parse_write_style(write_style);
         builder
     };
-    builder.target(target).init();
-
-    KRUN_SUCCESS
+    if LOG_INITIALIZED.swap(true, Ordering::SeqCst) {
+        return -libc::EEXIST;
+    }
+    builder.target(target);
+    init_logger(builder)
 }
 
 #[no_mangle]
@@ -466,6 +955,7 @@ pub extern "C" fn krun_create_ctx() -> i32 {
             ..Default::default()
         }
     };
+    ctx_cfg.vmr.boot_timeline.record(BootPhase::ContextCreated);
 
     let ctx_id = CTX_IDS.fetch_add(1, Ordering::SeqCst);
     if ctx_id == i32::MAX || CTX_MAP.lock().unwrap().contains_key(&(ctx_id as u32)) {
@@ -479,12 +969,53 @@ pub extern "C" fn krun_create_ctx() -> i32 {
 
 #[no_mangle]
 pub extern "C" fn krun_free_ctx(ctx_id: u32) -> i32 {
+    // Dropping the context also removes its scratch directory.
     match CTX_MAP.lock().unwrap().remove(&ctx_id) {
-        Some(_) => KRUN_SUCCESS,
+        Some(_) => {
+            AGENT_SOCKETS.lock().unwrap().remove(&ctx_id);
+            KRUN_SUCCESS
+        }
         None => -libc::ENOENT,
     }
 }
 
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_scratch_dir(
+    ctx_id: u32,
+    c_buf: *mut c_char,
+    buf_len: size_t,
+) -> i32 {
+    let path = match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => match ctx_cfg.get_mut().scratch_dir() {
+            Ok(dir) => dir.path().to_string_lossy().into_owned(),
+            Err(e) => return io_error_to_errno(e),
+        },
+        Entry::Vacant(_) => return -libc::ENOENT,
+    };
+
+    write_json_to_buf(path, c_buf, buf_len)
+}
+
+#[no_mangle]
+pub extern "C" fn krun_enable_cleanup_supervisor(ctx_id: u32) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.cleanup_supervisor = true;
+            if let Some(dir) = &mut cfg.scratch_dir {
+                if let Err(e) = dir.supervise() {
+                    error!("Error spawning the cleanup supervisor: {e}");
+                    return io_error_to_errno(e);
+                }
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
 #[no_mangle]
 pub extern "C" fn krun_set_vm_config(ctx_id: u32, num_vcpus: u8, ram_mib: u32) -> i32 {
     let mem_size_mib: usize = match ram_mib.try_into() {
@@ -534,6 +1065,12 @@ pub unsafe extern "C" fn krun_set_root(ctx_id: u32, c_root_path: *const c_char)
                 shared_dir,
                 // Default to a conservative 512 MB window.
                 shm_size: Some(1 << 29),
+                writeback: false,
+                allow_direct_io: false,
+                fd_cache_size: 0,
+                readonly: false,
+                quota: None,
+                threads: 1,
             });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
@@ -566,6 +1103,12 @@ pub unsafe extern "C" fn krun_add_virtiofs(
                 fs_id: tag.to_string(),
                 shared_dir: path.to_string(),
                 shm_size: None,
+                writeback: false,
+                allow_direct_io: false,
+                fd_cache_size: 0,
+                readonly: false,
+                quota: None,
+                threads: 1,
             });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
@@ -599,6 +1142,12 @@ pub unsafe extern "C" fn krun_add_virtiofs2(
                 fs_id: tag.to_string(),
                 shared_dir: path.to_string(),
                 shm_size: Some(shm_size.try_into().unwrap()),
+                writeback: false,
+                allow_direct_io: false,
+                fd_cache_size: 0,
+                readonly: false,
+                quota: None,
+                threads: 1,
             });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
@@ -607,46 +1156,35 @@ pub unsafe extern "C" fn krun_add_virtiofs2(
     KRUN_SUCCESS
 }
 
-#[allow(clippy::missing_safety_doc)]
-#[no_mangle]
 #[cfg(not(feature = "tee"))]
-pub unsafe extern "C" fn krun_set_mapped_volumes(
-    _ctx_id: u32,
-    _c_mapped_volumes: *const *const c_char,
-) -> i32 {
-    -libc::EINVAL
-}
+const KRUN_VIRTIOFS_WRITEBACK_CACHE: u32 = 1 << 0;
+#[cfg(not(feature = "tee"))]
+const KRUN_VIRTIOFS_DIRECT_IO: u32 = 1 << 1;
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(feature = "blk")]
-pub unsafe extern "C" fn krun_add_disk(
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_cache(
     ctx_id: u32,
-    c_block_id: *const c_char,
-    c_disk_path: *const c_char,
-    read_only: bool,
+    c_tag: *const c_char,
+    flags: u32,
 ) -> i32 {
-    let disk_path = match CStr::from_ptr(c_disk_path).to_str() {
-        Ok(disk) => disk,
-        Err(_) => return -libc::EINVAL,
-    };
-
-    let block_id = match CStr::from_ptr(c_block_id).to_str() {
-        Ok(block_id) => block_id,
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
         Err(_) => return -libc::EINVAL,
     };
+    if flags & !(KRUN_VIRTIOFS_WRITEBACK_CACHE | KRUN_VIRTIOFS_DIRECT_IO) != 0 {
+        return -libc::EINVAL;
+    }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            let block_device_config = BlockDeviceConfig {
-                block_id: block_id.to_string(),
-                cache_type: CacheType::auto(disk_path),
-                disk_image_path: disk_path.to_string(),
-                disk_image_format: ImageType::Raw,
-                is_disk_read_only: read_only,
+            let Some(fs) = cfg.vmr.fs.iter_mut().find(|fs| fs.fs_id == tag) else {
+                return -libc::ENODEV;
             };
-            cfg.add_block_cfg(block_device_config);
+            fs.writeback = flags & KRUN_VIRTIOFS_WRITEBACK_CACHE != 0;
+            fs.allow_direct_io = flags & KRUN_VIRTIOFS_DIRECT_IO != 0;
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -656,44 +1194,26 @@ pub unsafe extern "C" fn krun_add_disk(
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(feature = "blk")]
-pub unsafe extern "C" fn krun_add_disk2(
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_limits(
     ctx_id: u32,
-    c_block_id: *const c_char,
-    c_disk_path: *const c_char,
-    disk_format: u32,
-    read_only: bool,
+    c_tag: *const c_char,
+    readonly: bool,
+    quota: u64,
 ) -> i32 {
-    let disk_path = match CStr::from_ptr(c_disk_path).to_str() {
-        Ok(disk) => disk,
-        Err(_) => return -libc::EINVAL,
-    };
-
-    let block_id = match CStr::from_ptr(c_block_id).to_str() {
-        Ok(block_id) => block_id,
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
         Err(_) => return -libc::EINVAL,
     };
 
-    let format = match disk_format {
-        0 => ImageType::Raw,
-        1 => ImageType::Qcow2,
-        _ => {
-            // Do not continue if the user cannot specify a valid disk format
-            return -libc::EINVAL;
-        }
-    };
-
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            let block_device_config = BlockDeviceConfig {
-                block_id: block_id.to_string(),
-                cache_type: CacheType::auto(disk_path),
-                disk_image_path: disk_path.to_string(),
-                disk_image_format: format,
-                is_disk_read_only: read_only,
+            let Some(fs) = cfg.vmr.fs.iter_mut().find(|fs| fs.fs_id == tag) else {
+                return -libc::ENODEV;
             };
-            cfg.add_block_cfg(block_device_config);
+            fs.readonly = readonly;
+            fs.quota = (quota != 0).then_some(quota);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -703,24 +1223,24 @@ pub unsafe extern "C" fn krun_add_disk2(
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(feature = "blk")]
-pub unsafe extern "C" fn krun_set_root_disk(ctx_id: u32, c_disk_path: *const c_char) -> i32 {
-    let disk_path = match CStr::from_ptr(c_disk_path).to_str() {
-        Ok(disk) => disk,
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_fd_cache(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    max_fds: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
         Err(_) => return -libc::EINVAL,
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            let block_device_config = BlockDeviceConfig {
-                block_id: "root".to_string(),
-                cache_type: CacheType::auto(disk_path),
-                disk_image_path: disk_path.to_string(),
-                disk_image_format: ImageType::Raw,
-                is_disk_read_only: false,
+            let Some(fs) = cfg.vmr.fs.iter_mut().find(|fs| fs.fs_id == tag) else {
+                return -libc::ENODEV;
             };
-            cfg.set_root_block_cfg(block_device_config);
+            fs.fd_cache_size = max_fds as usize;
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -730,24 +1250,27 @@ pub unsafe extern "C" fn krun_set_root_disk(ctx_id: u32, c_disk_path: *const c_c
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(feature = "blk")]
-pub unsafe extern "C" fn krun_set_data_disk(ctx_id: u32, c_disk_path: *const c_char) -> i32 {
-    let disk_path = match CStr::from_ptr(c_disk_path).to_str() {
-        Ok(disk) => disk,
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_threads(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    threads: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
         Err(_) => return -libc::EINVAL,
     };
+    if threads == 0 {
+        return -libc::EINVAL;
+    }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            let block_device_config = BlockDeviceConfig {
-                block_id: "data".to_string(),
-                cache_type: CacheType::auto(disk_path),
-                disk_image_path: disk_path.to_string(),
-                disk_image_format: ImageType::Raw,
-                is_disk_read_only: false,
+            let Some(fs) = cfg.vmr.fs.iter_mut().find(|fs| fs.fs_id == tag) else {
+                return -libc::ENODEV;
             };
-            cfg.set_data_block_cfg(block_device_config);
+            fs.threads = threads as usize;
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -755,341 +1278,505 @@ pub unsafe extern "C" fn krun_set_data_disk(ctx_id: u32, c_disk_path: *const c_c
     KRUN_SUCCESS
 }
 
-/*
- * Send the VFKIT magic after establishing the connection,
- * as required by gvproxy in vfkit mode.
- */
-#[cfg(feature = "net")]
-const NET_FLAG_VFKIT: u32 = 1 << 0;
-
-/* Taken from uapi/linux/virtio_net.h */
-#[cfg(feature = "net")]
-const NET_FEATURE_CSUM: u32 = 1 << 0;
-#[cfg(feature = "net")]
-const NET_FEATURE_GUEST_CSUM: u32 = 1 << 1;
-#[cfg(feature = "net")]
-const NET_FEATURE_GUEST_TSO4: u32 = 1 << 7;
-#[cfg(feature = "net")]
-const NET_FEATURE_GUEST_TSO6: u32 = 1 << 8;
-#[cfg(feature = "net")]
-const NET_FEATURE_GUEST_UFO: u32 = 1 << 10;
-#[cfg(feature = "net")]
-const NET_FEATURE_HOST_TSO4: u32 = 1 << 11;
-#[cfg(feature = "net")]
-const NET_FEATURE_HOST_TSO6: u32 = 1 << 12;
-#[cfg(feature = "net")]
-const NET_FEATURE_HOST_UFO: u32 = 1 << 14;
-/*
- * These are the flags enabled by default on each virtio-net instance
- * before the introduction of "krun_add_net_*". They are now used in
- * the legacy API ("krun_set_passt_fd" and "krun_set_gvproxy_path")
- * for compatiblity reasons.
- */
-#[cfg(feature = "net")]
-const NET_COMPAT_FEATURES: u32 = NET_FEATURE_CSUM
-    | NET_FEATURE_GUEST_CSUM
-    | NET_FEATURE_GUEST_TSO4
-    | NET_FEATURE_GUEST_UFO
-    | NET_FEATURE_HOST_TSO4
-    | NET_FEATURE_HOST_UFO;
-#[cfg(feature = "net")]
-const NET_ALL_FEATURES: u32 = NET_FEATURE_CSUM
-    | NET_FEATURE_GUEST_CSUM
-    | NET_FEATURE_GUEST_TSO4
-    | NET_FEATURE_GUEST_TSO6
-    | NET_FEATURE_GUEST_UFO
-    | NET_FEATURE_HOST_TSO4
-    | NET_FEATURE_HOST_TSO6
-    | NET_FEATURE_HOST_UFO;
+const KRUN_MOUNT_BIND: u32 = 0;
+const KRUN_MOUNT_TMPFS: u32 = 1;
+const KRUN_MOUNT_IMAGE: u32 = 2;
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(feature = "net")]
-pub unsafe extern "C" fn krun_add_net_unixstream(
+pub unsafe extern "C" fn krun_add_mount(
     ctx_id: u32,
-    c_path: *const c_char,
-    fd: c_int,
-    c_mac: *const u8,
-    features: u32,
-    flags: u32,
+    mount_type: u32,
+    c_source: *const c_char,
+    c_guest_path: *const c_char,
+    c_options: *const c_char,
 ) -> i32 {
-    let path = if !c_path.is_null() {
-        match CStr::from_ptr(c_path).to_str() {
-            Ok(path) => Some(PathBuf::from(path)),
-            Err(_) => None,
+    // Fields of the comma-separated KRUN_MOUNTS entries on the kernel
+    // command line.
+    fn valid_field(field: &str) -> bool {
+        !field.contains([':', ';', '"']) && !field.contains(char::is_whitespace)
+    }
+
+    let source = if c_source.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(c_source).to_str() {
+            Ok(source) => Some(source),
+            Err(_) => return -libc::EINVAL,
         }
+    };
+    let guest_path = match CStr::from_ptr(c_guest_path).to_str() {
+        Ok(path) if path.starts_with('/') && path != "/" && valid_field(path) => path,
+        _ => return -libc::EINVAL,
+    };
+    let options = if c_options.is_null() {
+        ""
     } else {
-        None
+        match CStr::from_ptr(c_options).to_str() {
+            Ok(options) if valid_field(options) => options,
+            _ => return -libc::EINVAL,
+        }
     };
 
-    if fd >= 0 && path.is_some() {
-        return -libc::EINVAL;
-    }
-    if fd < 0 && path.is_none() {
-        return -libc::EINVAL;
+    // The options libkrun handles itself, the others are left to the guest.
+    let mut read_only = false;
+    let mut guest_options = Vec::new();
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        if mount_type == KRUN_MOUNT_IMAGE && option.starts_with("format=") {
+            continue;
+        }
+        read_only |= option == "ro";
+        guest_options.push(option);
     }
-    let backend = if let Some(path) = path {
-        VirtioNetBackend::UnixstreamPath(path)
-    } else {
-        VirtioNetBackend::UnixstreamFd(fd)
+
+    let mut ctx_map = CTX_MAP.lock().unwrap();
+    let cfg = match ctx_map.get_mut(&ctx_id) {
+        Some(cfg) => cfg,
+        None => return -libc::ENOENT,
     };
 
-    let mac: [u8; 6] = match slice::from_raw_parts(c_mac, 6).try_into() {
-        Ok(m) => m,
-        Err(_) => return -libc::EINVAL,
+    let mount_source = match mount_type {
+        #[cfg(not(feature = "tee"))]
+        KRUN_MOUNT_BIND => {
+            let Some(path) = source else {
+                return -libc::EINVAL;
+            };
+            let tag = format!("krun-mount{}", cfg.mounts.len());
+            cfg.vmr.add_fs_device(FsDeviceConfig {
+                fs_id: tag.clone(),
+                shared_dir: path.to_string(),
+                shm_size: None,
+                writeback: false,
+                allow_direct_io: false,
+                fd_cache_size: 0,
+                readonly: read_only,
+                quota: None,
+                threads: 1,
+            });
+            MountSource::Virtiofs(tag)
+        }
+        #[cfg(feature = "tee")]
+        KRUN_MOUNT_BIND => return -libc::ENOTSUP,
+        KRUN_MOUNT_TMPFS => MountSource::Tmpfs,
+        #[cfg(feature = "blk")]
+        KRUN_MOUNT_IMAGE => {
+            let Some(path) = source else {
+                return -libc::EINVAL;
+            };
+            let format = options
+                .split(',')
+                .find_map(|option| option.strip_prefix("format="));
+            let format = match format {
+                None | Some("raw") => ImageType::Raw,
+                Some("qcow2") => ImageType::Qcow2,
+                Some(_) => return -libc::EINVAL,
+            };
+            let block_id = format!("krun-mount{}", cfg.mounts.len());
+            cfg.mount_block_cfgs.push(BlockDeviceConfig {
+                block_id: block_id.clone(),
+                cache_type: CacheType::auto(path),
+                disk_image_path: path.to_string(),
+                disk_image_format: format,
+                is_disk_read_only: read_only,
+                overlay: None,
+                luks_passphrase: None,
+                verity: None,
+                http_url: None,
+                zoned: None,
+            });
+            MountSource::Image(block_id)
+        }
+        #[cfg(not(feature = "blk"))]
+        KRUN_MOUNT_IMAGE => return -libc::ENOTSUP,
+        _ => return -libc::EINVAL,
     };
 
-    /* The unixstream backend doesn't support any flags */
-    if flags != 0 {
-        return -libc::EINVAL;
-    }
+    cfg.mounts.push(GuestMount {
+        source: mount_source,
+        target: guest_path.to_string(),
+        options: guest_options.join(","),
+    });
 
-    if (features & !NET_ALL_FEATURES) != 0 {
-        return -libc::EINVAL;
-    }
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_mapped_volumes(
+    _ctx_id: u32,
+    _c_mapped_volumes: *const *const c_char,
+) -> i32 {
+    -libc::EINVAL
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_add_disk(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_disk_path: *const c_char,
+    read_only: bool,
+) -> i32 {
+    let disk_path = match CStr::from_ptr(c_disk_path).to_str() {
+        Ok(disk) => disk,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
+        Err(_) => return -libc::EINVAL,
+    };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            create_virtio_net(cfg, backend, mac, features);
+            let block_device_config = BlockDeviceConfig {
+                block_id: block_id.to_string(),
+                cache_type: CacheType::auto(disk_path),
+                disk_image_path: disk_path.to_string(),
+                disk_image_format: ImageType::Raw,
+                is_disk_read_only: read_only,
+                overlay: None,
+                luks_passphrase: None,
+                verity: None,
+                http_url: None,
+                zoned: None,
+            };
+            cfg.add_block_cfg(block_device_config);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
+
     KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(feature = "net")]
-pub unsafe extern "C" fn krun_add_net_unixgram(
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_add_disk2(
     ctx_id: u32,
-    c_path: *const c_char,
-    fd: c_int,
-    c_mac: *const u8,
-    features: u32,
-    flags: u32,
+    c_block_id: *const c_char,
+    c_disk_path: *const c_char,
+    disk_format: u32,
+    read_only: bool,
 ) -> i32 {
-    let path = if !c_path.is_null() {
-        match CStr::from_ptr(c_path).to_str() {
-            Ok(path) => Some(PathBuf::from(path)),
-            Err(_) => None,
-        }
-    } else {
-        None
+    let disk_path = match CStr::from_ptr(c_disk_path).to_str() {
+        Ok(disk) => disk,
+        Err(_) => return -libc::EINVAL,
     };
 
-    if fd >= 0 && path.is_some() {
-        return -libc::EINVAL;
-    }
-    if fd < 0 && path.is_none() {
-        return -libc::EINVAL;
-    }
-
-    let mac: [u8; 6] = match slice::from_raw_parts(c_mac, 6).try_into() {
-        Ok(m) => m,
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
         Err(_) => return -libc::EINVAL,
     };
 
-    if (features & !NET_ALL_FEATURES) != 0 {
-        return -libc::EINVAL;
-    }
-
-    if (flags & !NET_FLAG_VFKIT) != 0 {
-        return -libc::EINVAL;
-    }
-    let send_vfkit_magic: bool = flags & NET_FLAG_VFKIT != 0;
-
-    let backend = if let Some(path) = path {
-        VirtioNetBackend::UnixgramPath(path, send_vfkit_magic)
-    } else {
-        VirtioNetBackend::UnixgramFd(fd)
+    let format = match disk_format {
+        0 => ImageType::Raw,
+        1 => ImageType::Qcow2,
+        _ => {
+            // Do not continue if the user cannot specify a valid disk format
+            return -libc::EINVAL;
+        }
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            create_virtio_net(cfg, backend, mac, features);
+            let block_device_config = BlockDeviceConfig {
+                block_id: block_id.to_string(),
+                cache_type: CacheType::auto(disk_path),
+                disk_image_path: disk_path.to_string(),
+                disk_image_format: format,
+                is_disk_read_only: read_only,
+                overlay: None,
+                luks_passphrase: None,
+                verity: None,
+                http_url: None,
+                zoned: None,
+            };
+            cfg.add_block_cfg(block_device_config);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
+
     KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(all(target_os = "linux", feature = "net"))]
-pub unsafe extern "C" fn krun_add_net_tap(
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_add_disk_http(
     ctx_id: u32,
-    c_tap_name: *const c_char,
-    c_mac: *const u8,
-    features: u32,
-    flags: u32,
-) -> i32 {
-    let tap_name = match CStr::from_ptr(c_tap_name).to_str() {
-        Ok(tap_name) => tap_name.to_string(),
-        Err(e) => {
-            debug!("Error parsing tap_name: {e:?}");
-            return -libc::EINVAL;
-        }
-    };
+    c_block_id: *const c_char,
+    c_url: *const c_char,
+    c_cache_path: *const c_char,
+    read_only: bool,
+) -> i32 {
+    if !cfg!(feature = "http") {
+        return -libc::ENOTSUP;
+    }
 
-    let mac: [u8; 6] = match slice::from_raw_parts(c_mac, 6).try_into() {
-        Ok(m) => m,
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
         Err(_) => return -libc::EINVAL,
     };
 
-    if (features & !NET_ALL_FEATURES) != 0 {
-        return -libc::EINVAL;
-    }
-
-    if features & (NET_FEATURE_GUEST_TSO4 | NET_FEATURE_GUEST_TSO6 | NET_FEATURE_GUEST_UFO) != 0
-        && features & NET_FEATURE_GUEST_CSUM == 0
-    {
-        debug!("Network tap backend requires GUEST_CSUM to be requested if any of GUEST_TSO4, GUEST_TSO6 and/or GUEST_UFO are required");
-        return -libc::EINVAL;
-    }
+    let url = match CStr::from_ptr(c_url).to_str() {
+        Ok(url) if url.starts_with("http://") || url.starts_with("https://") => url,
+        _ => return -libc::EINVAL,
+    };
 
-    /* The tap backend doesn't support any flags */
-    if flags != 0 {
-        return -libc::EINVAL;
-    }
+    let cache_path = match CStr::from_ptr(c_cache_path).to_str() {
+        Ok(cache_path) => cache_path,
+        Err(_) => return -libc::EINVAL,
+    };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            create_virtio_net(cfg, VirtioNetBackend::Tap(tap_name), mac, features);
+            let block_device_config = BlockDeviceConfig {
+                block_id: block_id.to_string(),
+                cache_type: CacheType::auto(cache_path),
+                disk_image_path: cache_path.to_string(),
+                disk_image_format: ImageType::Raw,
+                is_disk_read_only: read_only,
+                overlay: None,
+                luks_passphrase: None,
+                verity: None,
+                http_url: Some(url.to_string()),
+                zoned: None,
+            };
+            cfg.add_block_cfg(block_device_config);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
+
     KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(all(not(target_os = "linux"), feature = "net"))]
-pub unsafe extern "C" fn krun_add_net_tap(
-    _ctx_id: u32,
-    _c_tap_name: *const c_char,
-    _c_mac: *const u8,
-    _features: u32,
-    _flags: u32,
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_set_disk_zoned(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    zone_size: u64,
+    max_open_zones: u32,
+    max_active_zones: u32,
 ) -> i32 {
-    -libc::EINVAL
-}
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
+        Err(_) => return -libc::EINVAL,
+    };
 
-#[allow(clippy::missing_safety_doc)]
-#[no_mangle]
-#[cfg(feature = "net")]
-pub unsafe extern "C" fn krun_set_passt_fd(ctx_id: u32, fd: c_int) -> i32 {
-    if fd < 0 {
+    if !zone_size.is_power_of_two()
+        || zone_size < 4096
+        || zone_size > (u32::MAX as u64) << 9
+        || (max_active_zones != 0 && max_open_zones > max_active_zones)
+    {
         return -libc::EINVAL;
     }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            // The legacy interface only supports a single network interface.
-            if cfg.net_index != 0 {
-                return -libc::EINVAL;
-            }
-            cfg.legacy_net_cfg = Some(LegacyNetworkConfig::VirtioNetPasst(fd));
+            let Some(block_cfg) = cfg.find_block_cfg(block_id) else {
+                return -libc::ENODEV;
+            };
+            block_cfg.zoned = Some(ZonedConfig {
+                zone_size,
+                max_open: max_open_zones,
+                max_active: max_active_zones,
+            });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
+
     KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(feature = "net")]
-pub unsafe extern "C" fn krun_set_gvproxy_path(ctx_id: u32, c_path: *const c_char) -> i32 {
-    let path_str = match CStr::from_ptr(c_path).to_str() {
-        Ok(path) => path,
-        Err(e) => {
-            debug!("Error parsing gvproxy_path: {e:?}");
-            return -libc::EINVAL;
-        }
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_add_disk_overlay(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_base_path: *const c_char,
+    base_format: u32,
+    c_overlay_path: *const c_char,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
+        Err(_) => return -libc::EINVAL,
     };
 
-    let path = PathBuf::from(path_str);
+    let base_path = match CStr::from_ptr(c_base_path).to_str() {
+        Ok(base_path) => base_path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let base_format = match base_format {
+        0 => ImageType::Raw,
+        1 => ImageType::Qcow2,
+        _ => return -libc::EINVAL,
+    };
+
+    let overlay_path = if c_overlay_path.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(c_overlay_path).to_str() {
+            Ok(path) => Some(path.to_string()),
+            Err(_) => return -libc::EINVAL,
+        }
+    };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            // The legacy interface only supports a single network interface.
-            if cfg.net_index != 0 {
-                return -libc::EINVAL;
-            }
-            cfg.legacy_net_cfg = Some(LegacyNetworkConfig::VirtioNetGvproxy(path));
+            let (overlay_path, ephemeral) = match overlay_path {
+                Some(path) => (path, false),
+                None => match cfg.scratch_path(&format!("overlay-{block_id}.qcow2")) {
+                    Ok(path) => (path.to_string_lossy().into_owned(), true),
+                    Err(e) => return io_error_to_errno(e),
+                },
+            };
+            let block_device_config = BlockDeviceConfig {
+                block_id: block_id.to_string(),
+                cache_type: CacheType::auto(&overlay_path),
+                disk_image_path: overlay_path,
+                disk_image_format: ImageType::Qcow2,
+                is_disk_read_only: false,
+                overlay: Some(OverlayConfig {
+                    base_path: base_path.to_string(),
+                    base_format,
+                    ephemeral,
+                }),
+                luks_passphrase: None,
+                verity: None,
+                http_url: None,
+                zoned: None,
+            };
+            cfg.add_block_cfg(block_device_config);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
+
     KRUN_SUCCESS
 }
 
-#[allow(clippy::missing_safety_doc)]
-#[no_mangle]
-#[cfg(feature = "net")]
-pub unsafe extern "C" fn krun_set_net_mac(ctx_id: u32, c_mac: *const u8) -> i32 {
-    let mac: [u8; 6] = match slice::from_raw_parts(c_mac, 6).try_into() {
-        Ok(m) => m,
-        Err(_) => return -libc::EINVAL,
-    };
+#[cfg(feature = "blk")]
+fn set_disk_luks_passphrase(ctx_id: u32, block_id: &str, passphrase: Vec<u8>) -> i32 {
+    if !cfg!(feature = "luks") {
+        return -libc::ENOTSUP;
+    }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.set_net_mac(mac);
+            let Some(block_cfg) = cfg.find_block_cfg(block_id) else {
+                return -libc::ENODEV;
+            };
+            block_cfg.luks_passphrase = Some(LuksPassphrase(passphrase));
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
+
     KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *const c_char) -> i32 {
-    let mut port_map = HashMap::new();
-    let port_map_array: &[*const c_char] = slice::from_raw_parts(c_port_map, MAX_ARGS);
-    for item in port_map_array.iter().take(MAX_ARGS) {
-        if item.is_null() {
-            break;
-        } else {
-            let s = match CStr::from_ptr(*item).to_str() {
-                Ok(s) => s,
-                Err(_) => return -libc::EINVAL,
-            };
-            let port_tuple: Vec<&str> = s.split(':').collect();
-            if port_tuple.len() != 2 {
-                return -libc::EINVAL;
-            }
-            let host_port: u16 = match port_tuple[0].parse() {
-                Ok(p) => p,
-                Err(_) => return -libc::EINVAL,
-            };
-            let guest_port: u16 = match port_tuple[1].parse() {
-                Ok(p) => p,
-                Err(_) => return -libc::EINVAL,
-            };
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_set_disk_luks_passphrase(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_passphrase: *const c_char,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
+        Err(_) => return -libc::EINVAL,
+    };
 
-            if port_map.contains_key(&guest_port) {
-                return -libc::EINVAL;
-            }
-            for hp in port_map.values() {
-                if *hp == host_port {
-                    return -libc::EINVAL;
-                }
-            }
-            port_map.insert(guest_port, host_port);
+    let passphrase = CStr::from_ptr(c_passphrase).to_bytes().to_vec();
+    set_disk_luks_passphrase(ctx_id, block_id, passphrase)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_set_disk_luks_key_file(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_key_file: *const c_char,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let key_file = match CStr::from_ptr(c_key_file).to_str() {
+        Ok(key_file) => key_file,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    // Like cryptsetup, the whole file is the passphrase.
+    match std::fs::read(key_file) {
+        Ok(passphrase) => set_disk_luks_passphrase(ctx_id, block_id, passphrase),
+        Err(e) => {
+            error!("Failed to read the key file {key_file}: {e}");
+            -e.raw_os_error().unwrap_or(libc::EIO)
         }
     }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_set_disk_verity(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_hash_path: *const c_char,
+    c_root_hash: *const c_char,
+) -> i32 {
+    if !cfg!(feature = "verity") {
+        return -libc::ENOTSUP;
+    }
+
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let hash_path = match CStr::from_ptr(c_hash_path).to_str() {
+        Ok(hash_path) => hash_path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let root_hash = match CStr::from_ptr(c_root_hash).to_str() {
+        Ok(root_hash) if root_hash.len() % 2 == 0 => root_hash,
+        _ => return -libc::EINVAL,
+    };
+    let Ok(root_hash) = (0..root_hash.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&root_hash[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+    else {
+        return -libc::EINVAL;
+    };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            if cfg.set_port_map(port_map).is_err() {
-                return -libc::EINVAL;
-            }
+            let Some(block_cfg) = cfg.find_block_cfg(block_id) else {
+                return -libc::ENODEV;
+            };
+            block_cfg.is_disk_read_only = true;
+            block_cfg.verity = Some(VerityConfig {
+                hash_path: hash_path.to_string(),
+                root_hash,
+            });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1099,49 +1786,57 @@ pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *cons
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_rlimits(ctx_id: u32, c_rlimits: *const *const c_char) -> i32 {
-    let rlimits = if c_rlimits.is_null() {
-        return -libc::EINVAL;
-    } else {
-        let mut strvec = Vec::new();
-
-        let array: &[*const c_char] = slice::from_raw_parts(c_rlimits, MAX_ARGS);
-        for item in array.iter().take(MAX_ARGS) {
-            if item.is_null() {
-                break;
-            } else {
-                let s = match CStr::from_ptr(*item).to_str() {
-                    Ok(s) => s,
-                    Err(_) => return -libc::EINVAL,
-                };
-                strvec.push(s);
-            }
-        }
+pub unsafe extern "C" fn krun_create_disk_image(
+    c_path: *const c_char,
+    size: u64,
+    fs_type: u32,
+) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
 
-        format!("\"{}\"", strvec.join(","))
+    let fs_type = match fs_type {
+        0 => FsType::None,
+        1 => FsType::Ext4,
+        2 => FsType::Btrfs,
+        _ => return -libc::EINVAL,
     };
 
-    match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => {
-            ctx_cfg.get_mut().set_rlimits(rlimits);
+    match mkfs::create_image(Path::new(path), size, fs_type) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            error!("Failed to create the disk image {path}: {e}");
+            -e.raw_os_error().unwrap_or(libc::EIO)
         }
-        Entry::Vacant(_) => return -libc::ENOENT,
     }
-
-    KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_workdir(ctx_id: u32, c_workdir_path: *const c_char) -> i32 {
-    let workdir_path = match CStr::from_ptr(c_workdir_path).to_str() {
-        Ok(workdir) => workdir,
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_set_root_disk(ctx_id: u32, c_disk_path: *const c_char) -> i32 {
+    let disk_path = match CStr::from_ptr(c_disk_path).to_str() {
+        Ok(disk) => disk,
         Err(_) => return -libc::EINVAL,
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            ctx_cfg.get_mut().set_workdir(workdir_path.to_string());
+            let cfg = ctx_cfg.get_mut();
+            let block_device_config = BlockDeviceConfig {
+                block_id: "root".to_string(),
+                cache_type: CacheType::auto(disk_path),
+                disk_image_path: disk_path.to_string(),
+                disk_image_format: ImageType::Raw,
+                is_disk_read_only: false,
+                overlay: None,
+                luks_passphrase: None,
+                verity: None,
+                http_url: None,
+                zoned: None,
+            };
+            cfg.set_root_block_cfg(block_device_config);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1149,72 +1844,31 @@ pub unsafe extern "C" fn krun_set_workdir(ctx_id: u32, c_workdir_path: *const c_
     KRUN_SUCCESS
 }
 
-unsafe fn collapse_str_array(array: &[*const c_char]) -> Result<String, std::str::Utf8Error> {
-    let mut strvec = Vec::new();
-
-    for item in array.iter().take(MAX_ARGS) {
-        if item.is_null() {
-            break;
-        } else {
-            let s = CStr::from_ptr(*item).to_str()?;
-            strvec.push(format!("\"{s}\""));
-        }
-    }
-
-    Ok(strvec.join(" "))
-}
-
-#[allow(clippy::format_collect)]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_exec(
-    ctx_id: u32,
-    c_exec_path: *const c_char,
-    c_argv: *const *const c_char,
-    c_envp: *const *const c_char,
-) -> i32 {
-    let exec_path = match CStr::from_ptr(c_exec_path).to_str() {
-        Ok(path) => path,
-        Err(e) => {
-            debug!("Error parsing exec_path: {e:?}");
-            return -libc::EINVAL;
-        }
-    };
-
-    let args = if !c_argv.is_null() {
-        let argv_array: &[*const c_char] = slice::from_raw_parts(c_argv, MAX_ARGS);
-        match collapse_str_array(argv_array) {
-            Ok(s) => s,
-            Err(e) => {
-                debug!("Error parsing args: {e:?}");
-                return -libc::EINVAL;
-            }
-        }
-    } else {
-        "".to_string()
-    };
-
-    let env = if !c_envp.is_null() {
-        let envp_array: &[*const c_char] = slice::from_raw_parts(c_envp, MAX_ARGS);
-        match collapse_str_array(envp_array) {
-            Ok(s) => s,
-            Err(e) => {
-                debug!("Error parsing args: {e:?}");
-                return -libc::EINVAL;
-            }
-        }
-    } else {
-        env::vars()
-            .map(|(key, value)| format!(" {key}=\"{value}\""))
-            .collect()
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_set_data_disk(ctx_id: u32, c_disk_path: *const c_char) -> i32 {
+    let disk_path = match CStr::from_ptr(c_disk_path).to_str() {
+        Ok(disk) => disk,
+        Err(_) => return -libc::EINVAL,
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.set_exec_path(exec_path.to_string());
-            cfg.set_env(env);
-            cfg.set_args(args);
+            let block_device_config = BlockDeviceConfig {
+                block_id: "data".to_string(),
+                cache_type: CacheType::auto(disk_path),
+                disk_image_path: disk_path.to_string(),
+                disk_image_format: ImageType::Raw,
+                is_disk_read_only: false,
+                overlay: None,
+                luks_passphrase: None,
+                verity: None,
+                http_url: None,
+                zoned: None,
+            };
+            cfg.set_data_block_cfg(block_device_config);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1222,29 +1876,22 @@ pub unsafe extern "C" fn krun_set_exec(
     KRUN_SUCCESS
 }
 
-#[allow(clippy::format_collect)]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_env(ctx_id: u32, c_envp: *const *const c_char) -> i32 {
-    let env = if !c_envp.is_null() {
-        let envp_array: &[*const c_char] = slice::from_raw_parts(c_envp, MAX_ARGS);
-        match collapse_str_array(envp_array) {
-            Ok(s) => s,
-            Err(e) => {
-                debug!("Error parsing args: {e:?}");
-                return -libc::EINVAL;
-            }
-        }
-    } else {
-        env::vars()
-            .map(|(key, value)| format!(" {key}=\"{value}\""))
-            .collect()
+#[cfg(all(target_os = "linux", not(feature = "tee")))]
+pub unsafe extern "C" fn krun_add_vdpa(ctx_id: u32, c_path: *const c_char) -> i32 {
+    if c_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.set_env(env);
+            cfg.vmr.vdpa_devices.push(path);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1254,301 +1901,633 @@ pub unsafe extern "C" fn krun_set_env(ctx_id: u32, c_envp: *const *const c_char)
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-#[cfg(feature = "tee")]
-pub unsafe extern "C" fn krun_set_tee_config_file(ctx_id: u32, c_filepath: *const c_char) -> i32 {
-    let filepath = match CStr::from_ptr(c_filepath).to_str() {
-        Ok(f) => f,
+#[cfg(not(all(target_os = "linux", not(feature = "tee"))))]
+pub unsafe extern "C" fn krun_add_vdpa(_ctx_id: u32, _c_path: *const c_char) -> i32 {
+    -libc::EINVAL
+}
+
+/*
+ * Send the VFKIT magic after establishing the connection,
+ * as required by gvproxy in vfkit mode.
+ */
+#[cfg(feature = "net")]
+const NET_FLAG_VFKIT: u32 = 1 << 0;
+
+/* Taken from uapi/linux/virtio_net.h */
+#[cfg(feature = "net")]
+const NET_FEATURE_CSUM: u32 = 1 << 0;
+#[cfg(feature = "net")]
+const NET_FEATURE_GUEST_CSUM: u32 = 1 << 1;
+#[cfg(feature = "net")]
+const NET_FEATURE_GUEST_TSO4: u32 = 1 << 7;
+#[cfg(feature = "net")]
+const NET_FEATURE_GUEST_TSO6: u32 = 1 << 8;
+#[cfg(feature = "net")]
+const NET_FEATURE_GUEST_UFO: u32 = 1 << 10;
+#[cfg(feature = "net")]
+const NET_FEATURE_HOST_TSO4: u32 = 1 << 11;
+#[cfg(feature = "net")]
+const NET_FEATURE_HOST_TSO6: u32 = 1 << 12;
+#[cfg(feature = "net")]
+const NET_FEATURE_HOST_UFO: u32 = 1 << 14;
+/*
+ * These are the flags enabled by default on each virtio-net instance
+ * before the introduction of "krun_add_net_*". They are now used in
+ * the legacy API ("krun_set_passt_fd" and "krun_set_gvproxy_path")
+ * for compatiblity reasons.
+ */
+#[cfg(feature = "net")]
+const NET_COMPAT_FEATURES: u32 = NET_FEATURE_CSUM
+    | NET_FEATURE_GUEST_CSUM
+    | NET_FEATURE_GUEST_TSO4
+    | NET_FEATURE_GUEST_UFO
+    | NET_FEATURE_HOST_TSO4
+    | NET_FEATURE_HOST_UFO;
+#[cfg(feature = "net")]
+const NET_ALL_FEATURES: u32 = NET_FEATURE_CSUM
+    | NET_FEATURE_GUEST_CSUM
+    | NET_FEATURE_GUEST_TSO4
+    | NET_FEATURE_GUEST_TSO6
+    | NET_FEATURE_GUEST_UFO
+    | NET_FEATURE_HOST_TSO4
+    | NET_FEATURE_HOST_TSO6
+    | NET_FEATURE_HOST_UFO;
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_add_net_unixstream(
+    ctx_id: u32,
+    c_path: *const c_char,
+    fd: c_int,
+    c_mac: *const u8,
+    features: u32,
+    flags: u32,
+) -> i32 {
+    let path = if !c_path.is_null() {
+        match CStr::from_ptr(c_path).to_str() {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => None,
+        }
+    } else {
+        None
+    };
+
+    if fd >= 0 && path.is_some() {
+        return -libc::EINVAL;
+    }
+    if fd < 0 && path.is_none() {
+        return -libc::EINVAL;
+    }
+    let backend = if let Some(path) = path {
+        VirtioNetBackend::UnixstreamPath(path)
+    } else {
+        VirtioNetBackend::UnixstreamFd(fd)
+    };
+
+    let mac: [u8; 6] = match slice::from_raw_parts(c_mac, 6).try_into() {
+        Ok(m) => m,
         Err(_) => return -libc::EINVAL,
     };
 
+    /* The unixstream backend doesn't support any flags */
+    if flags != 0 {
+        return -libc::EINVAL;
+    }
+
+    if (features & !NET_ALL_FEATURES) != 0 {
+        return -libc::EINVAL;
+    }
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.set_tee_config_file(PathBuf::from(filepath.to_string()));
+            create_virtio_net(cfg, backend, mac, features);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
-
     KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_add_vsock_port(
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_add_net_unixgram(
     ctx_id: u32,
-    port: u32,
-    c_filepath: *const c_char,
+    c_path: *const c_char,
+    fd: c_int,
+    c_mac: *const u8,
+    features: u32,
+    flags: u32,
 ) -> i32 {
-    krun_add_vsock_port2(ctx_id, port, c_filepath, false)
-}
+    let path = if !c_path.is_null() {
+        match CStr::from_ptr(c_path).to_str() {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => None,
+        }
+    } else {
+        None
+    };
 
-#[allow(clippy::missing_safety_doc)]
-#[no_mangle]
-pub unsafe extern "C" fn krun_add_vsock_port2(
-    ctx_id: u32,
-    port: u32,
-    c_filepath: *const c_char,
-    listen: bool,
-) -> i32 {
-    #[cfg(feature = "nitro")]
-    if listen {
+    if fd >= 0 && path.is_some() {
+        return -libc::EINVAL;
+    }
+    if fd < 0 && path.is_none() {
         return -libc::EINVAL;
     }
 
-    let filepath = match CStr::from_ptr(c_filepath).to_str() {
-        Ok(f) => PathBuf::from(f.to_string()),
+    let mac: [u8; 6] = match slice::from_raw_parts(c_mac, 6).try_into() {
+        Ok(m) => m,
         Err(_) => return -libc::EINVAL,
     };
 
-    if listen {
-        match filepath.try_exists() {
-            Ok(true) => return -libc::EEXIST,
-            Err(_) => return -libc::EINVAL,
-            _ => {}
-        }
+    if (features & !NET_ALL_FEATURES) != 0 {
+        return -libc::EINVAL;
     }
 
+    if (flags & !NET_FLAG_VFKIT) != 0 {
+        return -libc::EINVAL;
+    }
+    let send_vfkit_magic: bool = flags & NET_FLAG_VFKIT != 0;
+
+    let backend = if let Some(path) = path {
+        VirtioNetBackend::UnixgramPath(path, send_vfkit_magic)
+    } else {
+        VirtioNetBackend::UnixgramFd(fd)
+    };
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.add_vsock_port(port, filepath, listen);
+            create_virtio_net(cfg, backend, mac, features);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
-
     KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_gpu_options(ctx_id: u32, virgl_flags: u32) -> i32 {
-    match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            cfg.set_gpu_virgl_flags(virgl_flags);
+#[cfg(all(target_os = "linux", feature = "net"))]
+pub unsafe extern "C" fn krun_add_net_tap(
+    ctx_id: u32,
+    c_tap_name: *const c_char,
+    c_mac: *const u8,
+    features: u32,
+    flags: u32,
+) -> i32 {
+    let tap_name = match CStr::from_ptr(c_tap_name).to_str() {
+        Ok(tap_name) => tap_name.to_string(),
+        Err(e) => {
+            debug!("Error parsing tap_name: {e:?}");
+            return -libc::EINVAL;
         }
-        Entry::Vacant(_) => return -libc::ENOENT,
+    };
+
+    let mac: [u8; 6] = match slice::from_raw_parts(c_mac, 6).try_into() {
+        Ok(m) => m,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    if (features & !NET_ALL_FEATURES) != 0 {
+        return -libc::EINVAL;
     }
 
-    KRUN_SUCCESS
-}
+    if features & (NET_FEATURE_GUEST_TSO4 | NET_FEATURE_GUEST_TSO6 | NET_FEATURE_GUEST_UFO) != 0
+        && features & NET_FEATURE_GUEST_CSUM == 0
+    {
+        debug!("Network tap backend requires GUEST_CSUM to be requested if any of GUEST_TSO4, GUEST_TSO6 and/or GUEST_UFO are required");
+        return -libc::EINVAL;
+    }
+
+    /* The tap backend doesn't support any flags */
+    if flags != 0 {
+        return -libc::EINVAL;
+    }
 
-#[allow(clippy::missing_safety_doc)]
-#[no_mangle]
-pub unsafe extern "C" fn krun_set_gpu_options2(
-    ctx_id: u32,
-    virgl_flags: u32,
-    shm_size: u64,
-) -> i32 {
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.set_gpu_virgl_flags(virgl_flags);
-            cfg.set_gpu_shm_size(shm_size.try_into().unwrap());
+            create_virtio_net(cfg, VirtioNetBackend::Tap(tap_name), mac, features);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
-
     KRUN_SUCCESS
 }
 
-#[cfg(not(feature = "gpu"))]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub extern "C" fn krun_set_display_backend(
+#[cfg(all(not(target_os = "linux"), feature = "net"))]
+pub unsafe extern "C" fn krun_add_net_tap(
     _ctx_id: u32,
+    _c_tap_name: *const c_char,
+    _c_mac: *const u8,
     _features: u32,
-    _vtable: *const c_void,
-    _vtable_size: usize,
+    _flags: u32,
 ) -> i32 {
-    -libc::ENOTSUP
+    -libc::EINVAL
 }
 
-#[cfg(feature = "gpu")]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub extern "C" fn krun_set_display_backend(
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_add_net_switch(
     ctx_id: u32,
-    vtable: *const c_void,
-    vtable_size: usize,
+    c_path: *const c_char,
+    c_mac: *const u8,
+    features: u32,
+    flags: u32,
+    vlan: u16,
 ) -> i32 {
-    if vtable_size < size_of::<DisplayBackend>() {
+    if c_path.is_null() {
         return -libc::EINVAL;
     }
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
 
-    // SAFETY: We have checked the vtable size is fine, otherwise we have to trust the user. Just
-    // to be extra careful, this uses read_unaligned, but we could probably get away with ptr::read.
-    let display_backend: DisplayBackend =
-        unsafe { std::ptr::read_unaligned(vtable as *const DisplayBackend) };
+    let mac: [u8; 6] = match slice::from_raw_parts(c_mac, 6).try_into() {
+        Ok(m) => m,
+        Err(_) => return -libc::EINVAL,
+    };
 
-    if !display_backend.verify() {
+    if (features & !NET_ALL_FEATURES) != 0 {
+        return -libc::EINVAL;
+    }
+
+    /* The switch backend doesn't support any flags */
+    if flags != 0 || vlan > switch::MAX_VLAN {
         return -libc::EINVAL;
     }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.vmr.display_backend = Some(display_backend);
+            create_virtio_net(cfg, VirtioNetBackend::Switch(path, vlan), mac, features);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
-
     KRUN_SUCCESS
 }
 
-#[cfg(feature = "gpu")]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_add_display(ctx_id: u32, width: u32, height: u32) -> i32 {
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_set_passt_fd(ctx_id: u32, fd: c_int) -> i32 {
+    if fd < 0 {
+        return -libc::EINVAL;
+    }
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            if cfg.vmr.displays.len() >= MAX_DISPLAYS {
-                return -libc::ENOMEM;
+            // The legacy interface only supports a single network interface.
+            if cfg.net_index != 0 {
+                return -libc::EINVAL;
             }
-
-            cfg.vmr.displays.push(DisplayInfo::new(width, height));
-            (cfg.vmr.displays.len() - 1) as i32
+            cfg.legacy_net_cfg = Some(LegacyNetworkConfig::VirtioNetPasst(fd));
         }
-        Entry::Vacant(_) => -libc::ENOENT,
+        Entry::Vacant(_) => return -libc::ENOENT,
     }
+    KRUN_SUCCESS
 }
 
-#[cfg(not(feature = "gpu"))]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_add_display(_ctx_id: u32, _width: u32, _height: u32) -> i32 {
-    -libc::ENOTSUP
-}
-
-#[cfg(feature = "gpu")]
-#[no_mangle]
-pub extern "C" fn krun_display_set_refresh_rate(
-    ctx_id: u32,
-    display_id: u32,
-    refresh_rate: u32,
-) -> i32 {
-    with_cfg(ctx_id, |cfg| {
-        let Some(display_info) = cfg.vmr.displays.get_mut(display_id as usize) else {
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_set_gvproxy_path(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path_str = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(e) => {
+            debug!("Error parsing gvproxy_path: {e:?}");
             return -libc::EINVAL;
-        };
+        }
+    };
 
-        let DisplayInfoEdid::Generated(ref mut edid_params) = display_info.edid else {
-            return -libc::EALREADY;
-        };
+    let path = PathBuf::from(path_str);
 
-        edid_params.refresh_rate = refresh_rate;
-        KRUN_SUCCESS
-    })
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            // The legacy interface only supports a single network interface.
+            if cfg.net_index != 0 {
+                return -libc::EINVAL;
+            }
+            cfg.legacy_net_cfg = Some(LegacyNetworkConfig::VirtioNetGvproxy(path));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+    KRUN_SUCCESS
 }
 
-#[cfg(not(feature = "gpu"))]
+#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub extern "C" fn krun_display_set_refresh_rate(
-    _ctx_id: u32,
-    _display_id: u32,
-    _refresh_rate: u32,
-) -> i32 {
-    -libc::ENOTSUP
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_set_net_mac(ctx_id: u32, c_mac: *const u8) -> i32 {
+    let mac: [u8; 6] = match slice::from_raw_parts(c_mac, 6).try_into() {
+        Ok(m) => m,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_net_mac(mac);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+    KRUN_SUCCESS
 }
 
-#[cfg(feature = "gpu")]
+#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_set_net_mtu(ctx_id: u32, mtu: u16) -> i32 {
+    // The minimum for IPv4.
+    if mtu < 68 {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.net_mtu = Some(mtu);
+            for net in cfg.vmr.net.list.iter() {
+                net.lock().unwrap().set_mtu(mtu);
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+    KRUN_SUCCESS
+}
+
 #[allow(clippy::missing_safety_doc)]
-pub unsafe extern "C" fn krun_display_set_edid(
+#[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_set_net_netem(
     ctx_id: u32,
-    display_id: u32,
-    edid: *const u8,
-    size: size_t,
+    iface: u32,
+    delay_ms: u32,
+    jitter_ms: u32,
+    loss_ppm: u32,
+    rate: u64,
 ) -> i32 {
-    with_cfg(ctx_id, |cfg| {
-        let Some(display_info) = cfg.vmr.displays.get_mut(display_id as usize) else {
-            return -libc::EINVAL;
-        };
+    if loss_ppm > 1_000_000 {
+        return -libc::EINVAL;
+    }
+    let config = NetemConfig {
+        delay: Duration::from_millis(delay_ms.into()),
+        jitter: Duration::from_millis(jitter_ms.into()),
+        loss_ppm,
+        rate,
+    };
 
-        if edid.is_null() {
-            return -libc::EINVAL;
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let netem = &mut ctx_cfg.get_mut().net_netem;
+            if config == NetemConfig::default() {
+                netem.remove(&iface);
+            } else {
+                netem.insert(iface, config);
+            }
         }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+    KRUN_SUCCESS
+}
 
-        let blob = unsafe { slice::from_raw_parts(edid, size) };
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_add_net_egress_rule(ctx_id: u32, c_rule: *const c_char) -> i32 {
+    if c_rule.is_null() {
+        return -libc::EINVAL;
+    }
+    let Some(rule) = CStr::from_ptr(c_rule)
+        .to_str()
+        .ok()
+        .and_then(egress::Rule::parse)
+    else {
+        return -libc::EINVAL;
+    };
 
-        display_info.edid = DisplayInfoEdid::Provided(Box::from(blob));
-        KRUN_SUCCESS
-    })
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().net_egress_rules.push(rule),
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+    KRUN_SUCCESS
 }
 
-#[cfg(not(feature = "gpu"))]
+#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_set_net_egress_default(ctx_id: u32, allow: bool) -> i32 {
+    let action = if allow {
+        egress::Action::Allow
+    } else {
+        egress::Action::Deny
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().net_egress_default = Some(action),
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+    KRUN_SUCCESS
+}
+
 #[allow(clippy::missing_safety_doc)]
-pub unsafe extern "C" fn krun_display_set_edid(
-    _ctx_id: u32,
-    _display_id: u32,
-    _edid: *const u8,
-    _size: size_t,
-) -> i32 {
-    -libc::ENOTSUP
+#[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_set_net_link_state(ctx_id: u32, iface: u32, up: bool) -> i32 {
+    if !ctx_exists(ctx_id) {
+        return -libc::ENOENT;
+    }
+    let devices = NET_DEVICES.lock().unwrap();
+    let Some(devices) = devices.get(&ctx_id) else {
+        // The context isn't running yet.
+        return -libc::EBUSY;
+    };
+    let Some(net) = devices.get(iface as usize) else {
+        return -libc::ENODEV;
+    };
+    net.lock().unwrap().set_link_up(up);
+    KRUN_SUCCESS
 }
 
-#[cfg(feature = "gpu")]
+#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub extern "C" fn krun_display_set_physical_size(
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_set_gvproxy_api_socket(ctx_id: u32, c_path: *const c_char) -> i32 {
+    if c_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+    if !ctx_exists(ctx_id) {
+        return -libc::ENOENT;
+    }
+
+    GVPROXY_API_SOCKETS.lock().unwrap().insert(ctx_id, path);
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_net_expose_port(
     ctx_id: u32,
-    display_id: u32,
-    width_mm: u16,
-    height_mm: u16,
+    c_protocol: *const c_char,
+    c_local: *const c_char,
+    c_remote: *const c_char,
 ) -> i32 {
-    with_cfg(ctx_id, |cfg| {
-        let Some(display_info) = cfg.vmr.displays.get_mut(display_id as usize) else {
-            return -libc::EINVAL;
-        };
-        let DisplayInfoEdid::Generated(ref mut edid_params) = display_info.edid else {
-            return -libc::EALREADY;
-        };
-        edid_params.physical_size = PhysicalSize::DimensionsMillimeters(width_mm, height_mm);
-        KRUN_SUCCESS
-    })
+    if c_protocol.is_null() || c_local.is_null() || c_remote.is_null() {
+        return -libc::EINVAL;
+    }
+    let (Some(protocol), Ok(local), Ok(remote)) = (
+        CStr::from_ptr(c_protocol)
+            .to_str()
+            .ok()
+            .and_then(Protocol::parse),
+        CStr::from_ptr(c_local).to_str(),
+        CStr::from_ptr(c_remote).to_str(),
+    ) else {
+        return -libc::EINVAL;
+    };
+    let Some(path) = gvproxy_api_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+
+    match GvproxyClient::new(&path)
+        .with_timeout(GVPROXY_TIMEOUT)
+        .expose(protocol, local, remote)
+    {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => io_error_to_errno(e),
+    }
 }
 
-#[cfg(not(feature = "gpu"))]
+#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub extern "C" fn krun_display_set_physical_size(
-    _ctx_id: u32,
-    _display_id: u32,
-    _width_mm: u16,
-    _height_mm: u16,
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_net_unexpose_port(
+    ctx_id: u32,
+    c_protocol: *const c_char,
+    c_local: *const c_char,
 ) -> i32 {
-    -libc::ENOTSUP
+    if c_protocol.is_null() || c_local.is_null() {
+        return -libc::EINVAL;
+    }
+    let (Some(protocol), Ok(local)) = (
+        CStr::from_ptr(c_protocol)
+            .to_str()
+            .ok()
+            .and_then(Protocol::parse),
+        CStr::from_ptr(c_local).to_str(),
+    ) else {
+        return -libc::EINVAL;
+    };
+    let Some(path) = gvproxy_api_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+
+    match GvproxyClient::new(&path)
+        .with_timeout(GVPROXY_TIMEOUT)
+        .unexpose(protocol, local)
+    {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => io_error_to_errno(e),
+    }
 }
 
-#[cfg(feature = "gpu")]
-#[no_mangle]
 #[allow(clippy::missing_safety_doc)]
-pub extern "C" fn krun_display_set_dpi(ctx_id: u32, display_id: u32, dpi: u32) -> i32 {
-    with_cfg(ctx_id, |cfg| {
-        let Some(display_info) = cfg.vmr.displays.get_mut(display_id as usize) else {
-            return -libc::EINVAL;
-        };
-        let DisplayInfoEdid::Generated(ref mut edid_params) = display_info.edid else {
-            return -libc::EINVAL;
-        };
-        edid_params.physical_size = PhysicalSize::Dpi(dpi);
-        KRUN_SUCCESS
-    })
+#[no_mangle]
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_net_get_forwards_json(
+    ctx_id: u32,
+    c_buf: *mut c_char,
+    buf_len: size_t,
+) -> i32 {
+    let Some(path) = gvproxy_api_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+
+    match GvproxyClient::new(&path)
+        .with_timeout(GVPROXY_TIMEOUT)
+        .forwards()
+    {
+        Ok(json) => write_json_to_buf(json, c_buf, buf_len),
+        Err(e) => io_error_to_errno(e),
+    }
 }
 
-#[cfg(not(feature = "gpu"))]
+#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub extern "C" fn krun_display_set_dpi(_ctx_id: u32, _display_id: u32, _dpi: u32) -> i32 {
-    -libc::ENOTSUP
+#[cfg(feature = "net")]
+pub unsafe extern "C" fn krun_net_get_stats_json(
+    ctx_id: u32,
+    c_buf: *mut c_char,
+    buf_len: size_t,
+) -> i32 {
+    let Some(path) = gvproxy_api_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+
+    match GvproxyClient::new(&path)
+        .with_timeout(GVPROXY_TIMEOUT)
+        .stats()
+    {
+        Ok(json) => write_json_to_buf(json, c_buf, buf_len),
+        Err(e) => io_error_to_errno(e),
+    }
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_snd_device(ctx_id: u32, enable: bool) -> i32 {
+pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *const c_char) -> i32 {
+    let mut port_map = HashMap::new();
+    let port_map_array: &[*const c_char] = slice::from_raw_parts(c_port_map, MAX_ARGS);
+    for item in port_map_array.iter().take(MAX_ARGS) {
+        if item.is_null() {
+            break;
+        } else {
+            let s = match CStr::from_ptr(*item).to_str() {
+                Ok(s) => s,
+                Err(_) => return -libc::EINVAL,
+            };
+            let port_tuple: Vec<&str> = s.split(':').collect();
+            if port_tuple.len() != 2 {
+                return -libc::EINVAL;
+            }
+            let host_port: u16 = match port_tuple[0].parse() {
+                Ok(p) => p,
+                Err(_) => return -libc::EINVAL,
+            };
+            let guest_port: u16 = match port_tuple[1].parse() {
+                Ok(p) => p,
+                Err(_) => return -libc::EINVAL,
+            };
+
+            if port_map.contains_key(&guest_port) {
+                return -libc::EINVAL;
+            }
+            for hp in port_map.values() {
+                if *hp == host_port {
+                    return -libc::EINVAL;
+                }
+            }
+            port_map.insert(guest_port, host_port);
+        }
+    }
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.enable_snd = enable;
+            if cfg.set_port_map(port_map).is_err() {
+                return -libc::EINVAL;
+            }
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1556,151 +2535,231 @@ pub unsafe extern "C" fn krun_set_snd_device(ctx_id: u32, enable: bool) -> i32 {
     KRUN_SUCCESS
 }
 
-#[allow(unused_assignments)]
+#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub extern "C" fn krun_get_shutdown_eventfd(ctx_id: u32) -> i32 {
+pub unsafe extern "C" fn krun_add_unix_socket_map(
+    ctx_id: u32,
+    c_guest_name: *const c_char,
+    c_host_path: *const c_char,
+) -> i32 {
+    if c_guest_name.is_null() || c_host_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let (Ok(guest_name), Ok(host_path)) = (
+        CStr::from_ptr(c_guest_name).to_str(),
+        CStr::from_ptr(c_host_path).to_str(),
+    ) else {
+        return -libc::EINVAL;
+    };
+    if guest_name.is_empty() || guest_name == "@" || host_path.is_empty() {
+        return -libc::EINVAL;
+    }
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            if let Some(efd) = cfg.shutdown_efd.as_ref() {
-                #[cfg(target_os = "macos")]
-                return efd.get_write_fd();
-                #[cfg(target_os = "linux")]
-                return efd.as_raw_fd();
-            } else {
-                -libc::EINVAL
+            if cfg
+                .add_unix_socket_map(guest_name.to_string(), PathBuf::from(host_path))
+                .is_err()
+            {
+                return -libc::ENOTSUP;
             }
         }
-        Entry::Vacant(_) => -libc::ENOENT,
+        Entry::Vacant(_) => return -libc::ENOENT,
     }
+
+    KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_console_output(ctx_id: u32, c_filepath: *const c_char) -> i32 {
-    let filepath = match CStr::from_ptr(c_filepath).to_str() {
-        Ok(f) => f,
-        Err(_) => return -libc::EINVAL,
+pub unsafe extern "C" fn krun_set_http_proxy(
+    ctx_id: u32,
+    c_proxy_url: *const c_char,
+    c_ports: *const u16,
+    num_ports: usize,
+) -> i32 {
+    let ports = if c_ports.is_null() {
+        http_proxy::DEFAULT_PORTS.to_vec()
+    } else {
+        slice::from_raw_parts(c_ports, num_ports).to_vec()
+    };
+
+    let http_proxy = if c_proxy_url.is_null() {
+        HttpProxyConfig::from_env(ports)
+    } else {
+        let Some(http_proxy) = CStr::from_ptr(c_proxy_url)
+            .to_str()
+            .ok()
+            .and_then(|url| HttpProxyConfig::from_url(url, ports))
+        else {
+            return -libc::EINVAL;
+        };
+        Some(http_proxy)
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            if cfg.console_output.is_some() {
-                -libc::EINVAL
-            } else {
-                cfg.console_output = Some(PathBuf::from(filepath.to_string()));
-                KRUN_SUCCESS
+            if ctx_cfg.get_mut().set_http_proxy(http_proxy).is_err() {
+                return -libc::ENOTSUP;
             }
         }
-        Entry::Vacant(_) => -libc::ENOENT,
+        Entry::Vacant(_) => return -libc::ENOENT,
     }
+
+    KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_nested_virt(ctx_id: u32, enabled: bool) -> i32 {
-    if enabled && !cfg!(target_os = "macos") {
+pub unsafe extern "C" fn krun_add_tcp_listen_fd(ctx_id: u32, guest_port: u32, fd: c_int) -> i32 {
+    let Ok(guest_port) = u16::try_from(guest_port) else {
+        return -libc::EINVAL;
+    };
+
+    let mut sock_type: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as libc::socklen_t;
+    if libc::getsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_TYPE,
+        &mut sock_type as *mut c_int as *mut libc::c_void,
+        &mut len,
+    ) < 0
+    {
+        return -std::io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EINVAL);
+    }
+    if sock_type != libc::SOCK_STREAM {
         return -libc::EINVAL;
     }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.vmr.nested_enabled = enabled;
-            KRUN_SUCCESS
+            if cfg.add_listen_fd(guest_port, fd).is_err() {
+                return -libc::ENOTSUP;
+            }
         }
-        Entry::Vacant(_) => -libc::ENOENT,
-    }
-}
-
-#[allow(clippy::missing_safety_doc)]
-#[no_mangle]
-pub unsafe extern "C" fn krun_check_nested_virt() -> i32 {
-    #[cfg(target_os = "macos")]
-    match hvf::check_nested_virt() {
-        Ok(supp) => supp as i32,
-        Err(_) => -libc::EINVAL,
+        Entry::Vacant(_) => return -libc::ENOENT,
     }
 
-    #[cfg(not(target_os = "macos"))]
-    -libc::EOPNOTSUPP
+    KRUN_SUCCESS
 }
 
-/// Gets the maximum number of vCPUs supported by the hypervisor.
-///
-/// Returns the maximum number of vCPUs that can be created by this hypervisor,
-/// or a negative error code on failure.
-#[cfg(any(target_os = "macos", target_os = "linux"))]
-#[no_mangle]
-pub extern "C" fn krun_get_max_vcpus() -> i32 {
-    #[cfg(target_os = "macos")]
-    {
-        use hvf::bindings::{hv_vm_get_max_vcpu_count, HV_SUCCESS};
-        let mut max_vcpu_count: u32 = 0;
-        let ret = unsafe { hv_vm_get_max_vcpu_count(&mut max_vcpu_count as *mut u32) };
-        if ret == HV_SUCCESS {
-            max_vcpu_count as i32
-        } else {
-            error!("Error retrieving max vcpu count: {ret:?}");
-            -libc::EINVAL
+/// Translates an rlimit in the "RESOURCE=RLIM_CUR:RLIM_MAX" format, where
+/// RESOURCE may also be a name like "nofile" or "RLIMIT_NOFILE" and the
+/// limits may be "unlimited", to the numeric form init expects.
+fn normalize_rlimit(rlimit: &str) -> Option<String> {
+    // The resource IDs used by Linux guests, whatever the host is.
+    const RLIMIT_NAMES: [&str; 16] = [
+        "cpu",
+        "fsize",
+        "data",
+        "stack",
+        "core",
+        "rss",
+        "nproc",
+        "nofile",
+        "memlock",
+        "as",
+        "locks",
+        "sigpending",
+        "msgqueue",
+        "nice",
+        "rtprio",
+        "rttime",
+    ];
+
+    let (resource, limits) = rlimit.split_once('=')?;
+    let (cur, max) = limits.split_once(':')?;
+
+    let id = match resource.parse::<u32>() {
+        Ok(id) => id,
+        Err(_) => {
+            let name = resource.to_ascii_lowercase();
+            let name = name.strip_prefix("rlimit_").unwrap_or(&name);
+            RLIMIT_NAMES.iter().position(|n| *n == name)? as u32
         }
-    }
+    };
+    let limit = |value: &str| match value {
+        "unlimited" => Some(u64::MAX),
+        _ => value.parse::<u64>().ok(),
+    };
 
-    #[cfg(target_os = "linux")]
-    {
-        use kvm_ioctls::Kvm;
-        match Kvm::new() {
-            Ok(kvm) => kvm.get_max_vcpus() as i32,
-            Err(e) => {
-                error!("Error retrieving max vcpu count: {e:?}");
-                -libc::EINVAL
-            }
-        }
-    }
+    Some(format!("{id}={}:{}", limit(cur)?, limit(max)?))
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub extern "C" fn krun_split_irqchip(ctx_id: u32, enable: bool) -> i32 {
-    if enable && !cfg!(target_arch = "x86_64") {
+pub unsafe extern "C" fn krun_set_rlimits(ctx_id: u32, c_rlimits: *const *const c_char) -> i32 {
+    let rlimits = if c_rlimits.is_null() {
         return -libc::EINVAL;
-    }
+    } else {
+        let mut strvec = Vec::new();
+
+        let array: &[*const c_char] = slice::from_raw_parts(c_rlimits, MAX_ARGS);
+        for item in array.iter().take(MAX_ARGS) {
+            if item.is_null() {
+                break;
+            } else {
+                let s = match CStr::from_ptr(*item)
+                    .to_str()
+                    .ok()
+                    .and_then(normalize_rlimit)
+                {
+                    Some(s) => s,
+                    None => return -libc::EINVAL,
+                };
+                strvec.push(s);
+            }
+        }
+
+        format!("\"{}\"", strvec.join(","))
+    };
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            cfg.vmr.split_irqchip = enable;
-            KRUN_SUCCESS
+            ctx_cfg.get_mut().set_rlimits(rlimits);
         }
-        Entry::Vacant(_) => -libc::ENOENT,
+        Entry::Vacant(_) => return -libc::ENOENT,
     }
+
+    KRUN_SUCCESS
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_smbios_oem_strings(
-    ctx_id: u32,
-    oem_strings: *const *const c_char,
-) -> i32 {
-    if oem_strings.is_null() {
-        return -libc::EINVAL;
-    }
-
-    let cstr_ptr_slice = slice::from_raw_parts(oem_strings, MAX_ARGS);
-
-    let mut oem_strings = Vec::new();
+pub unsafe extern "C" fn krun_set_ephemeral_root(ctx_id: u32, c_dirs: *const *const c_char) -> i32 {
+    const DEFAULT_EPHEMERAL_DIRS: [&str; 3] = ["/tmp", "/run", "/var"];
 
-    for cstr_ptr in cstr_ptr_slice.iter().take_while(|p| !p.is_null()) {
-        let Ok(s) = CStr::from_ptr(*cstr_ptr).to_str() else {
-            return -libc::EINVAL;
-        };
-        oem_strings.push(s.to_string());
-    }
+    let dirs = if c_dirs.is_null() {
+        DEFAULT_EPHEMERAL_DIRS.map(String::from).to_vec()
+    } else {
+        let mut dirs = Vec::new();
+        let array: &[*const c_char] = slice::from_raw_parts(c_dirs, MAX_ARGS);
+        for item in array.iter().take_while(|item| !item.is_null()) {
+            // They end up in a comma-separated list on the kernel command
+            // line.
+            match CStr::from_ptr(*item).to_str() {
+                Ok(dir)
+                    if dir.starts_with('/')
+                        && dir != "/"
+                        && !dir.contains(',')
+                        && !dir.contains(char::is_whitespace) =>
+                {
+                    dirs.push(dir.to_string())
+                }
+                _ => return -libc::EINVAL,
+            }
+        }
+        dirs
+    };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            ctx_cfg.get_mut().vmr.smbios_oem_strings =
-                (!oem_strings.is_empty()).then_some(oem_strings)
+            ctx_cfg.get_mut().ephemeral_dirs = Some(dirs);
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1708,239 +2767,130 @@ pub unsafe extern "C" fn krun_set_smbios_oem_strings(
     KRUN_SUCCESS
 }
 
-#[cfg(feature = "net")]
-fn create_virtio_net(
-    ctx_cfg: &mut ContextConfig,
-    backend: VirtioNetBackend,
-    mac: [u8; 6],
-    features: u32,
-) {
-    let network_interface_config = NetworkInterfaceConfig {
-        iface_id: format!("eth{}", ctx_cfg.net_index),
-        backend,
-        mac,
-        features,
-    };
-    ctx_cfg.net_index += 1;
-    ctx_cfg
-        .vmr
-        .add_network_interface(network_interface_config)
-        .expect("Failed to create network interface");
-}
-
-#[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
-fn map_kernel(ctx_id: u32, kernel_path: &PathBuf) -> i32 {
-    let file = match File::options().read(true).write(false).open(kernel_path) {
-        Ok(file) => file,
-        Err(err) => {
-            error!("Error opening external kernel: {err}");
-            return -libc::EINVAL;
-        }
-    };
-
-    let kernel_size = file.metadata().unwrap().len();
-
-    let kernel_host_addr = unsafe {
-        libc::mmap(
-            std::ptr::null_mut(),
-            kernel_size as usize,
-            libc::PROT_READ,
-            libc::MAP_SHARED,
-            file.as_raw_fd(),
-            0_i64,
-        )
-    };
-    if std::ptr::eq(kernel_host_addr, libc::MAP_FAILED) {
-        error!("Can't load kernel into process map");
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_kernel_modules_dir(ctx_id: u32, c_path: *const c_char) -> i32 {
+    if c_path.is_null() {
         return -libc::EINVAL;
     }
-
-    let kernel_bundle = KernelBundle {
-        host_addr: kernel_host_addr as u64,
-        guest_addr: 0x8000_0000,
-        entry_addr: 0x8000_0000,
-        size: kernel_size as usize,
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(p) => p,
+        Err(_) => return -libc::EINVAL,
     };
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => return -libc::ENOTDIR,
+        Err(e) => return io_error_to_errno(e),
+    }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => ctx_cfg
-            .get_mut()
-            .vmr
-            .set_kernel_bundle(kernel_bundle)
-            .unwrap(),
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.fs.retain(|fs| fs.fs_id != MODULES_TAG);
+            cfg.vmr.add_fs_device(FsDeviceConfig {
+                fs_id: MODULES_TAG.to_string(),
+                shared_dir: path.to_string(),
+                shm_size: None,
+                writeback: false,
+                allow_direct_io: false,
+                fd_cache_size: 0,
+                readonly: true,
+                quota: None,
+                threads: 1,
+            });
+            cfg.kernel_modules_dir = true;
+        }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
 
     KRUN_SUCCESS
 }
 
-#[cfg(feature = "tee")]
-#[allow(clippy::format_collect)]
-#[allow(clippy::missing_safety_doc)]
-#[no_mangle]
-pub unsafe extern "C" fn krun_set_kernel(_ctx_id: u32, _c_kernel_path: *const c_char) -> i32 {
-    -libc::EOPNOTSUPP
-}
-
-#[cfg(not(feature = "tee"))]
-#[allow(clippy::format_collect)]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_kernel(
+pub unsafe extern "C" fn krun_add_kernel_module(
     ctx_id: u32,
-    c_kernel_path: *const c_char,
-    kernel_format: u32,
-    c_initramfs_path: *const c_char,
-    c_cmdline: *const c_char,
+    c_module: *const c_char,
+    c_params: *const c_char,
 ) -> i32 {
-    let path = match CStr::from_ptr(c_kernel_path).to_str() {
-        Ok(path) => PathBuf::from(path),
-        Err(e) => {
-            error!("Error parsing kernel_path: {e:?}");
-            return -libc::EINVAL;
-        }
-    };
-
-    let format = match kernel_format {
-        // For raw kernels in x86_64, we map the kernel into the
-        // process and treat it as a bundled kernel.
-        #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
-        0 => return map_kernel(ctx_id, &path),
-        #[cfg(target_arch = "aarch64")]
-        0 => KernelFormat::Raw,
-        1 => KernelFormat::Elf,
-        2 => KernelFormat::PeGz,
-        3 => KernelFormat::ImageBz2,
-        4 => KernelFormat::ImageGz,
-        5 => KernelFormat::ImageZstd,
-        _ => {
-            return -libc::EINVAL;
+    if c_module.is_null() {
+        return -libc::EINVAL;
+    }
+    // Neither of them may break the list init gets on the kernel command
+    // line, where the parameters follow the module after a colon.
+    let module = match CStr::from_ptr(c_module).to_str() {
+        Ok(m)
+            if !m.is_empty()
+                && !m.contains([',', ':', '"'])
+                && !m.contains(char::is_whitespace) =>
+        {
+            m
         }
+        _ => return -libc::EINVAL,
     };
-
-    let (initramfs_path, initramfs_size) = if !c_initramfs_path.is_null() {
-        match CStr::from_ptr(c_initramfs_path).to_str() {
-            Ok(path) => {
-                let path = PathBuf::from(path);
-                let size = match std::fs::metadata(&path) {
-                    Ok(metadata) => metadata.len(),
-                    Err(e) => {
-                        error!("Can't read initramfs metadata: {e:?}");
-                        return -libc::EINVAL;
-                    }
-                };
-                (Some(path), size)
-            }
-            Err(e) => {
-                error!("Error parsing initramfs path: {e:?}");
-                return -libc::EINVAL;
-            }
-        }
+    let params = if c_params.is_null() {
+        ""
     } else {
-        (None, 0)
-    };
-
-    let cmdline = if !c_cmdline.is_null() {
-        match CStr::from_ptr(c_cmdline).to_str() {
-            Ok(cmdline) => Some(cmdline.to_string()),
-            Err(e) => {
-                error!("Error parsing kernel cmdline: {e:?}");
-                return -libc::EINVAL;
-            }
+        match CStr::from_ptr(c_params).to_str() {
+            Ok(p) if !p.contains([',', '"', '\n']) => p.trim(),
+            _ => return -libc::EINVAL,
         }
-    } else {
-        None
-    };
-
-    let external_kernel = ExternalKernel {
-        path,
-        format,
-        initramfs_path,
-        initramfs_size,
-        cmdline,
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().vmr.set_external_kernel(external_kernel),
+        Entry::Occupied(mut ctx_cfg) => {
+            let entry = if params.is_empty() {
+                module.to_string()
+            } else {
+                format!("{module}:{params}")
+            };
+            ctx_cfg.get_mut().kernel_modules.push(entry);
+        }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
 
     KRUN_SUCCESS
 }
 
-#[cfg(not(feature = "tee"))]
-#[allow(clippy::format_collect)]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_firmware(ctx_id: u32, c_firmware_path: *const c_char) -> i32 {
-    let path = match CStr::from_ptr(c_firmware_path).to_str() {
-        Ok(path) => PathBuf::from(path),
-        Err(e) => {
-            error!("Error parsing firmware_path: {e:?}");
-            return -libc::EINVAL;
-        }
-    };
-
-    let firmware_config = FirmwareConfig { path };
-
-    match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().vmr.set_firmware_config(firmware_config),
-        Entry::Vacant(_) => return -libc::ENOENT,
+pub unsafe extern "C" fn krun_add_sysctl(
+    ctx_id: u32,
+    c_key: *const c_char,
+    c_value: *const c_char,
+) -> i32 {
+    if c_key.is_null() || c_value.is_null() {
+        return -libc::EINVAL;
     }
-
-    KRUN_SUCCESS
-}
-
-unsafe fn load_krunfw_payload(
-    krunfw: &KrunfwBindings,
-    vmr: &mut VmResources,
-) -> Result<(), libloading::Error> {
-    let mut kernel_guest_addr: u64 = 0;
-    let mut kernel_entry_addr: u64 = 0;
-    let mut kernel_size: usize = 0;
-    let kernel_host_addr = unsafe {
-        (krunfw.get_kernel)(
-            &mut kernel_guest_addr as *mut u64,
-            &mut kernel_entry_addr as *mut u64,
-            &mut kernel_size as *mut usize,
-        )
-    };
-    let kernel_bundle = KernelBundle {
-        host_addr: kernel_host_addr as u64,
-        guest_addr: kernel_guest_addr,
-        entry_addr: kernel_entry_addr,
-        size: kernel_size,
+    let (Ok(key), Ok(value)) = (
+        CStr::from_ptr(c_key).to_str(),
+        CStr::from_ptr(c_value).to_str(),
+    ) else {
+        return -libc::EINVAL;
     };
-    vmr.set_kernel_bundle(kernel_bundle).unwrap();
-
-    #[cfg(feature = "tee")]
-    {
-        let mut qboot_size: usize = 0;
-        let qboot_host_addr = unsafe { (krunfw.get_qboot)(&mut qboot_size as *mut usize) };
-        let qboot_bundle = QbootBundle {
-            host_addr: qboot_host_addr as u64,
-            size: qboot_size,
-        };
-        vmr.set_qboot_bundle(qboot_bundle).unwrap();
 
-        let mut initrd_size: usize = 0;
-        let initrd_host_addr = unsafe { (krunfw.get_initrd)(&mut initrd_size as *mut usize) };
-        let initrd_bundle = InitrdBundle {
-            host_addr: initrd_host_addr as u64,
-            size: initrd_size,
-        };
-        vmr.set_initrd_bundle(initrd_bundle).unwrap();
+    // The key must name an entry of /proc/sys, and neither of them may break
+    // the list init gets on the kernel command line.
+    let valid_key = key.split(['.', '/']).all(|component| {
+        !component.is_empty()
+            && component
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+    });
+    let valid_value = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b == b' ' || (b.is_ascii_graphic() && b != b',' && b != b'"'));
+    if !valid_key || !valid_value {
+        return -libc::EINVAL;
     }
 
-    Ok(())
-}
-
-#[no_mangle]
-pub extern "C" fn krun_setuid(ctx_id: u32, uid: libc::uid_t) -> i32 {
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            cfg.set_vmm_uid(uid);
+            let sysctls = &mut ctx_cfg.get_mut().sysctls;
+            sysctls.retain(|(k, _)| k != key);
+            sysctls.push((key.to_string(), value.to_string()));
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1949,11 +2899,30 @@ pub extern "C" fn krun_setuid(ctx_id: u32, uid: libc::uid_t) -> i32 {
 }
 
 #[no_mangle]
-pub extern "C" fn krun_setgid(ctx_id: u32, gid: libc::gid_t) -> i32 {
+pub extern "C" fn krun_set_workload_limits(
+    ctx_id: u32,
+    memory_max: u64,
+    pids_max: u64,
+    cpu_millis: u32,
+) -> i32 {
+    // cgroup v2 CPU quotas are expressed in microseconds per period.
+    const CPU_PERIOD_US: u64 = 100_000;
+
+    let mut limits = Vec::new();
+    if memory_max != 0 {
+        limits.push(format!("memory:{memory_max}"));
+    }
+    if pids_max != 0 {
+        limits.push(format!("pids:{pids_max}"));
+    }
+    if cpu_millis != 0 {
+        let quota = cpu_millis as u64 * CPU_PERIOD_US / 1000;
+        limits.push(format!("cpu:{}", quota.max(1000)));
+    }
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            cfg.set_vmm_gid(gid);
+            ctx_cfg.get_mut().workload_limits = (!limits.is_empty()).then(|| limits.join(","));
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1961,19 +2930,71 @@ pub extern "C" fn krun_setgid(ctx_id: u32, gid: libc::gid_t) -> i32 {
     KRUN_SUCCESS
 }
 
-#[cfg(feature = "nitro")]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_nitro_set_image(ctx_id: u32, c_image_filepath: *const c_char) -> i32 {
-    let filepath = match CStr::from_ptr(c_image_filepath).to_str() {
-        Ok(f) => PathBuf::from(f.to_string()),
-        Err(_) => return -libc::EINVAL,
+pub unsafe extern "C" fn krun_set_workload_io_limits(
+    ctx_id: u32,
+    c_device: *const c_char,
+    rbps: u64,
+    wbps: u64,
+    riops: u64,
+    wiops: u64,
+) -> i32 {
+    let device = match CStr::from_ptr(c_device).to_str() {
+        Ok(d) if !d.is_empty() && d.bytes().all(|b| b.is_ascii_alphanumeric()) => d,
+        _ => return -libc::EINVAL,
     };
 
+    let limit = [
+        ("rbps", rbps),
+        ("wbps", wbps),
+        ("riops", riops),
+        ("wiops", wiops),
+    ]
+    .iter()
+    .map(|(key, value)| match value {
+        0 => format!("{key}=max"),
+        value => format!("{key}={value}"),
+    })
+    .collect::<Vec<_>>()
+    .join("/");
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            cfg.set_nitro_image(filepath);
+            let limits = &mut ctx_cfg.get_mut().workload_io_limits;
+            limits.retain(|(d, _)| d != device);
+            limits.push((device.to_string(), limit));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_workload_cgroup_delegation(ctx_id: u32, enable: bool) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().delegate_workload_cgroup = enable;
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_kernel_lockdown(ctx_id: u32, mode: u32) -> i32 {
+    let mode = match mode {
+        0 => None,
+        1 => Some("integrity"),
+        2 => Some("confidentiality"),
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().lockdown = mode;
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -1981,23 +3002,156 @@ pub unsafe extern "C" fn krun_nitro_set_image(ctx_id: u32, c_image_filepath: *co
     KRUN_SUCCESS
 }
 
-#[cfg(feature = "nitro")]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_nitro_set_start_flags(ctx_id: u32, start_flags: u64) -> i32 {
-    let mut flags = StartFlags::empty();
+pub unsafe extern "C" fn krun_set_kdump(
+    ctx_id: u32,
+    reserve_mib: u32,
+    c_kernel_path: *const c_char,
+    c_initrd_path: *const c_char,
+    c_dump_device: *const c_char,
+) -> i32 {
+    // These end up in the kernel command line, which is split on whitespace.
+    let arg = |c_str: *const c_char| {
+        CStr::from_ptr(c_str)
+            .to_str()
+            .ok()
+            .filter(|s| !s.is_empty() && !s.contains(char::is_whitespace))
+    };
 
-    // Only debug mode is supported at the moment. To avoid doing conversion and
-    // checking if the "start_flags" argument is valid, set the flags to debug mode
-    // if the "start_flags" argument is greater than zero.
-    if start_flags > 0 {
-        flags |= StartFlags::DEBUG;
+    if reserve_mib == 0 || c_kernel_path.is_null() || c_dump_device.is_null() {
+        return -libc::EINVAL;
+    }
+    let (Some(kernel_path), Some(dump_device)) = (arg(c_kernel_path), arg(c_dump_device)) else {
+        return -libc::EINVAL;
+    };
+    let mut kdump = format!(
+        "crashkernel={reserve_mib}M KRUN_KDUMP_KERNEL={kernel_path} KRUN_KDUMP_DEVICE={dump_device}"
+    );
+    if !c_initrd_path.is_null() {
+        let Some(initrd_path) = arg(c_initrd_path) else {
+            return -libc::EINVAL;
+        };
+        kdump += &format!(" KRUN_KDUMP_INITRD={initrd_path}");
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().kdump = Some(kdump);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_user(
+    ctx_id: u32,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    c_groups: *const libc::gid_t,
+    num_groups: size_t,
+) -> i32 {
+    // Matches NGROUPS_MAX in Linux.
+    if num_groups > 65536 || (c_groups.is_null() && num_groups != 0) {
+        return -libc::EINVAL;
+    }
+    let groups: Vec<String> = if num_groups == 0 {
+        Vec::new()
+    } else {
+        slice::from_raw_parts(c_groups, num_groups)
+            .iter()
+            .map(|gid| gid.to_string())
+            .collect()
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().workload_user = Some(format!("{uid}:{gid}:{}", groups.join(",")));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+/// Whether `name` is a valid host or domain name, so it can be passed on
+/// the kernel command line and written to the guest configuration files.
+fn is_valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_host_entry(
+    ctx_id: u32,
+    c_hostname: *const c_char,
+    c_ip: *const c_char,
+) -> i32 {
+    if c_hostname.is_null() || c_ip.is_null() {
+        return -libc::EINVAL;
+    }
+    let hostname = match CStr::from_ptr(c_hostname).to_str() {
+        Ok(hostname) if is_valid_hostname(hostname) => hostname.to_string(),
+        _ => return -libc::EINVAL,
+    };
+    let Some(ip) = CStr::from_ptr(c_ip)
+        .to_str()
+        .ok()
+        .and_then(|ip| ip.parse::<IpAddr>().ok())
+    else {
+        return -libc::EINVAL;
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().host_entries.push((hostname, ip));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_dns(
+    ctx_id: u32,
+    c_servers: *const *const c_char,
+    c_search: *const *const c_char,
+) -> i32 {
+    let mut servers = Vec::new();
+    for server in c_str_array(c_servers) {
+        match std::str::from_utf8(&server)
+            .ok()
+            .and_then(|s| s.parse::<IpAddr>().ok())
+        {
+            Some(ip) => servers.push(ip),
+            None => return -libc::EINVAL,
+        }
+    }
+    let mut search = Vec::new();
+    for domain in c_str_array(c_search) {
+        match String::from_utf8(domain) {
+            Ok(domain) if is_valid_hostname(&domain) => search.push(domain),
+            _ => return -libc::EINVAL,
+        }
+    }
+    if servers.is_empty() {
+        return -libc::EINVAL;
     }
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.set_nitro_start_flags(flags);
+            cfg.dns_servers = servers;
+            cfg.dns_search = search;
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -2005,100 +3159,3137 @@ pub unsafe extern "C" fn krun_nitro_set_start_flags(ctx_id: u32, start_flags: u6
     KRUN_SUCCESS
 }
 
-#[cfg(all(feature = "blk", not(feature = "tee")))]
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_root_disk_remount(
+pub unsafe extern "C" fn krun_set_workdir(ctx_id: u32, c_workdir_path: *const c_char) -> i32 {
+    let workdir_path = match CStr::from_ptr(c_workdir_path).to_str() {
+        Ok(workdir) => workdir,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().set_workdir(workdir_path.to_string());
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+unsafe fn collapse_str_array(array: &[*const c_char]) -> Result<String, std::str::Utf8Error> {
+    let mut strvec = Vec::new();
+
+    for item in array.iter().take(MAX_ARGS) {
+        if item.is_null() {
+            break;
+        } else {
+            let s = CStr::from_ptr(*item).to_str()?;
+            strvec.push(format!("\"{s}\""));
+        }
+    }
+
+    Ok(strvec.join(" "))
+}
+
+#[allow(clippy::format_collect)]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_exec(
     ctx_id: u32,
-    c_device: *const c_char,
-    c_fstype: *const c_char,
-    c_options: *const c_char,
+    c_exec_path: *const c_char,
+    c_argv: *const *const c_char,
+    c_envp: *const *const c_char,
 ) -> i32 {
-    let device = match CStr::from_ptr(c_device).to_str() {
-        Ok(device) => device.to_string(),
+    let exec_path = match CStr::from_ptr(c_exec_path).to_str() {
+        Ok(path) => path,
         Err(e) => {
-            error!("Error parsing device path: {e:?}");
+            debug!("Error parsing exec_path: {e:?}");
             return -libc::EINVAL;
         }
     };
 
-    let fstype = if !c_fstype.is_null() {
-        match CStr::from_ptr(c_fstype).to_str() {
-            Ok(fstype) => {
-                if fstype == "auto" {
-                    None
-                } else {
-                    Some(fstype.to_string())
-                }
-            }
+    let args = if !c_argv.is_null() {
+        let argv_array: &[*const c_char] = slice::from_raw_parts(c_argv, MAX_ARGS);
+        match collapse_str_array(argv_array) {
+            Ok(s) => s,
             Err(e) => {
-                error!("Error parsing fstype: {e:?}");
+                debug!("Error parsing args: {e:?}");
                 return -libc::EINVAL;
             }
         }
     } else {
-        None
+        "".to_string()
     };
 
-    let options = if !c_options.is_null() {
-        match CStr::from_ptr(c_options).to_str() {
-            Ok(options) => Some(options.to_string()),
+    let env = if !c_envp.is_null() {
+        let envp_array: &[*const c_char] = slice::from_raw_parts(c_envp, MAX_ARGS);
+        match collapse_str_array(envp_array) {
+            Ok(s) => s,
             Err(e) => {
-                error!("Error parsing options: {e:?}");
+                debug!("Error parsing args: {e:?}");
                 return -libc::EINVAL;
             }
         }
     } else {
-        None
+        env::vars()
+            .map(|(key, value)| format!(" {key}=\"{value}\""))
+            .collect()
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            let ctx_cfg = ctx_cfg.get_mut();
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_exec_path(exec_path.to_string());
+            cfg.set_env(env);
+            cfg.set_args(args);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
 
-            if ctx_cfg.vmr.fs.iter().any(|fs| fs.fs_id == "/dev/root") {
-                error!("Root filesystem already configured");
-                return -libc::EINVAL;
-            }
+    KRUN_SUCCESS
+}
 
-            if ctx_cfg.block_cfgs.is_empty() {
-                error!("No block devices configured");
+#[allow(clippy::format_collect)]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_env(ctx_id: u32, c_envp: *const *const c_char) -> i32 {
+    let env = if !c_envp.is_null() {
+        let envp_array: &[*const c_char] = slice::from_raw_parts(c_envp, MAX_ARGS);
+        match collapse_str_array(envp_array) {
+            Ok(s) => s,
+            Err(e) => {
+                debug!("Error parsing args: {e:?}");
                 return -libc::EINVAL;
             }
+        }
+    } else {
+        env::vars()
+            .map(|(key, value)| format!(" {key}=\"{value}\""))
+            .collect()
+    };
 
-            // To boot from a filesystem other than virtiofs,
-            // we need to setup a temporary root from which init.krun can be executed.
-            // Otherwise, it would have to be copied to the target filesystem beforehand.
-            // Instead, init.krun will run from virtiofs and then switch to the real root.
-            let root_dir_suffix = Alphanumeric.sample_string(&mut rand::rng(), 6);
-            let empty_root = env::temp_dir().join(format!("krun-empty-root-{root_dir_suffix}"));
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_env(env);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
 
-            if let Err(e) = std::fs::create_dir_all(&empty_root) {
-                error!("Failed to create empty root directory: {e:?}");
-                return -libc::EINVAL;
-            }
+    KRUN_SUCCESS
+}
 
-            ctx_cfg.vmr.add_fs_device(FsDeviceConfig {
-                fs_id: "/dev/root".into(),
-                shared_dir: empty_root.to_string_lossy().into(),
-                // Default to a conservative 512 MB window.
-                shm_size: Some(1 << 29),
-            });
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "tee")]
+pub unsafe extern "C" fn krun_set_tee_config_file(ctx_id: u32, c_filepath: *const c_char) -> i32 {
+    let filepath = match CStr::from_ptr(c_filepath).to_str() {
+        Ok(f) => f,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_tee_config_file(PathBuf::from(filepath.to_string()));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+// The GUEST_FIELD_SELECT bits of SNP_GET_DERIVED_KEY.
+#[cfg(feature = "amd-sev")]
+const KRUN_SEAL_ALL_FIELDS: u64 = (1 << 6) - 1;
+// Without it, the key would only be bound to the chip, and any guest could unseal the disk.
+#[cfg(feature = "amd-sev")]
+const KRUN_SEAL_MEASUREMENT: u64 = 1 << 3;
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "amd-sev")]
+pub unsafe extern "C" fn krun_set_sealed_disk(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_guest_path: *const c_char,
+    fields: u64,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
+        Err(_) => return -libc::EINVAL,
+    };
+    let guest_path = match CStr::from_ptr(c_guest_path).to_str() {
+        Ok(path)
+            if path.starts_with('/')
+                && path != "/"
+                && !path.contains(':')
+                && !path.contains(char::is_whitespace) =>
+        {
+            path
+        }
+        _ => return -libc::EINVAL,
+    };
+    if fields & !KRUN_SEAL_ALL_FIELDS != 0 || fields & KRUN_SEAL_MEASUREMENT == 0 {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg.find_block_cfg(block_id).is_none() {
+                return -libc::ENOENT;
+            }
+            cfg.sealed_disk = Some((block_id.to_string(), guest_path.to_string(), fields));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "amd-sev"))]
+pub unsafe extern "C" fn krun_set_sealed_disk(
+    _ctx_id: u32,
+    _c_block_id: *const c_char,
+    _c_guest_path: *const c_char,
+    _fields: u64,
+) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_vsock_port(
+    ctx_id: u32,
+    port: u32,
+    c_filepath: *const c_char,
+) -> i32 {
+    krun_add_vsock_port2(ctx_id, port, c_filepath, false)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_vsock_port2(
+    ctx_id: u32,
+    port: u32,
+    c_filepath: *const c_char,
+    listen: bool,
+) -> i32 {
+    #[cfg(feature = "nitro")]
+    if listen {
+        return -libc::EINVAL;
+    }
+
+    let filepath = match CStr::from_ptr(c_filepath).to_str() {
+        Ok(f) => PathBuf::from(f.to_string()),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    if listen {
+        match filepath.try_exists() {
+            Ok(true) => return -libc::EEXIST,
+            Err(_) => return -libc::EINVAL,
+            _ => {}
+        }
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.add_vsock_port(port, filepath, listen);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_vsock_siblings(
+    ctx_id: u32,
+    cid: u32,
+    c_dir: *const c_char,
+) -> i32 {
+    // 0-2 are reserved and u32::MAX is VMADDR_CID_ANY.
+    if cid < 3 || cid == u32::MAX || c_dir.is_null() {
+        return -libc::EINVAL;
+    }
+    let dir = match CStr::from_ptr(c_dir).to_str() {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => return -libc::EINVAL,
+    };
+    if !dir.is_dir() {
+        return -libc::ENOENT;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vsock_cid = Some(cid);
+            cfg.vsock_sibling_dir = Some(dir);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_gpu_options(ctx_id: u32, virgl_flags: u32) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_gpu_virgl_flags(virgl_flags);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_gpu_options2(
+    ctx_id: u32,
+    virgl_flags: u32,
+    shm_size: u64,
+) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_gpu_virgl_flags(virgl_flags);
+            cfg.set_gpu_shm_size(shm_size.try_into().unwrap());
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(not(feature = "gpu"))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub extern "C" fn krun_set_display_backend(
+    _ctx_id: u32,
+    _features: u32,
+    _vtable: *const c_void,
+    _vtable_size: usize,
+) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[cfg(feature = "gpu")]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub extern "C" fn krun_set_display_backend(
+    ctx_id: u32,
+    vtable: *const c_void,
+    vtable_size: usize,
+) -> i32 {
+    if vtable_size < size_of::<DisplayBackend>() {
+        return -libc::EINVAL;
+    }
+
+    // SAFETY: We have checked the vtable size is fine, otherwise we have to trust the user. Just
+    // to be extra careful, this uses read_unaligned, but we could probably get away with ptr::read.
+    let display_backend: DisplayBackend =
+        unsafe { std::ptr::read_unaligned(vtable as *const DisplayBackend) };
+
+    if !display_backend.verify() {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.display_backend = Some(display_backend);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(not(all(feature = "gpu", not(feature = "tee"))))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_display_vnc(_ctx_id: u32, _c_socket_path: *const c_char) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[cfg(all(feature = "gpu", not(feature = "tee")))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_display_vnc(ctx_id: u32, c_socket_path: *const c_char) -> i32 {
+    if c_socket_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let path = match CStr::from_ptr(c_socket_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            let (keyboard, tablet) = match (InputEventQueue::new(), InputEventQueue::new()) {
+                (Ok(keyboard), Ok(tablet)) => (Arc::new(keyboard), Arc::new(tablet)),
+                _ => return -libc::EINVAL,
+            };
+            let server = Arc::new(RfbServer::new(keyboard.clone(), tablet.clone()));
+            // The display backend outlives the context, so it's never freed.
+            let userdata: &'static Arc<RfbServer> = Box::leak(Box::new(server.clone()));
+            cfg.vmr.display_backend = Some(RfbDisplayBackend::into_display_backend(Some(userdata)));
+            cfg.vmr.input_devices =
+                vec![(InputKind::Keyboard, keyboard), (InputKind::Tablet, tablet)];
+            cfg.vnc = Some((server, path));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+type FrameFn = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    scanout_id: u32,
+    data: *const u8,
+    width: u32,
+    height: u32,
+    format: u32,
+    damage_x: u32,
+    damage_y: u32,
+    damage_width: u32,
+    damage_height: u32,
+);
+
+#[cfg(not(feature = "gpu"))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_frame_callback(
+    _ctx_id: u32,
+    _max_fps: u32,
+    _callback: Option<FrameFn>,
+    _user_data: *mut c_void,
+) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[cfg(feature = "gpu")]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_frame_callback(
+    ctx_id: u32,
+    max_fps: u32,
+    callback: Option<FrameFn>,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(callback) = callback else {
+        return -libc::EINVAL;
+    };
+
+    let user_data = UserData(user_data);
+    let callback: FrameCallback = Box::new(move |scanout_id, frame, damage| {
+        callback(
+            user_data.as_ptr(),
+            scanout_id,
+            frame.data.as_ptr(),
+            frame.width,
+            frame.height,
+            frame.format as u32,
+            damage.x,
+            damage.y,
+            damage.width,
+            damage.height,
+        )
+    });
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().frame_stream = Some((callback, max_fps));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(not(feature = "gpu"))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_screenshot(
+    _ctx_id: u32,
+    _scanout_id: u32,
+    _c_path: *const c_char,
+) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[cfg(feature = "gpu")]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_screenshot(
+    ctx_id: u32,
+    scanout_id: u32,
+    c_path: *const c_char,
+) -> i32 {
+    if c_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => Path::new(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let Some(capture) = SCANOUT_CAPTURES.lock().unwrap().get(&ctx_id).cloned() else {
+        return if ctx_exists(ctx_id) {
+            -libc::ENODEV
+        } else {
+            -libc::ENOENT
+        };
+    };
+
+    match capture.screenshot(scanout_id, path) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(CaptureError::NoScanout) => -libc::ENODEV,
+        Err(CaptureError::Write(e)) => {
+            error!("Error writing screenshot: {e:?}");
+            -e.raw_os_error().unwrap_or(libc::EIO)
+        }
+    }
+}
+
+#[cfg(feature = "gpu")]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_display(ctx_id: u32, width: u32, height: u32) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg.vmr.displays.len() >= MAX_DISPLAYS {
+                return -libc::ENOMEM;
+            }
+
+            cfg.vmr.displays.push(DisplayInfo::new(width, height));
+            (cfg.vmr.displays.len() - 1) as i32
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[cfg(not(feature = "gpu"))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_display(_ctx_id: u32, _width: u32, _height: u32) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[cfg(feature = "gpu")]
+#[no_mangle]
+pub extern "C" fn krun_display_set_refresh_rate(
+    ctx_id: u32,
+    display_id: u32,
+    refresh_rate: u32,
+) -> i32 {
+    with_cfg(ctx_id, |cfg| {
+        let Some(display_info) = cfg.vmr.displays.get_mut(display_id as usize) else {
+            return -libc::EINVAL;
+        };
+
+        let DisplayInfoEdid::Generated(ref mut edid_params) = display_info.edid else {
+            return -libc::EALREADY;
+        };
+
+        edid_params.refresh_rate = refresh_rate;
+        KRUN_SUCCESS
+    })
+}
+
+#[cfg(not(feature = "gpu"))]
+#[no_mangle]
+pub extern "C" fn krun_display_set_refresh_rate(
+    _ctx_id: u32,
+    _display_id: u32,
+    _refresh_rate: u32,
+) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[cfg(feature = "gpu")]
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn krun_display_set_edid(
+    ctx_id: u32,
+    display_id: u32,
+    edid: *const u8,
+    size: size_t,
+) -> i32 {
+    with_cfg(ctx_id, |cfg| {
+        let Some(display_info) = cfg.vmr.displays.get_mut(display_id as usize) else {
+            return -libc::EINVAL;
+        };
+
+        if edid.is_null() {
+            return -libc::EINVAL;
+        }
+
+        let blob = unsafe { slice::from_raw_parts(edid, size) };
+
+        display_info.edid = DisplayInfoEdid::Provided(Box::from(blob));
+        KRUN_SUCCESS
+    })
+}
+
+#[cfg(not(feature = "gpu"))]
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn krun_display_set_edid(
+    _ctx_id: u32,
+    _display_id: u32,
+    _edid: *const u8,
+    _size: size_t,
+) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[cfg(feature = "gpu")]
+#[no_mangle]
+pub extern "C" fn krun_display_set_physical_size(
+    ctx_id: u32,
+    display_id: u32,
+    width_mm: u16,
+    height_mm: u16,
+) -> i32 {
+    with_cfg(ctx_id, |cfg| {
+        let Some(display_info) = cfg.vmr.displays.get_mut(display_id as usize) else {
+            return -libc::EINVAL;
+        };
+        let DisplayInfoEdid::Generated(ref mut edid_params) = display_info.edid else {
+            return -libc::EALREADY;
+        };
+        edid_params.physical_size = PhysicalSize::DimensionsMillimeters(width_mm, height_mm);
+        KRUN_SUCCESS
+    })
+}
+
+#[cfg(not(feature = "gpu"))]
+#[no_mangle]
+pub extern "C" fn krun_display_set_physical_size(
+    _ctx_id: u32,
+    _display_id: u32,
+    _width_mm: u16,
+    _height_mm: u16,
+) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[cfg(feature = "gpu")]
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub extern "C" fn krun_display_set_dpi(ctx_id: u32, display_id: u32, dpi: u32) -> i32 {
+    with_cfg(ctx_id, |cfg| {
+        let Some(display_info) = cfg.vmr.displays.get_mut(display_id as usize) else {
+            return -libc::EINVAL;
+        };
+        let DisplayInfoEdid::Generated(ref mut edid_params) = display_info.edid else {
+            return -libc::EINVAL;
+        };
+        edid_params.physical_size = PhysicalSize::Dpi(dpi);
+        KRUN_SUCCESS
+    })
+}
+
+#[cfg(not(feature = "gpu"))]
+#[no_mangle]
+pub extern "C" fn krun_display_set_dpi(_ctx_id: u32, _display_id: u32, _dpi: u32) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_snd_device(ctx_id: u32, enable: bool) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.enable_snd = enable;
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(unused_assignments)]
+#[no_mangle]
+pub extern "C" fn krun_get_shutdown_eventfd(ctx_id: u32) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if let Some(efd) = cfg.shutdown_efd.as_ref() {
+                #[cfg(target_os = "macos")]
+                return efd.get_write_fd();
+                #[cfg(target_os = "linux")]
+                return efd.as_raw_fd();
+            } else {
+                -libc::EINVAL
+            }
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_console_output(ctx_id: u32, c_filepath: *const c_char) -> i32 {
+    let filepath = match CStr::from_ptr(c_filepath).to_str() {
+        Ok(f) => f,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg.console_output.is_some() {
+                -libc::EINVAL
+            } else {
+                cfg.console_output = Some(PathBuf::from(filepath.to_string()));
+                KRUN_SUCCESS
+            }
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_boot_log_socket(
+    ctx_id: u32,
+    c_socket_path: *const c_char,
+) -> i32 {
+    let socket_path = match CStr::from_ptr(c_socket_path).to_str() {
+        Ok(s) => s,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.boot_log = Some(PathBuf::from(socket_path));
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_nested_virt(ctx_id: u32, enabled: bool) -> i32 {
+    if enabled && cfg!(feature = "tee") {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.nested_enabled = enabled;
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_check_nested_virt() -> i32 {
+    #[cfg(target_os = "macos")]
+    match hvf::check_nested_virt() {
+        Ok(supp) => supp as i32,
+        Err(_) => -libc::EINVAL,
+    }
+
+    #[cfg(target_os = "linux")]
+    match kvm_ioctls::Kvm::new() {
+        Ok(kvm) => vmm::nested_virt_supported(&kvm) as i32,
+        Err(e) => {
+            error!("Error opening KVM: {e:?}");
+            -libc::EINVAL
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_pmu(ctx_id: u32, enabled: bool) -> i32 {
+    // Hypervisor.framework doesn't virtualize the PMU.
+    if enabled && (cfg!(target_os = "macos") || cfg!(feature = "tee")) {
+        return -libc::ENOTSUP;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.pmu_enabled = enabled;
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_core_scheduling(ctx_id: u32, mode: u32) -> i32 {
+    let core_scheduling = match mode {
+        0 => CoreScheduling::Disabled,
+        1 => CoreScheduling::Process,
+        2 => CoreScheduling::Vcpu,
+        _ => return -libc::EINVAL,
+    };
+    if core_scheduling != CoreScheduling::Disabled && !cfg!(target_os = "linux") {
+        return -libc::ENOTSUP;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.core_scheduling = core_scheduling;
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_start_paused(ctx_id: u32, paused: bool) -> i32 {
+    if paused && !cfg!(target_os = "linux") {
+        return -libc::ENOTSUP;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.start_paused = paused;
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_deterministic(
+    ctx_id: u32,
+    c_journal_path: *const c_char,
+    replay: bool,
+    seed: u64,
+) -> i32 {
+    if cfg!(feature = "tee") {
+        return -libc::ENOTSUP;
+    }
+
+    let journal_path = match CStr::from_ptr(c_journal_path).to_str() {
+        Ok(s) => s,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.deterministic = Some(DeterministicConfig {
+                journal: PathBuf::from(journal_path),
+                replay,
+                seed,
+            });
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+/// Gets the maximum number of vCPUs supported by the hypervisor.
+///
+/// Returns the maximum number of vCPUs that can be created by this hypervisor,
+/// or a negative error code on failure.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+#[no_mangle]
+pub extern "C" fn krun_get_max_vcpus() -> i32 {
+    #[cfg(target_os = "macos")]
+    {
+        use hvf::bindings::{hv_vm_get_max_vcpu_count, HV_SUCCESS};
+        let mut max_vcpu_count: u32 = 0;
+        let ret = unsafe { hv_vm_get_max_vcpu_count(&mut max_vcpu_count as *mut u32) };
+        if ret == HV_SUCCESS {
+            max_vcpu_count as i32
+        } else {
+            error!("Error retrieving max vcpu count: {ret:?}");
+            -libc::EINVAL
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use kvm_ioctls::Kvm;
+        match Kvm::new() {
+            Ok(kvm) => kvm.get_max_vcpus() as i32,
+            Err(e) => {
+                error!("Error retrieving max vcpu count: {e:?}");
+                -libc::EINVAL
+            }
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub extern "C" fn krun_split_irqchip(ctx_id: u32, enable: bool) -> i32 {
+    if enable && !cfg!(target_arch = "x86_64") {
+        return -libc::EINVAL;
+    }
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.split_irqchip = enable;
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub extern "C" fn krun_set_idle_throttle(ctx_id: u32, idle_ms: u32) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.idle_throttle = (idle_ms != 0).then(|| Duration::from_millis(idle_ms.into()));
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+// Virtio device types whose queue size can be set, from linux/virtio_ids.h.
+const QUEUE_SIZE_DEVICE_TYPES: [u32; 10] = [1, 2, 3, 4, 5, 16, 18, 19, 25, 26];
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub extern "C" fn krun_set_queue_size(ctx_id: u32, device_type: u32, queue_size: u16) -> i32 {
+    if !QUEUE_SIZE_DEVICE_TYPES.contains(&device_type) || !Queue::is_valid_max_size(queue_size) {
+        return -libc::EINVAL;
+    }
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.queue_sizes.insert(device_type, queue_size);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+fn add_guest_mem_region(ctx_id: u32, region: GuestMemoryRegionConfig) -> i32 {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+    if region.size == 0
+        || !region.guest_addr.is_multiple_of(page_size)
+        || !(region.size as u64).is_multiple_of(page_size)
+        || region.guest_addr.checked_add(region.size as u64).is_none()
+    {
+        return -libc::EINVAL;
+    }
+    with_cfg(ctx_id, |cfg| {
+        cfg.vmr.guest_memory_regions.push(region);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub extern "C" fn krun_add_guest_mem_fd(
+    ctx_id: u32,
+    guest_addr: u64,
+    size: u64,
+    fd: c_int,
+    offset: u64,
+) -> i32 {
+    if fd < 0 {
+        return -libc::EINVAL;
+    }
+    // Keep a copy of the descriptor, so the caller can close theirs.
+    let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return -io::Error::last_os_error().raw_os_error().unwrap();
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    add_guest_mem_region(
+        ctx_id,
+        GuestMemoryRegionConfig {
+            guest_addr,
+            size: size as usize,
+            backing: GuestMemoryBacking::File { file, offset },
+        },
+    )
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_guest_mem_host(
+    ctx_id: u32,
+    guest_addr: u64,
+    size: u64,
+    host_addr: *mut c_void,
+) -> i32 {
+    let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+    if host_addr.is_null() || !(host_addr as usize).is_multiple_of(page_size) {
+        return -libc::EINVAL;
+    }
+    add_guest_mem_region(
+        ctx_id,
+        GuestMemoryRegionConfig {
+            guest_addr,
+            size: size as usize,
+            backing: GuestMemoryBacking::Host(host_addr as usize),
+        },
+    )
+}
+
+fn running_guest_memory(ctx_id: u32) -> Option<GuestMemoryMmap> {
+    GUEST_MEMORY.lock().unwrap().get(&ctx_id).cloned()
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_read_guest_mem(
+    ctx_id: u32,
+    guest_addr: u64,
+    buf: *mut c_void,
+    len: size_t,
+) -> i32 {
+    if buf.is_null() {
+        return -libc::EINVAL;
+    }
+    let Some(guest_mem) = running_guest_memory(ctx_id) else {
+        return -libc::ENOENT;
+    };
+    let buf = slice::from_raw_parts_mut(buf as *mut u8, len);
+    match guest_mem.read_slice(buf, GuestAddress(guest_addr)) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(_) => -libc::EFAULT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_write_guest_mem(
+    ctx_id: u32,
+    guest_addr: u64,
+    buf: *const c_void,
+    len: size_t,
+) -> i32 {
+    if buf.is_null() {
+        return -libc::EINVAL;
+    }
+    let Some(guest_mem) = running_guest_memory(ctx_id) else {
+        return -libc::ENOENT;
+    };
+    let buf = slice::from_raw_parts(buf as *const u8, len);
+    match guest_mem.write_slice(buf, GuestAddress(guest_addr)) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(_) => -libc::EFAULT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_virtio_device(
+    ctx_id: u32,
+    ops: *const c_void,
+    ops_size: size_t,
+    opaque: *mut c_void,
+) -> i32 {
+    if ops.is_null() || ops_size < size_of::<VirtioDeviceOps>() {
+        return -libc::EINVAL;
+    }
+    // SAFETY: We have checked the size is fine, and newer versions of the
+    // table may only append fields to it.
+    let ops: VirtioDeviceOps = std::ptr::read_unaligned(ops as *const VirtioDeviceOps);
+    let Some(plugin) = CPlugin::new(ops, opaque) else {
+        return -libc::EINVAL;
+    };
+
+    with_cfg(ctx_id, |cfg| {
+        cfg.vmr.virtio_plugins.push(Arc::new(Mutex::new(plugin)));
+        KRUN_SUCCESS
+    })
+}
+
+#[cfg(not(all(target_os = "linux", not(any(feature = "tee", feature = "nitro")))))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_serve_vhost_user_fs(
+    _c_socket_path: *const c_char,
+    _c_shared_dir: *const c_char,
+) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[cfg(all(target_os = "linux", not(any(feature = "tee", feature = "nitro"))))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_serve_vhost_user_fs(
+    c_socket_path: *const c_char,
+    c_shared_dir: *const c_char,
+) -> i32 {
+    if c_socket_path.is_null() || c_shared_dir.is_null() {
+        return -libc::EINVAL;
+    }
+    let socket_path = match CStr::from_ptr(c_socket_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+    let shared_dir = match CStr::from_ptr(c_shared_dir).to_str() {
+        Ok(dir) => dir.to_string(),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let mut backend = match FsBackend::new(shared_dir) {
+        Ok(backend) => backend,
+        Err(e) => {
+            error!("Error opening the shared directory: {e}");
+            return -e.raw_os_error().unwrap_or(libc::EINVAL);
+        }
+    };
+    let Err(err) = vhost_user::serve(&socket_path, &mut backend) else {
+        return KRUN_SUCCESS;
+    };
+    error!("Error serving vhost-user-fs: {err:?}");
+    match err {
+        vhost_user::Error::Bind(e) | vhost_user::Error::Accept(e) => {
+            -e.raw_os_error().unwrap_or(libc::EINVAL)
+        }
+        _ => -libc::EIO,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_smbios_oem_strings(
+    ctx_id: u32,
+    oem_strings: *const *const c_char,
+) -> i32 {
+    if oem_strings.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let cstr_ptr_slice = slice::from_raw_parts(oem_strings, MAX_ARGS);
+
+    let mut oem_strings = Vec::new();
+
+    for cstr_ptr in cstr_ptr_slice.iter().take_while(|p| !p.is_null()) {
+        let Ok(s) = CStr::from_ptr(*cstr_ptr).to_str() else {
+            return -libc::EINVAL;
+        };
+        oem_strings.push(s.to_string());
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.smbios_oem_strings =
+                (!oem_strings.is_empty()).then_some(oem_strings)
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "net")]
+fn create_virtio_net(
+    ctx_cfg: &mut ContextConfig,
+    backend: VirtioNetBackend,
+    mac: [u8; 6],
+    features: u32,
+) {
+    let network_interface_config = NetworkInterfaceConfig {
+        iface_id: format!("eth{}", ctx_cfg.net_index),
+        backend,
+        mac,
+        features,
+        mtu: ctx_cfg.net_mtu,
+    };
+    ctx_cfg.net_index += 1;
+    ctx_cfg
+        .vmr
+        .add_network_interface(network_interface_config)
+        .expect("Failed to create network interface");
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+fn map_kernel(ctx_id: u32, kernel_path: &PathBuf) -> i32 {
+    let file = match File::options().read(true).write(false).open(kernel_path) {
+        Ok(file) => file,
+        Err(err) => {
+            error!("Error opening external kernel: {err}");
+            return -libc::EINVAL;
+        }
+    };
+
+    let kernel_size = file.metadata().unwrap().len();
+
+    let kernel_host_addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            kernel_size as usize,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0_i64,
+        )
+    };
+    if std::ptr::eq(kernel_host_addr, libc::MAP_FAILED) {
+        error!("Can't load kernel into process map");
+        return -libc::EINVAL;
+    }
+
+    let kernel_bundle = KernelBundle {
+        host_addr: kernel_host_addr as u64,
+        guest_addr: 0x8000_0000,
+        entry_addr: 0x8000_0000,
+        size: kernel_size as usize,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg
+            .get_mut()
+            .vmr
+            .set_kernel_bundle(kernel_bundle)
+            .unwrap(),
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "tee")]
+#[allow(clippy::format_collect)]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_kernel(_ctx_id: u32, _c_kernel_path: *const c_char) -> i32 {
+    -libc::EOPNOTSUPP
+}
+
+#[cfg(not(feature = "tee"))]
+#[allow(clippy::format_collect)]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_kernel(
+    ctx_id: u32,
+    c_kernel_path: *const c_char,
+    kernel_format: u32,
+    c_initramfs_path: *const c_char,
+    c_cmdline: *const c_char,
+) -> i32 {
+    let path = match CStr::from_ptr(c_kernel_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(e) => {
+            error!("Error parsing kernel_path: {e:?}");
+            return -libc::EINVAL;
+        }
+    };
+
+    let format = match kernel_format {
+        // For raw kernels in x86_64, we map the kernel into the
+        // process and treat it as a bundled kernel.
+        #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+        0 => return map_kernel(ctx_id, &path),
+        #[cfg(target_arch = "aarch64")]
+        0 => KernelFormat::Raw,
+        1 => KernelFormat::Elf,
+        2 => KernelFormat::PeGz,
+        3 => KernelFormat::ImageBz2,
+        4 => KernelFormat::ImageGz,
+        5 => KernelFormat::ImageZstd,
+        _ => {
+            return -libc::EINVAL;
+        }
+    };
+
+    let (initramfs_path, initramfs_size) = if !c_initramfs_path.is_null() {
+        match CStr::from_ptr(c_initramfs_path).to_str() {
+            Ok(path) => {
+                let path = PathBuf::from(path);
+                let size = match std::fs::metadata(&path) {
+                    Ok(metadata) => metadata.len(),
+                    Err(e) => {
+                        error!("Can't read initramfs metadata: {e:?}");
+                        return -libc::EINVAL;
+                    }
+                };
+                (Some(path), size)
+            }
+            Err(e) => {
+                error!("Error parsing initramfs path: {e:?}");
+                return -libc::EINVAL;
+            }
+        }
+    } else {
+        (None, 0)
+    };
+
+    let cmdline = if !c_cmdline.is_null() {
+        match CStr::from_ptr(c_cmdline).to_str() {
+            Ok(cmdline) => Some(cmdline.to_string()),
+            Err(e) => {
+                error!("Error parsing kernel cmdline: {e:?}");
+                return -libc::EINVAL;
+            }
+        }
+    } else {
+        None
+    };
+
+    let external_kernel = ExternalKernel {
+        path,
+        format,
+        initramfs_path,
+        initramfs_size,
+        cmdline,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().vmr.set_external_kernel(external_kernel),
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+/// The alignment arm64 and riscv64 kernel images must be loaded at.
+const KERNEL_LOAD_ALIGN: u64 = 0x20_0000;
+
+#[no_mangle]
+pub extern "C" fn krun_set_kernel_load_addr(ctx_id: u32, addr: u64) -> i32 {
+    // The x86_64 kernels are loaded where their ELF headers say.
+    if cfg!(target_arch = "x86_64") {
+        return -libc::ENOTSUP;
+    }
+    if addr == 0 || !addr.is_multiple_of(KERNEL_LOAD_ALIGN) {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.kernel_load_addr = Some(addr);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_kaslr_seed(ctx_id: u32, seed: u64) -> i32 {
+    // The seed is passed in the device tree, which x86_64 guests don't have.
+    if seed != 0 && cfg!(target_arch = "x86_64") {
+        return -libc::ENOTSUP;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.kaslr_seed = Some(seed);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[cfg(not(feature = "tee"))]
+#[allow(clippy::format_collect)]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_firmware(ctx_id: u32, c_firmware_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_firmware_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(e) => {
+            error!("Error parsing firmware_path: {e:?}");
+            return -libc::EINVAL;
+        }
+    };
+
+    let firmware_config = FirmwareConfig { path };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => ctx_cfg.get_mut().vmr.set_firmware_config(firmware_config),
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+unsafe fn load_krunfw_payload(
+    krunfw: &KrunfwBindings,
+    vmr: &mut VmResources,
+) -> Result<(), libloading::Error> {
+    let mut kernel_guest_addr: u64 = 0;
+    let mut kernel_entry_addr: u64 = 0;
+    let mut kernel_size: usize = 0;
+    let kernel_host_addr = unsafe {
+        (krunfw.get_kernel)(
+            &mut kernel_guest_addr as *mut u64,
+            &mut kernel_entry_addr as *mut u64,
+            &mut kernel_size as *mut usize,
+        )
+    };
+    let kernel_bundle = KernelBundle {
+        host_addr: kernel_host_addr as u64,
+        guest_addr: kernel_guest_addr,
+        entry_addr: kernel_entry_addr,
+        size: kernel_size,
+    };
+    vmr.set_kernel_bundle(kernel_bundle).unwrap();
+
+    #[cfg(feature = "tee")]
+    {
+        let mut qboot_size: usize = 0;
+        let qboot_host_addr = unsafe { (krunfw.get_qboot)(&mut qboot_size as *mut usize) };
+        let qboot_bundle = QbootBundle {
+            host_addr: qboot_host_addr as u64,
+            size: qboot_size,
+        };
+        vmr.set_qboot_bundle(qboot_bundle).unwrap();
+
+        let mut initrd_size: usize = 0;
+        let initrd_host_addr = unsafe { (krunfw.get_initrd)(&mut initrd_size as *mut usize) };
+        let initrd_bundle = InitrdBundle {
+            host_addr: initrd_host_addr as u64,
+            size: initrd_size,
+        };
+        vmr.set_initrd_bundle(initrd_bundle).unwrap();
+    }
+
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn krun_setuid(ctx_id: u32, uid: libc::uid_t) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_vmm_uid(uid);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn krun_setgid(ctx_id: u32, gid: libc::gid_t) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_vmm_gid(gid);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "nitro")]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_nitro_set_image(ctx_id: u32, c_image_filepath: *const c_char) -> i32 {
+    let filepath = match CStr::from_ptr(c_image_filepath).to_str() {
+        Ok(f) => PathBuf::from(f.to_string()),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_nitro_image(filepath);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "nitro")]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_nitro_set_start_flags(ctx_id: u32, start_flags: u64) -> i32 {
+    let mut flags = StartFlags::empty();
+
+    // Only debug mode is supported at the moment. To avoid doing conversion and
+    // checking if the "start_flags" argument is valid, set the flags to debug mode
+    // if the "start_flags" argument is greater than zero.
+    if start_flags > 0 {
+        flags |= StartFlags::DEBUG;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.set_nitro_start_flags(flags);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(all(feature = "blk", not(feature = "tee")))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_root_disk_remount(
+    ctx_id: u32,
+    c_device: *const c_char,
+    c_fstype: *const c_char,
+    c_options: *const c_char,
+) -> i32 {
+    let device = match CStr::from_ptr(c_device).to_str() {
+        Ok(device) => device.to_string(),
+        Err(e) => {
+            error!("Error parsing device path: {e:?}");
+            return -libc::EINVAL;
+        }
+    };
+
+    let fstype = if !c_fstype.is_null() {
+        match CStr::from_ptr(c_fstype).to_str() {
+            Ok(fstype) => {
+                if fstype == "auto" {
+                    None
+                } else {
+                    Some(fstype.to_string())
+                }
+            }
+            Err(e) => {
+                error!("Error parsing fstype: {e:?}");
+                return -libc::EINVAL;
+            }
+        }
+    } else {
+        None
+    };
+
+    let options = if !c_options.is_null() {
+        match CStr::from_ptr(c_options).to_str() {
+            Ok(options) => Some(options.to_string()),
+            Err(e) => {
+                error!("Error parsing options: {e:?}");
+                return -libc::EINVAL;
+            }
+        }
+    } else {
+        None
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let ctx_cfg = ctx_cfg.get_mut();
+
+            if ctx_cfg.vmr.fs.iter().any(|fs| fs.fs_id == "/dev/root") {
+                error!("Root filesystem already configured");
+                return -libc::EINVAL;
+            }
+
+            if ctx_cfg.block_cfgs.is_empty() {
+                error!("No block devices configured");
+                return -libc::EINVAL;
+            }
+
+            // To boot from a filesystem other than virtiofs,
+            // we need to setup a temporary root from which init.krun can be executed.
+            // Otherwise, it would have to be copied to the target filesystem beforehand.
+            // Instead, init.krun will run from virtiofs and then switch to the real root.
+            let empty_root = match ctx_cfg.scratch_path("empty-root") {
+                Ok(path) => path,
+                Err(e) => return io_error_to_errno(e),
+            };
+
+            if let Err(e) = std::fs::create_dir_all(&empty_root) {
+                error!("Failed to create empty root directory: {e:?}");
+                return -libc::EINVAL;
+            }
+
+            ctx_cfg.vmr.add_fs_device(FsDeviceConfig {
+                fs_id: "/dev/root".into(),
+                shared_dir: empty_root.to_string_lossy().into(),
+                // Default to a conservative 512 MB window.
+                shm_size: Some(1 << 29),
+                writeback: false,
+                allow_direct_io: false,
+                fd_cache_size: 0,
+                readonly: false,
+                quota: None,
+                threads: 1,
+            });
+
+            ctx_cfg.set_block_root(device, fstype, options);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    };
+
+    KRUN_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn krun_disable_implicit_console(ctx_id: u32) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.disable_implicit_console = true;
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_virtio_console_default(
+    ctx_id: u32,
+    input_fd: libc::c_int,
+    output_fd: libc::c_int,
+    err_fd: libc::c_int,
+) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.consoles.entry(ConsoleType::Virtio).or_default();
+
+            // safe to unwrap since we inserted an empty Vec if the key didn't exist
+            let consoles = cfg.vmr.consoles.get_mut(&ConsoleType::Virtio).unwrap();
+            consoles.push(ConsoleConfig {
+                output_path: None,
+                input_fd,
+                output_fd,
+                err_fd,
+            });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_serial_console_default(
+    ctx_id: u32,
+    input_fd: libc::c_int,
+    output_fd: libc::c_int,
+) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.consoles.entry(ConsoleType::Serial).or_default();
+
+            // safe to unwrap since we inserted an empty Vec if the key didn't exist
+            let consoles = cfg.vmr.consoles.get_mut(&ConsoleType::Serial).unwrap();
+            consoles.push(ConsoleConfig {
+                output_path: None,
+                input_fd,
+                output_fd,
+                err_fd: -1,
+            });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_kernel_console(ctx_id: u32, console_id: *const c_char) -> i32 {
+    let console_id = match CStr::from_ptr(console_id).to_str() {
+        Ok(id) => id.to_string(),
+        Err(_) => return -libc::EINVAL,
+    };
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.kernel_console = Some(console_id);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_metrics_socket(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().metrics_socket = Some(PathBuf::from(path));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "tracing")]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_trace_file(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().trace_file = Some(PathBuf::from(path));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(not(feature = "tracing"))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_trace_file(_ctx_id: u32, _c_path: *const c_char) -> i32 {
+    -libc::ENOTSUP
+}
+
+/// Returns the metrics of the context, whether it's running or still being
+/// configured.
+fn ctx_metrics(ctx_id: u32) -> Option<Arc<Metrics>> {
+    if let Some(metrics) = VM_METRICS.lock().unwrap().get(&ctx_id) {
+        return Some(metrics.clone());
+    }
+    CTX_MAP
+        .lock()
+        .unwrap()
+        .get(&ctx_id)
+        .map(|ctx_cfg| ctx_cfg.vmr.metrics.clone())
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_metrics_json(
+    ctx_id: u32,
+    c_buf: *mut c_char,
+    buf_len: size_t,
+) -> i32 {
+    match ctx_metrics(ctx_id) {
+        Some(metrics) => write_json_to_buf(metrics.to_json(), c_buf, buf_len),
+        None => -libc::ENOENT,
+    }
+}
+
+/// Signature of the function called when the guest is under memory pressure.
+type MemoryPressureFn =
+    unsafe extern "C" fn(user_data: *mut c_void, available: u64, total: u64, oom_kills: u64);
+
+/// Opaque pointer handed back to the embedder's callbacks.
+struct UserData(*mut c_void);
+
+// Safe because we never dereference the pointer, it is only passed back to
+// the embedder, who is responsible for synchronizing access to it.
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+impl UserData {
+    fn as_ptr(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+#[cfg(not(feature = "tee"))]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_memory_pressure_callback(
+    ctx_id: u32,
+    threshold_pct: u32,
+    callback: Option<MemoryPressureFn>,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(callback) = callback else {
+        return -libc::EINVAL;
+    };
+    if threshold_pct == 0 || threshold_pct > 100 {
+        return -libc::EINVAL;
+    }
+
+    let user_data = UserData(user_data);
+    let config = devices::virtio::MemoryPressureConfig {
+        threshold_pct,
+        callback: std::sync::Arc::new(move |stats| {
+            callback(
+                user_data.as_ptr(),
+                stats.available,
+                stats.total,
+                stats.oom_kills,
+            )
+        }),
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.memory_pressure = Some(config);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "tee")]
+#[no_mangle]
+pub extern "C" fn krun_set_memory_pressure_callback(
+    _ctx_id: u32,
+    _threshold_pct: u32,
+    _callback: Option<MemoryPressureFn>,
+    _user_data: *mut c_void,
+) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_boot_timeline_print(ctx_id: u32, enable: bool) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().print_boot_timeline = enable;
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_boot_timeline_json(
+    ctx_id: u32,
+    c_buf: *mut c_char,
+    buf_len: size_t,
+) -> i32 {
+    let timeline = match BOOT_TIMELINES.lock().unwrap().get(&ctx_id) {
+        Some(timeline) => timeline.clone(),
+        None => match CTX_MAP.lock().unwrap().get(&ctx_id) {
+            Some(ctx_cfg) => ctx_cfg.vmr.boot_timeline.clone(),
+            None => return -libc::ENOENT,
+        },
+    };
+
+    write_json_to_buf(timeline.to_json(), c_buf, buf_len)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_workload_stats_json(
+    ctx_id: u32,
+    c_buf: *mut c_char,
+    buf_len: size_t,
+) -> i32 {
+    if !ctx_exists(ctx_id) {
+        return -libc::ENOENT;
+    }
+
+    write_json_to_buf(WORKLOAD_STATS.to_json(), c_buf, buf_len)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_workload_stats_path(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(p) => PathBuf::from(p),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().workload_stats_path = Some(path);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(not(feature = "tee"))]
+#[no_mangle]
+pub extern "C" fn krun_set_measured_boot(ctx_id: u32, expose_to_guest: bool) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.measured_boot = Some(MeasuredBootConfig {
+                disk: None,
+                expose_to_guest,
+            });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "tee")]
+#[no_mangle]
+pub extern "C" fn krun_set_measured_boot(_ctx_id: u32, _expose_to_guest: bool) -> i32 {
+    -libc::ENOTSUP
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_measured_boot_log_json(
+    ctx_id: u32,
+    c_buf: *mut c_char,
+    buf_len: size_t,
+) -> i32 {
+    if !ctx_exists(ctx_id) {
+        return -libc::ENOENT;
+    }
+
+    write_json_to_buf(MEASUREMENT_LOG.to_json(), c_buf, buf_len)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_device_stats(
+    ctx_id: u32,
+    c_dev_id: *const c_char,
+    c_buf: *mut c_char,
+    buf_len: size_t,
+) -> i32 {
+    let dev_id = match CStr::from_ptr(c_dev_id).to_str() {
+        Ok(id) => id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let Some(metrics) = ctx_metrics(ctx_id) else {
+        return -libc::ENOENT;
+    };
+
+    match metrics.find_device(dev_id) {
+        Some(metrics) => write_json_to_buf(metrics.to_json(), c_buf, buf_len),
+        None => -libc::ENODEV,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_tsi_flows_json(
+    ctx_id: u32,
+    c_buf: *mut c_char,
+    buf_len: size_t,
+) -> i32 {
+    if !ctx_exists(ctx_id) {
+        return -libc::ENOENT;
+    }
+
+    let flows = TSI_FLOWS
+        .lock()
+        .unwrap()
+        .get(&ctx_id)
+        .map(FlowTable::flows)
+        .unwrap_or_default();
+    write_json_to_buf(flows::to_json(&flows), c_buf, buf_len)
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_device_worker_threads(ctx_id: u32, num_threads: u32) -> i32 {
+    if num_threads == 0 {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().device_worker_threads = Some(num_threads as usize);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_io_uring(ctx_id: u32, enable: bool) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().io_uring = enable;
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_tsc_khz(ctx_id: u32, tsc_khz: u32) -> i32 {
+    if tsc_khz == 0 || !cfg!(target_arch = "x86_64") {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.clock_config.tsc_khz = Some(tsc_khz);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_invariant_tsc(ctx_id: u32, enable: bool) -> i32 {
+    if !cfg!(target_arch = "x86_64") {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.clock_config.invariant_tsc = Some(enable);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_kvmclock(ctx_id: u32, enable: bool) -> i32 {
+    if !cfg!(target_arch = "x86_64") {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.clock_config.kvmclock = enable;
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_init_binary(ctx_id: u32, c_path: *const c_char) -> i32 {
+    if c_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(p) => p,
+        Err(_) => return -libc::EINVAL,
+    };
+    let binary = match std::fs::read(path) {
+        Ok(binary) if !binary.is_empty() => binary,
+        Ok(_) => return -libc::EINVAL,
+        Err(e) => return io_error_to_errno(e),
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.init_binary = Some(Arc::from(binary));
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub extern "C" fn krun_set_rosetta(ctx_id: u32, enable: bool) -> i32 {
+    if !cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+        return -libc::EINVAL;
+    }
+    if enable && !Path::new(ROSETTA_DIR).exists() {
+        return -libc::ENOENT;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if enable && !cfg.rosetta {
+                cfg.vmr.add_fs_device(FsDeviceConfig {
+                    fs_id: ROSETTA_TAG.to_string(),
+                    shared_dir: ROSETTA_DIR.to_string(),
+                    shm_size: None,
+                    writeback: false,
+                    allow_direct_io: false,
+                    fd_cache_size: 0,
+                    readonly: false,
+                    quota: None,
+                    threads: 1,
+                });
+            } else if !enable && cfg.rosetta {
+                cfg.vmr.fs.retain(|fs| fs.fs_id != ROSETTA_TAG);
+            }
+            cfg.rosetta = enable;
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_enable_agent(ctx_id: u32) -> i32 {
+    let path = match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            let path = match cfg.scratch_path("agent.sock") {
+                Ok(path) => path,
+                Err(e) => return io_error_to_errno(e),
+            };
+            cfg.add_vsock_port(AGENT_PORT, path.clone(), true);
+            cfg.agent = true;
+            path
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    };
+
+    AGENT_SOCKETS.lock().unwrap().insert(ctx_id, path);
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_guest_exec(
+    ctx_id: u32,
+    c_argv: *const *const c_char,
+    c_buf: *mut c_char,
+    buf_len: size_t,
+    exit_status: *mut i32,
+) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+    if c_argv.is_null() || exit_status.is_null() || (c_buf.is_null() && buf_len != 0) {
+        return -libc::EINVAL;
+    }
+
+    let mut argv = Vec::new();
+    for item in slice::from_raw_parts(c_argv, MAX_ARGS) {
+        if item.is_null() {
+            break;
+        }
+        match CStr::from_ptr(*item).to_str() {
+            Ok(arg) => argv.push(arg),
+            Err(_) => return -libc::EINVAL,
+        }
+    }
+
+    match AgentClient::new(&path).exec(&argv) {
+        Ok((status, output)) => {
+            let len = output.len().min(buf_len);
+            if len > 0 {
+                slice::from_raw_parts_mut(c_buf as *mut u8, len).copy_from_slice(&output[..len]);
+            }
+            *exit_status = status;
+            output.len() as i32
+        }
+        Err(e) => io_error_to_errno(e),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_guest_stats(ctx_id: u32, c_buf: *mut c_char, buf_len: size_t) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+
+    match AgentClient::new(&path).with_timeout(AGENT_TIMEOUT).stats() {
+        Ok(json) => write_json_to_buf(json, c_buf, buf_len),
+        Err(e) => io_error_to_errno(e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_guest_sync_time(ctx_id: u32) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+
+    match AgentClient::new(&path)
+        .with_timeout(AGENT_TIMEOUT)
+        .sync_time()
+    {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => io_error_to_errno(e),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_guest_shutdown(ctx_id: u32) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+
+    match AgentClient::new(&path)
+        .with_timeout(AGENT_TIMEOUT)
+        .shutdown()
+    {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => io_error_to_errno(e),
+    }
+}
+
+/// Exit code of a microVM stopped by the last stage of krun_shutdown(), as if
+/// it had been killed.
+const HARD_STOP_EXIT_CODE: i32 = 128 + libc::SIGKILL;
+
+#[derive(Clone, Copy, Debug)]
+enum ShutdownStage {
+    /// Pressing the power button of the guest, for it to shut down on its own.
+    Guest = 1,
+    /// Sending SIGTERM to the workload through the guest agent.
+    Workload = 2,
+    /// Stopping the vCPUs and the VMM.
+    Hard = 3,
+}
+
+fn vm_running(ctx_id: u32) -> bool {
+    SUPERVISED_VMMS.lock().unwrap().contains_key(&ctx_id)
+        || RUNNING_CTXS.lock().unwrap().contains(&ctx_id)
+}
+
+/// Waits for the microVM to exit. It never returns true when it runs in this
+/// process, as the process exits along.
+fn wait_vm_exit(ctx_id: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while vm_running(ctx_id) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+fn shutdown_event(ctx_id: u32) -> Option<EventFd> {
+    if let Some(efd) = SHUTDOWN_EVENTS.lock().unwrap().get(&ctx_id) {
+        return efd.try_clone().ok();
+    }
+    // The supervisor keeps the context, sharing the event with the VMM.
+    CTX_MAP
+        .lock()
+        .unwrap()
+        .get(&ctx_id)?
+        .shutdown_efd
+        .as_ref()?
+        .try_clone()
+        .ok()
+}
+
+/// Starts `stage`, returning whether it was possible.
+fn start_shutdown_stage(ctx_id: u32, stage: ShutdownStage) -> bool {
+    match stage {
+        ShutdownStage::Guest => match shutdown_event(ctx_id) {
+            Some(efd) => efd.write(1).is_ok(),
+            None => false,
+        },
+        ShutdownStage::Workload => match agent_socket(ctx_id) {
+            Some(path) => match AgentClient::new(&path)
+                .with_timeout(AGENT_TIMEOUT)
+                .shutdown()
+            {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Couldn't ask the workload to terminate: {e}");
+                    false
+                }
+            },
+            None => false,
+        },
+        ShutdownStage::Hard => {
+            if let Some(pid) = SUPERVISED_VMMS.lock().unwrap().get(&ctx_id) {
+                return unsafe { libc::kill(*pid, libc::SIGKILL) } == 0;
+            }
+            let vmm = RUNNING_VMMS.lock().unwrap().get(&ctx_id).cloned();
+            match vmm {
+                Some(vmm) => {
+                    vmm.lock().unwrap().stop(HARD_STOP_EXIT_CODE);
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_shutdown(ctx_id: u32, timeout_ms: u32) -> i32 {
+    if !vm_running(ctx_id) {
+        return if ctx_exists(ctx_id) {
+            -libc::ESRCH
+        } else {
+            -libc::ENOENT
+        };
+    }
+
+    let timeout = Duration::from_millis(timeout_ms.into());
+    for stage in [
+        ShutdownStage::Guest,
+        ShutdownStage::Workload,
+        ShutdownStage::Hard,
+    ] {
+        if !start_shutdown_stage(ctx_id, stage) {
+            debug!("Skipping the {stage:?} shutdown stage");
+            continue;
+        }
+        info!("Shutting down the microVM: {stage:?} stage started");
+        if wait_vm_exit(ctx_id, timeout) {
+            info!("The microVM shut down in the {stage:?} stage");
+            return stage as i32;
+        }
+    }
+
+    -libc::ETIMEDOUT
+}
+
+/// Signals of the host which can be forwarded to the workload.
+const FORWARDABLE_SIGNALS: [c_int; 5] = [
+    libc::SIGTERM,
+    libc::SIGINT,
+    libc::SIGHUP,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+];
+
+#[no_mangle]
+pub extern "C" fn krun_set_signal_forwarding(ctx_id: u32, signum: c_int, forward: bool) -> i32 {
+    if !FORWARDABLE_SIGNALS.contains(&signum) {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let signals = &mut ctx_cfg.get_mut().forwarded_signals;
+            signals.retain(|&sig| sig != signum);
+            if forward {
+                signals.push(signum);
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+/// Forwards `signals` to the workload through the guest agent listening on
+/// `agent_path`, instead of letting them take their course in the process.
+fn spawn_signal_forwarder(signals: &[c_int], agent_path: PathBuf) -> io::Result<()> {
+    let mut set: libc::sigset_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::sigemptyset(&mut set) };
+    for &sig in signals {
+        unsafe { libc::sigaddset(&mut set, sig) };
+    }
+
+    // Blocked before the VMM spawns any thread, so all of them inherit the
+    // mask and the signals are only ever picked up by the forwarder.
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+
+    #[cfg(target_os = "linux")]
+    let mut signalfd = {
+        let fd = unsafe { libc::signalfd(-1, &set, libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because the descriptor was just created and is owned here.
+        unsafe { File::from_raw_fd(fd) }
+    };
+
+    thread::Builder::new()
+        .name("signal forwarder".into())
+        .spawn(move || loop {
+            #[cfg(target_os = "linux")]
+            let signum = {
+                let mut info = [0u8; std::mem::size_of::<libc::signalfd_siginfo>()];
+                if let Err(e) = io::Read::read_exact(&mut signalfd, &mut info) {
+                    error!("Error reading the forwarded signals: {e}");
+                    return;
+                }
+                // ssi_signo is the first field.
+                u32::from_ne_bytes(info[..4].try_into().unwrap()) as c_int
+            };
+            #[cfg(target_os = "macos")]
+            let signum = {
+                let mut sig = 0;
+                if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+                    continue;
+                }
+                sig
+            };
+
+            debug!("Forwarding signal {signum} to the workload");
+            if let Err(e) = AgentClient::new(&agent_path)
+                .with_timeout(AGENT_TIMEOUT)
+                .signal(signum)
+            {
+                warn!("Couldn't forward signal {signum} to the workload: {e}");
+            }
+        })?;
+    Ok(())
+}
+
+fn suspend_guest(agent_path: &Path) -> io::Result<()> {
+    GUEST_SLEEP.request_suspend();
+    let result = AgentClient::new(agent_path)
+        .with_timeout(AGENT_TIMEOUT)
+        .suspend();
+    if result.is_err() {
+        GUEST_SLEEP.wake();
+    }
+    result
+}
+
+/// Wakes the guest up, if it's suspended, and fixes its clock, which stopped
+/// while it was.
+fn resume_guest(agent_path: &Path) -> bool {
+    if !GUEST_SLEEP.wake() {
+        return false;
+    }
+    if let Err(e) = AgentClient::new(agent_path)
+        .with_timeout(AGENT_TIMEOUT)
+        .sync_time()
+    {
+        warn!("Couldn't sync the guest clock after resuming it: {e}");
+    }
+    true
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub extern "C" fn krun_guest_suspend(ctx_id: u32) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+
+    match suspend_guest(&path) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => io_error_to_errno(e),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub extern "C" fn krun_guest_resume(ctx_id: u32) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+
+    if resume_guest(&path) {
+        KRUN_SUCCESS
+    } else {
+        -libc::EINVAL
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_resume(ctx_id: u32) -> i32 {
+    if SUPERVISED_VMMS.lock().unwrap().contains_key(&ctx_id) {
+        return -libc::ENOTSUP;
+    }
+    let Some(vmm) = RUNNING_VMMS.lock().unwrap().get(&ctx_id).cloned() else {
+        return if ctx_exists(ctx_id) {
+            -libc::ESRCH
+        } else {
+            -libc::ENOENT
+        };
+    };
+
+    let released = vmm.lock().unwrap().release_vcpus();
+    match released {
+        Ok(true) => KRUN_SUCCESS,
+        Ok(false) => -libc::EINVAL,
+        Err(e) => {
+            error!("Couldn't resume the vCPUs: {e}");
+            -libc::EIO
+        }
+    }
+}
+
+/// Follows the host going to sleep and waking up, suspending the guest and
+/// resuming it or, where the host can't be kept awake until the guest is
+/// suspended, just fixing its clock.
+fn sync_host_sleep(agent_path: &Path, suspended: &AtomicBool, event: HostSleepEvent) {
+    match event {
+        HostSleepEvent::WillSleep => {
+            if let Err(e) = suspend_guest(agent_path) {
+                warn!("Couldn't suspend the guest before the host sleeps: {e}");
+                return;
+            }
+            suspended.store(true, Ordering::Relaxed);
+            if !GUEST_SLEEP.wait_suspended(GUEST_SUSPEND_TIMEOUT) {
+                warn!("The guest didn't finish suspending before the host sleeps");
+            }
+        }
+        HostSleepEvent::Woke => {
+            // Don't resume a guest that was suspended through the API.
+            if suspended.swap(false, Ordering::Relaxed) && resume_guest(agent_path) {
+                return;
+            }
+            if let Err(e) = AgentClient::new(agent_path)
+                .with_timeout(AGENT_TIMEOUT)
+                .sync_time()
+            {
+                warn!("Couldn't sync the guest clock after the host woke up: {e}");
+            }
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub extern "C" fn krun_set_host_sleep_sync(ctx_id: u32, enable: bool) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().host_sleep_sync = enable;
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_copy_to_guest(
+    ctx_id: u32,
+    c_host_path: *const c_char,
+    c_guest_path: *const c_char,
+) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+    let (Ok(host_path), Ok(guest_path)) = (
+        CStr::from_ptr(c_host_path).to_str(),
+        CStr::from_ptr(c_guest_path).to_str(),
+    ) else {
+        return -libc::EINVAL;
+    };
+
+    match AgentClient::new(&path)
+        .with_timeout(AGENT_TIMEOUT)
+        .copy_to_guest(Path::new(host_path), guest_path)
+    {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => io_error_to_errno(e),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_copy_from_guest(
+    ctx_id: u32,
+    c_guest_path: *const c_char,
+    c_host_path: *const c_char,
+) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+    let (Ok(guest_path), Ok(host_path)) = (
+        CStr::from_ptr(c_guest_path).to_str(),
+        CStr::from_ptr(c_host_path).to_str(),
+    ) else {
+        return -libc::EINVAL;
+    };
+
+    match AgentClient::new(&path)
+        .with_timeout(AGENT_TIMEOUT)
+        .copy_from_guest(guest_path, Path::new(host_path))
+    {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => io_error_to_errno(e),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_clipboard_set(
+    ctx_id: u32,
+    c_mime_type: *const c_char,
+    data: *const u8,
+    len: size_t,
+) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+    if c_mime_type.is_null() || (data.is_null() && len != 0) {
+        return -libc::EINVAL;
+    }
+    let Ok(mime_type) = CStr::from_ptr(c_mime_type).to_str() else {
+        return -libc::EINVAL;
+    };
+    let data = if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    };
+
+    match AgentClient::new(&path)
+        .with_timeout(AGENT_TIMEOUT)
+        .set_clipboard(mime_type, data)
+    {
+        Ok(()) => {
+            // Don't report our own change back to the embedder.
+            if let Some(state) = CLIPBOARD_SYNCS.lock().unwrap().get(&ctx_id) {
+                if state.mime_type == mime_type {
+                    *state.contents.lock().unwrap() = Some(data.to_vec());
+                }
+            }
+            KRUN_SUCCESS
+        }
+        Err(e) => io_error_to_errno(e),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_clipboard_get(
+    ctx_id: u32,
+    c_mime_type: *const c_char,
+    buf: *mut u8,
+    buf_len: size_t,
+) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+    if c_mime_type.is_null() || (buf.is_null() && buf_len != 0) {
+        return -libc::EINVAL;
+    }
+    let Ok(mime_type) = CStr::from_ptr(c_mime_type).to_str() else {
+        return -libc::EINVAL;
+    };
+
+    match AgentClient::new(&path)
+        .with_timeout(AGENT_TIMEOUT)
+        .get_clipboard(mime_type)
+    {
+        Ok(data) => {
+            let len = data.len().min(buf_len);
+            if len > 0 {
+                slice::from_raw_parts_mut(buf, len).copy_from_slice(&data[..len]);
+            }
+            data.len() as i32
+        }
+        Err(e) => io_error_to_errno(e),
+    }
+}
+
+type ClipboardFn = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    mime_type: *const c_char,
+    data: *const u8,
+    len: size_t,
+);
+
+/// Contents of the guest clipboard last seen by a `ClipboardSync`, shared
+/// with `krun_clipboard_set`.
+struct ClipboardState {
+    mime_type: String,
+    contents: Mutex<Option<Vec<u8>>>,
+}
+
+static CLIPBOARD_SYNCS: Lazy<Mutex<HashMap<u32, Arc<ClipboardState>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Polls the guest clipboard through the agent, reporting its changes to
+/// the embedder.
+struct ClipboardSync {
+    mime_type: CString,
+    interval: Duration,
+    callback: ClipboardFn,
+    user_data: UserData,
+}
+
+impl ClipboardSync {
+    fn spawn(self, ctx_id: u32, agent_path: PathBuf) -> io::Result<()> {
+        let state = Arc::new(ClipboardState {
+            mime_type: self.mime_type.to_string_lossy().into_owned(),
+            contents: Mutex::new(None),
+        });
+        CLIPBOARD_SYNCS
+            .lock()
+            .unwrap()
+            .insert(ctx_id, state.clone());
+
+        thread::Builder::new()
+            .name("clipboard sync".into())
+            .spawn(move || {
+                let client = AgentClient::new(&agent_path).with_timeout(AGENT_TIMEOUT);
+                loop {
+                    thread::sleep(self.interval);
+                    // Errors are expected until the guest has booted.
+                    let data = match client.get_clipboard(&state.mime_type) {
+                        Ok(data) => data,
+                        Err(e) if e.raw_os_error() == Some(libc::ENODATA) => Vec::new(),
+                        Err(_) => continue,
+                    };
+
+                    let mut contents = state.contents.lock().unwrap();
+                    if contents.as_ref() == Some(&data) {
+                        continue;
+                    }
+                    // Reporting the initial, empty, clipboard isn't useful.
+                    let report = contents.is_some() || !data.is_empty();
+                    *contents = Some(data);
+                    if report {
+                        let data = contents.as_ref().unwrap();
+                        // SAFETY: the embedder guarantees the callback can be
+                        // called with its user data from any thread.
+                        unsafe {
+                            (self.callback)(
+                                self.user_data.as_ptr(),
+                                self.mime_type.as_ptr(),
+                                data.as_ptr(),
+                                data.len(),
+                            )
+                        };
+                    }
+                }
+            })?;
+        Ok(())
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_clipboard_callback(
+    ctx_id: u32,
+    c_mime_type: *const c_char,
+    interval_ms: u32,
+    callback: Option<ClipboardFn>,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(callback) = callback else {
+        return -libc::EINVAL;
+    };
+    if c_mime_type.is_null() || interval_ms == 0 {
+        return -libc::EINVAL;
+    }
+    let mime_type = CStr::from_ptr(c_mime_type);
+    if mime_type.is_empty() || mime_type.to_str().is_err() {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().clipboard_sync = Some(ClipboardSync {
+                mime_type: mime_type.to_owned(),
+                interval: Duration::from_millis(interval_ms.into()),
+                callback,
+                user_data: UserData(user_data),
+            });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+/// Copies a null-terminated array of C strings, which may be null itself.
+unsafe fn c_str_array(c_array: *const *const c_char) -> Vec<Vec<u8>> {
+    if c_array.is_null() {
+        return Vec::new();
+    }
+    slice::from_raw_parts(c_array, MAX_ARGS)
+        .iter()
+        .take_while(|item| !item.is_null())
+        .map(|item| CStr::from_ptr(*item).to_bytes().to_vec())
+        .collect()
+}
+
+/// Relays the standard input and output of a process started through the
+/// guest agent between `stream` and the given sockets, sending its exit
+/// status to `status_sender` once it's done.
+fn spawn_exec_relay(
+    stream: UnixStream,
+    stdin: UnixStream,
+    mut stdout: UnixStream,
+    mut stderr: UnixStream,
+    status_sender: crossbeam_channel::Sender<i32>,
+) -> io::Result<()> {
+    let mut input = stream.try_clone()?;
+    thread::Builder::new()
+        .name("krun exec stdin".into())
+        .spawn(move || {
+            let _ = io::copy(&mut &stdin, &mut input);
+            let _ = input.shutdown(std::net::Shutdown::Write);
+        })?;
+    thread::Builder::new()
+        .name("krun exec".into())
+        .spawn(move || {
+            let status = agent::relay_output(&mut &stream, &mut stdout, &mut stderr)
+                .unwrap_or_else(io_error_to_errno);
+            let _ = status_sender.send(status);
+        })?;
+    Ok(())
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_exec(
+    ctx_id: u32,
+    c_argv: *const *const c_char,
+    c_envp: *const *const c_char,
+    stdin_fd: *mut c_int,
+    stdout_fd: *mut c_int,
+    stderr_fd: *mut c_int,
+) -> i32 {
+    let Some(path) = agent_socket(ctx_id) else {
+        return -libc::ENOENT;
+    };
+    if stdin_fd.is_null() || stdout_fd.is_null() || stderr_fd.is_null() {
+        return -libc::EINVAL;
+    }
+    let spec = ExecSpec {
+        argv: c_str_array(c_argv),
+        env: c_str_array(c_envp),
+    };
+    if spec.argv.is_empty() {
+        return -libc::EINVAL;
+    }
+
+    let (stdin, stdin_peer) = match UnixStream::pair() {
+        Ok(pair) => pair,
+        Err(e) => return io_error_to_errno(e),
+    };
+    let (stdout, stdout_peer) = match UnixStream::pair() {
+        Ok(pair) => pair,
+        Err(e) => return io_error_to_errno(e),
+    };
+    let (stderr, stderr_peer) = match UnixStream::pair() {
+        Ok(pair) => pair,
+        Err(e) => return io_error_to_errno(e),
+    };
+
+    let client = AgentClient::new(&path).with_timeout(AGENT_TIMEOUT);
+    let (pid, stream) = match client.spawn(&spec) {
+        Ok(spawned) => spawned,
+        Err(e) => return io_error_to_errno(e),
+    };
+
+    let (status_sender, status_receiver) = crossbeam_channel::bounded(1);
+    if let Err(e) = spawn_exec_relay(stream, stdin, stdout, stderr, status_sender) {
+        return io_error_to_errno(e);
+    }
+    EXEC_STATUS
+        .lock()
+        .unwrap()
+        .insert((ctx_id, pid), status_receiver);
+
+    *stdin_fd = stdin_peer.into_raw_fd();
+    *stdout_fd = stdout_peer.into_raw_fd();
+    *stderr_fd = stderr_peer.into_raw_fd();
+    pid
+}
+
+#[no_mangle]
+pub extern "C" fn krun_exec_wait(ctx_id: u32, pid: i32) -> i32 {
+    let Some(receiver) = EXEC_STATUS.lock().unwrap().remove(&(ctx_id, pid)) else {
+        return -libc::ESRCH;
+    };
+    // The sender is only dropped without sending if the relay thread panics.
+    receiver.recv().unwrap_or(-libc::EIO)
+}
+
+/// Signature of the function called before init runs the workload.
+type ExecHookFn = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+    buf: *mut c_char,
+    buf_len: size_t,
+) -> i32;
+
+struct ExecHook {
+    hook: ExecHookFn,
+    user_data: UserData,
+    path: PathBuf,
+}
+
+impl ExecHook {
+    /// Answers the request init sends on `EXEC_HOOK_PORT`.
+    fn serve(&self, stream: &mut UnixStream) -> io::Result<()> {
+        let (op, payload) = agent::read_request(stream)?;
+        if op != agent::Op::ExecHook as u8 {
+            return agent::write_response(stream, -libc::ENOSYS, &[]);
+        }
+        let Some(mut spec) = ExecSpec::decode(&payload) else {
+            return agent::write_response(stream, -libc::EINVAL, &[]);
+        };
+
+        // The items of an ExecSpec can't contain NUL bytes.
+        let to_cstrings = |items: &[Vec<u8>]| -> Vec<CString> {
+            items
+                .iter()
+                .map(|item| CString::new(item.clone()).unwrap())
+                .collect()
+        };
+        let argv = to_cstrings(&spec.argv);
+        let env = to_cstrings(&spec.env);
+        let to_ptrs = |items: &[CString]| -> Vec<*const c_char> {
+            items
+                .iter()
+                .map(|item| item.as_ptr())
+                .chain([std::ptr::null()])
+                .collect()
+        };
+
+        let mut buf = vec![0u8; agent::MAX_PAYLOAD];
+        // SAFETY: the arrays are null-terminated and, like the buffer, valid
+        // for the duration of the call.
+        let ret = unsafe {
+            (self.hook)(
+                self.user_data.as_ptr(),
+                to_ptrs(&argv).as_ptr(),
+                to_ptrs(&env).as_ptr(),
+                buf.as_mut_ptr() as *mut c_char,
+                buf.len(),
+            )
+        };
+        if ret < 0 {
+            return agent::write_response(stream, ret, &[]);
+        }
+        let Some(changes) = buf.get(..ret as usize) else {
+            return agent::write_response(stream, -libc::ENOSPC, &[]);
+        };
+        if spec.amend(changes).is_none() {
+            return agent::write_response(stream, -libc::EINVAL, &[]);
+        }
+        agent::write_response(stream, 0, &spec.encode())
+    }
+
+    /// Starts a thread waiting for init to connect, which it only does once.
+    fn spawn(self) -> io::Result<()> {
+        let _ = std::fs::remove_file(&self.path);
+        let listener = UnixListener::bind(&self.path)?;
+        thread::Builder::new()
+            .name("exec hook".into())
+            .spawn(move || {
+                match listener.accept() {
+                    Ok((mut stream, _)) => {
+                        if let Err(e) = self.serve(&mut stream) {
+                            error!("Error running the exec hook: {e:?}");
+                        }
+                    }
+                    Err(e) => error!("Error accepting the exec hook connection: {e:?}"),
+                }
+                let _ = std::fs::remove_file(&self.path);
+            })?;
+        Ok(())
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_exec_hook(
+    ctx_id: u32,
+    hook: Option<ExecHookFn>,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(hook) = hook else {
+        return -libc::EINVAL;
+    };
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            let path = match cfg.scratch_path("exec-hook.sock") {
+                Ok(path) => path,
+                Err(e) => return io_error_to_errno(e),
+            };
+            cfg.add_vsock_port(EXEC_HOOK_PORT, path.clone(), false);
+            cfg.exec_hook = Some(ExecHook {
+                hook,
+                user_data: UserData(user_data),
+                path,
+            });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+/// vsock port the guest sshd is reachable on.
+const SSHD_PORT: u32 = 22;
+
+/// vsock port init connects to for fetching the SSH authorized keys.
+const SSHD_KEYS_PORT: u32 = 1027;
+
+struct SshKeys {
+    keys: Vec<u8>,
+    path: PathBuf,
+}
+
+impl SshKeys {
+    /// Starts a thread handing the keys to init, which only asks for them
+    /// once.
+    fn spawn(self) -> io::Result<()> {
+        let _ = std::fs::remove_file(&self.path);
+        let listener = UnixListener::bind(&self.path)?;
+        thread::Builder::new()
+            .name("ssh keys".into())
+            .spawn(move || {
+                match listener.accept() {
+                    Ok((mut stream, _)) => {
+                        if let Err(e) = stream.write_all(&self.keys) {
+                            error!("Error sending the SSH authorized keys: {e:?}");
+                        }
+                    }
+                    Err(e) => error!("Error accepting the SSH keys connection: {e:?}"),
+                }
+                let _ = std::fs::remove_file(&self.path);
+            })?;
+        Ok(())
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_enable_ssh(
+    ctx_id: u32,
+    c_authorized_keys: *const c_char,
+    c_socket_path: *const c_char,
+) -> i32 {
+    if c_authorized_keys.is_null() || c_socket_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let keys = CStr::from_ptr(c_authorized_keys).to_bytes().to_vec();
+    if keys.is_empty() {
+        return -libc::EINVAL;
+    }
+    let socket_path = match CStr::from_ptr(c_socket_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            let path = match cfg.scratch_path("ssh-keys.sock") {
+                Ok(path) => path,
+                Err(e) => return io_error_to_errno(e),
+            };
+            cfg.add_vsock_port(SSHD_PORT, socket_path, true);
+            cfg.add_vsock_port(SSHD_KEYS_PORT, path.clone(), false);
+            cfg.ssh_keys = Some(SshKeys { keys, path });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
 
-            ctx_cfg.set_block_root(device, fstype, options);
+/// vsock port the guest HID shim connects to for relaying FIDO reports.
+const FIDO_PORT: u32 = 1028;
+
+/// Size of the reports of FIDO HID authenticators.
+const FIDO_REPORT_LEN: usize = 64;
+
+struct FidoBridge {
+    hidraw: PathBuf,
+    path: PathBuf,
+}
+
+impl FidoBridge {
+    /// Copies the reports sent by the guest to the authenticator, while
+    /// another thread copies the ones coming from it to the guest.
+    fn relay(hidraw: File, mut stream: UnixStream) -> io::Result<()> {
+        let mut reader = hidraw.try_clone()?;
+        let mut writer = stream.try_clone()?;
+        thread::Builder::new()
+            .name("fido input".into())
+            .spawn(move || {
+                let mut report = [0u8; FIDO_REPORT_LEN];
+                loop {
+                    match io::Read::read(&mut reader, &mut report) {
+                        Ok(0) => break,
+                        // Short reads are padded, which is what the guest
+                        // expects anyway.
+                        Ok(n) => report[n..].fill(0),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            debug!("Error reading from the FIDO authenticator: {e:?}");
+                            break;
+                        }
+                    }
+                    if writer.write_all(&report).is_err() {
+                        break;
+                    }
+                }
+                let _ = writer.shutdown(std::net::Shutdown::Both);
+            })?;
+
+        let mut hidraw = hidraw;
+        // hidraw expects the report number first, which is always 0 for
+        // FIDO devices.
+        let mut report = [0u8; FIDO_REPORT_LEN + 1];
+        loop {
+            match io::Read::read_exact(&mut stream, &mut report[1..]) {
+                Ok(()) => hidraw.write_all(&report)?,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
         }
-        Entry::Vacant(_) => return -libc::ENOENT,
-    };
+    }
 
-    KRUN_SUCCESS
+    /// Starts a thread waiting for the guest HID shim to connect, which it
+    /// only does once.
+    fn spawn(self) -> io::Result<()> {
+        let _ = std::fs::remove_file(&self.path);
+        let listener = UnixListener::bind(&self.path)?;
+        thread::Builder::new()
+            .name("fido bridge".into())
+            .spawn(move || {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let result = std::fs::OpenOptions::new()
+                            .read(true)
+                            .write(true)
+                            .open(&self.hidraw)
+                            .and_then(|hidraw| Self::relay(hidraw, stream));
+                        if let Err(e) = result {
+                            error!("Error relaying the FIDO authenticator: {e:?}");
+                        }
+                    }
+                    Err(e) => error!("Error accepting the FIDO bridge connection: {e:?}"),
+                }
+                let _ = std::fs::remove_file(&self.path);
+            })?;
+        Ok(())
+    }
 }
 
+#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub extern "C" fn krun_disable_implicit_console(ctx_id: u32) -> i32 {
+pub unsafe extern "C" fn krun_set_fido_device(ctx_id: u32, c_hidraw_path: *const c_char) -> i32 {
+    if c_hidraw_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let hidraw = match CStr::from_ptr(c_hidraw_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-            cfg.vmr.disable_implicit_console = true;
+            let path = match cfg.scratch_path("fido.sock") {
+                Ok(path) => path,
+                Err(e) => return io_error_to_errno(e),
+            };
+            cfg.add_vsock_port(FIDO_PORT, path.clone(), false);
+            cfg.fido = Some(FidoBridge { hidraw, path });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -2106,27 +6297,27 @@ pub extern "C" fn krun_disable_implicit_console(ctx_id: u32) -> i32 {
     KRUN_SUCCESS
 }
 
-#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_add_virtio_console_default(
+pub extern "C" fn krun_set_workload_stdio(
     ctx_id: u32,
-    input_fd: libc::c_int,
-    output_fd: libc::c_int,
-    err_fd: libc::c_int,
+    input_fd: c_int,
+    output_fd: c_int,
+    err_fd: c_int,
 ) -> i32 {
+    for fd in [input_fd, output_fd, err_fd] {
+        if fd >= 0 && unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            return -libc::EBADF;
+        }
+    }
+    let stdio = (input_fd >= 0 || output_fd >= 0 || err_fd >= 0).then_some(WorkloadStdioConfig {
+        input_fd,
+        output_fd,
+        err_fd,
+    });
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            cfg.vmr.consoles.entry(ConsoleType::Virtio).or_default();
-
-            // safe to unwrap since we inserted an empty Vec if the key didn't exist
-            let consoles = cfg.vmr.consoles.get_mut(&ConsoleType::Virtio).unwrap();
-            consoles.push(ConsoleConfig {
-                output_path: None,
-                input_fd,
-                output_fd,
-                err_fd,
-            });
+            ctx_cfg.get_mut().vmr.workload_stdio = stdio;
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -2134,26 +6325,18 @@ pub unsafe extern "C" fn krun_add_virtio_console_default(
     KRUN_SUCCESS
 }
 
-#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_add_serial_console_default(
-    ctx_id: u32,
-    input_fd: libc::c_int,
-    output_fd: libc::c_int,
-) -> i32 {
+pub extern "C" fn krun_set_workload_tty(ctx_id: u32, mode: u32) -> i32 {
+    let tty = match mode {
+        0 => WorkloadTty::Auto,
+        1 => WorkloadTty::Enabled,
+        2 => WorkloadTty::Disabled,
+        _ => return -libc::EINVAL,
+    };
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            cfg.vmr.consoles.entry(ConsoleType::Serial).or_default();
-
-            // safe to unwrap since we inserted an empty Vec if the key didn't exist
-            let consoles = cfg.vmr.consoles.get_mut(&ConsoleType::Serial).unwrap();
-            consoles.push(ConsoleConfig {
-                output_path: None,
-                input_fd,
-                output_fd,
-                err_fd: -1,
-            });
+            ctx_cfg.get_mut().vmr.workload_tty = tty;
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -2161,17 +6344,36 @@ pub unsafe extern "C" fn krun_add_serial_console_default(
     KRUN_SUCCESS
 }
 
-#[allow(clippy::missing_safety_doc)]
+#[derive(Clone, Copy)]
+enum CoreDumpPolicy {
+    /// Keep the limits of the calling process.
+    Inherit,
+    Disable,
+    Enable,
+}
+
+/// How a VMM forked from the calling process is supervised.
+#[derive(Clone, Copy)]
+struct SupervisorConfig {
+    max_restarts: u32,
+    core_dump: CoreDumpPolicy,
+}
+
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_kernel_console(ctx_id: u32, console_id: *const c_char) -> i32 {
-    let console_id = match CStr::from_ptr(console_id).to_str() {
-        Ok(id) => id.to_string(),
-        Err(_) => return -libc::EINVAL,
+pub extern "C" fn krun_set_supervisor(ctx_id: u32, max_restarts: u32, core_dump: u32) -> i32 {
+    let core_dump = match core_dump {
+        0 => CoreDumpPolicy::Inherit,
+        1 => CoreDumpPolicy::Disable,
+        2 => CoreDumpPolicy::Enable,
+        _ => return -libc::EINVAL,
     };
+
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            cfg.vmr.kernel_console = Some(console_id);
+            ctx_cfg.get_mut().supervisor = Some(SupervisorConfig {
+                max_restarts,
+                core_dump,
+            });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -2179,9 +6381,195 @@ pub unsafe extern "C" fn krun_set_kernel_console(ctx_id: u32, console_id: *const
     KRUN_SUCCESS
 }
 
+/// Signals a VMM is considered to have crashed on, the ones dumping core by
+/// default.
+const CRASH_SIGNALS: [c_int; 7] = [
+    libc::SIGABRT,
+    libc::SIGBUS,
+    libc::SIGFPE,
+    libc::SIGILL,
+    libc::SIGSEGV,
+    libc::SIGSYS,
+    libc::SIGTRAP,
+];
+
+/// Forks the calling process, which may have other threads, for a VMM to run
+/// in the child. The registries of libkrun and the standard streams are
+/// locked across the fork, so that none is left held in the child by a
+/// thread that doesn't exist there. The C library takes care of the
+/// allocator.
+fn fork_vmm() -> libc::pid_t {
+    let _registries = (
+        CTX_MAP.lock().unwrap(),
+        RUNNING_CTXS.lock().unwrap(),
+        AGENT_SOCKETS.lock().unwrap(),
+        TSI_FLOWS.lock().unwrap(),
+        VM_METRICS.lock().unwrap(),
+        BOOT_TIMELINES.lock().unwrap(),
+        RUNNING_VMMS.lock().unwrap(),
+        SHUTDOWN_EVENTS.lock().unwrap(),
+        GUEST_MEMORY.lock().unwrap(),
+        EXEC_STATUS.lock().unwrap(),
+        CLIPBOARD_SYNCS.lock().unwrap(),
+    );
+    #[cfg(feature = "net")]
+    let _net = (
+        GVPROXY_API_SOCKETS.lock().unwrap(),
+        NET_DEVICES.lock().unwrap(),
+    );
+    #[cfg(feature = "gpu")]
+    let _gpu = SCANOUT_CAPTURES.lock().unwrap();
+    let _stdio = (io::stdout().lock(), io::stderr().lock());
+    unsafe { libc::fork() }
+}
+
+/// Runs the microVM with `start` in forked children until it exits other
+/// than by a crash, or has been restarted `max_restarts` times, and returns
+/// its exit code.
+fn supervise_vmm(ctx_id: u32, config: SupervisorConfig, start: fn(u32) -> i32) -> i32 {
+    // The sockets a crashed VMM was listening on are left behind, and must be
+    // removed for the next one to bind them again. They are its own, as
+    // the paths of listening ports can't exist when they are added.
+    let listen_paths: Vec<PathBuf> = CTX_MAP
+        .lock()
+        .unwrap()
+        .get(&ctx_id)
+        .and_then(|ctx_cfg| ctx_cfg.unix_ipc_port_map.as_ref())
+        .map(|map| {
+            map.values()
+                .filter(|(_, listen)| *listen)
+                .map(|(path, _)| path.clone())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut restarts = 0;
+    let ret = 'supervise: loop {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            break -io::Error::last_os_error().raw_os_error().unwrap();
+        }
+        // Safe because the descriptors were just created and are owned here.
+        let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        for fd in fds {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+
+        let pid = fork_vmm();
+        if pid < 0 {
+            break -io::Error::last_os_error().raw_os_error().unwrap();
+        }
+        if pid == 0 {
+            drop(reader);
+            run_supervised_vmm(ctx_id, config.core_dump, writer, start);
+        }
+        drop(writer);
+        SUPERVISED_VMMS.lock().unwrap().insert(ctx_id, pid);
+
+        let mut status = 0;
+        while unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                error!("Failed to wait for the VMM: {e}");
+                break 'supervise io_error_to_errno(e);
+            }
+        }
+
+        // Errors starting the microVM are reported through the pipe, which
+        // is closed without any data when the VMM exits.
+        let mut err = [0u8; 4];
+        if io::Read::read_exact(&mut &reader, &mut err).is_ok() {
+            break i32::from_ne_bytes(err);
+        }
+        if libc::WIFEXITED(status) {
+            break libc::WEXITSTATUS(status);
+        }
+
+        let signal = libc::WTERMSIG(status);
+        let core = if libc::WCOREDUMP(status) {
+            " (core dumped)"
+        } else {
+            ""
+        };
+        if !CRASH_SIGNALS.contains(&signal) {
+            break 128 + signal;
+        }
+        if restarts == config.max_restarts {
+            error!("VMM crashed with signal {signal}{core}, giving up after {restarts} restarts");
+            break 128 + signal;
+        }
+        restarts += 1;
+        warn!(
+            "VMM crashed with signal {signal}{core}, restarting it ({restarts}/{})",
+            config.max_restarts
+        );
+        for path in &listen_paths {
+            if let Err(e) = std::fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to remove {}: {e}", path.display());
+                }
+            }
+        }
+    };
+
+    SUPERVISED_VMMS.lock().unwrap().remove(&ctx_id);
+    CTX_MAP.lock().unwrap().remove(&ctx_id);
+    AGENT_SOCKETS.lock().unwrap().remove(&ctx_id);
+    ret
+}
+
+fn run_supervised_vmm(
+    ctx_id: u32,
+    core_dump: CoreDumpPolicy,
+    mut errors: File,
+    start: fn(u32) -> i32,
+) -> ! {
+    // The VMM must not outlive its supervisor.
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL)
+    };
+
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let set_limit = match core_dump {
+        CoreDumpPolicy::Inherit => false,
+        CoreDumpPolicy::Disable => true,
+        CoreDumpPolicy::Enable => {
+            unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) };
+            limit.rlim_cur = limit.rlim_max;
+            true
+        }
+    };
+    if set_limit && unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } < 0 {
+        warn!(
+            "Failed to set the core dump size limit: {}",
+            io::Error::last_os_error()
+        );
+    }
+
+    let ret = start(ctx_id);
+    let _ = errors.write_all(&ret.to_ne_bytes());
+    unsafe { libc::_exit(1) }
+}
+
 #[no_mangle]
-#[allow(unreachable_code)]
 pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
+    let supervisor = CTX_MAP
+        .lock()
+        .unwrap()
+        .get(&ctx_id)
+        .and_then(|ctx_cfg| ctx_cfg.supervisor);
+    match supervisor {
+        Some(config) => supervise_vmm(ctx_id, config, start_enter),
+        None => start_enter(ctx_id),
+    }
+}
+
+#[allow(unreachable_code)]
+fn start_enter(ctx_id: u32) -> i32 {
     #[cfg(target_os = "linux")]
     {
         let prname = match env::var("HOSTNAME") {
@@ -2194,7 +6582,28 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
     #[cfg(feature = "nitro")]
     return krun_start_enter_nitro(ctx_id);
 
-    let mut event_manager = match EventManager::new() {
+    let mut ctx_cfg = match CTX_MAP.lock().unwrap().remove(&ctx_id) {
+        Some(ctx_cfg) => ctx_cfg,
+        None => return -libc::ENOENT,
+    };
+
+    if !ctx_cfg.forwarded_signals.is_empty() {
+        let Some(path) = agent_socket(ctx_id) else {
+            error!("Forwarding signals requires the guest agent");
+            return -libc::EINVAL;
+        };
+        if let Err(e) = spawn_signal_forwarder(&ctx_cfg.forwarded_signals, path) {
+            error!("Error setting up signal forwarding: {e}");
+            return io_error_to_errno(e);
+        }
+    }
+
+    let event_manager = if ctx_cfg.io_uring {
+        EventManager::new_io_uring()
+    } else {
+        EventManager::new()
+    };
+    let mut event_manager = match event_manager {
         Ok(em) => em,
         Err(e) => {
             error!("Unable to create EventManager: {e:?}");
@@ -2202,10 +6611,27 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         }
     };
 
-    let mut ctx_cfg = match CTX_MAP.lock().unwrap().remove(&ctx_id) {
-        Some(ctx_cfg) => ctx_cfg,
-        None => return -libc::ENOENT,
-    };
+    let boot_timeline = ctx_cfg.vmr.boot_timeline.clone();
+    boot_timeline.record(BootPhase::VmmStarted);
+    boot_timeline.set_print(ctx_cfg.print_boot_timeline);
+    BOOT_TIMELINES.lock().unwrap().insert(ctx_id, boot_timeline);
+    if let Some(threads) = ctx_cfg.device_worker_threads {
+        utils::worker_pool::set_shared_pool_size(threads);
+    }
+
+    #[cfg(feature = "tracing")]
+    if let Some(ref path) = ctx_cfg.trace_file {
+        let subscriber = match utils::chrome_trace::ChromeTraceSubscriber::create(path) {
+            Ok(subscriber) => subscriber,
+            Err(e) => {
+                error!("Error creating trace file: {e:?}");
+                return -libc::EINVAL;
+            }
+        };
+        if tracing::subscriber::set_global_default(subscriber).is_err() {
+            warn!("A global tracing subscriber is already installed, ignoring trace file");
+        }
+    }
 
     if ctx_cfg.vmr.external_kernel.is_none()
         && ctx_cfg.vmr.kernel_bundle.is_none()
@@ -2223,6 +6649,22 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         }
     }
 
+    #[cfg(all(feature = "blk", not(feature = "tee")))]
+    if ctx_cfg.vmr.measured_boot.is_some() {
+        // The first disk is the one the guest sees as /dev/vda.
+        let disk = ctx_cfg
+            .get_block_cfg()
+            .first()
+            .filter(|cfg| cfg.http_url.is_none())
+            .map(|cfg| match &cfg.overlay {
+                Some(overlay) => PathBuf::from(&overlay.base_path),
+                None => PathBuf::from(&cfg.disk_image_path),
+            });
+        if let Some(measured_boot) = ctx_cfg.vmr.measured_boot.as_mut() {
+            measured_boot.disk = disk;
+        }
+    }
+
     #[cfg(feature = "blk")]
     for block_cfg in ctx_cfg.get_block_cfg() {
         if ctx_cfg.vmr.add_block_device(block_cfg).is_err() {
@@ -2248,14 +6690,53 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         return -libc::EINVAL;
     }
 
+    // A virtio-fs root is protected by the host, but not the temporary root
+    // init prepares the switch to a block device root in.
+    #[cfg(not(feature = "tee"))]
+    if ctx_cfg.ephemeral_dirs.is_some() && ctx_cfg.get_block_root().is_empty() {
+        for fs in ctx_cfg
+            .vmr
+            .fs
+            .iter_mut()
+            .filter(|fs| fs.fs_id == "/dev/root")
+        {
+            fs.readonly = true;
+        }
+    }
+
+    let nokaslr = if ctx_cfg.vmr.kaslr_seed == Some(0) {
+        " nokaslr"
+    } else {
+        ""
+    };
+
     let kernel_cmdline = KernelCmdlineConfig {
-        prolog: Some(format!("{DEFAULT_KERNEL_CMDLINE} init={INIT_PATH}")),
+        prolog: Some(format!(
+            "{DEFAULT_KERNEL_CMDLINE} init={INIT_PATH}{nokaslr}"
+        )),
         krun_env: Some(format!(
-            " {} {} {} {} {}",
+            " {}={} {} {} {} {} {} {} {} {} {} {} {} {} {} {} {} {} {} {} {} {}",
+            InitEnv::Protocol.name(),
+            PROTOCOL_VERSION,
             ctx_cfg.get_exec_path(),
             ctx_cfg.get_workdir(),
             ctx_cfg.get_block_root(),
             ctx_cfg.get_rlimits(),
+            ctx_cfg.get_ephemeral(),
+            ctx_cfg.get_kernel_modules(),
+            ctx_cfg.get_mounts(),
+            ctx_cfg.get_sealed_disk(),
+            ctx_cfg.get_sysctls(),
+            ctx_cfg.get_workload_limits(),
+            ctx_cfg.get_lockdown(),
+            ctx_cfg.get_kdump(),
+            ctx_cfg.get_workload_user(),
+            ctx_cfg.get_network_names(),
+            ctx_cfg.get_rosetta(),
+            ctx_cfg.get_agent(),
+            ctx_cfg.get_exec_hook(),
+            ctx_cfg.get_ssh(),
+            ctx_cfg.get_fido(),
             ctx_cfg.get_env(),
         )),
         epilog: Some(format!(" -- {}", ctx_cfg.get_args())),
@@ -2265,6 +6746,60 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         return -libc::EINVAL;
     }
 
+    if let Some(exec_hook) = ctx_cfg.exec_hook.take() {
+        if let Err(e) = exec_hook.spawn() {
+            error!("Error setting up the exec hook: {e:?}");
+            return -libc::EINVAL;
+        }
+    }
+
+    if let Some(ssh_keys) = ctx_cfg.ssh_keys.take() {
+        if let Err(e) = ssh_keys.spawn() {
+            error!("Error setting up the SSH keys: {e:?}");
+            return -libc::EINVAL;
+        }
+    }
+
+    if let Some(fido) = ctx_cfg.fido.take() {
+        if let Err(e) = fido.spawn() {
+            error!("Error setting up the FIDO bridge: {e:?}");
+            return -libc::EINVAL;
+        }
+    }
+
+    if let Some(clipboard_sync) = ctx_cfg.clipboard_sync.take() {
+        let Some(agent_path) = agent_socket(ctx_id) else {
+            error!("Clipboard sharing requires the guest agent");
+            return -libc::EINVAL;
+        };
+        if let Err(e) = clipboard_sync.spawn(ctx_id, agent_path) {
+            error!("Error setting up clipboard sharing: {e:?}");
+            return -libc::EINVAL;
+        }
+    }
+
+    if ctx_cfg.host_sleep_sync {
+        let Some(agent_path) = agent_socket(ctx_id) else {
+            error!("Following host sleep requires the guest agent");
+            return -libc::EINVAL;
+        };
+        let suspended = AtomicBool::new(false);
+        if let Err(e) = host_sleep::watch(Box::new(move |event| {
+            sync_host_sleep(&agent_path, &suspended, event)
+        })) {
+            error!("Error watching for host sleep: {e:?}");
+            return -libc::EINVAL;
+        }
+    }
+
+    #[cfg(all(feature = "gpu", not(feature = "tee")))]
+    if let Some((server, path)) = ctx_cfg.vnc.take() {
+        if let Err(e) = server.listen(&path) {
+            error!("Error listening for VNC clients: {e:?}");
+            return -libc::EINVAL;
+        }
+    }
+
     #[cfg(feature = "net")]
     {
         if let Some(legacy_net_cfg) = ctx_cfg.legacy_net_cfg.clone() {
@@ -2285,19 +6820,29 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
     let mut vsock_set = false;
     let mut vsock_config = VsockDeviceConfig {
         vsock_id: "vsock0".to_string(),
-        guest_cid: 3,
+        guest_cid: ctx_cfg.vsock_cid.unwrap_or(3),
         host_port_map: None,
+        unix_socket_map: None,
+        listen_fds: HashMap::new(),
+        http_proxy: None,
         unix_ipc_port_map: None,
+        sibling_dir: None,
     };
 
     #[cfg(feature = "net")]
     if ctx_cfg.vmr.net.list.is_empty() && ctx_cfg.legacy_net_cfg.is_none() {
         vsock_config.host_port_map = ctx_cfg.tsi_port_map;
+        vsock_config.unix_socket_map = ctx_cfg.tsi_unix_socket_map.take();
+        vsock_config.listen_fds = std::mem::take(&mut ctx_cfg.tsi_listen_fds);
+        vsock_config.http_proxy = ctx_cfg.tsi_http_proxy.take();
         vsock_set = true;
     }
     #[cfg(not(feature = "net"))]
     {
         vsock_config.host_port_map = ctx_cfg.tsi_port_map;
+        vsock_config.unix_socket_map = ctx_cfg.tsi_unix_socket_map.take();
+        vsock_config.listen_fds = std::mem::take(&mut ctx_cfg.tsi_listen_fds);
+        vsock_config.http_proxy = ctx_cfg.tsi_http_proxy.take();
         vsock_set = true;
     }
 
@@ -2306,8 +6851,19 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         vsock_set = true;
     }
 
+    if let Some(dir) = ctx_cfg.vsock_sibling_dir.take() {
+        vsock_config.sibling_dir = Some(dir);
+        vsock_set = true;
+    }
+
     if vsock_set {
         ctx_cfg.vmr.set_vsock_device(vsock_config).unwrap();
+        if let Some(vsock) = ctx_cfg.vmr.vsock.get() {
+            TSI_FLOWS
+                .lock()
+                .unwrap()
+                .insert(ctx_id, vsock.lock().unwrap().flow_table());
+        }
     }
 
     if let Some(virgl_flags) = ctx_cfg.gpu_virgl_flags {
@@ -2316,6 +6872,16 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
     if let Some(shm_size) = ctx_cfg.gpu_shm_size {
         ctx_cfg.vmr.set_gpu_shm_size(shm_size);
     }
+    #[cfg(feature = "gpu")]
+    if ctx_cfg.gpu_virgl_flags.is_some() {
+        let (callback, max_fps) = match ctx_cfg.frame_stream.take() {
+            Some((callback, max_fps)) => (Some(callback), max_fps),
+            None => (None, 0),
+        };
+        let capture = Arc::new(ScanoutCapture::new(callback, max_fps));
+        ctx_cfg.vmr.scanout_capture = Some(capture.clone());
+        SCANOUT_CAPTURES.lock().unwrap().insert(ctx_id, capture);
+    }
 
     #[cfg(feature = "snd")]
     ctx_cfg.vmr.set_snd_device(ctx_cfg.enable_snd);
@@ -2324,6 +6890,33 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         ctx_cfg.vmr.set_console_output(console_output);
     }
 
+    #[cfg(feature = "net")]
+    if !ctx_cfg.net_egress_rules.is_empty() || ctx_cfg.net_egress_default.is_some() {
+        let filter = Arc::new(egress::EgressFilter::new(
+            std::mem::take(&mut ctx_cfg.net_egress_rules),
+            ctx_cfg.net_egress_default.unwrap_or(egress::Action::Allow),
+        ));
+        for net in ctx_cfg.vmr.net.list.iter() {
+            net.lock().unwrap().set_egress_filter(filter.clone());
+        }
+    }
+    #[cfg(feature = "net")]
+    for (iface, config) in ctx_cfg.net_netem.drain() {
+        match ctx_cfg.vmr.net.list.get(iface as usize) {
+            Some(net) => net.lock().unwrap().set_netem(config),
+            None => warn!("No network device {iface} to emulate network conditions on"),
+        }
+    }
+
+    // The VMM creates the sockets and files of the scratch directory after
+    // dropping its privileges, and removes them on exit.
+    if let Some(dir) = &ctx_cfg.scratch_dir {
+        if let Err(e) = std::os::unix::fs::chown(dir.path(), ctx_cfg.vmm_uid, ctx_cfg.vmm_gid) {
+            error!("Failed to change the owner of the scratch directory: {e}");
+            return io_error_to_errno(e);
+        }
+    }
+
     if let Some(gid) = ctx_cfg.vmm_gid {
         if unsafe { libc::setgid(gid) } != 0 {
             error!("Failed to set gid {gid}");
@@ -2338,8 +6931,24 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         }
     }
 
+    if let Some(ref path) = ctx_cfg.metrics_socket {
+        if let Err(e) = utils::metrics::spawn_exporter(path, ctx_cfg.vmr.metrics.clone()) {
+            error!("Error starting metrics exporter: {e:?}");
+            return -libc::EINVAL;
+        }
+    }
+
     let (sender, _receiver) = unbounded();
 
+    if let Some(efd) = &ctx_cfg.shutdown_efd {
+        match efd.try_clone() {
+            Ok(efd) => {
+                SHUTDOWN_EVENTS.lock().unwrap().insert(ctx_id, efd);
+            }
+            Err(e) => warn!("Failed to keep the shutdown event: {e}"),
+        }
+    }
+
     let _vmm = match vmm::builder::build_microvm(
         &ctx_cfg.vmr,
         &mut event_manager,
@@ -2353,6 +6962,31 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         }
     };
 
+    RUNNING_VMMS.lock().unwrap().insert(ctx_id, _vmm.clone());
+
+    // The VMM exits the process without the context ever being dropped.
+    if let Some(dir) = &ctx_cfg.scratch_dir {
+        let path = dir.path().to_path_buf();
+        _vmm.lock()
+            .unwrap()
+            .add_exit_observer(Arc::new(Mutex::new(move || {
+                let _ = std::fs::remove_dir_all(&path);
+            })));
+    }
+
+    if let Some(path) = ctx_cfg.workload_stats_path.take() {
+        _vmm.lock()
+            .unwrap()
+            .add_exit_observer(Arc::new(Mutex::new(move || {
+                if let Err(e) = std::fs::write(&path, WORKLOAD_STATS.to_json()) {
+                    error!(
+                        "Failed to write the workload stats to {}: {e}",
+                        path.display()
+                    );
+                }
+            })));
+    }
+
     #[cfg(target_os = "macos")]
     if ctx_cfg.gpu_virgl_flags.is_some() {
         vmm::worker::start_worker_thread(_vmm.clone(), _receiver).unwrap();
@@ -2366,6 +7000,21 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
     #[cfg(any(feature = "amd-sev", feature = "tdx"))]
     vmm::worker::start_worker_thread(_vmm.clone(), _receiver.clone()).unwrap();
 
+    GUEST_MEMORY
+        .lock()
+        .unwrap()
+        .insert(ctx_id, _vmm.lock().unwrap().guest_memory().clone());
+    VM_METRICS
+        .lock()
+        .unwrap()
+        .insert(ctx_id, ctx_cfg.vmr.metrics.clone());
+    #[cfg(feature = "net")]
+    NET_DEVICES
+        .lock()
+        .unwrap()
+        .insert(ctx_id, ctx_cfg.vmr.net.list.iter().cloned().collect());
+    RUNNING_CTXS.lock().unwrap().insert(ctx_id);
+
     loop {
         match event_manager.run() {
             Ok(_) => {}
@@ -2399,3 +7048,142 @@ fn krun_start_enter_nitro(ctx_id: u32) -> i32 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Listens on the vsock port of the context, crashing the first time.
+    fn crash_once(ctx_id: u32) -> i32 {
+        let path = CTX_MAP.lock().unwrap()[&ctx_id]
+            .unix_ipc_port_map
+            .as_ref()
+            .unwrap()[&1024]
+            .0
+            .clone();
+        let _listener = match UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(e) => return io_error_to_errno(e),
+        };
+        let crashed = path.with_extension("crashed");
+        if !crashed.exists() {
+            File::create(crashed).unwrap();
+            unsafe { libc::abort() };
+        }
+        KRUN_SUCCESS
+    }
+
+    #[test]
+    fn test_supervisor_restart() {
+        let dir = env::temp_dir().join(format!("libkrun-supervisor-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("port.sock");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let ctx_id = krun_create_ctx() as u32;
+        assert_eq!(
+            unsafe { krun_add_vsock_port2(ctx_id, 1024, c_path.as_ptr(), true) },
+            KRUN_SUCCESS
+        );
+        let config = SupervisorConfig {
+            max_restarts: 1,
+            core_dump: CoreDumpPolicy::Disable,
+        };
+        // The VMM started after the crash binds the socket again.
+        assert_eq!(supervise_vmm(ctx_id, config, crash_once), KRUN_SUCCESS);
+        assert!(path.with_extension("crashed").exists());
+        assert!(!ctx_exists(ctx_id));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_metrics_per_context() {
+        let ctx_ids = [krun_create_ctx() as u32, krun_create_ctx() as u32];
+        let metrics = ctx_metrics(ctx_ids[0]).unwrap();
+        metrics.vm_exits.hlt.add(2);
+        metrics.device("block", "root").rx_bytes.add(512);
+
+        let get = |ctx_id| {
+            let mut buf = [0u8; 1024];
+            let len = unsafe { krun_get_metrics_json(ctx_id, buf.as_mut_ptr().cast(), buf.len()) };
+            assert!(len > 0);
+            String::from_utf8(buf[..len as usize].to_vec()).unwrap()
+        };
+        assert!(get(ctx_ids[0]).contains("\"hlt\":2,"));
+        assert!(get(ctx_ids[1]).contains("\"hlt\":0,"));
+
+        let dev_id = CString::new("root").unwrap();
+        let mut buf = [0u8; 256];
+        let stats = |ctx_id, buf: &mut [u8]| unsafe {
+            krun_get_device_stats(ctx_id, dev_id.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+        };
+        assert!(stats(ctx_ids[0], &mut buf) > 0);
+        assert_eq!(stats(ctx_ids[1], &mut buf), -libc::ENODEV);
+
+        for ctx_id in ctx_ids {
+            assert_eq!(krun_free_ctx(ctx_id), KRUN_SUCCESS);
+        }
+        assert_eq!(
+            unsafe { krun_get_metrics_json(ctx_ids[0], buf.as_mut_ptr().cast(), buf.len()) },
+            -libc::ENOENT
+        );
+    }
+
+    #[test]
+    fn test_boot_timeline_per_context() {
+        let ctx_ids = [krun_create_ctx() as u32, krun_create_ctx() as u32];
+        CTX_MAP.lock().unwrap()[&ctx_ids[0]]
+            .vmr
+            .boot_timeline
+            .record(BootPhase::VmmStarted);
+
+        let get = |ctx_id| {
+            let mut buf = [0u8; 256];
+            let len =
+                unsafe { krun_get_boot_timeline_json(ctx_id, buf.as_mut_ptr().cast(), buf.len()) };
+            assert!(len > 0);
+            String::from_utf8(buf[..len as usize].to_vec()).unwrap()
+        };
+        assert!(get(ctx_ids[0]).starts_with("{\"context_created\":0,\"vmm_started\":"));
+        assert!(!get(ctx_ids[0]).contains("\"vmm_started\":null"));
+        assert!(get(ctx_ids[1]).starts_with("{\"context_created\":0,\"vmm_started\":null,"));
+
+        for ctx_id in ctx_ids {
+            assert_eq!(krun_free_ctx(ctx_id), KRUN_SUCCESS);
+        }
+    }
+
+    #[test]
+    fn test_agent_socket_freed_with_context() {
+        let ctx_id = krun_create_ctx() as u32;
+        assert_eq!(krun_enable_agent(ctx_id), KRUN_SUCCESS);
+        assert!(AGENT_SOCKETS.lock().unwrap().contains_key(&ctx_id));
+
+        assert_eq!(krun_free_ctx(ctx_id), KRUN_SUCCESS);
+        assert!(!AGENT_SOCKETS.lock().unwrap().contains_key(&ctx_id));
+    }
+
+    #[cfg(feature = "amd-sev")]
+    #[test]
+    fn test_sealed_disk_fields() {
+        let ctx_id = krun_create_ctx() as u32;
+        let block_id = CString::new("data").unwrap();
+        let guest_path = CString::new("/data").unwrap();
+        let set = |fields| unsafe {
+            krun_set_sealed_disk(ctx_id, block_id.as_ptr(), guest_path.as_ptr(), fields)
+        };
+
+        // Keys that aren't bound to the measurement are rejected.
+        assert_eq!(set(0), -libc::EINVAL);
+        assert_eq!(
+            set(KRUN_SEAL_ALL_FIELDS & !KRUN_SEAL_MEASUREMENT),
+            -libc::EINVAL
+        );
+        assert_eq!(set(1 << 6 | KRUN_SEAL_MEASUREMENT), -libc::EINVAL);
+        // The fields are valid, but there's no such disk.
+        assert_eq!(set(KRUN_SEAL_MEASUREMENT), -libc::ENOENT);
+
+        assert_eq!(krun_free_ctx(ctx_id), KRUN_SUCCESS);
+    }
+}
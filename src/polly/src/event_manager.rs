@@ -9,6 +9,9 @@ use std::sync::{Arc, Mutex};
 
 use utils::epoll::{self, Epoll, EpollEvent};
 
+#[cfg(target_os = "linux")]
+use crate::io_uring::IoUring;
+
 pub type Result<T> = std::result::Result<T, Error>;
 pub type Pollable = RawFd;
 
@@ -57,20 +60,78 @@ pub trait Subscriber {
     ///   the `libc::epoll_ctl` operations.
     fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager);
 
+    /// Callback called when a read submitted through
+    /// `EventManager::submit_read` on behalf of this subscriber completes.
+    ///
+    /// # Arguments
+    /// * token - the value passed when submitting the operation
+    /// * result - the number of bytes transferred, or a negative errno
+    /// * event_manager - Reference to the `EventManager`, as in `process`.
+    fn process_completion(&mut self, _token: u64, _result: i32, _event_manager: &mut EventManager) {
+    }
+
     /// Returns a list of `EpollEvent` that this subscriber is interested in.
     fn interest_list(&self) -> Vec<EpollEvent>;
 }
 
-/// Manages I/O notifications using epoll mechanism.
+enum Poller {
+    Epoll(Epoll),
+    #[cfg(target_os = "linux")]
+    IoUring(Box<IoUring>),
+}
+
+impl Poller {
+    fn ctl(
+        &mut self,
+        operation: epoll::ControlOperation,
+        fd: RawFd,
+        event: &EpollEvent,
+    ) -> io::Result<()> {
+        match self {
+            Poller::Epoll(epoll) => epoll.ctl(operation, fd, event),
+            #[cfg(target_os = "linux")]
+            Poller::IoUring(ring) => ring.ctl(operation, fd, event),
+        }
+    }
+
+    fn wait(
+        &mut self,
+        max_events: usize,
+        timeout: i32,
+        events: &mut [EpollEvent],
+    ) -> io::Result<usize> {
+        match self {
+            Poller::Epoll(epoll) => epoll.wait(max_events, timeout, events),
+            #[cfg(target_os = "linux")]
+            Poller::IoUring(ring) => ring.wait(max_events, timeout, events),
+        }
+    }
+}
+
+/// A read submitted on behalf of a subscriber.
+struct IoOperation {
+    pollable: Pollable,
+    token: u64,
+}
+
+/// Manages I/O notifications using epoll mechanism, or optionally io_uring.
 pub struct EventManager {
-    epoll: Epoll,
+    poller: Poller,
     subscribers: HashMap<RawFd, Arc<Mutex<dyn Subscriber>>>,
     ready_events: Vec<EpollEvent>,
+    io_operations: HashMap<u32, IoOperation>,
+    next_io_tag: u32,
+    /// Completed reads waiting to be dispatched.
+    completions: Vec<(IoOperation, i32)>,
 }
 
 impl AsRawFd for EventManager {
     fn as_raw_fd(&self) -> RawFd {
-        self.epoll.as_raw_fd()
+        match &self.poller {
+            Poller::Epoll(epoll) => epoll.as_raw_fd(),
+            #[cfg(target_os = "linux")]
+            Poller::IoUring(ring) => ring.as_raw_fd(),
+        }
     }
 }
 
@@ -80,15 +141,36 @@ impl EventManager {
     /// Create a new EventManager.
     pub fn new() -> Result<EventManager> {
         let epoll_fd = epoll::Epoll::new().map_err(Error::EpollCreate)?;
+        Ok(Self::with_poller(Poller::Epoll(epoll_fd)))
+    }
+
+    /// Create a new EventManager backed by io_uring, falling back to epoll if
+    /// io_uring isn't available on this host.
+    pub fn new_io_uring() -> Result<EventManager> {
+        #[cfg(target_os = "linux")]
+        if let Ok(ring) = IoUring::new() {
+            return Ok(Self::with_poller(Poller::IoUring(Box::new(ring))));
+        }
+        Self::new()
+    }
 
-        Ok(EventManager {
-            epoll: epoll_fd,
+    fn with_poller(poller: Poller) -> EventManager {
+        EventManager {
+            poller,
             subscribers: HashMap::new(),
             // This buffer is used for storing the events returned by `epoll_wait()`.
             // We preallocate memory for this buffer in order to not repeat this
             // operation every time `run()` loop is executed.
             ready_events: vec![epoll::EpollEvent::default(); EventManager::EVENT_BUFFER_SIZE],
-        })
+            io_operations: HashMap::new(),
+            next_io_tag: 0,
+            completions: Vec::new(),
+        }
+    }
+
+    /// Returns whether this EventManager is backed by io_uring.
+    pub fn is_io_uring(&self) -> bool {
+        !matches!(self.poller, Poller::Epoll(_))
     }
 
     /// Returns a clone of the subscriber associated with the `fd`.
@@ -128,7 +210,7 @@ impl EventManager {
             return Err(Error::AlreadyExists(pollable));
         };
 
-        self.epoll
+        self.poller
             .ctl(epoll::ControlOperation::Add, pollable, &epoll_event)
             .map_err(Error::Poll)?;
 
@@ -140,7 +222,7 @@ impl EventManager {
     pub fn unregister(&mut self, pollable: Pollable) -> Result<()> {
         match self.subscribers.remove(&pollable) {
             Some(_) => {
-                self.epoll
+                self.poller
                     .ctl(
                         epoll::ControlOperation::Delete,
                         pollable,
@@ -158,7 +240,7 @@ impl EventManager {
     /// Update the events monitored by `pollable`.
     pub fn modify(&mut self, pollable: Pollable, epoll_event: EpollEvent) -> Result<()> {
         if self.subscribers.contains_key(&pollable) {
-            self.epoll
+            self.poller
                 .ctl(epoll::ControlOperation::Modify, pollable, &epoll_event)
                 .map_err(Error::Poll)?;
         } else {
@@ -170,14 +252,27 @@ impl EventManager {
 
     /// Check if a file descriptor is pollable
     pub fn is_pollable(&mut self, pollable: Pollable) -> bool {
-        self.epoll
+        // io_uring happily polls anything, so ask epoll in every case.
+        let probe;
+        let epoll = match &self.poller {
+            Poller::Epoll(epoll) => epoll,
+            #[cfg(target_os = "linux")]
+            Poller::IoUring(_) => match Epoll::new() {
+                Ok(epoll) => {
+                    probe = epoll;
+                    &probe
+                }
+                Err(_) => return false,
+            },
+        };
+        epoll
             .ctl(
                 epoll::ControlOperation::Add,
                 pollable,
                 &epoll::EpollEvent::default(),
             )
             .is_ok_and(|_| {
-                self.epoll
+                epoll
                     .ctl(
                         epoll::ControlOperation::Delete,
                         pollable,
@@ -196,7 +291,13 @@ impl EventManager {
     /// Wait for events for a maximum timeout of `miliseconds`. Dispatch the events to the
     /// registered signal handlers.
     pub fn run_with_timeout(&mut self, milliseconds: i32) -> Result<usize> {
-        let event_count = match self.epoll.wait(
+        // Don't wait if there are completions ready to be dispatched already.
+        let milliseconds = if self.completions.is_empty() {
+            milliseconds
+        } else {
+            0
+        };
+        let event_count = match self.poller.wait(
             EventManager::EVENT_BUFFER_SIZE,
             milliseconds,
             &mut self.ready_events[..],
@@ -206,8 +307,73 @@ impl EventManager {
             Err(e) => return Err(Error::Poll(e)),
         };
         self.dispatch_events(event_count);
+        let completion_count = self.dispatch_completions();
+
+        Ok(event_count + completion_count)
+    }
+
+    /// Submits a read of up to `len` bytes from `fd` into `buf`, at `offset`
+    /// or at the current file position if `None`. Once done,
+    /// `process_completion` is called on the subscriber of `pollable` with
+    /// `token`. Without io_uring, the read is performed right away.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid for writes of `len` bytes until the completion is
+    /// dispatched.
+    pub unsafe fn submit_read(
+        &mut self,
+        pollable: Pollable,
+        fd: RawFd,
+        buf: *mut u8,
+        len: u32,
+        offset: Option<u64>,
+        token: u64,
+    ) -> Result<()> {
+        let op = IoOperation { pollable, token };
+        match &mut self.poller {
+            #[cfg(target_os = "linux")]
+            Poller::IoUring(ring) => {
+                let tag = self.next_io_tag;
+                ring.submit_read(fd, buf, len, offset.unwrap_or(u64::MAX), tag)
+                    .map_err(Error::Poll)?;
+                self.next_io_tag = self.next_io_tag.wrapping_add(1);
+                self.io_operations.insert(tag, op);
+            }
+            Poller::Epoll(_) => {
+                let ret = match offset {
+                    Some(offset) => {
+                        libc::pread(fd, buf as *mut _, len as usize, offset as libc::off_t)
+                    }
+                    None => libc::read(fd, buf as *mut _, len as usize),
+                };
+                self.completions.push((op, result_or_errno(ret)));
+            }
+        }
+        Ok(())
+    }
 
-        Ok(event_count)
+    fn dispatch_completions(&mut self) -> usize {
+        #[cfg(target_os = "linux")]
+        if let Poller::IoUring(ring) = &mut self.poller {
+            for completion in ring.take_completions() {
+                if let Some(op) = self.io_operations.remove(&(completion.tag as u32)) {
+                    self.completions.push((op, completion.result));
+                }
+            }
+        }
+
+        let completions = std::mem::take(&mut self.completions);
+        let count = completions.len();
+        for (op, result) in completions {
+            if let Some(subscriber) = self.subscribers.get(&op.pollable).cloned() {
+                subscriber
+                    .lock()
+                    .unwrap()
+                    .process_completion(op.token, result, self);
+            }
+        }
+        count
     }
 
     fn dispatch_events(&mut self, event_count: usize) {
@@ -230,6 +396,16 @@ impl EventManager {
     }
 }
 
+fn result_or_errno(ret: isize) -> i32 {
+    if ret < 0 {
+        -io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO)
+    } else {
+        ret as i32
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -467,6 +643,129 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_io_uring_modify() {
+        let mut event_manager = EventManager::new_io_uring().unwrap();
+        let dummy_subscriber = Arc::new(Mutex::new(DummySubscriber::new()));
+
+        event_manager
+            .add_subscriber(dummy_subscriber.clone())
+            .unwrap();
+
+        dummy_subscriber.lock().unwrap().modify_ev1();
+        event_manager.run().unwrap();
+        assert!(dummy_subscriber.lock().unwrap().processed_ev1_out());
+
+        dummy_subscriber.lock().unwrap().reset_state();
+        dummy_subscriber
+            .lock()
+            .unwrap()
+            .event_fd_1
+            .write(1)
+            .unwrap();
+
+        event_manager.run().unwrap();
+        assert!(!dummy_subscriber.lock().unwrap().processed_ev1_out());
+        assert!(dummy_subscriber.lock().unwrap().processed_ev1_in());
+    }
+
+    struct ReadSubscriber {
+        event_fd: EventFd,
+        buf: [u8; 8],
+        completion: Option<(u64, i32)>,
+        completion_count: usize,
+    }
+
+    impl Subscriber for ReadSubscriber {
+        fn process(&mut self, _event: &EpollEvent, _event_manager: &mut EventManager) {}
+
+        fn process_completion(
+            &mut self,
+            token: u64,
+            result: i32,
+            _event_manager: &mut EventManager,
+        ) {
+            self.completion = Some((token, result));
+            self.completion_count += 1;
+        }
+
+        fn interest_list(&self) -> Vec<EpollEvent> {
+            vec![EpollEvent::new(
+                EventSet::IN,
+                self.event_fd.as_raw_fd() as u64,
+            )]
+        }
+    }
+
+    #[test]
+    fn test_submit_read() {
+        for mut event_manager in [
+            EventManager::new().unwrap(),
+            EventManager::new_io_uring().unwrap(),
+        ] {
+            let subscriber = Arc::new(Mutex::new(ReadSubscriber {
+                event_fd: EventFd::new(0).unwrap(),
+                buf: [0; 8],
+                completion: None,
+                completion_count: 0,
+            }));
+            event_manager.add_subscriber(subscriber.clone()).unwrap();
+
+            let (fd, buf) = {
+                let mut s = subscriber.lock().unwrap();
+                s.event_fd.write(42).unwrap();
+                (s.event_fd.as_raw_fd(), s.buf.as_mut_ptr())
+            };
+            // SAFETY: the buffer lives in the subscriber, which outlives the
+            // event manager loop below.
+            unsafe { event_manager.submit_read(fd, fd, buf, 8, None, 7) }.unwrap();
+
+            while subscriber.lock().unwrap().completion.is_none() {
+                event_manager.run_with_timeout(100).unwrap();
+            }
+            let s = subscriber.lock().unwrap();
+            assert_eq!(s.completion, Some((7, 8)));
+            assert_eq!(u64::from_ne_bytes(s.buf), 42);
+        }
+    }
+
+    #[test]
+    fn test_submit_read_overflowing_ring() {
+        let mut event_manager = EventManager::new_io_uring().unwrap();
+        let subscriber = Arc::new(Mutex::new(ReadSubscriber {
+            event_fd: EventFd::new(0).unwrap(),
+            buf: [0; 8],
+            completion: None,
+            completion_count: 0,
+        }));
+        event_manager.add_subscriber(subscriber.clone()).unwrap();
+        let pollable = subscriber.lock().unwrap().event_fd.as_raw_fd();
+
+        // More reads than the submission queue holds, which must be handed to
+        // the kernel rather than overwritten.
+        let zero = std::fs::File::open("/dev/zero").unwrap();
+        let mut bufs = vec![[0xffu8; 8]; 300];
+        for (token, buf) in bufs.iter_mut().enumerate() {
+            // SAFETY: the buffers outlive the event manager loop below.
+            unsafe {
+                event_manager.submit_read(
+                    pollable,
+                    zero.as_raw_fd(),
+                    buf.as_mut_ptr(),
+                    8,
+                    None,
+                    token as u64,
+                )
+            }
+            .unwrap();
+        }
+
+        while subscriber.lock().unwrap().completion_count < bufs.len() {
+            event_manager.run_with_timeout(100).unwrap();
+        }
+        assert!(bufs.iter().all(|buf| *buf == [0; 8]));
+    }
+
     // Test that registering the same event twice throws an error.
     #[test]
     fn test_register_errors() {
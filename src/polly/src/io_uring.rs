@@ -0,0 +1,578 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! A minimal io_uring instance offering the same readiness interface as
+//! `Epoll`, built on `IORING_OP_POLL_ADD`, plus the ability to submit reads
+//! through the same ring.
+//!
+//! Level triggered registrations use one-shot polls that are re-armed once
+//! the event has been dispatched, while edge triggered ones use multishot
+//! polls when the kernel supports them.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use utils::epoll::{ControlOperation, EpollEvent, EventSet};
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+const IORING_ENTER_GETEVENTS: u32 = 1;
+
+const IORING_OP_POLL_ADD: u8 = 6;
+const IORING_OP_POLL_REMOVE: u8 = 7;
+const IORING_OP_TIMEOUT: u8 = 11;
+const IORING_OP_TIMEOUT_REMOVE: u8 = 12;
+const IORING_OP_READ: u8 = 22;
+
+const IORING_POLL_ADD_MULTI: u32 = 1;
+const IORING_CQE_F_MORE: u32 = 1 << 1;
+
+/// Number of submission queue entries.
+const RING_ENTRIES: u32 = 256;
+
+// The kind of request is stored in the top byte of the user data.
+const KIND_SHIFT: u32 = 56;
+const KIND_POLL: u64 = 1;
+const KIND_TIMEOUT: u64 = 2;
+const KIND_IO: u64 = 3;
+const KIND_CANCEL: u64 = 4;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct IoUringParams {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct KernelTimespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+/// A region of memory shared with the kernel.
+struct Mapping {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        // SAFETY: We map a region of the io_uring file descriptor, of the
+        // size reported by the kernel, and check the result.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { addr, len })
+    }
+
+    /// # Safety
+    ///
+    /// `offset` must be within the mapping and suitably aligned for `T`.
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        (self.addr as *mut u8).add(offset as usize) as *mut T
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: The region was mapped by us and nothing refers to it anymore.
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
+
+struct Registration {
+    generation: u32,
+    event: EpollEvent,
+    multishot: bool,
+}
+
+/// The completion of a read submitted to the ring.
+pub struct Completion {
+    pub tag: u64,
+    pub result: i32,
+}
+
+pub struct IoUring {
+    fd: RawFd,
+    sq_ring: Mapping,
+    cq_ring: Mapping,
+    sqes: Mapping,
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+    sq_entries: u32,
+    to_submit: u32,
+    registrations: HashMap<RawFd, Registration>,
+    next_generation: u32,
+    /// Registrations whose one-shot poll must be re-armed, which is only
+    /// done once their last event has been dispatched.
+    rearm: Vec<(RawFd, u32)>,
+    timeout: KernelTimespec,
+    timeout_id: u64,
+    timeout_armed: bool,
+    completions: Vec<Completion>,
+}
+
+// SAFETY: The mappings are only accessed through `&mut self`.
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    pub fn new() -> io::Result<Self> {
+        let mut params = IoUringParams::default();
+        // SAFETY: `params` is a valid `struct io_uring_params`.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                RING_ENTRIES,
+                &mut params as *mut IoUringParams,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+
+        let mapped = (|| {
+            let sq_len =
+                params.sq_off.array as usize + params.sq_entries as usize * size_of::<u32>();
+            let cq_len =
+                params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+            let sqes_len = params.sq_entries as usize * size_of::<Sqe>();
+            Ok::<_, io::Error>((
+                Mapping::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+                Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?,
+                Mapping::new(fd, sqes_len, IORING_OFF_SQES)?,
+            ))
+        })();
+        let (sq_ring, cq_ring, sqes) = match mapped {
+            Ok(mappings) => mappings,
+            Err(e) => {
+                // SAFETY: We own the file descriptor.
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        };
+
+        Ok(IoUring {
+            fd,
+            sq_ring,
+            cq_ring,
+            sqes,
+            sq_entries: params.sq_entries,
+            sq_off: params.sq_off,
+            cq_off: params.cq_off,
+            to_submit: 0,
+            registrations: HashMap::new(),
+            next_generation: 0,
+            rearm: Vec::new(),
+            timeout: KernelTimespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            timeout_id: 0,
+            timeout_armed: false,
+            completions: Vec::new(),
+        })
+    }
+
+    fn sq_atomic(&self, offset: u32) -> &AtomicU32 {
+        // SAFETY: The offsets come from the kernel and point to u32 fields of
+        // the submission ring, which lives as long as `self`.
+        unsafe { &*self.sq_ring.at::<AtomicU32>(offset) }
+    }
+
+    fn cq_atomic(&self, offset: u32) -> &AtomicU32 {
+        // SAFETY: As above, for the completion ring.
+        unsafe { &*self.cq_ring.at::<AtomicU32>(offset) }
+    }
+
+    fn enter(&mut self, min_complete: u32) -> io::Result<()> {
+        let flags = if min_complete > 0 {
+            IORING_ENTER_GETEVENTS
+        } else {
+            0
+        };
+        // SAFETY: The ring is valid and no signal mask is passed.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd,
+                self.to_submit,
+                min_complete,
+                flags,
+                ptr::null::<libc::sigset_t>(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.to_submit -= (ret as u32).min(self.to_submit);
+        Ok(())
+    }
+
+    /// Queues `sqe`, handing the queued entries to the kernel first if the
+    /// submission queue is full. Fails with `EBUSY` if the kernel doesn't
+    /// consume any, rather than overwriting them.
+    fn push(&mut self, sqe: Sqe) -> io::Result<()> {
+        let tail = self.sq_atomic(self.sq_off.tail).load(Ordering::Relaxed);
+        let head = self.sq_atomic(self.sq_off.head).load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= self.sq_entries {
+            self.enter(0)?;
+            let head = self.sq_atomic(self.sq_off.head).load(Ordering::Acquire);
+            if tail.wrapping_sub(head) >= self.sq_entries {
+                return Err(io::Error::from_raw_os_error(libc::EBUSY));
+            }
+        }
+
+        let mask = self
+            .sq_atomic(self.sq_off.ring_mask)
+            .load(Ordering::Relaxed);
+        let index = tail & mask;
+        // SAFETY: `index` is within the submission queue entries and the
+        // array, both sized for `sq_entries` elements.
+        unsafe {
+            self.sqes.at::<Sqe>(0).add(index as usize).write(sqe);
+            self.sq_ring
+                .at::<u32>(self.sq_off.array)
+                .add(index as usize)
+                .write(index);
+        }
+        self.sq_atomic(self.sq_off.tail)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        self.to_submit += 1;
+        Ok(())
+    }
+
+    fn poll_user_data(fd: RawFd, generation: u32) -> u64 {
+        (KIND_POLL << KIND_SHIFT) | (u64::from(generation & 0xff_ffff) << 32) | u64::from(fd as u32)
+    }
+
+    fn arm(&mut self, fd: RawFd) -> io::Result<()> {
+        let Some(reg) = self.registrations.get(&fd) else {
+            return Ok(());
+        };
+        let sqe = Sqe {
+            opcode: IORING_OP_POLL_ADD,
+            fd,
+            len: if reg.multishot {
+                IORING_POLL_ADD_MULTI
+            } else {
+                0
+            },
+            // The poll mask shares its bits with the epoll one, minus the
+            // flags that only make sense to epoll.
+            op_flags: reg.event.events()
+                & !(EventSet::EDGE_TRIGGERED | EventSet::ONE_SHOT | EventSet::EXCLUSIVE).bits(),
+            user_data: Self::poll_user_data(fd, reg.generation),
+            ..Default::default()
+        };
+        self.push(sqe)
+    }
+
+    fn disarm(&mut self, fd: RawFd) -> io::Result<()> {
+        let Some(reg) = self.registrations.remove(&fd) else {
+            return Ok(());
+        };
+        self.push(Sqe {
+            opcode: IORING_OP_POLL_REMOVE,
+            addr: Self::poll_user_data(fd, reg.generation),
+            user_data: KIND_CANCEL << KIND_SHIFT,
+            ..Default::default()
+        })
+    }
+
+    /// Mirrors `Epoll::ctl`.
+    pub fn ctl(
+        &mut self,
+        operation: ControlOperation,
+        fd: RawFd,
+        event: &EpollEvent,
+    ) -> io::Result<()> {
+        match operation {
+            ControlOperation::Add | ControlOperation::Modify => {
+                let exists = self.registrations.contains_key(&fd);
+                if matches!(operation, ControlOperation::Add) && exists {
+                    return Err(io::Error::from_raw_os_error(libc::EEXIST));
+                }
+                if matches!(operation, ControlOperation::Modify) && !exists {
+                    return Err(io::Error::from_raw_os_error(libc::ENOENT));
+                }
+                self.disarm(fd)?;
+
+                self.next_generation = self.next_generation.wrapping_add(1);
+                let multishot = event.event_set().contains(EventSet::EDGE_TRIGGERED);
+                self.registrations.insert(
+                    fd,
+                    Registration {
+                        generation: self.next_generation,
+                        event: event.clone(),
+                        multishot,
+                    },
+                );
+                self.arm(fd)
+            }
+            ControlOperation::Delete => {
+                if !self.registrations.contains_key(&fd) {
+                    return Err(io::Error::from_raw_os_error(libc::ENOENT));
+                }
+                self.disarm(fd)
+            }
+        }
+    }
+
+    /// Submits a read of `len` bytes at `offset` of `fd` into `buf`. The
+    /// result is returned by `take_completions` with `tag`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid until the completion is returned.
+    pub unsafe fn submit_read(
+        &mut self,
+        fd: RawFd,
+        buf: *mut u8,
+        len: u32,
+        offset: u64,
+        tag: u32,
+    ) -> io::Result<()> {
+        self.push(Sqe {
+            opcode: IORING_OP_READ,
+            fd,
+            off: offset,
+            addr: buf as u64,
+            len,
+            user_data: (KIND_IO << KIND_SHIFT) | u64::from(tag),
+            ..Default::default()
+        })
+    }
+
+    /// Returns the reads completed since the last call.
+    pub fn take_completions(&mut self) -> Vec<Completion> {
+        std::mem::take(&mut self.completions)
+    }
+
+    fn arm_timeout(&mut self, milliseconds: i32) -> io::Result<()> {
+        self.timeout_id = self.timeout_id.wrapping_add(1) & 0xffff_ffff;
+        self.timeout = KernelTimespec {
+            tv_sec: i64::from(milliseconds / 1000),
+            tv_nsec: i64::from(milliseconds % 1000) * 1_000_000,
+        };
+        let addr = &self.timeout as *const KernelTimespec as u64;
+        self.push(Sqe {
+            opcode: IORING_OP_TIMEOUT,
+            addr,
+            len: 1,
+            user_data: (KIND_TIMEOUT << KIND_SHIFT) | self.timeout_id,
+            ..Default::default()
+        })?;
+        self.timeout_armed = true;
+        Ok(())
+    }
+
+    fn disarm_timeout(&mut self) -> io::Result<()> {
+        self.timeout_armed = false;
+        self.push(Sqe {
+            opcode: IORING_OP_TIMEOUT_REMOVE,
+            addr: (KIND_TIMEOUT << KIND_SHIFT) | self.timeout_id,
+            user_data: KIND_CANCEL << KIND_SHIFT,
+            ..Default::default()
+        })
+    }
+
+    /// Consumes up to `events.len()` readiness events from the completion
+    /// queue, returning how many were stored in `events`.
+    fn reap(&mut self, events: &mut [EpollEvent]) -> usize {
+        let mut count = 0;
+        let mask = self
+            .cq_atomic(self.cq_off.ring_mask)
+            .load(Ordering::Relaxed);
+        let mut head = self.cq_atomic(self.cq_off.head).load(Ordering::Relaxed);
+        let tail = self.cq_atomic(self.cq_off.tail).load(Ordering::Acquire);
+
+        while head != tail && count < events.len() {
+            // SAFETY: Entries between the head and the tail have been
+            // written by the kernel and are within the completion queue.
+            let cqe: Cqe = unsafe {
+                self.cq_ring
+                    .at::<Cqe>(self.cq_off.cqes)
+                    .add((head & mask) as usize)
+                    .read()
+            };
+            head = head.wrapping_add(1);
+
+            match cqe.user_data >> KIND_SHIFT {
+                KIND_POLL => {
+                    let fd = cqe.user_data as u32 as RawFd;
+                    let generation = ((cqe.user_data >> 32) & 0xff_ffff) as u32;
+                    let Some(reg) = self.registrations.get_mut(&fd) else {
+                        continue;
+                    };
+                    if reg.generation & 0xff_ffff != generation {
+                        continue;
+                    }
+                    if cqe.flags & IORING_CQE_F_MORE == 0 {
+                        self.rearm.push((fd, reg.generation));
+                    }
+                    let events_set = match cqe.res {
+                        res if res >= 0 => EventSet::from_bits_truncate(res as u32),
+                        res if -res == libc::EINVAL && reg.multishot => {
+                            // Multishot polls aren't supported by this kernel.
+                            reg.multishot = false;
+                            continue;
+                        }
+                        res if -res == libc::ECANCELED => continue,
+                        _ => EventSet::ERROR,
+                    };
+                    events[count] = EpollEvent::new(events_set, reg.event.data());
+                    count += 1;
+                }
+                KIND_TIMEOUT if cqe.user_data & 0xffff_ffff == self.timeout_id => {
+                    self.timeout_armed = false;
+                }
+                KIND_IO => self.completions.push(Completion {
+                    tag: cqe.user_data & 0xffff_ffff,
+                    result: cqe.res,
+                }),
+                _ => {}
+            }
+        }
+
+        self.cq_atomic(self.cq_off.head)
+            .store(head, Ordering::Release);
+        count
+    }
+
+    fn has_completions(&self) -> bool {
+        self.cq_atomic(self.cq_off.head).load(Ordering::Relaxed)
+            != self.cq_atomic(self.cq_off.tail).load(Ordering::Acquire)
+    }
+
+    /// Mirrors `Epoll::wait`. Reads completing while waiting
+    /// also wake up the caller, with no readiness events.
+    pub fn wait(
+        &mut self,
+        max_events: usize,
+        timeout: i32,
+        events: &mut [EpollEvent],
+    ) -> io::Result<usize> {
+        // The events returned by the previous call have been dispatched, so
+        // the one-shot polls can be re-armed now.
+        for (fd, generation) in std::mem::take(&mut self.rearm) {
+            if self
+                .registrations
+                .get(&fd)
+                .is_some_and(|reg| reg.generation == generation)
+            {
+                self.arm(fd)?;
+            }
+        }
+
+        let max_events = max_events.min(events.len());
+        if !self.has_completions() && timeout != 0 {
+            if timeout > 0 {
+                self.arm_timeout(timeout)?;
+            }
+            self.enter(1)?;
+        } else if self.to_submit > 0 {
+            self.enter(0)?;
+        }
+
+        let count = self.reap(&mut events[..max_events]);
+        if self.timeout_armed {
+            self.disarm_timeout()?;
+            self.enter(0)?;
+        }
+        Ok(count)
+    }
+}
+
+impl AsRawFd for IoUring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for IoUring {
+    fn drop(&mut self) {
+        // SAFETY: We own the file descriptor. The mappings are released
+        // right after this.
+        unsafe { libc::close(self.fd) };
+    }
+}
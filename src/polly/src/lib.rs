@@ -2,3 +2,5 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod event_manager;
+#[cfg(target_os = "linux")]
+mod io_uring;
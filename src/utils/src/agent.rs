@@ -0,0 +1,688 @@
+//! Host side of the protocol spoken with the agent built into the guest init.
+//!
+//! The agent listens on vsock port `AGENT_PORT`, which the VMM proxies to a
+//! UNIX socket in the host. Every connection carries a single request and
+//! its response, both made of a fixed header followed by a payload:
+//!
+//! ```text
+//! request:  u8 op, u8[3] reserved, le32 payload_len, payload
+//! response: le32 result, le32 payload_len, payload
+//! ```
+//!
+//! `result` is the exit status of the command for `Op::Exec` and zero or a
+//! negative Linux errno for everything else.
+//!
+//! `Op::Spawn` is the only exception to the single request and response
+//! rule: after the response, the connection keeps carrying the standard
+//! input of the process from the host, and its output from the guest, as
+//! a sequence of frames:
+//!
+//! ```text
+//! frame: u8 kind, u8[3] reserved, le32 data_len, data
+//! ```
+//!
+//! The same framing is used in the opposite direction on `EXEC_HOOK_PORT`,
+//! where init asks the host to amend the arguments and environment of the
+//! workload right before running it.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// vsock port the guest agent listens on.
+pub const AGENT_PORT: u32 = 1025;
+
+/// vsock port init connects to when the host has registered an exec hook.
+pub const EXEC_HOOK_PORT: u32 = 1026;
+
+/// Largest payload accepted in either direction.
+pub const MAX_PAYLOAD: usize = 1 << 20;
+
+/// Amount of file data transferred per request by the file copy
+/// operations, leaving room in the payload for the path of the file.
+const FILE_CHUNK: usize = MAX_PAYLOAD / 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Op {
+    Ping = 1,
+    /// Runs a command, the payload being its NUL-separated arguments. The
+    /// response carries its combined stdout and stderr.
+    Exec = 2,
+    /// Returns a JSON document with guest statistics.
+    Stats = 3,
+    /// Sets the guest clock, the payload being le64 nanoseconds since the
+    /// UNIX epoch.
+    SetTime = 4,
+    /// Asks the workload to terminate.
+    Shutdown = 5,
+    /// Writes to a file, the payload being le64 offset, le32 mode, the
+    /// NUL-terminated path and the data. The file is created with `mode`,
+    /// or truncated, when the offset is zero.
+    WriteFile = 6,
+    /// Reads from a file, the payload being le64 offset, le32 length and
+    /// the NUL-terminated path. The response carries the data, being
+    /// shorter than requested at the end of the file.
+    ReadFile = 7,
+    /// Sent by init, the payload being an `ExecSpec` with the arguments and
+    /// environment of the workload. The response carries the `ExecSpec` to
+    /// run it with.
+    ExecHook = 8,
+    /// Starts a process without waiting for it, the payload being an
+    /// `ExecSpec`, whose environment replaces the guest init one unless
+    /// it's empty. `result` is the guest PID of the process.
+    Spawn = 9,
+    /// Sets the guest clipboard, the payload being the NUL-terminated MIME
+    /// type and the data. The agent runs `wl-copy`, or `xclip` if it's not
+    /// available, so the guest needs one of them.
+    SetClipboard = 10,
+    /// Reads the guest clipboard, the payload being the NUL-terminated MIME
+    /// type. The response carries the data, or `-ENODATA` if there's
+    /// nothing in the clipboard of that type.
+    GetClipboard = 11,
+    /// Suspends the guest to RAM. The response is sent before suspending,
+    /// or carries `-EOPNOTSUPP` if the guest kernel can't do it.
+    Suspend = 12,
+    /// Sends a signal to the workload, the payload being its le32 number.
+    Signal = 13,
+}
+
+/// Kinds of the frames sent by the guest after an `Op::Spawn` request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    Stdout = 1,
+    Stderr = 2,
+    /// The last frame, with the le32 exit status of the process, or 128
+    /// plus the signal number if it was killed by a signal.
+    Exit = 3,
+}
+
+/// Copies the output of a process started with `Op::Spawn` to `stdout` and
+/// `stderr` until it exits, returning its exit status. Errors writing the
+/// output are ignored, so the process can keep running if the output is
+/// no longer wanted.
+pub fn relay_output(
+    r: &mut impl Read,
+    stdout: &mut impl Write,
+    stderr: &mut impl Write,
+) -> io::Result<i32> {
+    let mut data = Vec::new();
+    loop {
+        let mut hdr = [0u8; 8];
+        r.read_exact(&mut hdr)?;
+        let len = u32::from_le_bytes(hdr[4..8].try_into().unwrap()) as usize;
+        if len > MAX_PAYLOAD {
+            return Err(io::Error::from_raw_os_error(libc::EPROTO));
+        }
+        data.resize(len, 0);
+        r.read_exact(&mut data)?;
+
+        match hdr[0] {
+            kind if kind == FrameKind::Stdout as u8 => {
+                let _ = stdout.write_all(&data);
+            }
+            kind if kind == FrameKind::Stderr as u8 => {
+                let _ = stderr.write_all(&data);
+            }
+            kind if kind == FrameKind::Exit as u8 => {
+                let status = data
+                    .get(..4)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EPROTO))?;
+                return Ok(i32::from_le_bytes(status.try_into().unwrap()));
+            }
+            _ => return Err(io::Error::from_raw_os_error(libc::EPROTO)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+    pub result: i32,
+    pub payload: Vec<u8>,
+}
+
+pub fn write_request(w: &mut impl Write, op: Op, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(io::Error::from_raw_os_error(libc::E2BIG));
+    }
+    let mut hdr = [0u8; 8];
+    hdr[0] = op as u8;
+    hdr[4..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    w.write_all(&hdr)?;
+    w.write_all(payload)
+}
+
+pub fn read_request(r: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut hdr = [0u8; 8];
+    r.read_exact(&mut hdr)?;
+    let len = u32::from_le_bytes(hdr[4..8].try_into().unwrap()) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(io::Error::from_raw_os_error(libc::E2BIG));
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok((hdr[0], payload))
+}
+
+pub fn write_response(w: &mut impl Write, result: i32, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(io::Error::from_raw_os_error(libc::E2BIG));
+    }
+    let mut hdr = [0u8; 8];
+    hdr[0..4].copy_from_slice(&result.to_le_bytes());
+    hdr[4..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    w.write_all(&hdr)?;
+    w.write_all(payload)
+}
+
+pub fn read_response(r: &mut impl Read) -> io::Result<Response> {
+    let mut hdr = [0u8; 8];
+    r.read_exact(&mut hdr)?;
+    let result = i32::from_le_bytes(hdr[0..4].try_into().unwrap());
+    let len = u32::from_le_bytes(hdr[4..8].try_into().unwrap()) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(io::Error::from_raw_os_error(libc::EPROTO));
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok(Response { result, payload })
+}
+
+/// The arguments and environment of a command, encoded as its
+/// NUL-terminated arguments, an empty string, and its NUL-terminated
+/// `KEY=VALUE` environment variables.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ExecSpec {
+    pub argv: Vec<Vec<u8>>,
+    pub env: Vec<Vec<u8>>,
+}
+
+impl ExecSpec {
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let mut spec = ExecSpec::default();
+        let mut in_env = false;
+        for item in payload.strip_suffix(&[0])?.split(|b| *b == 0) {
+            if item.is_empty() && !in_env {
+                in_env = true;
+            } else if in_env {
+                spec.env.push(item.to_vec());
+            } else {
+                spec.argv.push(item.to_vec());
+            }
+        }
+        (in_env && !spec.argv.is_empty()).then_some(spec)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for item in self.argv.iter().chain([&Vec::new()]).chain(&self.env) {
+            payload.extend_from_slice(item);
+            payload.push(0);
+        }
+        payload
+    }
+
+    /// Applies the changes returned by an exec hook: a list of NUL-terminated
+    /// `KEY=VALUE` strings setting environment variables, optionally followed
+    /// by a `--` string and the arguments replacing the current ones, the
+    /// program to run excluded. Returns `None` if `changes` is malformed.
+    pub fn amend(&mut self, changes: &[u8]) -> Option<()> {
+        if changes.is_empty() {
+            return Some(());
+        }
+        let mut args: Option<Vec<Vec<u8>>> = None;
+        for item in changes.strip_suffix(&[0])?.split(|b| *b == 0) {
+            if let Some(args) = args.as_mut() {
+                args.push(item.to_vec());
+                continue;
+            }
+            if item == b"--" {
+                args = Some(Vec::new());
+                continue;
+            }
+
+            let key_len = item
+                .iter()
+                .position(|b| *b == b'=')
+                .filter(|len| *len > 0)?;
+            let key = &item[..=key_len];
+            match self.env.iter_mut().find(|var| var.starts_with(key)) {
+                Some(var) => *var = item.to_vec(),
+                None => self.env.push(item.to_vec()),
+            }
+        }
+        if let Some(args) = args {
+            self.argv.truncate(1);
+            self.argv.extend(args);
+        }
+        Some(())
+    }
+}
+
+/// A client for the guest agent reachable through the UNIX socket at `path`.
+pub struct AgentClient<'a> {
+    path: &'a Path,
+    timeout: Option<Duration>,
+}
+
+impl<'a> AgentClient<'a> {
+    pub fn new(path: &'a Path) -> Self {
+        AgentClient {
+            path,
+            timeout: None,
+        }
+    }
+
+    /// Sets how long to wait for the guest to respond. There's no timeout
+    /// by default, since commands run through `exec` may take arbitrarily
+    /// long to complete.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn request(&self, op: Op, payload: &[u8]) -> io::Result<Response> {
+        let mut stream = UnixStream::connect(self.path)?;
+        stream.set_read_timeout(self.timeout)?;
+        write_request(&mut stream, op, payload)?;
+        read_response(&mut stream)
+    }
+
+    fn request_status(&self, op: Op, payload: &[u8]) -> io::Result<()> {
+        match self.request(op, payload)?.result {
+            0 => Ok(()),
+            err => Err(io::Error::from_raw_os_error(-err)),
+        }
+    }
+
+    pub fn ping(&self) -> io::Result<()> {
+        self.request_status(Op::Ping, &[])
+    }
+
+    /// Runs `argv` in the guest, returning its exit status and output.
+    pub fn exec<S: AsRef<str>>(&self, argv: &[S]) -> io::Result<(i32, Vec<u8>)> {
+        if argv.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let mut payload = Vec::new();
+        for arg in argv {
+            let arg = arg.as_ref();
+            if arg.contains('\0') {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            payload.extend_from_slice(arg.as_bytes());
+            payload.push(0);
+        }
+        let response = self.request(Op::Exec, &payload)?;
+        Ok((response.result, response.payload))
+    }
+
+    pub fn stats(&self) -> io::Result<String> {
+        let response = self.request(Op::Stats, &[])?;
+        if response.result != 0 {
+            return Err(io::Error::from_raw_os_error(-response.result));
+        }
+        String::from_utf8(response.payload).map_err(|_| io::Error::from_raw_os_error(libc::EPROTO))
+    }
+
+    /// Starts `spec` in the guest, returning its guest PID and the
+    /// connection to use with `relay_output` and to write its standard
+    /// input to.
+    pub fn spawn(&self, spec: &ExecSpec) -> io::Result<(i32, UnixStream)> {
+        let mut stream = UnixStream::connect(self.path)?;
+        stream.set_read_timeout(self.timeout)?;
+        write_request(&mut stream, Op::Spawn, &spec.encode())?;
+        let response = read_response(&mut stream)?;
+        if response.result < 0 {
+            return Err(io::Error::from_raw_os_error(-response.result));
+        }
+        // The process may run for arbitrarily long.
+        stream.set_read_timeout(None)?;
+        Ok((response.result, stream))
+    }
+
+    /// Sets the guest clock to the current host time.
+    pub fn sync_time(&self) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        self.request_status(Op::SetTime, &(now.as_nanos() as u64).to_le_bytes())
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.request_status(Op::Shutdown, &[])
+    }
+
+    pub fn suspend(&self) -> io::Result<()> {
+        self.request_status(Op::Suspend, &[])
+    }
+
+    pub fn signal(&self, signum: i32) -> io::Result<()> {
+        self.request_status(Op::Signal, &signum.to_le_bytes())
+    }
+
+    pub fn set_clipboard(&self, mime_type: &str, data: &[u8]) -> io::Result<()> {
+        let mut payload = clipboard_header(mime_type)?;
+        payload.extend_from_slice(data);
+        self.request_status(Op::SetClipboard, &payload)
+    }
+
+    pub fn get_clipboard(&self, mime_type: &str) -> io::Result<Vec<u8>> {
+        let response = self.request(Op::GetClipboard, &clipboard_header(mime_type)?)?;
+        if response.result != 0 {
+            return Err(io::Error::from_raw_os_error(-response.result));
+        }
+        Ok(response.payload)
+    }
+
+    /// Copies the host file at `host_path` to `guest_path`, preserving its
+    /// permissions.
+    pub fn copy_to_guest(&self, host_path: &Path, guest_path: &str) -> io::Result<()> {
+        let mut file = File::open(host_path)?;
+        let mode = file.metadata()?.permissions().mode() & 0o7777;
+
+        let mut buf = vec![0u8; FILE_CHUNK];
+        let mut offset = 0u64;
+        loop {
+            let len = read_chunk(&mut file, &mut buf)?;
+            // Always send the first chunk, so empty files get created too.
+            if len == 0 && offset != 0 {
+                return Ok(());
+            }
+            let mut payload = file_header(offset, mode, guest_path)?;
+            payload.extend_from_slice(&buf[..len]);
+            self.request_status(Op::WriteFile, &payload)?;
+            if len < FILE_CHUNK {
+                return Ok(());
+            }
+            offset += len as u64;
+        }
+    }
+
+    /// Copies the guest file at `guest_path` to `host_path`, which is
+    /// created if it doesn't exist.
+    pub fn copy_from_guest(&self, guest_path: &str, host_path: &Path) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o644)
+            .open(host_path)?;
+
+        let mut offset = 0u64;
+        loop {
+            let payload = file_header(offset, FILE_CHUNK as u32, guest_path)?;
+            let response = self.request(Op::ReadFile, &payload)?;
+            if response.result != 0 {
+                return Err(io::Error::from_raw_os_error(-response.result));
+            }
+            if response.payload.len() > FILE_CHUNK {
+                return Err(io::Error::from_raw_os_error(libc::EPROTO));
+            }
+            file.write_all(&response.payload)?;
+            if response.payload.len() < FILE_CHUNK {
+                return Ok(());
+            }
+            offset += FILE_CHUNK as u64;
+        }
+    }
+}
+
+fn clipboard_header(mime_type: &str) -> io::Result<Vec<u8>> {
+    if mime_type.is_empty() || mime_type.contains('\0') {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    let mut payload = mime_type.as_bytes().to_vec();
+    payload.push(0);
+    Ok(payload)
+}
+
+/// Builds the common part of the `WriteFile` and `ReadFile` payloads, `arg`
+/// being the mode or the length, respectively.
+fn file_header(offset: u64, arg: u32, path: &str) -> io::Result<Vec<u8>> {
+    if path.is_empty() || path.contains('\0') {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    if path.len() >= libc::PATH_MAX as usize {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    let mut payload = Vec::with_capacity(13 + path.len() + FILE_CHUNK);
+    payload.extend_from_slice(&offset.to_le_bytes());
+    payload.extend_from_slice(&arg.to_le_bytes());
+    payload.extend_from_slice(path.as_bytes());
+    payload.push(0);
+    Ok(payload)
+}
+
+/// Fills `buf` as much as possible, returning less than its length only at
+/// the end of the file.
+fn read_chunk(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn test_request_encoding() {
+        let mut buf = Vec::new();
+        write_request(&mut buf, Op::Exec, b"ls\0").unwrap();
+        assert_eq!(buf, [2, 0, 0, 0, 3, 0, 0, 0, b'l', b's', 0]);
+
+        let big = vec![0u8; MAX_PAYLOAD + 1];
+        assert!(write_request(&mut Vec::new(), Op::Exec, &big).is_err());
+    }
+
+    #[test]
+    fn test_response_decoding() {
+        let mut buf = Vec::new();
+        write_response(&mut buf, -2, b"x").unwrap();
+        assert_eq!(
+            read_response(&mut buf.as_slice()).unwrap(),
+            Response {
+                result: -2,
+                payload: b"x".to_vec(),
+            }
+        );
+
+        // Truncated payload.
+        assert!(read_response(&mut &buf[..8]).is_err());
+    }
+
+    #[test]
+    fn test_exec_spec() {
+        let payload = b"/bin/app\0-v\0\0HOME=/\0PORT=80\0";
+        let mut spec = ExecSpec::decode(payload).unwrap();
+        assert_eq!(spec.argv, [b"/bin/app".to_vec(), b"-v".to_vec()]);
+        assert_eq!(spec.env, [b"HOME=/".to_vec(), b"PORT=80".to_vec()]);
+        assert_eq!(spec.encode(), payload);
+
+        spec.amend(b"PORT=8080\0DEBUG=\0--\0serve\0").unwrap();
+        assert_eq!(
+            spec.encode(),
+            b"/bin/app\0serve\0\0HOME=/\0PORT=8080\0DEBUG=\0"
+        );
+        spec.amend(b"").unwrap();
+        spec.amend(b"--\0").unwrap();
+        assert_eq!(spec.argv, [b"/bin/app".to_vec()]);
+
+        assert!(spec.amend(b"NOVALUE\0").is_none());
+        assert!(spec.amend(b"=x\0").is_none());
+        assert!(spec.amend(b"A=1").is_none());
+        assert!(ExecSpec::decode(b"/bin/app\0").is_none());
+        assert!(ExecSpec::decode(b"\0HOME=/\0").is_none());
+    }
+
+    fn write_frame(w: &mut impl Write, kind: FrameKind, data: &[u8]) {
+        w.write_all(&[kind as u8, 0, 0, 0]).unwrap();
+        w.write_all(&(data.len() as u32).to_le_bytes()).unwrap();
+        w.write_all(data).unwrap();
+    }
+
+    #[test]
+    fn test_relay_output() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, FrameKind::Stdout, b"out");
+        write_frame(&mut buf, FrameKind::Stderr, b"err");
+        write_frame(&mut buf, FrameKind::Stdout, b"put");
+        write_frame(&mut buf, FrameKind::Exit, &137i32.to_le_bytes());
+
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        assert_eq!(
+            relay_output(&mut buf.as_slice(), &mut stdout, &mut stderr).unwrap(),
+            137
+        );
+        assert_eq!(stdout, b"output");
+        assert_eq!(stderr, b"err");
+
+        // The process hasn't exited yet.
+        let len = buf.len() - 12;
+        assert!(relay_output(&mut &buf[..len], &mut stdout, &mut stderr).is_err());
+    }
+
+    #[test]
+    fn test_client() {
+        let dir = TempDir::new_with_prefix("/tmp/krun-agent-test").unwrap();
+        let path = dir.as_path().join("agent.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut hdr = [0u8; 8];
+                stream.read_exact(&mut hdr).unwrap();
+                let mut payload = vec![0u8; hdr[4] as usize];
+                stream.read_exact(&mut payload).unwrap();
+                match hdr[0] {
+                    2 => {
+                        assert_eq!(payload, b"echo\0hi\0");
+                        write_response(&mut stream, 3, b"hi\n").unwrap();
+                    }
+                    _ => write_response(&mut stream, -libc::ENOSYS, &[]).unwrap(),
+                }
+            }
+        });
+
+        let client = AgentClient::new(&path).with_timeout(Duration::from_secs(5));
+        assert_eq!(client.exec(&["echo", "hi"]).unwrap(), (3, b"hi\n".to_vec()));
+        assert_eq!(
+            client.shutdown().unwrap_err().raw_os_error(),
+            Some(libc::ENOSYS)
+        );
+        assert!(client.exec::<&str>(&[]).is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_clipboard() {
+        let dir = TempDir::new_with_prefix("/tmp/krun-agent-test").unwrap();
+        let path = dir.as_path().join("agent.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let mut clipboard = Vec::new();
+            for _ in 0..3 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let (op, payload) = read_request(&mut stream).unwrap();
+                match op {
+                    10 => {
+                        clipboard = payload;
+                        write_response(&mut stream, 0, &[]).unwrap();
+                    }
+                    11 if clipboard.starts_with(&payload) => {
+                        write_response(&mut stream, 0, &clipboard[payload.len()..]).unwrap();
+                    }
+                    11 => write_response(&mut stream, -libc::ENODATA, &[]).unwrap(),
+                    _ => unreachable!(),
+                }
+            }
+        });
+
+        let client = AgentClient::new(&path).with_timeout(Duration::from_secs(5));
+        client.set_clipboard("text/plain", b"hello").unwrap();
+        assert_eq!(client.get_clipboard("text/plain").unwrap(), b"hello");
+        assert_eq!(
+            client
+                .get_clipboard("image/png")
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENODATA)
+        );
+        assert!(client.set_clipboard("", b"hello").is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_copy_files() {
+        let dir = TempDir::new_with_prefix("/tmp/krun-agent-test").unwrap();
+        let path = dir.as_path().join("agent.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Doesn't fit in a single request.
+        let data: Vec<u8> = (0..FILE_CHUNK * 2 + 10).map(|i| i as u8).collect();
+        let src = dir.as_path().join("src");
+        std::fs::write(&src, &data).unwrap();
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o750)).unwrap();
+
+        // Serves a single guest file from memory.
+        let server = thread::spawn(move || {
+            let mut guest_file = Vec::new();
+            for _ in 0..6 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut hdr = [0u8; 8];
+                stream.read_exact(&mut hdr).unwrap();
+                let len = u32::from_le_bytes(hdr[4..8].try_into().unwrap());
+                let mut payload = vec![0u8; len as usize];
+                stream.read_exact(&mut payload).unwrap();
+
+                let offset = u64::from_le_bytes(payload[0..8].try_into().unwrap()) as usize;
+                let arg = u32::from_le_bytes(payload[8..12].try_into().unwrap()) as usize;
+                assert_eq!(&payload[12..22], b"/tmp/file\0");
+                match hdr[0] {
+                    6 => {
+                        assert_eq!(arg, 0o750);
+                        assert_eq!(offset, guest_file.len());
+                        guest_file.extend_from_slice(&payload[22..]);
+                        write_response(&mut stream, 0, &[]).unwrap();
+                    }
+                    7 => {
+                        let end = guest_file.len().min(offset + arg);
+                        write_response(&mut stream, 0, &guest_file[offset..end]).unwrap();
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        });
+
+        let client = AgentClient::new(&path).with_timeout(Duration::from_secs(5));
+        client.copy_to_guest(&src, "/tmp/file").unwrap();
+        let dst = dir.as_path().join("dst");
+        client.copy_from_guest("/tmp/file", &dst).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        server.join().unwrap();
+
+        assert_eq!(
+            client.copy_to_guest(&src, "").unwrap_err().raw_os_error(),
+            Some(libc::EINVAL)
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! Record of the moments at which a microVM reached each of its boot
+//! milestones, meant to help users optimizing cold-start times to tell which
+//! phase regressed.
+//!
+//! Every microVM has its own timeline. Phases are reported as offsets from
+//! the earliest one recorded, normally the creation of its context.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Boot milestones, in the order they are expected to be reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootPhase {
+    /// The configuration context was created.
+    ContextCreated = 0,
+    /// `krun_start_enter` started building the microVM.
+    VmmStarted,
+    /// The firmware and/or kernel were loaded into guest memory.
+    PayloadLoaded,
+    /// A vCPU entered the guest for the first time.
+    FirstVcpuRun,
+    /// The guest kernel handed over control to init.
+    InitStarted,
+    /// init is about to execute the workload.
+    WorkloadExec,
+}
+
+const PHASES: [BootPhase; 6] = [
+    BootPhase::ContextCreated,
+    BootPhase::VmmStarted,
+    BootPhase::PayloadLoaded,
+    BootPhase::FirstVcpuRun,
+    BootPhase::InitStarted,
+    BootPhase::WorkloadExec,
+];
+
+impl BootPhase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BootPhase::ContextCreated => "context_created",
+            BootPhase::VmmStarted => "vmm_started",
+            BootPhase::PayloadLoaded => "payload_loaded",
+            BootPhase::FirstVcpuRun => "first_vcpu_run",
+            BootPhase::InitStarted => "init_started",
+            BootPhase::WorkloadExec => "workload_exec",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BootTimeline {
+    phases: Mutex<[Option<Instant>; PHASES.len()]>,
+    print: AtomicBool,
+}
+
+impl Default for BootTimeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BootTimeline {
+    pub const fn new() -> Self {
+        BootTimeline {
+            phases: Mutex::new([None; PHASES.len()]),
+            print: AtomicBool::new(false),
+        }
+    }
+
+    /// Requests the timeline to be printed to stderr once the workload is
+    /// about to be executed.
+    pub fn set_print(&self, print: bool) {
+        self.print.store(print, Ordering::Relaxed);
+    }
+
+    /// Records that `phase` has been reached. Only the first time a phase is
+    /// reached is taken into account.
+    pub fn record(&self, phase: BootPhase) {
+        {
+            let mut phases = self.phases.lock().unwrap();
+            if phases[phase as usize].is_some() {
+                return;
+            }
+            phases[phase as usize] = Some(Instant::now());
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(phase = phase.name(), "boot_phase");
+
+        if phase == BootPhase::WorkloadExec && self.print.load(Ordering::Relaxed) {
+            eprint!("{}", self.to_report());
+        }
+    }
+
+    /// Returns, for each phase, the number of microseconds elapsed since the
+    /// first recorded phase, or `None` if the phase wasn't reached.
+    pub fn offsets(&self) -> Vec<(BootPhase, Option<u64>)> {
+        let phases = self.phases.lock().unwrap();
+        let origin = phases.iter().flatten().min().copied();
+        PHASES
+            .iter()
+            .map(|&phase| {
+                let offset = phases[phase as usize]
+                    .zip(origin)
+                    .map(|(t, origin)| t.duration_since(origin).as_micros() as u64);
+                (phase, offset)
+            })
+            .collect()
+    }
+
+    /// Serializes the timeline as a JSON object mapping each phase to its
+    /// offset in microseconds, or `null` if it wasn't reached.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{");
+        for (i, (phase, offset)) in self.offsets().into_iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            match offset {
+                Some(offset) => write!(out, "\"{}\":{}", phase.name(), offset),
+                None => write!(out, "\"{}\":null", phase.name()),
+            }
+            .unwrap();
+        }
+        out.push('}');
+        out
+    }
+
+    /// Formats the timeline as a human readable report.
+    pub fn to_report(&self) -> String {
+        let mut out = String::from("libkrun boot timeline (ms):\n");
+        let mut last = None;
+        for (phase, offset) in self.offsets() {
+            let Some(offset) = offset else {
+                writeln!(out, "  {:<16} -", phase.name()).unwrap();
+                continue;
+            };
+            let delta = offset - last.unwrap_or(offset);
+            writeln!(
+                out,
+                "  {:<16} {:>10.3} (+{:.3})",
+                phase.name(),
+                offset as f64 / 1000.0,
+                delta as f64 / 1000.0
+            )
+            .unwrap();
+            last = Some(offset);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let timeline = BootTimeline::new();
+        assert_eq!(
+            timeline.to_json(),
+            "{\"context_created\":null,\"vmm_started\":null,\"payload_loaded\":null,\
+             \"first_vcpu_run\":null,\"init_started\":null,\"workload_exec\":null}"
+        );
+
+        timeline.record(BootPhase::ContextCreated);
+        timeline.record(BootPhase::FirstVcpuRun);
+        let first = timeline.offsets()[BootPhase::FirstVcpuRun as usize].1;
+        timeline.record(BootPhase::FirstVcpuRun);
+
+        let offsets = timeline.offsets();
+        assert_eq!(offsets[BootPhase::ContextCreated as usize].1, Some(0));
+        assert_eq!(offsets[BootPhase::VmmStarted as usize].1, None);
+        assert_eq!(offsets[BootPhase::FirstVcpuRun as usize].1, first);
+
+        let json = timeline.to_json();
+        assert!(json.starts_with("{\"context_created\":0,\"vmm_started\":null,"));
+
+        let report = timeline.to_report();
+        assert!(report.contains("  context_created       0.000 (+0.000)\n"));
+        assert!(report.contains("  vmm_started      -\n"));
+    }
+}
@@ -0,0 +1,264 @@
+//! A `tracing` subscriber writing spans and events in the Chrome Trace Event
+//! format, which can be loaded in Perfetto (ui.perfetto.dev) or
+//! chrome://tracing.
+//!
+//! Events are written using the JSON Array Format, one event per line. The
+//! closing bracket is optional in that format, so the trace stays loadable
+//! even if the process exits without flushing anything, which is the common
+//! case for the VMM.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+use crate::json::escape;
+
+static NEXT_TID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static TID: Cell<u64> = const { Cell::new(0) };
+}
+
+struct SpanData {
+    name: &'static str,
+    target: &'static str,
+    args: String,
+    refs: usize,
+}
+
+pub struct ChromeTraceSubscriber<W: Write + Send + 'static> {
+    start: Instant,
+    pid: u32,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    out: Mutex<W>,
+}
+
+impl ChromeTraceSubscriber<File> {
+    /// Creates a subscriber writing the trace to a newly created file at `path`.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Self::new(File::create(path)?)
+    }
+}
+
+impl<W: Write + Send + 'static> ChromeTraceSubscriber<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(b"[\n")?;
+        Ok(ChromeTraceSubscriber {
+            start: Instant::now(),
+            pid: std::process::id(),
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+            out: Mutex::new(out),
+        })
+    }
+
+    fn tid(&self) -> u64 {
+        TID.with(|tid| {
+            if tid.get() == 0 {
+                tid.set(NEXT_TID.fetch_add(1, Ordering::Relaxed));
+                let thread = thread::current();
+                let name = thread.name().unwrap_or("unnamed");
+                self.write(&format!(
+                    "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+                    self.pid,
+                    tid.get(),
+                    escape(name)
+                ));
+            }
+            tid.get()
+        })
+    }
+
+    fn write_event(&self, name: &str, target: &str, phase: char, args: &str) {
+        let tid = self.tid();
+        let ts = self.start.elapsed().as_nanos() as f64 / 1000.0;
+        let mut line = format!(
+            "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"{}\",\"ts\":{:.3},\"pid\":{},\"tid\":{}",
+            escape(name),
+            escape(target),
+            phase,
+            ts,
+            self.pid,
+            tid
+        );
+        if phase == 'i' {
+            line.push_str(",\"s\":\"t\"");
+        }
+        if !args.is_empty() {
+            let _ = write!(line, ",\"args\":{{{args}}}");
+        }
+        line.push('}');
+        self.write(&line);
+    }
+
+    fn write(&self, line: &str) {
+        let mut out = self.out.lock().unwrap();
+        // Errors are ignored, there is nobody we could report them to.
+        let _ = writeln!(out, "{line},");
+    }
+}
+
+impl<W: Write + Send + 'static> Subscriber for ChromeTraceSubscriber<W> {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut visitor = ArgsVisitor::default();
+        attrs.record(&mut visitor);
+        self.spans.lock().unwrap().insert(
+            id,
+            SpanData {
+                name: attrs.metadata().name(),
+                target: attrs.metadata().target(),
+                args: visitor.0,
+                refs: 1,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            let mut visitor = ArgsVisitor(std::mem::take(&mut data.args));
+            values.record(&mut visitor);
+            data.args = visitor.0;
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut visitor = ArgsVisitor::default();
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+        self.write_event(metadata.name(), metadata.target(), 'i', &visitor.0);
+    }
+
+    fn enter(&self, span: &Id) {
+        let span = self
+            .spans
+            .lock()
+            .unwrap()
+            .get(&span.into_u64())
+            .map(|data| (data.name, data.target, data.args.clone()));
+        if let Some((name, target, args)) = span {
+            self.write_event(name, target, 'B', &args);
+        }
+    }
+
+    fn exit(&self, span: &Id) {
+        let span = self
+            .spans
+            .lock()
+            .unwrap()
+            .get(&span.into_u64())
+            .map(|data| (data.name, data.target));
+        if let Some((name, target)) = span {
+            self.write_event(name, target, 'E', "");
+        }
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            data.refs += 1;
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        match spans.get_mut(&id.into_u64()) {
+            Some(data) if data.refs > 1 => {
+                data.refs -= 1;
+                false
+            }
+            Some(_) => {
+                spans.remove(&id.into_u64());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Collects the fields of a span or event as the body of a JSON object.
+#[derive(Default)]
+struct ArgsVisitor(String);
+
+impl ArgsVisitor {
+    fn push(&mut self, field: &Field, value: fmt::Arguments) {
+        if !self.0.is_empty() {
+            self.0.push(',');
+        }
+        let _ = write!(self.0, "\"{}\":{}", escape(field.name()), value);
+    }
+}
+
+impl Visit for ArgsVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, format_args!("{value}"));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, format_args!("{value}"));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, format_args!("{value}"));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, format_args!("\"{}\"", escape(value)));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, format_args!("\"{}\"", escape(&format!("{value:?}"))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spans_and_events() {
+        let subscriber = ChromeTraceSubscriber::new(Vec::new()).unwrap();
+        let dispatch = tracing::Dispatch::new(subscriber);
+
+        tracing::dispatcher::with_default(&dispatch, || {
+            let span = tracing::info_span!("boot", vcpus = 2);
+            let _guard = span.enter();
+            tracing::info!(phase = "kernel", "loaded");
+        });
+
+        let subscriber: &ChromeTraceSubscriber<Vec<u8>> = dispatch.downcast_ref().unwrap();
+        let out = String::from_utf8(subscriber.out.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines[0], "[");
+        assert!(lines[1].contains("\"ph\":\"M\""));
+        assert!(lines[2].contains("\"name\":\"boot\""));
+        assert!(lines[2].contains("\"ph\":\"B\""));
+        assert!(lines[2].contains("\"args\":{\"vcpus\":2}"));
+        assert!(lines[3].contains("\"ph\":\"i\""));
+        assert!(lines[3].contains("\"phase\":\"kernel\""));
+        assert!(lines[3].contains("\"message\":\"loaded\""));
+        assert!(lines[4].contains("\"name\":\"boot\""));
+        assert!(lines[4].contains("\"ph\":\"E\""));
+        assert!(lines.iter().skip(1).all(|l| l.ends_with("},")));
+    }
+}
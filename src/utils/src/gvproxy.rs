@@ -0,0 +1,280 @@
+//! Client for the HTTP API gvproxy serves on its control socket (the
+//! `-listen unix://<path>` option), to change the ports it forwards to the
+//! guest while the VM runs and read its statistics.
+//!
+//! Requests are made with HTTP/1.0, so gvproxy closes the connection after
+//! each response instead of chunking it, and the body is whatever follows
+//! the headers.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use log::warn;
+
+/// Largest response accepted.
+const MAX_RESPONSE: u64 = 1 << 20;
+
+/// Protocol of a forwarded port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn parse(protocol: &str) -> Option<Self> {
+        match protocol {
+            "tcp" => Some(Protocol::Tcp),
+            "udp" => Some(Protocol::Udp),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+fn json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn json_object(fields: &[(&str, &str)]) -> String {
+    let mut out = String::from("{");
+    for (i, (key, value)) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json_string(&mut out, key);
+        out.push(':');
+        json_string(&mut out, value);
+    }
+    out.push('}');
+    out
+}
+
+/// Splits an HTTP response into its status code and body.
+fn parse_response(response: &[u8]) -> io::Result<(u16, &[u8])> {
+    let invalid = || io::Error::from_raw_os_error(libc::EPROTO);
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = std::str::from_utf8(&response[..end]).map_err(|_| invalid())?;
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((status, &response[end + 4..]))
+}
+
+fn status_to_error(status: u16) -> io::Error {
+    let errno = match status {
+        400 => libc::EINVAL,
+        404 => libc::ENOENT,
+        405 | 501 => libc::ENOTSUP,
+        _ => libc::EIO,
+    };
+    io::Error::from_raw_os_error(errno)
+}
+
+/// A client for the gvproxy API reachable through the UNIX socket at `path`.
+pub struct GvproxyClient<'a> {
+    path: &'a Path,
+    timeout: Option<Duration>,
+}
+
+impl<'a> GvproxyClient<'a> {
+    pub fn new(path: &'a Path) -> Self {
+        GvproxyClient {
+            path,
+            timeout: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends a request to `uri`, returning the body of the response if it
+    /// succeeded.
+    fn request(&self, method: &str, uri: &str, body: Option<&str>) -> io::Result<Vec<u8>> {
+        let mut stream = UnixStream::connect(self.path)?;
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+
+        let mut request = format!("{method} {uri} HTTP/1.0\r\nHost: gvproxy\r\n");
+        if let Some(body) = body {
+            let _ = write!(
+                request,
+                "Content-Type: application/json\r\nContent-Length: {}\r\n",
+                body.len()
+            );
+        }
+        request.push_str("\r\n");
+        request.push_str(body.unwrap_or_default());
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = Vec::new();
+        stream.take(MAX_RESPONSE).read_to_end(&mut response)?;
+        let (status, body) = parse_response(&response)?;
+        if !(200..300).contains(&status) {
+            warn!(
+                "gvproxy: {method} {uri} failed with status {status}: {}",
+                String::from_utf8_lossy(body).trim()
+            );
+            return Err(status_to_error(status));
+        }
+        Ok(body.to_vec())
+    }
+
+    fn request_json(&self, uri: &str) -> io::Result<String> {
+        String::from_utf8(self.request("GET", uri, None)?)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EPROTO))
+    }
+
+    /// Forwards connections to `local`, an address of the host like
+    /// "127.0.0.1:8080" or ":8080", to `remote`, an address of the guest
+    /// network like "192.168.127.2:80".
+    pub fn expose(&self, protocol: Protocol, local: &str, remote: &str) -> io::Result<()> {
+        let body = json_object(&[
+            ("local", local),
+            ("remote", remote),
+            ("protocol", protocol.as_str()),
+        ]);
+        self.request("POST", "/services/forwarder/expose", Some(&body))
+            .map(|_| ())
+    }
+
+    /// Stops forwarding the connections to `local`.
+    pub fn unexpose(&self, protocol: Protocol, local: &str) -> io::Result<()> {
+        let body = json_object(&[("local", local), ("protocol", protocol.as_str())]);
+        self.request("POST", "/services/forwarder/unexpose", Some(&body))
+            .map(|_| ())
+    }
+
+    /// Returns the JSON array of the ports forwarded.
+    pub fn forwards(&self) -> io::Result<String> {
+        self.request_json("/services/forwarder/all")
+    }
+
+    /// Returns the JSON document with the statistics of the virtual network.
+    pub fn stats(&self) -> io::Result<String> {
+        self.request_json("/stats")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use crate::tempdir::TempDir;
+
+    /// Serves a single request, answering with `response`. Returns the
+    /// request received.
+    fn serve(listener: UnixListener, response: &'static str) -> thread::JoinHandle<String> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(len) = line.strip_prefix("Content-Length: ") {
+                    content_length = len.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(std::str::from_utf8(&body).unwrap());
+            (&stream).write_all(response.as_bytes()).unwrap();
+            request
+        })
+    }
+
+    #[test]
+    fn test_expose() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("gvproxy.sock");
+        let client = GvproxyClient::new(&path);
+
+        let server = serve(
+            UnixListener::bind(&path).unwrap(),
+            "HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n",
+        );
+        client
+            .expose(Protocol::Tcp, ":8080", "192.168.127.2:80")
+            .unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /services/forwarder/expose HTTP/1.0\r\n"));
+        assert!(request.ends_with(
+            "\r\n\r\n{\"local\":\":8080\",\"remote\":\"192.168.127.2:80\",\"protocol\":\"tcp\"}"
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+        let server = serve(
+            UnixListener::bind(&path).unwrap(),
+            "HTTP/1.0 500 Internal Server Error\r\n\r\nlisten tcp :8080: address already in use\n",
+        );
+        let err = client.unexpose(Protocol::Udp, "\"").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert!(server
+            .join()
+            .unwrap()
+            .ends_with("{\"local\":\"\\\"\",\"protocol\":\"udp\"}"));
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("gvproxy.sock");
+        let server = serve(
+            UnixListener::bind(&path).unwrap(),
+            "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{\"BytesSent\":1}",
+        );
+        assert_eq!(
+            GvproxyClient::new(&path).stats().unwrap(),
+            "{\"BytesSent\":1}"
+        );
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("GET /stats HTTP/1.0\r\n"));
+
+        assert_eq!(
+            parse_response(b"HTTP/1.1 404 Not Found")
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EPROTO)
+        );
+    }
+}
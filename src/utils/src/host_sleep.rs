@@ -0,0 +1,190 @@
+//! Notifications of the host going to sleep and waking up, so the guest can
+//! be suspended along with it and get its clock fixed afterwards.
+//!
+//! On macOS they come from IOKit, which waits for the handler to return
+//! before letting the host sleep. Linux has no such notification without
+//! going through logind, so only wake ups are reported there, detected by
+//! the boot time clock getting ahead of the monotonic one, which doesn't
+//! count the time spent suspended.
+
+use std::io;
+use std::thread;
+
+use log::error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostSleepEvent {
+    WillSleep,
+    Woke,
+}
+
+pub type HostSleepHandler = Box<dyn Fn(HostSleepEvent) + Send>;
+
+/// Calls `handler` from a new thread on every host sleep event.
+pub fn watch(handler: HostSleepHandler) -> io::Result<()> {
+    thread::Builder::new()
+        .name("host sleep".into())
+        .spawn(move || {
+            if let Err(e) = platform::run(handler) {
+                error!("Can't watch for host sleep: {e}");
+            }
+        })
+        .map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+    use std::thread;
+    use std::time::Duration;
+
+    use log::debug;
+
+    use super::{HostSleepEvent, HostSleepHandler};
+    use crate::time::{get_time, ClockType};
+
+    const POLL_INTERVAL: Duration = Duration::from_secs(2);
+    /// Smaller jumps may just be the thread getting scheduled late.
+    const MIN_SLEEP_NS: u64 = 1_000_000_000;
+
+    /// Time the host spent suspended since it booted.
+    fn suspended_ns() -> u64 {
+        get_time(ClockType::Boot).saturating_sub(get_time(ClockType::Monotonic))
+    }
+
+    /// Returns whether the host slept between two readings of `suspended_ns`.
+    pub(super) fn slept(before: u64, after: u64) -> bool {
+        after.saturating_sub(before) >= MIN_SLEEP_NS
+    }
+
+    pub(super) fn run(handler: HostSleepHandler) -> io::Result<()> {
+        let mut before = suspended_ns();
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let after = suspended_ns();
+            if slept(before, after) {
+                debug!("host woke up after {}ms", (after - before) / 1_000_000);
+                handler(HostSleepEvent::Woke);
+            }
+            before = after;
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::ffi::c_void;
+    use std::io;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::{HostSleepEvent, HostSleepHandler};
+
+    type IoConnect = u32;
+    type IoObject = u32;
+    type IoNotificationPortRef = *mut c_void;
+    type CfRunLoopRef = *mut c_void;
+    type CfRunLoopSourceRef = *mut c_void;
+    type CfStringRef = *const c_void;
+    type IoServiceInterestCallback = extern "C" fn(*mut c_void, IoObject, u32, *mut c_void);
+
+    const K_IO_MESSAGE_CAN_SYSTEM_SLEEP: u32 = 0xe000_0270;
+    const K_IO_MESSAGE_SYSTEM_WILL_SLEEP: u32 = 0xe000_0280;
+    const K_IO_MESSAGE_SYSTEM_HAS_POWERED_ON: u32 = 0xe000_0300;
+
+    #[link(name = "IOKit", kind = "framework")]
+    extern "C" {
+        fn IORegisterForSystemPower(
+            refcon: *mut c_void,
+            port: *mut IoNotificationPortRef,
+            callback: IoServiceInterestCallback,
+            notifier: *mut IoObject,
+        ) -> IoConnect;
+        fn IONotificationPortGetRunLoopSource(port: IoNotificationPortRef) -> CfRunLoopSourceRef;
+        fn IOAllowPowerChange(kernel_port: IoConnect, notification_id: isize) -> i32;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        static kCFRunLoopDefaultMode: CfStringRef;
+        fn CFRunLoopGetCurrent() -> CfRunLoopRef;
+        fn CFRunLoopAddSource(rl: CfRunLoopRef, source: CfRunLoopSourceRef, mode: CfStringRef);
+        fn CFRunLoopRun();
+    }
+
+    struct Watcher {
+        root_port: AtomicU32,
+        handler: HostSleepHandler,
+    }
+
+    extern "C" fn power_callback(
+        refcon: *mut c_void,
+        _service: IoObject,
+        message_type: u32,
+        argument: *mut c_void,
+    ) {
+        // SAFETY: `refcon` is the watcher leaked by `run`, which lives as long
+        // as the process.
+        let watcher = unsafe { &*(refcon as *const Watcher) };
+        let root_port = watcher.root_port.load(Ordering::Acquire);
+        match message_type {
+            K_IO_MESSAGE_CAN_SYSTEM_SLEEP => unsafe {
+                IOAllowPowerChange(root_port, argument as isize);
+            },
+            K_IO_MESSAGE_SYSTEM_WILL_SLEEP => {
+                (watcher.handler)(HostSleepEvent::WillSleep);
+                // SAFETY: `argument` is the notification id IOKit expects.
+                unsafe { IOAllowPowerChange(root_port, argument as isize) };
+            }
+            K_IO_MESSAGE_SYSTEM_HAS_POWERED_ON => (watcher.handler)(HostSleepEvent::Woke),
+            _ => {}
+        }
+    }
+
+    pub(super) fn run(handler: HostSleepHandler) -> io::Result<()> {
+        let watcher: &'static Watcher = Box::leak(Box::new(Watcher {
+            root_port: AtomicU32::new(0),
+            handler,
+        }));
+        let mut port: IoNotificationPortRef = std::ptr::null_mut();
+        let mut notifier: IoObject = 0;
+        // SAFETY: the pointers are valid, and the watcher is never freed.
+        let root_port = unsafe {
+            IORegisterForSystemPower(
+                watcher as *const Watcher as *mut c_void,
+                &mut port,
+                power_callback,
+                &mut notifier,
+            )
+        };
+        if root_port == 0 {
+            return Err(io::Error::other("IORegisterForSystemPower failed"));
+        }
+        watcher.root_port.store(root_port, Ordering::Release);
+
+        // SAFETY: `port` was just created, and the run loop belongs to this
+        // thread, which never leaves it.
+        unsafe {
+            CFRunLoopAddSource(
+                CFRunLoopGetCurrent(),
+                IONotificationPortGetRunLoopSource(port),
+                kCFRunLoopDefaultMode,
+            );
+            CFRunLoopRun();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::platform::slept;
+
+    #[test]
+    fn test_slept() {
+        assert!(!slept(5_000_000_000, 5_000_000_000));
+        assert!(!slept(5_000_000_000, 5_200_000_000));
+        assert!(slept(5_000_000_000, 65_000_000_000));
+        // The clocks are read separately, so the difference can go back a bit.
+        assert!(!slept(5_000_000_000, 4_999_999_000));
+    }
+}
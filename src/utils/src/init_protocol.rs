@@ -0,0 +1,195 @@
+//! The protocol between the VMM and the init of the guest, whether the one
+//! bundled with libkrun or a replacement provided by the embedder.
+//!
+//! The guest kernel executes init as `/init.krun` from the virtio-fs device
+//! serving the root file system, or serving the temporary root init switches
+//! away from when the root is a block device. init is then configured by:
+//!
+//! - The environment variables of `InitEnv` the kernel passes to it from its
+//!   command line, along with those of the workload. `InitEnv::Protocol`
+//!   always carries `PROTOCOL_VERSION`, so init can refuse a protocol it
+//!   doesn't implement.
+//! - Its arguments, which are those of the workload.
+//! - The JSON document at `CONFIG_FILE_PATH` in the root file system, if any,
+//!   with the OCI-like "Env", "Cwd", "Cmd" and "Entrypoint" of the workload.
+//!
+//! init reports back to the VMM with the `InitIoctl` ioctls on the root
+//! directory of the virtio-fs root, passing their value as argument. Each of
+//! them is optional, the VMM only uses what it gets.
+//!
+//! The version is bumped whenever a change of the protocol breaks either side.
+
+/// Version of the protocol implemented by the VMM.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Path of init in the guest.
+pub const INIT_PATH: &str = "/init.krun";
+
+/// Default path of the configuration document in the root file system.
+pub const CONFIG_FILE_PATH: &str = "/.krun_config.json";
+
+/// The environment variables configuring init.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitEnv {
+    /// The version of the protocol, `PROTOCOL_VERSION`.
+    Protocol,
+    /// The executable of the workload, overriding the configuration document.
+    Exec,
+    /// The working directory of the workload.
+    Workdir,
+    /// The path of the configuration document, if not `CONFIG_FILE_PATH`.
+    Config,
+    /// The block device holding the root file system.
+    BlockRootDevice,
+    /// The file system type of `BlockRootDevice`.
+    BlockRootFstype,
+    /// The mount options of `BlockRootDevice`.
+    BlockRootOptions,
+    /// Comma-separated `ID=CUR:MAX` resource limits of the workload.
+    Rlimits,
+    /// Comma-separated directories made writable with an in-memory overlay
+    /// over the root file system, mounted read-only.
+    Ephemeral,
+    /// Comma-separated `KEY=VALUE` sysctls.
+    Sysctl,
+    /// Comma-separated `MODULE[:PARAMS]` kernel modules to load.
+    Modules,
+    /// Where to mount the directory of the kernel modules, shared with the
+    /// "krun-modules" tag.
+    ModulesDir,
+    /// Semicolon-separated `TYPE:SOURCE:TARGET:OPTIONS` volumes to mount.
+    Mounts,
+    /// `DEVICE:TARGET:FIELDS` of the disk sealed to the SEV-SNP attestation
+    /// state, with `FIELDS` the GUEST_FIELD_SELECT bits of the sealing key.
+    SealedDisk,
+    /// Comma-separated `CONTROLLER:VALUE` limits of the workload cgroup.
+    Cgroup,
+    /// The lockdown mode of the kernel.
+    Lockdown,
+    /// The kernel to load for crash dumps.
+    KdumpKernel,
+    /// The initrd of `KdumpKernel`.
+    KdumpInitrd,
+    /// The block device crash dumps are written to.
+    KdumpDevice,
+    /// `UID:GID:GROUPS` the workload runs as.
+    User,
+    /// Comma-separated `NAME=ADDRESS` entries of /etc/hosts.
+    Hosts,
+    /// Comma-separated DNS servers.
+    Dns,
+    /// Comma-separated DNS search domains.
+    DnsSearch,
+    /// Where to mount the Rosetta runtime, shared with the "rosetta" tag.
+    Rosetta,
+    /// Whether to start the guest agent.
+    Agent,
+    /// Whether to run the exec hook.
+    ExecHook,
+    /// Whether to start the SSH server.
+    Sshd,
+    /// Whether to start the FIDO relay.
+    Fido,
+    /// The `HOME` of the workload.
+    Home,
+    /// The `TERM` of the workload.
+    Term,
+}
+
+impl InitEnv {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InitEnv::Protocol => "KRUN_INIT_PROTOCOL",
+            InitEnv::Exec => "KRUN_INIT",
+            InitEnv::Workdir => "KRUN_WORKDIR",
+            InitEnv::Config => "KRUN_CONFIG",
+            InitEnv::BlockRootDevice => "KRUN_BLOCK_ROOT_DEVICE",
+            InitEnv::BlockRootFstype => "KRUN_BLOCK_ROOT_FSTYPE",
+            InitEnv::BlockRootOptions => "KRUN_BLOCK_ROOT_OPTIONS",
+            InitEnv::Rlimits => "KRUN_RLIMITS",
+            InitEnv::Ephemeral => "KRUN_EPHEMERAL",
+            InitEnv::Sysctl => "KRUN_SYSCTL",
+            InitEnv::Modules => "KRUN_MODULES",
+            InitEnv::ModulesDir => "KRUN_MODULES_DIR",
+            InitEnv::Mounts => "KRUN_MOUNTS",
+            InitEnv::SealedDisk => "KRUN_SEALED_DISK",
+            InitEnv::Cgroup => "KRUN_CGROUP",
+            InitEnv::Lockdown => "KRUN_LOCKDOWN",
+            InitEnv::KdumpKernel => "KRUN_KDUMP_KERNEL",
+            InitEnv::KdumpInitrd => "KRUN_KDUMP_INITRD",
+            InitEnv::KdumpDevice => "KRUN_KDUMP_DEVICE",
+            InitEnv::User => "KRUN_USER",
+            InitEnv::Hosts => "KRUN_HOSTS",
+            InitEnv::Dns => "KRUN_DNS",
+            InitEnv::DnsSearch => "KRUN_DNS_SEARCH",
+            InitEnv::Rosetta => "KRUN_ROSETTA",
+            InitEnv::Agent => "KRUN_AGENT",
+            InitEnv::ExecHook => "KRUN_EXEC_HOOK",
+            InitEnv::Sshd => "KRUN_SSHD",
+            InitEnv::Fido => "KRUN_FIDO",
+            InitEnv::Home => "KRUN_HOME",
+            InitEnv::Term => "KRUN_TERM",
+        }
+    }
+}
+
+/// The ioctls init reports to the VMM with, `_IO('v', nr)` as encoded by
+/// Linux whatever the host is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitIoctl {
+    /// The exit code of the workload.
+    ExitCode = 2,
+    /// Remove the temporary root directory init ran from.
+    RemoveRootDir = 3,
+    /// The workload is about to be executed.
+    WorkloadExec = 4,
+    /// The maximum resident set size of the workload, in KiB.
+    WorkloadMaxRss = 5,
+    /// The CPU time the workload spent in user mode, in microseconds.
+    WorkloadUserTime = 6,
+    /// The CPU time the workload spent in kernel mode, in microseconds.
+    WorkloadSysTime = 7,
+    /// The time the workload ran for, in microseconds.
+    WorkloadWallTime = 8,
+}
+
+const IOCTL_MAGIC: u32 = b'v' as u32;
+
+impl InitIoctl {
+    const ALL: [InitIoctl; 7] = [
+        InitIoctl::ExitCode,
+        InitIoctl::RemoveRootDir,
+        InitIoctl::WorkloadExec,
+        InitIoctl::WorkloadMaxRss,
+        InitIoctl::WorkloadUserTime,
+        InitIoctl::WorkloadSysTime,
+        InitIoctl::WorkloadWallTime,
+    ];
+
+    /// The request code of the ioctl.
+    pub const fn request(self) -> u32 {
+        (IOCTL_MAGIC << 8) | self as u32
+    }
+
+    pub fn from_request(request: u32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|ioctl| ioctl.request() == request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_requests() {
+        assert_eq!(InitIoctl::ExitCode.request(), 0x7602);
+        assert_eq!(InitIoctl::WorkloadWallTime.request(), 0x7608);
+        assert_eq!(
+            InitIoctl::from_request(0x7604),
+            Some(InitIoctl::WorkloadExec)
+        );
+        assert_eq!(InitIoctl::from_request(0x7601), None);
+    }
+}
@@ -5,7 +5,15 @@ pub use vmm_sys_util::{errno, tempdir, tempfile, terminal};
 #[cfg(target_os = "linux")]
 pub use vmm_sys_util::{eventfd, ioctl};
 
+pub mod agent;
+pub mod boot_timeline;
 pub mod byte_order;
+#[cfg(feature = "tracing")]
+pub mod chrome_trace;
+pub mod gvproxy;
+pub mod host_sleep;
+pub mod init_protocol;
+mod json;
 #[cfg(target_os = "linux")]
 pub mod linux;
 #[cfg(target_os = "linux")]
@@ -16,8 +24,11 @@ pub mod macos;
 pub use macos::epoll;
 #[cfg(target_os = "macos")]
 pub use macos::eventfd;
+pub mod metrics;
+pub mod mkfs;
 pub mod pollable_channel;
 pub mod rand;
+pub mod scratch;
 #[cfg(target_os = "linux")]
 pub mod signal;
 pub mod sized_vec;
@@ -25,3 +36,5 @@ pub mod sm;
 pub mod syscall;
 pub mod time;
 pub mod worker_message;
+pub mod worker_pool;
+pub mod workload_stats;
@@ -0,0 +1,416 @@
+//! Counters describing the activity of a microVM.
+//!
+//! Every microVM has its own `Metrics`, shared by its components. Counters
+//! are plain relaxed atomics so they can be bumped from the vCPU
+//! threads, the device workers and the event loop without any locking. A
+//! consistent point-in-time view is not guaranteed, which is fine for the
+//! capacity planning use case these are meant for.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, error};
+
+use crate::json;
+
+/// A monotonically increasing counter.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Number of vCPU exits, broken down by exit reason.
+#[derive(Debug, Default)]
+pub struct VmExitMetrics {
+    pub io_in: Counter,
+    pub io_out: Counter,
+    pub mmio_read: Counter,
+    pub mmio_write: Counter,
+    pub hypercall: Counter,
+    pub memory_fault: Counter,
+    pub hlt: Counter,
+    pub shutdown: Counter,
+    pub system_event: Counter,
+    pub interrupted: Counter,
+    pub other: Counter,
+}
+
+impl VmExitMetrics {
+    const fn new() -> Self {
+        VmExitMetrics {
+            io_in: Counter::new(),
+            io_out: Counter::new(),
+            mmio_read: Counter::new(),
+            mmio_write: Counter::new(),
+            hypercall: Counter::new(),
+            memory_fault: Counter::new(),
+            hlt: Counter::new(),
+            shutdown: Counter::new(),
+            system_event: Counter::new(),
+            interrupted: Counter::new(),
+            other: Counter::new(),
+        }
+    }
+
+    fn counters(&self) -> [(&'static str, &Counter); 11] {
+        [
+            ("io_in", &self.io_in),
+            ("io_out", &self.io_out),
+            ("mmio_read", &self.mmio_read),
+            ("mmio_write", &self.mmio_write),
+            ("hypercall", &self.hypercall),
+            ("memory_fault", &self.memory_fault),
+            ("hlt", &self.hlt),
+            ("shutdown", &self.shutdown),
+            ("system_event", &self.system_event),
+            ("interrupted", &self.interrupted),
+            ("other", &self.other),
+        ]
+    }
+}
+
+/// Per-device activity. "rx" is data flowing from the device into the
+/// guest, "tx" is data the guest hands over to the device.
+#[derive(Debug, Default)]
+pub struct DeviceMetrics {
+    pub queue_kicks: Counter,
+    pub interrupts: Counter,
+    /// Descriptor chains placed in the used ring.
+    pub descriptors: Counter,
+    /// Times the device had data for the guest but found no available
+    /// buffers to put it in.
+    pub queue_full: Counter,
+    pub rx_bytes: Counter,
+    pub tx_bytes: Counter,
+}
+
+impl DeviceMetrics {
+    fn counters(&self) -> [(&'static str, &Counter); 6] {
+        [
+            ("queue_kicks", &self.queue_kicks),
+            ("interrupts", &self.interrupts),
+            ("descriptors", &self.descriptors),
+            ("queue_full", &self.queue_full),
+            ("rx_bytes", &self.rx_bytes),
+            ("tx_bytes", &self.tx_bytes),
+        ]
+    }
+
+    /// Serializes a snapshot of the counters as a JSON object.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        json_object(&mut out, &self.counters());
+        out
+    }
+}
+
+/// Transparent Socket Impersonation (TSI) connection statistics.
+#[derive(Debug, Default)]
+pub struct TsiMetrics {
+    pub connections_opened: Counter,
+    pub connections_failed: Counter,
+    pub connections_accepted: Counter,
+    pub connections_closed: Counter,
+    pub listeners: Counter,
+    pub rx_bytes: Counter,
+    pub tx_bytes: Counter,
+}
+
+impl TsiMetrics {
+    const fn new() -> Self {
+        TsiMetrics {
+            connections_opened: Counter::new(),
+            connections_failed: Counter::new(),
+            connections_accepted: Counter::new(),
+            connections_closed: Counter::new(),
+            listeners: Counter::new(),
+            rx_bytes: Counter::new(),
+            tx_bytes: Counter::new(),
+        }
+    }
+
+    fn counters(&self) -> [(&'static str, &Counter); 7] {
+        [
+            ("connections_opened", &self.connections_opened),
+            ("connections_failed", &self.connections_failed),
+            ("connections_accepted", &self.connections_accepted),
+            ("connections_closed", &self.connections_closed),
+            ("listeners", &self.listeners),
+            ("rx_bytes", &self.rx_bytes),
+            ("tx_bytes", &self.tx_bytes),
+        ]
+    }
+}
+
+/// Guest memory mapping activity.
+#[derive(Debug, Default)]
+pub struct MemoryMetrics {
+    /// Memory slots added at runtime, after the guest memory was set up.
+    pub slots_added: Counter,
+    pub slots_removed: Counter,
+    /// Guest accesses to unmapped guest physical addresses that trapped to
+    /// the VMM. KVM resolves most of these faults in the host kernel, so
+    /// this is only accounted for on HVF.
+    pub stage2_faults: Counter,
+}
+
+impl MemoryMetrics {
+    const fn new() -> Self {
+        MemoryMetrics {
+            slots_added: Counter::new(),
+            slots_removed: Counter::new(),
+            stage2_faults: Counter::new(),
+        }
+    }
+
+    fn counters(&self) -> [(&'static str, &Counter); 3] {
+        [
+            ("slots_added", &self.slots_added),
+            ("slots_removed", &self.slots_removed),
+            ("stage2_faults", &self.stage2_faults),
+        ]
+    }
+}
+
+struct DeviceEntry {
+    kind: String,
+    id: String,
+    metrics: Arc<DeviceMetrics>,
+}
+
+pub struct Metrics {
+    pub vm_exits: VmExitMetrics,
+    pub tsi: TsiMetrics,
+    pub memory: MemoryMetrics,
+    devices: Mutex<Vec<DeviceEntry>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            vm_exits: VmExitMetrics::new(),
+            tsi: TsiMetrics::new(),
+            memory: MemoryMetrics::new(),
+            devices: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the metrics for the device of type `kind` identified by `id`,
+    /// registering them if this is the first time the device is seen.
+    pub fn device(&self, kind: &str, id: &str) -> Arc<DeviceMetrics> {
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(entry) = devices.iter().find(|e| e.id == id) {
+            return entry.metrics.clone();
+        }
+        let metrics = Arc::new(DeviceMetrics::default());
+        devices.push(DeviceEntry {
+            kind: kind.to_string(),
+            id: id.to_string(),
+            metrics: metrics.clone(),
+        });
+        metrics
+    }
+
+    /// Returns the metrics of the device identified by `id`, if any.
+    pub fn find_device(&self, id: &str) -> Option<Arc<DeviceMetrics>> {
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| e.id == id)
+            .map(|e| e.metrics.clone())
+    }
+
+    /// Serializes a snapshot of all counters as a JSON document.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        out.push_str("{\"vm_exits\":");
+        json_object(&mut out, &self.vm_exits.counters());
+        out.push_str(",\"devices\":{");
+        for (i, entry) in self.devices.lock().unwrap().iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "\"{}\":", json::escape(&entry.id));
+            json_object(&mut out, &entry.metrics.counters());
+        }
+        out.push_str("},\"tsi\":");
+        json_object(&mut out, &self.tsi.counters());
+        out.push_str(",\"memory\":");
+        json_object(&mut out, &self.memory.counters());
+        out.push('}');
+        out
+    }
+
+    /// Serializes a snapshot of all counters in the Prometheus text
+    /// exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# TYPE krun_vm_exits_total counter\n");
+        for (reason, counter) in self.vm_exits.counters() {
+            let _ = writeln!(
+                out,
+                "krun_vm_exits_total{{reason=\"{reason}\"}} {}",
+                counter.get()
+            );
+        }
+
+        let devices = self.devices.lock().unwrap();
+        for (i, (metric, _)) in DeviceMetrics::default().counters().iter().enumerate() {
+            let _ = writeln!(out, "# TYPE krun_device_{metric}_total counter");
+            for entry in devices.iter() {
+                let _ = writeln!(
+                    out,
+                    "krun_device_{metric}_total{{device=\"{}\",id=\"{}\"}} {}",
+                    label_value(&entry.kind),
+                    label_value(&entry.id),
+                    entry.metrics.counters()[i].1.get()
+                );
+            }
+        }
+
+        for (metric, counter) in self.tsi.counters() {
+            let _ = writeln!(out, "# TYPE krun_tsi_{metric}_total counter");
+            let _ = writeln!(out, "krun_tsi_{metric}_total {}", counter.get());
+        }
+
+        for (metric, counter) in self.memory.counters() {
+            let _ = writeln!(out, "# TYPE krun_memory_{metric}_total counter");
+            let _ = writeln!(out, "krun_memory_{metric}_total {}", counter.get());
+        }
+
+        out
+    }
+}
+
+/// Escapes `s` to be written between the quotes of a Prometheus label value.
+fn label_value(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn json_object(out: &mut String, counters: &[(&str, &Counter)]) {
+    out.push('{');
+    for (i, (name, counter)) in counters.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "\"{}\":{}", name, counter.get());
+    }
+    out.push('}');
+}
+
+/// Starts a thread listening on a UNIX socket at `path`. Every client
+/// connecting to it receives a snapshot of `metrics` in the Prometheus text
+/// format, after which the connection is closed.
+pub fn spawn_exporter(path: &Path, metrics: Arc<Metrics>) -> io::Result<thread::JoinHandle<()>> {
+    let listener = UnixListener::bind(path)?;
+    thread::Builder::new()
+        .name("metrics exporter".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(mut stream) => {
+                        if let Err(e) = stream.write_all(metrics.to_prometheus().as_bytes()) {
+                            debug!("metrics: error writing to client: {e}");
+                        }
+                    }
+                    Err(e) => {
+                        error!("metrics: error accepting connection: {e}");
+                        break;
+                    }
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_snapshot() {
+        let metrics = Metrics::new();
+        metrics.vm_exits.mmio_write.add(3);
+        metrics.device("block", "root").queue_kicks.inc();
+        metrics.device("block", "root").rx_bytes.add(512);
+        metrics.tsi.connections_opened.inc();
+        metrics.memory.stage2_faults.add(2);
+
+        let json = metrics.to_json();
+        assert!(json.starts_with("{\"vm_exits\":{\"io_in\":0,"));
+        assert!(json.contains("\"mmio_write\":3"));
+        assert!(json.contains(
+            "\"devices\":{\"root\":{\"queue_kicks\":1,\"interrupts\":0,\"descriptors\":0,\
+             \"queue_full\":0,\"rx_bytes\":512,\"tx_bytes\":0}}"
+        ));
+        assert!(json.contains("\"tsi\":{\"connections_opened\":1,"));
+        assert!(json
+            .ends_with(",\"memory\":{\"slots_added\":0,\"slots_removed\":0,\"stage2_faults\":2}}"));
+    }
+
+    #[test]
+    fn test_prometheus_snapshot() {
+        let metrics = Metrics::new();
+        metrics.vm_exits.hlt.inc();
+        metrics.device("net", "net0").tx_bytes.add(1500);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("krun_vm_exits_total{reason=\"hlt\"} 1\n"));
+        assert!(text.contains("krun_device_tx_bytes_total{device=\"net\",id=\"net0\"} 1500\n"));
+        assert!(text.contains("krun_tsi_listeners_total 0\n"));
+        assert!(text.contains("krun_memory_slots_added_total 0\n"));
+    }
+
+    #[test]
+    fn test_escaped_ids() {
+        let metrics = Metrics::new();
+        metrics.device("net", "a\"b\\c\nd").rx_bytes.inc();
+
+        assert!(metrics
+            .to_json()
+            .contains("\"devices\":{\"a\\\"b\\\\c\\nd\":{\"queue_kicks\":0,"));
+        assert!(metrics
+            .to_prometheus()
+            .contains("krun_device_rx_bytes_total{device=\"net\",id=\"a\\\"b\\\\c\\nd\"} 1\n"));
+    }
+
+    #[test]
+    fn test_find_device() {
+        let metrics = Metrics::new();
+        metrics.device("vsock", "vsock0").queue_full.inc();
+
+        assert!(metrics.find_device("vsock1").is_none());
+        let json = metrics.find_device("vsock0").unwrap().to_json();
+        assert!(json.contains("\"queue_full\":1,"));
+    }
+}
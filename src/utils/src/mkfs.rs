@@ -0,0 +1,391 @@
+//! Creation of empty disk images holding a file system, to give the guests
+//! writable data volumes.
+//!
+//! ext4 file systems are laid out here rather than by `mkfs.ext4`, which
+//! hosts like macOS don't have. They are kept simple, without a journal, and
+//! the guest kernel grows them into whatever it needs once mounted. btrfs
+//! file systems are left to `mkfs.btrfs`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsType {
+    /// No file system, the image is left empty.
+    None,
+    Ext4,
+    Btrfs,
+}
+
+/// Creates a sparse image of `size` bytes at `path`, holding an empty file
+/// system of type `fs_type`. Existing files are never overwritten.
+pub fn create_image(path: &Path, size: u64, fs_type: FsType) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+
+    let result = file
+        .set_len(size)
+        .and_then(|()| match fs_type {
+            FsType::None => Ok(()),
+            FsType::Ext4 => format_ext4(&file, size),
+            FsType::Btrfs => format_btrfs(path),
+        })
+        .and_then(|()| file.sync_all());
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+fn format_btrfs(path: &Path) -> io::Result<()> {
+    let status = Command::new("mkfs.btrfs").arg("-q").arg(path).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("mkfs.btrfs failed: {status}")));
+    }
+    Ok(())
+}
+
+const BLOCK_SIZE: u64 = 4096;
+const BLOCKS_PER_GROUP: u64 = 8 * BLOCK_SIZE;
+const INODE_SIZE: u64 = 256;
+const INODES_PER_BLOCK: u64 = BLOCK_SIZE / INODE_SIZE;
+const BYTES_PER_INODE: u64 = 16384;
+const DESC_SIZE: u64 = 32;
+
+const ROOT_INO: u32 = 2;
+/// The first inode that isn't reserved, which is lost+found's.
+const LOST_FOUND_INO: u32 = 11;
+
+const EXT4_SUPER_MAGIC: u16 = 0xef53;
+const COMPAT_EXT_ATTR: u32 = 0x8;
+const COMPAT_DIR_INDEX: u32 = 0x20;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_DIR_NLINK: u32 = 0x20;
+const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+const EXTENTS_FL: u32 = 0x80000;
+const EXTENT_MAGIC: u16 = 0xf30a;
+/// Bytes of the inodes beyond the 128 of the original ext2 ones.
+const EXTRA_ISIZE: u16 = 32;
+const FT_DIR: u8 = 2;
+
+fn put16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Sets the bits `start..end` of `bitmap`.
+fn set_bits(bitmap: &mut [u8], start: u64, end: u64) {
+    for bit in start..end {
+        bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+    }
+}
+
+/// Whether `group` holds a copy of the superblock, with sparse_super.
+fn has_super(group: u64) -> bool {
+    let is_power_of = |base: u64| {
+        let mut n = base;
+        while n < group {
+            n *= base;
+        }
+        n == group
+    };
+    group <= 1 || is_power_of(3) || is_power_of(5) || is_power_of(7)
+}
+
+#[derive(Debug)]
+struct Layout {
+    blocks: u64,
+    groups: u64,
+    inodes_per_group: u64,
+    inode_table_blocks: u64,
+    gdt_blocks: u64,
+}
+
+impl Layout {
+    fn new(size: u64) -> io::Result<Self> {
+        let mut blocks = size / BLOCK_SIZE;
+        if blocks > u64::from(u32::MAX) {
+            return Err(io::Error::from_raw_os_error(libc::EFBIG));
+        }
+
+        loop {
+            let groups = blocks.div_ceil(BLOCKS_PER_GROUP).max(1);
+            let inodes_per_group = (blocks / groups * BLOCK_SIZE / BYTES_PER_INODE)
+                .next_multiple_of(INODES_PER_BLOCK)
+                .clamp(INODES_PER_BLOCK, BLOCKS_PER_GROUP / 4);
+            let layout = Self {
+                blocks,
+                groups,
+                inodes_per_group,
+                inode_table_blocks: inodes_per_group / INODES_PER_BLOCK,
+                gdt_blocks: (groups * DESC_SIZE).div_ceil(BLOCK_SIZE),
+            };
+
+            // Like mkfs.ext4, drop a last group too small to be of any use.
+            let last = groups - 1;
+            if last > 0 && layout.group_blocks(last) < layout.overhead(last) + 64 {
+                blocks = last * BLOCKS_PER_GROUP;
+                continue;
+            }
+            // The first group also holds the root directory and lost+found.
+            if layout.group_blocks(0) < layout.overhead(0) + 16 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            return Ok(layout);
+        }
+    }
+
+    fn group_blocks(&self, group: u64) -> u64 {
+        BLOCKS_PER_GROUP.min(self.blocks - group * BLOCKS_PER_GROUP)
+    }
+
+    /// Blocks taken by the copies of the superblock and descriptors in
+    /// `group`.
+    fn super_blocks(&self, group: u64) -> u64 {
+        if has_super(group) {
+            1 + self.gdt_blocks
+        } else {
+            0
+        }
+    }
+
+    /// Blocks taken by the metadata at the start of `group`.
+    fn overhead(&self, group: u64) -> u64 {
+        self.super_blocks(group) + 2 + self.inode_table_blocks
+    }
+
+    /// The block bitmap of `group`, followed by its inode bitmap and table.
+    fn bitmaps_start(&self, group: u64) -> u64 {
+        group * BLOCKS_PER_GROUP + self.super_blocks(group)
+    }
+}
+
+fn dir_entry(block: &mut [u8], offset: usize, inode: u32, rec_len: u16, name: &[u8]) {
+    put32(block, offset, inode);
+    put16(block, offset + 4, rec_len);
+    block[offset + 6] = name.len() as u8;
+    block[offset + 7] = FT_DIR;
+    block[offset + 8..offset + 8 + name.len()].copy_from_slice(name);
+}
+
+/// A directory inode whose entries are in `block`.
+fn dir_inode(mode: u16, links: u16, block: u64, now: u32) -> [u8; INODE_SIZE as usize] {
+    let mut inode = [0u8; INODE_SIZE as usize];
+    put16(&mut inode, 0x0, libc::S_IFDIR as u16 | mode);
+    put32(&mut inode, 0x4, BLOCK_SIZE as u32);
+    put32(&mut inode, 0x8, now);
+    put32(&mut inode, 0xc, now);
+    put32(&mut inode, 0x10, now);
+    put16(&mut inode, 0x1a, links);
+    put32(&mut inode, 0x1c, (BLOCK_SIZE / 512) as u32);
+    put32(&mut inode, 0x20, EXTENTS_FL);
+    // The extent tree, a single extent mapping the only block.
+    put16(&mut inode, 0x28, EXTENT_MAGIC);
+    put16(&mut inode, 0x2a, 1);
+    put16(&mut inode, 0x2c, 4);
+    put16(&mut inode, 0x38, 1);
+    put32(&mut inode, 0x3c, block as u32);
+    put16(&mut inode, 0x80, EXTRA_ISIZE);
+    put32(&mut inode, 0x90, now);
+    inode
+}
+
+fn random_bytes(buf: &mut [u8]) {
+    if File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(buf))
+        .is_err()
+    {
+        for chunk in buf.chunks_mut(4) {
+            let r = crate::rand::xor_rng_u32().to_le_bytes();
+            chunk.copy_from_slice(&r[..chunk.len()]);
+        }
+    }
+}
+
+fn format_ext4(file: &File, size: u64) -> io::Result<()> {
+    let layout = Layout::new(size)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32);
+
+    let mut gdt = vec![0u8; (layout.gdt_blocks * BLOCK_SIZE) as usize];
+    let mut free_blocks = 0;
+    let mut root_block = 0;
+    for group in 0..layout.groups {
+        let start = group * BLOCKS_PER_GROUP;
+        let block_bitmap = layout.bitmaps_start(group);
+        let inode_bitmap = block_bitmap + 1;
+        let inode_table = block_bitmap + 2;
+        let mut used = layout.overhead(group);
+        let mut free_inodes = layout.inodes_per_group;
+        let mut dirs = 0;
+
+        let mut bitmap = vec![0u8; BLOCK_SIZE as usize];
+        set_bits(&mut bitmap, layout.inodes_per_group, 8 * BLOCK_SIZE);
+        if group == 0 {
+            // The reserved inodes, and lost+found.
+            set_bits(&mut bitmap, 0, u64::from(LOST_FOUND_INO));
+            free_inodes -= u64::from(LOST_FOUND_INO);
+            dirs = 2;
+            root_block = start + used;
+            used += 2;
+        }
+        file.write_all_at(&bitmap, inode_bitmap * BLOCK_SIZE)?;
+
+        let group_blocks = layout.group_blocks(group);
+        let mut bitmap = vec![0u8; BLOCK_SIZE as usize];
+        set_bits(&mut bitmap, 0, used);
+        set_bits(&mut bitmap, group_blocks, 8 * BLOCK_SIZE);
+        file.write_all_at(&bitmap, block_bitmap * BLOCK_SIZE)?;
+        free_blocks += group_blocks - used;
+
+        let desc = &mut gdt[(group * DESC_SIZE) as usize..][..DESC_SIZE as usize];
+        put32(desc, 0x0, block_bitmap as u32);
+        put32(desc, 0x4, inode_bitmap as u32);
+        put32(desc, 0x8, inode_table as u32);
+        put16(desc, 0xc, (group_blocks - used) as u16);
+        put16(desc, 0xe, free_inodes as u16);
+        put16(desc, 0x10, dirs);
+    }
+
+    // The root directory and lost+found, the inode table being all zeros
+    // already in the sparse image.
+    let lost_found_block = root_block + 1;
+    let inode_table = layout.bitmaps_start(0) + 2;
+    let inode_offset = |ino: u32| inode_table * BLOCK_SIZE + u64::from(ino - 1) * INODE_SIZE;
+    file.write_all_at(
+        &dir_inode(0o755, 3, root_block, now),
+        inode_offset(ROOT_INO),
+    )?;
+    file.write_all_at(
+        &dir_inode(0o700, 2, lost_found_block, now),
+        inode_offset(LOST_FOUND_INO),
+    )?;
+
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    dir_entry(&mut block, 0, ROOT_INO, 12, b".");
+    dir_entry(&mut block, 12, ROOT_INO, 12, b"..");
+    dir_entry(
+        &mut block,
+        24,
+        LOST_FOUND_INO,
+        BLOCK_SIZE as u16 - 24,
+        b"lost+found",
+    );
+    file.write_all_at(&block, root_block * BLOCK_SIZE)?;
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    dir_entry(&mut block, 0, LOST_FOUND_INO, 12, b".");
+    dir_entry(&mut block, 12, ROOT_INO, BLOCK_SIZE as u16 - 12, b"..");
+    file.write_all_at(&block, lost_found_block * BLOCK_SIZE)?;
+
+    let inodes = layout.groups * layout.inodes_per_group;
+    let mut sb = [0u8; 1024];
+    put32(&mut sb, 0x0, inodes as u32);
+    put32(&mut sb, 0x4, layout.blocks as u32);
+    put32(&mut sb, 0x8, (layout.blocks / 20) as u32);
+    put32(&mut sb, 0xc, free_blocks as u32);
+    put32(&mut sb, 0x10, (inodes - u64::from(LOST_FOUND_INO)) as u32);
+    // Blocks of 1024 << 2 bytes.
+    put32(&mut sb, 0x18, 2);
+    put32(&mut sb, 0x1c, 2);
+    put32(&mut sb, 0x20, BLOCKS_PER_GROUP as u32);
+    put32(&mut sb, 0x24, BLOCKS_PER_GROUP as u32);
+    put32(&mut sb, 0x28, layout.inodes_per_group as u32);
+    put32(&mut sb, 0x30, now);
+    put16(&mut sb, 0x36, u16::MAX);
+    put16(&mut sb, 0x38, EXT4_SUPER_MAGIC);
+    // Cleanly unmounted, and errors don't stop the guest.
+    put16(&mut sb, 0x3a, 1);
+    put16(&mut sb, 0x3c, 1);
+    put32(&mut sb, 0x40, now);
+    put32(&mut sb, 0x4c, 1);
+    put32(&mut sb, 0x54, LOST_FOUND_INO);
+    put16(&mut sb, 0x58, INODE_SIZE as u16);
+    put32(&mut sb, 0x5c, COMPAT_EXT_ATTR | COMPAT_DIR_INDEX);
+    put32(&mut sb, 0x60, INCOMPAT_FILETYPE | INCOMPAT_EXTENTS);
+    put32(
+        &mut sb,
+        0x64,
+        RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_DIR_NLINK | RO_COMPAT_EXTRA_ISIZE,
+    );
+    let mut uuid = [0u8; 16];
+    random_bytes(&mut uuid);
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    sb[0x68..0x78].copy_from_slice(&uuid);
+    random_bytes(&mut sb[0xec..0xfc]);
+    // Half MD4 hashes for the indexed directories.
+    sb[0xfc] = 1;
+    // user_xattr and acl.
+    put32(&mut sb, 0x100, 0xc);
+    put32(&mut sb, 0x108, now);
+    put16(&mut sb, 0x15c, EXTRA_ISIZE);
+    put16(&mut sb, 0x15e, EXTRA_ISIZE);
+
+    for group in (0..layout.groups).filter(|&group| has_super(group)) {
+        let start = group * BLOCKS_PER_GROUP * BLOCK_SIZE;
+        put16(&mut sb, 0x5a, group as u16);
+        // The primary superblock is 1024 bytes into the first block.
+        let offset = if group == 0 { 1024 } else { start };
+        file.write_all_at(&sb, offset)?;
+        file.write_all_at(&gdt, start + BLOCK_SIZE)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn test_layout() {
+        assert!(has_super(0) && has_super(1) && has_super(25) && has_super(49));
+        assert!(!has_super(2) && !has_super(10));
+
+        let layout = Layout::new(1 << 30).unwrap();
+        assert_eq!(layout.groups, 8);
+        assert_eq!(layout.inodes_per_group, 8192);
+        assert_eq!(layout.overhead(1), 1 + 1 + 2 + 512);
+        assert_eq!(layout.overhead(2), 2 + 512);
+
+        // A last group too small for its metadata is dropped.
+        let layout = Layout::new((1 << 30) + 100 * BLOCK_SIZE).unwrap();
+        assert_eq!(layout.blocks, 8 * BLOCKS_PER_GROUP);
+
+        assert!(Layout::new(16 * BLOCK_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_create_ext4() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("data.img");
+        create_image(&path, 64 << 20, FsType::Ext4).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 64 << 20);
+
+        let file = File::open(&path).unwrap();
+        let mut sb = [0u8; 1024];
+        file.read_exact_at(&mut sb, 1024).unwrap();
+        assert_eq!(u16::from_le_bytes([sb[0x38], sb[0x39]]), EXT4_SUPER_MAGIC);
+        assert_eq!(u32::from_le_bytes(sb[0x4..0x8].try_into().unwrap()), 16384);
+
+        // Existing files are left alone.
+        assert_eq!(
+            create_image(&path, 1 << 20, FsType::None)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(fs::metadata(&path).unwrap().len(), 64 << 20);
+    }
+}
@@ -0,0 +1,204 @@
+//! Private scratch directories holding the sockets and files a microVM
+//! creates on the host, so they can all be removed at once when it goes away.
+//!
+//! A directory is removed when its `ScratchDir` is dropped. Since that can't
+//! happen if the process crashes or is killed, the removal can also be left
+//! to a supervisor: a small process detached from ours which only holds the
+//! read end of a pipe, and removes the directory once it sees the end of file
+//! that follows the death of the writer.
+
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+
+use crate::rand::xor_rng_u32;
+
+const RM_PATH: &str = "/bin/rm";
+
+pub struct ScratchDir {
+    path: PathBuf,
+    /// The write end of the pipe the supervisor is waiting on, if any.
+    supervisor: Option<OwnedFd>,
+}
+
+impl ScratchDir {
+    /// Creates a directory only accessible by the current user in the
+    /// temporary directory of the host, named after `prefix` and the PID of
+    /// the process.
+    pub fn create(prefix: &str) -> io::Result<Self> {
+        let mut builder = DirBuilder::new();
+        builder.mode(0o700);
+        loop {
+            let path =
+                env::temp_dir().join(format!("{prefix}-{}-{:08x}", process::id(), xor_rng_u32()));
+            match builder.create(&path) {
+                Ok(()) => {
+                    return Ok(ScratchDir {
+                        path,
+                        supervisor: None,
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of the entry `name` of the directory.
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// Whether a supervisor removes the directory if the process dies.
+    pub fn is_supervised(&self) -> bool {
+        self.supervisor.is_some()
+    }
+
+    /// Spawns a supervisor removing the directory once this process is gone,
+    /// however it ends.
+    pub fn supervise(&mut self) -> io::Result<()> {
+        if self.supervisor.is_some() {
+            return Ok(());
+        }
+
+        // Everything the supervisor needs is prepared here, as only
+        // async-signal-safe functions may be called after forking a
+        // multithreaded process.
+        let rm = CString::new(RM_PATH).unwrap();
+        let args = [
+            CString::new("rm").unwrap(),
+            CString::new("-rf").unwrap(),
+            CString::new("--").unwrap(),
+            CString::new(self.path.as_os_str().as_bytes())
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+        ];
+        let argv: Vec<*const libc::c_char> = args
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .chain([ptr::null()])
+            .collect();
+        let max_fd = match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+            n if n > 0 => n as libc::c_int,
+            _ => 1024,
+        };
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (reader, writer) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        // Nothing else spawned by this process must keep the pipe open.
+        if unsafe { libc::fcntl(fds[1], libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The supervisor is a grandchild, so it is reparented to init as
+        // soon as the intermediate child exits and never becomes a zombie
+        // of ours.
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if pid == 0 {
+            unsafe {
+                // A session of its own keeps it out of the reach of the
+                // signals sent to the process group of the terminal.
+                libc::setsid();
+                match libc::fork() {
+                    0 => {}
+                    -1 => libc::_exit(1),
+                    _ => libc::_exit(0),
+                }
+                for fd in 0..max_fd {
+                    if fd != fds[0] {
+                        libc::close(fd);
+                    }
+                }
+                let mut byte = 0u8;
+                while libc::read(fds[0], &mut byte as *mut u8 as *mut libc::c_void, 1) < 0
+                    && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+                {
+                }
+                libc::execv(rm.as_ptr(), argv.as_ptr());
+                libc::_exit(1);
+            }
+        }
+
+        drop(reader);
+        let mut status = 0;
+        while unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+            return Err(io::Error::other("failed to spawn the cleanup supervisor"));
+        }
+
+        self.supervisor = Some(writer);
+        Ok(())
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("Failed to remove {}: {e}", self.path.display());
+            }
+        }
+        // Closing the pipe lets the supervisor go, finding nothing left to
+        // remove.
+        self.supervisor.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_scratch_dir() {
+        let dir = ScratchDir::create("krun-test-scratch").unwrap();
+        let path = dir.path().to_path_buf();
+        fs::create_dir(dir.join("root")).unwrap();
+        fs::write(dir.join("root").join("file"), b"data").unwrap();
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_scratch_dir_supervisor() {
+        let mut dir = ScratchDir::create("krun-test-scratch").unwrap();
+        dir.supervise().unwrap();
+        assert!(dir.is_supervised());
+        fs::write(dir.join("file"), b"data").unwrap();
+
+        // Losing the pipe without removing the directory, as a crash would.
+        let path = dir.path().to_path_buf();
+        dir.supervisor.take();
+        for _ in 0..100 {
+            if !path.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(!path.exists());
+    }
+}
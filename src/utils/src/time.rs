@@ -16,6 +16,9 @@ pub enum ClockType {
     ProcessCpu,
     /// Equivalent to `libc::CLOCK_THREAD_CPUTIME_ID`.
     ThreadCpu,
+    /// Equivalent to `libc::CLOCK_BOOTTIME`.
+    #[cfg(target_os = "linux")]
+    Boot,
 }
 
 impl From<ClockType> for libc::clockid_t {
@@ -25,6 +28,8 @@ impl From<ClockType> for libc::clockid_t {
             ClockType::Real => libc::CLOCK_REALTIME,
             ClockType::ProcessCpu => libc::CLOCK_PROCESS_CPUTIME_ID,
             ClockType::ThreadCpu => libc::CLOCK_THREAD_CPUTIME_ID,
+            #[cfg(target_os = "linux")]
+            ClockType::Boot => libc::CLOCK_BOOTTIME,
         }
     }
 }
@@ -19,4 +19,9 @@ pub enum WorkerMessage {
     #[cfg(target_os = "macos")]
     GpuRemoveMapping(crossbeam_channel::Sender<bool>, u64, u64),
     ConvertMemory(crossbeam_channel::Sender<bool>, MemoryProperties),
+    /// Maps `len` bytes at host address `host_addr` at `guest_addr` in the
+    /// guest, replying with the id of the new memory slot.
+    AddMemorySlot(crossbeam_channel::Sender<Option<u32>>, u64, u64, u64),
+    /// Removes the memory slot with the given id.
+    RemoveMemorySlot(crossbeam_channel::Sender<bool>, u32),
 }
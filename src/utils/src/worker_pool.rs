@@ -0,0 +1,481 @@
+//! A pool of threads shared by the device backends, so a microVM with many
+//! devices doesn't end up with dozens of mostly idle host threads.
+//!
+//! The pool is opt-in, with `set_shared_pool_size`: by default each backend
+//! runs on a thread of its own, so one blocked in a slow request (e.g. a
+//! virtio-fs one) can't hold up the queues of the other devices. Backends
+//! start through `run_handler` either way.
+//!
+//! Handlers register the file descriptors they are interested in with the
+//! pool, and a poller thread turns the events on them into jobs for the
+//! workers. The jobs of a given handler never run concurrently, so handlers
+//! don't need any locking of their own. Each worker has its own job queue
+//! and steals work from the others once it runs dry.
+//!
+//! File descriptors are always registered as edge triggered, so handlers
+//! must consume all the pending data of a source before returning. As a
+//! consequence, handlers may also be called for a source on which there is
+//! nothing left to read, and must treat `EAGAIN` as such.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::fd::RawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+
+use log::{debug, error};
+
+use crate::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+
+static SHARED_POOL: OnceLock<WorkerPool> = OnceLock::new();
+static SHARED_POOL_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs the device backends of the process on a shared pool of `workers`
+/// threads, rather than on a thread each. This only has an effect if called
+/// before the first backend is started.
+pub fn set_shared_pool_size(workers: usize) {
+    SHARED_POOL_SIZE.store(workers, Ordering::Relaxed);
+}
+
+/// Returns the pool shared by all the devices of the process, starting it
+/// if needed, or `None` if it isn't enabled.
+fn shared_pool() -> Option<&'static WorkerPool> {
+    match SHARED_POOL_SIZE.load(Ordering::Relaxed) {
+        0 => SHARED_POOL.get(),
+        workers => Some(SHARED_POOL.get_or_init(|| {
+            WorkerPool::new(workers).expect("failed to start the device worker pool")
+        })),
+    }
+}
+
+/// Starts `handler`, to be called whenever any of the events in `fds` is
+/// raised on the corresponding file descriptor, on the shared pool if it's
+/// enabled, or on a thread of its own named `name` otherwise.
+pub fn run_handler(
+    name: &str,
+    fds: &[(RawFd, EventSet)],
+    handler: Box<dyn PoolHandler>,
+) -> io::Result<PoolTask> {
+    match shared_pool() {
+        Some(pool) => pool.register(fds, handler),
+        None => run_dedicated(name, fds, handler),
+    }
+}
+
+/// Runs `handler` on a thread of its own, as the only one of a pool.
+fn run_dedicated(
+    name: &str,
+    fds: &[(RawFd, EventSet)],
+    handler: Box<dyn PoolHandler>,
+) -> io::Result<PoolTask> {
+    let epoll = Epoll::new()?;
+    for &(fd, events) in fds {
+        let event = EpollEvent::new(events | EventSet::EDGE_TRIGGERED, fd as u32 as u64);
+        epoll.ctl(ControlOperation::Add, fd, &event)?;
+    }
+
+    let task = Arc::new(Task {
+        id: 0,
+        fds: fds.iter().map(|&(fd, _)| fd).collect(),
+        handler: Mutex::new(Some(handler)),
+        state: Mutex::new(TaskState::default()),
+        done: Condvar::new(),
+    });
+    let thread_task = task.clone();
+    thread::Builder::new().name(name.into()).spawn(move || {
+        let mut handler = thread_task.handler.lock().unwrap().take().unwrap();
+        let mut events = vec![EpollEvent::default(); thread_task.fds.len().max(1)];
+        let result = panic::catch_unwind(AssertUnwindSafe(|| loop {
+            match epoll.wait(events.len(), -1, &mut events) {
+                Ok(count) => {
+                    for event in &events[..count] {
+                        let source = event.data() as u32 as RawFd;
+                        if !handler.handle_event(source, event.event_set()) {
+                            return;
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    error!("worker pool: failed to wait for events: {e}");
+                    return;
+                }
+            }
+        }));
+        if result.is_err() {
+            error!("worker pool: handler panicked");
+        }
+
+        // As in the pool, the file descriptors must be removed from the
+        // epoll set before the handler closes them.
+        drop(epoll);
+        drop(handler);
+
+        let mut state = thread_task.state.lock().unwrap();
+        state.result = Some(result);
+        thread_task.done.notify_all();
+    })?;
+
+    Ok(PoolTask(task))
+}
+
+pub trait PoolHandler: Send {
+    /// Handles `events` on `source`. Returning `false` removes the handler
+    /// from the pool, which is how devices stop their backend on reset.
+    fn handle_event(&mut self, source: RawFd, events: EventSet) -> bool;
+}
+
+#[derive(Default)]
+struct TaskState {
+    pending: VecDeque<(RawFd, EventSet)>,
+    scheduled: bool,
+    /// Set once the handler has been removed from the pool, with the payload
+    /// of its panic if it panicked.
+    result: Option<thread::Result<()>>,
+}
+
+struct Task {
+    id: u32,
+    fds: Vec<RawFd>,
+    handler: Mutex<Option<Box<dyn PoolHandler>>>,
+    state: Mutex<TaskState>,
+    done: Condvar,
+}
+
+/// A handler registered with a `WorkerPool`.
+pub struct PoolTask(Arc<Task>);
+
+impl PoolTask {
+    /// Waits for the handler to be removed from the pool. As with
+    /// `JoinHandle::join`, an error holds the payload of the panic if the
+    /// handler panicked.
+    pub fn join(self) -> thread::Result<()> {
+        let mut state = self.0.state.lock().unwrap();
+        loop {
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+            state = self.0.done.wait(state).unwrap();
+        }
+    }
+}
+
+struct Shared {
+    epoll: Epoll,
+    tasks: Mutex<HashMap<u32, Arc<Task>>>,
+    next_id: AtomicU32,
+    queues: Vec<Mutex<VecDeque<Arc<Task>>>>,
+    next_queue: AtomicUsize,
+    /// Number of jobs queued and not yet claimed by a worker.
+    queued: Mutex<usize>,
+    wakeup: Condvar,
+}
+
+impl Shared {
+    fn dispatch(&self, data: u64, events: EventSet) {
+        let id = (data >> 32) as u32;
+        let fd = data as u32 as RawFd;
+        let Some(task) = self.tasks.lock().unwrap().get(&id).cloned() else {
+            return;
+        };
+
+        let mut state = task.state.lock().unwrap();
+        if state.result.is_some() {
+            return;
+        }
+        match state.pending.iter_mut().find(|(source, _)| *source == fd) {
+            Some((_, pending)) => *pending |= events,
+            None => state.pending.push_back((fd, events)),
+        }
+        if !state.scheduled {
+            state.scheduled = true;
+            drop(state);
+            self.schedule(task);
+        }
+    }
+
+    fn schedule(&self, task: Arc<Task>) {
+        let index = self.next_queue.fetch_add(1, Ordering::Relaxed) % self.queues.len();
+        let mut queued = self.queued.lock().unwrap();
+        self.queues[index].lock().unwrap().push_back(task);
+        *queued += 1;
+        self.wakeup.notify_one();
+    }
+
+    /// Claims a job, looking first at the queue of `worker` and then
+    /// stealing from the back of the others.
+    fn next_job(&self, worker: usize) -> Arc<Task> {
+        let mut queued = self.queued.lock().unwrap();
+        while *queued == 0 {
+            queued = self.wakeup.wait(queued).unwrap();
+        }
+        *queued -= 1;
+        drop(queued);
+
+        // There are at least as many jobs in the queues as claims, but the
+        // one we saw may be taken by a worker that claimed after us.
+        loop {
+            if let Some(task) = self.queues[worker].lock().unwrap().pop_front() {
+                return task;
+            }
+            for i in 1..self.queues.len() {
+                let victim = (worker + i) % self.queues.len();
+                if let Some(task) = self.queues[victim].lock().unwrap().pop_back() {
+                    return task;
+                }
+            }
+            thread::yield_now();
+        }
+    }
+
+    fn run(&self, task: &Task) {
+        let mut handler = task.handler.lock().unwrap();
+        loop {
+            let (fd, events) = {
+                let mut state = task.state.lock().unwrap();
+                match state.pending.pop_front() {
+                    Some(event) => event,
+                    None => {
+                        state.scheduled = false;
+                        return;
+                    }
+                }
+            };
+            let Some(h) = handler.as_mut() else {
+                return;
+            };
+
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| h.handle_event(fd, events)))
+            {
+                Ok(true) => continue,
+                Ok(false) => Ok(()),
+                Err(payload) => {
+                    error!("worker pool: handler panicked");
+                    Err(payload)
+                }
+            };
+
+            // The file descriptors must be removed from the epoll set before
+            // the handler closes them, as they may have been duplicated.
+            self.tasks.lock().unwrap().remove(&task.id);
+            for &fd in &task.fds {
+                let _ = self
+                    .epoll
+                    .ctl(ControlOperation::Delete, fd, &EpollEvent::default());
+            }
+            *handler = None;
+
+            let mut state = task.state.lock().unwrap();
+            state.pending.clear();
+            state.result = Some(result);
+            task.done.notify_all();
+            return;
+        }
+    }
+}
+
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+}
+
+impl WorkerPool {
+    /// Starts a pool with `workers` threads, plus the poller thread.
+    pub fn new(workers: usize) -> io::Result<Self> {
+        let workers = workers.max(1);
+        let shared = Arc::new(Shared {
+            epoll: Epoll::new()?,
+            tasks: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+            queues: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+            next_queue: AtomicUsize::new(0),
+            queued: Mutex::new(0),
+            wakeup: Condvar::new(),
+        });
+
+        for worker in 0..workers {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("device worker {worker}"))
+                .spawn(move || loop {
+                    let task = shared.next_job(worker);
+                    shared.run(&task);
+                })?;
+        }
+
+        let poller = shared.clone();
+        thread::Builder::new()
+            .name("device poller".into())
+            .spawn(move || {
+                let mut events = vec![EpollEvent::default(); 64];
+                loop {
+                    match poller.epoll.wait(events.len(), -1, &mut events) {
+                        Ok(count) => {
+                            for event in &events[..count] {
+                                poller.dispatch(event.data(), event.event_set());
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => {
+                            error!("worker pool: failed to wait for events: {e}");
+                            break;
+                        }
+                    }
+                }
+            })?;
+
+        debug!("worker pool: started {workers} workers");
+        Ok(WorkerPool { shared })
+    }
+
+    /// Registers `handler` to be called whenever any of the events in `fds`
+    /// is raised on the corresponding file descriptor.
+    pub fn register(
+        &self,
+        fds: &[(RawFd, EventSet)],
+        handler: Box<dyn PoolHandler>,
+    ) -> io::Result<PoolTask> {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let task = Arc::new(Task {
+            id,
+            fds: fds.iter().map(|&(fd, _)| fd).collect(),
+            handler: Mutex::new(Some(handler)),
+            state: Mutex::new(TaskState::default()),
+            done: Condvar::new(),
+        });
+        self.shared.tasks.lock().unwrap().insert(id, task.clone());
+
+        for (i, &(fd, events)) in fds.iter().enumerate() {
+            let data = (u64::from(id) << 32) | u64::from(fd as u32);
+            let event = EpollEvent::new(events | EventSet::EDGE_TRIGGERED, data);
+            if let Err(e) = self.shared.epoll.ctl(ControlOperation::Add, fd, &event) {
+                for &(fd, _) in &fds[..i] {
+                    let _ =
+                        self.shared
+                            .epoll
+                            .ctl(ControlOperation::Delete, fd, &EpollEvent::default());
+                }
+                self.shared.tasks.lock().unwrap().remove(&id);
+                return Err(e);
+            }
+        }
+
+        Ok(PoolTask(task))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::eventfd::{EventFd, EFD_NONBLOCK};
+
+    struct Forwarder {
+        evt: EventFd,
+        stop: EventFd,
+        sender: mpsc::Sender<(usize, u64)>,
+        index: usize,
+    }
+
+    impl PoolHandler for Forwarder {
+        fn handle_event(&mut self, source: RawFd, _events: EventSet) -> bool {
+            if source == self.stop.as_raw_fd() {
+                return false;
+            }
+            if self.index == usize::MAX {
+                panic!("test panic");
+            }
+            match self.evt.read() {
+                Ok(value) => self.sender.send((self.index, value)).unwrap(),
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+            }
+            true
+        }
+    }
+
+    fn register(
+        pool: &WorkerPool,
+        index: usize,
+        sender: &mpsc::Sender<(usize, u64)>,
+    ) -> (EventFd, EventFd, PoolTask) {
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let stop = EventFd::new(EFD_NONBLOCK).unwrap();
+        let fds = [
+            (evt.as_raw_fd(), EventSet::IN),
+            (stop.as_raw_fd(), EventSet::IN),
+        ];
+        let (evt_clone, stop_clone) = (evt.try_clone().unwrap(), stop.try_clone().unwrap());
+        let handler = Forwarder {
+            evt,
+            stop,
+            sender: sender.clone(),
+            index,
+        };
+        let task = pool.register(&fds, Box::new(handler)).unwrap();
+        (evt_clone, stop_clone, task)
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let pool = WorkerPool::new(2).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let (evt0, stop0, task0) = register(&pool, 0, &sender);
+        let (evt1, stop1, task1) = register(&pool, 1, &sender);
+
+        evt1.write(3).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), (1, 3));
+        evt0.write(2).unwrap();
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), (0, 2));
+
+        stop0.write(1).unwrap();
+        task0.join().unwrap();
+        // Events on a handler which has been removed are ignored.
+        evt0.write(1).unwrap();
+        evt1.write(1).unwrap();
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), (1, 1));
+
+        stop1.write(1).unwrap();
+        task1.join().unwrap();
+    }
+
+    #[test]
+    fn test_dedicated() {
+        let (sender, receiver) = mpsc::channel();
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let stop = EventFd::new(EFD_NONBLOCK).unwrap();
+        let fds = [
+            (evt.as_raw_fd(), EventSet::IN),
+            (stop.as_raw_fd(), EventSet::IN),
+        ];
+        let (evt_clone, stop_clone) = (evt.try_clone().unwrap(), stop.try_clone().unwrap());
+        let handler = Forwarder {
+            evt,
+            stop,
+            sender,
+            index: 0,
+        };
+        let task = run_dedicated("test worker", &fds, Box::new(handler)).unwrap();
+
+        evt_clone.write(2).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), (0, 2));
+
+        stop_clone.write(1).unwrap();
+        task.join().unwrap();
+    }
+
+    #[test]
+    fn test_handler_panic() {
+        let pool = WorkerPool::new(1).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let (evt, _stop, task) = register(&pool, usize::MAX, &sender);
+
+        evt.write(1).unwrap();
+        assert!(task.join().is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Process-wide record of the resources used by the workload of the microVM,
+//! as reported by init when it exits, so CI consumers can collect per-job
+//! resource statistics along with the exit code.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Global workload statistics instance.
+pub static WORKLOAD_STATS: WorkloadStats = WorkloadStats::new();
+
+/// Value of the statistics that weren't reported.
+const UNREPORTED: u64 = u64::MAX;
+
+/// Statistics reported by init, in the order it reports them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkloadStat {
+    /// Maximum resident set size of the workload inside the guest, in KiB.
+    MaxRss = 0,
+    /// Time spent by the workload in user mode, in microseconds.
+    UserTime,
+    /// Time spent by the workload in kernel mode, in microseconds.
+    SysTime,
+    /// Time elapsed between the start and the exit of the workload, in
+    /// microseconds.
+    WallTime,
+}
+
+const STATS: [WorkloadStat; 4] = [
+    WorkloadStat::MaxRss,
+    WorkloadStat::UserTime,
+    WorkloadStat::SysTime,
+    WorkloadStat::WallTime,
+];
+
+impl WorkloadStat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WorkloadStat::MaxRss => "max_rss_kib",
+            WorkloadStat::UserTime => "user_time_us",
+            WorkloadStat::SysTime => "sys_time_us",
+            WorkloadStat::WallTime => "wall_time_us",
+        }
+    }
+}
+
+pub struct WorkloadStats {
+    values: [AtomicU64; STATS.len()],
+}
+
+impl WorkloadStats {
+    const fn new() -> Self {
+        WorkloadStats {
+            values: [const { AtomicU64::new(UNREPORTED) }; STATS.len()],
+        }
+    }
+
+    /// Records the value init reported for `stat`.
+    pub fn set(&self, stat: WorkloadStat, value: u64) {
+        self.values[stat as usize].store(value, Ordering::Relaxed);
+    }
+
+    /// Returns the value reported for `stat`, or `None` if it wasn't
+    /// reported.
+    pub fn get(&self, stat: WorkloadStat) -> Option<u64> {
+        match self.values[stat as usize].load(Ordering::Relaxed) {
+            UNREPORTED => None,
+            value => Some(value),
+        }
+    }
+
+    /// Serializes the statistics as a JSON object mapping each of them to
+    /// its value, or `null` if it wasn't reported.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{");
+        for (i, stat) in STATS.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            match self.get(*stat) {
+                Some(value) => write!(out, "\"{}\":{}", stat.name(), value),
+                None => write!(out, "\"{}\":null", stat.name()),
+            }
+            .unwrap();
+        }
+        out.push('}');
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workload_stats() {
+        let stats = WorkloadStats::new();
+        assert_eq!(
+            stats.to_json(),
+            "{\"max_rss_kib\":null,\"user_time_us\":null,\"sys_time_us\":null,\
+             \"wall_time_us\":null}"
+        );
+
+        stats.set(WorkloadStat::MaxRss, 2048);
+        stats.set(WorkloadStat::WallTime, 1500000);
+        assert_eq!(stats.get(WorkloadStat::MaxRss), Some(2048));
+        assert_eq!(stats.get(WorkloadStat::UserTime), None);
+        assert_eq!(
+            stats.to_json(),
+            "{\"max_rss_kib\":2048,\"user_time_us\":null,\"sys_time_us\":null,\
+             \"wall_time_us\":1500000}"
+        );
+    }
+}
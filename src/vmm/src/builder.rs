@@ -24,10 +24,15 @@ use super::{Error, Vmm};
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::legacy::PortIODeviceManager;
 use crate::device_manager::mmio::MMIODeviceManager;
-use crate::resources::{ConsoleType, VmResources};
+#[cfg(not(feature = "tee"))]
+use crate::measured_boot::{MeasuredBootConfig, MEASUREMENT_LOG};
+use crate::resources::{ConsoleType, VmResources, WorkloadStdioConfig, WorkloadTty};
 use crate::vmm_config::external_kernel::{ExternalKernel, KernelFormat};
+use crate::vmm_config::guest_memory::{GuestMemoryBacking, GuestMemoryRegionConfig};
 #[cfg(feature = "net")]
 use crate::vmm_config::net::NetBuilder;
+#[cfg(not(feature = "tee"))]
+use devices::journal::Journal;
 #[cfg(all(target_os = "linux", target_arch = "riscv64"))]
 use devices::legacy::KvmAia;
 #[cfg(target_arch = "x86_64")]
@@ -66,11 +71,15 @@ use crate::vstate::{Error as VstateError, Vcpu, VcpuConfig, Vm};
 use arch::{ArchMemoryInfo, InitrdConfig};
 use device_manager::shm::ShmManager;
 #[cfg(feature = "gpu")]
+use devices::virtio::capture::ScanoutCapture;
+#[cfg(feature = "gpu")]
 use devices::virtio::display::DisplayInfo;
 #[cfg(feature = "gpu")]
 use devices::virtio::display::NoopDisplayBackend;
 #[cfg(not(any(feature = "tee", feature = "nitro")))]
 use devices::virtio::{fs::ExportTable, VirtioShmRegion};
+#[cfg(not(feature = "tee"))]
+use devices::virtio::{InputEventQueue, InputKind};
 use flate2::read::GzDecoder;
 #[cfg(feature = "gpu")]
 use krun_display::DisplayBackend;
@@ -83,18 +92,16 @@ use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
 use linux_loader::loader::{self, KernelLoader};
 use nix::unistd::isatty;
 use polly::event_manager::{Error as EventManagerError, EventManager};
+use utils::boot_timeline::{BootPhase, BootTimeline};
 use utils::eventfd::EventFd;
 use utils::worker_message::WorkerMessage;
-#[cfg(all(target_arch = "x86_64", not(feature = "efi"), not(feature = "tee")))]
 use vm_memory::mmap::MmapRegion;
 #[cfg(not(any(feature = "tee", feature = "nitro")))]
 use vm_memory::Address;
 use vm_memory::Bytes;
 #[cfg(not(feature = "nitro"))]
 use vm_memory::GuestMemory;
-#[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
-use vm_memory::GuestRegionMmap;
-use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap, GuestRegionMmap};
 
 #[cfg(target_arch = "aarch64")]
 #[allow(dead_code)]
@@ -105,12 +112,24 @@ static EDK2_BINARY: &[u8] = include_bytes!("../../../edk2/KRUN_EFI.silent.fd");
 pub enum StartMicrovmError {
     /// Unable to attach block device to Vmm.
     AttachBlockDevice(io::Error),
+    /// Cannot set up core scheduling for the vCPUs.
+    #[cfg(target_os = "linux")]
+    CoreScheduling(io::Error),
     #[cfg(target_os = "macos")]
     /// Failed to create HVF in-kernel IrqChip.
     CreateHvfIrqChip(hvf::Error),
+    /// Cannot create a virtio-input device.
+    #[cfg(not(feature = "tee"))]
+    CreateInputDevice(devices::virtio::input::InputError),
+    /// Cannot create or open the journal of a deterministic run.
+    CreateJournal(io::Error),
     #[cfg(target_os = "linux")]
     /// Failed to create KVM in-kernel IrqChip.
     CreateKvmIrqChip(kvm_ioctls::Error),
+    /// Cannot create a device implemented by a plugin.
+    CreatePluginDevice(devices::virtio::plugin::PluginError),
+    /// Cannot create the socket serving the boot log.
+    CreateBootLog(io::Error),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
     /// Cannot open the file containing the kernel code.
@@ -123,6 +142,8 @@ pub enum StartMicrovmError {
     FirmwareRead(io::Error),
     /// Memory regions are overlapping or mmap fails.
     GuestMemoryMmap(vm_memory::Error),
+    /// Cannot map a guest memory region backed by the embedder.
+    GuestMemoryRegion(vm_memory::mmap::MmapRegionError),
     /// The BZIP2 decoder couldn't decompress the kernel.
     ImageBz2Decoder(io::Error),
     /// Cannot find compressed kernel in file.
@@ -159,12 +180,21 @@ pub enum StartMicrovmError {
     KernelCmdline(String),
     /// The supplied kernel format is not supported.
     KernelFormatUnsupported,
+    /// The kernel doesn't fit in the guest memory at the given load address.
+    KernelLoadAddress(u64),
+    /// The guest can't run a kernel built with this page size.
+    KernelPageSizeUnsupported(usize),
     /// Cannot load command line string.
     LoadCommandline(kernel::cmdline::Error),
+    /// Cannot measure a component of the boot.
+    #[cfg(not(feature = "tee"))]
+    MeasureBoot(io::Error),
     /// The start command was issued more than once.
     MicroVMAlreadyRunning,
     /// Cannot start the VM because the kernel was not configured.
     MissingKernelConfig,
+    /// Nested virtualization was requested but the host doesn't support it.
+    NestedVirtUnsupported,
     /// Cannot start the VM because the size of the guest memory  was not specified.
     MissingMemSizeConfig,
     /// The net device configuration is missing the tap device.
@@ -173,12 +203,17 @@ pub enum StartMicrovmError {
     OpenBlockDevice(io::Error),
     /// Cannot open console output file.
     OpenConsoleFile(io::Error),
+    /// Cannot open or set up a vhost-vdpa device.
+    #[cfg(all(target_os = "linux", not(feature = "tee")))]
+    OpenVdpaDevice(devices::virtio::vdpa::VdpaError),
     /// The GZIP decoder couldn't decompress the kernel.
     PeGzDecoder(io::Error),
     /// Cannot open the file containing the kernel code.
     PeGzOpenKernel(io::Error),
     /// Cannot find compressed kernel in file.
     PeGzInvalid,
+    /// A PMU was requested but the host doesn't support it.
+    PmuUnsupported,
     /// Cannot open the file containing the kernel code.
     RawOpenKernel(io::Error),
     /// Cannot initialize a MMIO Balloon device or add a device to the MMIO Bus.
@@ -196,12 +231,20 @@ pub enum StartMicrovmError {
     RegisterFsSigwinch(kvm_ioctls::Error),
     /// Cannot initialize a MMIO Gpu device or add a device to the MMIO Bus.
     RegisterGpuDevice(device_manager::mmio::Error),
+    /// Cannot initialize a MMIO Input device or add a device to the MMIO Bus.
+    #[cfg(not(feature = "tee"))]
+    RegisterInputDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Network Device or add a device to the MMIO Bus.
     RegisterNetDevice(device_manager::mmio::Error),
+    /// Cannot initialize a MMIO plugin device or add a device to the MMIO Bus.
+    RegisterPluginDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Rng device or add a device to the MMIO Bus.
     RegisterRngDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Snd device or add a device to the MMIO Bus.
     RegisterSndDevice(device_manager::mmio::Error),
+    /// Cannot initialize a MMIO vDPA Device or add a device to the MMIO Bus.
+    #[cfg(all(target_os = "linux", not(feature = "tee")))]
+    RegisterVdpaDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Vsock Device or add a device to the MMIO Bus.
     RegisterVsockDevice(device_manager::mmio::Error),
     /// Cannot attest the VM in the Secure Virtualization context.
@@ -233,14 +276,25 @@ impl Display for StartMicrovmError {
             AttachBlockDevice(ref err) => {
                 write!(f, "Unable to attach block device to Vmm. Error: {err}")
             }
+            #[cfg(target_os = "linux")]
+            CoreScheduling(ref err) => write!(f, "Cannot set up core scheduling: {err}"),
             #[cfg(target_os = "macos")]
             CreateHvfIrqChip(ref err) => {
                 write!(f, "Cannot create HVF in-kernel IrqChip: {err}")
             }
+            #[cfg(not(feature = "tee"))]
+            CreateInputDevice(ref err) => write!(f, "Cannot create the input device. {err:?}"),
+            CreateJournal(ref err) => {
+                write!(f, "Cannot open the journal of the deterministic run: {err}")
+            }
             #[cfg(target_os = "linux")]
             CreateKvmIrqChip(ref err) => {
                 write!(f, "Cannot create KVM in-kernel IrqChip: {err}")
             }
+            CreatePluginDevice(ref err) => {
+                write!(f, "Cannot create the plugin device. {err:?}")
+            }
+            CreateBootLog(ref err) => write!(f, "Cannot create the boot log socket: {err}"),
             CreateRateLimiter(ref err) => write!(f, "Cannot create RateLimiter: {err}"),
             ElfOpenKernel(ref err) => {
                 write!(f, "Cannot open the file containing the kernel code: {err}")
@@ -263,6 +317,9 @@ impl Display for StartMicrovmError {
                 err_msg = err_msg.replace('\"', "");
                 write!(f, "Invalid Memory Configuration: {err_msg}")
             }
+            GuestMemoryRegion(ref err) => {
+                write!(f, "Cannot map guest memory region: {err}")
+            }
             ImageBz2Decoder(ref err) => {
                 write!(f, "The BZIP2 decoder couldn't decompress the kernel. {err}")
             }
@@ -327,13 +384,28 @@ impl Display for StartMicrovmError {
             KernelFormatUnsupported => {
                 write!(f, "The supplied kernel format is not supported.")
             }
+            KernelLoadAddress(addr) => write!(
+                f,
+                "The kernel doesn't fit in the guest memory at 0x{addr:x}."
+            ),
+            KernelPageSizeUnsupported(page_size) => write!(
+                f,
+                "The guest can't run a kernel built with {}K pages.",
+                page_size / 1024
+            ),
             LoadCommandline(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
                 write!(f, "Cannot load command line string. {err_msg}")
             }
+            #[cfg(not(feature = "tee"))]
+            MeasureBoot(ref err) => write!(f, "Cannot measure the boot of the microVM: {err}"),
             MicroVMAlreadyRunning => write!(f, "Microvm already running."),
             MissingKernelConfig => write!(f, "Cannot start microvm without kernel configuration."),
+            NestedVirtUnsupported => write!(
+                f,
+                "Nested virtualization isn't supported or enabled in the host KVM."
+            ),
             MissingMemSizeConfig => {
                 write!(f, "Cannot start microvm without guest mem_size config.")
             }
@@ -352,6 +424,8 @@ impl Display for StartMicrovmError {
 
                 write!(f, "Cannot open the console output file. {err_msg}")
             }
+            #[cfg(all(target_os = "linux", not(feature = "tee")))]
+            OpenVdpaDevice(ref err) => write!(f, "Cannot open the vhost-vdpa device. {err:?}"),
             PeGzDecoder(ref err) => {
                 write!(f, "The GZIP decoder couldn't decompress the kernel. {err}")
             }
@@ -361,6 +435,7 @@ impl Display for StartMicrovmError {
             PeGzInvalid => {
                 write!(f, "Cannot find compressed kernel in file.")
             }
+            PmuUnsupported => write!(f, "The host KVM can't expose a PMU to the guest."),
             RawOpenKernel(ref err) => {
                 write!(f, "Cannot open the file containing the kernel code: {err}")
             }
@@ -417,6 +492,15 @@ impl Display for StartMicrovmError {
                     "Cannot initialize a MMIO Gpu Device or add a device to the MMIO Bus. {err_msg}"
                 )
             }
+            #[cfg(not(feature = "tee"))]
+            RegisterInputDevice(ref err) => {
+                let mut err_msg = format!("{err}");
+                err_msg = err_msg.replace('\"', "");
+                write!(
+                    f,
+                    "Cannot initialize a MMIO Input Device or add a device to the MMIO Bus. {err_msg}"
+                )
+            }
             RegisterNetDevice(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -426,6 +510,15 @@ impl Display for StartMicrovmError {
                     "Cannot initialize a MMIO Network Device or add a device to the MMIO Bus. {err_msg}"
                 )
             }
+            RegisterPluginDevice(ref err) => {
+                let mut err_msg = format!("{err}");
+                err_msg = err_msg.replace('\"', "");
+                write!(
+                    f,
+                    "Cannot initialize a MMIO plugin Device or add a device to the MMIO Bus. \
+                     {err_msg}"
+                )
+            }
             RegisterRngDevice(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -442,6 +535,15 @@ impl Display for StartMicrovmError {
                     "Cannot initialize a MMIO Snd Device or add a device to the MMIO Bus. {err_msg}"
                 )
             }
+            #[cfg(all(target_os = "linux", not(feature = "tee")))]
+            RegisterVdpaDevice(ref err) => {
+                let mut err_msg = format!("{err}");
+                err_msg = err_msg.replace('\"', "");
+                write!(
+                    f,
+                    "Cannot initialize a MMIO vDPA Device or add a device to the MMIO Bus. {err_msg}"
+                )
+            }
             RegisterVsockDevice(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -541,6 +643,7 @@ fn choose_payload(vm_resources: &VmResources) -> Result<Payload, StartMicrovmErr
 ///
 /// An `Arc` reference of the built `Vmm` is also plugged in the `EventManager`, while another
 /// is returned.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 pub fn build_microvm(
     vm_resources: &super::resources::VmResources,
     event_manager: &mut EventManager,
@@ -557,6 +660,7 @@ pub fn build_microvm(
         vm_resources,
         &payload,
     )?;
+    vm_resources.boot_timeline.record(BootPhase::PayloadLoaded);
 
     let vcpu_config = vm_resources.vcpu_config();
 
@@ -591,11 +695,14 @@ pub fn build_microvm(
     }
 
     #[cfg(not(feature = "tee"))]
-    #[allow(unused_mut)]
-    let mut vm = setup_vm(&guest_memory, vm_resources.nested_enabled)?;
+    let mut vm = setup_vm(
+        &guest_memory,
+        vm_resources.nested_enabled,
+        vm_resources.pmu_enabled,
+    )?;
 
     #[cfg(feature = "tee")]
-    let (_kvm, vm) = {
+    let (_kvm, mut vm) = {
         let kvm = KvmContext::new()
             .map_err(Error::KvmContext)
             .map_err(StartMicrovmError::Internal)?;
@@ -608,6 +715,12 @@ pub fn build_microvm(
         )?;
         (kvm, vm)
     };
+    vm.set_metrics(vm_resources.metrics.clone());
+
+    #[cfg(target_os = "linux")]
+    if vm_resources.idle_throttle.is_some() {
+        vm.disable_halt_polling();
+    }
 
     #[cfg(feature = "tee")]
     let tee = vm_resources.tee_config().tee;
@@ -742,6 +855,27 @@ pub fn build_microvm(
         serial_devices.push(setup_serial_device(event_manager, input, output)?);
     }
 
+    // The boot log gets the output of the early console when there's no
+    // serial console for it. The kernel stops using it as soon as the virtio
+    // console is registered.
+    if let Some(path) = &vm_resources.boot_log {
+        if serial_devices.is_empty() {
+            let boot_log =
+                devices::legacy::BootLog::new(path).map_err(StartMicrovmError::CreateBootLog)?;
+            serial_devices.push(setup_serial_device(
+                event_manager,
+                None,
+                Some(Box::new(boot_log)),
+            )?);
+            // The MMIO device manager adds it when registering the UART on
+            // the other architectures.
+            #[cfg(target_arch = "x86_64")]
+            kernel_cmdline.insert("earlycon", "uart8250,io,0x3f8")?;
+        } else {
+            warn!("The early console goes to the serial console, not the boot log");
+        }
+    }
+
     let exit_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK)
         .map_err(Error::EventFd)
         .map_err(StartMicrovmError::Internal)?;
@@ -853,6 +987,12 @@ pub fn build_microvm(
             Arc::new(Mutex::new(gic))
         };
 
+        for vcpu in vcpus.iter() {
+            vcpu.init_pmu()
+                .map_err(Error::Vcpu)
+                .map_err(StartMicrovmError::Internal)?;
+        }
+
         attach_legacy_devices(
             &vm,
             &mut mmio_device_manager,
@@ -922,23 +1062,47 @@ pub fn build_microvm(
     let exit_code = Arc::new(AtomicI32::new(i32::MAX));
 
     let mut vmm = Vmm {
+        console_signals: Vec::new(),
         guest_memory,
         arch_memory_info,
         kernel_cmdline,
         vcpus_handles: Vec::new(),
+        vcpus_held: false,
         exit_evt,
         exit_observers: Vec::new(),
         exit_code: exit_code.clone(),
         vm,
         mmio_device_manager,
+        queue_sizes: vm_resources.queue_sizes.clone(),
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
     };
 
     #[cfg(not(feature = "tee"))]
-    attach_balloon_device(&mut vmm, event_manager, intc.clone())?;
+    let journal = match &vm_resources.deterministic {
+        Some(config) if config.replay => Some(Journal::replay(&config.journal)),
+        Some(config) => Some(Journal::record(&config.journal, config.seed)),
+        None => None,
+    }
+    .transpose()
+    .map_err(StartMicrovmError::CreateJournal)?
+    .map(Arc::new);
+
+    #[cfg(not(feature = "tee"))]
+    attach_balloon_device(
+        &mut vmm,
+        event_manager,
+        intc.clone(),
+        vm_resources.memory_pressure.clone(),
+    )?;
     #[cfg(not(feature = "tee"))]
-    attach_rng_device(&mut vmm, event_manager, intc.clone())?;
+    attach_rng_device(
+        &mut vmm,
+        event_manager,
+        intc.clone(),
+        journal.as_ref().map(|journal| journal.seed()),
+    )?;
+    attach_plugin_devices(&mut vmm, event_manager, intc.clone(), vm_resources)?;
     let mut console_id = 0;
     if !vm_resources.disable_implicit_console {
         attach_console_devices(
@@ -969,6 +1133,16 @@ pub fn build_microvm(
         console_id += 1;
     }
 
+    if let Some(ref workload_stdio) = vm_resources.workload_stdio {
+        attach_workload_stdio_device(
+            &mut vmm,
+            event_manager,
+            intc.clone(),
+            workload_stdio,
+            console_id,
+        )?;
+    }
+
     #[cfg(not(any(feature = "tee", feature = "nitro")))]
     let export_table: Option<ExportTable> = if cfg!(feature = "gpu") {
         Some(Default::default())
@@ -992,6 +1166,7 @@ pub fn build_microvm(
             virgl_flags,
             Box::from(&vm_resources.displays[..]),
             display_backend,
+            vm_resources.scanout_capture.clone(),
             #[cfg(target_os = "macos")]
             _sender.clone(),
         )?;
@@ -1000,11 +1175,13 @@ pub fn build_microvm(
     attach_fs_devices(
         &mut vmm,
         &vm_resources.fs,
+        vm_resources.init_binary.clone(),
         &mut _shm_manager,
         #[cfg(not(feature = "tee"))]
         export_table,
         intc.clone(),
         exit_code,
+        &vm_resources.boot_timeline,
         #[cfg(target_os = "macos")]
         _sender,
     )?;
@@ -1020,8 +1197,29 @@ pub fn build_microvm(
             vmm.kernel_cmdline.insert_str("tsi_hijack")?;
         }
     }
+    #[cfg(all(feature = "net", not(feature = "tee")))]
+    if let Some(journal) = &journal {
+        for net_device in vm_resources.net.list.iter() {
+            net_device.lock().unwrap().set_journal(journal.clone());
+        }
+    }
     #[cfg(feature = "net")]
     attach_net_devices(&mut vmm, &vm_resources.net, intc.clone())?;
+    #[cfg(all(target_os = "linux", not(feature = "tee")))]
+    attach_vdpa_devices(
+        &mut vmm,
+        event_manager,
+        &vm_resources.vdpa_devices,
+        intc.clone(),
+    )?;
+    #[cfg(not(feature = "tee"))]
+    attach_input_devices(
+        &mut vmm,
+        event_manager,
+        &vm_resources.input_devices,
+        intc.clone(),
+        journal.as_ref(),
+    )?;
     #[cfg(feature = "snd")]
     if vm_resources.snd_device {
         attach_snd_device(&mut vmm, intc.clone())?;
@@ -1036,11 +1234,20 @@ pub fn build_microvm(
     #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
     load_cmdline(&vmm)?;
 
+    #[cfg(not(feature = "tee"))]
+    let smbios_oem_strings = match &vm_resources.measured_boot {
+        Some(config) => measure_boot(vm_resources, config, &vmm.kernel_cmdline)?,
+        None => vm_resources.smbios_oem_strings.clone(),
+    };
+    #[cfg(feature = "tee")]
+    let smbios_oem_strings = vm_resources.smbios_oem_strings.clone();
+
     vmm.configure_system(
         vcpus.as_slice(),
         &intc,
         &payload_config.initrd_config,
-        &vm_resources.smbios_oem_strings,
+        &smbios_oem_strings,
+        vm_resources.kaslr_seed.filter(|seed| *seed != 0),
     )
     .map_err(StartMicrovmError::Internal)?;
 
@@ -1078,7 +1285,21 @@ pub fn build_microvm(
         println!("Starting TEE/microVM.");
     }
 
-    vmm.start_vcpus(vcpus)
+    let mut vcpus = vcpus;
+    for vcpu in vcpus.iter_mut() {
+        vcpu.set_boot_timeline(vm_resources.boot_timeline.clone());
+    }
+
+    #[cfg(target_os = "linux")]
+    if crate::linux::core_sched::setup(vm_resources.core_scheduling)
+        .map_err(StartMicrovmError::CoreScheduling)?
+    {
+        for vcpu in vcpus.iter_mut() {
+            vcpu.set_own_core_sched_cookie();
+        }
+    }
+
+    vmm.start_vcpus(vcpus, vm_resources.start_paused)
         .map_err(StartMicrovmError::Internal)?;
 
     // Clippy thinks we don't need Arc<Mutex<...
@@ -1092,11 +1313,48 @@ pub fn build_microvm(
     Ok(vmm)
 }
 
+/// Returns the page size an arm64 Image was built with, if its header says.
+#[cfg(target_arch = "aarch64")]
+fn image_page_size(image: &[u8]) -> Option<usize> {
+    // As per Documentation/arch/arm64/booting.rst.
+    const IMAGE_MAGIC: &[u8] = b"ARM\x64";
+
+    if image.get(56..60)? != IMAGE_MAGIC {
+        return None;
+    }
+    let flags = u64::from_le_bytes(image.get(24..32)?.try_into().ok()?);
+    match (flags >> 1) & 0x3 {
+        1 => Some(0x1000),
+        2 => Some(0x4000),
+        3 => Some(0x1_0000),
+        _ => None,
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn check_kernel_page_size(image: &[u8]) -> std::result::Result<(), StartMicrovmError> {
+    let Some(page_size) = image_page_size(image) else {
+        return Ok(());
+    };
+    debug!("Kernel built with {}K pages", page_size / 1024);
+
+    // Apple Silicon doesn't implement the 64K translation granule.
+    if cfg!(target_os = "macos") && page_size > 0x4000 {
+        return Err(StartMicrovmError::KernelPageSizeUnsupported(page_size));
+    }
+
+    Ok(())
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn load_external_kernel(
     guest_mem: &GuestMemoryMmap,
     arch_mem_info: &ArchMemoryInfo,
     external_kernel: &ExternalKernel,
+    _load_addr: Option<u64>,
 ) -> std::result::Result<(GuestAddress, Option<InitrdConfig>, Option<String>), StartMicrovmError> {
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    let load_addr = GuestAddress(_load_addr.unwrap_or(0x8000_0000));
     let entry_addr = match external_kernel.format {
         // Raw images are treated as bundled kernels on x86_64
         #[cfg(target_arch = "x86_64")]
@@ -1105,8 +1363,12 @@ fn load_external_kernel(
         KernelFormat::Raw => {
             let data: Vec<u8> = std::fs::read(external_kernel.path.clone())
                 .map_err(StartMicrovmError::RawOpenKernel)?;
-            guest_mem.write(&data, GuestAddress(0x8000_0000)).unwrap();
-            GuestAddress(0x8000_0000)
+            #[cfg(target_arch = "aarch64")]
+            check_kernel_page_size(&data)?;
+            guest_mem
+                .write_slice(&data, load_addr)
+                .map_err(|_| StartMicrovmError::KernelLoadAddress(load_addr.raw_value()))?;
+            load_addr
         }
         #[cfg(target_arch = "x86_64")]
         KernelFormat::Elf => {
@@ -1133,10 +1395,12 @@ fn load_external_kernel(
                 let mut kernel_data: Vec<u8> = Vec::new();
                 gz.read_to_end(&mut kernel_data)
                     .map_err(StartMicrovmError::PeGzDecoder)?;
+                #[cfg(target_arch = "aarch64")]
+                check_kernel_page_size(&kernel_data)?;
                 guest_mem
-                    .write(&kernel_data, GuestAddress(0x8000_0000))
-                    .unwrap();
-                GuestAddress(0x8000_0000)
+                    .write_slice(&kernel_data, load_addr)
+                    .map_err(|_| StartMicrovmError::KernelLoadAddress(load_addr.raw_value()))?;
+                load_addr
             } else {
                 return Err(StartMicrovmError::PeGzInvalid);
             }
@@ -1238,6 +1502,7 @@ fn load_external_kernel(
     Ok((entry_addr, initrd_config, external_kernel.cmdline.clone()))
 }
 
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn load_payload(
     _vm_resources: &VmResources,
     guest_mem: GuestMemoryMmap,
@@ -1306,8 +1571,12 @@ fn load_payload(
             ))
         }
         Payload::ExternalKernel(external_kernel) => {
-            let (entry_addr, initrd_config, cmdline) =
-                load_external_kernel(&guest_mem, _arch_mem_info, external_kernel)?;
+            let (entry_addr, initrd_config, cmdline) = load_external_kernel(
+                &guest_mem,
+                _arch_mem_info,
+                external_kernel,
+                _vm_resources.kernel_load_addr,
+            )?;
             Ok((guest_mem, entry_addr, initrd_config, cmdline))
         }
         #[cfg(test)]
@@ -1376,6 +1645,7 @@ struct PayloadConfig {
     kernel_cmdline: Option<String>,
 }
 
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn create_guest_memory(
     mem_size: usize,
     vm_resources: &VmResources,
@@ -1453,8 +1723,11 @@ fn create_guest_memory(
 
     arch_mem_regions.extend(shm_manager.regions());
 
-    let guest_mem = GuestMemoryMmap::from_ranges(&arch_mem_regions)
+    let mut guest_mem = GuestMemoryMmap::from_ranges(&arch_mem_regions)
         .map_err(StartMicrovmError::GuestMemoryMmap)?;
+    for region in vm_resources.guest_memory_regions.iter() {
+        guest_mem = add_guest_memory_region(guest_mem, region)?;
+    }
 
     let (guest_mem, entry_addr, initrd_config, cmdline) =
         load_payload(vm_resources, guest_mem, &arch_mem_info, payload)?;
@@ -1474,7 +1747,86 @@ fn create_guest_memory(
     Ok((guest_mem, arch_mem_info, shm_manager, payload_config))
 }
 
+/// Measures the firmware, kernel, initramfs, command line and disk the
+/// microVM boots, returning the SMBIOS OEM strings to give the guest.
+#[cfg(not(feature = "tee"))]
+fn measure_boot(
+    vm_resources: &VmResources,
+    config: &MeasuredBootConfig,
+    cmdline: &kernel::cmdline::Cmdline,
+) -> std::result::Result<Option<Vec<String>>, StartMicrovmError> {
+    let log = &MEASUREMENT_LOG;
+    #[cfg(feature = "efi")]
+    log.measure("firmware", EDK2_BINARY);
+    #[cfg(not(feature = "efi"))]
+    if let Some(firmware) = &vm_resources.firmware_config {
+        log.measure_file("firmware", &firmware.path)
+            .map_err(StartMicrovmError::MeasureBoot)?;
+    }
+    if let Some(kernel_bundle) = &vm_resources.kernel_bundle {
+        // SAFETY: the bundle stays mapped while the microVM runs.
+        let kernel = unsafe {
+            std::slice::from_raw_parts(kernel_bundle.host_addr as *const u8, kernel_bundle.size)
+        };
+        log.measure("kernel", kernel);
+    }
+    if let Some(external_kernel) = &vm_resources.external_kernel {
+        log.measure_file("kernel", &external_kernel.path)
+            .map_err(StartMicrovmError::MeasureBoot)?;
+        if let Some(initramfs_path) = &external_kernel.initramfs_path {
+            log.measure_file("initramfs", initramfs_path)
+                .map_err(StartMicrovmError::MeasureBoot)?;
+        }
+    }
+    log.measure("cmdline", cmdline.as_str().as_bytes());
+    if let Some(disk) = &config.disk {
+        log.measure_file("disk", disk)
+            .map_err(StartMicrovmError::MeasureBoot)?;
+    }
+
+    let mut oem_strings = vm_resources.smbios_oem_strings.clone();
+    if config.expose_to_guest {
+        oem_strings
+            .get_or_insert_with(Vec::new)
+            .extend(log.oem_strings());
+    }
+    Ok(oem_strings)
+}
+
+fn add_guest_memory_region(
+    guest_mem: GuestMemoryMmap,
+    config: &GuestMemoryRegionConfig,
+) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
+    let mmap_region = match &config.backing {
+        GuestMemoryBacking::File { file, offset } => {
+            let file = file.try_clone().map_err(|e| {
+                StartMicrovmError::GuestMemoryRegion(vm_memory::mmap::MmapRegionError::Mmap(e))
+            })?;
+            MmapRegion::from_file(FileOffset::new(file, *offset), config.size)
+        }
+        // SAFETY: the embedder guarantees the allocation is valid for the
+        // lifetime of the microVM.
+        GuestMemoryBacking::Host(host_addr) => unsafe {
+            MmapRegion::build_raw(
+                *host_addr as *mut u8,
+                config.size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+            )
+        },
+    }
+    .map_err(StartMicrovmError::GuestMemoryRegion)?;
+
+    let region = GuestRegionMmap::new(mmap_region, GuestAddress(config.guest_addr))
+        .map_err(StartMicrovmError::GuestMemoryMmap)?;
+    // Fails if the region overlaps with any other one.
+    guest_mem
+        .insert_region(Arc::new(region))
+        .map_err(StartMicrovmError::GuestMemoryMmap)
+}
+
 #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn load_cmdline(vmm: &Vmm) -> std::result::Result<(), StartMicrovmError> {
     kernel::loader::load_cmdline(
         vmm.guest_memory(),
@@ -1487,13 +1839,21 @@ fn load_cmdline(vmm: &Vmm) -> std::result::Result<(), StartMicrovmError> {
 }
 
 #[cfg(all(target_os = "linux", not(feature = "tee")))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 pub(crate) fn setup_vm(
     guest_memory: &GuestMemoryMmap,
-    _nested_enabled: bool,
+    nested_enabled: bool,
+    pmu_enabled: bool,
 ) -> std::result::Result<Vm, StartMicrovmError> {
     let kvm = KvmContext::new()
         .map_err(Error::KvmContext)
         .map_err(StartMicrovmError::Internal)?;
+    if nested_enabled && !kvm.nested_virt_supported() {
+        return Err(StartMicrovmError::NestedVirtUnsupported);
+    }
+    if pmu_enabled && !kvm.pmu_supported() {
+        return Err(StartMicrovmError::PmuUnsupported);
+    }
     let mut vm = Vm::new(kvm.fd())
         .map_err(Error::Vm)
         .map_err(StartMicrovmError::Internal)?;
@@ -1503,6 +1863,7 @@ pub(crate) fn setup_vm(
     Ok(vm)
 }
 #[cfg(all(target_os = "linux", feature = "tee"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 pub(crate) fn setup_vm(
     kvm: &KvmContext,
     guest_memory: &GuestMemoryMmap,
@@ -1523,9 +1884,11 @@ pub(crate) fn setup_vm(
     Ok(vm)
 }
 #[cfg(target_os = "macos")]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 pub(crate) fn setup_vm(
     guest_memory: &GuestMemoryMmap,
     nested_enabled: bool,
+    _pmu_enabled: bool,
 ) -> std::result::Result<Vm, StartMicrovmError> {
     let mut vm = Vm::new(nested_enabled)
         .map_err(Error::Vm)
@@ -1561,6 +1924,7 @@ pub fn setup_serial_device(
 }
 
 #[cfg(target_arch = "x86_64")]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_legacy_devices(
     vm: &Vm,
     split_irqchip: bool,
@@ -1605,6 +1969,7 @@ fn attach_legacy_devices(
     any(target_arch = "aarch64", target_arch = "riscv64"),
     target_os = "linux"
 ))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_legacy_devices(
     vm: &Vm,
     mmio_device_manager: &mut MMIODeviceManager,
@@ -1628,6 +1993,7 @@ fn attach_legacy_devices(
 }
 
 #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_legacy_devices(
     vm: &Vm,
     mmio_device_manager: &mut MMIODeviceManager,
@@ -1666,6 +2032,7 @@ fn attach_legacy_devices(
 
 #[cfg(target_arch = "x86_64")]
 #[allow(clippy::too_many_arguments)]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn create_vcpus_x86_64(
     vm: &Vm,
     vcpu_config: &VcpuConfig,
@@ -1693,12 +2060,14 @@ fn create_vcpus_x86_64(
         vcpu.configure_x86_64(guest_mem, entry_addr, vcpu_config, kernel_boot)
             .map_err(Error::Vcpu)?;
 
+        vcpu.set_metrics(vm.metrics().clone());
         vcpus.push(vcpu);
     }
     Ok(vcpus)
 }
 
 #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn create_vcpus_aarch64(
     vm: &Vm,
     vcpu_config: &VcpuConfig,
@@ -1715,17 +2084,19 @@ fn create_vcpus_aarch64(
         )
         .map_err(Error::Vcpu)?;
 
-        vcpu.configure_aarch64(vm.fd(), guest_mem, entry_addr)
+        vcpu.configure_aarch64(vm.fd(), guest_mem, entry_addr, vcpu_config)
             .map_err(Error::Vcpu)?;
 
+        vcpu.set_metrics(vm.metrics().clone());
         vcpus.push(vcpu);
     }
     Ok(vcpus)
 }
 
 #[cfg(all(target_arch = "aarch64", target_os = "macos"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn create_vcpus_aarch64(
-    _vm: &Vm,
+    vm: &Vm,
     vcpu_config: &VcpuConfig,
     guest_mem: &GuestMemoryMmap,
     entry_addr: GuestAddress,
@@ -1755,11 +2126,15 @@ fn create_vcpus_aarch64(
         .map_err(Error::Vcpu)?;
 
         vcpu.configure_aarch64(guest_mem).map_err(Error::Vcpu)?;
+        if let Some(threshold) = vcpu_config.idle_throttle {
+            vcpu.set_idle_throttle(threshold);
+        }
 
         if let Some(boot_sender) = boot_sender {
             boot_senders.insert(vcpu.get_mpidr(), boot_sender);
         }
 
+        vcpu.set_metrics(vm.metrics().clone());
         vcpus.push(vcpu);
     }
 
@@ -1769,6 +2144,7 @@ fn create_vcpus_aarch64(
 }
 
 #[cfg(all(target_arch = "riscv64", target_os = "linux"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn create_vcpus_riscv64(
     vm: &Vm,
     vcpu_config: &VcpuConfig,
@@ -1788,6 +2164,7 @@ fn create_vcpus_riscv64(
         vcpu.configure_riscv64(vm.fd(), guest_mem, entry_addr)
             .map_err(Error::Vcpu)?;
 
+        vcpu.set_metrics(vm.metrics().clone());
         vcpus.push(vcpu);
     }
     Ok(vcpus)
@@ -1800,7 +2177,14 @@ fn attach_mmio_device(
     intc: IrqChip,
     device: Arc<Mutex<dyn VirtioDevice>>,
 ) -> std::result::Result<(), device_manager::mmio::Error> {
-    let mmio_device = MmioTransport::new(vmm.guest_memory().clone(), intc, device)?;
+    {
+        let mut device = device.lock().unwrap();
+        if let Some(&size) = vmm.queue_sizes.get(&device.device_type()) {
+            device.set_queue_size(size);
+        }
+    }
+    let mut mmio_device = MmioTransport::new(vmm.guest_memory().clone(), intc, device)?;
+    mmio_device.set_device_id(&id, vmm.vm.metrics());
 
     let type_id = mmio_device.locked_device().device_type();
     let _cmdline = &mut vmm.kernel_cmdline;
@@ -1822,13 +2206,17 @@ fn attach_mmio_device(
 }
 
 #[cfg(not(any(feature = "tee", feature = "nitro")))]
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_fs_devices(
     vmm: &mut Vmm,
     fs_devs: &[FsDeviceConfig],
+    init_binary: Option<Arc<[u8]>>,
     shm_manager: &mut ShmManager,
     #[cfg(not(feature = "tee"))] export_table: Option<ExportTable>,
     intc: IrqChip,
     exit_code: Arc<AtomicI32>,
+    boot_timeline: &Arc<BootTimeline>,
     #[cfg(target_os = "macos")] map_sender: Sender<WorkerMessage>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
@@ -1844,6 +2232,17 @@ fn attach_fs_devices(
         ));
 
         let id = format!("{}{}", String::from(fs.lock().unwrap().id()), i);
+        {
+            let mut fs = fs.lock().unwrap();
+            fs.set_writeback(config.writeback);
+            fs.set_allow_direct_io(config.allow_direct_io);
+            fs.set_fd_cache_size(config.fd_cache_size);
+            fs.set_readonly(config.readonly);
+            fs.set_quota(config.quota);
+            fs.set_threads(config.threads);
+            fs.set_init_binary(init_binary.clone());
+            fs.set_boot_timeline(boot_timeline.clone());
+        }
 
         if let Some(shm_region) = shm_manager.fs_region(i) {
             fs.lock().unwrap().set_shm_region(VirtioShmRegion {
@@ -1871,6 +2270,7 @@ fn attach_fs_devices(
     Ok(())
 }
 
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_console_devices(
     vmm: &mut Vmm,
     event_manager: &mut EventManager,
@@ -1903,14 +2303,19 @@ fn attach_console_devices(
             Some(c) => (c.input_fd, c.output_fd, c.err_fd),
             None => (STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO),
         };
-        let stdin_is_terminal =
-            isatty(unsafe { BorrowedFd::borrow_raw(input_fd) }).unwrap_or(false);
-        let stdout_is_terminal =
-            isatty(unsafe { BorrowedFd::borrow_raw(output_fd) }).unwrap_or(false);
-        let stderr_is_terminal = isatty(unsafe { BorrowedFd::borrow_raw(err_fd) }).unwrap_or(false);
-
-        if let Err(e) = term_set_raw_mode(!stdin_is_terminal) {
-            log::error!("Failed to set terminal to raw mode: {e}")
+        let is_terminal = |fd| match vm_resources.workload_tty {
+            WorkloadTty::Auto => isatty(unsafe { BorrowedFd::borrow_raw(fd) }).unwrap_or(false),
+            WorkloadTty::Enabled => true,
+            WorkloadTty::Disabled => false,
+        };
+        let stdin_is_terminal = is_terminal(input_fd);
+        let stdout_is_terminal = is_terminal(output_fd);
+        let stderr_is_terminal = is_terminal(err_fd);
+
+        if vm_resources.workload_tty != WorkloadTty::Disabled {
+            if let Err(e) = term_set_raw_mode(!stdin_is_terminal) {
+                log::error!("Failed to set terminal to raw mode: {e}")
+            }
         }
 
         let console_input = if stdin_is_terminal {
@@ -1924,7 +2329,8 @@ fn attach_console_devices(
             {
                 let sigint_input = port_io::PortInputSigInt::new();
                 let sigint_input_fd = sigint_input.sigint_evt().as_raw_fd();
-                register_sigint_handler(sigint_input_fd).map_err(RegisterFsSigwinch)?;
+                vmm.console_signals
+                    .push(register_sigint_handler(sigint_input_fd).map_err(RegisterFsSigwinch)?);
                 Some(Box::new(sigint_input) as _)
             }
             #[cfg(not(target_os = "linux"))]
@@ -1957,21 +2363,29 @@ fn attach_console_devices(
             output: console_err,
         });
 
-        if !stdin_is_terminal && input_fd == STDIN_FILENO {
+        // Streams redirected with krun_set_workload_stdio() get their own
+        // ports, in a separate device.
+        let workload_stdio = vm_resources.workload_stdio.unwrap_or(WorkloadStdioConfig {
+            input_fd: -1,
+            output_fd: -1,
+            err_fd: -1,
+        });
+
+        if !stdin_is_terminal && input_fd == STDIN_FILENO && workload_stdio.input_fd < 0 {
             ports.push(PortDescription::InputPipe {
                 name: "krun-stdin".into(),
                 input: port_io::stdin().unwrap(),
             })
         }
 
-        if !stdout_is_terminal && output_fd == STDOUT_FILENO {
+        if !stdout_is_terminal && output_fd == STDOUT_FILENO && workload_stdio.output_fd < 0 {
             ports.push(PortDescription::OutputPipe {
                 name: "krun-stdout".into(),
                 output: port_io::stdout().unwrap(),
             })
         };
 
-        if !stderr_is_terminal && err_fd == STDERR_FILENO {
+        if !stderr_is_terminal && err_fd == STDERR_FILENO && workload_stdio.err_fd < 0 {
             ports.push(PortDescription::OutputPipe {
                 name: "krun-stderr".into(),
                 output: port_io::stderr().unwrap(),
@@ -1990,8 +2404,61 @@ fn attach_console_devices(
         .map_err(RegisterEvent)?;
 
     #[cfg(target_os = "linux")]
-    register_sigwinch_handler(console.lock().unwrap().get_sigwinch_fd())
-        .map_err(RegisterFsSigwinch)?;
+    vmm.console_signals.push(
+        register_sigwinch_handler(console.lock().unwrap().get_sigwinch_fd())
+            .map_err(RegisterFsSigwinch)?,
+    );
+
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_mmio_device(vmm, format!("hvc{id_number}"), intc, console)
+        .map_err(RegisterConsoleDevice)?;
+
+    Ok(())
+}
+
+/// Attaches a virtio-console device with the ports init redirects the stdio
+/// of the workload to.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+fn attach_workload_stdio_device(
+    vmm: &mut Vmm,
+    event_manager: &mut EventManager,
+    intc: IrqChip,
+    cfg: &WorkloadStdioConfig,
+    id_number: u32,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    // The first port must be a console, but nothing is connected to it.
+    let mut ports = vec![PortDescription::Console {
+        input: None,
+        output: None,
+    }];
+    if cfg.input_fd >= 0 {
+        ports.push(PortDescription::InputPipe {
+            name: "krun-stdin".into(),
+            input: port_io::input_to_raw_fd_dup(cfg.input_fd).unwrap(),
+        });
+    }
+    if cfg.output_fd >= 0 {
+        ports.push(PortDescription::OutputPipe {
+            name: "krun-stdout".into(),
+            output: port_io::output_to_raw_fd_dup(cfg.output_fd).unwrap(),
+        });
+    }
+    if cfg.err_fd >= 0 {
+        ports.push(PortDescription::OutputPipe {
+            name: "krun-stderr".into(),
+            output: port_io::output_to_raw_fd_dup(cfg.err_fd).unwrap(),
+        });
+    }
+
+    let console = Arc::new(Mutex::new(devices::virtio::Console::new(ports).unwrap()));
+
+    vmm.exit_observers.push(console.clone());
+
+    event_manager
+        .add_subscriber(console.clone())
+        .map_err(RegisterEvent)?;
 
     // The device mutex mustn't be locked here otherwise it will deadlock.
     attach_mmio_device(vmm, format!("hvc{id_number}"), intc, console)
@@ -2001,6 +2468,7 @@ fn attach_console_devices(
 }
 
 #[cfg(feature = "net")]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_net_devices(
     vmm: &mut Vmm,
     net_devices: &NetBuilder,
@@ -2015,6 +2483,67 @@ fn attach_net_devices(
     Ok(())
 }
 
+#[cfg(all(target_os = "linux", not(feature = "tee")))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+fn attach_vdpa_devices(
+    vmm: &mut Vmm,
+    event_manager: &mut EventManager,
+    vdpa_devices: &[PathBuf],
+    intc: IrqChip,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    for (index, path) in vdpa_devices.iter().enumerate() {
+        let vdpa = Arc::new(Mutex::new(
+            devices::virtio::Vdpa::new(format!("vdpa{index}"), path).map_err(OpenVdpaDevice)?,
+        ));
+
+        event_manager
+            .add_subscriber(vdpa.clone())
+            .map_err(RegisterEvent)?;
+
+        let id = String::from(vdpa.lock().unwrap().id());
+
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_mmio_device(vmm, id, intc.clone(), vdpa).map_err(RegisterVdpaDevice)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "tee"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+fn attach_input_devices(
+    vmm: &mut Vmm,
+    event_manager: &mut EventManager,
+    input_devices: &[(InputKind, Arc<InputEventQueue>)],
+    intc: IrqChip,
+    journal: Option<&Arc<Journal>>,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    for (index, (kind, events)) in input_devices.iter().enumerate() {
+        let mut input = devices::virtio::Input::new(format!("input{index}"), *kind, events.clone())
+            .map_err(CreateInputDevice)?;
+        if let Some(journal) = journal {
+            input.set_journal(journal.clone());
+        }
+        let input = Arc::new(Mutex::new(input));
+
+        event_manager
+            .add_subscriber(input.clone())
+            .map_err(RegisterEvent)?;
+
+        let id = String::from(input.lock().unwrap().id());
+
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_mmio_device(vmm, id, intc.clone(), input).map_err(RegisterInputDevice)?;
+    }
+
+    Ok(())
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_unixsock_vsock_device(
     vmm: &mut Vmm,
     unix_vsock: &Arc<Mutex<Vsock>>,
@@ -2027,7 +2556,11 @@ fn attach_unixsock_vsock_device(
         .add_subscriber(unix_vsock.clone())
         .map_err(RegisterEvent)?;
 
-    let id = String::from(unix_vsock.lock().unwrap().id());
+    let id = {
+        let mut vsock = unix_vsock.lock().unwrap();
+        vsock.set_metrics(vmm.vm.metrics().clone());
+        String::from(vsock.id())
+    };
 
     // The device mutex mustn't be locked here otherwise it will deadlock.
     attach_mmio_device(vmm, id, intc, unix_vsock.clone()).map_err(RegisterVsockDevice)?;
@@ -2036,14 +2569,20 @@ fn attach_unixsock_vsock_device(
 }
 
 #[cfg(not(feature = "tee"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_balloon_device(
     vmm: &mut Vmm,
     event_manager: &mut EventManager,
     intc: IrqChip,
+    memory_pressure: Option<devices::virtio::MemoryPressureConfig>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
-    let balloon = Arc::new(Mutex::new(devices::virtio::Balloon::new().unwrap()));
+    let mut balloon = devices::virtio::Balloon::new().unwrap();
+    if let Some(config) = memory_pressure {
+        balloon.set_memory_pressure(config);
+    }
+    let balloon = Arc::new(Mutex::new(balloon));
 
     event_manager
         .add_subscriber(balloon.clone())
@@ -2058,6 +2597,7 @@ fn attach_balloon_device(
 }
 
 #[cfg(feature = "blk")]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_block_devices(
     vmm: &mut Vmm,
     block_devs: &BlockBuilder,
@@ -2076,14 +2616,20 @@ fn attach_block_devices(
 }
 
 #[cfg(not(feature = "tee"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_rng_device(
     vmm: &mut Vmm,
     event_manager: &mut EventManager,
     intc: IrqChip,
+    seed: Option<u64>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
-    let rng = Arc::new(Mutex::new(devices::virtio::Rng::new().unwrap()));
+    let rng = match seed {
+        Some(seed) => devices::virtio::Rng::with_seed(seed),
+        None => devices::virtio::Rng::new(),
+    };
+    let rng = Arc::new(Mutex::new(rng.unwrap()));
 
     event_manager
         .add_subscriber(rng.clone())
@@ -2097,8 +2643,37 @@ fn attach_rng_device(
     Ok(())
 }
 
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+fn attach_plugin_devices(
+    vmm: &mut Vmm,
+    event_manager: &mut EventManager,
+    intc: IrqChip,
+    vm_resources: &VmResources,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    for (index, plugin) in vm_resources.virtio_plugins.iter().enumerate() {
+        let device = Arc::new(Mutex::new(
+            devices::virtio::PluginDevice::new(format!("virtio_plugin{index}"), plugin.clone())
+                .map_err(CreatePluginDevice)?,
+        ));
+
+        event_manager
+            .add_subscriber(device.clone())
+            .map_err(RegisterEvent)?;
+
+        let id = String::from(device.lock().unwrap().id());
+
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_mmio_device(vmm, id, intc.clone(), device).map_err(RegisterPluginDevice)?;
+    }
+
+    Ok(())
+}
+
 #[cfg(feature = "gpu")]
 #[allow(clippy::too_many_arguments)]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_gpu_device(
     vmm: &mut Vmm,
     event_manager: &mut EventManager,
@@ -2108,6 +2683,7 @@ fn attach_gpu_device(
     virgl_flags: u32,
     displays: Box<[DisplayInfo]>,
     display_backend: DisplayBackend<'static>,
+    capture: Option<Arc<ScanoutCapture>>,
     #[cfg(target_os = "macos")] map_sender: Sender<WorkerMessage>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
@@ -2145,6 +2721,10 @@ fn attach_gpu_device(
         gpu.lock().unwrap().set_export_table(export_table);
     }
 
+    if let Some(capture) = capture {
+        gpu.lock().unwrap().set_capture(capture);
+    }
+
     // The device mutex mustn't be locked here otherwise it will deadlock.
     attach_mmio_device(vmm, id, intc, gpu).map_err(RegisterGpuDevice)?;
 
@@ -2152,6 +2732,7 @@ fn attach_gpu_device(
 }
 
 #[cfg(feature = "snd")]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
 fn attach_snd_device(vmm: &mut Vmm, intc: IrqChip) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
@@ -2195,11 +2776,15 @@ pub mod tests {
             vcpu_count,
             ht_enabled: false,
             cpu_template: None,
+            clock: Default::default(),
+            nested: false,
+            pmu: false,
+            deterministic: false,
         };
 
         let (guest_memory, _arch_memory_info, _shm_manager, _payload_config) =
             default_guest_memory(128).unwrap();
-        let vm = setup_vm(&guest_memory, false).unwrap();
+        let vm = setup_vm(&guest_memory, false, false).unwrap();
         let _kvmioapic = KvmIoapic::new(&vm.fd()).unwrap();
 
         // Dummy entry_addr, vcpus will not boot.
@@ -2218,18 +2803,38 @@ pub mod tests {
         assert_eq!(vcpu_vec.len(), vcpu_count as usize);
     }
 
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_image_page_size() {
+        let mut image = vec![0u8; 64];
+        assert_eq!(image_page_size(&image), None);
+
+        image[56..60].copy_from_slice(b"ARM\x64");
+        assert_eq!(image_page_size(&image), None);
+        image[24] = 0b011;
+        assert_eq!(image_page_size(&image), Some(0x1000));
+        image[24] = 0b101;
+        assert_eq!(image_page_size(&image), Some(0x4000));
+        image[24] = 0b111;
+        assert_eq!(image_page_size(&image), Some(0x1_0000));
+        assert_eq!(image_page_size(&image[..32]), None);
+    }
+
     #[test]
     #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
     fn test_create_vcpus_aarch64() {
         let (guest_memory, _arch_memory_info) =
             create_guest_memory(128, None, Payload::Empty).unwrap();
-        let vm = setup_vm(&guest_memory, false).unwrap();
+        let vm = setup_vm(&guest_memory, false, false).unwrap();
         let vcpu_count = 2;
 
         let vcpu_config = VcpuConfig {
             vcpu_count,
             ht_enabled: false,
             cpu_template: None,
+            nested: false,
+            pmu: false,
+            deterministic: false,
         };
 
         // Dummy entry_addr, vcpus will not boot.
@@ -2251,6 +2856,18 @@ pub mod tests {
         let err = AttachBlockDevice(io::Error::from_raw_os_error(0));
         let _ = format!("{err}{err:?}");
 
+        #[cfg(target_os = "linux")]
+        {
+            let err = CoreScheduling(io::Error::from_raw_os_error(0));
+            let _ = format!("{err}{err:?}");
+        }
+
+        let err = CreateJournal(io::Error::from_raw_os_error(0));
+        let _ = format!("{err}{err:?}");
+
+        let err = CreateBootLog(io::Error::from_raw_os_error(0));
+        let _ = format!("{err}{err:?}");
+
         let err = CreateRateLimiter(io::Error::from_raw_os_error(0));
         let _ = format!("{err}{err:?}");
 
@@ -2263,6 +2880,12 @@ pub mod tests {
         let err = KernelCmdline(String::from("dummy --cmdline"));
         let _ = format!("{err}{err:?}");
 
+        let err = KernelLoadAddress(0x8000_0000);
+        let _ = format!("{err}{err:?}");
+
+        let err = KernelPageSizeUnsupported(0x1_0000);
+        let _ = format!("{err}{err:?}");
+
         let err = LoadCommandline(kernel::cmdline::Error::TooLarge);
         let _ = format!("{err}{err:?}");
 
@@ -2272,6 +2895,12 @@ pub mod tests {
         let err = MissingKernelConfig;
         let _ = format!("{err}{err:?}");
 
+        let err = NestedVirtUnsupported;
+        let _ = format!("{err}{err:?}");
+
+        let err = PmuUnsupported;
+        let _ = format!("{err}{err:?}");
+
         let err = MissingMemSizeConfig;
         let _ = format!("{err}{err:?}");
 
@@ -2302,6 +2931,38 @@ pub mod tests {
         let _ = format!("{err}{err:?}");
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_add_guest_memory_region() {
+        use std::os::fd::FromRawFd;
+        use std::os::unix::fs::FileExt;
+
+        let (guest_mem, _, _, _) = default_guest_memory(128).unwrap();
+        let file = unsafe {
+            std::fs::File::from_raw_fd(libc::memfd_create(c"region".as_ptr(), libc::MFD_CLOEXEC))
+        };
+        file.set_len(0x2000).unwrap();
+        let region = |guest_addr| GuestMemoryRegionConfig {
+            guest_addr,
+            size: 0x1000,
+            backing: GuestMemoryBacking::File {
+                file: file.try_clone().unwrap(),
+                offset: 0x1000,
+            },
+        };
+
+        // Overlapping with RAM.
+        assert!(add_guest_memory_region(guest_mem.clone(), &region(0)).is_err());
+
+        let guest_mem = add_guest_memory_region(guest_mem, &region(0x2_0000_0000)).unwrap();
+        guest_mem
+            .write_obj(0xdead_beef_u32, GuestAddress(0x2_0000_0010))
+            .unwrap();
+        let mut buf = [0u8; 4];
+        file.read_exact_at(&mut buf, 0x1010).unwrap();
+        assert_eq!(u32::from_ne_bytes(buf), 0xdead_beef);
+    }
+
     #[test]
     fn test_kernel_cmdline_err_to_startuvm_err() {
         let err = StartMicrovmError::from(kernel::cmdline::Error::HasSpace);
@@ -74,8 +74,12 @@ type Result<T> = ::std::result::Result<T, Error>;
 /// This represents the size of the mmio device specified to the kernel as a cmdline option
 /// It has to be larger than 0x100 (the offset where the configuration space starts from
 /// the beginning of the memory mapped device registers) + the size of the configuration space
-/// Currently hardcoded to 4K.
+/// Currently hardcoded to 4K, or to a 64K page on aarch64 so guests can map each device
+/// whatever their page size.
+#[cfg(not(target_arch = "aarch64"))]
 const MMIO_LEN: u64 = 0x1000;
+#[cfg(target_arch = "aarch64")]
+const MMIO_LEN: u64 = arch::aarch64::layout::GUEST_PAGE_SIZE_MAX as u64;
 
 /// Manages the complexities of registering a MMIO device.
 pub struct MMIODeviceManager {
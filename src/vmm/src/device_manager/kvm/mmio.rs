@@ -72,8 +72,12 @@ type Result<T> = ::std::result::Result<T, Error>;
 /// This represents the size of the mmio device specified to the kernel as a cmdline option
 /// It has to be larger than 0x100 (the offset where the configuration space starts from
 /// the beginning of the memory mapped device registers) + the size of the configuration space
-/// Currently hardcoded to 4K.
+/// Currently hardcoded to 4K, or to a 64K page on aarch64 so guests can map each device
+/// whatever their page size.
+#[cfg(not(target_arch = "aarch64"))]
 const MMIO_LEN: u64 = 0x1000;
+#[cfg(target_arch = "aarch64")]
+const MMIO_LEN: u64 = arch::aarch64::layout::GUEST_PAGE_SIZE_MAX as u64;
 
 /// Manages the complexities of registering a MMIO device.
 pub struct MMIODeviceManager {
@@ -432,7 +436,7 @@ mod tests {
         let guest_mem =
             GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
         let vcpu_count: u8 = 1;
-        let vm = builder::setup_vm(&guest_mem, false).unwrap();
+        let vm = builder::setup_vm(&guest_mem, false, false).unwrap();
         let mut device_manager =
             MMIODeviceManager::new(&mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
         let _kvmioapic = KvmIoapic::new(vm.fd()).unwrap();
@@ -452,7 +456,7 @@ mod tests {
         let guest_mem =
             GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
         let vcpu_count: u8 = 1;
-        let vm = builder::setup_vm(&guest_mem, false).unwrap();
+        let vm = builder::setup_vm(&guest_mem, false, false).unwrap();
         let mut device_manager =
             MMIODeviceManager::new(&mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
         let _kvmioapic = KvmIoapic::new(vm.fd()).unwrap();
@@ -553,7 +557,7 @@ mod tests {
         let guest_mem =
             GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
         let vcpu_count = 1;
-        let vm = builder::setup_vm(&guest_mem, false).unwrap();
+        let vm = builder::setup_vm(&guest_mem, false, false).unwrap();
         let mut device_manager =
             MMIODeviceManager::new(&mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
         let mut cmdline = kernel_cmdline::Cmdline::new(4096);
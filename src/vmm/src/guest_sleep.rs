@@ -0,0 +1,134 @@
+//! Coordination between the vCPU that puts the guest to sleep, through PSCI
+//! SYSTEM_SUSPEND, and whoever wakes it up later.
+//!
+//! The suspending vCPU blocks in `GuestSleep::suspend` until `wake` is
+//! called, and then resumes the guest at the entry point it asked for. The
+//! other vCPUs were already powered off by the guest before suspending.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SleepState {
+    Awake,
+    /// The host asked the guest to suspend, but it hasn't done it yet.
+    Suspending,
+    Suspended,
+}
+
+struct Inner {
+    state: SleepState,
+    /// The guest was woken up before it got to suspend.
+    wake_pending: bool,
+}
+
+pub struct GuestSleep {
+    inner: Mutex<Inner>,
+    changed: Condvar,
+}
+
+pub static GUEST_SLEEP: GuestSleep = GuestSleep::new();
+
+impl GuestSleep {
+    const fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                state: SleepState::Awake,
+                wake_pending: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn state(&self) -> SleepState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Records that the guest was asked to suspend, so a `wake` racing with
+    /// the guest getting there isn't lost.
+    pub fn request_suspend(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == SleepState::Awake {
+            inner.state = SleepState::Suspending;
+            inner.wake_pending = false;
+        }
+    }
+
+    /// Called from the vCPU suspending the guest. Blocks until `wake` is
+    /// called, unless it already was since `request_suspend`.
+    pub fn suspend(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if std::mem::take(&mut inner.wake_pending) {
+            return;
+        }
+        inner.state = SleepState::Suspended;
+        self.changed.notify_all();
+        info!("guest suspended");
+
+        let _inner = self
+            .changed
+            .wait_while(inner, |inner| inner.state != SleepState::Awake)
+            .unwrap();
+        info!("guest resumed");
+    }
+
+    /// Wakes the guest up. Returns whether it was suspended or about to.
+    pub fn wake(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            SleepState::Awake => return false,
+            SleepState::Suspending => inner.wake_pending = true,
+            SleepState::Suspended => {}
+        }
+        inner.state = SleepState::Awake;
+        self.changed.notify_all();
+        true
+    }
+
+    /// Waits up to `timeout` for the guest to finish suspending.
+    pub fn wait_suspended(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.inner.lock().unwrap();
+        while inner.state != SleepState::Suspended {
+            let now = Instant::now();
+            if inner.state == SleepState::Awake || now >= deadline {
+                return false;
+            }
+            inner = self.changed.wait_timeout(inner, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_suspend_wake() {
+        let sleep = Arc::new(GuestSleep::new());
+        assert!(!sleep.wake());
+
+        sleep.request_suspend();
+        assert_eq!(sleep.state(), SleepState::Suspending);
+        let vcpu = {
+            let sleep = sleep.clone();
+            thread::spawn(move || sleep.suspend())
+        };
+        assert!(sleep.wait_suspended(Duration::from_secs(10)));
+        assert!(sleep.wake());
+        vcpu.join().unwrap();
+        assert_eq!(sleep.state(), SleepState::Awake);
+
+        // Nothing to wait for if the guest was woken up in the meantime.
+        sleep.request_suspend();
+        assert!(sleep.wake());
+        assert!(!sleep.wait_suspended(Duration::from_secs(10)));
+        // So the guest getting there later doesn't block.
+        sleep.suspend();
+        assert_eq!(sleep.state(), SleepState::Awake);
+    }
+}
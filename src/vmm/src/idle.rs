@@ -0,0 +1,89 @@
+//! Detection of guests staying idle for long, to make their vCPUs wake up
+//! less often.
+//!
+//! A vCPU is idle over a window when it spent most of it waiting for an
+//! interrupt. Once it has been idle for longer than the configured threshold,
+//! its timer wake ups get a growing slack, so the guest ticks coalesce into
+//! fewer and longer host sleeps. Interrupts from devices still wake it up
+//! right away, and the first busy window drops the slack.
+
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_millis(500);
+/// Share of a window the vCPU must spend waiting to be considered idle.
+const IDLE_RESIDENCY_PERCENT: u32 = 95;
+const MAX_SLACK: Duration = Duration::from_millis(50);
+
+pub struct IdleTracker {
+    threshold: Duration,
+    window_start: Instant,
+    window_idle: Duration,
+    idle_since: Option<Instant>,
+}
+
+impl IdleTracker {
+    pub fn new(threshold: Duration, now: Instant) -> Self {
+        Self {
+            threshold,
+            window_start: now,
+            window_idle: Duration::ZERO,
+            idle_since: None,
+        }
+    }
+
+    /// Records that the vCPU waited for an interrupt for `waited`, until
+    /// `now`.
+    pub fn record_wait(&mut self, waited: Duration, now: Instant) {
+        self.window_idle += waited;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < WINDOW {
+            return;
+        }
+
+        if self.window_idle * 100 >= elapsed * IDLE_RESIDENCY_PERCENT {
+            self.idle_since.get_or_insert(self.window_start);
+        } else {
+            self.idle_since = None;
+        }
+        self.window_start = now;
+        self.window_idle = Duration::ZERO;
+    }
+
+    /// Returns how much later than its timer asks the vCPU may wake up.
+    pub fn slack(&self, now: Instant) -> Duration {
+        let Some(idle_since) = self.idle_since else {
+            return Duration::ZERO;
+        };
+        let idle_for = now.saturating_duration_since(idle_since);
+        // Ramp up, so the guest isn't slowed down much if it only stays idle
+        // a bit longer than the threshold.
+        (idle_for.saturating_sub(self.threshold) / 10).min(MAX_SLACK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_slack() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut idle = IdleTracker::new(ms(1000), start);
+
+        // Mostly waiting, but not idle for long enough yet.
+        idle.record_wait(ms(490), start + ms(500));
+        assert_eq!(idle.slack(start + ms(500)), Duration::ZERO);
+        idle.record_wait(ms(499), start + ms(1000));
+        assert_eq!(idle.slack(start + ms(1000)), Duration::ZERO);
+
+        idle.record_wait(ms(500), start + ms(1500));
+        assert_eq!(idle.slack(start + ms(1500)), ms(50));
+        assert_eq!(idle.slack(start + ms(1200)), ms(20));
+        assert_eq!(idle.slack(start + ms(10000)), MAX_SLACK);
+
+        // A busy window starts over.
+        idle.record_wait(ms(100), start + ms(2000));
+        assert_eq!(idle.slack(start + ms(2000)), Duration::ZERO);
+    }
+}
@@ -16,6 +16,12 @@ extern crate log;
 /// Handles setup and initialization a `Vmm` object.
 pub mod builder;
 pub(crate) mod device_manager;
+/// Guest suspend and resume coordination.
+pub mod guest_sleep;
+#[cfg(any(target_os = "macos", test))]
+mod idle;
+/// Measurements of what the microVM boots.
+pub mod measured_boot;
 /// Resource store for configured microVM resources.
 pub mod resources;
 /// Signal handling utilities.
@@ -28,14 +34,18 @@ pub mod vmm_config;
 mod linux;
 #[cfg(target_os = "linux")]
 use crate::linux::vstate;
+#[cfg(target_os = "linux")]
+pub use crate::linux::vstate::nested_virt_supported;
 #[cfg(target_os = "macos")]
 mod macos;
+mod memory_slots;
 mod terminal;
 pub mod worker;
 
 #[cfg(target_os = "macos")]
 use macos::vstate;
 
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::os::unix::io::AsRawFd;
@@ -125,6 +135,8 @@ pub enum Error {
     VcpuEvent(vstate::Error),
     /// Cannot create a vCPU handle.
     VcpuHandle(vstate::Error),
+    /// vCPU pause failed.
+    VcpuPause,
     /// vCPU resume failed.
     VcpuResume,
     /// Cannot spawn a new Vcpu thread.
@@ -161,6 +173,7 @@ impl Display for Error {
             Vcpu(e) => write!(f, "Vcpu error: {e}"),
             VcpuEvent(e) => write!(f, "Cannot send event to vCPU. {e:?}"),
             VcpuHandle(e) => write!(f, "Cannot create a vCPU handle. {e}"),
+            VcpuPause => write!(f, "vCPUs pause failed."),
             VcpuResume => write!(f, "vCPUs resume failed."),
             VcpuSpawn(e) => write!(f, "Cannot spawn Vcpu thread: {e}"),
             Vm(e) => write!(f, "Vm error: {e}"),
@@ -192,6 +205,10 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 /// Contains the state and associated methods required for the Firecracker VMM.
 pub struct Vmm {
+    // The consoles notified of host signals, first so they're removed before
+    // the devices close their fds.
+    console_signals: Vec<signal_handler::ConsoleSignalGuard>,
+
     // Guest VM core resources.
     guest_memory: GuestMemoryMmap,
     arch_memory_info: ArchMemoryInfo,
@@ -199,6 +216,8 @@ pub struct Vmm {
     kernel_cmdline: KernelCmdline,
 
     vcpus_handles: Vec<VcpuHandle>,
+    // Whether the vCPUs were started held, and not released yet.
+    vcpus_held: bool,
     exit_evt: EventFd,
     vm: Vm,
     exit_observers: Vec<Arc<Mutex<dyn VmmExitObserver>>>,
@@ -206,6 +225,8 @@ pub struct Vmm {
 
     // Guest VM devices.
     mmio_device_manager: MMIODeviceManager,
+    // Queue sizes overriding the defaults, by virtio device type.
+    queue_sizes: HashMap<u32, u16>,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
 }
@@ -220,8 +241,10 @@ impl Vmm {
         self.mmio_device_manager.get_device(device_type, device_id)
     }
 
-    /// Starts the microVM vcpus.
-    pub fn start_vcpus(&mut self, mut vcpus: Vec<Vcpu>) -> Result<()> {
+    /// Starts the microVM vcpus, holding them before their first instruction
+    /// until `release_vcpus` is called if `held`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn start_vcpus(&mut self, mut vcpus: Vec<Vcpu>, held: bool) -> Result<()> {
         let vcpu_count = vcpus.len();
 
         Vcpu::register_kick_signal_handler();
@@ -236,14 +259,38 @@ impl Vmm {
         }
 
         // The vcpus start off in the `Paused` state, let them run.
-        self.resume_vcpus()?;
+        if held {
+            self.vcpus_held = true;
+        } else {
+            self.send_resume()?;
+        }
 
         Ok(())
     }
 
+    /// Lets the vcpus held by `start_vcpus` run, returning false if they
+    /// weren't held.
+    pub fn release_vcpus(&mut self) -> Result<bool> {
+        if !self.vcpus_held {
+            return Ok(false);
+        }
+        self.send_resume()?;
+        self.vcpus_held = false;
+        Ok(true)
+    }
+
     /// Sends a resume command to the vcpus.
     #[cfg(target_os = "linux")]
     pub fn resume_vcpus(&mut self) -> Result<()> {
+        self.send_resume()?;
+        // The guest clock stood still while the vCPUs were paused, have the
+        // guest step it to the host time.
+        devices::virtio::request_time_sync();
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_resume(&mut self) -> Result<()> {
         for handle in self.vcpus_handles.iter() {
             handle
                 .send_event(VcpuEvent::Resume)
@@ -261,18 +308,45 @@ impl Vmm {
         Ok(())
     }
 
+    /// Sends a pause command to the vcpus.
+    #[cfg(target_os = "linux")]
+    pub fn pause_vcpus(&mut self) -> Result<()> {
+        for handle in self.vcpus_handles.iter() {
+            handle
+                .send_event(VcpuEvent::Pause)
+                .map_err(Error::VcpuEvent)?;
+        }
+        for handle in self.vcpus_handles.iter() {
+            match handle
+                .response_receiver()
+                .recv_timeout(Duration::from_millis(1000))
+            {
+                Ok(VcpuResponse::Paused) => (),
+                _ => return Err(Error::VcpuPause),
+            }
+        }
+        Ok(())
+    }
+
     #[cfg(target_os = "macos")]
     pub fn resume_vcpus(&mut self) -> Result<()> {
         Ok(())
     }
 
+    #[cfg(target_os = "macos")]
+    fn send_resume(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Configures the system for boot.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     pub fn configure_system(
         &self,
         vcpus: &[Vcpu],
         _intc: &IrqChip,
         initrd: &Option<InitrdConfig>,
         _smbios_oem_strings: &Option<Vec<String>>,
+        _kaslr_seed: Option<u64>,
     ) -> Result<()> {
         #[cfg(target_arch = "x86_64")]
         {
@@ -296,6 +370,11 @@ impl Vmm {
         #[cfg(target_arch = "aarch64")]
         {
             let vcpu_mpidr = vcpus.iter().map(|cpu| cpu.get_mpidr()).collect();
+            #[cfg(target_os = "linux")]
+            let pmu = vcpus.iter().any(|cpu| cpu.has_pmu());
+            // Hypervisor.framework doesn't virtualize the PMU.
+            #[cfg(target_os = "macos")]
+            let pmu = false;
             fdt::create_fdt(
                 &self.guest_memory,
                 &self.arch_memory_info,
@@ -304,6 +383,8 @@ impl Vmm {
                 self.mmio_device_manager.get_device_info(),
                 _intc,
                 initrd,
+                pmu,
+                _kaslr_seed,
             )
             .map_err(Error::SetupFDT)?;
         }
@@ -330,6 +411,7 @@ impl Vmm {
                 self.mmio_device_manager.get_device_info(),
                 _intc,
                 initrd,
+                _kaslr_seed,
             )
             .map_err(Error::SetupFDT)?;
 
@@ -340,6 +422,11 @@ impl Vmm {
         Ok(())
     }
 
+    /// Registers an observer notified right before the process exits.
+    pub fn add_exit_observer(&mut self, observer: Arc<Mutex<dyn VmmExitObserver>>) {
+        self.exit_observers.push(observer);
+    }
+
     /// Returns a reference to the inner `GuestMemoryMmap` object if present, or `None` otherwise.
     pub fn guest_memory(&self) -> &GuestMemoryMmap {
         &self.guest_memory
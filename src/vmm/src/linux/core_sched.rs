@@ -0,0 +1,92 @@
+//! Core scheduling, so the vCPUs don't share a physical core with host tasks
+//! through SMT, which would let them use cross-hyperthread side channels
+//! against each other.
+//!
+//! Tasks with a different core scheduling cookie never run at the same time
+//! on the siblings of a core. New threads get the cookie of the thread
+//! creating them.
+
+use std::io;
+
+use crate::vmm_config::machine_config::CoreScheduling;
+
+// From include/uapi/linux/prctl.h, not in libc yet for every target.
+const PR_SCHED_CORE: libc::c_int = 62;
+const PR_SCHED_CORE_GET: libc::c_ulong = 0;
+const PR_SCHED_CORE_CREATE: libc::c_ulong = 1;
+
+// From include/linux/pid.h.
+const PIDTYPE_PID: libc::c_ulong = 0;
+const PIDTYPE_TGID: libc::c_ulong = 1;
+
+fn prctl_sched_core(
+    cmd: libc::c_ulong,
+    pid_type: libc::c_ulong,
+    cookie: *mut u64,
+) -> io::Result<()> {
+    // SAFETY: `cookie` is either null or points to a u64, as PR_SCHED_CORE_GET
+    // expects.
+    let ret = unsafe {
+        libc::prctl(
+            PR_SCHED_CORE,
+            cmd,
+            0 as libc::c_ulong,
+            pid_type,
+            cookie as libc::c_ulong,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Checks that the host kernel supports core scheduling. Returns `false` if
+/// it does but SMT isn't enabled, since there's nothing to protect from.
+pub fn supported() -> io::Result<bool> {
+    let mut cookie = 0u64;
+    match prctl_sched_core(PR_SCHED_CORE_GET, PIDTYPE_PID, &mut cookie) {
+        Ok(()) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::ENODEV) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Gives a new cookie to the whole process, so all of its threads,
+/// including the vCPUs created afterwards, only share cores between them.
+pub fn create_process_cookie() -> io::Result<()> {
+    prctl_sched_core(PR_SCHED_CORE_CREATE, PIDTYPE_TGID, std::ptr::null_mut())
+}
+
+/// Gives a new cookie to the calling thread, so it never shares a core with
+/// any other thread.
+pub fn create_thread_cookie() -> io::Result<()> {
+    prctl_sched_core(PR_SCHED_CORE_CREATE, PIDTYPE_PID, std::ptr::null_mut())
+}
+
+/// Applies `mode` to the process, before the vCPUs are started. Returns
+/// whether each vCPU must create its own cookie.
+pub fn setup(mode: CoreScheduling) -> io::Result<bool> {
+    if mode == CoreScheduling::Disabled {
+        return Ok(false);
+    }
+    if !supported()? {
+        info!("SMT isn't enabled, core scheduling isn't needed");
+        return Ok(false);
+    }
+    match mode {
+        CoreScheduling::Disabled => Ok(false),
+        CoreScheduling::Process => create_process_cookie().map(|_| false),
+        CoreScheduling::Vcpu => Ok(true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setup_disabled() {
+        assert!(!setup(CoreScheduling::Disabled).unwrap());
+    }
+}
@@ -1,3 +1,4 @@
+pub mod core_sched;
 #[cfg(feature = "tee")]
 pub mod tee;
 
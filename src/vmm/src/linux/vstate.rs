@@ -18,6 +18,7 @@ use std::os::unix::io::RawFd;
 use std::env;
 use std::result;
 use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
 #[cfg(not(test))]
 use std::sync::Barrier;
 use std::thread;
@@ -35,9 +36,13 @@ use super::tee::inteltdx::{Error as TdxError, IntelTdx};
 #[cfg(feature = "tee")]
 use kbs_types::Tee;
 
+use super::core_sched;
+#[cfg(target_arch = "aarch64")]
+use crate::guest_sleep::GUEST_SLEEP;
+use crate::memory_slots::{MemorySlot, MemorySlots};
 #[cfg(feature = "tee")]
 use crate::resources::TeeConfig;
-use crate::vmm_config::machine_config::CpuFeaturesTemplate;
+use crate::vmm_config::machine_config::{ClockConfig, CpuFeaturesTemplate};
 #[cfg(target_arch = "x86_64")]
 use cpuid::{c3, filter_cpuid, t2, VmSpec};
 #[cfg(target_arch = "x86_64")]
@@ -55,8 +60,12 @@ use kvm_bindings::{
 use kvm_bindings::{kvm_enable_cap, KVM_CAP_EXIT_HYPERCALL, KVM_MEMORY_EXIT_FLAG_PRIVATE};
 #[cfg(not(target_arch = "riscv64"))]
 use kvm_bindings::{kvm_memory_attributes, KVM_MEMORY_ATTRIBUTE_PRIVATE};
+#[cfg(target_arch = "aarch64")]
+use kvm_bindings::{KVM_CAP_ARM_SYSTEM_SUSPEND, KVM_SYSTEM_EVENT_SUSPEND};
 use kvm_ioctls::{Cap::*, *};
+use utils::boot_timeline::{BootPhase, BootTimeline};
 use utils::eventfd::EventFd;
+use utils::metrics::Metrics;
 use utils::signal::{register_signal_handler, sigrtmin, Killable};
 use utils::sm::StateMachine;
 #[cfg(feature = "tee")]
@@ -105,6 +114,10 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     /// Cannot set the local interruption due to bad configuration.
     LocalIntConfiguration(arch::x86_64::interrupts::Error),
+    /// There is no memory slot with the given id.
+    MemorySlotNotFound(u32),
+    /// The memory slot is empty or overlaps with an existing one.
+    MemorySlotOverlap,
     #[cfg(feature = "tee")]
     /// Missing TEE config
     MissingTeeConfig,
@@ -157,6 +170,9 @@ pub enum Error {
     #[cfg(target_arch = "aarch64")]
     /// Error getting the Vcpu preferred target on Arm.
     VcpuArmPreferredTarget(kvm_ioctls::Error),
+    #[cfg(target_arch = "aarch64")]
+    /// Error initializing the PMU of the Vcpu on Arm.
+    VcpuArmPmu(kvm_ioctls::Error),
     /// vCPU count is not initialized.
     VcpuCountNotInitialized,
     /// Cannot open the VCPU file descriptor.
@@ -180,6 +196,12 @@ pub enum Error {
     /// Failed to get KVM vcpu sregs.
     VcpuGetSregs(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
+    /// Failed to get KVM vcpu TSC offset.
+    VcpuGetTscOffset(kvm_ioctls::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// Failed to get KVM vcpu TSC frequency.
+    VcpuGetTscKhz(kvm_ioctls::Error),
+    #[cfg(target_arch = "x86_64")]
     /// Failed to get KVM vcpu event.
     VcpuGetVcpuEvents(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
@@ -212,6 +234,12 @@ pub enum Error {
     /// Failed to set KVM vcpu sregs.
     VcpuSetSregs(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
+    /// Failed to set KVM vcpu TSC offset.
+    VcpuSetTscOffset(kvm_ioctls::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// Failed to set KVM vcpu TSC frequency.
+    VcpuSetTscKhz(kvm_ioctls::Error),
+    #[cfg(target_arch = "x86_64")]
     /// Failed to set KVM vcpu event.
     VcpuSetVcpuEvents(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
@@ -293,6 +321,11 @@ impl Display for Error {
                 f,
                 "The number of configured slots is bigger than the maximum reported by KVM"
             ),
+            MemorySlotNotFound(slot) => write!(f, "Memory slot {slot} not found"),
+            MemorySlotOverlap => write!(
+                f,
+                "The memory slot is empty or overlaps with an existing one"
+            ),
             #[cfg(target_arch = "x86_64")]
             LocalIntConfiguration(e) => write!(
                 f,
@@ -366,6 +399,10 @@ impl Display for Error {
             #[cfg(target_arch = "x86_64")]
             VcpuGetSregs(e) => write!(f, "Failed to get KVM vcpu sregs: {e}"),
             #[cfg(target_arch = "x86_64")]
+            VcpuGetTscOffset(e) => write!(f, "Failed to get KVM vcpu TSC offset: {e}"),
+            #[cfg(target_arch = "x86_64")]
+            VcpuGetTscKhz(e) => write!(f, "Failed to get KVM vcpu TSC frequency: {e}"),
+            #[cfg(target_arch = "x86_64")]
             VcpuGetVcpuEvents(e) => write!(f, "Failed to get KVM vcpu event: {e}"),
             #[cfg(target_arch = "x86_64")]
             VcpuGetXcrs(e) => write!(f, "Failed to get KVM vcpu xcrs: {e}"),
@@ -386,6 +423,10 @@ impl Display for Error {
             #[cfg(target_arch = "x86_64")]
             VcpuSetSregs(e) => write!(f, "Failed to set KVM vcpu sregs: {e}"),
             #[cfg(target_arch = "x86_64")]
+            VcpuSetTscOffset(e) => write!(f, "Failed to set KVM vcpu TSC offset: {e}"),
+            #[cfg(target_arch = "x86_64")]
+            VcpuSetTscKhz(e) => write!(f, "Failed to set KVM vcpu TSC frequency: {e}"),
+            #[cfg(target_arch = "x86_64")]
             VcpuSetVcpuEvents(e) => write!(f, "Failed to set KVM vcpu event: {e}"),
             #[cfg(target_arch = "x86_64")]
             VcpuSetXcrs(e) => write!(f, "Failed to set KVM vcpu xcrs: {e}"),
@@ -415,6 +456,8 @@ impl Display for Error {
             }
             #[cfg(target_arch = "aarch64")]
             VcpuArmInit(e) => write!(f, "Error doing Vcpu Init on Arm: {e}"),
+            #[cfg(target_arch = "aarch64")]
+            VcpuArmPmu(e) => write!(f, "Error initializing the Vcpu PMU on Arm: {e}"),
 
             #[cfg(feature = "tee")]
             InvalidTee => write!(f, "TEE selected is not currently supported"),
@@ -477,6 +520,19 @@ impl KvmContext {
         &self.kvm
     }
 
+    /// Whether the guest can be given hardware virtualization support, to
+    /// run its own VMs.
+    #[cfg(not(feature = "tee"))]
+    pub fn nested_virt_supported(&self) -> bool {
+        nested_virt_supported(&self.kvm)
+    }
+
+    /// Whether the guest can be given a PMU.
+    #[cfg(not(feature = "tee"))]
+    pub fn pmu_supported(&self) -> bool {
+        pmu_supported(&self.kvm)
+    }
+
     /// Get the maximum number of memory slots reported by this KVM context.
     pub fn max_memslots(&self) -> usize {
         self.max_memslots
@@ -504,6 +560,9 @@ pub struct Vm {
     pub tee_config: Tee,
 
     pub guest_memfds: Vec<(Range<u64>, RawFd)>,
+
+    memory_slots: MemorySlots,
+    metrics: Arc<Metrics>,
 }
 
 impl Vm {
@@ -520,6 +579,8 @@ impl Vm {
         #[cfg(target_arch = "x86_64")]
         let supported_msrs =
             arch::x86_64::msr::supported_guest_msrs(kvm).map_err(Error::GuestMSRs)?;
+        #[cfg(target_arch = "aarch64")]
+        enable_system_suspend(&vm_fd);
 
         Ok(Vm {
             fd: vm_fd,
@@ -529,6 +590,8 @@ impl Vm {
             #[cfg(target_arch = "x86_64")]
             supported_msrs,
             guest_memfds: Vec::new(),
+            memory_slots: MemorySlots::default(),
+            metrics: Arc::new(Metrics::new()),
         })
     }
 
@@ -568,6 +631,8 @@ impl Vm {
             tee,
             tee_config: tee_config.tee,
             guest_memfds: Vec::new(),
+            memory_slots: MemorySlots::default(),
+            metrics: Arc::new(Metrics::new()),
         })
     }
 
@@ -613,6 +678,8 @@ impl Vm {
             tdx: Some(IntelTdx::new()),
             tee_config: tee_config.tee,
             guest_memfds: Vec::new(),
+            memory_slots: MemorySlots::default(),
+            metrics: Arc::new(Metrics::new()),
         })
     }
 
@@ -628,6 +695,15 @@ impl Vm {
         &self.supported_msrs
     }
 
+    /// Sets the metrics of the microVM, shared with its vCPUs and devices.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
     /// Initializes the guest memory.
     pub fn memory_init(
         &mut self,
@@ -814,6 +890,82 @@ impl Vm {
         &self.fd
     }
 
+    /// Makes idle vCPUs sleep right away, instead of polling for a wake up
+    /// for a while first, which burns host CPU time while the guest is idle.
+    pub fn disable_halt_polling(&self) {
+        if self
+            .fd
+            .check_extension_raw(kvm_bindings::KVM_CAP_HALT_POLL.into())
+            <= 0
+        {
+            debug!("KVM doesn't support configuring halt polling");
+            return;
+        }
+        // The maximum polling time, in ns, is the first argument.
+        let cap = kvm_bindings::kvm_enable_cap {
+            cap: kvm_bindings::KVM_CAP_HALT_POLL,
+            ..Default::default()
+        };
+        if let Err(e) = enable_vm_cap(&self.fd, &cap) {
+            warn!("Couldn't disable halt polling: {e}");
+        }
+    }
+
+    /// Maps `len` bytes at `host_addr` into the guest at `guest_addr` after
+    /// the guest memory has been initialized, returning the id of the new
+    /// memory slot.
+    pub fn add_memory_slot(&mut self, host_addr: u64, guest_addr: u64, len: u64) -> Result<u32> {
+        let slot = MemorySlot {
+            host_addr,
+            guest_addr,
+            len,
+        };
+        let id = self
+            .memory_slots
+            .insert(slot, self.next_mem_slot)
+            .ok_or(Error::MemorySlotOverlap)?;
+
+        let memory_region = kvm_userspace_memory_region {
+            slot: id,
+            guest_phys_addr: guest_addr,
+            memory_size: len,
+            userspace_addr: host_addr,
+            flags: 0,
+        };
+        // Safe because the caller guarantees the host memory stays mapped for
+        // as long as the slot exists.
+        if let Err(e) = unsafe { self.fd.set_user_memory_region(memory_region) } {
+            self.memory_slots.remove(id);
+            return Err(Error::SetUserMemoryRegion(e));
+        }
+
+        self.metrics.memory.slots_added.inc();
+        Ok(id)
+    }
+
+    /// Removes a memory slot added with `add_memory_slot`.
+    pub fn remove_memory_slot(&mut self, id: u32) -> Result<()> {
+        let slot = self
+            .memory_slots
+            .remove(id)
+            .ok_or(Error::MemorySlotNotFound(id))?;
+
+        // A memory_size of zero deletes the slot.
+        let memory_region = kvm_userspace_memory_region {
+            slot: id,
+            guest_phys_addr: slot.guest_addr,
+            memory_size: 0,
+            userspace_addr: slot.host_addr,
+            flags: 0,
+        };
+        // Safe because we're only removing the guest mapping.
+        unsafe { self.fd.set_user_memory_region(memory_region) }
+            .map_err(Error::SetUserMemoryRegion)?;
+
+        self.metrics.memory.slots_removed.inc();
+        Ok(())
+    }
+
     #[allow(unused)]
     #[cfg(target_arch = "x86_64")]
     /// Saves and returns the Kvm Vm state.
@@ -898,6 +1050,213 @@ pub struct VcpuConfig {
     pub ht_enabled: bool,
     /// CPUID template to use.
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// Configuration of the guest clocks.
+    #[cfg(target_arch = "x86_64")]
+    pub clock: ClockConfig,
+    /// Expose hardware virtualization (VMX/SVM or EL2) to the guest.
+    pub nested: bool,
+    /// Expose the performance monitoring unit to the guest.
+    pub pmu: bool,
+    /// Hide the sources of randomness the VMM can't record, for deterministic
+    /// runs.
+    pub deterministic: bool,
+}
+
+// kvm-ioctls only exposes the vCPU device attribute ioctls on aarch64.
+#[cfg(target_arch = "x86_64")]
+vmm_sys_util::ioctl_iow_nr!(
+    KVM_SET_DEVICE_ATTR,
+    kvm_bindings::KVMIO,
+    0xe1,
+    kvm_bindings::kvm_device_attr
+);
+#[cfg(target_arch = "x86_64")]
+vmm_sys_util::ioctl_iow_nr!(
+    KVM_GET_DEVICE_ATTR,
+    kvm_bindings::KVMIO,
+    0xe2,
+    kvm_bindings::kvm_device_attr
+);
+#[cfg(target_arch = "x86_64")]
+vmm_sys_util::ioctl_iow_nr!(
+    KVM_HAS_DEVICE_ATTR,
+    kvm_bindings::KVMIO,
+    0xe3,
+    kvm_bindings::kvm_device_attr
+);
+
+// kvm-ioctls only exposes KVM_ENABLE_CAP on x86_64.
+#[cfg(not(target_arch = "x86_64"))]
+vmm_sys_util::ioctl_iow_nr!(
+    KVM_ENABLE_CAP,
+    kvm_bindings::KVMIO,
+    0xa3,
+    kvm_bindings::kvm_enable_cap
+);
+
+#[cfg(target_arch = "x86_64")]
+fn enable_vm_cap(vm_fd: &VmFd, cap: &kvm_bindings::kvm_enable_cap) -> io::Result<()> {
+    vm_fd
+        .enable_cap(cap)
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn enable_vm_cap(vm_fd: &VmFd, cap: &kvm_bindings::kvm_enable_cap) -> io::Result<()> {
+    // SAFETY: the ioctl only reads the kvm_enable_cap we pass by reference.
+    let ret = unsafe { vmm_sys_util::ioctl::ioctl_with_ref(vm_fd, KVM_ENABLE_CAP(), cap) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Lets the guest suspend itself through PSCI SYSTEM_SUSPEND, which KVM then
+/// reports as a KVM_SYSTEM_EVENT_SUSPEND exit. Older kernels don't support it,
+/// and the guest keeps running without being able to suspend.
+#[cfg(target_arch = "aarch64")]
+fn enable_system_suspend(vm_fd: &VmFd) {
+    if vm_fd.check_extension_raw(KVM_CAP_ARM_SYSTEM_SUSPEND.into()) <= 0 {
+        debug!("KVM doesn't support guest suspend");
+        return;
+    }
+    let cap = kvm_bindings::kvm_enable_cap {
+        cap: KVM_CAP_ARM_SYSTEM_SUSPEND,
+        ..Default::default()
+    };
+    if let Err(e) = enable_vm_cap(vm_fd, &cap) {
+        warn!("Couldn't enable guest suspend: {e}");
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+const VMX_BITINDEX: u32 = 5;
+#[cfg(target_arch = "x86_64")]
+const SVM_BITINDEX: u32 = 2;
+#[cfg(target_arch = "x86_64")]
+const RDRAND_BITINDEX: u32 = 30;
+#[cfg(target_arch = "x86_64")]
+const RDSEED_BITINDEX: u32 = 18;
+
+// Not in kvm-bindings yet.
+#[cfg(target_arch = "aarch64")]
+const KVM_CAP_ARM_EL2: libc::c_ulong = 240;
+
+/// Whether KVM can give guests hardware virtualization support, for them to
+/// run VMs of their own.
+pub fn nested_virt_supported(kvm: &Kvm) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        // CPUID.01H:ECX[5] (VMX) and CPUID.80000001H:ECX[2] (SVM), which KVM
+        // only reports as supported when nested virtualization is enabled.
+        kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map(|cpuid| {
+                cpuid.as_slice().iter().any(|entry| {
+                    (entry.function == 0x1 && entry.ecx & (1 << VMX_BITINDEX) != 0)
+                        || (entry.function == 0x8000_0001 && entry.ecx & (1 << SVM_BITINDEX) != 0)
+                })
+            })
+            .unwrap_or(false)
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        kvm.check_extension_raw(KVM_CAP_ARM_EL2) > 0
+    }
+    #[cfg(target_arch = "riscv64")]
+    {
+        let _ = kvm;
+        false
+    }
+}
+
+/// Whether KVM can expose a PMU to guests, for them to use `perf`.
+#[cfg(not(feature = "tee"))]
+pub fn pmu_supported(kvm: &Kvm) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        // AMD CPUs have performance counters without reporting them in
+        // CPUID, Intel ones report their version in CPUID.0AH:EAX[7:0].
+        kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map(|cpuid| {
+                cpuid.as_slice().iter().any(|entry| {
+                    (entry.function == 0x0 && entry.ebx == u32::from_le_bytes(*b"Auth"))
+                        || (entry.function == 0xa && entry.eax & 0xff != 0)
+                })
+            })
+            .unwrap_or(false)
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        kvm.check_extension(ArmPmuV3)
+    }
+    #[cfg(target_arch = "riscv64")]
+    {
+        let _ = kvm;
+        false
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+/// Hides VMX and SVM from the guest, unless nested virtualization is enabled.
+fn apply_nested_config(cpuid: &mut CpuId, nested: bool) {
+    if nested {
+        return;
+    }
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            0x1 => entry.ecx &= !(1 << VMX_BITINDEX),
+            0x8000_0001 => entry.ecx &= !(1 << SVM_BITINDEX),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+/// Hides RDRAND and RDSEED from deterministic guests, since what they return
+/// can't be recorded, so the guest only gets randomness from virtio-rng.
+fn apply_deterministic_config(cpuid: &mut CpuId, deterministic: bool) {
+    if !deterministic {
+        return;
+    }
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            0x1 => entry.ecx &= !(1 << RDRAND_BITINDEX),
+            0x7 if entry.index == 0 => entry.ebx &= !(1 << RDSEED_BITINDEX),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+/// Exposes the architectural performance monitoring described by `perf_mon_entry`.
+fn apply_pmu_config(cpuid: &mut CpuId, perf_mon_entry: &kvm_bindings::kvm_cpuid_entry2) {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        if entry.function == 0xa {
+            *entry = *perf_mon_entry;
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+/// Adjusts the CPUID entries describing the guest clocks.
+fn apply_clock_config(cpuid: &mut CpuId, clock: &ClockConfig) {
+    // CPUID.80000007H:EDX[8]
+    const INVARIANT_TSC_BITINDEX: u32 = 8;
+    // KVM_FEATURE_CLOCKSOURCE, KVM_FEATURE_CLOCKSOURCE2 and
+    // KVM_FEATURE_CLOCKSOURCE_STABLE_BIT in CPUID.40000001H:EAX.
+    const KVMCLOCK_FEATURES: u32 = (1 << 0) | (1 << 3) | (1 << 24);
+
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            0x8000_0007 => match clock.invariant_tsc {
+                Some(true) => entry.edx |= 1 << INVARIANT_TSC_BITINDEX,
+                Some(false) => entry.edx &= !(1 << INVARIANT_TSC_BITINDEX),
+                None => {}
+            },
+            0x4000_0001 if !clock.kvmclock => entry.eax &= !KVMCLOCK_FEATURES,
+            _ => {}
+        }
+    }
 }
 
 // Using this for easier explicit type-casting to help IDEs interpret the code.
@@ -911,6 +1270,8 @@ pub struct Vcpu {
     #[allow(dead_code)]
     #[cfg_attr(all(test, target_arch = "aarch64"), allow(unused))]
     exit_evt: EventFd,
+    // Whether the vcpu thread gets a core scheduling cookie for itself.
+    own_core_sched_cookie: bool,
 
     #[cfg(target_arch = "x86_64")]
     io_bus: devices::Bus,
@@ -923,6 +1284,9 @@ pub struct Vcpu {
 
     #[cfg(target_arch = "aarch64")]
     mpidr: u64,
+    // Used to reset the vCPU when resuming from suspend.
+    #[cfg(target_arch = "aarch64")]
+    kvi: kvm_bindings::kvm_vcpu_init,
 
     // The receiving end of events channel owned by the vcpu side.
     event_receiver: Receiver<VcpuEvent>,
@@ -935,6 +1299,9 @@ pub struct Vcpu {
 
     #[cfg(feature = "tee")]
     pm_sender: Sender<WorkerMessage>,
+
+    metrics: Arc<Metrics>,
+    boot_timeline: Arc<BootTimeline>,
 }
 
 impl Vcpu {
@@ -1057,6 +1424,7 @@ impl Vcpu {
             id,
             mmio_bus: None,
             exit_evt,
+            own_core_sched_cookie: false,
             io_bus,
             cpuid,
             msr_list,
@@ -1067,6 +1435,8 @@ impl Vcpu {
             response_sender,
             #[cfg(feature = "tee")]
             pm_sender,
+            metrics: Arc::new(Metrics::new()),
+            boot_timeline: Default::default(),
         })
     }
 
@@ -1089,11 +1459,15 @@ impl Vcpu {
             id,
             mmio_bus: None,
             exit_evt,
+            own_core_sched_cookie: false,
             mpidr: 0,
+            kvi: Default::default(),
             event_receiver,
             event_sender: Some(event_sender),
             response_receiver: Some(response_receiver),
             response_sender,
+            metrics: Arc::new(Metrics::new()),
+            boot_timeline: Default::default(),
         })
     }
 
@@ -1116,10 +1490,13 @@ impl Vcpu {
             id,
             mmio_bus: None,
             exit_evt,
+            own_core_sched_cookie: false,
             event_receiver,
             event_sender: Some(event_sender),
             response_receiver: Some(response_receiver),
             response_sender,
+            metrics: Arc::new(Metrics::new()),
+            boot_timeline: Default::default(),
         })
     }
 
@@ -1134,11 +1511,60 @@ impl Vcpu {
         self.mpidr
     }
 
+    /// Whether the guest sees a PMU in this vcpu.
+    #[cfg(target_arch = "aarch64")]
+    pub fn has_pmu(&self) -> bool {
+        self.kvi.features[0] & (1 << kvm_bindings::KVM_ARM_VCPU_PMU_V3) != 0
+    }
+
+    /// Initializes the PMU of this vcpu, if it has one. The interrupt
+    /// controller must have been created already.
+    #[cfg(target_arch = "aarch64")]
+    pub fn init_pmu(&self) -> Result<()> {
+        if !self.has_pmu() {
+            return Ok(());
+        }
+        let irq = arch::aarch64::layout::VIRTUAL_PMU_IRQ;
+        let pmu_attr = |attr, addr| kvm_bindings::kvm_device_attr {
+            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: attr as u64,
+            addr,
+            flags: 0,
+        };
+        self.fd
+            .set_device_attr(&pmu_attr(
+                kvm_bindings::KVM_ARM_VCPU_PMU_V3_IRQ,
+                &irq as *const u32 as u64,
+            ))
+            .map_err(Error::VcpuArmPmu)?;
+        self.fd
+            .set_device_attr(&pmu_attr(kvm_bindings::KVM_ARM_VCPU_PMU_V3_INIT, 0))
+            .map_err(Error::VcpuArmPmu)
+    }
+
     /// Sets a MMIO bus for this vcpu.
     pub fn set_mmio_bus(&mut self, mmio_bus: devices::Bus) {
         self.mmio_bus = Some(mmio_bus);
     }
 
+    /// Makes the vcpu thread create its own core scheduling cookie, so it
+    /// never shares a core with other tasks.
+    pub fn set_own_core_sched_cookie(&mut self) {
+        self.own_core_sched_cookie = true;
+    }
+
+    /// Sets the metrics of the microVM, where the exits of the vcpu are
+    /// counted.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Sets the boot timeline of the microVM, where the first run of the vcpu
+    /// is recorded.
+    pub fn set_boot_timeline(&mut self, boot_timeline: Arc<BootTimeline>) {
+        self.boot_timeline = boot_timeline;
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[allow(unused_variables)]
     /// Configures a x86_64 specific vcpu and should be called once per vcpu.
@@ -1157,6 +1583,13 @@ impl Vcpu {
     ) -> Result<()> {
         let cpuid_vm_spec = VmSpec::new(self.id, vcpu_config.vcpu_count, vcpu_config.ht_enabled)
             .map_err(Error::CpuId)?;
+        // Filtering hides the PMU, so keep it around in case it's enabled.
+        let perf_mon_entry = self
+            .cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == 0xa)
+            .copied();
 
         filter_cpuid(&mut self.cpuid, &cpuid_vm_spec).map_err(|e| {
             error!("Failure in configuring CPUID for vcpu {}: {:?}", self.id, e);
@@ -1174,10 +1607,21 @@ impl Vcpu {
             }
         }
 
+        apply_clock_config(&mut self.cpuid, &vcpu_config.clock);
+        apply_nested_config(&mut self.cpuid, vcpu_config.nested);
+        apply_deterministic_config(&mut self.cpuid, vcpu_config.deterministic);
+        if let (true, Some(perf_mon_entry)) = (vcpu_config.pmu, perf_mon_entry) {
+            apply_pmu_config(&mut self.cpuid, &perf_mon_entry);
+        }
+
         self.fd
             .set_cpuid2(&self.cpuid)
             .map_err(Error::VcpuSetCpuid)?;
 
+        if let Some(tsc_khz) = vcpu_config.clock.tsc_khz {
+            self.fd.set_tsc_khz(tsc_khz).map_err(Error::VcpuSetTscKhz)?;
+        }
+
         if kernel_boot {
             arch::x86_64::msr::setup_msrs(&self.fd).map_err(Error::MSRSConfiguration)?;
             arch::x86_64::regs::setup_regs(&self.fd, kernel_start_addr.raw_value(), self.id)
@@ -1198,11 +1642,13 @@ impl Vcpu {
     /// * `vm_fd` - The kvm `VmFd` for this microvm.
     /// * `guest_mem` - The guest memory used by this microvm.
     /// * `kernel_load_addr` - Offset from `guest_mem` at which the kernel is loaded.
+    /// * `vcpu_config` - The vCPU configuration.
     pub fn configure_aarch64(
         &mut self,
         vm_fd: &VmFd,
         guest_mem: &GuestMemoryMmap,
         kernel_load_addr: GuestAddress,
+        vcpu_config: &VcpuConfig,
     ) -> Result<()> {
         let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
 
@@ -1223,8 +1669,16 @@ impl Vcpu {
         if vm_fd.check_extension(kvm_ioctls::Cap::ArmPtrAuthGeneric) {
             kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PTRAUTH_GENERIC;
         }
+        // We already checked that these capabilities are supported.
+        if vcpu_config.nested {
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_HAS_EL2;
+        }
+        if vcpu_config.pmu {
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PMU_V3;
+        }
 
         self.fd.vcpu_init(&kvi).map_err(Error::VcpuArmInit)?;
+        self.kvi = kvi;
         arch::aarch64::regs::setup_regs(&self.fd, self.id, kernel_load_addr.raw_value(), guest_mem)
             .map_err(Error::REGSConfiguration)?;
 
@@ -1261,6 +1715,12 @@ impl Vcpu {
         let vcpu_thread = thread::Builder::new()
             .name(format!("fc_vcpu {}", self.cpu_index()))
             .spawn(move || {
+                if self.own_core_sched_cookie {
+                    if let Err(e) = core_sched::create_thread_cookie() {
+                        error!("Failed to create a core scheduling cookie for vcpu: {e}");
+                    }
+                }
+
                 self.init_thread_local_data()
                     .expect("Cannot cleanly initialize vcpu TLS.");
 
@@ -1333,6 +1793,8 @@ impl Vcpu {
             .fd
             .get_vcpu_events()
             .map_err(Error::VcpuGetVcpuEvents)?;
+        let tsc_khz = self.fd.get_tsc_khz().map_err(Error::VcpuGetTscKhz)?;
+        let tsc_offset = self.get_tsc_offset()?;
         Ok(VcpuState {
             cpuid: self.cpuid.clone(),
             msrs,
@@ -1344,9 +1806,54 @@ impl Vcpu {
             vcpu_events,
             xcrs,
             xsave,
+            tsc_khz,
+            tsc_offset,
         })
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn tsc_offset_attr(offset: &mut u64) -> kvm_bindings::kvm_device_attr {
+        kvm_bindings::kvm_device_attr {
+            group: kvm_bindings::KVM_VCPU_TSC_CTRL,
+            attr: u64::from(kvm_bindings::KVM_VCPU_TSC_OFFSET),
+            addr: offset as *mut u64 as u64,
+            flags: 0,
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Issues one of the KVM_*_DEVICE_ATTR ioctls on the vCPU.
+    fn device_attr_ioctl(
+        &self,
+        request: libc::c_ulong,
+        attr: &kvm_bindings::kvm_device_attr,
+    ) -> std::result::Result<(), kvm_ioctls::Error> {
+        // SAFETY: the kernel reads `attr`, and at most accesses a u64 at the
+        // address it holds, which callers point to a valid one.
+        let ret = unsafe { vmm_sys_util::ioctl::ioctl_with_ref(&self.fd, request, attr) };
+        if ret != 0 {
+            return Err(kvm_ioctls::Error::last());
+        }
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the TSC offset of the vCPU, or `None` if KVM doesn't support
+    /// the KVM_VCPU_TSC_OFFSET attribute.
+    fn get_tsc_offset(&self) -> Result<Option<u64>> {
+        let mut offset = 0u64;
+        let attr = Self::tsc_offset_attr(&mut offset);
+        if self
+            .device_attr_ioctl(KVM_HAS_DEVICE_ATTR(), &attr)
+            .is_err()
+        {
+            return Ok(None);
+        }
+        self.device_attr_ioctl(KVM_GET_DEVICE_ATTR(), &attr)
+            .map_err(Error::VcpuGetTscOffset)?;
+        Ok(Some(offset))
+    }
+
     #[allow(unused)]
     #[cfg(target_arch = "x86_64")]
     fn restore_state(&self, state: VcpuState) -> Result<()> {
@@ -1394,7 +1901,19 @@ impl Vcpu {
         self.fd
             .set_lapic(&state.lapic)
             .map_err(Error::VcpuSetLapic)?;
+        // The frequency must be in place before restoring MSR_IA32_TSC.
+        self.fd
+            .set_tsc_khz(state.tsc_khz)
+            .map_err(Error::VcpuSetTscKhz)?;
         self.fd.set_msrs(&state.msrs).map_err(Error::VcpuSetMsrs)?;
+        // Writing MSR_IA32_TSC makes KVM compute a new offset from the time
+        // the write happens, which drifts a little for every vCPU. Restoring
+        // the saved offset instead keeps the TSCs in sync across vCPUs, and
+        // with the value the guest saw before.
+        if let Some(mut offset) = state.tsc_offset {
+            self.device_attr_ioctl(KVM_SET_DEVICE_ATTR(), &Self::tsc_offset_attr(&mut offset))
+                .map_err(Error::VcpuSetTscOffset)?;
+        }
         self.fd
             .set_vcpu_events(&state.vcpu_events)
             .map_err(Error::VcpuSetVcpuEvents)?;
@@ -1419,6 +1938,7 @@ impl Vcpu {
             Ok(run) => match run {
                 #[cfg(feature = "tee")]
                 VcpuExit::Hypercall(hypercall) => {
+                    self.metrics.vm_exits.hypercall.inc();
                     if hypercall.nr != 12
                     /* KVM_HC_MAP_GPA_RANGE */
                     {
@@ -1448,16 +1968,19 @@ impl Vcpu {
                 }
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoIn(addr, data) => {
+                    self.metrics.vm_exits.io_in.inc();
                     self.io_bus.read(0, u64::from(addr), data);
                     Ok(VcpuEmulation::Handled)
                 }
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoOut(addr, data) => {
+                    self.metrics.vm_exits.io_out.inc();
                     self.io_bus.write(0, u64::from(addr), data);
                     Ok(VcpuEmulation::Handled)
                 }
                 #[cfg(feature = "tee")]
                 VcpuExit::MemoryFault { gpa, size, flags } => {
+                    self.metrics.vm_exits.memory_fault.inc();
                     if flags & !kvm_bindings::KVM_MEMORY_EXIT_FLAG_PRIVATE as u64 != 0 {
                         println!("KVM_EXIT_MEMORY_FAULT: Unknown flag {flags}");
                         Err(Error::VcpuUnhandledKvmExit)
@@ -1479,36 +2002,47 @@ impl Vcpu {
                     }
                 }
                 VcpuExit::MmioRead(addr, data) => {
+                    self.metrics.vm_exits.mmio_read.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         mmio_bus.read(0, addr, data);
                     }
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioWrite(addr, data) => {
+                    self.metrics.vm_exits.mmio_write.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         mmio_bus.write(0, addr, data);
                     }
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::Hlt => {
+                    self.metrics.vm_exits.hlt.inc();
                     info!("Received KVM_EXIT_HLT signal");
                     Ok(VcpuEmulation::Stopped)
                 }
                 VcpuExit::Shutdown => {
+                    self.metrics.vm_exits.shutdown.inc();
                     info!("Received KVM_EXIT_SHUTDOWN signal");
                     Ok(VcpuEmulation::Stopped)
                 }
                 // Documentation specifies that below kvm exits are considered
                 // errors.
                 VcpuExit::FailEntry(reason, vcpu) => {
+                    self.metrics.vm_exits.other.inc();
                     error!("Received KVM_EXIT_FAIL_ENTRY signal: reason={reason}, vcpu={vcpu}");
                     Err(Error::VcpuUnhandledKvmExit)
                 }
                 VcpuExit::InternalError => {
+                    self.metrics.vm_exits.other.inc();
                     error!("Received KVM_EXIT_INTERNAL_ERROR signal");
                     Err(Error::VcpuUnhandledKvmExit)
                 }
                 VcpuExit::SystemEvent(event, _reason) => {
+                    self.metrics.vm_exits.system_event.inc();
+                    #[cfg(target_arch = "aarch64")]
+                    if event == KVM_SYSTEM_EVENT_SUSPEND {
+                        return Ok(VcpuEmulation::Suspended);
+                    }
                     match event {
                         KVM_SYSTEM_EVENT_SHUTDOWN => info!("Received KVM_SYSTEM_EVENT_SHUTDOWN"),
                         KVM_SYSTEM_EVENT_RESET => info!("Received KVM_SYSTEM_EVENT_RESET"),
@@ -1517,6 +2051,7 @@ impl Vcpu {
                     Ok(VcpuEmulation::Stopped)
                 }
                 r => {
+                    self.metrics.vm_exits.other.inc();
                     // TODO: Are we sure we want to finish running a vcpu upon
                     // receiving a vm exit that is not necessarily an error?
                     error!("Unexpected exit reason on vcpu run: {r:?}");
@@ -1529,6 +2064,7 @@ impl Vcpu {
                 match e.errno() {
                     libc::EAGAIN => Ok(VcpuEmulation::Handled),
                     libc::EINTR => {
+                        self.metrics.vm_exits.interrupted.inc();
                         self.fd.set_kvm_immediate_exit(0);
                         // Notify that this KVM_RUN was interrupted.
                         Ok(VcpuEmulation::Interrupted)
@@ -1542,6 +2078,22 @@ impl Vcpu {
         }
     }
 
+    /// Blocks until the guest is woken up, and then resets the vCPU to resume
+    /// it at the entry point it passed to SYSTEM_SUSPEND.
+    #[cfg(target_arch = "aarch64")]
+    fn suspend(&mut self) -> Result<()> {
+        let (entry, context_id) =
+            arch::aarch64::regs::read_suspend_args(&self.fd).map_err(Error::REGSConfiguration)?;
+        GUEST_SLEEP.suspend();
+
+        // Whichever vCPU suspended the guest must resume running.
+        let mut kvi = self.kvi;
+        kvi.features[0] &= !(1 << kvm_bindings::KVM_ARM_VCPU_POWER_OFF);
+        self.fd.vcpu_init(&kvi).map_err(Error::VcpuArmInit)?;
+        arch::aarch64::regs::setup_resume_regs(&self.fd, entry, context_id)
+            .map_err(Error::REGSConfiguration)
+    }
+
     /// Main loop of the vCPU thread.
     ///
     /// Runs the vCPU in KVM context in a loop. Handles KVM_EXITs then goes back in.
@@ -1554,6 +2106,7 @@ impl Vcpu {
 
     // This is the main loop of the `Running` state.
     fn running(&mut self) -> StateMachine<Self> {
+        self.boot_timeline.record(BootPhase::FirstVcpuRun);
         // This loop is here just for optimizing the emulation path.
         // No point in ticking the state machine if there are no external events.
         loop {
@@ -1570,6 +2123,14 @@ impl Vcpu {
                 // seccomp failure because musl calls `sigprocmask` as part of `pthread_exit`.
                 // So we pause vCPU0 and send a signal to the emulation thread to stop the VMM.
                 Ok(VcpuEmulation::Stopped) => return self.exit(FC_EXIT_CODE_OK),
+                // The guest suspended itself, wait until told to resume it.
+                #[cfg(target_arch = "aarch64")]
+                Ok(VcpuEmulation::Suspended) => {
+                    if let Err(e) = self.suspend() {
+                        error!("Failed to resume the guest: {e}");
+                        return self.exit(FC_EXIT_CODE_GENERIC_ERROR);
+                    }
+                }
                 // Emulation errors lead to vCPU exit.
                 Err(_) => return self.exit(FC_EXIT_CODE_GENERIC_ERROR),
             }
@@ -1587,8 +2148,15 @@ impl Vcpu {
                     .send(VcpuResponse::Paused)
                     .expect("failed to send pause status");
 
-                // TODO: we should call `KVM_KVMCLOCK_CTRL` here to make sure
-                // TODO continued: the guest soft lockup watchdog does not panic on Resume.
+                // Let the guest know the vCPU was stopped, so its soft lockup
+                // watchdog doesn't fire on resume. This fails with EINVAL if
+                // the guest isn't using kvmclock.
+                #[cfg(target_arch = "x86_64")]
+                if let Err(e) = self.fd.kvmclock_ctrl() {
+                    if e.errno() != libc::EINVAL {
+                        warn!("Failed to notify the guest about the vCPU pause: {e}");
+                    }
+                }
 
                 // Move to 'paused' state.
                 state = StateMachine::next(Self::paused);
@@ -1693,6 +2261,8 @@ pub struct VcpuState {
     vcpu_events: kvm_vcpu_events,
     xcrs: kvm_xcrs,
     xsave: kvm_xsave,
+    tsc_khz: u32,
+    tsc_offset: Option<u64>,
 }
 
 // Allow currently unused Pause and Exit events. These will be used by the vmm later on.
@@ -1764,6 +2334,8 @@ enum VcpuEmulation {
     Handled,
     Interrupted,
     Stopped,
+    #[cfg(target_arch = "aarch64")]
+    Suspended,
 }
 
 #[cfg(test)]
@@ -1862,6 +2434,95 @@ mod tests {
         assert!(vm.memory_init(&gm, kvm_context.max_memslots()).is_err());
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_apply_clock_config() {
+        let entry = |function, eax, edx| kvm_bindings::kvm_cpuid_entry2 {
+            function,
+            eax,
+            edx,
+            ..Default::default()
+        };
+        let mut cpuid =
+            CpuId::from_entries(&[entry(0x4000_0001, 0x0100_0009, 0), entry(0x8000_0007, 0, 0)])
+                .unwrap();
+
+        apply_clock_config(&mut cpuid, &ClockConfig::default());
+        assert_eq!(cpuid.as_slice()[0].eax, 0x0100_0009);
+        assert_eq!(cpuid.as_slice()[1].edx, 0);
+
+        let clock = ClockConfig {
+            tsc_khz: None,
+            invariant_tsc: Some(true),
+            kvmclock: false,
+        };
+        apply_clock_config(&mut cpuid, &clock);
+        assert_eq!(cpuid.as_slice()[0].eax, 0);
+        assert_eq!(cpuid.as_slice()[1].edx, 1 << 8);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_apply_nested_config() {
+        let entry = |function, ecx| kvm_bindings::kvm_cpuid_entry2 {
+            function,
+            ecx,
+            ..Default::default()
+        };
+        let mut cpuid = CpuId::from_entries(&[entry(0x1, 0x21), entry(0x8000_0001, 0x5)]).unwrap();
+
+        apply_nested_config(&mut cpuid, true);
+        assert_eq!(cpuid.as_slice()[0].ecx, 0x21);
+        assert_eq!(cpuid.as_slice()[1].ecx, 0x5);
+
+        apply_nested_config(&mut cpuid, false);
+        assert_eq!(cpuid.as_slice()[0].ecx, 0x1);
+        assert_eq!(cpuid.as_slice()[1].ecx, 0x1);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_apply_deterministic_config() {
+        let entry = |function, ebx, ecx| kvm_bindings::kvm_cpuid_entry2 {
+            function,
+            ebx,
+            ecx,
+            ..Default::default()
+        };
+        let mut cpuid =
+            CpuId::from_entries(&[entry(0x1, 0, 0x4000_0001), entry(0x7, 0x4_0001, 0)]).unwrap();
+
+        apply_deterministic_config(&mut cpuid, false);
+        assert_eq!(cpuid.as_slice()[0].ecx, 0x4000_0001);
+        assert_eq!(cpuid.as_slice()[1].ebx, 0x4_0001);
+
+        apply_deterministic_config(&mut cpuid, true);
+        assert_eq!(cpuid.as_slice()[0].ecx, 0x1);
+        assert_eq!(cpuid.as_slice()[1].ebx, 0x1);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_apply_pmu_config() {
+        let perf_mon_entry = kvm_bindings::kvm_cpuid_entry2 {
+            function: 0xa,
+            eax: 0x0830_0805,
+            ebx: 0xff,
+            edx: 0x8603,
+            ..Default::default()
+        };
+        let mut cpuid = CpuId::from_entries(&[kvm_bindings::kvm_cpuid_entry2 {
+            function: 0xa,
+            ..Default::default()
+        }])
+        .unwrap();
+
+        apply_pmu_config(&mut cpuid, &perf_mon_entry);
+        assert_eq!(cpuid.as_slice()[0].eax, 0x0830_0805);
+        assert_eq!(cpuid.as_slice()[0].ebx, 0xff);
+        assert_eq!(cpuid.as_slice()[0].edx, 0x8603);
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_configure_vcpu() {
@@ -1871,6 +2532,10 @@ mod tests {
             vcpu_count: 1,
             ht_enabled: false,
             cpu_template: None,
+            clock: ClockConfig::default(),
+            nested: false,
+            pmu: false,
+            deterministic: false,
         };
 
         assert!(vcpu
@@ -1899,6 +2564,14 @@ mod tests {
         assert!(vm.memory_init(&gm, kvm.max_memslots()).is_ok());
 
         // Try it for when vcpu id is 0.
+        let vcpu_config = VcpuConfig {
+            vcpu_count: 2,
+            ht_enabled: false,
+            cpu_template: None,
+            nested: false,
+            pmu: false,
+            deterministic: false,
+        };
         let mut vcpu = Vcpu::new_aarch64(
             0,
             vm.fd(),
@@ -1907,7 +2580,7 @@ mod tests {
         .unwrap();
 
         assert!(vcpu
-            .configure_aarch64(vm.fd(), &gm, GuestAddress(0))
+            .configure_aarch64(vm.fd(), &gm, GuestAddress(0), &vcpu_config)
             .is_ok());
 
         // Try it for when vcpu id is NOT 0.
@@ -1919,7 +2592,7 @@ mod tests {
         .unwrap();
 
         assert!(vcpu
-            .configure_aarch64(vm.fd(), &gm, GuestAddress(0))
+            .configure_aarch64(vm.fd(), &gm, GuestAddress(0), &vcpu_config)
             .is_ok());
     }
 
@@ -13,17 +13,22 @@ use std::result;
 #[cfg(not(test))]
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::super::{FC_EXIT_CODE_GENERIC_ERROR, FC_EXIT_CODE_OK};
+use crate::guest_sleep::GUEST_SLEEP;
+use crate::idle::IdleTracker;
+use crate::memory_slots::{MemorySlot, MemorySlots};
 use crate::vmm_config::machine_config::CpuFeaturesTemplate;
 
 use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
 use devices::legacy::VcpuList;
 use hvf::{HvfVcpu, HvfVm, VcpuExit, Vcpus};
+use utils::boot_timeline::{BootPhase, BootTimeline};
 use utils::eventfd::EventFd;
+use utils::metrics::Metrics;
 use vm_memory::{
-    Address, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+    Address, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
 };
 
 /// Errors associated with the wrappers over KVM ioctls.
@@ -31,6 +36,12 @@ use vm_memory::{
 pub enum Error {
     /// Invalid guest memory configuration.
     GuestMemoryMmap(GuestMemoryError),
+    /// There is no memory slot with the given id.
+    MemorySlotNotFound(u32),
+    /// The memory slot is empty or overlaps with an existing one.
+    MemorySlotOverlap,
+    /// Cannot remove a memory region.
+    MemoryUnmap(hvf::Error),
     /// The number of configured slots is bigger than the maximum reported by KVM.
     NotEnoughMemorySlots,
     /// Error configuring the general purpose aarch64 registers.
@@ -65,6 +76,12 @@ impl Display for Error {
 
         match self {
             GuestMemoryMmap(e) => write!(f, "Guest memory error: {e:?}"),
+            MemorySlotNotFound(slot) => write!(f, "Memory slot {slot} not found"),
+            MemorySlotOverlap => write!(
+                f,
+                "The memory slot is empty or overlaps with an existing one"
+            ),
+            MemoryUnmap(e) => write!(f, "Cannot remove the memory region: {e:?}"),
             VcpuCountNotInitialized => write!(f, "vCPU count is not initialized"),
             VmSetup(e) => write!(f, "Cannot configure the microvm: {e:?}"),
             VcpuRun => write!(f, "Cannot run the VCPUs"),
@@ -93,6 +110,8 @@ pub type Result<T> = result::Result<T, Error>;
 /// A wrapper around creating and using a VM.
 pub struct Vm {
     hvf_vm: HvfVm,
+    memory_slots: MemorySlots,
+    metrics: Arc<Metrics>,
 }
 
 impl Vm {
@@ -100,13 +119,26 @@ impl Vm {
     pub fn new(nested_enabled: bool) -> Result<Self> {
         let hvf_vm = HvfVm::new(nested_enabled).map_err(Error::VmSetup)?;
 
-        Ok(Vm { hvf_vm })
+        Ok(Vm {
+            hvf_vm,
+            memory_slots: MemorySlots::default(),
+            metrics: Arc::new(Metrics::new()),
+        })
     }
 
     pub fn hvf_vm(&self) -> &HvfVm {
         &self.hvf_vm
     }
 
+    /// Sets the metrics of the microVM, shared with its vCPUs and devices.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
     /// Initializes the guest memory.
     pub fn memory_init(&mut self, guest_mem: &GuestMemoryMmap) -> Result<()> {
         for region in guest_mem.iter() {
@@ -130,6 +162,45 @@ impl Vm {
         Ok(())
     }
 
+    /// Maps `len` bytes at `host_addr` into the guest at `guest_addr` after
+    /// the guest memory has been initialized, returning the id of the new
+    /// memory slot. HVF has no notion of slots, the ids are only
+    /// maintained to offer the same interface as KVM.
+    pub fn add_memory_slot(&mut self, host_addr: u64, guest_addr: u64, len: u64) -> Result<u32> {
+        let slot = MemorySlot {
+            host_addr,
+            guest_addr,
+            len,
+        };
+        let id = self
+            .memory_slots
+            .insert(slot, 0)
+            .ok_or(Error::MemorySlotOverlap)?;
+
+        if let Err(e) = self.hvf_vm.map_memory(host_addr, guest_addr, len) {
+            self.memory_slots.remove(id);
+            return Err(Error::SetUserMemoryRegion(e));
+        }
+
+        self.metrics.memory.slots_added.inc();
+        Ok(id)
+    }
+
+    /// Removes a memory slot added with `add_memory_slot`.
+    pub fn remove_memory_slot(&mut self, id: u32) -> Result<()> {
+        let slot = self
+            .memory_slots
+            .remove(id)
+            .ok_or(Error::MemorySlotNotFound(id))?;
+
+        self.hvf_vm
+            .unmap_memory(slot.guest_addr, slot.len)
+            .map_err(Error::MemoryUnmap)?;
+
+        self.metrics.memory.slots_removed.inc();
+        Ok(())
+    }
+
     pub fn add_mapping(
         &self,
         reply_sender: Sender<bool>,
@@ -170,6 +241,8 @@ pub struct VcpuConfig {
     pub ht_enabled: bool,
     /// CPUID template to use.
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// How long the guest must stay idle before the vCPUs are throttled.
+    pub idle_throttle: Option<Duration>,
 }
 
 // Using this for easier explicit type-casting to help IDEs interpret the code.
@@ -182,6 +255,7 @@ pub struct Vcpu {
     boot_receiver: Option<Receiver<u64>>,
     boot_senders: Option<HashMap<u64, Sender<u64>>>,
     fdt_addr: u64,
+    guest_mem: Option<GuestMemoryMmap>,
     mmio_bus: Option<devices::Bus>,
     #[cfg_attr(all(test, target_arch = "aarch64"), allow(unused))]
     exit_evt: EventFd,
@@ -200,6 +274,9 @@ pub struct Vcpu {
 
     vcpu_list: Arc<VcpuList>,
     nested_enabled: bool,
+    idle: Option<IdleTracker>,
+    metrics: Arc<Metrics>,
+    boot_timeline: Arc<BootTimeline>,
 }
 
 impl Vcpu {
@@ -284,6 +361,7 @@ impl Vcpu {
             boot_receiver,
             boot_senders: None,
             fdt_addr: 0,
+            guest_mem: None,
             mmio_bus: None,
             exit_evt,
             mpidr: id as u64,
@@ -293,6 +371,9 @@ impl Vcpu {
             response_sender,
             vcpu_list,
             nested_enabled,
+            idle: None,
+            metrics: Arc::new(Metrics::new()),
+            boot_timeline: Default::default(),
         })
     }
 
@@ -315,6 +396,23 @@ impl Vcpu {
         self.boot_senders = Some(boot_senders);
     }
 
+    /// Throttles the vCPU once the guest has been idle for `threshold`.
+    pub fn set_idle_throttle(&mut self, threshold: Duration) {
+        self.idle = Some(IdleTracker::new(threshold, Instant::now()));
+    }
+
+    /// Sets the metrics of the microVM, where the exits of the vcpu are
+    /// counted.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Sets the boot timeline of the microVM, where the first run of the vcpu
+    /// is recorded.
+    pub fn set_boot_timeline(&mut self, boot_timeline: Arc<BootTimeline>) {
+        self.boot_timeline = boot_timeline;
+    }
+
     /// Configures an aarch64 specific vcpu.
     ///
     /// # Arguments
@@ -322,6 +420,7 @@ impl Vcpu {
     /// * `guest_mem` - The guest memory used by this microvm.
     pub fn configure_aarch64(&mut self, guest_mem: &GuestMemoryMmap) -> Result<()> {
         self.fdt_addr = arch::aarch64::get_fdt_addr(guest_mem);
+        self.guest_mem = Some(guest_mem.clone());
 
         Ok(())
     }
@@ -361,14 +460,22 @@ impl Vcpu {
         match hvf_vcpu.run(self.vcpu_list.clone()) {
             Ok(exit) => match exit {
                 VcpuExit::Breakpoint => {
+                    self.metrics.vm_exits.other.inc();
                     debug!("vCPU {vcpuid} breakpoint");
                     Ok(VcpuEmulation::Interrupted)
                 }
                 VcpuExit::Canceled => {
+                    self.metrics.vm_exits.interrupted.inc();
                     debug!("vCPU {vcpuid} canceled");
                     Ok(VcpuEmulation::Handled)
                 }
+                VcpuExit::CpuOff => {
+                    self.metrics.vm_exits.hypercall.inc();
+                    debug!("vCPU {vcpuid} CpuOff");
+                    Ok(VcpuEmulation::CpuOff)
+                }
                 VcpuExit::CpuOn(mpidr, entry, context_id) => {
+                    self.metrics.vm_exits.hypercall.inc();
                     debug!("CpuOn: mpidr=0x{mpidr:x} entry=0x{entry:x} context_id={context_id}");
                     if let Some(boot_senders) = &self.boot_senders {
                         if let Some(sender) = boot_senders.get(&mpidr) {
@@ -380,10 +487,13 @@ impl Vcpu {
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::HypervisorCall => {
+                    self.metrics.vm_exits.hypercall.inc();
                     debug!("vCPU {vcpuid} HVC");
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioRead(addr, data) => {
+                    self.metrics.vm_exits.mmio_read.inc();
+                    self.metrics.memory.stage2_faults.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         debug!("vCPU {vcpuid} MMIO read 0x{addr:x}");
                         mmio_bus.read(vcpuid, addr, data);
@@ -391,41 +501,56 @@ impl Vcpu {
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioWrite(addr, data) => {
+                    self.metrics.vm_exits.mmio_write.inc();
+                    self.metrics.memory.stage2_faults.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         mmio_bus.write(vcpuid, addr, data);
                     }
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::PsciHandled => {
+                    self.metrics.vm_exits.hypercall.inc();
                     debug!("vCPU {vcpuid} PSCI");
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::SecureMonitorCall => {
+                    self.metrics.vm_exits.hypercall.inc();
                     debug!("vCPU {vcpuid} SMC");
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::Shutdown => {
+                    self.metrics.vm_exits.shutdown.inc();
                     info!("vCPU {vcpuid} received shutdown signal");
                     Ok(VcpuEmulation::Stopped)
                 }
                 VcpuExit::SystemRegister => {
+                    self.metrics.vm_exits.other.inc();
                     debug!("vCPU {vcpuid} accessed a system register");
                     Ok(VcpuEmulation::Handled)
                 }
+                VcpuExit::SystemSuspend(entry, context_id) => {
+                    self.metrics.vm_exits.system_event.inc();
+                    debug!("vCPU {vcpuid} SystemSuspend: entry=0x{entry:x}");
+                    Ok(VcpuEmulation::Suspended(entry, context_id))
+                }
                 VcpuExit::VtimerActivated => {
+                    self.metrics.vm_exits.other.inc();
                     debug!("vCPU {vcpuid} VtimerActivated");
                     self.vcpu_list.set_vtimer_irq(vcpuid);
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::WaitForEvent => {
+                    self.metrics.vm_exits.hlt.inc();
                     debug!("vCPU {vcpuid} WaitForEvent");
                     Ok(VcpuEmulation::WaitForEvent)
                 }
                 VcpuExit::WaitForEventExpired => {
+                    self.metrics.vm_exits.hlt.inc();
                     debug!("vCPU {vcpuid} WaitForEventExpired");
                     Ok(VcpuEmulation::WaitForEventExpired)
                 }
                 VcpuExit::WaitForEventTimeout(duration) => {
+                    self.metrics.vm_exits.hlt.inc();
                     debug!("vCPU {vcpuid} WaitForEventTimeout timeout={duration:?}");
                     Ok(VcpuEmulation::WaitForEventTimeout(duration))
                 }
@@ -440,6 +565,12 @@ impl Vcpu {
             HvfVcpu::new(self.mpidr, self.nested_enabled).expect("Can't create HVF vCPU");
         let hvf_vcpuid = hvf_vcpu.id();
 
+        if let Some(guest_mem) = self.guest_mem.clone() {
+            hvf_vcpu.set_guest_memory_reader(Box::new(move |addr, buf| {
+                guest_mem.read_slice(buf, GuestAddress(addr)).is_ok()
+            }));
+        }
+
         init_tls_sender
             .send(true)
             .expect("Cannot notify vcpu TLS initialization.");
@@ -457,6 +588,8 @@ impl Vcpu {
             .set_initial_state(entry_addr, self.fdt_addr)
             .unwrap_or_else(|_| panic!("Can't set HVF vCPU {hvf_vcpuid} initial state"));
 
+        self.boot_timeline.record(BootPhase::FirstVcpuRun);
+
         loop {
             match self.run_emulation(&mut hvf_vcpu) {
                 // Emulation ran successfully, continue.
@@ -471,6 +604,26 @@ impl Vcpu {
                 Ok(VcpuEmulation::WaitForEventTimeout(timeout)) => {
                     self.wait_for_event(hvf_vcpuid, &wfe_receiver, Some(timeout))
                 }
+                // The guest turned this vCPU off, wait for it to be turned on
+                // again. Only secondary vCPUs can be.
+                Ok(VcpuEmulation::CpuOff) => {
+                    let entry = self.boot_receiver.as_ref().and_then(|r| r.recv().ok());
+                    let powered_on = entry.map(|entry| hvf_vcpu.power_on(entry, self.fdt_addr));
+                    if !matches!(powered_on, Some(Ok(()))) {
+                        error!("vCPU {hvf_vcpuid} couldn't be turned back on");
+                        self.exit(FC_EXIT_CODE_GENERIC_ERROR);
+                        break;
+                    }
+                }
+                // The guest suspended itself, wait until told to resume it.
+                Ok(VcpuEmulation::Suspended(entry, context_id)) => {
+                    GUEST_SLEEP.suspend();
+                    if hvf_vcpu.power_on(entry, context_id).is_err() {
+                        error!("Failed to resume the guest on vCPU {hvf_vcpuid}");
+                        self.exit(FC_EXIT_CODE_GENERIC_ERROR);
+                        break;
+                    }
+                }
                 // The guest was rebooted or halted.
                 Ok(VcpuEmulation::Stopped) => {
                     self.exit(FC_EXIT_CODE_OK);
@@ -492,7 +645,12 @@ impl Vcpu {
         timeout: Option<Duration>,
     ) {
         if self.vcpu_list.should_wait(hvf_vcpuid) {
-            if let Some(timeout) = timeout {
+            let start = Instant::now();
+            let slack = self
+                .idle
+                .as_ref()
+                .map_or(Duration::ZERO, |idle| idle.slack(start));
+            if let Some(timeout) = timeout.map(|timeout| timeout + slack) {
                 match receiver.recv_timeout(timeout) {
                     Ok(_) => {}
                     Err(e) => match e {
@@ -503,6 +661,10 @@ impl Vcpu {
             } else {
                 receiver.recv().unwrap();
             }
+            if let Some(idle) = &mut self.idle {
+                let now = Instant::now();
+                idle.record_wait(now - start, now);
+            }
         }
     }
 
@@ -592,6 +754,8 @@ enum VcpuEmulation {
     Handled,
     Interrupted,
     Stopped,
+    CpuOff,
+    Suspended(u64, u64),
     WaitForEvent,
     WaitForEventExpired,
     WaitForEventTimeout(Duration),
@@ -751,6 +915,7 @@ mod tests {
             vcpu_count: 1,
             ht_enabled: false,
             cpu_template: None,
+            idle_throttle: None,
         };
 
         assert!(vcpu
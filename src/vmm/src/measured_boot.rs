@@ -0,0 +1,163 @@
+//! Process-wide log of the measurements of what the microVM boots, giving
+//! basic evidence of the integrity of the boot without a TEE or a TPM.
+//!
+//! Each component is hashed with SHA-256 before the guest runs, and the
+//! digests are also chained into an aggregate, like a TPM extends a PCR:
+//! starting from zeros, `aggregate = sha256(aggregate || digest)`.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use sha2::{Digest, Sha256};
+
+/// Global measurement log instance.
+pub static MEASUREMENT_LOG: MeasurementLog = MeasurementLog::new();
+
+/// Prefix of the SMBIOS OEM strings the measurements are exposed to the
+/// guest with.
+const OEM_STRING_PREFIX: &str = "krun.measurement.";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MeasuredBootConfig {
+    /// The disk image to measure along with the boot payload, usually the
+    /// one holding the root file system.
+    pub disk: Option<PathBuf>,
+    /// Whether to expose the measurements to the guest as SMBIOS OEM strings.
+    pub expose_to_guest: bool,
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().fold(String::new(), |mut out, byte| {
+        write!(out, "{byte:02x}").unwrap();
+        out
+    })
+}
+
+pub struct MeasurementLog {
+    events: Mutex<Vec<(String, [u8; 32])>>,
+}
+
+impl MeasurementLog {
+    const fn new() -> Self {
+        MeasurementLog {
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Records the digest of `data` as the measurement of `name`.
+    pub fn measure(&self, name: &str, data: &[u8]) {
+        self.record(name, Sha256::digest(data).into());
+    }
+
+    /// Records the digest of the contents of the file at `path` as the
+    /// measurement of `name`.
+    pub fn measure_file(&self, name: &str, path: &Path) -> io::Result<()> {
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 1 << 20];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => hasher.update(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.record(name, hasher.finalize().into());
+        Ok(())
+    }
+
+    fn record(&self, name: &str, digest: [u8; 32]) {
+        debug!("measured {name}: sha256:{}", hex(&digest));
+        self.events.lock().unwrap().push((name.to_string(), digest));
+    }
+
+    /// The digests of the log chained in order.
+    pub fn aggregate(&self) -> [u8; 32] {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .fold([0u8; 32], |aggregate, (_, digest)| {
+                Sha256::new()
+                    .chain_update(aggregate)
+                    .chain_update(digest)
+                    .finalize()
+                    .into()
+            })
+    }
+
+    /// Serializes the log as a JSON object with the measurements in the
+    /// order they were taken, and their aggregate.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"algorithm\":\"sha256\",\"events\":[");
+        for (i, (name, digest)) in self.events.lock().unwrap().iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(
+                out,
+                "{{\"name\":\"{}\",\"digest\":\"{}\"}}",
+                name,
+                hex(digest)
+            )
+            .unwrap();
+        }
+        write!(out, "],\"aggregate\":\"{}\"}}", hex(&self.aggregate())).unwrap();
+        out
+    }
+
+    /// The measurements and their aggregate as `krun.measurement.<name>=<digest>`
+    /// strings.
+    pub fn oem_strings(&self) -> Vec<String> {
+        let mut strings: Vec<String> = self
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, digest)| format!("{OEM_STRING_PREFIX}{name}=sha256:{}", hex(digest)))
+            .collect();
+        strings.push(format!(
+            "{OEM_STRING_PREFIX}aggregate=sha256:{}",
+            hex(&self.aggregate())
+        ));
+        strings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measurement_log() {
+        let log = MeasurementLog::new();
+        assert_eq!(hex(&log.aggregate()), "0".repeat(64));
+
+        log.measure("cmdline", b"abc");
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let expected: [u8; 32] = Sha256::new()
+            .chain_update([0u8; 32])
+            .chain_update(Sha256::digest(b"abc"))
+            .finalize()
+            .into();
+        assert_eq!(log.aggregate(), expected);
+        assert_eq!(
+            log.to_json(),
+            format!(
+                "{{\"algorithm\":\"sha256\",\"events\":[{{\"name\":\"cmdline\",\"digest\":\"{abc}\"}}],\"aggregate\":\"{}\"}}",
+                hex(&expected)
+            )
+        );
+        assert_eq!(
+            log.oem_strings(),
+            [
+                format!("krun.measurement.cmdline=sha256:{abc}"),
+                format!("krun.measurement.aggregate=sha256:{}", hex(&expected)),
+            ]
+        );
+    }
+}
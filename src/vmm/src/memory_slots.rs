@@ -0,0 +1,84 @@
+//! Bookkeeping for the guest memory regions added and removed at runtime,
+//! shared by the KVM and HVF backends.
+
+use std::collections::BTreeMap;
+
+/// A host memory range mapped into the guest physical address space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemorySlot {
+    pub host_addr: u64,
+    pub guest_addr: u64,
+    pub len: u64,
+}
+
+impl MemorySlot {
+    fn overlaps(&self, other: &MemorySlot) -> bool {
+        self.guest_addr < other.guest_addr + other.len
+            && other.guest_addr < self.guest_addr + self.len
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemorySlots {
+    slots: BTreeMap<u32, MemorySlot>,
+}
+
+impl MemorySlots {
+    /// Registers `slot` under the lowest free id greater or equal than
+    /// `min_id`. Returns `None` if it overlaps with an existing slot.
+    pub fn insert(&mut self, slot: MemorySlot, min_id: u32) -> Option<u32> {
+        if slot.len == 0 || self.slots.values().any(|s| s.overlaps(&slot)) {
+            return None;
+        }
+
+        let mut id = min_id;
+        for used in self.slots.range(min_id..).map(|(id, _)| *id) {
+            if used != id {
+                break;
+            }
+            id += 1;
+        }
+        self.slots.insert(id, slot);
+        Some(id)
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<MemorySlot> {
+        self.slots.remove(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(guest_addr: u64, len: u64) -> MemorySlot {
+        MemorySlot {
+            host_addr: 0x1000_0000 + guest_addr,
+            guest_addr,
+            len,
+        }
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut slots = MemorySlots::default();
+        assert_eq!(slots.insert(slot(0x1000, 0x1000), 4), Some(4));
+        assert_eq!(slots.insert(slot(0x2000, 0x1000), 4), Some(5));
+        assert_eq!(slots.insert(slot(0x8000, 0x1000), 4), Some(6));
+
+        assert_eq!(slots.remove(5), Some(slot(0x2000, 0x1000)));
+        assert_eq!(slots.remove(5), None);
+        assert_eq!(slots.insert(slot(0x3000, 0x1000), 4), Some(5));
+        assert_eq!(slots.insert(slot(0x4000, 0x1000), 0), Some(0));
+    }
+
+    #[test]
+    fn test_insert_overlapping() {
+        let mut slots = MemorySlots::default();
+        assert_eq!(slots.insert(slot(0x2000, 0x2000), 0), Some(0));
+        assert_eq!(slots.insert(slot(0x1000, 0x1001), 0), None);
+        assert_eq!(slots.insert(slot(0x3fff, 0x1000), 0), None);
+        assert_eq!(slots.insert(slot(0x4000, 0), 0), None);
+        assert_eq!(slots.insert(slot(0x1000, 0x1000), 0), Some(1));
+    }
+}
@@ -10,32 +10,48 @@ use std::fs::File;
 use std::io::BufReader;
 use std::os::fd::RawFd;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[cfg(feature = "tee")]
 use serde::{Deserialize, Serialize};
 
+#[cfg(not(feature = "tee"))]
+use crate::measured_boot::MeasuredBootConfig;
 #[cfg(feature = "blk")]
 use crate::vmm_config::block::{BlockBuilder, BlockConfigError, BlockDeviceConfig};
 use crate::vmm_config::external_kernel::ExternalKernel;
 use crate::vmm_config::firmware::FirmwareConfig;
 #[cfg(not(feature = "tee"))]
 use crate::vmm_config::fs::*;
+use crate::vmm_config::guest_memory::GuestMemoryRegionConfig;
 #[cfg(feature = "tee")]
 use crate::vmm_config::kernel_bundle::{InitrdBundle, QbootBundle, QbootBundleError};
 use crate::vmm_config::kernel_bundle::{KernelBundle, KernelBundleError};
 use crate::vmm_config::kernel_cmdline::{KernelCmdlineConfig, KernelCmdlineConfigError};
-use crate::vmm_config::machine_config::{VmConfig, VmConfigError};
+use crate::vmm_config::machine_config::{
+    ClockConfig, CoreScheduling, DeterministicConfig, VmConfig, VmConfigError,
+};
 #[cfg(feature = "net")]
 use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig, NetworkInterfaceError};
 use crate::vmm_config::vsock::*;
 use crate::vstate::VcpuConfig;
+#[cfg(not(feature = "tee"))]
+use devices::virtio::MemoryPressureConfig;
+use devices::virtio::VirtioPlugin;
+#[cfg(not(feature = "tee"))]
+use devices::virtio::{InputEventQueue, InputKind};
 
+#[cfg(feature = "gpu")]
+use devices::virtio::capture::ScanoutCapture;
 #[cfg(feature = "gpu")]
 use devices::virtio::display::DisplayInfo;
 #[cfg(feature = "tee")]
 use kbs_types::Tee;
 #[cfg(feature = "gpu")]
 use krun_display::DisplayBackend;
+use utils::boot_timeline::BootTimeline;
+use utils::metrics::Metrics;
 
 type Result<E> = std::result::Result<(), E>;
 
@@ -97,6 +113,27 @@ pub struct ConsoleConfig {
     pub err_fd: RawFd,
 }
 
+/// Whether the workload is connected to a terminal in the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WorkloadTty {
+    /// Only if the host stdio is connected to a terminal.
+    #[default]
+    Auto,
+    /// Always, putting the host terminal, if any, in raw mode.
+    Enabled,
+    /// Never, leaving the host terminal alone.
+    Disabled,
+}
+
+/// File descriptors the stdio of the workload is redirected to, through
+/// dedicated virtio-console ports. Negative ones are left alone.
+#[derive(Clone, Copy, Debug)]
+pub struct WorkloadStdioConfig {
+    pub input_fd: RawFd,
+    pub output_fd: RawFd,
+    pub err_fd: RawFd,
+}
+
 /// A data structure that encapsulates the device configurations
 /// held in the Vmm.
 #[derive(Default)]
@@ -120,6 +157,9 @@ pub struct VmResources {
     /// The fs device.
     #[cfg(not(feature = "tee"))]
     pub fs: Vec<FsDeviceConfig>,
+    /// The init the fs devices serve, replacing the bundled one.
+    #[cfg(not(feature = "tee"))]
+    pub init_binary: Option<Arc<[u8]>>,
     /// The vsock device.
     pub vsock: VsockBuilder,
     /// The virtio-blk device.
@@ -138,6 +178,9 @@ pub struct VmResources {
     pub display_backend: Option<DisplayBackend<'static>>,
     #[cfg(feature = "gpu")]
     pub displays: Vec<DisplayInfo>,
+    /// Copy of the contents of the scanouts, for screenshots and frame streaming.
+    #[cfg(feature = "gpu")]
+    pub scanout_capture: Option<Arc<ScanoutCapture>>,
     #[cfg(feature = "snd")]
     /// Enable the virtio-snd device.
     pub snd_device: bool,
@@ -147,14 +190,61 @@ pub struct VmResources {
     pub smbios_oem_strings: Option<Vec<String>>,
     /// Whether to enable nested virtualization.
     pub nested_enabled: bool,
+    /// Whether to expose the performance monitoring unit to the guest.
+    pub pmu_enabled: bool,
+    /// Which host tasks the vCPUs may share a core with.
+    pub core_scheduling: CoreScheduling,
+    /// Whether to hold the vCPUs before their first instruction until the
+    /// embedder resumes them.
+    pub start_paused: bool,
+    /// The guest address an external kernel is loaded at, instead of the
+    /// default of the architecture.
+    pub kernel_load_addr: Option<u64>,
+    /// The seed the guest kernel randomizes its layout with, `Some(0)`
+    /// disabling the randomization.
+    pub kaslr_seed: Option<u64>,
+    /// Record the device inputs to a journal, or replay them from one.
+    pub deterministic: Option<DeterministicConfig>,
+    /// Configuration of the guest clocks.
+    pub clock_config: ClockConfig,
     /// Whether to enable split irqchip
     pub split_irqchip: bool,
+    /// How long the guest must stay idle before its vCPUs are throttled.
+    pub idle_throttle: Option<Duration>,
+    /// Queue sizes overriding the defaults, by virtio device type.
+    pub queue_sizes: HashMap<u32, u16>,
+    /// Guest memory regions backed by the embedder.
+    pub guest_memory_regions: Vec<GuestMemoryRegionConfig>,
+    /// Devices implemented by the embedder.
+    pub virtio_plugins: Vec<Arc<Mutex<dyn VirtioPlugin>>>,
     /// Do not create an implicit console device in the guest
     pub disable_implicit_console: bool,
     /// The console id to use for console= in the kernel cmdline
     pub kernel_console: Option<String>,
+    /// Socket serving the early console output, when there's no serial console
+    pub boot_log: Option<PathBuf>,
     /// Consoles to attach to the guest
     pub consoles: HashMap<ConsoleType, Vec<ConsoleConfig>>,
+    /// Redirection of the stdio of the workload
+    pub workload_stdio: Option<WorkloadStdioConfig>,
+    /// Whether the workload gets a terminal
+    pub workload_tty: WorkloadTty,
+    /// Notify the embedder when the guest is under memory pressure.
+    #[cfg(not(feature = "tee"))]
+    pub memory_pressure: Option<MemoryPressureConfig>,
+    /// Measure the boot payload, command line and disk of the guest.
+    #[cfg(not(feature = "tee"))]
+    pub measured_boot: Option<MeasuredBootConfig>,
+    /// vhost-vdpa character devices to expose to the guest.
+    #[cfg(all(target_os = "linux", not(feature = "tee")))]
+    pub vdpa_devices: Vec<PathBuf>,
+    /// virtio-input devices, fed with the events pushed to their queues.
+    #[cfg(not(feature = "tee"))]
+    pub input_devices: Vec<(InputKind, Arc<InputEventQueue>)>,
+    /// Activity counters of the microVM, kept by its vCPUs and devices.
+    pub metrics: Arc<Metrics>,
+    /// The moments the microVM reached each of its boot milestones.
+    pub boot_timeline: Arc<BootTimeline>,
 }
 
 impl VmResources {
@@ -166,6 +256,20 @@ impl VmResources {
             vcpu_count: self.vm_config().vcpu_count.unwrap(),
             ht_enabled: self.vm_config().ht_enabled.unwrap(),
             cpu_template: self.vm_config().cpu_template,
+            #[cfg(target_arch = "x86_64")]
+            clock: ClockConfig {
+                // kvmclock follows the host's wall clock.
+                kvmclock: self.clock_config.kvmclock && self.deterministic.is_none(),
+                ..self.clock_config
+            },
+            #[cfg(target_os = "macos")]
+            idle_throttle: self.idle_throttle,
+            #[cfg(target_os = "linux")]
+            nested: self.nested_enabled,
+            #[cfg(target_os = "linux")]
+            pmu: self.pmu_enabled,
+            #[cfg(target_os = "linux")]
+            deterministic: self.deterministic.is_some(),
         }
     }
 
@@ -377,6 +481,7 @@ mod tests {
             kernel_bundle: Default::default(),
             external_kernel: None,
             fs: Default::default(),
+            init_binary: None,
             vsock: Default::default(),
             #[cfg(feature = "net")]
             net_builder: Default::default(),
@@ -386,15 +491,41 @@ mod tests {
             display_backend: DisplayBackendConfig::Noop,
             #[cfg(feature = "gpu")]
             displays: Vec::new(),
+            #[cfg(feature = "gpu")]
+            scanout_capture: None,
             #[cfg(feature = "snd")]
             enable_snd: False,
             console_output: None,
             smbios_oem_strings: None,
             nested_enabled: false,
+            pmu_enabled: false,
+            core_scheduling: Default::default(),
+            start_paused: false,
+            kernel_load_addr: None,
+            kaslr_seed: None,
+            deterministic: None,
+            clock_config: Default::default(),
             split_irqchip: false,
+            idle_throttle: None,
+            queue_sizes: HashMap::new(),
+            guest_memory_regions: Vec::new(),
+            virtio_plugins: Vec::new(),
             disable_implicit_console: false,
+            boot_log: None,
             consoles: HashMap::new(),
+            workload_stdio: None,
+            workload_tty: Default::default(),
             kernel_console: None,
+            #[cfg(not(feature = "tee"))]
+            memory_pressure: None,
+            #[cfg(not(feature = "tee"))]
+            measured_boot: None,
+            #[cfg(all(target_os = "linux", not(feature = "tee")))]
+            vdpa_devices: Vec::new(),
+            #[cfg(not(feature = "tee"))]
+            input_devices: Vec::new(),
+            metrics: Default::default(),
+            boot_timeline: Default::default(),
         }
     }
 
@@ -405,6 +536,16 @@ mod tests {
             vcpu_count: vm_resources.vm_config().vcpu_count.unwrap(),
             ht_enabled: vm_resources.vm_config().ht_enabled.unwrap(),
             cpu_template: vm_resources.vm_config().cpu_template,
+            #[cfg(target_arch = "x86_64")]
+            clock: Default::default(),
+            #[cfg(target_os = "macos")]
+            idle_throttle: None,
+            #[cfg(target_os = "linux")]
+            nested: false,
+            #[cfg(target_os = "linux")]
+            pmu: false,
+            #[cfg(target_os = "linux")]
+            deterministic: false,
         };
 
         let vcpu_config = vm_resources.vcpu_config();
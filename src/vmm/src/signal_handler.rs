@@ -16,8 +16,65 @@ const SI_OFF_SYSCALL: isize = 6;
 
 const SYS_SECCOMP_CODE: i32 = 1;
 
-static CONSOLE_SIGWINCH_FD: AtomicI32 = AtomicI32::new(-1);
-static CONSOLE_SIGINT_FD: AtomicI32 = AtomicI32::new(-1);
+/// The most consoles of all the microVMs of the process notified of a signal.
+const MAX_CONSOLES: usize = 64;
+
+/// Event fds of the consoles notified of a signal, one per microVM, so all of
+/// them see it when several microVMs run in the process. Free slots are -1.
+struct ConsoleFds([AtomicI32; MAX_CONSOLES]);
+
+impl ConsoleFds {
+    const fn new() -> Self {
+        ConsoleFds([const { AtomicI32::new(-1) }; MAX_CONSOLES])
+    }
+
+    fn add(&self, fd: RawFd) -> utils::errno::Result<()> {
+        self.0
+            .iter()
+            .find(|slot| {
+                slot.compare_exchange(-1, fd, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .map(|_| ())
+            .ok_or_else(|| {
+                error!("Can't notify more than {MAX_CONSOLES} consoles of signals");
+                utils::errno::Error::new(libc::ENOSPC)
+            })
+    }
+
+    fn remove(&self, fd: RawFd) {
+        for slot in &self.0 {
+            let _ = slot.compare_exchange(fd, -1, Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+
+    /// Notifies every console. Only async-signal-safe functions are called.
+    fn notify(&self) {
+        let val: u64 = 1;
+        for slot in &self.0 {
+            let fd = slot.load(Ordering::Relaxed);
+            if fd >= 0 {
+                let _ = unsafe { libc::write(fd, &val as *const _ as *const c_void, 8) };
+            }
+        }
+    }
+}
+
+static CONSOLE_SIGWINCH_FDS: ConsoleFds = ConsoleFds::new();
+static CONSOLE_SIGINT_FDS: ConsoleFds = ConsoleFds::new();
+
+/// A console notified of a signal, until dropped along with the microVM, so
+/// the signal handlers never write to an fd closed or reused since.
+pub struct ConsoleSignalGuard {
+    fds: &'static ConsoleFds,
+    fd: RawFd,
+}
+
+impl Drop for ConsoleSignalGuard {
+    fn drop(&mut self) {
+        self.fds.remove(self.fd);
+    }
+}
 
 /// Signal handler for `SIGSYS`.
 ///
@@ -84,9 +141,7 @@ extern "C" fn sigwinch_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c
         unsafe { _exit(i32::from(super::FC_EXIT_CODE_UNEXPECTED_ERROR)) };
     }
 
-    let val: u64 = 1;
-    let console_fd = CONSOLE_SIGWINCH_FD.load(Ordering::Relaxed);
-    let _ = unsafe { libc::write(console_fd, &val as *const _ as *const c_void, 8) };
+    CONSOLE_SIGWINCH_FDS.notify();
 }
 
 extern "C" fn sigint_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_void) {
@@ -99,25 +154,31 @@ extern "C" fn sigint_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_v
         unsafe { _exit(i32::from(super::FC_EXIT_CODE_UNEXPECTED_ERROR)) };
     }
 
-    let val: u64 = 1;
-    let console_fd = CONSOLE_SIGINT_FD.load(Ordering::Relaxed);
-    let _ = unsafe { libc::write(console_fd, &val as *const _ as *const c_void, 8) };
+    CONSOLE_SIGINT_FDS.notify();
 }
 
-pub fn register_sigwinch_handler(console_fd: RawFd) -> utils::errno::Result<()> {
-    CONSOLE_SIGWINCH_FD.store(console_fd, Ordering::Relaxed);
+pub fn register_sigwinch_handler(console_fd: RawFd) -> utils::errno::Result<ConsoleSignalGuard> {
+    CONSOLE_SIGWINCH_FDS.add(console_fd)?;
+    let guard = ConsoleSignalGuard {
+        fds: &CONSOLE_SIGWINCH_FDS,
+        fd: console_fd,
+    };
 
     register_signal_handler(SIGWINCH, sigwinch_handler)?;
 
-    Ok(())
+    Ok(guard)
 }
 
-pub fn register_sigint_handler(sigint_fd: RawFd) -> utils::errno::Result<()> {
-    CONSOLE_SIGINT_FD.store(sigint_fd, Ordering::Relaxed);
+pub fn register_sigint_handler(sigint_fd: RawFd) -> utils::errno::Result<ConsoleSignalGuard> {
+    CONSOLE_SIGINT_FDS.add(sigint_fd)?;
+    let guard = ConsoleSignalGuard {
+        fds: &CONSOLE_SIGINT_FDS,
+        fd: sigint_fd,
+    };
 
     register_signal_handler(SIGINT, sigint_handler)?;
 
-    Ok(())
+    Ok(guard)
 }
 
 /// Registers all the required signal handlers.
@@ -134,3 +195,21 @@ pub fn register_signal_handlers() -> utils::errno::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_console_fds() {
+        let fds = ConsoleFds::new();
+        for fd in 0..MAX_CONSOLES as RawFd {
+            fds.add(fd).unwrap();
+        }
+        assert_eq!(fds.add(100).unwrap_err().errno(), libc::ENOSPC);
+
+        fds.remove(3);
+        assert!(fds.0.iter().all(|slot| slot.load(Ordering::Relaxed) != 3));
+        fds.add(100).unwrap();
+    }
+}
@@ -1,7 +1,12 @@
 use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
-use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg, Termios};
 use nix::unistd::isatty;
-use std::os::fd::BorrowedFd;
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::sync::Mutex;
+
+/// Attributes of the terminal before switching it to raw mode for the first
+/// time, to be restored when the VMM exits.
+static SAVED_TERMIOS: Mutex<Option<(RawFd, Termios)>> = Mutex::new(None);
 
 pub fn term_set_raw_mode(handle_signals_by_terminal: bool) -> Result<(), nix::Error> {
     if let Some(fd) = get_connected_term_fd() {
@@ -11,7 +16,14 @@ pub fn term_set_raw_mode(handle_signals_by_terminal: bool) -> Result<(), nix::Er
     }
 }
 
+/// Restores the terminal attributes saved by `term_fd_set_raw_mode()` or,
+/// if there are none, puts the connected terminal in canonical mode.
 pub fn term_set_canonical_mode() -> Result<(), nix::Error> {
+    if let Some((fd, termios)) = SAVED_TERMIOS.lock().unwrap().take() {
+        // SAFETY: the terminal file descriptors are never closed.
+        let term = unsafe { BorrowedFd::borrow_raw(fd) };
+        return tcsetattr(term, SetArg::TCSANOW, &termios);
+    }
     if let Some(fd) = get_connected_term_fd() {
         term_fd_set_canonical_mode(fd)
     } else {
@@ -24,6 +36,10 @@ pub fn term_fd_set_raw_mode(
     handle_signals_by_terminal: bool,
 ) -> Result<(), nix::Error> {
     let mut termios = tcgetattr(term)?;
+    SAVED_TERMIOS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| (term.as_raw_fd(), termios.clone()));
 
     let mut mask = LocalFlags::ECHO | LocalFlags::ICANON;
     if !handle_signals_by_terminal {
@@ -1,13 +1,28 @@
 use std::collections::VecDeque;
 use std::fmt;
+use std::fs;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
-use devices::virtio::{block::ImageType, Block, CacheType};
+#[cfg(feature = "http")]
+use devices::virtio::block::HttpDisk;
+use devices::virtio::block::{create_overlay, ImageType};
+use devices::virtio::{Block, CacheType};
 
 #[derive(Debug)]
 pub enum BlockConfigError {
     /// Failed to create the block device.
     CreateBlockDevice(std::io::Error),
+    /// Failed to create the copy-on-write overlay of the block device.
+    CreateOverlay(std::io::Error),
+    /// Failed to unlock the encrypted disk of the block device.
+    UnlockDisk(std::io::Error),
+    /// Failed to open the dm-verity hash tree of the block device.
+    OpenVerity(std::io::Error),
+    /// Failed to prepare the cache of the remote image of the block device.
+    OpenHttpDisk(std::io::Error),
+    /// Failed to split the disk of the block device in zones.
+    SetZoned(std::io::Error),
 }
 
 impl fmt::Display for BlockConfigError {
@@ -15,6 +30,11 @@ impl fmt::Display for BlockConfigError {
         use self::BlockConfigError::*;
         match *self {
             CreateBlockDevice(ref e) => write!(f, "Cannot create block device: {e:?}"),
+            CreateOverlay(ref e) => write!(f, "Cannot create disk overlay: {e:?}"),
+            UnlockDisk(ref e) => write!(f, "Cannot unlock encrypted disk: {e:?}"),
+            OpenVerity(ref e) => write!(f, "Cannot open dm-verity hash tree: {e:?}"),
+            OpenHttpDisk(ref e) => write!(f, "Cannot open remote disk image: {e:?}"),
+            SetZoned(ref e) => write!(f, "Cannot make the disk zoned: {e:?}"),
         }
     }
 }
@@ -28,6 +48,66 @@ pub struct BlockDeviceConfig {
     pub disk_image_path: String,
     pub disk_image_format: ImageType,
     pub is_disk_read_only: bool,
+    /// Makes the disk image a copy-on-write overlay over a base image.
+    pub overlay: Option<OverlayConfig>,
+    /// Unlocks the disk image, a LUKS2 volume, on the host.
+    pub luks_passphrase: Option<LuksPassphrase>,
+    /// Checks the reads of the disk image against a dm-verity hash tree.
+    pub verity: Option<VerityConfig>,
+    /// Makes the disk image a cache of the image at this URL, fetched as the
+    /// guest uses it.
+    pub http_url: Option<String>,
+    /// Exposes the disk image as a zoned device.
+    pub zoned: Option<ZonedConfig>,
+}
+
+/// The zones a disk image is split in, whose write pointers are kept next to
+/// the image.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ZonedConfig {
+    /// The size of each zone, in bytes.
+    pub zone_size: u64,
+    /// The most zones open at once, 0 if not limited.
+    pub max_open: u32,
+    /// The most zones open or closed at once, 0 if not limited.
+    pub max_active: u32,
+}
+
+/// The dm-verity hash tree of a read-only disk image, trusted through its
+/// root hash.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerityConfig {
+    pub hash_path: String,
+    pub root_hash: Vec<u8>,
+}
+
+/// The passphrase of a LUKS2 volume, kept out of the logs and wiped once
+/// dropped.
+#[derive(Clone, Eq, PartialEq)]
+pub struct LuksPassphrase(pub Vec<u8>);
+
+impl fmt::Debug for LuksPassphrase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LuksPassphrase(..)")
+    }
+}
+
+impl Drop for LuksPassphrase {
+    fn drop(&mut self) {
+        self.0.fill(0);
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// A base image shared by the overlays of many VMs, which is only read.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OverlayConfig {
+    pub base_path: String,
+    pub base_format: ImageType,
+    /// Whether the overlay is created anew and deleted once open, so the
+    /// changes of the guest are dropped when the VM exits. Otherwise, an
+    /// existing overlay is kept.
+    pub ephemeral: bool,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -56,7 +136,49 @@ impl BlockBuilder {
     }
 
     pub fn create_block(config: BlockDeviceConfig) -> Result<Block> {
-        devices::virtio::Block::new(
+        let path = config.disk_image_path.clone();
+        let ephemeral = config
+            .overlay
+            .as_ref()
+            .is_some_and(|overlay| overlay.ephemeral);
+        if let Some(overlay) = &config.overlay {
+            if ephemeral {
+                let _ = fs::remove_file(&path);
+            }
+            if ephemeral || !Path::new(&path).exists() {
+                create_overlay(
+                    Path::new(&path),
+                    Path::new(&overlay.base_path),
+                    &overlay.base_format,
+                )
+                .map_err(BlockConfigError::CreateOverlay)?;
+            }
+        }
+
+        if config.http_url.is_some()
+            && (config.luks_passphrase.is_some() || config.verity.is_some())
+        {
+            return Err(BlockConfigError::OpenHttpDisk(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "remote disk images can't be encrypted or checked by dm-verity",
+            )));
+        }
+        #[cfg(feature = "http")]
+        let http_disk = match &config.http_url {
+            Some(url) => Some(Arc::new(
+                HttpDisk::open(url, Path::new(&path)).map_err(BlockConfigError::OpenHttpDisk)?,
+            )),
+            None => None,
+        };
+        #[cfg(not(feature = "http"))]
+        if config.http_url.is_some() {
+            return Err(BlockConfigError::OpenHttpDisk(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "libkrun was built without support for remote disk images",
+            )));
+        }
+
+        let block = devices::virtio::Block::new(
             config.block_id,
             None,
             config.cache_type,
@@ -64,6 +186,35 @@ impl BlockBuilder {
             config.disk_image_format,
             config.is_disk_read_only,
         )
-        .map_err(BlockConfigError::CreateBlockDevice)
+        .map_err(BlockConfigError::CreateBlockDevice);
+        if ephemeral {
+            let _ = fs::remove_file(&path);
+        }
+        let mut block = block?;
+        #[cfg(feature = "http")]
+        if let Some(http_disk) = http_disk {
+            block.set_http_disk(http_disk);
+        }
+        if let Some(passphrase) = &config.luks_passphrase {
+            block
+                .unlock(&passphrase.0)
+                .map_err(BlockConfigError::UnlockDisk)?;
+        }
+        if let Some(verity) = &config.verity {
+            block
+                .set_verity(Path::new(&verity.hash_path), &verity.root_hash)
+                .map_err(BlockConfigError::OpenVerity)?;
+        }
+        if let Some(zoned) = &config.zoned {
+            block
+                .set_zoned(
+                    Path::new(&format!("{path}.zones")),
+                    zoned.zone_size,
+                    zoned.max_open,
+                    zoned.max_active,
+                )
+                .map_err(BlockConfigError::SetZoned)?;
+        }
+        Ok(block)
     }
 }
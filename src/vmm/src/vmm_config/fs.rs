@@ -3,4 +3,18 @@ pub struct FsDeviceConfig {
     pub fs_id: String,
     pub shared_dir: String,
     pub shm_size: Option<usize>,
+    /// Whether the guest may cache the writes to the files, see `passthrough::Config::writeback`.
+    pub writeback: bool,
+    /// Whether `O_DIRECT` is honored on the host, see
+    /// `passthrough::Config::allow_direct_io`.
+    pub allow_direct_io: bool,
+    /// How many closed files are kept open for reuse, see
+    /// `passthrough::Config::fd_cache_size`.
+    pub fd_cache_size: usize,
+    /// Whether the guest is refused any change to the shared directory.
+    pub readonly: bool,
+    /// The most bytes the files of the shared directory may use on disk.
+    pub quota: Option<u64>,
+    /// How many threads serve the requests of the guest.
+    pub threads: usize,
 }
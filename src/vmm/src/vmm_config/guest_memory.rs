@@ -0,0 +1,21 @@
+use std::fs::File;
+
+/// Memory provided by the embedder to back a region of guest memory.
+#[derive(Debug)]
+pub enum GuestMemoryBacking {
+    /// A memfd or regular file, mapped shared starting at `offset`.
+    File { file: File, offset: u64 },
+    /// An existing allocation of the embedder, which must stay valid for
+    /// the lifetime of the microVM.
+    Host(usize),
+}
+
+/// A region of guest physical memory backed by the embedder. It isn't
+/// reported to the guest as usable RAM, so the kernel doesn't allocate from
+/// it, and it's up to the drivers to know where to find it.
+#[derive(Debug)]
+pub struct GuestMemoryRegionConfig {
+    pub guest_addr: u64,
+    pub size: usize,
+    pub backing: GuestMemoryBacking,
+}
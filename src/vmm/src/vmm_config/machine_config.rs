@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fmt;
+use std::path::PathBuf;
 
 /// Firecracker aims to support small scale workloads only, so limit the maximum
 /// vCPUs supported.
@@ -69,6 +70,54 @@ impl fmt::Display for VmConfig {
     }
 }
 
+/// Configuration of the clocks exposed to the guest. Only honored on x86_64.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClockConfig {
+    /// Guest TSC frequency in kHz, or `None` to use the host's frequency.
+    pub tsc_khz: Option<u32>,
+    /// Whether to advertise an invariant TSC, or `None` to keep what KVM
+    /// reports for the host.
+    pub invariant_tsc: Option<bool>,
+    /// Whether to expose the kvmclock paravirtual clocksource.
+    pub kvmclock: bool,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig {
+            tsc_khz: None,
+            invariant_tsc: None,
+            kvmclock: true,
+        }
+    }
+}
+
+/// Configuration of a deterministic run, whose device inputs are recorded
+/// to a journal, or replayed from one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeterministicConfig {
+    /// Path of the journal.
+    pub journal: PathBuf,
+    /// Whether to replay the journal, instead of recording a new one.
+    pub replay: bool,
+    /// Seed of the deterministic devices when recording. Replays use the one
+    /// recorded in the journal.
+    pub seed: u64,
+}
+
+/// Which tasks the vCPU threads may share a physical core with through SMT,
+/// using Linux core scheduling. Only honored on Linux.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CoreScheduling {
+    /// Any task, as decided by the host scheduler.
+    #[default]
+    Disabled,
+    /// The threads of the VMM process, including the other vCPUs.
+    Process,
+    /// None, each vCPU gets a core for itself while it runs.
+    Vcpu,
+}
+
 /// Template types available for configuring the CPU features that map
 /// to EC2 instances.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -18,6 +18,9 @@ pub mod external_kernel;
 #[cfg(not(feature = "tee"))]
 pub mod fs;
 
+/// Wrapper for configuring guest memory regions backed by the embedder.
+pub mod guest_memory;
+
 /// Wrapper over the microVM general information attached to the microVM.
 pub mod instance_info;
 
@@ -18,6 +18,8 @@ pub struct NetworkInterfaceConfig {
     pub mac: [u8; 6],
     /// virtio-net features for the network interface.
     pub features: u32,
+    /// MTU advertised to the guest.
+    pub mtu: Option<u16>,
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
@@ -65,7 +67,11 @@ impl NetBuilder {
     /// Creates a Net device from a NetworkInterfaceConfig.
     pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
         // Create and return the Net device
-        Net::new(cfg.iface_id, cfg.backend, cfg.mac, cfg.features)
-            .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        let mut net = Net::new(cfg.iface_id, cfg.backend, cfg.mac, cfg.features)
+            .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        if let Some(mtu) = cfg.mtu {
+            net.set_mtu(mtu);
+        }
+        Ok(net)
     }
 }
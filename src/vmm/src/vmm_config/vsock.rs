@@ -3,9 +3,11 @@
 
 use std::collections::HashMap;
 use std::fmt;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
+use devices::virtio::vsock::http_proxy::HttpProxyConfig;
 use devices::virtio::{Vsock, VsockError};
 
 type MutexVsock = Arc<Mutex<Vsock>>;
@@ -38,8 +40,20 @@ pub struct VsockDeviceConfig {
     pub guest_cid: u32,
     /// An optional map of host to guest port mappings.
     pub host_port_map: Option<HashMap<u16, u16>>,
+    /// An optional map of guest UNIX socket names, starting with '@' for
+    /// abstract ones, to the host paths they're exposed on.
+    pub unix_socket_map: Option<HashMap<String, PathBuf>>,
+    /// Listening TCP sockets bound by the caller, e.g. to privileged ports,
+    /// by the guest port they're forwarded to. The device created takes
+    /// ownership of them.
+    pub listen_fds: HashMap<u16, RawFd>,
+    /// An optional HTTP proxy the TCP connections to some ports go through.
+    pub http_proxy: Option<HttpProxyConfig>,
     /// An optional map of guest port to host UNIX domain sockets for IPC.
     pub unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
+    /// An optional directory shared with other microVMs, for routing vsock
+    /// connections to their CIDs.
+    pub sibling_dir: Option<PathBuf>,
 }
 
 struct VsockWrapper {
@@ -74,12 +88,23 @@ impl VsockBuilder {
 
     /// Creates a Vsock device from a VsockDeviceConfig.
     pub fn create_vsock(cfg: VsockDeviceConfig) -> Result<Vsock> {
-        Vsock::new(
+        let mut vsock = Vsock::new(
             u64::from(cfg.guest_cid),
             cfg.host_port_map,
+            cfg.unix_socket_map,
+            cfg.listen_fds
+                .into_iter()
+                // SAFETY: the caller handed the fds over to us.
+                .map(|(port, fd)| (port, unsafe { OwnedFd::from_raw_fd(fd) }))
+                .collect(),
             cfg.unix_ipc_port_map,
+            cfg.sibling_dir,
         )
-        .map_err(VsockConfigError::CreateVsockDevice)
+        .map_err(VsockConfigError::CreateVsockDevice)?;
+        if let Some(http_proxy) = cfg.http_proxy {
+            vsock.set_http_proxy(http_proxy);
+        }
+        Ok(vsock)
     }
 }
 
@@ -114,7 +139,11 @@ pub(crate) mod tests {
             vsock_id: vsock_dev_id.to_string(),
             guest_cid: 3,
             host_port_map: None,
+            unix_socket_map: None,
+            listen_fds: HashMap::new(),
+            http_proxy: None,
             unix_ipc_port_map: None,
+            sibling_dir: None,
         }
     }
 
@@ -38,7 +38,7 @@ pub fn start_worker_thread(
 }
 
 impl super::Vmm {
-    fn match_worker_message(&self, msg: WorkerMessage) {
+    fn match_worker_message(&mut self, msg: WorkerMessage) {
         match msg {
             #[cfg(target_os = "macos")]
             WorkerMessage::GpuAddMapping(s, h, g, l) => self.add_mapping(s, h, g, l),
@@ -59,6 +59,23 @@ impl super::Vmm {
                     .send(self.vm.fd().set_irq_line(irq, active).is_ok())
                     .unwrap();
             }
+            WorkerMessage::AddMemorySlot(sender, host_addr, guest_addr, len) => {
+                let slot = match self.vm.add_memory_slot(host_addr, guest_addr, len) {
+                    Ok(slot) => Some(slot),
+                    Err(e) => {
+                        error!("unable to add memory slot at GPA 0x{guest_addr:x}: {e}");
+                        None
+                    }
+                };
+                sender.send(slot).unwrap();
+            }
+            WorkerMessage::RemoveMemorySlot(sender, slot) => {
+                let res = self.vm.remove_memory_slot(slot);
+                if let Err(e) = &res {
+                    error!("unable to remove memory slot {slot}: {e}");
+                }
+                sender.send(res.is_ok()).unwrap();
+            }
             WorkerMessage::ConvertMemory(_sender, _properties) =>
             {
                 #[cfg(feature = "tee")]